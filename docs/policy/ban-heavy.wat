@@ -0,0 +1,96 @@
+;; An example --policy-module, see "Policy modules" in the README. The daemon loads the text
+;; format as it is; `wat2wasm ban-heavy.wat` makes the binary one.
+;;
+;; - sources of the group `partners` are left alone, and an auto-ban of one is lifted
+;; - a source at twice its budget or more is banned for 10 minutes
+;; - a source over its budget gets tag score 50, half its normal budget
+(module
+  (memory (export "memory") 1)
+
+  ;; The fields looked for, at fixed offsets
+  (data (i32.const 0) "\"packets\":")               ;; 10 bytes
+  (data (i32.const 16) "\"limit\":")                ;; 8 bytes
+  (data (i32.const 32) "\"group\":\"partners\"")    ;; 18 bytes
+  (data (i32.const 64) "\"decision\":\"auto-ban\"") ;; 21 bytes
+
+  ;; One source at a time, always in the same place
+  (func (export "alloc") (param $len i32) (result i32)
+    (i32.const 1024))
+
+  ;; Offset of the `nlen` bytes at `needle` in the `len` bytes at `hay`, or -1
+  (func $find (param $hay i32) (param $len i32) (param $needle i32) (param $nlen i32)
+    (result i32)
+    (local $i i32)
+    (local $j i32)
+    (block $none
+      (loop $next
+        (br_if $none (i32.gt_s (i32.add (local.get $i) (local.get $nlen)) (local.get $len)))
+        (local.set $j (i32.const 0))
+        (block $miss
+          (loop $byte
+            (if (i32.eq (local.get $j) (local.get $nlen))
+              (then (return (local.get $i))))
+            (br_if $miss
+              (i32.ne
+                (i32.load8_u (i32.add (local.get $hay) (i32.add (local.get $i) (local.get $j))))
+                (i32.load8_u (i32.add (local.get $needle) (local.get $j)))))
+            (local.set $j (i32.add (local.get $j) (i32.const 1)))
+            (br $byte)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (i32.const -1))
+
+  ;; The number following the field name at `needle`, 0 without one
+  (func $number (param $hay i32) (param $len i32) (param $needle i32) (param $nlen i32)
+    (result i64)
+    (local $at i32)
+    (local $end i32)
+    (local $digit i32)
+    (local $value i64)
+    (local.set $at
+      (call $find (local.get $hay) (local.get $len) (local.get $needle) (local.get $nlen)))
+    (if (i32.lt_s (local.get $at) (i32.const 0))
+      (then (return (i64.const 0))))
+    (local.set $at (i32.add (local.get $hay) (i32.add (local.get $at) (local.get $nlen))))
+    (local.set $end (i32.add (local.get $hay) (local.get $len)))
+    (block $done
+      (loop $more
+        (br_if $done (i32.ge_u (local.get $at) (local.get $end)))
+        (local.set $digit (i32.sub (i32.load8_u (local.get $at)) (i32.const 48)))
+        (br_if $done (i32.gt_u (local.get $digit) (i32.const 9)))
+        (local.set $value
+          (i64.add
+            (i64.mul (local.get $value) (i64.const 10))
+            (i64.extend_i32_u (local.get $digit))))
+        (local.set $at (i32.add (local.get $at) (i32.const 1)))
+        (br $more)))
+    (local.get $value))
+
+  ;; The action in the low byte, its argument in the upper 32 bits
+  (func (export "evaluate") (param $ptr i32) (param $len i32) (result i64)
+    (local $packets i64)
+    (local $limit i64)
+    (if (i32.ge_s
+          (call $find (local.get $ptr) (local.get $len) (i32.const 32) (i32.const 18))
+          (i32.const 0))
+      (then
+        ;; trust
+        (if (i32.ge_s
+              (call $find (local.get $ptr) (local.get $len) (i32.const 64) (i32.const 21))
+              (i32.const 0))
+          (then (return (i64.const 3))))
+        (return (i64.const 0))))
+    (local.set $packets
+      (call $number (local.get $ptr) (local.get $len) (i32.const 0) (i32.const 10)))
+    (local.set $limit
+      (call $number (local.get $ptr) (local.get $len) (i32.const 16) (i32.const 8)))
+    ;; Blocked by its tag already
+    (if (i64.eqz (local.get $limit))
+      (then (return (i64.const 0))))
+    ;; ban for 600 seconds
+    (if (i64.ge_u (local.get $packets) (i64.shl (local.get $limit) (i64.const 1)))
+      (then (return (i64.or (i64.shl (i64.const 600) (i64.const 32)) (i64.const 1)))))
+    ;; greylist with score 50
+    (if (i64.gt_u (local.get $packets) (local.get $limit))
+      (then (return (i64.or (i64.shl (i64.const 50) (i64.const 32)) (i64.const 2)))))
+    (i64.const 0)))
@@ -0,0 +1,185 @@
+//! Async client for a running `xdp-api-guard`, over the control socket or the REST API.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use std::time::Duration;
+//!
+//! use xdp_api_guard_client::{GuardClient, HttpTransport};
+//!
+//! let guard = GuardClient::new(HttpTransport::new("127.0.0.1:9100").token("s3cret"));
+//! guard
+//!     .block("1.2.3.4".parse()?, Some(Duration::from_secs(3600)))
+//!     .await?;
+//! for entry in guard.list().await? {
+//!     println!("{entry}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Reads are retried when the transport fails. Changes are retried too, under one idempotency
+//! key for all attempts, so a change whose reply got lost runs once. The daemon remembers
+//! keys for 10 minutes, which is far longer than the retries take. A command the daemon
+//! answered with `err` is a `CommandFailed` and never retried. Neither transport streams, so
+//! blocklist changes have to be polled with `list`.
+
+mod transport;
+
+use std::{
+    fmt,
+    net::Ipv4Addr,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+pub use transport::{HttpTransport, Refused, Transport, UnixTransport};
+use xdp_api_guard_common::api::split_reply;
+pub use xdp_api_guard_common::api::{BlocklistPage, ListEntry};
+
+/// When and how often a failed call is tried again.
+#[derive(Clone, Copy, Debug)]
+pub struct Retry {
+    /// Tries in all, at least 1.
+    pub attempts: u32,
+    /// Wait before the first retry, doubled for each one after.
+    pub backoff: Duration,
+    /// Limit on each attempt.
+    pub timeout: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            backoff: Duration::from_millis(200),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The daemon ran the command and replied `err`, with what follows it.
+#[derive(Debug)]
+pub struct CommandFailed(pub String);
+
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+pub struct GuardClient<T> {
+    transport: T,
+    retry: Retry,
+}
+
+impl<T: Transport> GuardClient<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            retry: Retry::default(),
+        }
+    }
+
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Blocks `ip`, for `ttl` or until unblocked. Returns the state the block left behind,
+    /// e.g. `1.2.3.4 manual-block, expires in 3600s`.
+    pub async fn block(&self, ip: Ipv4Addr, ttl: Option<Duration>) -> anyhow::Result<String> {
+        let line = match ttl {
+            Some(ttl) => format!("block {ip} --ttl {}", ttl.as_secs()),
+            None => format!("block {ip}"),
+        };
+        self.command(&line).await
+    }
+
+    /// Removes the blocklist entry of `ip`, if it has one.
+    pub async fn unblock(&self, ip: Ipv4Addr) -> anyhow::Result<String> {
+        self.command(&format!("unblock {ip}")).await
+    }
+
+    /// Blocklist and management networks, addresses in order.
+    pub async fn list(&self) -> anyhow::Result<Vec<ListEntry>> {
+        let reply = self.send("list", None).await?;
+        // `N entries` first
+        reply
+            .lines()
+            .skip(1)
+            .map(|line| {
+                line.parse()
+                    .map_err(|e| anyhow!("unexpected list line {line:?}: {e}"))
+            })
+            .collect()
+    }
+
+    /// Up to `limit` blocklist entries after `cursor`, addresses in order, and the cursor of
+    /// the next page, `None` after the last. Start without a cursor. Pages tolerate changes
+    /// in between: no entry comes twice, an entry added meanwhile may or may not.
+    pub async fn export_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<BlocklistPage> {
+        self.attempts(|| self.transport.blocklist_page(cursor, limit))
+            .await
+    }
+
+    /// The stats document of `/v1/stats`, see the README for its fields. REST API only.
+    pub async fn stats(&self, window: Option<usize>) -> anyhow::Result<serde_json::Value> {
+        self.attempts(|| self.transport.stats(window)).await
+    }
+
+    /// Any command `guardctl` would send, under an idempotency key of its own. Returns the
+    /// reply without its `ok`.
+    pub async fn command(&self, line: &str) -> anyhow::Result<String> {
+        self.send(line, Some(&new_key())).await
+    }
+
+    async fn send(&self, line: &str, key: Option<&str>) -> anyhow::Result<String> {
+        let reply = self.attempts(|| self.transport.command(line, key)).await?;
+        match split_reply(&reply) {
+            Some(Ok(ok)) => Ok(ok.to_owned()),
+            Some(Err(err)) => Err(CommandFailed(err.to_owned()).into()),
+            None => bail!("unexpected reply {reply:?}"),
+        }
+    }
+
+    async fn attempts<R, F>(&self, call: impl Fn() -> F) -> anyhow::Result<R>
+    where
+        F: Future<Output = anyhow::Result<R>>,
+    {
+        let mut backoff = self.retry.backoff;
+        let mut attempt = 1;
+        loop {
+            let result = match tokio::time::timeout(self.retry.timeout, call()).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("no reply within {:?}", self.retry.timeout)),
+            };
+            match result {
+                Err(e) if attempt < self.retry.attempts && !e.is::<Refused>() => {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+// Unique across processes and restarts: time, pid and a counter
+fn new_key() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    format!("client-{now:x}-{:x}-{n}", process::id())
+}
@@ -0,0 +1,229 @@
+//! The two ways to reach the daemon. Both carry the same command lines and replies.
+
+use std::{
+    fmt::{self, Write as _},
+    path::PathBuf,
+};
+
+use anyhow::{Context as _, anyhow, bail};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpStream, UnixStream},
+};
+use xdp_api_guard_common::{
+    DEFAULT_CONTROL_SOCKET,
+    api::{BlocklistPage, CommandReply, ErrorReply, split_reply},
+};
+
+pub trait Transport {
+    /// Sends one command line, with the idempotency key if there is one, and returns the
+    /// reply line, `err` replies included.
+    fn command(
+        &self,
+        line: &str,
+        key: Option<&str>,
+    ) -> impl Future<Output = anyhow::Result<String>> + Send;
+
+    /// The stats document of `/v1/stats`, trimmed to the last `window` seconds of history.
+    fn stats(
+        &self,
+        window: Option<usize>,
+    ) -> impl Future<Output = anyhow::Result<serde_json::Value>> + Send {
+        let _ = window;
+        async { bail!("stats are only served by the REST API") }
+    }
+
+    /// Up to `limit` blocklist entries after `cursor`, see `GuardClient::export_page`.
+    fn blocklist_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> impl Future<Output = anyhow::Result<BlocklistPage>> + Send {
+        let _ = (cursor, limit);
+        async { bail!("this transport doesn't export the blocklist") }
+    }
+}
+
+/// The daemon refused a request before running it, e.g. for a missing token. Not retried.
+#[derive(Debug)]
+pub struct Refused {
+    pub status: u16,
+    pub message: String,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// The control socket, `--control-socket`. Whoever can open it is admin.
+#[derive(Clone, Debug)]
+pub struct UnixTransport {
+    pub path: PathBuf,
+}
+
+impl UnixTransport {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Default for UnixTransport {
+    fn default() -> Self {
+        Self::new(DEFAULT_CONTROL_SOCKET)
+    }
+}
+
+impl Transport for UnixTransport {
+    async fn command(&self, line: &str, key: Option<&str>) -> anyhow::Result<String> {
+        let mut stream = UnixStream::connect(&self.path)
+            .await
+            .with_context(|| format!("failed to connect to {}", self.path.display()))?;
+        let line = match key {
+            Some(key) => format!("req_id={key} {line}\n"),
+            None => format!("{line}\n"),
+        };
+        stream.write_all(line.as_bytes()).await?;
+        stream.shutdown().await?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        Ok(reply.trim_end().to_owned())
+    }
+
+    // With the `export` command, which pages like `GET /v1/blocklist`
+    async fn blocklist_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<BlocklistPage> {
+        let line = match cursor {
+            Some(cursor) => format!("export --cursor {cursor} --limit {limit}"),
+            None => format!("export --limit {limit}"),
+        };
+        let reply = self.command(&line, None).await?;
+        match split_reply(&reply) {
+            Some(Ok(page)) => page
+                .parse()
+                .map_err(|e| anyhow!("unexpected export reply: {e}")),
+            Some(Err(err)) => bail!("{err}"),
+            None => bail!("unexpected reply {reply:?}"),
+        }
+    }
+}
+
+/// The REST API, `--http-listen`. Plain HTTP like the server, so keep it on loopback or a
+/// management network.
+#[derive(Clone, Debug)]
+pub struct HttpTransport {
+    /// `host:port`
+    pub addr: String,
+    /// Bearer token, needed for commands and wherever the daemon has tokens configured.
+    pub token: Option<String>,
+}
+
+impl HttpTransport {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            token: None,
+        }
+    }
+
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    // One request per connection, the server closes it after the reply
+    async fn request(
+        &self,
+        method: &str,
+        target: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> anyhow::Result<(u16, Vec<u8>)> {
+        let mut stream = TcpStream::connect(&self.addr)
+            .await
+            .with_context(|| format!("failed to connect to {}", self.addr))?;
+        let mut head = format!(
+            "{method} {target} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.addr,
+            body.len()
+        );
+        if let Some(token) = &self.token {
+            let _ = write!(head, "Authorization: Bearer {token}\r\n");
+        }
+        for (name, value) in headers {
+            let _ = write!(head, "{name}: {value}\r\n");
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        let split = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| anyhow!("malformed HTTP response"))?;
+        let status = std::str::from_utf8(&response[..split])
+            .ok()
+            .and_then(|head| head.split_whitespace().nth(1))
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| anyhow!("malformed HTTP status line"))?;
+        Ok((status, response[split + 4..].to_vec()))
+    }
+}
+
+// Refusals carry an `ErrorReply`, everything else is the caller's to decode
+fn refused(status: u16, body: &[u8]) -> Refused {
+    let message = serde_json::from_slice::<ErrorReply>(body)
+        .map(|reply| reply.error)
+        .unwrap_or_else(|_| String::from_utf8_lossy(body).into_owned());
+    Refused { status, message }
+}
+
+impl Transport for HttpTransport {
+    async fn command(&self, line: &str, key: Option<&str>) -> anyhow::Result<String> {
+        let header = key.map(|key| ("Idempotency-Key", key));
+        let (status, body) = self
+            .request("POST", "/v1/command", header.as_slice(), line)
+            .await?;
+        // A failed command is a 400 with a reply like any other
+        match serde_json::from_slice::<CommandReply>(&body) {
+            Ok(reply) if matches!(status, 200 | 400) => Ok(reply.response),
+            _ => Err(refused(status, &body).into()),
+        }
+    }
+
+    async fn stats(&self, window: Option<usize>) -> anyhow::Result<serde_json::Value> {
+        let target = match window {
+            Some(window) => format!("/v1/stats?window={window}"),
+            None => "/v1/stats".to_owned(),
+        };
+        let (status, body) = self.request("GET", &target, &[], "").await?;
+        if status != 200 {
+            return Err(refused(status, &body).into());
+        }
+        serde_json::from_slice(&body).context("malformed stats document")
+    }
+
+    async fn blocklist_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<BlocklistPage> {
+        let target = match cursor {
+            Some(cursor) => format!("/v1/blocklist?cursor={cursor}&limit={limit}"),
+            None => format!("/v1/blocklist?limit={limit}"),
+        };
+        let (status, body) = self.request("GET", &target, &[], "").await?;
+        if status != 200 {
+            return Err(refused(status, &body).into());
+        }
+        serde_json::from_slice(&body).context("malformed blocklist page")
+    }
+}
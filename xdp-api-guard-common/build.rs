@@ -0,0 +1,46 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Generates the build identity both the eBPF object and userspace are stamped with (see
+/// `VersionInfo`). Both sides include the same generated file, so a mismatch at runtime means
+/// the loaded program comes from another build.
+fn main() {
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    let mut git_hash = [0u8; 16];
+    let len = hash.len().min(git_hash.len());
+    git_hash[..len].copy_from_slice(&hash.as_bytes()[..len]);
+
+    // Honour reproducible builds
+    let build_time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        });
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("version.rs");
+    fs::write(
+        out,
+        format!(
+            "pub const GIT_HASH: [u8; 16] = {git_hash:?};\npub const BUILD_TIME: u64 = {build_time};\n"
+        ),
+    )
+    .unwrap();
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
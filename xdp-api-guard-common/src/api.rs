@@ -0,0 +1,167 @@
+//! Replies of the control socket and the REST API, shared by the daemon, which writes them,
+//! and `xdp-api-guard-client`, which reads them. Commands themselves are plain text lines,
+//! as `guardctl` sends them.
+
+use alloc::{borrow::ToOwned as _, string::String, vec::Vec};
+use core::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as _};
+
+use crate::Origin;
+
+/// Body of a `POST /v1/command` reply: the reply line the control socket would have sent.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandReply {
+    pub response: String,
+}
+
+/// Body of every other REST API error.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorReply {
+    pub error: String,
+}
+
+/// What follows `ok` or `err` in a reply line, `Err` for `err`. `None` for a line that is
+/// neither.
+pub fn split_reply(response: &str) -> Option<Result<&str, &str>> {
+    let rest = |rest: &str| rest.strip_prefix(' ').unwrap_or(rest);
+    if let Some(ok) = response.strip_prefix("ok") {
+        Some(Ok(rest(ok)))
+    } else {
+        response.strip_prefix("err").map(|err| Err(rest(err)))
+    }
+}
+
+/// One line of the `list` reply, e.g. `1.2.3.4 cluster from edge-2 expires in 300s`. The
+/// target is an address, or a CIDR for management networks.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListEntry {
+    pub target: String,
+    pub origin: Origin,
+    /// Node a cluster ban came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    /// Seconds left, `None` for an entry without a TTL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<u64>,
+}
+
+impl fmt::Display for ListEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.target, self.origin.name())?;
+        if let Some(node) = &self.node {
+            write!(f, " from {node}")?;
+        }
+        if let Some(secs) = self.expires_in {
+            write!(f, " expires in {secs}s")?;
+        }
+        Ok(())
+    }
+}
+
+/// Body of `GET /v1/blocklist` and, as text, the `export` reply: the entries after the
+/// cursor, addresses in order, and the cursor to ask for the next page with, `None` after
+/// the last. Cursors are opaque.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlocklistPage {
+    pub entries: Vec<ListEntry>,
+    pub next_cursor: Option<String>,
+}
+
+// `2 entries, next 01020305` and a `ListEntry` per line
+impl fmt::Display for BlocklistPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} entries", self.entries.len())?;
+        if let Some(cursor) = &self.next_cursor {
+            write!(f, ", next {cursor}")?;
+        }
+        for entry in &self.entries {
+            write!(f, "\n{entry}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for BlocklistPage {
+    type Err = InvalidEntry;
+
+    fn from_str(reply: &str) -> Result<Self, Self::Err> {
+        let mut lines = reply.lines();
+        let head = lines.next().ok_or(InvalidEntry)?;
+        let (count, next_cursor) = match head.split_once(", next ") {
+            Some((count, cursor)) => (count, Some(cursor.to_owned())),
+            None => (head, None),
+        };
+        let count: usize = count
+            .strip_suffix(" entries")
+            .and_then(|n| n.parse().ok())
+            .ok_or(InvalidEntry)?;
+        let entries = lines
+            .map(str::parse)
+            .collect::<Result<Vec<ListEntry>, _>>()?;
+        if entries.len() != count {
+            return Err(InvalidEntry);
+        }
+        Ok(Self {
+            entries,
+            next_cursor,
+        })
+    }
+}
+
+/// A line that isn't a `list` entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidEntry;
+
+impl fmt::Display for InvalidEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("not a blocklist entry")
+    }
+}
+
+impl FromStr for ListEntry {
+    type Err = InvalidEntry;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let target = words.next().ok_or(InvalidEntry)?.to_owned();
+        let origin = words
+            .next()
+            .and_then(Origin::from_name)
+            .ok_or(InvalidEntry)?;
+        let mut entry = ListEntry {
+            target,
+            origin,
+            node: None,
+            expires_in: None,
+        };
+        while let Some(word) = words.next() {
+            match (word, words.next()) {
+                ("from", Some(node)) if entry.node.is_none() => entry.node = Some(node.to_owned()),
+                ("expires", Some("in")) if entry.expires_in.is_none() => {
+                    let secs = words.next().and_then(|secs| secs.strip_suffix('s'));
+                    entry.expires_in = secs.and_then(|secs| secs.parse().ok());
+                    if entry.expires_in.is_none() {
+                        return Err(InvalidEntry);
+                    }
+                }
+                _ => return Err(InvalidEntry),
+            }
+        }
+        Ok(entry)
+    }
+}
+
+/// By name, as in replies and the state file.
+impl Serialize for Origin {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Origin {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Origin::from_name(&name).ok_or_else(|| D::Error::custom("unknown origin"))
+    }
+}
@@ -1 +1,1818 @@
 #![no_std]
+
+#[cfg(feature = "api")]
+extern crate alloc;
+
+#[cfg(feature = "api")]
+pub mod api;
+
+/// Who put an entry into the blocklist. The discriminant doubles as the priority: when two
+/// origins disagree about an address, the higher one wins.
+///
+/// Precedence, highest first:
+///
+/// manual allow > management CIDR > manual block > policy-module ban > auto-ban > cluster ban >
+/// feed block > default policy
+///
+/// The kernel enforces this in one place by checking, in order: an allow entry in `BLOCKLIST`,
+/// the `MGMT_CIDRS` trie, and finally a drop entry in `BLOCKLIST`. Userspace keeps at most one
+/// `BLOCKLIST` entry per address and refuses writes that would replace a higher-priority one.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Origin {
+    Feed = 1,
+    /// A ban received from another node, see the `cluster` feature.
+    Cluster = 2,
+    AutoBan = 3,
+    /// A ban from the `--policy-module`, see the `wasm-policy` feature.
+    Policy = 4,
+    ManualBlock = 5,
+    Management = 6,
+    ManualAllow = 7,
+}
+
+impl Origin {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Origin::Feed),
+            2 => Some(Origin::Cluster),
+            3 => Some(Origin::AutoBan),
+            4 => Some(Origin::Policy),
+            5 => Some(Origin::ManualBlock),
+            6 => Some(Origin::Management),
+            7 => Some(Origin::ManualAllow),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Origin::Feed => "feed",
+            Origin::Cluster => "cluster",
+            Origin::AutoBan => "auto-ban",
+            Origin::Policy => "policy-module",
+            Origin::ManualBlock => "manual-block",
+            Origin::Management => "management",
+            Origin::ManualAllow => "manual-allow",
+        }
+    }
+
+    /// Every origin, lowest precedence first.
+    pub const ALL: [Origin; 7] = [
+        Origin::Feed,
+        Origin::Cluster,
+        Origin::AutoBan,
+        Origin::Policy,
+        Origin::ManualBlock,
+        Origin::Management,
+        Origin::ManualAllow,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|origin| origin.name() == name)
+    }
+
+    /// Manual decisions survive bulk flushes unless explicitly included.
+    pub fn is_manual(self) -> bool {
+        matches!(self, Origin::ManualBlock | Origin::ManualAllow)
+    }
+
+    /// The verdict an entry of this origin stands for.
+    pub fn action(self) -> u8 {
+        match self {
+            Origin::Management | Origin::ManualAllow => ACTION_ALLOW,
+            _ => ACTION_DROP,
+        }
+    }
+}
+
+pub const ACTION_DROP: u8 = 0;
+pub const ACTION_ALLOW: u8 = 1;
+
+/// Value stored in `BLOCKLIST`, keyed by the host-order IPv4 source address.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct BlockEntry {
+    pub action: u8,
+    pub origin: u8,
+    /// Which `--feed` a feed entry came from, below `MAX_FEEDS`. 0 for other origins.
+    pub feed: u8,
+}
+
+/// `--feed` files told apart in the drop counters.
+pub const MAX_FEEDS: u32 = 8;
+
+/// `NAT_MAP` key of a host-order address and source port.
+#[inline(always)]
+pub fn nat_key(addr: u32, port: u16) -> u64 {
+    u64::from(addr) << 16 | u64::from(port)
+}
+
+/// The address and source port of a `NAT_MAP` key.
+pub fn nat_source(key: u64) -> (u32, u16) {
+    ((key >> 16) as u32, key as u16)
+}
+
+/// Host-order mask of the prefix `Config::prefix_quota` counts sources by, a /16. Key of
+/// `PREFIX_SOURCES` and `PREFIX_MAP` is the masked host-order address.
+pub const PREFIX_MASK: u32 = 0xffff_0000;
+
+impl BlockEntry {
+    /// The `feature` whose enforcement decides whether the entry drops: bans of this node, of
+    /// its policy module and of its peers, or blocks by operators and feeds.
+    #[inline(always)]
+    pub fn feature(&self) -> u32 {
+        if self.origin == Origin::AutoBan as u8
+            || self.origin == Origin::Policy as u8
+            || self.origin == Origin::Cluster as u8
+        {
+            feature::BANS
+        } else {
+            feature::BLOCKLIST
+        }
+    }
+
+    /// Slot of the entry in the `stat::ORIGIN_DROP` and `stat::ORIGIN_BYTES` ranges.
+    #[inline(always)]
+    pub fn attribution(&self) -> u32 {
+        if self.origin == Origin::Feed as u8 {
+            stat::FEED_SLOT + u32::from(self.feed)
+        } else {
+            u32::from(self.origin)
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for BlockEntry {}
+
+/// Indices into the per-CPU `STATS` array. Every drop counts towards `DROP` plus, where there
+/// is one, its reason-specific slot.
+pub mod stat {
+    pub const DROP: u32 = 0;
+    pub const PASS: u32 = 1;
+    /// QUIC long-header packets seen (passed or dropped).
+    pub const QUIC_INITIAL: u32 = 2;
+    /// QUIC long-header packets dropped for exceeding the QUIC initial budget.
+    pub const DROP_QUIC_INITIAL: u32 = 3;
+    /// Bare TCP ACKs for untracked flows dropped for exceeding `--ack-limit`.
+    pub const DROP_ACK_FLOOD: u32 = 4;
+    /// SYNs advertising an MSS below `--min-mss`, whatever the action.
+    pub const TINY_MSS: u32 = 5;
+    /// Of those, SYNs dropped with `--min-mss-action drop`.
+    pub const DROP_TINY_MSS: u32 = 6;
+    /// Dropped by the `drop` action of the source's zone.
+    pub const DROP_ZONE: u32 = 7;
+    /// ICMP errors dropped for what they quote, see `config_flags::ICMP_INNER`.
+    pub const DROP_ICMP_INNER: u32 = 8;
+    /// Packets passed only because filtering is paused, see `config_flags::PAUSED`.
+    pub const PAUSED_DROP: u32 = 9;
+    /// Dropped early by WRED, below the hard limit. See `config_flags::WRED`.
+    pub const DROP_WRED: u32 = 10;
+    /// HTTP request lines dropped for exceeding `--http-rps-limit`.
+    pub const DROP_HTTP_FLOOD: u32 = 11;
+    /// SYNs dropped because their destination port used up its `--service-rate`.
+    pub const DROP_SERVICE_RATE: u32 = 12;
+    /// Dropped by the `drop` action of their DSCP code point.
+    pub const DROP_DSCP: u32 = 13;
+    /// First of `DSCP_CLASSES` slots counting IPv4 packets by DSCP class (the top three bits
+    /// of the code point). Only counted with `config_flags::DSCP_POLICY`.
+    pub const DSCP_CLASS: u32 = 14;
+    pub const DSCP_CLASSES: u32 = 8;
+    /// First of `ORIGIN_SLOTS` slots counting packets dropped by a `BLOCKLIST` entry, by
+    /// `BlockEntry::attribution`: the `Origin` of the entry, or `FEED_SLOT` plus the feed id
+    /// for feed entries.
+    pub const ORIGIN_DROP: u32 = DSCP_CLASS + DSCP_CLASSES;
+    /// The same, in bytes.
+    pub const ORIGIN_BYTES: u32 = ORIGIN_DROP + ORIGIN_SLOTS;
+    pub const FEED_SLOT: u32 = 8;
+    pub const ORIGIN_SLOTS: u32 = FEED_SLOT + super::MAX_FEEDS;
+    /// Frames longer than their linear part, so header and payload checks saw only the
+    /// start of them. Only counted by the multi-buffer program.
+    pub const TRUNCATED: u32 = ORIGIN_BYTES + ORIGIN_SLOTS;
+    /// Dropped because all sources together used up `--global-rate`.
+    pub const DROP_GLOBAL: u32 = TRUNCATED + 1;
+    /// Dropped early from a heavy source as the global budget ran low, see `config_flags::RED`.
+    pub const DROP_PRESSURE: u32 = TRUNCATED + 2;
+    /// Packets the program returned `XDP_ABORTED` for, see `LAST_ABORT` for the last one.
+    pub const ABORTED: u32 = TRUNCATED + 3;
+    /// TLS ClientHellos charged to a per-name budget, see `Config::sni_limit`.
+    pub const CLIENT_HELLO: u32 = TRUNCATED + 4;
+    /// Of those, ClientHellos whose server name couldn't be read: split across segments,
+    /// encrypted (ECH) or malformed.
+    pub const SNI_UNKNOWN: u32 = TRUNCATED + 5;
+    /// ClientHellos dropped because their server name used up its budget.
+    pub const DROP_SNI: u32 = TRUNCATED + 6;
+    /// Drops by each `feature` while it enforces, one slot per feature.
+    pub const ENFORCED: u32 = DROP_SNI + 1;
+    /// Packets each `feature` would have dropped while observing, passed on to the checks
+    /// after it. One slot per feature.
+    pub const OBSERVED: u32 = ENFORCED + super::feature::LEN;
+    /// Packets to multicast and to broadcast addresses under a `cast_action` other than
+    /// `SOURCE`, then the drops among them. Each pair is indexed by `cast`.
+    pub const CAST: u32 = OBSERVED + super::feature::LEN;
+    pub const DROP_CAST: u32 = CAST + 2;
+    /// Packets of IPv4 sources without a `RATE_LIMIT_MAP` entry of their own because their
+    /// /16 had `Config::prefix_quota` of them, charged to the /16's shared budget instead.
+    pub const AGGREGATED: u32 = DROP_CAST + 2;
+    /// Of those, dropped for exceeding `Config::prefix_limit`.
+    pub const DROP_PREFIX: u32 = AGGREGATED + 1;
+    /// Dropped because their source port behind a `NAT_PREFIXES` address went over the
+    /// source's limit, see `nat_key`.
+    pub const DROP_NAT_PORT: u32 = DROP_PREFIX + 1;
+    /// Malformed packets by `malformed` kind, one slot per kind, whatever their
+    /// `malformed_action`.
+    pub const MALFORMED: u32 = DROP_NAT_PORT + 1;
+    /// Limiter entries idle for longer than `Config::idle_ns` that the program deleted
+    /// itself when their source came back, ahead of the sweeper.
+    pub const EXPIRED_INLINE: u32 = MALFORMED + super::malformed::LEN;
+    /// IPv4 packets passed at the end of the checks, and trusted flows, whose TCP flow is in
+    /// `CONNTRACK`. Only counted with `config_flags::CONNTRACK`.
+    pub const PASS_ESTABLISHED: u32 = EXPIRED_INLINE + 1;
+    /// The rest of those: SYNs, TCP segments of unknown flows and everything but TCP.
+    pub const PASS_NEW: u32 = EXPIRED_INLINE + 2;
+    /// Malformed packet samples written to `MALFORMED_SAMPLES`, those that found the ring
+    /// full and those skipped by their kind's `SampleRate::divisor`.
+    pub const SAMPLES: u32 = PASS_NEW + 1;
+    pub const SAMPLES_LOST: u32 = SAMPLES + 1;
+    pub const SAMPLES_THINNED: u32 = SAMPLES + 2;
+    /// SYNs that opened a `CONNTRACK` flow, and the flows among them that went on to their
+    /// first packet without SYN, see `flow_flags::CONVERTED`. Only counted with
+    /// `config_flags::CONNTRACK`.
+    pub const SYNS_TRACKED: u32 = SAMPLES_THINNED + 1;
+    pub const SYNS_CONVERTED: u32 = SAMPLES_THINNED + 2;
+    /// Copies of the settings taken again because `CONFIG_ACTIVE` moved on too far while
+    /// they were taken, see `config_unchanged`. It takes three updates during one copy.
+    pub const CONFIG_TORN: u32 = SYNS_CONVERTED + 1;
+    /// TCP segments of a `CONNTRACK` flow carrying nothing past `Flow::next_seq`: resent
+    /// data, or resent FINs and keepalives. Then those the limiter didn't charge, see
+    /// `Config::retransmit_divisor`.
+    pub const RETRANSMITS: u32 = CONFIG_TORN + 1;
+    pub const RETRANSMITS_FORGIVEN: u32 = CONFIG_TORN + 2;
+
+    pub const LEN: u32 = RETRANSMITS_FORGIVEN + 1;
+}
+
+/// Declares the `feature` indices and their names from a single list, like `code_paths!`.
+macro_rules! drop_features {
+    ($($(#[$doc:meta])* $name:ident => $label:literal,)*) => {
+        /// What drops packets, each enforcing or only observing on its own, see
+        /// `Config::observe`. One bit of it and one slot of `stat::ENFORCED` and
+        /// `stat::OBSERVED` per feature.
+        pub mod feature {
+            #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+            #[repr(u32)]
+            enum Index {
+                $($name,)*
+            }
+
+            $($(#[$doc])* pub const $name: u32 = Index::$name as u32;)*
+
+            /// Names as `--observe` and `guardctl enforce` take them, by index.
+            pub const NAMES: &[&str] = &[$($label,)*];
+            pub const LEN: u32 = NAMES.len() as u32;
+
+            pub fn from_name(name: &str) -> Option<u32> {
+                NAMES.iter().position(|n| *n == name).map(|i| i as u32)
+            }
+        }
+    };
+}
+
+drop_features! {
+    /// Manual blocks and feed entries.
+    BLOCKLIST => "blocklist",
+    /// Auto-bans and bans received from cluster peers.
+    BANS => "bans",
+    /// Per-source limits, IPv4 and IPv6, tagged and WRED drops included.
+    RATE_LIMIT => "rate-limit",
+    /// The drop action of a zone (`--local-action`, `--external-action`) or group.
+    ZONE => "zone",
+    /// Per-port SYN budgets of `--service-rate`.
+    SERVICE_RATE => "service-rate",
+    QUIC_INITIAL => "quic-initial",
+    ACK_FLOOD => "ack-flood",
+    HTTP_FLOOD => "http-flood",
+    TINY_MSS => "tiny-mss",
+    ICMP_INNER => "icmp-inner",
+    DSCP => "dscp",
+    /// The global budget and the early drops under pressure.
+    GLOBAL => "global",
+    /// Per-server-name ClientHello budgets.
+    SNI => "sni",
+    /// Policies of `--multicast` and `--broadcast`, in `cast` order.
+    MULTICAST => "multicast",
+    BROADCAST => "broadcast",
+}
+
+// One bit each in `Config::observe`
+const _: () = assert!(feature::LEN <= u16::BITS);
+
+/// Declares the `path` indices and their display names from a single list, so adding a path
+/// in one place and forgetting the other is impossible.
+macro_rules! code_paths {
+    ($($(#[$doc:meta])* $name:ident => $label:literal,)*) => {
+        /// Indices into the per-CPU `PATH_STATS` array: which way packets go through the
+        /// program. Only counted while `config_flags::PROFILE` is set.
+        pub mod path {
+            #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+            #[repr(u32)]
+            enum Index {
+                $($name,)*
+            }
+
+            $($(#[$doc])* pub const $name: u32 = Index::$name as u32;)*
+
+            /// Display names, by index.
+            pub const NAMES: &[&str] = &[$($label,)*];
+            pub const LEN: u32 = NAMES.len() as u32;
+        }
+    };
+}
+
+code_paths! {
+    /// Every packet, the other paths are shares of this.
+    PACKET => "packets",
+    /// Neither IPv4 nor IPv6, passed untouched.
+    NOT_IP => "non-ip",
+    IPV6 => "ipv6",
+    /// PPPoE session frame carrying IPv4 or IPv6, with `--pppoe`.
+    PPPOE => "pppoe",
+    ALLOW => "allowlist hit",
+    MGMT => "management hit",
+    BLOCK => "blocklist hit",
+    NO_RATE_LIMIT => "blocklist only",
+    QUIC_INITIAL => "quic initial",
+    /// Bare ACK of a flow conntrack has not seen, charged to the ACK budget.
+    ACK_UNTRACKED => "untracked ack",
+    /// SYN advertising an MSS below `--min-mss`.
+    TINY_MSS => "tiny mss syn",
+    /// Source in `--local-subnet`.
+    LOCAL => "local subnet",
+    /// IPv4 source in a `--group` network.
+    GROUP => "group member",
+    /// ICMP error whose quoted header was inspected.
+    ICMP_ERROR => "icmp error",
+    /// TCP segment to an HTTP port starting with a request method, charged to the HTTP budget.
+    HTTP_REQUEST => "http request line",
+    /// SYN to a port with a `--service-rate`, charged to that port's budget.
+    SERVICE_SYN => "service syn",
+    /// IPv4 packet whose DSCP code point is exempt from rate limiting.
+    DSCP_EXEMPT => "dscp exempt",
+    /// TCP flow found in `--trusted-flow-map`, not rate limited.
+    TRUSTED_FLOW => "trusted flow",
+    /// Entry of the per-source limiter (IPv4, IPv6, per /16 or NAT) found, same window. The
+    /// ACK, HTTP, service and QUIC budgets have paths of their own.
+    LIMIT_FAST => "limiter fast path",
+    /// Entry of the per-source limiter found, window expired.
+    LIMIT_RESET => "limiter new window",
+    /// No entry of the per-source limiter yet.
+    LIMIT_INSERT => "limiter insert",
+    /// Within its own limit with the global budget past `--red-start`, checked for an early
+    /// drop.
+    PRESSURE => "global pressure",
+    /// IPv4 source the `--blocklist-bloom` filter ruled out, `BLOCKLIST` not looked at.
+    BLOOM_MISS => "bloom negative",
+    /// TLS ClientHello charged to the budget of its server name, with the `sni` feature.
+    CLIENT_HELLO => "tls client hello",
+    /// Packet to a multicast or broadcast address with a policy of its class, sender not
+    /// tracked.
+    CAST => "multicast/broadcast",
+    /// Source port behind a `--nat-prefix` address charged to its own budget.
+    NAT_PORT => "nat source port",
+}
+
+pub const DEFAULT_RATE_LIMIT: u64 = 10;
+/// Slots for `--http-ports`.
+pub const HTTP_PORTS: usize = 4;
+pub const DEFAULT_WINDOW_NS: u64 = 1_000_000_000;
+
+/// Slots of the `CONFIG` array, a ring the settings of each generation take in turn, see
+/// [`config_slot`]. Userspace writes a whole `Config` into the slot of the next generation and
+/// then stores the generation in `CONFIG_ACTIVE`.
+///
+/// The program reads `CONFIG_ACTIVE`, copies the slot it names and reads `CONFIG_ACTIVE`
+/// again. The slot is only written again once the generation has moved on `CONFIG_SLOTS - 1`
+/// times, so as long as it moved less than that the copy is of one update, see
+/// [`config_unchanged`]. Otherwise the copy may be torn and is taken again.
+pub const CONFIG_SLOTS: u32 = 4;
+
+/// The `CONFIG` slot of the settings of `generation`.
+#[inline(always)]
+pub fn config_slot(generation: u64) -> u32 {
+    (generation % CONFIG_SLOTS as u64) as u32
+}
+
+/// Whether a copy of the slot of generation `before` is whole, `after` being `CONFIG_ACTIVE`
+/// once the copy is taken. The writer of the slot's next settings, generation
+/// `before + CONFIG_SLOTS`, only starts once `CONFIG_ACTIVE` holds the one before it.
+#[inline(always)]
+pub fn config_unchanged(before: u64, after: u64) -> bool {
+    after.wrapping_sub(before) < CONFIG_SLOTS as u64 - 1
+}
+
+/// Runtime settings, written by userspace into the inactive slot of the `CONFIG` array.
+///
+/// A zero window means userspace never wrote the slot; the program then falls back to the
+/// defaults above.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Bumped by every update, and where in the ring of `CONFIG_SLOTS` the settings are.
+    pub generation: u64,
+    /// Packets allowed per IPv4 source per window.
+    pub rate_limit: u64,
+    pub window_ns: u64,
+    /// Packets allowed per IPv6 source per window.
+    pub rate_limit6: u64,
+    pub window_ns6: u64,
+    /// QUIC long-header (connection setup) packets allowed per source per window, 0 turns
+    /// QUIC inspection off.
+    pub quic_initial_limit: u64,
+    /// Bare ACKs for flows not in `CONNTRACK` allowed per source per window, 0 disables.
+    pub ack_limit: u64,
+    /// Packets allowed per IPv4 source in the local subnet per window.
+    pub local_rate_limit: u64,
+    /// Limiter entries idle for longer are deleted when their source comes back, which then
+    /// starts over as a new one. 0 never does. Userspace deletes the rest after the same
+    /// time.
+    pub idle_ns: u64,
+    /// Segments starting an HTTP request line allowed per source per second, 0 turns HTTP
+    /// inspection off.
+    pub http_rps_limit: u64,
+    /// Packets a source may send over its limit in bursts, from a credit that refills while
+    /// it stays within the limit. 0 disables. Applies to the per-source `--rate` limits only.
+    pub burst: u64,
+    /// Packets allowed per IPv4 source per window for code points with `dscp_action::TIGHT`,
+    /// if that is below their normal limit.
+    pub dscp_tight_limit: u64,
+    /// Packets allowed per window from every source together, once each is within its own
+    /// limit. 0 disables.
+    pub global_limit: u64,
+    /// TLS ClientHellos allowed per server name per window, 0 turns SNI inspection off. Only
+    /// in programs built with the `sni` feature.
+    pub sni_limit: u64,
+    /// The same for ClientHellos whose name couldn't be read, all sharing one budget. 0 only
+    /// counts them.
+    pub sni_unknown_limit: u64,
+    /// Local subnet, host order. Only used with `config_flags::LOCAL_SUBNET`.
+    pub local_net: u32,
+    pub local_mask: u32,
+    /// UDP destination port carrying QUIC.
+    pub quic_port: u16,
+    /// `config_flags` bits.
+    pub flags: u16,
+    /// One bit per `feature`: set, the feature only counts what it would drop and lets the
+    /// packet go on to the next check. `config_flags::PAUSED` observes them all at once.
+    pub observe: u16,
+    /// SYNs advertising a smaller MSS are flagged, 0 turns the check off.
+    pub min_mss: u16,
+    /// Plaintext HTTP destination ports, 0 for unused slots.
+    pub http_ports: [u16; HTTP_PORTS],
+    /// `zone_action` for IPv4 sources inside and outside the local subnet.
+    pub local_action: u8,
+    pub external_action: u8,
+    /// WRED watermarks, in percent of the limit. Only used with `config_flags::WRED`.
+    pub wred_low: u8,
+    pub wred_high: u8,
+    /// Where early drops start and reach full strength, in percent of `global_limit`. Only
+    /// used with `config_flags::RED`.
+    pub red_start: u8,
+    pub red_full: u8,
+    /// Which half of `BLOOM` is current. Only used with `config_flags::BLOOM`.
+    pub bloom_half: u8,
+    /// TCP destination port whose ClientHellos are charged to `sni_limit`.
+    pub tls_port: u16,
+    /// Packets allowed per window to multicast and to broadcast addresses, by `cast`. Only
+    /// used with `cast_action::LIMIT`.
+    pub cast_limit: [u64; 2],
+    /// `cast_action` for multicast and broadcast destinations, by `cast`.
+    pub cast_action: [u8; 2],
+    /// Nonzero to go on counting IPv4 sources in `RATE_LIMIT_MAP` while their `BLOCKLIST`
+    /// entry drops them, so their window is still running when the entry goes.
+    pub account_blocked: u8,
+    /// Nonzero to count IPv4 sources the allowlist or a management network passes in
+    /// `RATE_LIMIT_MAP`, which they otherwise skip, so they show up among the offenders.
+    pub track_allowed: u8,
+    /// Nonzero while `PROTECTED_DSTS` has entries: IPv4 packets to them are counted per
+    /// tenant in `TENANT_STATS`.
+    pub tenants: u8,
+    /// Most `RATE_LIMIT_MAP` entries the IPv4 sources of one /16 may hold, 0 for no limit.
+    /// Sources past it share a `PREFIX_MAP` entry, see `PREFIX_MASK`.
+    pub prefix_quota: u32,
+    /// Packets allowed per window to all the sources of a /16 sharing its entry.
+    pub prefix_limit: u64,
+    /// Packets allowed per window to an address in `NAT_PREFIXES`, all its source ports
+    /// together. Each port gets the limit a source would. 0 when there are no NAT prefixes.
+    pub nat_limit: u64,
+    /// `malformed_action` for each `malformed` kind.
+    pub malformed_action: [u8; MALFORMED_KINDS],
+    /// One bit per `malformed` kind: set, packets of the kind are copied to
+    /// `MALFORMED_SAMPLES`.
+    pub malformed_sample: u8,
+    /// Percentage of a second's samples of a kind lost to a full ring past which the
+    /// program samples the kind half as often, see `SampleRate`. 0 samples every packet.
+    pub sample_loss_pct: u8,
+    /// A source is charged one in this many of the retransmissions it sends in a window,
+    /// see `stat::RETRANSMITS`. 0 charges every one. Only used with `config_flags::CONNTRACK`.
+    pub retransmit_divisor: u8,
+}
+
+pub mod config_flags {
+    /// Skip every limiter, only the blocklist applies.
+    pub const NO_RATE_LIMIT: u16 = 1 << 0;
+    /// Track TCP flows in `CONNTRACK`.
+    pub const CONNTRACK: u16 = 1 << 1;
+    /// Count code paths in `PATH_STATS`.
+    pub const PROFILE: u16 = 1 << 2;
+    /// Raise the tag score of sources sending tiny-MSS SYNs by `TINY_MSS_SCORE`.
+    pub const MSS_SCORE: u16 = 1 << 3;
+    /// Drop tiny-MSS SYNs.
+    pub const MSS_DROP: u16 = 1 << 4;
+    /// `local_net`/`local_mask` are set.
+    pub const LOCAL_SUBNET: u16 = 1 << 5;
+    /// Check the IPv4 header quoted by ICMP errors: it must be from a packet sent to the
+    /// error's source, and neither of its addresses may be blocked.
+    pub const ICMP_INNER: u16 = 1 << 6;
+    /// Pass every packet that would be dropped, and count it in `stat::PAUSED_DROP`. The
+    /// verdict counters keep counting what the program decided.
+    pub const PAUSED: u16 = 1 << 7;
+    /// `TRUSTED_FLOWS` holds an external map of verified flows to look TCP packets up in.
+    pub const TRUSTED_FLOWS: u16 = 1 << 8;
+    /// Between `wred_low` and `wred_high` percent of its limit, a source's packets are dropped
+    /// with a probability growing linearly from 0 to 1. Past `wred_high` all are.
+    pub const WRED: u16 = 1 << 9;
+    /// Look into PPPoE session frames for the IP packet they carry.
+    pub const PPPOE: u16 = 1 << 10;
+    /// `SERVICE_RATES` has entries: SYNs to those destination ports share one budget per port.
+    pub const SERVICE_RATE: u16 = 1 << 11;
+    /// Look up the DSCP code point of IPv4 packets in `DSCP_POLICY` and count their class.
+    pub const DSCP_POLICY: u16 = 1 << 12;
+    /// `GROUP_CIDRS` has entries: the policy of a source's group replaces that of its zone.
+    pub const GROUPS: u16 = 1 << 13;
+    /// Past `red_start` percent of `global_limit`, drop packets of heavy sources with a
+    /// probability growing with the pressure and with the source's use of its own limit.
+    pub const RED: u16 = 1 << 14;
+    /// Look IPv4 sources up in the current half of `BLOOM` first, and in `BLOCKLIST` only if
+    /// it may hold them.
+    pub const BLOOM: u16 = 1 << 15;
+}
+
+/// Address bits of a `BLOOM` word index.
+pub const BLOOM_WORD_BITS: u32 = 17;
+/// Words in each of the two halves of `BLOOM`, 1 MiB each. At 500,000 addresses about one
+/// in a hundred addresses that aren't listed passes the filter.
+pub const BLOOM_WORDS: u32 = 1 << BLOOM_WORD_BITS;
+
+/// Where host-order IPv4 address `addr` sits in a half of `BLOOM`: a word, and three bits in
+/// it. With all of an address's bits in one word, checking it takes a single lookup.
+#[inline(always)]
+pub fn bloom_probe(addr: u32) -> (u32, u64) {
+    // The finalizer of MurmurHash3, so neighbouring addresses end up far apart
+    let mut h = u64::from(addr);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^= h >> 33;
+    let word = (h >> (64 - BLOOM_WORD_BITS)) as u32;
+    let bits = (1 << (h & 63)) | (1 << ((h >> 6) & 63)) | (1 << ((h >> 12) & 63));
+    (word, bits)
+}
+
+/// Entries of `DSCP_POLICY`, one per code point.
+pub const DSCP_CODE_POINTS: u32 = 64;
+
+/// Treatment of a DSCP code point, the values of `DSCP_POLICY`.
+pub mod dscp_action {
+    pub const NORMAL: u8 = 0;
+    /// Skips every limiter. The blocklist and zone actions still apply.
+    pub const EXEMPT: u8 = 1;
+    /// Limited to `Config::dscp_tight_limit` per window.
+    pub const TIGHT: u8 = 2;
+    pub const DROP: u8 = 3;
+}
+
+/// Default treatment of a zone's sources, after the blocklist and management networks.
+pub mod zone_action {
+    /// Through the limiters, like any source.
+    pub const LIMIT: u8 = 0;
+    pub const PASS: u8 = 1;
+    pub const DROP: u8 = 2;
+}
+
+/// Classes of destination addresses with a policy of their own, see `cast_action`. Index
+/// into `CAST_MAP` and the `stat::CAST` and `stat::DROP_CAST` pairs, and offset from
+/// `feature::MULTICAST`.
+pub mod cast {
+    /// IPv4 224.0.0.0/4 and IPv6 ff00::/8.
+    pub const MULTICAST: u32 = 0;
+    /// 255.255.255.255, and the local subnet's broadcast address with one.
+    pub const BROADCAST: u32 = 1;
+}
+
+/// What packets to a `cast` class get once their sender is past the blocklist and management
+/// networks.
+pub mod cast_action {
+    /// Treated like unicast: the sender is tracked and limited like any source.
+    pub const SOURCE: u8 = 0;
+    pub const PASS: u8 = 1;
+    pub const DROP: u8 = 2;
+    /// The class shares one budget per window, whoever sends. Senders aren't tracked.
+    pub const LIMIT: u8 = 3;
+}
+
+/// Group ids one past the last usable one. Id 0 is no group, so `GROUP_CIDRS` never holds it.
+pub const MAX_GROUPS: u32 = 16;
+
+/// Value of `GROUP_POLICY`, by group id: what members of the group get instead of the
+/// defaults of their zone.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GroupPolicy {
+    /// Packets allowed per member source per window, 0 for the limit of its zone.
+    pub rate: u64,
+    /// `zone_action` of every member.
+    pub action: u8,
+    pub _pad: [u8; 7],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for GroupPolicy {}
+
+/// Indices into the per-CPU `GROUP_STATS` array, two slots per group id.
+pub mod group_stat {
+    /// IPv4 packets from members of the group.
+    pub const PACKETS: u32 = 0;
+    /// Of those, packets dropped, whatever the reason.
+    pub const DROPS: u32 = 1;
+    pub const PER_GROUP: u32 = 2;
+
+    pub const LEN: u32 = super::MAX_GROUPS * PER_GROUP;
+}
+
+/// Tenant ids one past the last usable one. Id 0 is no tenant, so `PROTECTED_DSTS` never
+/// holds it.
+pub const MAX_TENANTS: u32 = 64;
+
+/// Indices into the per-CPU `TENANT_STATS` array, `PER_TENANT` slots per tenant id.
+pub mod tenant_stat {
+    /// IPv4 packets to the tenant's address.
+    pub const PACKETS: u32 = 0;
+    /// Their bytes, whole frames.
+    pub const BYTES: u32 = 1;
+    /// Of those packets, the ones a feature dropped, one slot per `feature`.
+    pub const FEATURE_DROPS: u32 = 2;
+    /// Bitmap of `SOURCE_BITS` bits, one set for every source by a hash of its address, for
+    /// a linear counting estimate of the distinct sources. Userspace clears it.
+    pub const SOURCES: u32 = FEATURE_DROPS + super::feature::LEN;
+    pub const SOURCE_WORDS: u32 = 16;
+    pub const SOURCE_BITS: u32 = SOURCE_WORDS * 64;
+    /// SYNs to the tenant that opened a `CONNTRACK` flow, and the flows that converted, as
+    /// in `stat::SYNS_TRACKED`.
+    pub const SYNS: u32 = SOURCES + SOURCE_WORDS;
+    pub const CONVERTED: u32 = SYNS + 1;
+    pub const PER_TENANT: u32 = CONVERTED + 1;
+
+    pub const LEN: u32 = super::MAX_TENANTS * PER_TENANT;
+
+    /// The bit of host-order IPv4 source `addr` in `SOURCES`.
+    #[inline(always)]
+    pub fn source_bit(addr: u32) -> u32 {
+        addr.wrapping_mul(0x9e37_79b1) >> (32 - SOURCE_BITS.trailing_zeros())
+    }
+}
+
+impl Config {
+    pub const DEFAULT: Config = Config {
+        generation: 0,
+        rate_limit: DEFAULT_RATE_LIMIT,
+        window_ns: DEFAULT_WINDOW_NS,
+        rate_limit6: DEFAULT_RATE_LIMIT,
+        window_ns6: DEFAULT_WINDOW_NS,
+        quic_initial_limit: 0,
+        ack_limit: 0,
+        local_rate_limit: DEFAULT_RATE_LIMIT,
+        idle_ns: 0,
+        http_rps_limit: 0,
+        burst: 0,
+        dscp_tight_limit: 1,
+        global_limit: 0,
+        sni_limit: 0,
+        sni_unknown_limit: 0,
+        local_net: 0,
+        local_mask: 0,
+        quic_port: 443,
+        flags: 0,
+        observe: 0,
+        min_mss: 0,
+        http_ports: [80, 0, 0, 0],
+        local_action: zone_action::LIMIT,
+        external_action: zone_action::LIMIT,
+        wred_low: 80,
+        wred_high: 100,
+        red_start: 80,
+        red_full: 95,
+        bloom_half: 0,
+        tls_port: 443,
+        cast_limit: [0; 2],
+        cast_action: [cast_action::SOURCE; 2],
+        account_blocked: 0,
+        track_allowed: 0,
+        tenants: 0,
+        prefix_quota: 0,
+        prefix_limit: 0,
+        nat_limit: 0,
+        malformed_action: malformed_action::DEFAULT,
+        malformed_sample: 0,
+        sample_loss_pct: 0,
+        retransmit_divisor: 0,
+    };
+
+    #[inline(always)]
+    pub fn has(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Whether `feature` only observes. Pausing doesn't show here, it applies on top.
+    #[inline(always)]
+    pub fn observes(&self, feature: u32) -> bool {
+        self.observe & (1 << feature) != 0
+    }
+
+    /// Whether the host-order IPv4 `addr` is in the local subnet.
+    #[inline(always)]
+    pub fn is_local(&self, addr: u32) -> bool {
+        self.has(config_flags::LOCAL_SUBNET) && addr & self.local_mask == self.local_net
+    }
+
+    /// The `cast` class of the host-order IPv4 destination `addr`, `None` for unicast.
+    #[inline(always)]
+    pub fn ipv4_cast(&self, addr: u32) -> Option<u32> {
+        if addr >> 28 == 0xe {
+            Some(cast::MULTICAST)
+        } else if addr == u32::MAX
+            || self.has(config_flags::LOCAL_SUBNET) && addr == self.local_net | !self.local_mask
+        {
+            Some(cast::BROADCAST)
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_http_port(&self, port: u16) -> bool {
+        port != 0 && self.http_ports.contains(&port)
+    }
+
+    /// Rate limit of an untagged IPv4 source, by zone.
+    #[inline(always)]
+    pub fn ipv4_limit(&self, addr: u32) -> u64 {
+        if self.is_local(addr) {
+            self.local_rate_limit
+        } else {
+            self.rate_limit
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Config {}
+
+/// Limiter state in `RATE_LIMIT_MAP` and `RATE_LIMIT_MAP6`, and in the budget maps keyed the
+/// same way. `SERVICE_MAP` keeps one per destination port instead of per source.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PacketLog {
+    pub count: u64,
+    pub last_seen: u64, //Nanoseconds since boot
+    /// When the entry was created, never updated afterwards. Also nanoseconds since boot.
+    pub first_seen: u64,
+    /// Burst credit left: packets over the limit that may still pass. Only used with a burst
+    /// allowance, see `Config::burst`.
+    pub credit: u64,
+    /// Retransmitted TCP segments among the packets of the current window, charged or not.
+    pub retransmits: u64,
+}
+
+/// Windows within its limit it takes a source to earn its full burst credit back.
+pub const BURST_REFILL_WINDOWS: u64 = 10;
+
+/// Credit earned per window a source stays within its limit.
+#[inline(always)]
+pub fn burst_refill(burst: u64) -> u64 {
+    if burst < BURST_REFILL_WINDOWS {
+        1
+    } else {
+        burst / BURST_REFILL_WINDOWS
+    }
+}
+
+/// Whether the `nth` retransmission of a window, counting from 1, is charged to its source:
+/// the first of every `divisor`, see `Config::retransmit_divisor`.
+#[inline(always)]
+pub fn charges_retransmit(nth: u64, divisor: u8) -> bool {
+    divisor <= 1 || nth % u64::from(divisor) == 1
+}
+
+/// How many of a window's `retransmits` were charged.
+pub fn charged_retransmits(retransmits: u64, divisor: u8) -> u64 {
+    if divisor <= 1 {
+        retransmits
+    } else {
+        retransmits.div_ceil(u64::from(divisor))
+    }
+}
+
+impl PacketLog {
+    /// Packets sent in the window that is current at `now` and the retransmissions among
+    /// them. `count` is what the source was charged, which leaves out the retransmissions
+    /// `divisor` forgave.
+    pub fn current_packets(&self, now: u64, window_ns: u64, divisor: u8) -> (u64, u64) {
+        if now.saturating_sub(self.last_seen) > window_ns {
+            return (0, 0);
+        }
+        let forgiven = self.retransmits - charged_retransmits(self.retransmits, divisor);
+        (self.count.saturating_add(forgiven), self.retransmits)
+    }
+
+    /// Packets counted in the window that is current at `now`. The datapath starts a new
+    /// window on the next packet once `window_ns` has passed, so a stale entry counts as 0.
+    pub fn current_count(&self, now: u64, window_ns: u64) -> u64 {
+        if now.saturating_sub(self.last_seen) > window_ns {
+            0
+        } else {
+            self.count
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PacketLog {}
+
+/// Value of the `SERVICE_RATES` and `DSCP_POLICY` maps: a rule and how it has been matching.
+/// Both are per-CPU, so the kernel counts on the entry it fetched for the decision without
+/// atomics and userspace sums the CPUs.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rule {
+    /// New connections per window for a service, a `dscp_action` for a code point.
+    pub value: u64,
+    pub hits: u64,
+    /// Nanoseconds since boot of the last hit, 0 if there was none.
+    pub last_match: u64,
+}
+
+impl Rule {
+    pub const fn new(value: u64) -> Self {
+        Self {
+            value,
+            hits: 0,
+            last_match: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn hit(&mut self, now: u64) {
+        self.hits += 1;
+        self.last_match = now;
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Rule {}
+
+/// Limiter algorithms as plain state machines, for simulating traffic against them offline.
+pub mod limiter {
+    use crate::PacketLog;
+
+    pub trait Limiter {
+        /// One packet at `now` (ns). Returns true if it is dropped.
+        fn hit(&mut self, now: u64) -> bool;
+    }
+
+    /// What the datapath runs (`rate_limited` in the eBPF program): up to `limit` packets per
+    /// window, a window starting with the first packet after the previous one ended.
+    pub struct FixedWindow {
+        pub limit: u64,
+        pub window_ns: u64,
+        log: Option<PacketLog>,
+    }
+
+    impl FixedWindow {
+        pub fn new(limit: u64, window_ns: u64) -> Self {
+            Self {
+                limit,
+                window_ns,
+                log: None,
+            }
+        }
+    }
+
+    impl Limiter for FixedWindow {
+        fn hit(&mut self, now: u64) -> bool {
+            let Some(log) = &mut self.log else {
+                // A new source always gets its first packet through
+                self.log = Some(PacketLog {
+                    count: 1,
+                    last_seen: now,
+                    first_seen: now,
+                    credit: 0,
+                    retransmits: 0,
+                });
+                return false;
+            };
+            if now - log.last_seen > self.window_ns {
+                log.count = 1;
+                log.last_seen = now;
+            } else {
+                log.count += 1;
+            }
+            log.count > self.limit
+        }
+    }
+
+    /// Token bucket holding up to `limit` tokens and refilled at `limit` per window, in its
+    /// integer form (GCRA): each packet pushes a theoretical arrival time forward by one
+    /// token's worth of time, and packets arriving too far ahead of it are dropped.
+    pub struct TokenBucket {
+        interval_ns: u64,
+        tolerance_ns: u64,
+        tat: u64,
+    }
+
+    impl TokenBucket {
+        pub fn new(limit: u64, window_ns: u64) -> Self {
+            let interval_ns = window_ns / limit.max(1);
+            Self {
+                interval_ns,
+                tolerance_ns: window_ns.saturating_sub(interval_ns),
+                tat: 0,
+            }
+        }
+    }
+
+    impl Limiter for TokenBucket {
+        fn hit(&mut self, now: u64) -> bool {
+            let tat = self.tat.max(now);
+            if tat - now > self.tolerance_ns {
+                return true;
+            }
+            self.tat = tat + self.interval_ns;
+            false
+        }
+    }
+
+    /// Leaky bucket as virtual scheduling: a packet passes once `now` reaches the source's
+    /// virtual finish time, which then moves on by `ns_per_packet`. Up to `depth` packets may
+    /// pass ahead of schedule; after those, passes are spaced out at the rate instead of
+    /// coming in a burst of a whole window like with the token bucket.
+    pub struct LeakyBucket {
+        ns_per_packet: u64,
+        early_ns: u64,
+        vft: u64,
+    }
+
+    impl LeakyBucket {
+        pub fn new(ns_per_packet: u64, depth: u64) -> Self {
+            Self {
+                ns_per_packet,
+                early_ns: ns_per_packet.saturating_mul(depth),
+                vft: 0,
+            }
+        }
+    }
+
+    impl Limiter for LeakyBucket {
+        fn hit(&mut self, now: u64) -> bool {
+            let vft = self.vft.max(now);
+            if vft - now > self.early_ns {
+                return true;
+            }
+            self.vft = vft + self.ns_per_packet;
+            false
+        }
+    }
+}
+
+/// Key of `CONNTRACK`: a TCP flow as seen on ingress. Addresses and ports are host order.
+///
+/// Also the key schema of a `--trusted-flow-map`: 12 bytes, source address, destination
+/// address, source port, destination port, no padding. Its values are never read.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub src: u32,
+    pub dst: u32,
+    pub sport: u16,
+    pub dport: u16,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowKey {}
+
+/// Value of `CONNTRACK`. Every entry is opened by a SYN that passed.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Flow {
+    /// When the SYN passed, nanoseconds since boot.
+    pub opened: u64,
+    /// `flow_flags`.
+    pub flags: u32,
+    /// The sequence number after the last one the source sent, compared in serial number
+    /// arithmetic (RFC 1982) so it wraps. A segment ending at or before it is a
+    /// retransmission, see `stat::RETRANSMITS`.
+    pub next_seq: u32,
+}
+
+impl Flow {
+    /// Whether the segment taking up the `len` sequence numbers from `seq` on is a
+    /// retransmission: all of it is at or before `next_seq`. Otherwise `next_seq` moves on to
+    /// its end. A segment overtaken by a later one on the way is taken for a retransmission,
+    /// and one that repeats some data and brings new data isn't. Bare ACKs (`len` 0) never are.
+    #[inline(always)]
+    pub fn retransmits(&mut self, seq: u32, len: u32) -> bool {
+        if len == 0 {
+            return false;
+        }
+        let end = seq.wrapping_add(len);
+        if seq_after(end, self.next_seq) {
+            self.next_seq = end;
+            return false;
+        }
+        true
+    }
+}
+
+/// Whether `a` comes after `b` in serial number arithmetic (RFC 1982): less than half the
+/// sequence space ahead of it, across the wrap.
+#[inline(always)]
+pub fn seq_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Flow {}
+
+pub mod flow_flags {
+    /// A packet without SYN came after the SYN: the handshake went through, or at least the
+    /// source answered the SYN-ACK. Counted once per flow in `SYN_SOURCES`.
+    pub const CONVERTED: u32 = 1;
+}
+
+/// Value of `SYN_SOURCES`, keyed by host-order IPv4 source: the SYNs a source opened
+/// `CONNTRACK` flows with and the flows that converted, since the entry was created. Userspace
+/// takes the ratio over the difference between two reads.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Handshakes {
+    pub syns: u64,
+    pub converted: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Handshakes {}
+
+/// Tags in `TAGS` are suspicion scores from external logic. A tagged source gets
+/// `(TAG_MAX - score) / TAG_MAX` of the normal rate limit, so `TAG_MAX` or more blocks it
+/// outright while it stays tagged.
+pub const TAG_MAX: u32 = 100;
+
+/// Tag score a source gains for every tiny-MSS SYN with `config_flags::MSS_SCORE`, so a few
+/// of them throttle it and `TAG_MAX / TINY_MSS_SCORE` block it.
+pub const TINY_MSS_SCORE: u32 = 20;
+
+/// Rate limit for a source carrying tag `score`.
+#[inline(always)]
+pub fn tagged_limit(limit: u64, score: u32) -> u64 {
+    if score >= TAG_MAX {
+        return 0;
+    }
+    // In whole and partial shares, the product of a limit near u64::MAX would wrap. No u128,
+    // the verifier has no division for it.
+    let (max, left) = (u64::from(TAG_MAX), u64::from(TAG_MAX - score));
+    limit / max * left + limit % max * left / max
+}
+
+/// Sources that used at most this fraction of their own limit are never dropped early.
+pub const RED_LIGHT_SHARE: u64 = 4;
+
+/// Chance, in 1/65536, that a packet from a source that sent `count` of its `limit` this
+/// window is dropped early, with `total` of the global budget used and early drops starting
+/// at `start` and reaching full strength at `full` (both packets, `start < full`).
+///
+/// The pressure grows linearly from 0 at `start` to 1 at `full`, and is scaled by the share
+/// of its own limit the source used, so the heaviest sources pay first and sources below
+/// `1 / RED_LIGHT_SHARE` of their limit don't pay at all.
+#[inline(always)]
+pub fn pressure_drop_chance(total: u64, start: u64, full: u64, count: u64, limit: u64) -> u32 {
+    if total <= start || limit == 0 || count * RED_LIGHT_SHARE <= limit {
+        return 0;
+    }
+    // Both in 1/65536, so the product stays far from overflowing
+    let pressure = if total >= full {
+        1 << 16
+    } else {
+        ((total - start) << 16) / (full - start)
+    };
+    let share = (count.min(limit) << 16) / limit;
+    ((pressure * share) >> 16) as u32
+}
+
+/// Why the program returned `XDP_ABORTED`: the header it couldn't read, or the map it
+/// couldn't write.
+pub mod abort {
+    pub const ETH: u8 = 1;
+    pub const IPV4: u8 = 2;
+    pub const IPV6: u8 = 3;
+    pub const TCP: u8 = 4;
+    pub const UDP: u8 = 5;
+    /// A limiter map refused a new entry.
+    pub const MAP_FULL: u8 = 6;
+    /// An IPv4 total length shorter than the header or longer than the frame.
+    pub const LENGTH: u8 = 7;
+    /// An IPv4 header checksum that doesn't add up.
+    pub const CHECKSUM: u8 = 8;
+
+    pub fn name(reason: u8) -> &'static str {
+        match reason {
+            ETH => "ethernet header",
+            IPV4 => "ipv4 header",
+            IPV6 => "ipv6 header",
+            TCP => "tcp header",
+            UDP => "udp header",
+            MAP_FULL => "map insert",
+            LENGTH => "ipv4 length",
+            CHECKSUM => "ipv4 checksum",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Kinds of malformed packets, each with an action of its own in `Config::malformed_action`
+/// and a slot of `stat::MALFORMED`.
+pub mod malformed {
+    use super::abort;
+
+    /// Too short for an Ethernet header.
+    pub const TRUNCATED_L2: u32 = 0;
+    /// An IP header cut short, or an IPv4 header length below 20 bytes.
+    pub const BAD_IP_HEADER: u32 = 1;
+    /// A TCP header cut short, in a packet that isn't a later fragment. A data offset below
+    /// the fixed header or past the end of the packet counts as cut short.
+    pub const TRUNCATED_L4: u32 = 2;
+    /// An IPv4 total length shorter than the header or longer than the frame.
+    pub const BAD_LENGTH: u32 = 3;
+    /// An IPv4 header checksum that doesn't add up. Only checked while the action of the
+    /// kind isn't `malformed_action::PASS` or it is sampled.
+    pub const BAD_CHECKSUM: u32 = 4;
+    pub const LEN: u32 = 5;
+
+    pub const NAMES: [&str; LEN as usize] = [
+        "truncated-l2",
+        "bad-ip-header",
+        "truncated-l4",
+        "bad-length",
+        "bad-checksum",
+    ];
+
+    /// The kind of packet an `abort` reason stands for. A full map is no fault of the packet.
+    #[inline(always)]
+    pub fn of(reason: u8) -> Option<u32> {
+        match reason {
+            abort::ETH => Some(TRUNCATED_L2),
+            abort::IPV4 | abort::IPV6 => Some(BAD_IP_HEADER),
+            abort::TCP | abort::UDP => Some(TRUNCATED_L4),
+            abort::LENGTH => Some(BAD_LENGTH),
+            abort::CHECKSUM => Some(BAD_CHECKSUM),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<u32> {
+        NAMES.iter().position(|n| *n == name).map(|i| i as u32)
+    }
+}
+
+pub const MALFORMED_KINDS: usize = malformed::LEN as usize;
+
+/// What a `malformed` packet gets, the values of `Config::malformed_action`.
+pub mod malformed_action {
+    /// `XDP_ABORTED`, recorded in `LAST_ABORT` and counted in `stat::ABORTED`.
+    pub const ABORT: u8 = 0;
+    /// Dropped and counted in `stat::DROP`. Paused filtering passes it.
+    pub const DROP: u8 = 1;
+    /// Counted in `stat::PASS`. Bad lengths and checksums and cut-short TCP headers go on
+    /// through the checks that don't need what's missing, the rest is passed as it is.
+    pub const PASS: u8 = 2;
+
+    /// Frames without a whole Ethernet or IP header abort. A TCP header cut short passes on
+    /// to the limits, since the program reads it for every TCP packet with the tiny-MSS
+    /// check on by default, and lengths and checksums aren't looked at.
+    pub const DEFAULT: [u8; super::MALFORMED_KINDS] = [ABORT, ABORT, PASS, PASS, PASS];
+
+    pub const NAMES: [&str; 3] = ["abort", "drop", "pass"];
+}
+
+/// Bytes of the packet kept in `MalformedSample::head`.
+pub const MALFORMED_HEAD: usize = 128;
+
+/// Record of the `MALFORMED_SAMPLES` ring buffer: the start of a malformed packet of a
+/// sampled kind.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct MalformedSample {
+    /// Kernel clock, CLOCK_BOOTTIME where the kernel has `bpf_ktime_get_boot_ns`.
+    pub at: u64,
+    pub ifindex: u32,
+    /// Length of the packet.
+    pub len: u16,
+    /// A `malformed` kind.
+    pub kind: u8,
+    /// How many bytes of `head` are the packet's.
+    pub captured: u8,
+    pub head: [u8; MALFORMED_HEAD],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for MalformedSample {}
+
+/// Largest `SampleRate::divisor`.
+pub const MAX_SAMPLE_DIVISOR: u32 = 1024;
+
+/// Nanoseconds of each `SampleRate` period.
+pub const SAMPLE_PERIOD_NS: u64 = 1_000_000_000;
+
+/// Value of the per-CPU `SAMPLE_RATES` map, one entry per `malformed` kind: how often the
+/// CPU samples packets of the kind, and what came of it in the current period.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SampleRate {
+    /// One packet in this many is sampled, a power of two. 0 before the first period.
+    pub divisor: u32,
+    /// Packets of the kind in the period, sampled or not.
+    pub seen: u32,
+    /// Samples written and samples that found the ring full in the period.
+    pub sent: u32,
+    pub lost: u32,
+    /// Kernel clock at the start of the period.
+    pub start: u64,
+}
+
+impl SampleRate {
+    /// Ends the period: past `loss_pct` percent of the samples lost, the divisor doubles;
+    /// with none lost it halves again. Halving may lose a few samples before it doubles
+    /// back, so under a steady storm the divisor settles between two values.
+    #[inline(always)]
+    pub fn adapt(&mut self, loss_pct: u8) {
+        let divisor = self.divisor.max(1);
+        let tried = u64::from(self.sent) + u64::from(self.lost);
+        self.divisor = if loss_pct == 0 {
+            1
+        } else if u64::from(self.lost) * 100 > tried * u64::from(loss_pct) {
+            (divisor * 2).min(MAX_SAMPLE_DIVISOR)
+        } else if self.lost == 0 {
+            (divisor / 2).max(1)
+        } else {
+            divisor
+        };
+        self.seen = 0;
+        self.sent = 0;
+        self.lost = 0;
+    }
+
+    /// Whether the `seen`th packet of the period is sampled.
+    #[inline(always)]
+    pub fn takes(&self, seen: u32) -> bool {
+        seen & (self.divisor.max(1) - 1) == 0
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for SampleRate {}
+
+/// Bytes of the packet kept in `AbortRecord::head`.
+pub const ABORT_HEAD: usize = 64;
+
+/// Value of the single-slot per-CPU `LAST_ABORT` map: the last packet the CPU aborted on.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct AbortRecord {
+    /// Aborts on this CPU since the program was loaded.
+    pub count: u64,
+    /// Kernel clock of the last one, like `MalformedSample::at`. 0 for none yet.
+    pub at: u64,
+    pub ifindex: u32,
+    /// Where the header that didn't fit starts.
+    pub offset: u16,
+    /// Length of the packet.
+    pub len: u16,
+    /// An `abort` reason.
+    pub reason: u8,
+    /// How many bytes of `head` are the packet's.
+    pub captured: u8,
+    pub _pad: [u8; 6],
+    pub head: [u8; ABORT_HEAD],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AbortRecord {}
+
+/// Layout of the per-CPU `DROP_BUCKETS` array: the last `SECONDS` seconds of the drop and
+/// pass counters and of the drops by `feature`, so a daemon starting up next to a program
+/// that kept running can tell what happened while it was gone. One bucket of `LEN` `Tally`
+/// counters per second of the kernel clock, at the second modulo `SECONDS`.
+pub mod bucket {
+    use super::{feature, stat};
+
+    pub const SECONDS: u32 = 60;
+    pub const DROP: u32 = 0;
+    pub const PASS: u32 = 1;
+    /// First of one counter per `feature`, counting its `stat::ENFORCED` slot.
+    pub const ENFORCED: u32 = 2;
+    pub const LEN: u32 = ENFORCED + feature::LEN;
+
+    /// The counter a `stat` slot is also counted in, if it is.
+    #[inline(always)]
+    pub fn of(slot: u32) -> Option<u32> {
+        match slot {
+            stat::DROP => Some(DROP),
+            stat::PASS => Some(PASS),
+            slot if (stat::ENFORCED..stat::OBSERVED).contains(&slot) => {
+                Some(ENFORCED + slot - stat::ENFORCED)
+            }
+            _ => None,
+        }
+    }
+
+    /// Index of `counter` in the bucket of `second`.
+    #[inline(always)]
+    pub fn index(second: u64, counter: u32) -> u32 {
+        (second % u64::from(SECONDS)) as u32 * LEN + counter
+    }
+}
+
+/// Value of `DROP_BUCKETS`. A tally of an earlier second is stale and starts over at the
+/// first count in its new second, so a bucket never has to be cleared all at once.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tally {
+    /// Second of the kernel clock the count is for.
+    pub second: u64,
+    pub count: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Tally {}
+
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/xdp-api-guard.sock";
+
+/// Bumped whenever something shared with the kernel changes shape: map value structs, key
+/// formats or map sizes. Maps from another schema can't be reused.
+pub const SCHEMA_VERSION: u32 = 41;
+
+/// Generated by `build.rs`.
+pub mod build {
+    include!(concat!(env!("OUT_DIR"), "/version.rs"));
+}
+
+/// Value of `VERSION_INFO`: which build the loaded eBPF object comes from.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub schema: u32,
+    pub _pad: u32,
+    /// Unix time of the build.
+    pub build_time: u64,
+    /// Abbreviated git commit, ASCII, zero padded.
+    pub git_hash: [u8; 16],
+    /// The `BUILD_ID` the object carries, see [`build_id`].
+    pub build_id: [u8; 16],
+    pub object_sha256: [u8; 32],
+}
+
+impl VersionInfo {
+    /// This build, for an eBPF object with hash `object_sha256` and id `build_id`.
+    pub const fn current(object_sha256: [u8; 32], build_id: [u8; 16]) -> Self {
+        Self {
+            schema: SCHEMA_VERSION,
+            _pad: 0,
+            build_time: build::BUILD_TIME,
+            git_hash: build::GIT_HASH,
+            build_id,
+            object_sha256,
+        }
+    }
+}
+
+/// The id the build script of xdp-api-guard draws for each build of the program, 32 hex
+/// digits in `XDP_API_GUARD_BUILD_ID`. The object embeds it in `.rodata.build_id` and the
+/// daemon in itself. Zeros when the variable is missing or not 32 hex digits: an object built
+/// on its own.
+pub const fn build_id(hex: Option<&str>) -> [u8; 16] {
+    const fn digit(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            _ => None,
+        }
+    }
+    let mut id = [0; 16];
+    let Some(hex) = hex else {
+        return id;
+    };
+    let hex = hex.as_bytes();
+    if hex.len() != 32 {
+        return id;
+    }
+    let mut i = 0;
+    while i < 16 {
+        let (Some(high), Some(low)) = (digit(hex[2 * i]), digit(hex[2 * i + 1])) else {
+            return [0; 16];
+        };
+        id[i] = high << 4 | low;
+        i += 1;
+    }
+    id
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for VersionInfo {}
+
+/// `MapMeta::magic` of maps a daemon stamped.
+pub const MAP_MAGIC: u64 = u64::from_be_bytes(*b"XDPGUARD");
+
+/// Value of `MAP_META`: the layout the maps beside it are in, stamped by the first daemon to
+/// run on them. A daemon taking over pinned maps refuses any other layout.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MapMeta {
+    /// [`MAP_MAGIC`], zero while nothing stamped the maps.
+    pub magic: u64,
+    /// The [`SCHEMA_VERSION`] of whoever stamped them.
+    pub schema: u32,
+    /// Daemons started on the maps so far, counted on across restarts.
+    pub starts: u32,
+}
+
+impl MapMeta {
+    /// Maps in this build's layout, `starts` daemons in.
+    pub const fn current(starts: u32) -> Self {
+        Self {
+            magic: MAP_MAGIC,
+            schema: SCHEMA_VERSION,
+            starts,
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for MapMeta {}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicBool, AtomicU64, Ordering, fence};
+    use std::{sync::Arc, thread, vec::Vec};
+
+    use super::*;
+
+    // A shape change that fails here needs SCHEMA_VERSION bumped along with the numbers
+    #[test]
+    fn layout_changes_bump_the_schema() {
+        use core::mem::size_of;
+        let layout = [
+            stat::LEN as usize,
+            feature::LEN as usize,
+            path::LEN as usize,
+            malformed::LEN as usize,
+            bucket::LEN as usize,
+            group_stat::LEN as usize,
+            tenant_stat::LEN as usize,
+            size_of::<Config>(),
+            size_of::<BlockEntry>(),
+            size_of::<GroupPolicy>(),
+            size_of::<PacketLog>(),
+            size_of::<Rule>(),
+            size_of::<FlowKey>(),
+            size_of::<Flow>(),
+            size_of::<Handshakes>(),
+            size_of::<MalformedSample>(),
+            size_of::<SampleRate>(),
+            size_of::<AbortRecord>(),
+            size_of::<Tally>(),
+            size_of::<VersionInfo>(),
+            size_of::<MapMeta>(),
+        ];
+        let at_41 = [
+            114, 15, 26, 5, 17, 32, 2240, 216, 3, 16, 40, 24, 12, 16, 16, 144, 24, 96, 16, 80, 16,
+        ];
+        assert_eq!((SCHEMA_VERSION, layout), (41, at_41));
+    }
+
+    #[test]
+    fn tagged_limits_scale_without_wrapping() {
+        assert_eq!(tagged_limit(1000, 0), 1000);
+        assert_eq!(tagged_limit(1000, 25), 750);
+        assert_eq!(tagged_limit(999, 50), 499);
+        assert_eq!(tagged_limit(7, 99), 0);
+        assert_eq!(tagged_limit(1000, TAG_MAX), 0);
+        assert_eq!(tagged_limit(1000, u32::MAX), 0);
+        assert_eq!(tagged_limit(u64::MAX, 0), u64::MAX);
+        assert_eq!(tagged_limit(u64::MAX, 50), u64::MAX / 2);
+        // As the exact product has it, for every score
+        for limit in [
+            1,
+            99,
+            100,
+            101,
+            123_456_789,
+            u64::MAX / 3,
+            u64::MAX - 1,
+            u64::MAX,
+        ] {
+            for score in 0..TAG_MAX {
+                let exact = u128::from(limit) * u128::from(TAG_MAX - score) / u128::from(TAG_MAX);
+                assert_eq!(
+                    u128::from(tagged_limit(limit, score)),
+                    exact,
+                    "{limit} {score}"
+                );
+            }
+        }
+    }
+
+    // Arrival gaps, the same on every run
+    struct Lcg(u64);
+
+    impl Lcg {
+        // In [0, below)
+        fn next(&mut self, below: u64) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % below
+        }
+    }
+
+    // Virtual scheduling as the textbook has it, in floating point: a packet conforms unless
+    // it comes more than the tolerance before the theoretical arrival time
+    struct Scheduling {
+        interval: f64,
+        tolerance: f64,
+        tat: f64,
+    }
+
+    impl Scheduling {
+        fn dropped(&mut self, now: f64) -> bool {
+            if now < self.tat - self.tolerance {
+                return true;
+            }
+            self.tat = self.tat.max(now) + self.interval;
+            false
+        }
+    }
+
+    #[test]
+    fn leaky_bucket_schedules_like_the_reference() {
+        use limiter::{LeakyBucket, Limiter as _};
+        let mut rng = Lcg(1);
+        for (interval, depth) in [
+            (1_000, 0),
+            (1_000, 1),
+            (1_000, 10),
+            (333_333, 3),
+            (1, 1000),
+            (10_000_000, 100),
+            (0, 0),
+            (0, 5),
+        ] {
+            let mut bucket = LeakyBucket::new(interval, depth);
+            // Exact in an f64 for every time reached here
+            let mut reference = Scheduling {
+                interval: interval as f64,
+                tolerance: (interval * depth) as f64,
+                tat: 0.0,
+            };
+            let mut now = 0;
+            for step in 0..10_000 {
+                assert_eq!(
+                    bucket.hit(now),
+                    reference.dropped(now as f64),
+                    "{interval} {depth}: step {step} at {now}"
+                );
+                // Bursts, and gaps around the interval
+                if rng.next(4) != 0 {
+                    now += rng.next(3 * interval + 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn leaky_bucket_edges() {
+        use limiter::{LeakyBucket, Limiter as _};
+        // Without depth only packets on schedule pass
+        let mut bucket = LeakyBucket::new(1_000, 0);
+        assert!(!bucket.hit(5_000));
+        assert!(bucket.hit(5_999));
+        assert!(!bucket.hit(6_000));
+        assert!(bucket.hit(6_000));
+        // Without an interval nothing is ever early
+        let mut bucket = LeakyBucket::new(0, 0);
+        assert!((0..100).all(|_| !bucket.hit(7)));
+        // The finish time starts at 0, behind any first packet, so a new source gets its
+        // depth and one more at once whenever it starts, at 0 too
+        for start in [0, 1, 1_000_000] {
+            let mut bucket = LeakyBucket::new(1_000, 2);
+            let passed = (0..10).filter(|_| !bucket.hit(start)).count();
+            assert_eq!(passed, 3, "{start}");
+        }
+    }
+
+    #[test]
+    fn build_ids_are_read_from_hex() {
+        let id = build_id(Some("00112233445566778899aabbccddeeff"));
+        assert_eq!(id[..3], [0x00, 0x11, 0x22]);
+        assert_eq!(id[15], 0xff);
+        for bad in [
+            None,
+            Some(""),
+            Some("0011"),
+            Some("00112233445566778899AABBCCDDEEFF"),
+        ] {
+            assert_eq!(build_id(bad), [0; 16], "{bad:?}");
+        }
+        assert_eq!(build_id(Some("0g112233445566778899aabbccddeeff")), [0; 16]);
+    }
+
+    #[test]
+    fn slots_go_round_the_ring() {
+        let slots: Vec<u32> = (0..6).map(config_slot).collect();
+        assert_eq!(slots, [0, 1, 2, 3, 0, 1]);
+        // Up to CONFIG_SLOTS - 2 updates during a copy leave its slot alone
+        assert!(config_unchanged(5, 5));
+        assert!(config_unchanged(5, 7));
+        assert!(!config_unchanged(5, 8));
+        assert!(!config_unchanged(5, 100));
+        assert!(config_unchanged(u64::MAX, 0));
+    }
+
+    // Settings of 8 words in a ring like CONFIG's, each word of a slot set to the generation
+    // written into it
+    struct Ring {
+        slots: [[AtomicU64; 8]; CONFIG_SLOTS as usize],
+        active: AtomicU64,
+    }
+
+    impl Ring {
+        fn write(&self, generation: u64) {
+            for word in &self.slots[config_slot(generation) as usize] {
+                word.store(generation, Ordering::Relaxed);
+            }
+            self.active.store(generation, Ordering::SeqCst);
+            // As the syscall of the next write would be
+            fence(Ordering::SeqCst);
+        }
+
+        // `config_copy` in the program: the generation, then the copy, then the generation
+        fn copy(&self) -> Option<(u64, [u64; 8])> {
+            let before = self.active.fetch_add(0, Ordering::SeqCst);
+            let copy = self.slots[config_slot(before) as usize]
+                .each_ref()
+                .map(|word| word.load(Ordering::Acquire));
+            let after = self.active.fetch_add(0, Ordering::SeqCst);
+            config_unchanged(before, after).then_some((before, copy))
+        }
+    }
+
+    #[test]
+    fn copies_are_of_one_update_under_a_hammering_writer() {
+        let ring = Arc::new(Ring {
+            slots: core::array::from_fn(|_| core::array::from_fn(|_| AtomicU64::new(1))),
+            active: AtomicU64::new(1),
+        });
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let (ring, done) = (ring.clone(), done.clone());
+                thread::spawn(move || {
+                    let (mut whole, mut again) = (0u64, 0u64);
+                    while !done.load(Ordering::Relaxed) {
+                        match ring.copy() {
+                            Some((generation, copy)) => {
+                                assert!(copy.iter().all(|&word| word == generation), "{copy:?}");
+                                whole += 1;
+                            }
+                            None => again += 1,
+                        }
+                    }
+                    (whole, again)
+                })
+            })
+            .collect();
+        for generation in 2..200_000 {
+            ring.write(generation);
+        }
+        done.store(true, Ordering::Relaxed);
+        let whole: u64 = readers.into_iter().map(|r| r.join().unwrap().0).sum();
+        assert!(whole > 0);
+    }
+
+    #[test]
+    fn sequence_numbers_compare_across_the_wrap() {
+        assert!(seq_after(2, 1));
+        assert!(!seq_after(1, 2));
+        assert!(!seq_after(7, 7));
+        assert!(seq_after(3, u32::MAX - 3));
+        assert!(!seq_after(u32::MAX - 3, 3));
+        // Half the sequence space on is as far as "after" reaches
+        assert!(seq_after((1 << 31) - 1, 0));
+        assert!(!seq_after(1 << 31, 0));
+    }
+
+    fn flow(next_seq: u32) -> Flow {
+        Flow {
+            opened: 0,
+            flags: 0,
+            next_seq,
+        }
+    }
+
+    #[test]
+    fn segments_in_order_move_the_flow_on() {
+        let mut flow = flow(1001);
+        assert!(!flow.retransmits(1001, 100));
+        assert!(!flow.retransmits(1101, 100));
+        assert_eq!(flow.next_seq, 1201);
+        // Bare ACKs take up no sequence space
+        assert!(!flow.retransmits(1201, 0));
+        assert_eq!(flow.next_seq, 1201);
+    }
+
+    #[test]
+    fn segments_sent_before_are_retransmissions() {
+        let mut flow = flow(1001);
+        flow.retransmits(1001, 100);
+        flow.retransmits(1101, 100);
+        assert!(flow.retransmits(1001, 100));
+        assert!(flow.retransmits(1101, 100));
+        assert!(flow.retransmits(1150, 51));
+        assert_eq!(flow.next_seq, 1201);
+        // Some old data and some new isn't
+        assert!(!flow.retransmits(1150, 100));
+        assert_eq!(flow.next_seq, 1250);
+        // A segment overtaken on the way looks like one
+        flow.retransmits(1350, 100);
+        assert!(flow.retransmits(1250, 100));
+    }
+
+    #[test]
+    fn flows_carry_on_across_the_wrap() {
+        let mut flow = flow(u32::MAX - 49);
+        assert!(!flow.retransmits(u32::MAX - 49, 100));
+        assert_eq!(flow.next_seq, 50);
+        assert!(flow.retransmits(u32::MAX - 49, 100));
+        assert!(flow.retransmits(u32::MAX - 9, 10));
+        assert!(!flow.retransmits(50, 1));
+        assert_eq!(flow.next_seq, 51);
+    }
+
+    #[test]
+    fn one_retransmission_in_divisor_is_charged() {
+        let charged: Vec<u64> = (1..=9).filter(|&nth| charges_retransmit(nth, 4)).collect();
+        assert_eq!(charged, [1, 5, 9]);
+        for divisor in [0, 1] {
+            assert!((1..=9).all(|nth| charges_retransmit(nth, divisor)));
+        }
+        // What the kernel charged, counted back
+        for divisor in [0, 1, 2, 4, 7] {
+            for retransmits in 0..30 {
+                let charged = (1..=retransmits)
+                    .filter(|&nth| charges_retransmit(nth, divisor))
+                    .count();
+                assert_eq!(
+                    charged_retransmits(retransmits, divisor),
+                    charged as u64,
+                    "{retransmits} / {divisor}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bloom_probes_stay_in_one_word_and_spread_neighbours() {
+        let mut words = std::collections::BTreeSet::new();
+        // 198.51.100.0/24
+        for addr in 0xc633_6400..0xc633_6500u32 {
+            let (word, bits) = bloom_probe(addr);
+            assert!(word < BLOOM_WORDS);
+            assert!((1..=3).contains(&bits.count_ones()), "{addr:#x}");
+            assert_eq!(bloom_probe(addr), (word, bits));
+            words.insert(word);
+        }
+        assert!(words.len() > 250, "{} words", words.len());
+    }
+}
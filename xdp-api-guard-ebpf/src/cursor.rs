@@ -0,0 +1,147 @@
+// Bounds-checked reads of the packet. Header lengths and field offsets are worked out here
+// once, the program itself only moves from one header to the next.
+
+use aya_ebpf::programs::XdpContext;
+use core::mem;
+use network_types::{eth::EthHdr, ip::Ipv4Hdr, tcp::TcpHdr};
+
+// PPPoE header (version/type, code, session id, length) and the PPP protocol field
+pub const PPPOE_HDR_LEN: usize = 8;
+
+// A read that ran past the end of the linear part, at the offset it started from
+pub struct OutOfBounds {
+    pub offset: usize,
+}
+
+// Where in the packet a parser is. Copy, so keeping the start of a header around while
+// moving on past it costs nothing.
+#[derive(Clone, Copy)]
+pub struct Cursor {
+    start: usize,
+    end: usize,
+    off: usize,
+}
+
+// A TCP header and what's worked out from it
+pub struct TcpHeader {
+    pub hdr: *const TcpHdr,
+    pub flags: u8,
+    // The options, from the end of the fixed header to the data offset
+    pub options: Cursor,
+    pub options_len: usize,
+}
+
+impl Cursor {
+    #[inline(always)]
+    pub fn new(ctx: &XdpContext) -> Self {
+        Cursor {
+            start: ctx.data(),
+            end: ctx.data_end(),
+            off: 0,
+        }
+    }
+
+    // Offset from the start of the packet
+    #[inline(always)]
+    pub fn offset(&self) -> usize {
+        self.off
+    }
+
+    // A `T` at `rel` bytes past the cursor, if the packet has all of it
+    #[inline(always)]
+    pub fn peek_at<T>(&self, rel: usize) -> Result<*const T, OutOfBounds> {
+        let offset = self.off + rel;
+        //Check: Does the packet have enough bytes
+        if self.start + offset + mem::size_of::<T>() > self.end {
+            return Err(OutOfBounds { offset });
+        }
+        Ok((self.start + offset) as *const T)
+    }
+
+    #[inline(always)]
+    pub fn peek<T>(&self) -> Result<*const T, OutOfBounds> {
+        self.peek_at(0)
+    }
+
+    // `peek`, then past the `T`
+    #[inline(always)]
+    pub fn read<T>(&mut self) -> Result<*const T, OutOfBounds> {
+        let ptr = self.peek::<T>()?;
+        self.off += mem::size_of::<T>();
+        Ok(ptr)
+    }
+
+    // Moves `n` bytes on, as far as the end of the packet but not past it
+    #[inline(always)]
+    pub fn advance(&mut self, n: usize) -> Result<(), OutOfBounds> {
+        if self.start + self.off + n > self.end {
+            return Err(OutOfBounds { offset: self.off });
+        }
+        self.off += n;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn byte(&self, rel: usize) -> Option<u8> {
+        Some(unsafe { *self.peek_at::<u8>(rel).ok()? })
+    }
+
+    #[inline(always)]
+    pub fn be16(&self, rel: usize) -> Option<u16> {
+        Some(u16::from_be_bytes(unsafe { *self.peek_at::<[u8; 2]>(rel).ok()? }))
+    }
+
+    // EtherType, host order. Read as raw bytes, EtherType has no variant for PPPoE.
+    #[inline(always)]
+    pub fn parse_eth(&mut self) -> Result<u16, OutOfBounds> {
+        let eth = self.read::<EthHdr>()?;
+        Ok(u16::from_be_bytes(unsafe { *eth.cast::<u8>().add(12).cast::<[u8; 2]>() }))
+    }
+
+    // PPP protocol of a PPPoE session header, and on to the packet it carries
+    #[inline(always)]
+    pub fn parse_pppoe(&mut self) -> Option<u16> {
+        let proto = self.be16(6)?;
+        self.advance(PPPOE_HDR_LEN).ok()?;
+        Some(proto)
+    }
+
+    // On to the L4 header, options skipped. That one isn't checked here, whoever reads it
+    // is: a header of 20 bytes and nothing after is still an IPv4 packet. A header length
+    // below those 20 bytes fails like a header cut short.
+    #[inline(always)]
+    pub fn parse_ipv4(&mut self) -> Result<*const Ipv4Hdr, OutOfBounds> {
+        let ipv4 = self.peek::<Ipv4Hdr>()?;
+        let ihl = unsafe { (*ipv4).ihl() };
+        if ihl < 5 {
+            return Err(OutOfBounds { offset: self.off });
+        }
+        self.off += usize::from(ihl) * 4;
+        Ok(ipv4)
+    }
+
+    // On to the payload. A data offset below the fixed header or options running past the
+    // end fail like a header cut short, so whatever comes back can be read to the payload.
+    #[inline(always)]
+    pub fn parse_tcp(&mut self) -> Result<TcpHeader, OutOfBounds> {
+        let hdr = self.peek::<TcpHdr>()?;
+        // Data offset is the high nibble of byte 12, the flags are byte 13. Both are in the
+        // fixed header that was just checked.
+        let (Some(data_off), Some(flags)) = (self.byte(12), self.byte(13)) else {
+            return Err(OutOfBounds { offset: self.off });
+        };
+        let len = usize::from(data_off >> 4) * 4;
+        if len < TcpHdr::LEN {
+            return Err(OutOfBounds { offset: self.off });
+        }
+        let mut options = *self;
+        options.off += TcpHdr::LEN;
+        self.advance(len)?;
+        Ok(TcpHeader {
+            hdr,
+            flags,
+            options,
+            options_len: len - TcpHdr::LEN,
+        })
+    }
+}
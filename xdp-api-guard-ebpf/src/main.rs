@@ -1,92 +1,603 @@
 #![no_std]
 #![no_main]
+// Parsers and helpers only some subsystems use sit idle in builds without them
+#![cfg_attr(
+    not(all(
+        feature = "conntrack",
+        feature = "port-rules",
+        feature = "syn-checks",
+        feature = "http",
+        feature = "decap",
+        feature = "dscp",
+        feature = "bloom",
+        feature = "sni"
+    )),
+    allow(dead_code, unused_imports)
+)]
 
-use aya_ebpf::helpers::bpf_ktime_get_ns;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use aya_ebpf::helpers::{
+    bpf_get_prandom_u32, bpf_ktime_get_boot_ns, bpf_ktime_get_ns, bpf_xdp_get_buff_len,
+};
 use aya_ebpf::{
-    bindings::xdp_action,
+    bindings::{BPF_F_NO_PREALLOC, BPF_F_RDONLY_PROG, xdp_action, xdp_md},
     macros::{map, xdp},
+    maps::Array,
     maps::HashMap,
+    maps::LruHashMap,
     maps::PerCpuArray,
+    maps::PerCpuHashMap,
+    maps::ProgramArray,
+    maps::RingBuf,
+    maps::lpm_trie::{Key, LpmTrie},
     programs::XdpContext,
 };
 use aya_log_ebpf::info;
-use core::mem;
-use network_types::{
-    eth::{EthHdr, EtherType},
-    ip::Ipv4Hdr,
+use cursor::{Cursor, OutOfBounds};
+use network_types::ip::{IpProto, Ipv4Hdr};
+use slot::MapSlot;
+use xdp_api_guard_common::{
+    ABORT_HEAD, ACTION_ALLOW, AbortRecord, BLOOM_WORDS, BlockEntry, CONFIG_SLOTS, Config,
+    DSCP_CODE_POINTS, Flow, FlowKey, GroupPolicy, Handshakes, MALFORMED_HEAD, MAX_GROUPS,
+    MAX_TENANTS, MalformedSample, MapMeta, PREFIX_MASK, PacketLog, Rule, SAMPLE_PERIOD_NS,
+    SampleRate, TAG_MAX, TINY_MSS_SCORE, Tally, VersionInfo, abort, bloom_probe, bucket,
+    build_id, burst_refill, cast, cast_action, charges_retransmit, config_flags, config_slot,
+    config_unchanged, dscp_action, feature, flow_flags, group_stat, malformed, malformed_action,
+    nat_key, path, pressure_drop_chance, stat, tagged_limit, tenant_stat, zone_action,
 };
 
-// Map 1: Manual Blocklist
+mod cursor;
+mod slot;
+
+// Map 1: Blocklist (and allowlist). The action byte in each entry says which one,
+// see `Origin` in the common crate for the precedence rules.
+#[map]
+static BLOCKLIST: HashMap<u32, BlockEntry> =
+    HashMap::<u32, BlockEntry>::with_max_entries(1024, 0);
+
+// Management networks that must never be blocked or rate limited.
+// Key data is the network-order IPv4 address.
 #[map]
-static BLOCKLIST: HashMap<u32, u32> = HashMap::<u32, u32>::with_max_entries(1024, 0);
+static MGMT_CIDRS: LpmTrie<u32, u8> =
+    LpmTrie::<u32, u8>::with_max_entries(256, BPF_F_NO_PREALLOC);
 
 #[map]
 static RATE_LIMIT_MAP: HashMap<u32, PacketLog> =
     HashMap::<u32, PacketLog>::with_max_entries(1024, 0);
 
-// Key: Index (0 = DROP, 1 = PASS )
+// Where the program finds the BLOCKLIST and RATE_LIMIT_MAP it works with, see `blocklist`.
+// Userspace points them at the maps above after loading, and at larger copies on resize.
+#[map]
+static BLOCKLIST_SLOT: MapSlot<u32, BlockEntry> = MapSlot::pinned();
+
+#[map]
+static RATE_LIMIT_SLOT: MapSlot<u32, PacketLog> = MapSlot::pinned();
+
+// Per /16, how many RATE_LIMIT_MAP entries its sources hold (see `admit`). The sweeper
+// recounts it from the map.
+#[map]
+static PREFIX_SOURCES: HashMap<u32, u32> = HashMap::<u32, u32>::with_max_entries(1024, 0);
+
+// One entry per /16 past its --prefix-quota, shared by its sources without an entry
+#[map]
+static PREFIX_MAP: HashMap<u32, PacketLog> = HashMap::<u32, PacketLog>::with_max_entries(1024, 0);
+
+// Carrier-grade NAT pools from --nat-prefix. Key data as in MGMT_CIDRS.
+#[map]
+static NAT_PREFIXES: LpmTrie<u32, u8> =
+    LpmTrie::<u32, u8>::with_max_entries(64, BPF_F_NO_PREALLOC);
+
+// Per source port of an address in NAT_PREFIXES, key from `nat_key`. LRU, since the ports
+// are the clients' to pick.
+#[map]
+static NAT_MAP: LruHashMap<u64, PacketLog> = LruHashMap::with_max_entries(65536, 0);
+
+// Key: Index (see `stat` in the common crate)
+// Value: u64 (Packet count)
+#[map]
+static STATS: PerCpuArray<u64> = PerCpuArray::with_max_entries(stat::LEN, 0);
+
+// Key: `bucket::index`, value: how often a counter counted in that second (see `bucket` in
+// the common crate)
+#[map]
+static DROP_BUCKETS: PerCpuArray<Tally> =
+    PerCpuArray::with_max_entries(bucket::SECONDS * bucket::LEN, 0);
+
+// Separate budget for QUIC connection attempts
+#[map]
+static QUIC_INITIAL_MAP: HashMap<u32, PacketLog> =
+    HashMap::<u32, PacketLog>::with_max_entries(1024, 0);
+
+#[map]
+static RATE_LIMIT_MAP6: HashMap<[u8; 16], PacketLog> =
+    HashMap::<[u8; 16], PacketLog>::with_max_entries(1024, 0);
+
+// TCP flows whose SYN we passed
+#[cfg(feature = "conntrack")]
+#[map]
+static CONNTRACK: LruHashMap<FlowKey, Flow> = LruHashMap::with_max_entries(65536, 0);
+
+// SYNs each source opened flows with and how many of those converted, see `Handshakes`
+#[cfg(feature = "conntrack")]
+#[map]
+static SYN_SOURCES: LruHashMap<u32, Handshakes> = LruHashMap::with_max_entries(16384, 0);
+
+// Flows verified by a program in front of us, e.g. a TLS terminator. Pinned by name so the
+// loader can substitute the external map for it; without one it stays empty.
+#[map]
+static TRUSTED_FLOWS: HashMap<FlowKey, u8> = HashMap::<FlowKey, u8>::pinned(1, 0);
+
+// Separate budget for bare ACKs of flows not in CONNTRACK
+#[cfg(feature = "conntrack")]
+#[map]
+static ACK_MAP: HashMap<u32, PacketLog> = HashMap::<u32, PacketLog>::with_max_entries(1024, 0);
+
+// Separate budget for segments starting an HTTP request line, per second
+#[cfg(feature = "http")]
+#[map]
+static HTTP_MAP: HashMap<u32, PacketLog> = HashMap::<u32, PacketLog>::with_max_entries(1024, 0);
+
+// New connections allowed per window to each protected destination port, from --service-rate
+#[cfg(feature = "port-rules")]
+#[map]
+static SERVICE_RATES: PerCpuHashMap<u16, Rule> = PerCpuHashMap::with_max_entries(64, 0);
+
+// One budget per protected port, shared by every source
+#[cfg(feature = "port-rules")]
+#[map]
+static SERVICE_MAP: HashMap<u16, PacketLog> = HashMap::<u16, PacketLog>::with_max_entries(64, 0);
+
+// Per DSCP code point, a `dscp_action`. Written by userspace before attaching.
+#[cfg(feature = "dscp")]
+#[map]
+static DSCP_POLICY: PerCpuArray<Rule> = PerCpuArray::with_max_entries(DSCP_CODE_POINTS, 0);
+
+// Networks of the named groups, the value is the group id. Key data as in MGMT_CIDRS.
+#[map]
+static GROUP_CIDRS: LpmTrie<u32, u8> =
+    LpmTrie::<u32, u8>::with_max_entries(1024, BPF_F_NO_PREALLOC);
+
+// Per group id, what its members get instead of their zone's defaults
+#[map]
+static GROUP_POLICY: Array<GroupPolicy> = Array::with_max_entries(MAX_GROUPS, 0);
+
+// Key: Index (see `group_stat` in the common crate)
 // Value: u64 (Packet count)
 #[map]
-static STATS: PerCpuArray<u64> = PerCpuArray::with_max_entries(2, 0);
+static GROUP_STATS: PerCpuArray<u64> = PerCpuArray::with_max_entries(group_stat::LEN, 0);
+
+// Protected destinations of --tenant, host order, the value is the tenant id
+#[map]
+static PROTECTED_DSTS: HashMap<u32, u8> = HashMap::<u32, u8>::with_max_entries(MAX_TENANTS, 0);
+
+// Key: Index (see `tenant_stat` in the common crate)
+// Value: u64 (Packet count, bytes or bitmap word)
+#[map]
+static TENANT_STATS: PerCpuArray<u64> = PerCpuArray::with_max_entries(tenant_stat::LEN, 0);
+
+// Single slot per CPU: the tenant id of the packet being looked at, 0 for none
+#[map]
+static TENANT_SCRATCH: PerCpuArray<u32> = PerCpuArray::with_max_entries(1, 0);
+
+// Two halves of BLOOM_WORDS words, each a Bloom filter of every BLOCKLIST key.
+// `Config::bloom_half` says which one is current, userspace rebuilds the other and swaps.
+// Sized down to one entry by the loader without --blocklist-bloom.
+#[cfg(feature = "bloom")]
+#[map]
+static BLOOM: Array<u64> = Array::with_max_entries(2 * BLOOM_WORDS, 0);
+
+// ClientHellos per window for each server name, keyed by a hash of the name. Key 0 is the
+// bucket of hellos whose name couldn't be read. LRU, since clients pick the names.
+#[cfg(feature = "sni")]
+#[map]
+static SNI_MAP: LruHashMap<u32, PacketLog> = LruHashMap::with_max_entries(4096, 0);
+
+// One slot per `cast` class: every packet to it together against --multicast or
+// --broadcast ratelimit:N
+#[map]
+static CAST_MAP: Array<PacketLog> = Array::with_max_entries(2, 0);
+
+// Single slot: every source together against --global-rate
+#[map]
+static GLOBAL_MAP: Array<PacketLog> = Array::with_max_entries(1, 0);
+
+// Suspicion scores pushed by external logic, see `tagged_limit`
+#[map]
+static TAGS: HashMap<u32, u32> = HashMap::<u32, u32>::with_max_entries(1024, 0);
+
+// A ring of settings, the current generation in CONFIG_ACTIVE names its slot, see
+// `CONFIG_SLOTS`. Filled in by userspace before the program is attached
+#[map]
+static CONFIG: Array<Config> = Array::with_max_entries(CONFIG_SLOTS, 0);
+
+#[map]
+static CONFIG_ACTIVE: Array<u64> = Array::with_max_entries(1, 0);
+
+// Which build this object comes from, written by the loader. The program never touches it,
+// it is there so anyone holding the maps can tell.
+#[map]
+static VERSION_INFO: Array<VersionInfo> = Array::with_max_entries(1, BPF_F_RDONLY_PROG);
+
+// The layout the maps are in, stamped by the daemon. While VERSION_INFO is about this object,
+// this is about the maps, which outlive it when pinned.
+#[map]
+static MAP_META: Array<MapMeta> = Array::with_max_entries(1, BPF_F_RDONLY_PROG);
+
+// The id the build script of xdp-api-guard drew for this build, the same the daemon is built
+// with. Alone in its section, so loaded it is a map of its own the loader can read back.
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".rodata.build_id")]
+#[used]
+static BUILD_ID: [u8; 16] = build_id(option_env!("XDP_API_GUARD_BUILD_ID"));
+
+// Optional next XDP program, run on every packet we pass
+#[map]
+static NEXT_PROG: ProgramArray = ProgramArray::with_max_entries(1, 0);
 
-// Threshold:10 packets per sconds
-const LIMIT: u64 = 10;
-const WINDOW_NS: u64 = 1_000_000_000;
+// Key: Index (see `path` in the common crate)
+// Value: u64 (Packet count), only counted while profiling
+#[map]
+static PATH_STATS: PerCpuArray<u64> = PerCpuArray::with_max_entries(path::LEN, 0);
+
+// Single slot per CPU: the last packet the CPU returned XDP_ABORTED for, see `guardctl last-abort`
+#[map]
+static LAST_ABORT: PerCpuArray<AbortRecord> = PerCpuArray::with_max_entries(1, 0);
+
+// Start of malformed packets whose kind has its bit in `Config::malformed_sample`, for
+// `--malformed-pcap`. A full ring loses the samples, not the packets.
+#[map]
+static MALFORMED_SAMPLES: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+// Per CPU and `malformed` kind, how often packets are sampled, see `SampleRate`
+#[map]
+static SAMPLE_RATES: PerCpuArray<SampleRate> = PerCpuArray::with_max_entries(malformed::LEN, 0);
 
-#[repr(C)]
-#[derive(Clone, Copy)]
-pub struct PacketLog {
-    pub count: u64,
-    pub last_seen: u64, //Nanoseconds since boot
+// Nonzero where the kernel has bpf_ktime_get_boot_ns (5.8), set by the loader. Read-only
+// data is a constant to the verifier, so older kernels never see the call.
+#[unsafe(no_mangle)]
+static BOOT_CLOCK: u8 = 0;
+
+// Counts a code path while profiling is on. Off, it costs one predictable branch.
+macro_rules! profile {
+    ($cfg:expr, $path:ident) => {
+        if $cfg.has(config_flags::PROFILE)
+            && let Some(ptr) = PATH_STATS.get_ptr_mut(path::$path)
+        {
+            unsafe { *ptr += 1 }
+        }
+    };
 }
 
+// Loaded where the kernel can't do multi-buffer XDP. A driver in multi-buffer mode (jumbo MTU)
+// refuses it.
 #[xdp]
 pub fn xdp_api_guard(ctx: XdpContext) -> u32 {
-    match try_xdp_api_guard(ctx) {
+    run::<false, false>(&ctx)
+}
+
+// The same program loaded with BPF_F_XDP_HAS_FRAGS, where the kernel has it
+#[xdp(frags)]
+pub fn xdp_api_guard_frags(ctx: XdpContext) -> u32 {
+    run::<true, false>(&ctx)
+}
+
+// The same program again as an extension, in place of a function `int prog0(struct xdp_md
+// *ctx)` of another loader's dispatcher (`--attach-mode chained`). aya-ebpf has no macro for
+// it. The verdict goes back to the dispatcher, which runs whatever comes next.
+#[unsafe(no_mangle)]
+#[unsafe(link_section = "freplace")]
+pub fn xdp_api_guard_ext(ctx: *mut xdp_md) -> u32 {
+    run::<false, true>(&XdpContext::new(ctx))
+}
+
+// CHAINED: running in a dispatcher, which chains on its own
+#[inline(always)]
+fn run<const FRAGS: bool, const CHAINED: bool>(ctx: &XdpContext) -> u32 {
+    //Whatever the last packet on this CPU was sent to, this one isn't yet
+    if let Some(tenant) = TENANT_SCRATCH.get_ptr_mut(0) {
+        unsafe { *tenant = 0 }
+    }
+    let linear = (ctx.data_end() - ctx.data()) as u64;
+    let len = if FRAGS {
+        //Fragments beyond the first page are only in the total
+        let len = unsafe { bpf_xdp_get_buff_len(ctx.ctx) };
+        if len > linear {
+            //Header and payload checks only see the linear part
+            inc_stat(stat::TRUNCATED);
+        }
+        len
+    } else {
+        linear
+    };
+    match try_xdp_api_guard(ctx, len) {
+        Ok(xdp_action::XDP_PASS) => pass::<CHAINED>(ctx),
+        Ok(xdp_action::XDP_DROP) if paused() => {
+            //Paused: the verdict still counts, the packet goes through anyway
+            inc_stat(stat::PAUSED_DROP);
+            pass::<CHAINED>(ctx)
+        }
         Ok(ret) => ret,
-        Err(_) => xdp_action::XDP_ABORTED,
+        Err(abort) => malformed::<CHAINED>(ctx, abort, len),
+    }
+}
+
+// The `malformed_action` of the packet's kind. Anything that isn't the packet's fault aborts.
+#[inline(always)]
+fn malformed<const CHAINED: bool>(ctx: &XdpContext, abort: Abort, len: u64) -> u32 {
+    let Some(kind) = malformed::of(abort.reason) else {
+        record_abort(ctx, abort, len);
+        return xdp_action::XDP_ABORTED;
+    };
+    let cfg = match config() {
+        Some(cfg) if cfg.window_ns != 0 => cfg,
+        _ => &Config::DEFAULT,
+    };
+    inc_stat(stat::MALFORMED + kind);
+    if cfg.malformed_sample >> kind & 1 != 0 {
+        sample_malformed(ctx, kind, len, cfg);
+    }
+    let action = cfg.malformed_action.get(kind as usize).copied();
+    match action.unwrap_or(malformed_action::ABORT) {
+        malformed_action::DROP if cfg.has(config_flags::PAUSED) => {
+            inc_stat(stat::DROP);
+            inc_stat(stat::PAUSED_DROP);
+            pass::<CHAINED>(ctx)
+        }
+        malformed_action::DROP => {
+            inc_stat(stat::DROP);
+            xdp_action::XDP_DROP
+        }
+        malformed_action::PASS => {
+            inc_stat(stat::PASS);
+            pass::<CHAINED>(ctx)
+        }
+        _ => {
+            record_abort(ctx, abort, len);
+            xdp_action::XDP_ABORTED
+        }
     }
 }
 
-// Helper function to check bounds
-#[inline(always)] //Force inline
-fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
-    let start = ctx.data();
-    let end = ctx.data_end();
-    let len = mem::size_of::<T>();
+// Copies the start of the packet to `MALFORMED_SAMPLES`, if the ring has room and the
+// kind's divisor lets it through
+#[inline(always)]
+fn sample_malformed(ctx: &XdpContext, kind: u32, len: u64, cfg: &Config) {
+    let Some(rate) = SAMPLE_RATES.get_ptr_mut(kind) else {
+        return;
+    };
+    let rate = unsafe { &mut *rate };
+    let now = unsafe { bpf_ktime_get_ns() };
+    if now.wrapping_sub(rate.start) >= SAMPLE_PERIOD_NS {
+        rate.adapt(cfg.sample_loss_pct);
+        rate.start = now;
+    }
+    let seen = rate.seen;
+    rate.seen = seen.wrapping_add(1);
+    if !rate.takes(seen) {
+        inc_stat(stat::SAMPLES_THINNED);
+        return;
+    }
+    let Some(mut entry) = MALFORMED_SAMPLES.reserve::<MalformedSample>(0) else {
+        rate.lost += 1;
+        inc_stat(stat::SAMPLES_LOST);
+        return;
+    };
+    rate.sent += 1;
+    inc_stat(stat::SAMPLES);
+    let sample = unsafe { &mut *entry.as_mut_ptr() };
+    sample.at = stamp_ns();
+    sample.ifindex = unsafe { (*ctx.ctx).ingress_ifindex };
+    sample.len = len.min(u64::from(u16::MAX)) as u16;
+    sample.kind = kind as u8;
+    //Same bounded loop as `record_abort`, the rest of `head` is left as the ring had it
+    let packet = Cursor::new(ctx);
+    let mut captured = 0;
+    while captured < MALFORMED_HEAD {
+        let Some(byte) = packet.byte(captured) else {
+            break;
+        };
+        sample.head[captured] = byte;
+        captured += 1;
+    }
+    sample.captured = captured as u8;
+    entry.submit(0);
+}
 
-    //Check: Does the packet have enough bytes
-    if start + offset + len > end {
-        return Err(());
+// The `malformed` kind of an IPv4 header whose length or checksum is wrong, if it is. The
+// checksum is only summed where it matters: when its action isn't pass or it's sampled.
+#[inline(always)]
+fn ipv4_malformed(l3: Cursor, ipv4: *const Ipv4Hdr, len: u64, cfg: &Config) -> Option<u32> {
+    let hdr_len = usize::from(unsafe { (*ipv4).ihl() }) * 4;
+    let tot_len = usize::from(u16::from_be(unsafe { (*ipv4).tot_len }));
+    if tot_len < hdr_len || (l3.offset() + tot_len) as u64 > len {
+        return Some(malformed::BAD_LENGTH);
     }
-    // return the Raw pointer
-    Ok((start + offset) as *const T)
+    let kind = malformed::BAD_CHECKSUM;
+    if cfg.malformed_action[kind as usize] == malformed_action::PASS
+        && cfg.malformed_sample >> kind & 1 == 0
+    {
+        return None;
+    }
+    //One's complement sum over the header, checksum field included, comes to 0xffff
+    let mut sum = 0u32;
+    let mut i = 0;
+    while i < 30 {
+        if i * 2 >= hdr_len {
+            break;
+        }
+        //Options cut short are for the L4 parse to find
+        sum += u32::from(l3.be16(i * 2)?);
+        i += 1;
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    (sum != 0xffff).then_some(kind)
 }
-fn try_xdp_api_guard(ctx: XdpContext) -> Result<u32, ()> {
-    //Parse the ehternet header
-    let eth_proto = unsafe {
-        let ptr = ptr_at::<EthHdr>(&ctx, 0)?;
-        //Read the protocol id
-        (*ptr).ether_type
+
+// Why a packet gets XDP_ABORTED: an `abort` reason and the offset it happened at
+struct Abort {
+    reason: u8,
+    offset: usize,
+}
+
+// For headers the packet can't do without: missing, the packet is aborted
+trait Required<T> {
+    fn or_abort(self, reason: u8) -> Result<T, Abort>;
+}
+
+impl<T> Required<T> for Result<T, OutOfBounds> {
+    #[inline(always)]
+    fn or_abort(self, reason: u8) -> Result<T, Abort> {
+        self.map_err(|e| Abort {
+            reason,
+            offset: e.offset,
+        })
+    }
+}
+
+// Keeps the reason and the start of the packet in this CPU's `LAST_ABORT` slot
+#[inline(always)]
+fn record_abort(ctx: &XdpContext, abort: Abort, len: u64) {
+    inc_stat(stat::ABORTED);
+    let Some(record) = LAST_ABORT.get_ptr_mut(0) else {
+        return;
     };
+    let record = unsafe { &mut *record };
+    record.count += 1;
+    record.at = stamp_ns();
+    record.ifindex = unsafe { (*ctx.ctx).ingress_ifindex };
+    record.reason = abort.reason;
+    record.offset = abort.offset as u16;
+    record.len = len.min(u64::from(u16::MAX)) as u16;
+    //One byte at a time, the verifier can bound that loop
+    let packet = Cursor::new(ctx);
+    let mut captured = 0;
+    while captured < ABORT_HEAD {
+        let Some(byte) = packet.byte(captured) else {
+            break;
+        };
+        record.head[captured] = byte;
+        captured += 1;
+    }
+    record.captured = captured as u8;
+}
+
+#[inline(always)]
+fn pass<const CHAINED: bool>(ctx: &XdpContext) -> u32 {
+    // Only returns if the slot is empty, then it's a plain pass
+    if !CHAINED {
+        let _ = unsafe { NEXT_PROG.tail_call(ctx, 0) };
+    }
+    xdp_action::XDP_PASS
+}
+
+// Time of an event people look at later: CLOCK_BOOTTIME where there is one, so it still
+// converts to the right wall clock time after a suspend
+#[inline(always)]
+fn stamp_ns() -> u64 {
+    if unsafe { core::ptr::read_volatile(&BOOT_CLOCK) } != 0 {
+        unsafe { bpf_ktime_get_boot_ns() }
+    } else {
+        unsafe { bpf_ktime_get_ns() }
+    }
+}
+
+#[inline(always)]
+fn paused() -> bool {
+    config().is_some_and(|cfg| cfg.has(config_flags::PAUSED))
+}
+
+// The generation in CONFIG_ACTIVE. Read with an atomic add of nothing, a full barrier, so
+// a slot copied after one read is copied after it, and before the next.
+#[inline(always)]
+fn active_generation() -> Option<u64> {
+    let active = CONFIG_ACTIVE.get_ptr_mut(0)?;
+    Some(unsafe { AtomicU64::from_ptr(active) }.fetch_add(0, Ordering::SeqCst))
+}
+
+// The current settings in the map, for reading a field or two: an update can land between
+// two reads, and each field is of one update or the next. `config_copy` gives a whole one.
+#[inline(always)]
+fn config() -> Option<&'static Config> {
+    CONFIG.get(config_slot(active_generation()?))
+}
+
+// A copy of the current settings, the defaults while userspace hasn't written any. Taken
+// again, once, when CONFIG_ACTIVE moved on too far to tell the slot wasn't rewritten while we
+// copied; the defaults too if that happens twice in a row.
+#[inline(always)]
+fn config_copy() -> Config {
+    for _ in 0..2 {
+        let Some(before) = active_generation() else {
+            return Config::DEFAULT;
+        };
+        let Some(slot) = CONFIG.get_ptr(config_slot(before)) else {
+            return Config::DEFAULT;
+        };
+        // Volatile, so the copy stays between the two reads of the generation
+        let cfg = unsafe { core::ptr::read_volatile(slot) };
+        if active_generation().is_some_and(|after| config_unchanged(before, after)) {
+            return if cfg.window_ns == 0 { Config::DEFAULT } else { cfg };
+        }
+        inc_stat(stat::CONFIG_TORN);
+    }
+    Config::DEFAULT
+}
+
+// `len` is the whole frame, fragments included
+fn try_xdp_api_guard(ctx: &XdpContext, len: u64) -> Result<u32, Abort> {
+    //Parse the ehternet header
+    let mut cursor = Cursor::new(ctx);
+    let eth_proto = cursor.parse_eth().or_abort(abort::ETH)?;
+
+    let cfg = config_copy();
+    profile!(cfg, PACKET);
+
+    // The cursor is at the IP header now, or further in when PPPoE wraps it
+    let (is_ipv4, is_ipv6) = l3_proto(&mut cursor, eth_proto, &cfg);
+
+    if is_ipv6 {
+        return try_ipv6(cursor, &cfg);
+    }
 
     //Filter IPV4 packets only
-    if eth_proto != EtherType::Ipv4 {
+    if !is_ipv4 {
+        profile!(cfg, NOT_IP);
         return Ok(xdp_action::XDP_PASS);
     }
 
     // Parse IPV4 header
-    let ipv4_src = unsafe {
-        let ptr = ptr_at::<Ipv4Hdr>(&ctx, EthHdr::LEN)?;
-        u32::from_be((*ptr).src_addr)
-    };
-
-    // Helper closure to increment stats
-    let inc_stats = |index: u32|{
-        if let Some(ptr) = unsafe { STATS.get_ptr_mut(index)}{
-            unsafe { *ptr +=1}
+    let l3 = cursor;
+    let ipv4 = cursor.parse_ipv4().or_abort(abort::IPV4)?;
+    let l4 = cursor;
+    if let Some(kind) = ipv4_malformed(l3, ipv4, len, &cfg) {
+        //Bad lengths and checksums only go on through the checks while their action is pass
+        let reason = if kind == malformed::BAD_LENGTH {
+            abort::LENGTH
+        } else {
+            abort::CHECKSUM
+        };
+        if cfg.malformed_action[kind as usize] != malformed_action::PASS {
+            return Err(Abort {
+                reason,
+                offset: l3.offset(),
+            });
         }
-    };
+        inc_stat(stat::MALFORMED + kind);
+        if cfg.malformed_sample >> kind & 1 != 0 {
+            sample_malformed(ctx, kind, len, &cfg);
+        }
+    }
+    let ipv4_src = unsafe { u32::from_be((*ipv4).src_addr) };
+
+    // Packets to a protected address count toward its tenant before any verdict, and the
+    // drops after this count toward it too
+    if cfg.tenants != 0
+        && let Some(&tenant) = unsafe { PROTECTED_DSTS.get(&u32::from_be((*ipv4).dst_addr)) }
+    {
+        count_tenant(u32::from(tenant), ipv4_src, len);
+    }
 
     // Extracting the octets to reconstruct the IP
     let oct1 = (ipv4_src >> 24) & 0xFF;
@@ -95,52 +606,1341 @@ fn try_xdp_api_guard(ctx: XdpContext) -> Result<u32, ()> {
     let oct4 = ipv4_src & 0xFF;
 
     // Blocking Logic
+    // Precedence is fixed here: manual allow > management CIDR > any block entry.
+    let entry = if cfg.has(config_flags::BLOOM) && !bloom_may_hold(ipv4_src, &cfg) {
+        profile!(cfg, BLOOM_MISS);
+        None
+    } else {
+        unsafe { blocklist().get(&ipv4_src) }.copied()
+    };
+    if let Some(e) = entry {
+        if e.action == ACTION_ALLOW {
+            profile!(cfg, ALLOW);
+            inc_stat(stat::PASS);
+            //Trusted sources skip the limiter, seen there only when asked for
+            if cfg.track_allowed != 0 {
+                track_only(&ipv4_src, &cfg);
+            }
+            return Ok(xdp_action::XDP_PASS);
+        }
+    }
+    if MGMT_CIDRS.get(&Key::new(32, ipv4_src.to_be())).is_some() {
+        profile!(cfg, MGMT);
+        inc_stat(stat::PASS);
+        if cfg.track_allowed != 0 {
+            track_only(&ipv4_src, &cfg);
+        }
+        return Ok(xdp_action::XDP_PASS);
+    }
     //Check if source ip exists in the BLOCKING MAP
-    if unsafe { BLOCKLIST.get(&ipv4_src) }.is_some() {
-        // info!(&ctx, "MANUALLY BLOCKED:{}.{}.{}.{}", oct1, oct2, oct3, oct4);
-        inc_stats(0);
+    if let Some(e) = entry
+        && enforced(&cfg, e.feature())
+    {
+        // info!(ctx, "MANUALLY BLOCKED:{}.{}.{}.{}", oct1, oct2, oct3, oct4);
+        profile!(cfg, BLOCK);
+        inc_stat(stat::DROP);
+        //Charged to whoever put the entry there
+        let slot = e.attribution();
+        if slot < stat::ORIGIN_SLOTS {
+            inc_stat(stat::ORIGIN_DROP + slot);
+            add_stat(stat::ORIGIN_BYTES + slot, len);
+        }
+        //Still counted, so the window isn't fresh when the entry goes
+        if cfg.account_blocked != 0 {
+            track_only(&ipv4_src, &cfg);
+        }
         return Ok(xdp_action::XDP_DROP);
     }
 
-    // Get the current time
-    let now = unsafe { (bpf_ktime_get_ns()) };
-    // check the map
-    match unsafe { RATE_LIMIT_MAP.get_ptr_mut(&ipv4_src) } {
+    // ICMP errors carrying a quote of a packet we never sent are spoofed
+    #[cfg(feature = "decap")]
+    if cfg.has(config_flags::ICMP_INNER)
+        && unsafe { (*ipv4).proto } == IpProto::Icmp
+        && icmp_error_suspicious(l4, ipv4, &cfg)?
+        && enforced(&cfg, feature::ICMP_INNER)
+    {
+        inc_stat(stat::DROP);
+        inc_stat(stat::DROP_ICMP_INNER);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    // Multicast and broadcast with a policy of their own. Senders aren't tracked, so a
+    // chatty L2 domain doesn't fill the limiter map.
+    if let Some(class) = cfg.ipv4_cast(u32::from_be(unsafe { (*ipv4).dst_addr }))
+        && let Some(verdict) = check_cast(&cfg, class)
+    {
+        return Ok(verdict);
+    }
+
+    // Zones: the local subnet and everything else each get a default action
+    let mut zone = if cfg.is_local(ipv4_src) {
+        profile!(cfg, LOCAL);
+        cfg.local_action
+    } else {
+        cfg.external_action
+    };
+    // Groups: the longest matching network decides, and its policy replaces the zone's
+    let mut group = 0;
+    let mut group_rate = 0;
+    if cfg.has(config_flags::GROUPS)
+        && let Some(id) = GROUP_CIDRS.get(&Key::new(32, ipv4_src.to_be()))
+        && let Some(policy) = GROUP_POLICY.get(u32::from(*id))
+    {
+        profile!(cfg, GROUP);
+        group = u32::from(*id);
+        group_rate = policy.rate;
+        zone = policy.action;
+        inc_group(group, group_stat::PACKETS);
+    }
+    if zone == zone_action::PASS {
+        inc_stat(stat::PASS);
+        return Ok(xdp_action::XDP_PASS);
+    }
+    if zone == zone_action::DROP && enforced(&cfg, feature::ZONE) {
+        inc_stat(stat::DROP);
+        inc_stat(stat::DROP_ZONE);
+        inc_group(group, group_stat::DROPS);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    // Markings from upstream gear: counted by class, and some classes are trusted, limited
+    // more tightly or dropped. Anyone can set them, the policy is only as good as the edge.
+    let now = unsafe { bpf_ktime_get_ns() };
+    let dscp = match dscp_class(ipv4, now, group, &cfg) {
+        Ok(dscp) => dscp,
+        Err(verdict) => return Ok(verdict),
+    };
+
+    // Pure blocklist mode: nothing below runs, no limiter map is touched
+    if cfg.has(config_flags::NO_RATE_LIMIT) {
+        profile!(cfg, NO_RATE_LIMIT);
+        inc_stat(stat::PASS);
+        return Ok(xdp_action::XDP_PASS);
+    }
+
+    // QUIC connection attempts are charged to their own budget, and to the source's below
+    if cfg.quic_initial_limit != 0
+        && unsafe { (*ipv4).proto } == IpProto::Udp
+        && quic_limited(l3, l4, ipv4_src, now, &cfg)?
+    {
+        inc_stat(stat::DROP);
+        inc_stat(stat::DROP_QUIC_INITIAL);
+        inc_group(group, group_stat::DROPS);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    let inspect_tcp = cfg!(feature = "conntrack") && cfg.has(config_flags::CONNTRACK)
+        || cfg.has(config_flags::TRUSTED_FLOWS)
+        || cfg!(feature = "port-rules") && cfg.has(config_flags::SERVICE_RATE)
+        || cfg!(feature = "syn-checks") && cfg.min_mss != 0
+        || cfg!(feature = "http") && cfg.http_rps_limit != 0
+        || cfg!(feature = "sni") && cfg.sni_limit != 0;
+    // Later fragments carry no TCP header. One cut short goes on while its action is pass,
+    // the default, with none of the checks that read it
+    let tcp = if inspect_tcp && unsafe { (*ipv4).proto } == IpProto::Tcp && first_fragment(l3) {
+        let kind = malformed::TRUNCATED_L4;
+        match parse_tcp(l3, l4, ipv4, ipv4_src) {
+            Ok(tcp) => Some(tcp),
+            Err(abort) if cfg.malformed_action[kind as usize] != malformed_action::PASS => {
+                return Err(abort);
+            }
+            Err(_) => {
+                inc_stat(stat::MALFORMED + kind);
+                if cfg.malformed_sample >> kind & 1 != 0 {
+                    sample_malformed(ctx, kind, len, &cfg);
+                }
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Verified upstream, checking them again is wasted work. The blocklist still applied.
+    if let Some(tcp) = &tcp
+        && cfg.has(config_flags::TRUSTED_FLOWS)
+        && unsafe { TRUSTED_FLOWS.get(&tcp.flow) }.is_some()
+    {
+        profile!(cfg, TRUSTED_FLOW);
+        if cfg!(feature = "conntrack") && cfg.has(config_flags::CONNTRACK) {
+            inc_stat(stat::PASS_ESTABLISHED);
+        }
+        inc_stat(stat::PASS);
+        return Ok(xdp_action::XDP_PASS);
+    }
+
+    // A tiny MSS makes the server answer in floods of small segments
+    #[cfg(feature = "syn-checks")]
+    if let Some(tcp) = &tcp
+        && cfg.min_mss != 0
+        && tcp.flags & (TCP_SYN | TCP_ACK) == TCP_SYN
+        && let Some(mss) = tcp_mss(tcp)
+        && mss < cfg.min_mss
+    {
+        profile!(cfg, TINY_MSS);
+        inc_stat(stat::TINY_MSS);
+        if cfg.has(config_flags::MSS_DROP) && enforced(&cfg, feature::TINY_MSS) {
+            inc_stat(stat::DROP);
+            inc_stat(stat::DROP_TINY_MSS);
+            inc_group(group, group_stat::DROPS);
+            return Ok(xdp_action::XDP_DROP);
+        }
+        if cfg.has(config_flags::MSS_SCORE) {
+            add_tag_score(&ipv4_src, TINY_MSS_SCORE);
+        }
+    }
+
+    // Known flows are the ones whose SYN passed here before, see below
+    #[cfg(feature = "conntrack")]
+    let flow = tcp
+        .as_ref()
+        .filter(|tcp| cfg.has(config_flags::CONNTRACK) && tcp.flags & TCP_SYN == 0)
+        .and_then(|tcp| CONNTRACK.get_ptr_mut(&tcp.flow));
+
+    // Resent segments are what a lossy link costs, not more load on the server
+    #[cfg(feature = "conntrack")]
+    let retransmit = match (&tcp, flow) {
+        (Some(tcp), Some(flow)) => retransmitted(unsafe { &mut *flow }, tcp),
+        _ => false,
+    };
+    #[cfg(not(feature = "conntrack"))]
+    let retransmit = false;
+
+    // Bare ACKs of flows we never saw open are how ACK floods get past SYN defenses;
+    // established flows send them all the time, so only untracked ones are charged. The
+    // flows opened while CONNTRACK was on still count after it's turned off
+    #[cfg(feature = "conntrack")]
+    if let Some(tcp) = &tcp
+        && cfg.ack_limit != 0
+        && tcp.is_bare_ack()
+        && flow.is_none()
+        && unsafe { CONNTRACK.get(&tcp.flow) }.is_none()
+    {
+        profile!(cfg, ACK_UNTRACKED);
+        let limit = cfg.ack_limit;
+        if rate_limited::<false, _>(&ACK_MAP, &ipv4_src, now, limit, cfg.window_ns, 0, false, &cfg)?
+            && enforced(&cfg, feature::ACK_FLOOD)
+        {
+            inc_stat(stat::DROP);
+            inc_stat(stat::DROP_ACK_FLOOD);
+            inc_group(group, group_stat::DROPS);
+            return Ok(xdp_action::XDP_DROP);
+        }
+    }
+
+    // Plaintext HTTP floods: a segment starting with a method is taken as one request.
+    // Without reassembly, pipelined requests and methods split across segments go uncounted.
+    #[cfg(feature = "http")]
+    if let Some(tcp) = &tcp
+        && cfg.http_rps_limit != 0
+        && tcp.payload_len >= 4
+        && cfg.is_http_port(tcp.flow.dport)
+        && starts_request_line(tcp)
+    {
+        profile!(cfg, HTTP_REQUEST);
+        let limit = cfg.http_rps_limit;
+        if rate_limited::<false, _>(&HTTP_MAP, &ipv4_src, now, limit, NS_PER_SEC, 0, false, &cfg)?
+            && enforced(&cfg, feature::HTTP_FLOOD)
+        {
+            inc_stat(stat::DROP);
+            inc_stat(stat::DROP_HTTP_FLOOD);
+            inc_group(group, group_stat::DROPS);
+            return Ok(xdp_action::XDP_DROP);
+        }
+    }
+
+    // Tagged sources get a smaller budget, and no burst allowance
+    let base = if group_rate != 0 {
+        group_rate
+    } else {
+        cfg.ipv4_limit(ipv4_src)
+    };
+    let (limit, burst) = match unsafe { TAGS.get(&ipv4_src) } {
+        Some(score) => (tagged_limit(base, *score), 0),
+        None => (base, cfg.burst),
+    };
+    let limit = if dscp == dscp_action::TIGHT {
+        limit.min(cfg.dscp_tight_limit)
+    } else {
+        limit
+    };
+
+    // Behind a carrier-grade NAT one address is many users. Each source port gets the limit
+    // of a source, and the address as a whole the larger nat_limit.
+    let nat = cfg.nat_limit != 0 && NAT_PREFIXES.get(&Key::new(32, ipv4_src.to_be())).is_some();
+    if nat && let Some(port) = source_port(l3, l4, ipv4) {
+        profile!(cfg, NAT_PORT);
+        if nat_port_limited(&nat_key(ipv4_src, port), now, limit, &cfg)
+            && enforced(&cfg, feature::RATE_LIMIT)
+        {
+            inc_stat(stat::DROP);
+            inc_stat(stat::DROP_NAT_PORT);
+            inc_group(group, group_stat::DROPS);
+            return Ok(xdp_action::XDP_DROP);
+        }
+    }
+
+    // A /16 holding its quota of entries already gets one more for all its other sources,
+    // so a spoofed flood from one range can't push everyone else out of the map
+    let aggregated = !nat
+        && cfg.prefix_quota != 0
+        && unsafe { rate_limit_map().get(&ipv4_src) }.is_none()
+        && !admit(ipv4_src, &cfg);
+    let (tracking, key, limit, burst) = if aggregated {
+        inc_stat(stat::AGGREGATED);
+        (&PREFIX_MAP, ipv4_src & PREFIX_MASK, cfg.prefix_limit, 0)
+    } else if nat {
+        (rate_limit_map(), ipv4_src, cfg.nat_limit, 0)
+    } else {
+        (rate_limit_map(), ipv4_src, limit, burst)
+    };
+
+    if rate_limited::<true, _>(tracking, &key, now, limit, cfg.window_ns, burst, retransmit, &cfg)?
+        && enforced(&cfg, feature::RATE_LIMIT)
+    {
+        // info!(
+        //     ctx,
+        //     "LIMIT_EXCEEDED: {}.{}.{}.{}", oct1, oct2, oct3, oct4
+        // );
+        inc_stat(stat::DROP);
+        if aggregated {
+            inc_stat(stat::DROP_PREFIX);
+        }
+        inc_group(group, group_stat::DROPS);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    // Aggregate connection floods against one service, whichever sources they come from.
+    // Checked after the source's own limit so SYNs it drops anyway don't use up the budget.
+    #[cfg(feature = "port-rules")]
+    if let Some(tcp) = &tcp
+        && cfg.has(config_flags::SERVICE_RATE)
+        && tcp.flags & (TCP_SYN | TCP_ACK) == TCP_SYN
+        && let Some(rule) = SERVICE_RATES.get_ptr_mut(&tcp.flow.dport)
+    {
+        profile!(cfg, SERVICE_SYN);
+        let rule = unsafe { &mut *rule };
+        rule.hit(now);
+        let rate = rule.value;
+        let port = &tcp.flow.dport;
+        if rate_limited::<false, _>(&SERVICE_MAP, port, now, rate, cfg.window_ns, 0, false, &cfg)?
+            && enforced(&cfg, feature::SERVICE_RATE)
+        {
+            inc_stat(stat::DROP);
+            inc_stat(stat::DROP_SERVICE_RATE);
+            inc_group(group, group_stat::DROPS);
+            return Ok(xdp_action::XDP_DROP);
+        }
+    }
+
+    // New TLS connections charged to the name they're for, so a flood of one hostname behind
+    // a shared NAT doesn't use up the budget of the others
+    #[cfg(feature = "sni")]
+    if let Some(tcp) = &tcp
+        && cfg.sni_limit != 0
+        && tcp.flow.dport == cfg.tls_port
+        && tcp.payload_len > tls::RECORD_HDR
+    {
+        let key = match client_hello_name(tcp) {
+            Hello::NotHello => None,
+            Hello::Unknown => Some(0),
+            Hello::Name(hash) => Some(hash),
+        };
+        if let Some(key) = key {
+            profile!(cfg, CLIENT_HELLO);
+            inc_stat(stat::CLIENT_HELLO);
+            let budget = if key == 0 {
+                inc_stat(stat::SNI_UNKNOWN);
+                cfg.sni_unknown_limit
+            } else {
+                cfg.sni_limit
+            };
+            if sni_limited(&key, now, budget, cfg.window_ns) && enforced(&cfg, feature::SNI) {
+                inc_stat(stat::DROP);
+                inc_stat(stat::DROP_SNI);
+                inc_group(group, group_stat::DROPS);
+                return Ok(xdp_action::XDP_DROP);
+            }
+        }
+    }
+
+    if cfg.global_limit != 0
+        && let Some(reason) = global_pressure(tracking, &key, now, limit, &cfg)
+        && enforced(&cfg, feature::GLOBAL)
+    {
+        inc_stat(stat::DROP);
+        inc_stat(reason);
+        inc_group(group, group_stat::DROPS);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    #[cfg(feature = "conntrack")]
+    if cfg.has(config_flags::CONNTRACK) {
+        if let Some(flow) = flow {
+            convert(unsafe { &mut *flow }, ipv4_src);
+        }
+        inc_stat(if flow.is_some() { stat::PASS_ESTABLISHED } else { stat::PASS_NEW });
+    }
+
+    // Only handshakes that made it through the limiter open a flow
+    #[cfg(feature = "conntrack")]
+    if let Some(tcp) = &tcp
+        && cfg.has(config_flags::CONNTRACK)
+        && tcp.flags & (TCP_SYN | TCP_ACK) == TCP_SYN
+    {
+        let flow = Flow {
+            opened: now,
+            flags: 0,
+            // The SYN takes up one
+            next_seq: tcp.seq.wrapping_add(1),
+        };
+        if CONNTRACK.insert(&tcp.flow, &flow, 0).is_ok() {
+            count_handshake(ipv4_src, false);
+        }
+    }
+
+    inc_stat(stat::PASS); // Count PASS
+    Ok(xdp_action::XDP_PASS)
+}
+
+#[cfg(feature = "http")]
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+// First four bytes of each request method, the space included for the short ones
+#[cfg(feature = "http")]
+const HTTP_METHODS: [[u8; 4]; 9] = [
+    *b"GET ", *b"POST", *b"PUT ", *b"HEAD", *b"DELE", *b"OPTI", *b"PATC", *b"CONN", *b"TRAC",
+];
+
+#[cfg(feature = "http")]
+#[inline(always)]
+fn starts_request_line(tcp: &Tcp) -> bool {
+    let Ok(start) = tcp.payload.peek::<[u8; 4]>() else {
+        return false;
+    };
+    HTTP_METHODS.contains(unsafe { &*start })
+}
+
+// Per-name budgets of TLS ClientHellos, see `Config::sni_limit`
+#[cfg(feature = "sni")]
+mod tls {
+    // Record header: type, version, length
+    pub const RECORD_HDR: usize = 5;
+    pub const HANDSHAKE: u8 = 0x16;
+    pub const CLIENT_HELLO: u8 = 0x01;
+    pub const EXT_SERVER_NAME: u16 = 0x0000;
+    pub const EXT_ECH: u16 = 0xfe0d;
+    pub const EXT_ESNI: u16 = 0xffce;
+    // Parse limits. Hellos past any of them go to the unknown bucket rather than grow the
+    // program: a session id over 32 bytes is malformed anyway, browsers offer well under
+    // 512 bytes of cipher suites, and the server name comes within the first 32 extensions
+    // and the first 2 KiB of the record. Names are hashed up to the 255 bytes DNS allows.
+    pub const MAX_SESSION_ID: usize = 32;
+    pub const MAX_CIPHER_SUITES: usize = 512;
+    pub const MAX_EXTENSIONS: usize = 32;
+    pub const MAX_OFFSET: usize = 2048;
+    pub const MAX_NAME: usize = 255;
+}
+
+// What the first segment of a connection to the TLS port says about its server name
+#[cfg(feature = "sni")]
+enum Hello {
+    NotHello,
+    // A ClientHello whose name we can't read: split across segments, encrypted (ECH or
+    // ESNI), past the parse limits or malformed
+    Unknown,
+    // FNV-1a hash of the lowercased name, never 0
+    Name(u32),
+}
+
+// Only the segment at hand is looked at, there's no reassembly. A ClientHello is
+// recognised by its record and handshake types alone; anything after may be garbage, and
+// a read that fails or runs past a limit gives Unknown.
+#[cfg(feature = "sni")]
+#[inline(always)]
+fn client_hello_name(tcp: &Tcp) -> Hello {
+    let record = tcp.payload;
+    let (Some(kind), Some(major), Some(handshake)) = (
+        record.byte(0),
+        record.byte(1),
+        record.byte(tls::RECORD_HDR),
+    ) else {
+        return Hello::NotHello;
+    };
+    if kind != tls::HANDSHAKE || major != 3 || handshake != tls::CLIENT_HELLO {
+        return Hello::NotHello;
+    }
+    server_name(record).map_or(Hello::Unknown, Hello::Name)
+}
+
+#[cfg(feature = "sni")]
+#[inline(always)]
+fn server_name(record: Cursor) -> Option<u32> {
+    // Offsets from the start of the record. Handshake type and length, then the client
+    // version and random.
+    let mut off = tls::RECORD_HDR + 4 + 2 + 32;
+    let session_id = usize::from(record.byte(off)?);
+    if session_id > tls::MAX_SESSION_ID {
+        return None;
+    }
+    off += 1 + session_id;
+    let cipher_suites = usize::from(record.be16(off)?);
+    if cipher_suites > tls::MAX_CIPHER_SUITES {
+        return None;
+    }
+    off += 2 + cipher_suites;
+    // Compression methods
+    off += 1 + usize::from(record.byte(off)?);
+    let end = off + 2 + usize::from(record.be16(off)?);
+    off += 2;
+    for _ in 0..tls::MAX_EXTENSIONS {
+        if off + 4 > end || off > tls::MAX_OFFSET {
+            return None;
+        }
+        let ext = record.be16(off)?;
+        let len = usize::from(record.be16(off + 2)?);
+        match ext {
+            tls::EXT_SERVER_NAME => return name_hash(record, off + 4),
+            tls::EXT_ECH | tls::EXT_ESNI => return None,
+            _ => off += 4 + len,
+        }
+    }
+    None
+}
+
+// The first name of a server_name extension: list length, name type (0 for a host name),
+// name length, name
+#[cfg(feature = "sni")]
+#[inline(always)]
+fn name_hash(record: Cursor, off: usize) -> Option<u32> {
+    if record.byte(off + 2)? != 0 {
+        return None;
+    }
+    let len = usize::from(record.be16(off + 3)?);
+    if len == 0 || len > tls::MAX_NAME {
+        return None;
+    }
+    let name = off + 5;
+    let mut hash: u32 = 0x811c_9dc5;
+    for i in 0..tls::MAX_NAME {
+        if i == len {
+            break;
+        }
+        let b = record.byte(name + i)?;
+        hash = (hash ^ u32::from(b.to_ascii_lowercase())).wrapping_mul(0x0100_0193);
+    }
+    Some(hash.max(1))
+}
+
+// Fixed window with no burst, like the service budget, but on the LRU map. `limit` 0 only
+// counts.
+#[cfg(feature = "sni")]
+#[inline(always)]
+fn sni_limited(key: &u32, now: u64, limit: u64, window_ns: u64) -> bool {
+    match SNI_MAP.get_ptr_mut(key) {
         Some(entry) => {
             let log = unsafe { &mut *entry };
+            if now - log.last_seen > window_ns {
+                log.count = 1;
+                log.last_seen = now;
+            } else {
+                log.count += 1;
+            }
+            limit != 0 && log.count > limit
+        }
+        None => {
+            let log = PacketLog {
+                count: 1,
+                last_seen: now,
+                first_seen: now,
+                credit: 0,
+                retransmits: 0,
+            };
+            // LRU: a full map evicts, it doesn't fail
+            let _ = SNI_MAP.insert(key, &log, 0);
+            false
+        }
+    }
+}
+
+// Source port of a TCP or UDP packet, None for other protocols and for fragments after the
+// first, which carry no header
+#[inline(always)]
+fn source_port(l3: Cursor, l4: Cursor, ipv4: *const Ipv4Hdr) -> Option<u16> {
+    let proto = unsafe { (*ipv4).proto };
+    if proto != IpProto::Tcp && proto != IpProto::Udp {
+        return None;
+    }
+    if !first_fragment(l3) {
+        return None;
+    }
+    l4.be16(0)
+}
+
+// Whether an IPv4 packet is whole or the first fragment, the one with the L4 header. The
+// fragment offset is the low 13 bits of bytes 6 and 7.
+#[inline(always)]
+fn first_fragment(l3: Cursor) -> bool {
+    l3.be16(6).is_some_and(|frag| frag & 0x1fff == 0)
+}
+
+// One source port behind a NAT address against the limit of a source, fixed window like
+// `sni_limited`. The LRU map evicts the quietest ports rather than fail when it fills up.
+#[inline(always)]
+fn nat_port_limited(key: &u64, now: u64, limit: u64, cfg: &Config) -> bool {
+    match NAT_MAP.get_ptr_mut(key) {
+        Some(entry) => {
+            let log = unsafe { &mut *entry };
+            if now - log.last_seen > cfg.window_ns {
+                if cfg.idle_ns != 0 && now - log.last_seen > cfg.idle_ns {
+                    log.first_seen = now;
+                }
+                log.count = 1;
+                log.last_seen = now;
+            } else {
+                log.count += 1;
+            }
+            over_limit(log.count, limit, cfg)
+        }
+        None => {
+            let log = PacketLog {
+                count: 1,
+                last_seen: now,
+                first_seen: now,
+                credit: 0,
+                retransmits: 0,
+            };
+            let _ = NAT_MAP.insert(key, &log, 0);
+            false
+        }
+    }
+}
+
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+// PPPoE session frames, discovery has an EtherType of its own
+#[cfg(feature = "decap")]
+const ETH_P_PPP_SES: u16 = 0x8864;
+#[cfg(feature = "decap")]
+const PPP_IPV4: u16 = 0x0021;
+#[cfg(feature = "decap")]
+const PPP_IPV6: u16 = 0x0057;
+
+#[cfg(feature = "conntrack")]
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+
+// TCP option kinds
+const TCPOPT_EOL: u8 = 0;
+const TCPOPT_NOP: u8 = 1;
+const TCPOPT_MSS: u8 = 2;
+// Options take at most 40 bytes, so this many steps cover even a header full of NOPs
+const MAX_TCP_OPTIONS: usize = 40;
+
+struct Tcp {
+    flow: FlowKey,
+    flags: u8,
+    seq: u32,
+    payload_len: usize,
+    options: Cursor,
+    options_len: usize,
+    // At most the end of the packet, reads from it still check
+    payload: Cursor,
+}
+
+impl Tcp {
+    // ACK without SYN and without data
+    #[cfg(feature = "conntrack")]
+    #[inline(always)]
+    fn is_bare_ack(&self) -> bool {
+        self.flags & (TCP_ACK | TCP_SYN) == TCP_ACK && self.payload_len == 0
+    }
+}
+
+#[inline(always)]
+fn parse_tcp(l3: Cursor, l4: Cursor, ipv4: *const Ipv4Hdr, src: u32) -> Result<Tcp, Abort> {
+    let mut payload = l4;
+    let tcp = payload.parse_tcp().or_abort(abort::TCP)?;
+    // tot_len covers the IP header, the TCP header (options included) and the payload;
+    // anything after it is Ethernet padding, not payload
+    let tot_len = usize::from(u16::from_be(unsafe { (*ipv4).tot_len }));
+    Ok(Tcp {
+        flow: FlowKey {
+            src,
+            dst: u32::from_be(unsafe { (*ipv4).dst_addr }),
+            sport: u16::from_be(unsafe { (*tcp.hdr).source }),
+            dport: u16::from_be(unsafe { (*tcp.hdr).dest }),
+        },
+        flags: tcp.flags,
+        seq: u32::from_be(unsafe { (*tcp.hdr).seq }),
+        payload_len: tot_len.saturating_sub(payload.offset() - l3.offset()),
+        options: tcp.options,
+        options_len: tcp.options_len,
+        payload,
+    })
+}
+
+// Offset and length of the first option of `kind`. The walk stops at the end of the
+// option list, at the end of the header and at lengths that don't fit, so malformed
+// options read as absent. Anything else that looks at SYN options should go through here
+// rather than grow a second loop for the verifier to chew on.
+#[inline(always)]
+fn tcp_option(tcp: &Tcp, kind: u8) -> Option<(usize, usize)> {
+    let end = tcp.options_len;
+    let mut off = 0;
+    for _ in 0..MAX_TCP_OPTIONS {
+        if off >= end {
+            return None;
+        }
+        let found = tcp.options.byte(off)?;
+        if found == TCPOPT_EOL {
+            return None;
+        }
+        if found == TCPOPT_NOP {
+            off += 1;
+            continue;
+        }
+        let len = usize::from(tcp.options.byte(off + 1)?);
+        if len < 2 || off + len > end {
+            return None;
+        }
+        if found == kind {
+            return Some((off, len));
+        }
+        off += len;
+    }
+    None
+}
+
+// Advertised MSS, None without a (well-formed) MSS option
+#[cfg(feature = "syn-checks")]
+#[inline(always)]
+fn tcp_mss(tcp: &Tcp) -> Option<u16> {
+    let (off, len) = tcp_option(tcp, TCPOPT_MSS)?;
+    if len != 4 {
+        return None;
+    }
+    tcp.options.be16(off + 2)
+}
+
+// Scores saturate at TAG_MAX, which already blocks
+#[inline(always)]
+fn add_tag_score(src: &u32, score: u32) {
+    let current = unsafe { TAGS.get(src) }.copied().unwrap_or(0);
+    let _ = TAGS.insert(src, &current.saturating_add(score).min(TAG_MAX), 0);
+}
+
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_PARAMETERPROB: u8 = 12;
+// Type, code, checksum and 4 bytes of type-specific data come before the quote
+const ICMP_HDR_LEN: usize = 8;
+
+// True for ICMP errors whose quoted packet can't be one that was sent to the error's source,
+// or that involves a blocked address. Errors too short to quote a whole IPv4 header count as
+// suspicious, every other ICMP type is left alone.
+#[cfg(feature = "decap")]
+#[inline(always)]
+fn icmp_error_suspicious(icmp: Cursor, ipv4: *const Ipv4Hdr, cfg: &Config) -> Result<bool, Abort> {
+    let Some(kind) = icmp.byte(0) else {
+        return Ok(false);
+    };
+    if kind != ICMP_DEST_UNREACH && kind != ICMP_TIME_EXCEEDED && kind != ICMP_PARAMETERPROB {
+        return Ok(false);
+    }
+    profile!(cfg, ICMP_ERROR);
+    let Ok(inner) = icmp.peek_at::<Ipv4Hdr>(ICMP_HDR_LEN) else {
+        return Ok(true);
+    };
+    let inner_src = u32::from_be(unsafe { (*inner).src_addr });
+    let inner_dst = u32::from_be(unsafe { (*inner).dst_addr });
+    // Errors go back to the sender of the packet they are about
+    if inner_src != u32::from_be(unsafe { (*ipv4).dst_addr }) {
+        return Ok(true);
+    }
+    Ok(is_blocked(&inner_src) || is_blocked(&inner_dst))
+}
+
+// False only if `addr` is certainly not in BLOCKLIST
+#[cfg(feature = "bloom")]
+#[inline(always)]
+fn bloom_may_hold(addr: u32, cfg: &Config) -> bool {
+    let (word, bits) = bloom_probe(addr);
+    match BLOOM.get(u32::from(cfg.bloom_half) * BLOOM_WORDS + word) {
+        Some(found) => *found & bits == bits,
+        //Map sized without the filter: look it up
+        None => true,
+    }
+}
+
+// Built without the filter, every address gets looked up
+#[cfg(not(feature = "bloom"))]
+#[inline(always)]
+fn bloom_may_hold(_addr: u32, _cfg: &Config) -> bool {
+    true
+}
+
+// Whether the packet under the cursor carries IPv4 or IPv6, unwrapping a PPPoE session header
+// when asked to
+#[cfg(feature = "decap")]
+#[inline(always)]
+fn l3_proto(cursor: &mut Cursor, eth_proto: u16, cfg: &Config) -> (bool, bool) {
+    if cfg.has(config_flags::PPPOE)
+        && eth_proto == ETH_P_PPP_SES
+        && let Some(ppp_proto) = cursor.parse_pppoe()
+    {
+        profile!(cfg, PPPOE);
+        return (ppp_proto == PPP_IPV4, ppp_proto == PPP_IPV6);
+    }
+    (eth_proto == ETH_P_IP, eth_proto == ETH_P_IPV6)
+}
+
+#[cfg(not(feature = "decap"))]
+#[inline(always)]
+fn l3_proto(_cursor: &mut Cursor, eth_proto: u16, _cfg: &Config) -> (bool, bool) {
+    (eth_proto == ETH_P_IP, eth_proto == ETH_P_IPV6)
+}
+
+// The `dscp_action` of the packet's code point, or the verdict for one that drops or exempts
+#[cfg(feature = "dscp")]
+#[inline(always)]
+fn dscp_class(ipv4: *const Ipv4Hdr, now: u64, group: u32, cfg: &Config) -> Result<u8, u32> {
+    if !cfg.has(config_flags::DSCP_POLICY) {
+        return Ok(dscp_action::NORMAL);
+    }
+    let code = unsafe { (*ipv4).tos } >> 2;
+    inc_stat(stat::DSCP_CLASS + u32::from(code >> 3));
+    let mut dscp = dscp_action::NORMAL;
+    if let Some(rule) = DSCP_POLICY.get_ptr_mut(u32::from(code)) {
+        let rule = unsafe { &mut *rule };
+        rule.hit(now);
+        dscp = rule.value as u8;
+    }
+    if dscp == dscp_action::DROP && enforced(cfg, feature::DSCP) {
+        inc_stat(stat::DROP);
+        inc_stat(stat::DROP_DSCP);
+        inc_group(group, group_stat::DROPS);
+        return Err(xdp_action::XDP_DROP);
+    }
+    if dscp == dscp_action::EXEMPT {
+        profile!(cfg, DSCP_EXEMPT);
+        inc_stat(stat::PASS);
+        return Err(xdp_action::XDP_PASS);
+    }
+    Ok(dscp)
+}
+
+#[cfg(not(feature = "dscp"))]
+#[inline(always)]
+fn dscp_class(_ipv4: *const Ipv4Hdr, _now: u64, _group: u32, _cfg: &Config) -> Result<u8, u32> {
+    Ok(dscp_action::NORMAL)
+}
+
+// The blocklist the program works with: the one in BLOCKLIST_SLOT, BLOCKLIST itself until
+// userspace filled the slot in
+#[inline(always)]
+fn blocklist() -> &'static HashMap<u32, BlockEntry> {
+    BLOCKLIST_SLOT.get(&BLOCKLIST)
+}
+
+// The same for RATE_LIMIT_MAP
+#[inline(always)]
+fn rate_limit_map() -> &'static HashMap<u32, PacketLog> {
+    RATE_LIMIT_SLOT.get(&RATE_LIMIT_MAP)
+}
+
+#[inline(always)]
+fn is_blocked(addr: &u32) -> bool {
+    matches!(unsafe { blocklist().get(addr) }, Some(e) if e.action != ACTION_ALLOW)
+}
+
+// Whether a QUIC long-header packet is over the source's QUIC budget. Everything else,
+// short headers, other ports, empty payloads, only goes through the normal limiter.
+#[inline(always)]
+fn quic_limited(
+    l3: Cursor,
+    udp: Cursor,
+    src: u32,
+    now: u64,
+    cfg: &Config,
+) -> Result<bool, Abort> {
+    // Later fragments start with payload, not a UDP header
+    if !first_fragment(l3) {
+        return Ok(false);
+    }
+    // `udp` is past the IP options already. A header cut short isn't QUIC, whatever it is
+    // the limits after see to it. The payload starts after the 8 bytes of the header.
+    let (Some(dport), Some(first)) = (udp.be16(2), udp.byte(8)) else {
+        return Ok(false);
+    };
+    // The high bit of the first payload byte marks a long header
+    if dport != cfg.quic_port || first & 0x80 == 0 {
+        return Ok(false);
+    }
+
+    profile!(cfg, QUIC_INITIAL);
+    inc_stat(stat::QUIC_INITIAL);
+    let limit = cfg.quic_initial_limit;
+    // Observed, it goes on as if within the budget
+    Ok(
+        rate_limited::<false, _>(&QUIC_INITIAL_MAP, &src, now, limit, cfg.window_ns, 0, false, cfg)?
+            && enforced(cfg, feature::QUIC_INITIAL),
+    )
+}
+
+fn try_ipv6(l3: Cursor, cfg: &Config) -> Result<u32, Abort> {
+    profile!(cfg, IPV6);
+    // Destination address starts 24 bytes in, ff00::/8 is multicast
+    if cfg.cast_action[cast::MULTICAST as usize] != cast_action::SOURCE
+        && unsafe { *l3.peek_at::<u8>(24).or_abort(abort::IPV6)? } == 0xff
+        && let Some(verdict) = check_cast(cfg, cast::MULTICAST)
+    {
+        return Ok(verdict);
+    }
+    if cfg.has(config_flags::NO_RATE_LIMIT) {
+        profile!(cfg, NO_RATE_LIMIT);
+        inc_stat(stat::PASS);
+        return Ok(xdp_action::XDP_PASS);
+    }
+
+    // Source address sits 8 bytes into the fixed IPv6 header
+    let ipv6_src = unsafe { *l3.peek_at::<[u8; 16]>(8).or_abort(abort::IPV6)? };
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    let limit = cfg.rate_limit6;
+    let (window_ns, burst) = (cfg.window_ns6, cfg.burst);
+    let map = &RATE_LIMIT_MAP6;
+    if rate_limited::<true, _>(map, &ipv6_src, now, limit, window_ns, burst, false, cfg)?
+        && enforced(cfg, feature::RATE_LIMIT)
+    {
+        inc_stat(stat::DROP);
+        return Ok(xdp_action::XDP_DROP);
+    }
+    if cfg.global_limit != 0
+        && let Some(reason) = global_pressure(map, &ipv6_src, now, limit, cfg)
+        && enforced(cfg, feature::GLOBAL)
+    {
+        inc_stat(stat::DROP);
+        inc_stat(reason);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    inc_stat(stat::PASS);
+    Ok(xdp_action::XDP_PASS)
+}
+
+// Fixed window limiter shared by both address families.
+// Returns true when the source has used up its budget for the current window, and its burst
+// credit if it has any (`burst` 0 means none). A `retransmit` counts as a packet but is only
+// charged as `Config::retransmit_divisor` says. The `LIMIT_*` profile paths are the
+// per-source limiter's (`SOURCE`), the ACK, HTTP, service and QUIC budgets aren't counted.
+#[inline(always)]
+#[allow(clippy::too_many_arguments)]
+fn rate_limited<const SOURCE: bool, K>(
+    map: &HashMap<K, PacketLog>,
+    key: &K,
+    now: u64,
+    limit: u64,
+    window_ns: u64,
+    burst: u64,
+    retransmit: bool,
+    cfg: &Config,
+) -> Result<bool, Abort> {
+    // check the map
+    match map.get_ptr_mut(key) {
+        Some(entry) if !idle(unsafe { &*entry }, now, cfg) => {
+            let log = unsafe { &mut *entry };
 
-            // check if the 1 second window has passed
-            if now - log.last_seen > WINDOW_NS {
+            // check if the window has passed
+            if now - log.last_seen > window_ns {
+                if SOURCE {
+                    profile!(cfg, LIMIT_RESET);
+                }
+                // Every window since the last one started earns credit back, the last one
+                // only if the source stayed within its limit. Floods never refill.
+                if burst != 0 {
+                    let mut windows = (now - log.last_seen) / window_ns;
+                    if log.count > limit {
+                        windows -= 1;
+                    }
+                    let earned = windows.saturating_mul(burst_refill(burst));
+                    log.credit = log.credit.saturating_add(earned).min(burst);
+                }
                 // RESET the Window
                 log.count = 1;
                 log.last_seen = now;
+                log.retransmits = u64::from(retransmit);
             } else {
                 // Same Window
-                log.count += 1;
+                if SOURCE {
+                    profile!(cfg, LIMIT_FAST);
+                }
+                if retransmit {
+                    log.retransmits += 1;
+                }
+                if retransmit && !charges_retransmit(log.retransmits, cfg.retransmit_divisor) {
+                    //Still dropped below if the charged packets are over the limit
+                    inc_stat(stat::RETRANSMITS_FORGIVEN);
+                } else {
+                    log.count += 1;
+                }
             }
 
-            // Apply the limit
-            if log.count > LIMIT {
-                // info!(
-                //     &ctx,
-                //     "LIMIT_EXCEEDED: {}.{}.{}.{} (Count: {})", oct1, oct2, oct3, oct4, log.count
-                // );
-                inc_stats(0);
-                return Ok(xdp_action::XDP_DROP);
+            // Credit covers what the limit would drop. WRED still thins sources out below it.
+            if log.credit > 0 && log.count > limit {
+                log.credit -= 1;
+                return Ok(false);
             }
+
+            // Apply the limit
+            Ok(over_limit(log.count, limit, cfg))
         }
-        None => {
+        found => {
+            // Idle long enough for the sweeper to have deleted it: it goes, and the source
+            // starts over as a new one
+            if found.is_some() {
+                expire(map, key);
+            }
             // First time seeing this IP: Add to MAP
+            if SOURCE {
+                profile!(cfg, LIMIT_INSERT);
+            }
             let new_entry = PacketLog {
                 count: 1,
                 last_seen: now,
+                first_seen: now,
+                // A page load is often the first thing a client does
+                credit: burst,
+                retransmits: u64::from(retransmit),
             };
-            unsafe { RATE_LIMIT_MAP.insert(&ipv4_src, &new_entry, 0) }.map_err(|_| ())?;
+            map.insert(key, &new_entry, 0).map_err(|_| Abort {
+                reason: abort::MAP_FULL,
+                offset: 0,
+            })?;
+            Ok(false)
         }
     }
+}
 
-    inc_stats(1); // Count PASS
-    Ok(xdp_action::XDP_PASS)
+// Counts a packet of a blocklisted, allowlisted or management source in its `RATE_LIMIT_MAP`
+// entry the way `rate_limited` would, without a verdict: credit and the profile are left
+// alone. A full map only means the packet goes uncounted, its verdict is decided already.
+#[inline(always)]
+fn track_only(addr: &u32, cfg: &Config) {
+    let now = unsafe { bpf_ktime_get_ns() };
+    let tracking = rate_limit_map();
+    let entry = tracking.get_ptr_mut(addr);
+    // Its /16 slot stays taken, the new entry goes in without asking for one
+    let expired = entry.is_some_and(|entry| idle(unsafe { &*entry }, now, cfg));
+    if expired {
+        expire(tracking, addr);
+    }
+    match entry {
+        Some(entry) if !expired => {
+            let log = unsafe { &mut *entry };
+            if now - log.last_seen > cfg.window_ns {
+                log.count = 1;
+                log.last_seen = now;
+            } else {
+                log.count += 1;
+            }
+        }
+        // Past the quota of its /16 it stays uncounted, as it would in the shared entry
+        _ if expired || cfg.prefix_quota == 0 || admit(*addr, cfg) => {
+            let new_entry = PacketLog {
+                count: 1,
+                last_seen: now,
+                first_seen: now,
+                credit: cfg.burst,
+                retransmits: 0,
+            };
+            let _ = tracking.insert(addr, &new_entry, 0);
+        }
+        _ => {}
+    }
+}
+
+// Whether a limiter entry went unused for longer than `Config::idle_ns`, so the sweeper
+// would delete it on its next pass
+#[inline(always)]
+fn idle(log: &PacketLog, now: u64, cfg: &Config) -> bool {
+    cfg.idle_ns != 0 && now.saturating_sub(log.last_seen) > cfg.idle_ns
+}
+
+// Deletes an idle limiter entry its source came back to, ahead of the sweeper. The sweeper
+// or another CPU may have deleted it first, which the verdict doesn't care about.
+#[inline(always)]
+fn expire<K>(map: &HashMap<K, PacketLog>, key: &K) {
+    if map.remove(key).is_ok() {
+        inc_stat(stat::EXPIRED_INLINE);
+    }
+}
+
+// Whether a segment of a known flow is a retransmission, see `Flow::retransmits`. Moving
+// `next_seq` on whatever the verdict means resending a segment the limiter dropped counts too.
+#[cfg(feature = "conntrack")]
+#[inline(always)]
+fn retransmitted(flow: &mut Flow, tcp: &Tcp) -> bool {
+    // A FIN takes up one sequence number
+    let len = tcp.payload_len as u32 + u32::from(tcp.flags & TCP_FIN != 0);
+    let retransmit = flow.retransmits(tcp.seq, len);
+    if retransmit {
+        inc_stat(stat::RETRANSMITS);
+    }
+    retransmit
+}
+
+// The first packet without SYN of a flow opened by a SYN converts it, once
+#[cfg(feature = "conntrack")]
+#[inline(always)]
+fn convert(flow: &mut Flow, src: u32) {
+    if flow.flags & flow_flags::CONVERTED == 0 {
+        flow.flags |= flow_flags::CONVERTED;
+        count_handshake(src, true);
+    }
+}
+
+// Counts a SYN that opened a flow of `src`, or the conversion of one. CPUs counting for the
+// same source at once may lose a count, which the ratio doesn't notice.
+#[cfg(feature = "conntrack")]
+#[inline(always)]
+fn count_handshake(src: u32, converted: bool) {
+    inc_stat(if converted { stat::SYNS_CONVERTED } else { stat::SYNS_TRACKED });
+    inc_tenant(if converted { tenant_stat::CONVERTED } else { tenant_stat::SYNS });
+    match SYN_SOURCES.get_ptr_mut(&src) {
+        Some(handshakes) => {
+            let handshakes = unsafe { &mut *handshakes };
+            if converted {
+                handshakes.converted += 1;
+            } else {
+                handshakes.syns += 1;
+            }
+        }
+        //A conversion whose source was evicted since doesn't start a new entry
+        None if !converted => {
+            let first = Handshakes {
+                syns: 1,
+                converted: 0,
+            };
+            let _ = SYN_SOURCES.insert(&src, &first, 0);
+        }
+        None => {}
+    }
+}
+
+// Takes one of the `prefix_quota` slots of the /16 of `addr` for a new RATE_LIMIT_MAP entry,
+// false if none is left. CPUs admitting at once race and may let a few more in; the sweeper
+// recounts the slots from the map.
+#[inline(always)]
+fn admit(addr: u32, cfg: &Config) -> bool {
+    let prefix = addr & PREFIX_MASK;
+    match PREFIX_SOURCES.get_ptr_mut(&prefix) {
+        Some(sources) => {
+            let sources = unsafe { &mut *sources };
+            if *sources >= cfg.prefix_quota {
+                return false;
+            }
+            *sources += 1;
+            true
+        }
+        None => PREFIX_SOURCES.insert(&prefix, &1, 0).is_ok(),
+    }
+}
+
+// Charges a packet that passed its source's own limit (`limit`, with the source's entry in
+// `map`) to the global budget. Returns the reason slot if that drops it: the budget is used
+// up, or with RED, the budget is running low and the source is among the heavy ones.
+#[inline(always)]
+fn global_pressure<K>(
+    map: &HashMap<K, PacketLog>,
+    key: &K,
+    now: u64,
+    limit: u64,
+    cfg: &Config,
+) -> Option<u32> {
+    let log = unsafe { &mut *GLOBAL_MAP.get_ptr_mut(0)? };
+    // One entry for all CPUs: concurrent updates race, the count comes out a little low
+    if now - log.last_seen > cfg.window_ns {
+        log.count = 1;
+        log.last_seen = now;
+    } else {
+        log.count += 1;
+    }
+    let total = log.count;
+    if total > cfg.global_limit {
+        return Some(stat::DROP_GLOBAL);
+    }
+    if !cfg.has(config_flags::RED) {
+        return None;
+    }
+    let start = cfg.global_limit * u64::from(cfg.red_start) / 100;
+    //Below the start: no lookup, no random number
+    if total <= start {
+        return None;
+    }
+    profile!(cfg, PRESSURE);
+    let full = cfg.global_limit * u64::from(cfg.red_full) / 100;
+    let count = unsafe { map.get(key) }.map_or(0, |log| log.count);
+    let chance = pressure_drop_chance(total, start, full, count, limit);
+    if chance != 0 && unsafe { bpf_get_prandom_u32() } & 0xffff < chance {
+        return Some(stat::DROP_PRESSURE);
+    }
+    None
+}
+
+// Verdict for a packet to a `cast` class, None if the class goes the way of unicast
+#[inline(always)]
+fn check_cast(cfg: &Config, class: u32) -> Option<u32> {
+    let action = *cfg.cast_action.get(class as usize)?;
+    if action == cast_action::SOURCE {
+        return None;
+    }
+    profile!(cfg, CAST);
+    inc_stat(stat::CAST + class);
+    let over = match action {
+        cast_action::PASS => false,
+        cast_action::DROP => true,
+        _ => cast_over(class, *cfg.cast_limit.get(class as usize)?, cfg.window_ns),
+    };
+    if over && enforced(cfg, feature::MULTICAST + class) {
+        inc_stat(stat::DROP);
+        inc_stat(stat::DROP_CAST + class);
+        return Some(xdp_action::XDP_DROP);
+    }
+    inc_stat(stat::PASS);
+    Some(xdp_action::XDP_PASS)
+}
+
+// Charges a packet to the shared budget of its class. Like the global budget, concurrent
+// updates from several CPUs race and the count comes out a little low.
+#[inline(always)]
+fn cast_over(class: u32, limit: u64, window_ns: u64) -> bool {
+    let Some(log) = CAST_MAP.get_ptr_mut(class) else {
+        return false;
+    };
+    let log = unsafe { &mut *log };
+    let now = unsafe { bpf_ktime_get_ns() };
+    if now - log.last_seen > window_ns {
+        log.count = 1;
+        log.last_seen = now;
+    } else {
+        log.count += 1;
+    }
+    log.count > limit
+}
+
+// With WRED, sources between the watermarks lose a growing share of their packets instead
+// of all of them at once past the limit, so their retransmits don't all line up
+#[inline(always)]
+fn over_limit(count: u64, limit: u64, cfg: &Config) -> bool {
+    if !cfg.has(config_flags::WRED) {
+        return count > limit;
+    }
+    let low = limit * u64::from(cfg.wred_low) / 100;
+    let high = limit * u64::from(cfg.wred_high) / 100;
+    if count > high {
+        return true;
+    }
+    if count <= low {
+        return false;
+    }
+    // Drop with probability (count - low) / (high - low)
+    let roll = u64::from(unsafe { bpf_get_prandom_u32() }) % (high - low);
+    if roll < count - low {
+        inc_stat(stat::DROP_WRED);
+        return true;
+    }
+    false
+}
+
+// Whether `feature` drops the packet it just found over the line. Observing, it only counts
+// it, and the packet carries on to the checks after
+#[inline(always)]
+fn enforced(cfg: &Config, feature: u32) -> bool {
+    if cfg.observes(feature) {
+        inc_stat(stat::OBSERVED + feature);
+        return false;
+    }
+    inc_stat(stat::ENFORCED + feature);
+    inc_tenant(tenant_stat::FEATURE_DROPS + feature);
+    true
+}
+
+#[inline(always)]
+fn inc_stat(index: u32) {
+    if let Some(ptr) = STATS.get_ptr_mut(index) {
+        unsafe { *ptr += 1 }
+    }
+    if let Some(counter) = bucket::of(index) {
+        tally(counter);
+    }
+}
+
+// Counts in the bucket of the current second. The array is per CPU and a CPU runs one
+// packet at a time, so nothing else moves the tally on between checking its second and
+// starting it over.
+#[inline(always)]
+fn tally(counter: u32) {
+    let second = unsafe { bpf_ktime_get_ns() } / 1_000_000_000;
+    if let Some(ptr) = DROP_BUCKETS.get_ptr_mut(bucket::index(second, counter)) {
+        let tally = unsafe { &mut *ptr };
+        if tally.second == second {
+            tally.count += 1;
+        } else {
+            *tally = Tally { second, count: 1 };
+        }
+    }
+}
+
+#[inline(always)]
+fn add_stat(index: u32, value: u64) {
+    if let Some(ptr) = STATS.get_ptr_mut(index) {
+        unsafe { *ptr += value }
+    }
+}
+
+// Counts a `group_stat` slot of a source's group, nothing for sources in none
+#[inline(always)]
+fn inc_group(group: u32, slot: u32) {
+    if group != 0
+        && let Some(ptr) = GROUP_STATS.get_ptr_mut(group * group_stat::PER_GROUP + slot)
+    {
+        unsafe { *ptr += 1 }
+    }
+}
+
+// Counts a packet to tenant `tenant` from host-order `src`, and remembers the tenant for
+// the drops of the packet
+#[inline(always)]
+fn count_tenant(tenant: u32, src: u32, len: u64) {
+    if let Some(ptr) = TENANT_SCRATCH.get_ptr_mut(0) {
+        unsafe { *ptr = tenant }
+    }
+    let base = tenant * tenant_stat::PER_TENANT;
+    if let Some(ptr) = TENANT_STATS.get_ptr_mut(base + tenant_stat::PACKETS) {
+        unsafe { *ptr += 1 }
+    }
+    if let Some(ptr) = TENANT_STATS.get_ptr_mut(base + tenant_stat::BYTES) {
+        unsafe { *ptr += len }
+    }
+    let bit = tenant_stat::source_bit(src);
+    if let Some(ptr) = TENANT_STATS.get_ptr_mut(base + tenant_stat::SOURCES + bit / 64) {
+        unsafe { *ptr |= 1 << (bit % 64) }
+    }
+}
+
+// Counts a `tenant_stat` slot of the current packet's tenant, nothing for packets to none
+#[inline(always)]
+fn inc_tenant(slot: u32) {
+    let Some(tenant) = TENANT_SCRATCH.get(0).copied() else {
+        return;
+    };
+    if tenant != 0
+        && let Some(ptr) = TENANT_STATS.get_ptr_mut(tenant * tenant_stat::PER_TENANT + slot)
+    {
+        unsafe { *ptr += 1 }
+    }
 }
 
 #[cfg(not(test))]
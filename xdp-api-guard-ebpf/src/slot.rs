@@ -0,0 +1,60 @@
+// A one-entry BPF_MAP_TYPE_ARRAY_OF_MAPS, which aya-ebpf has no type for. The program reads
+// the hash map it works with from the slot, so userspace can swap in a larger one with a
+// single update of the slot.
+
+use aya_ebpf::{
+    bindings::{bpf_map_def, bpf_map_type::BPF_MAP_TYPE_ARRAY_OF_MAPS},
+    helpers::bpf_map_lookup_elem,
+    maps::HashMap,
+};
+use core::{cell::UnsafeCell, ffi::c_void, marker::PhantomData, mem};
+
+// LIBBPF_PIN_BY_NAME. The kernel wants the inner map's layout when a slot is created, which
+// the loader can't give it, so userspace creates the slot and pins it for the loader to reuse.
+const PIN_BY_NAME: u32 = 1;
+
+#[repr(transparent)]
+pub struct MapSlot<K, V> {
+    def: UnsafeCell<bpf_map_def>,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
+unsafe impl<K: Sync, V: Sync> Sync for MapSlot<K, V> {}
+
+impl<K, V> MapSlot<K, V> {
+    pub const fn pinned() -> Self {
+        Self {
+            def: UnsafeCell::new(bpf_map_def {
+                type_: BPF_MAP_TYPE_ARRAY_OF_MAPS,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<u32>() as u32,
+                max_entries: 1,
+                map_flags: 0,
+                id: 0,
+                pinning: PIN_BY_NAME,
+            }),
+            _k: PhantomData,
+            _v: PhantomData,
+        }
+    }
+
+    // The map in the slot, `fallback` while userspace hasn't filled it in. The lookup of an
+    // array of maps is inlined by the verifier, so this costs a few instructions.
+    #[inline(always)]
+    pub fn get(&self, fallback: &'static HashMap<K, V>) -> &'static HashMap<K, V> {
+        let key = 0u32;
+        let inner = unsafe {
+            bpf_map_lookup_elem(
+                self.def.get() as *mut c_void,
+                &key as *const u32 as *const c_void,
+            )
+        };
+        if inner.is_null() {
+            fallback
+        } else {
+            //A map is passed to helpers by its address, which is all a HashMap is
+            unsafe { &*(inner as *const HashMap<K, V>) }
+        }
+    }
+}
@@ -1,6 +1,18 @@
 use anyhow::{Context as _, anyhow};
 use aya_build::Toolchain;
 
+// Features of xdp-api-guard-ebpf, each also one of ours
+const KERNEL_FEATURES: &[&str] = &[
+    "conntrack",
+    "port-rules",
+    "syn-checks",
+    "http",
+    "decap",
+    "dscp",
+    "bloom",
+    "sni",
+];
+
 fn main() -> anyhow::Result<()> {
     let cargo_metadata::Metadata { packages, .. } = cargo_metadata::MetadataCommand::new()
         .no_deps()
@@ -15,13 +27,64 @@ fn main() -> anyhow::Result<()> {
         manifest_path,
         ..
     } = ebpf_package;
+    // The program's features follow ours of the same name
+    let features: Vec<&str> = KERNEL_FEATURES
+        .iter()
+        .copied()
+        .filter(|feature| {
+            let var = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+            std::env::var_os(var).is_some()
+        })
+        .collect();
     let ebpf_package = aya_build::Package {
         name: name.as_str(),
         root_dir: manifest_path
             .parent()
             .ok_or_else(|| anyhow!("no parent for {manifest_path}"))?
             .as_str(),
+        features: &features,
         ..Default::default()
     };
+    #[cfg(feature = "ffi")]
+    header()?;
+    // The program's build inherits our environment
+    let id = build_id();
+    unsafe { std::env::set_var("XDP_API_GUARD_BUILD_ID", &id) };
+    println!("cargo:rustc-env=XDP_API_GUARD_BUILD_ID={id}");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
     aya_build::build_ebpf([ebpf_package], Toolchain::default())
 }
+
+// A new id for each build of the program, embedded in it and in us so the daemon can tell its
+// own object from another's, see `VersionInfo::build_id`. Reproducible builds get the same id
+// for the same SOURCE_DATE_EPOCH.
+fn build_id() -> String {
+    use std::hash::{BuildHasher as _, BuildHasherDefault, DefaultHasher, RandomState};
+    let (high, low) = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => {
+            let fixed = BuildHasherDefault::<DefaultHasher>::default();
+            (fixed.hash_one((&epoch, 0)), fixed.hash_one((&epoch, 1)))
+        }
+        Err(_) => (
+            RandomState::new().hash_one(std::process::id()),
+            RandomState::new().hash_one(std::time::SystemTime::now()),
+        ),
+    };
+    format!("{high:016x}{low:016x}")
+}
+
+// The C header of `ffi`, `xdp_api_guard.h` in `OUT_DIR`
+#[cfg(feature = "ffi")]
+fn header() -> anyhow::Result<()> {
+    let src = std::path::PathBuf::from(std::env::var("CARGO_MANIFEST_DIR")?).join("src/ffi.rs");
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+    println!("cargo:rerun-if-changed={}", src.display());
+    cbindgen::Builder::new()
+        .with_language(cbindgen::Language::C)
+        .with_include_guard("XDP_API_GUARD_H")
+        .with_src(src)
+        .generate()
+        .context("cbindgen")?
+        .write_to_file(out_dir.join("xdp_api_guard.h"));
+    Ok(())
+}
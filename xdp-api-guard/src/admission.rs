@@ -0,0 +1,421 @@
+//! Who may use the control socket, and how much of it.
+//!
+//! Clients are told apart by the uid the kernel reports for the socket (`SO_PEERCRED`), so a
+//! process can't get a fresh budget by opening another connection. Each uid has a bucket of
+//! `--control-rate` units a second. Commands that walk a whole map cost `EXPENSIVE` units,
+//! the rest one, so a loop of `list` runs out long before it could keep the maps locked
+//! away from the sampler. A refused request gets `err throttled`, and a connection refused
+//! `MAX_STRIKES` times in a row is closed and logged.
+//!
+//! Commands that change anything can be held back for the uids of `--control-write-uid` and
+//! the members of the groups of `--control-write-gid`. Membership is the gid the kernel
+//! reports for the socket plus the groups the group database lists the uid's user in, looked
+//! up for each such command so a change to /etc/group takes without a restart. Root always
+//! passes, the daemon runs as root anyway.
+
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString, c_char, c_int},
+    fmt, mem, ptr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{net::unix::UCred, sync::Semaphore};
+
+use crate::{control::Command, tokens::Role, verify::VerifyTarget};
+
+/// Units a command that walks a map costs, cheap ones cost 1.
+pub const EXPENSIVE: u32 = 10;
+
+/// Refusals in a row after which a connection is closed.
+pub const MAX_STRIKES: u32 = 5;
+
+/// Longest request line, past it the connection is closed.
+pub const MAX_LINE_BYTES: usize = 4096;
+
+pub struct SocketLimits {
+    pub max_clients: usize,
+    /// Units a second per uid, also the burst.
+    pub rate: u32,
+    /// Empty with `write_gids` empty: whoever can open the socket may change things.
+    pub write_uids: Vec<u32>,
+    pub write_gids: Vec<u32>,
+}
+
+pub struct Admission {
+    limits: SocketLimits,
+    pub connections: Arc<Semaphore>,
+    buckets: Mutex<HashMap<u32, Bucket>>,
+}
+
+struct Bucket {
+    units: f64,
+    at: Instant,
+}
+
+#[derive(Debug)]
+pub enum Refusal {
+    Throttled(Duration),
+    ReadOnly(u32),
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::Throttled(wait) => {
+                write!(f, "throttled, retry in {}ms", wait.as_millis().max(1))
+            }
+            Refusal::ReadOnly(uid) => write!(f, "uid {uid} may only run read-only commands"),
+        }
+    }
+}
+
+impl Admission {
+    pub fn new(limits: SocketLimits) -> Self {
+        Self {
+            connections: Arc::new(Semaphore::new(limits.max_clients)),
+            limits,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Takes the cost of `cmd` from the bucket of `peer`, or says why not. `None` is a line
+    /// that didn't parse, it costs one unit like any cheap command.
+    pub fn admit(&self, peer: &UCred, cmd: Option<&Command>) -> Result<(), Refusal> {
+        let lookup = || groups(peer.uid(), peer.gid());
+        self.admit_at(peer.uid(), lookup, cmd, Instant::now())
+    }
+
+    // `groups` is only called for a command that needs write access
+    fn admit_at(
+        &self,
+        uid: u32,
+        groups: impl FnOnce() -> Vec<u32>,
+        cmd: Option<&Command>,
+        now: Instant,
+    ) -> Result<(), Refusal> {
+        if let Some(cmd) = cmd
+            && Role::needed(cmd) != Role::ReadOnly
+            && !self.may_write(uid, groups)
+        {
+            return Err(Refusal::ReadOnly(uid));
+        }
+        let cost = f64::from(cmd.map_or(1, cost));
+        let rate = f64::from(self.limits.rate);
+        // A second's worth, or one expensive command at rates below it
+        let capacity = rate.max(f64::from(EXPENSIVE));
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(uid).or_insert(Bucket {
+            units: capacity,
+            at: now,
+        });
+        let elapsed = now.duration_since(bucket.at).as_secs_f64();
+        bucket.units = (bucket.units + elapsed * rate).min(capacity);
+        // A client that read the clock before another took the lock mustn't move it back,
+        // or the time between is paid out twice
+        bucket.at = bucket.at.max(now);
+        if bucket.units < cost {
+            let wait = Duration::from_secs_f64((cost - bucket.units) / rate);
+            return Err(Refusal::Throttled(wait));
+        }
+        bucket.units -= cost;
+        Ok(())
+    }
+
+    fn may_write(&self, uid: u32, groups: impl FnOnce() -> Vec<u32>) -> bool {
+        let SocketLimits {
+            write_uids,
+            write_gids,
+            ..
+        } = &self.limits;
+        (write_uids.is_empty() && write_gids.is_empty())
+            || uid == 0
+            || write_uids.contains(&uid)
+            || (!write_gids.is_empty() && groups().iter().any(|gid| write_gids.contains(gid)))
+    }
+}
+
+/// The groups of `uid`: `gid`, the one `SO_PEERCRED` gave, and the supplementary groups of
+/// its user. Only `gid` for a uid without a passwd entry.
+fn groups(uid: u32, gid: u32) -> Vec<u32> {
+    let mut groups = user_name(uid).map_or_else(Vec::new, |name| group_list(&name, gid));
+    groups.push(gid);
+    groups
+}
+
+// Far past any real entry, in case the lookup keeps asking for more
+const LOOKUP_CAP: usize = 1 << 20;
+
+fn user_name(uid: u32) -> Option<CString> {
+    let mut buf: Vec<c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: all zeros is a valid passwd, getpwuid_r fills it in
+        let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut found = ptr::null_mut();
+        // SAFETY: the strings of pwd point into buf, which outlives the CStr taken of them
+        let err =
+            unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+        match err {
+            libc::ERANGE if buf.len() < LOOKUP_CAP => buf.resize(buf.len() * 2, 0),
+            0 if !found.is_null() => {
+                return Some(unsafe { CStr::from_ptr(pwd.pw_name) }.to_owned());
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn group_list(name: &CStr, gid: u32) -> Vec<u32> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut len = groups.len() as c_int;
+        // SAFETY: groups has room for len entries, getgrouplist writes at most that many
+        let n = unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut len) };
+        if n >= 0 {
+            groups.truncate(n as usize);
+            return groups;
+        }
+        if groups.len() >= LOOKUP_CAP {
+            return groups;
+        }
+        // len is the count it needs now
+        groups.resize((len.max(0) as usize).max(groups.len() * 2), 0);
+    }
+}
+
+/// Units `cmd` takes from its client's bucket. Every command is listed, so a new one has to
+/// pick.
+pub fn cost(cmd: &Command) -> u32 {
+    match cmd {
+        Command::List
+        | Command::Export { .. }
+        | Command::Offenders(_)
+        | Command::Rules(_)
+        | Command::Suggest { .. }
+        | Command::Profile(_)
+        | Command::SnapshotSave { .. }
+        | Command::SnapshotLoad(_)
+        | Command::Resize(..)
+        | Command::Verify(VerifyTarget::Sample(_))
+        | Command::Flush(_) => EXPENSIVE,
+        Command::Block(..)
+        | Command::Unblock(_)
+        | Command::Allow(_)
+        | Command::Tag(..)
+        | Command::Untag(_)
+        | Command::Reset(_)
+        | Command::Why(_)
+        | Command::Verify(VerifyTarget::Probe(_))
+        | Command::Group(_)
+        | Command::Chain(_)
+        | Command::Enforce { .. }
+        | Command::Pause
+        | Command::Resume
+        | Command::Params
+        | Command::Param(..)
+        | Command::Status
+        | Command::LastAbort
+        | Command::LogLevel { .. } => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Barrier,
+            atomic::{AtomicBool, Ordering},
+        },
+        thread,
+    };
+
+    use super::*;
+
+    fn limits(rate: u32, write_uids: &[u32], write_gids: &[u32]) -> Admission {
+        Admission::new(SocketLimits {
+            max_clients: 64,
+            rate,
+            write_uids: write_uids.to_vec(),
+            write_gids: write_gids.to_vec(),
+        })
+    }
+
+    fn command(line: &str) -> Command {
+        Command::parse(line).unwrap()
+    }
+
+    #[test]
+    fn supplementary_groups_may_write() {
+        let admission = limits(100, &[], &[50]);
+        let block = command("block 192.0.2.1");
+        let now = Instant::now();
+        let admit = |groups: &'static [u32]| {
+            admission.admit_at(1000, || groups.to_vec(), Some(&block), now)
+        };
+        assert!(admit(&[100, 50]).is_ok());
+        assert!(admit(&[50]).is_ok());
+        assert!(matches!(admit(&[100, 51]), Err(Refusal::ReadOnly(1000))));
+        // Root and listed uids never need the lookup
+        let unreachable = || -> Vec<u32> { panic!("looked up groups") };
+        assert!(
+            admission
+                .admit_at(0, unreachable, Some(&block), now)
+                .is_ok()
+        );
+        let admission = limits(100, &[1000], &[50]);
+        assert!(
+            admission
+                .admit_at(1000, unreachable, Some(&block), now)
+                .is_ok()
+        );
+        // Nor do read-only commands, or everyone being allowed
+        assert!(
+            admission
+                .admit_at(1001, unreachable, Some(&command("status")), now)
+                .is_ok()
+        );
+        let admission = limits(100, &[], &[]);
+        assert!(
+            admission
+                .admit_at(1001, unreachable, Some(&block), now)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn groups_hold_the_socket_gid() {
+        // No passwd entry, so only what the socket said
+        assert_eq!(groups(u32::MAX - 1, 4242), [4242]);
+        // Whatever root is a member of, it's at least in the gid it gave
+        assert!(groups(0, 0).contains(&0));
+    }
+
+    // A tiny LCG, the jitter only has to be irregular and the same on every run
+    fn jitter(seed: &mut u64, max_ms: u64) -> Duration {
+        *seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        Duration::from_millis((*seed >> 33) % (max_ms + 1))
+    }
+
+    #[test]
+    fn jittered_requests_get_the_rate_and_the_burst() {
+        let rate = 50;
+        let admission = limits(rate, &[], &[]);
+        let list = command("list");
+        let start = Instant::now();
+        let (mut at, mut seed) = (start, 7);
+        let (mut admitted, mut throttled) = (0, 0);
+        while at < start + Duration::from_secs(10) {
+            match admission.admit_at(1000, Vec::new, Some(&list), at) {
+                Ok(()) => admitted += EXPENSIVE,
+                Err(Refusal::Throttled(wait)) => {
+                    assert!(!wait.is_zero() && wait <= Duration::from_secs(1));
+                    throttled += 1;
+                }
+                Err(e) => panic!("{e}"),
+            }
+            at += jitter(&mut seed, 20);
+        }
+        let secs = at.duration_since(start).as_secs_f64();
+        let most = f64::from(rate) * secs + f64::from(rate.max(EXPENSIVE));
+        assert!(f64::from(admitted) <= most, "{admitted} units in {secs}s");
+        // Asking every 10ms on average, it gets nearly all it is due
+        assert!(f64::from(admitted) >= f64::from(rate) * secs - f64::from(EXPENSIVE));
+        assert!(throttled > 0);
+        // Another uid has a bucket of its own
+        assert!(admission.admit_at(1001, Vec::new, Some(&list), at).is_ok());
+    }
+
+    #[test]
+    fn many_clients_leave_the_sampler_on_time() {
+        const CLIENTS: usize = 32;
+        const TICK: Duration = Duration::from_millis(5);
+        // A round trip over the socket, and a whole map walked for an admitted `list`
+        const ROUND_TRIP: Duration = Duration::from_micros(100);
+        const WALK: Duration = Duration::from_millis(1);
+        // Each, far more than any bucket holds however the threads get scheduled
+        const REQUESTS: u32 = 500;
+        let rate = 100;
+        let admission = limits(rate, &[], &[50]);
+        // Held by a walk as by the sampler's tick
+        let maps = Mutex::new(());
+        let run = Duration::from_millis(400);
+        let barrier = Barrier::new(CLIENTS + 1);
+        let done = AtomicBool::new(false);
+        let start = Instant::now();
+        let (results, waited) = thread::scope(|scope| {
+            let clients: Vec<_> = (0..CLIENTS)
+                .map(|i| {
+                    let (admission, maps, barrier) = (&admission, &maps, &barrier);
+                    scope.spawn(move || {
+                        // Four connections to each uid, and some write attempts
+                        let uid = 1000 + (i % 8) as u32;
+                        let (list, block) = (command("list"), command("block 192.0.2.1"));
+                        let (mut admitted, mut throttled, mut read_only) = (0u32, 0u32, 0u32);
+                        barrier.wait();
+                        let mut sent = 0;
+                        while sent < REQUESTS || start.elapsed() < run {
+                            sent += 1;
+                            let cmd = if i % 4 == 0 { &block } else { &list };
+                            match admission.admit_uid(uid, Some(cmd)) {
+                                Ok(()) => {
+                                    admitted += cost(cmd);
+                                    let _maps = maps.lock().unwrap();
+                                    thread::sleep(WALK);
+                                }
+                                Err(Refusal::Throttled(_)) => throttled += 1,
+                                Err(Refusal::ReadOnly(_)) => read_only += 1,
+                            }
+                            thread::sleep(ROUND_TRIP);
+                        }
+                        (uid, admitted, throttled, read_only)
+                    })
+                })
+                .collect();
+            let sampler = scope.spawn(|| {
+                barrier.wait();
+                let (mut next, mut waited, mut ticks) = (Instant::now(), Duration::ZERO, 0);
+                while !done.load(Ordering::Relaxed) {
+                    next += TICK;
+                    thread::sleep(next.saturating_duration_since(Instant::now()));
+                    // Late for the walks, how late the sleep ends is the machine's business
+                    let at = Instant::now();
+                    drop(maps.lock().unwrap());
+                    waited += at.elapsed();
+                    ticks += 1;
+                }
+                waited / ticks.max(1)
+            });
+            let results: Vec<_> = clients.into_iter().map(|c| c.join().unwrap()).collect();
+            done.store(true, Ordering::Relaxed);
+            (results, sampler.join().unwrap())
+        });
+        let secs = start.elapsed().as_secs_f64();
+        // Admitted units and refusals of each uid, over all of its connections
+        let mut per_uid: HashMap<u32, (u32, u32)> = HashMap::new();
+        for &(uid, admitted, throttled, read_only) in &results {
+            let (units, refused) = per_uid.entry(uid).or_default();
+            *units += admitted;
+            *refused += throttled + read_only;
+        }
+        let most = f64::from(rate) * secs + f64::from(rate.max(EXPENSIVE));
+        for (uid, (units, refused)) in per_uid {
+            assert!(f64::from(units) <= most, "uid {uid}: {units} in {secs}s");
+            // The writers are refused before they reach the bucket
+            assert!(refused > 0, "uid {uid} was never refused");
+        }
+        assert!(results.iter().any(|&(_, _, _, read_only)| read_only > 0));
+        // Unthrottled, the 24 listing clients keep the maps and a tick waits about as long as
+        // it is apart from the next. Throttled they walk them about a fifth of the run
+        assert!(waited < TICK / 2, "ticks waited {waited:?} on average");
+    }
+
+    impl Admission {
+        // As `admit` does, for a peer without a socket whose only group is its uid
+        fn admit_uid(&self, uid: u32, cmd: Option<&Command>) -> Result<(), Refusal> {
+            self.admit_at(uid, || vec![uid], cmd, Instant::now())
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::stats::Dataplane;
+
+/// Raises an alert when a rate crosses its threshold and clears it when it falls back.
+/// Only transitions are logged, so a sustained attack produces two lines, not one per second.
+pub struct Alert {
+    name: &'static str,
+    threshold: f64,
+    unit: &'static str,
+    active: bool,
+}
+
+/// An alert raised or cleared, and the value that did it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Transition {
+    pub name: &'static str,
+    pub raised: bool,
+    pub value: f64,
+    pub threshold: f64,
+    #[serde(skip)]
+    unit: &'static str,
+}
+
+// The line logged for it
+impl std::fmt::Display for Transition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (value, threshold, unit) = (self.value, self.threshold, self.unit);
+        if self.raised {
+            write!(
+                f,
+                "ALERT {}: {value:.1}{unit} is above {threshold:.1}{unit}",
+                self.name
+            )
+        } else {
+            write!(
+                f,
+                "ALERT {} cleared: {value:.1}{unit} is back under {threshold:.1}{unit}",
+                self.name
+            )
+        }
+    }
+}
+
+impl Alert {
+    /// An alert on a rate, in packets per second.
+    pub fn new(name: &'static str, threshold: f64) -> Self {
+        Self {
+            name,
+            threshold,
+            unit: "/s",
+            active: false,
+        }
+    }
+
+    /// An alert on a percentage.
+    pub fn percent(name: &'static str, threshold: f64) -> Self {
+        Self {
+            unit: "%",
+            ..Self::new(name, threshold)
+        }
+    }
+
+    /// Logs and returns the transition `value` makes, if any.
+    pub fn check(&mut self, value: f64) -> Option<Transition> {
+        let above = value > self.threshold;
+        if above == self.active {
+            return None;
+        }
+        self.active = above;
+        let transition = Transition {
+            name: self.name,
+            raised: above,
+            value,
+            threshold: self.threshold,
+            unit: self.unit,
+        };
+        if above {
+            warn!("{transition}");
+        } else {
+            info!("{transition}");
+        }
+        Some(transition)
+    }
+}
+
+/// Logs a change of the dataplane in the alerts' format, so whatever watches the log for
+/// alerts also hears that the interface went down or the program went missing.
+pub fn dataplane(iface: &str, before: Dataplane, now: Dataplane) {
+    if now == Dataplane::Up {
+        info!("{}", dataplane_line(iface, before, now));
+    } else {
+        warn!("{}", dataplane_line(iface, before, now));
+    }
+}
+
+/// The line [`dataplane`] logs.
+pub fn dataplane_line(iface: &str, before: Dataplane, now: Dataplane) -> String {
+    if now == Dataplane::Up {
+        format!(
+            "ALERT dataplane cleared: {iface} is up again, it was {}",
+            before.name()
+        )
+    } else {
+        format!(
+            "ALERT dataplane: {iface} is {}, rates are paused",
+            now.name()
+        )
+    }
+}
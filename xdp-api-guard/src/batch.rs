@@ -0,0 +1,113 @@
+//! Batched hash map writes, `BPF_MAP_UPDATE_BATCH` and `BPF_MAP_DELETE_BATCH`, which aya
+//! doesn't wrap.
+//!
+//! One syscall writes a whole chunk of `CHUNK` entries instead of one per entry, so a feed of
+//! hundreds of thousands of addresses loads in a fraction of the time, and bulk removals hold
+//! the blocklist lock that much shorter. Kernels before 5.6 lack the commands; `supported`
+//! probes for them once, and callers write one entry at a time without.
+
+use std::{
+    io,
+    os::fd::{AsFd as _, AsRawFd as _},
+};
+
+use aya::{Pod, maps::MapData};
+
+/// Entries per syscall.
+pub const CHUNK: usize = 4096;
+
+const BPF_MAP_UPDATE_BATCH: libc::c_long = 26;
+const BPF_MAP_DELETE_BATCH: libc::c_long = 27;
+
+// The `batch` member of `union bpf_attr`
+#[repr(C)]
+#[derive(Default)]
+struct BatchAttr {
+    in_batch: u64,
+    out_batch: u64,
+    keys: u64,
+    values: u64,
+    count: u32,
+    map_fd: u32,
+    elem_flags: u64,
+    flags: u64,
+}
+
+/// Whether the kernel takes batched writes to `map`.
+pub fn supported(map: &MapData) -> bool {
+    // An empty batch changes nothing where the command exists, and fails where it doesn't
+    run(BPF_MAP_UPDATE_BATCH, map, 0, 0, 0).1.is_ok()
+}
+
+/// Writes `values[i]` under `keys[i]`, in order. On an error the entries before the one that
+/// failed are written, and their count comes with the error.
+pub fn update<K: Pod, V: Pod>(
+    map: &MapData,
+    keys: &[K],
+    values: &[V],
+) -> Result<(), (usize, io::Error)> {
+    assert_eq!(keys.len(), values.len());
+    let mut done = 0;
+    for (keys, values) in keys.chunks(CHUNK).zip(values.chunks(CHUNK)) {
+        let (count, result) = run(
+            BPF_MAP_UPDATE_BATCH,
+            map,
+            keys.as_ptr() as u64,
+            values.as_ptr() as u64,
+            keys.len(),
+        );
+        if let Err(e) = result {
+            return Err((done + count, e));
+        }
+        done += keys.len();
+    }
+    Ok(())
+}
+
+/// Deletes `keys`. Keys the map doesn't have are skipped; they would stop the batch short.
+/// On an error the keys before the one that failed are gone, and their count comes with it.
+pub fn delete<K: Pod>(map: &MapData, keys: &[K]) -> Result<(), (usize, io::Error)> {
+    let mut done = 0;
+    while done < keys.len() {
+        let len = (keys.len() - done).min(CHUNK);
+        let rest = keys[done..].as_ptr() as u64;
+        let (count, result) = run(BPF_MAP_DELETE_BATCH, map, rest, 0, len);
+        match result {
+            Ok(()) => done += len,
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => done += count + 1,
+            Err(e) => return Err((done + count, e)),
+        }
+    }
+    Ok(())
+}
+
+// The entries processed, which the kernel reports even when it stopped early, and the result
+fn run(
+    cmd: libc::c_long,
+    map: &MapData,
+    keys: u64,
+    values: u64,
+    count: usize,
+) -> (usize, io::Result<()>) {
+    let mut attr = BatchAttr {
+        keys,
+        values,
+        count: count as u32,
+        map_fd: map.fd().as_fd().as_raw_fd() as u32,
+        ..BatchAttr::default()
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            &mut attr as *mut BatchAttr,
+            size_of::<BatchAttr>() as u32,
+        )
+    };
+    let result = if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    };
+    (attr.count as usize, result)
+}
@@ -0,0 +1,277 @@
+//! Offline comparison of limiter algorithms: `guard-sim --pattern burst --rate 100`.
+//!
+//! Replays a synthetic traffic pattern or a pcap capture through the fixed window the datapath
+//! runs, a token bucket with the same budget and a leaky bucket at the same average rate, and
+//! reports what each would let through. Nothing is loaded into the kernel.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, bail};
+use clap::{Parser, ValueEnum};
+use xdp_api_guard_common::limiter::{FixedWindow, LeakyBucket, Limiter, TokenBucket};
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(Debug, Parser)]
+struct Opt {
+    /// Packets per window and source, as with --rate of the daemon
+    #[clap(long, default_value = "10")]
+    rate: u64,
+
+    /// Window length in milliseconds
+    #[clap(long, default_value = "1000")]
+    window: u64,
+
+    /// Replay a capture instead of a synthetic pattern (classic pcap, Ethernet)
+    #[clap(long, conflicts_with = "pattern")]
+    pcap: Option<PathBuf>,
+
+    #[clap(long, value_enum, default_value = "steady")]
+    pattern: Pattern,
+
+    /// Average packets per second of each synthetic source
+    #[clap(long, default_value = "20")]
+    pps: u64,
+
+    /// Number of synthetic sources
+    #[clap(long, default_value = "1")]
+    sources: u32,
+
+    /// Seconds of synthetic traffic
+    #[clap(long, default_value = "10")]
+    duration: u64,
+
+    /// Spacing of the leaky bucket, in nanoseconds per packet [default: --window / --rate]
+    #[clap(long)]
+    ns_per_packet: Option<u64>,
+
+    /// Packets the leaky bucket lets through ahead of schedule
+    #[clap(long, default_value = "0")]
+    leaky_depth: u64,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Pattern {
+    /// Evenly spaced packets
+    Steady,
+    /// A quarter second at four times the rate, then silence, every second
+    Burst,
+    /// From idle up to twice the rate over the duration
+    Ramp,
+}
+
+/// Verdicts of one limiter over the whole replay.
+#[derive(Debug, Default)]
+struct Outcome {
+    passed: u64,
+    dropped: u64,
+    /// Most packets one source got through within any span of one window.
+    peak: u64,
+}
+
+fn main() -> anyhow::Result<()> {
+    let opt = Opt::parse();
+    if opt.window == 0 {
+        bail!("--window must be at least 1ms");
+    }
+    let window_ns = opt.window * 1_000_000;
+    let packets = match &opt.pcap {
+        Some(path) => read_pcap(path)?,
+        None => synthesize(&opt),
+    };
+    let sources = packets
+        .iter()
+        .map(|(_, src)| src)
+        .collect::<HashSet<_>>()
+        .len();
+    let span = packets.last().map_or(0, |(t, _)| *t);
+
+    let fixed = simulate(&packets, window_ns, || {
+        FixedWindow::new(opt.rate, window_ns)
+    });
+    let bucket = simulate(&packets, window_ns, || {
+        TokenBucket::new(opt.rate, window_ns)
+    });
+    let ns_per_packet = opt.ns_per_packet.unwrap_or(window_ns / opt.rate.max(1));
+    if ns_per_packet == 0 {
+        bail!("--ns-per-packet must be at least 1");
+    }
+    let leaky = simulate(&packets, window_ns, || {
+        LeakyBucket::new(ns_per_packet, opt.leaky_depth)
+    });
+
+    println!(
+        "{} packets from {sources} sources over {:.1}s, limit {} per {}ms",
+        packets.len(),
+        span as f64 / NS_PER_SEC as f64,
+        opt.rate,
+        opt.window
+    );
+    println!(
+        "{:<14} {:>10} {:>10} {:>7} {:>12}",
+        "limiter", "passed", "dropped", "drop", "peak/window"
+    );
+    for (name, outcome) in [
+        ("fixed-window", &fixed),
+        ("token-bucket", &bucket),
+        ("leaky-bucket", &leaky),
+    ] {
+        let total = outcome.passed + outcome.dropped;
+        let share = if total == 0 {
+            0.0
+        } else {
+            outcome.dropped as f64 * 100.0 / total as f64
+        };
+        println!(
+            "{name:<14} {:>10} {:>10} {share:>6.1}% {:>12}",
+            outcome.passed, outcome.dropped, outcome.peak
+        );
+    }
+    Ok(())
+}
+
+/// Runs `packets`, sorted by time, through one limiter per source.
+fn simulate<L: Limiter>(packets: &[(u64, IpAddr)], window_ns: u64, new: impl Fn() -> L) -> Outcome {
+    let mut limiters = HashMap::new();
+    // Pass times of each source within the last window, for the peak
+    let mut passes: HashMap<IpAddr, VecDeque<u64>> = HashMap::new();
+    let mut outcome = Outcome::default();
+    for &(now, src) in packets {
+        if limiters.entry(src).or_insert_with(&new).hit(now) {
+            outcome.dropped += 1;
+            continue;
+        }
+        outcome.passed += 1;
+        let recent = passes.entry(src).or_default();
+        while recent.front().is_some_and(|&t| now - t >= window_ns) {
+            recent.pop_front();
+        }
+        recent.push_back(now);
+        outcome.peak = outcome.peak.max(recent.len() as u64);
+    }
+    outcome
+}
+
+fn synthesize(opt: &Opt) -> Vec<(u64, IpAddr)> {
+    let end = opt.duration * NS_PER_SEC;
+    let mut packets = Vec::new();
+    if opt.pps == 0 {
+        return packets;
+    }
+    for i in 0..opt.sources {
+        let src = IpAddr::V4(Ipv4Addr::from(0x0a00_0001 + i));
+        // Sources are spread across one packet gap so they don't arrive in lockstep
+        let phase = NS_PER_SEC / opt.pps * u64::from(i) / u64::from(opt.sources);
+        match opt.pattern {
+            Pattern::Steady => {
+                let gap = NS_PER_SEC / opt.pps;
+                packets.extend((phase..end).step_by(gap as usize).map(|t| (t, src)));
+            }
+            Pattern::Burst => {
+                let gap = (NS_PER_SEC / (opt.pps * 4)).max(1);
+                for second in (0..end).step_by(NS_PER_SEC as usize) {
+                    let burst = second + phase..second + NS_PER_SEC / 4;
+                    packets.extend(burst.step_by(gap as usize).map(|t| (t, src)));
+                }
+            }
+            Pattern::Ramp => {
+                let mut t = phase;
+                while t < end {
+                    packets.push((t, src));
+                    // Instantaneous rate grows linearly to 2 * pps at the end
+                    t += NS_PER_SEC / (2 * opt.pps * t / end).max(1);
+                }
+            }
+        }
+    }
+    packets.sort_by_key(|&(t, _)| t);
+    packets
+}
+
+/// Arrival time relative to the first packet and source address of every IP packet in a
+/// classic pcap file.
+fn read_pcap(path: &Path) -> anyhow::Result<Vec<(u64, IpAddr)>> {
+    let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    if data.len() < 24 {
+        bail!("{}: too short for a pcap header", path.display());
+    }
+    let magic = [data[0], data[1], data[2], data[3]];
+    let (big_endian, frac_ns) = match magic {
+        [0xa1, 0xb2, 0xc3, 0xd4] => (true, 1_000),
+        [0xd4, 0xc3, 0xb2, 0xa1] => (false, 1_000),
+        [0xa1, 0xb2, 0x3c, 0x4d] => (true, 1),
+        [0x4d, 0x3c, 0xb2, 0xa1] => (false, 1),
+        _ => bail!(
+            "{}: not a classic pcap file (pcapng captures can be converted with editcap -F pcap)",
+            path.display()
+        ),
+    };
+    let u32_at = |offset: usize| {
+        let bytes = [
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ];
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let linktype = u32_at(20);
+    if linktype != 1 {
+        bail!(
+            "{}: link type {linktype}, only Ethernet (1) is supported",
+            path.display()
+        );
+    }
+
+    let mut packets = Vec::new();
+    let mut offset = 24;
+    while offset + 16 <= data.len() {
+        let ts = u64::from(u32_at(offset)) * NS_PER_SEC + u64::from(u32_at(offset + 4)) * frac_ns;
+        let len = u32_at(offset + 8) as usize;
+        let start = offset + 16;
+        offset = start + len;
+        let Some(frame) = data.get(start..offset) else {
+            break;
+        };
+        if let Some(src) = source(frame) {
+            packets.push((ts, src));
+        }
+    }
+    packets.sort_by_key(|&(t, _)| t);
+    if let Some(&(first, _)) = packets.first() {
+        for (t, _) in &mut packets {
+            *t -= first;
+        }
+    }
+    Ok(packets)
+}
+
+// Source address of an Ethernet frame, looking through one VLAN tag
+fn source(frame: &[u8]) -> Option<IpAddr> {
+    let mut l3 = 14;
+    let mut ethertype = u16::from_be_bytes(frame.get(12..14)?.try_into().ok()?);
+    if ethertype == 0x8100 {
+        ethertype = u16::from_be_bytes(frame.get(16..18)?.try_into().ok()?);
+        l3 += 4;
+    }
+    match ethertype {
+        0x0800 => {
+            let src: [u8; 4] = frame.get(l3 + 12..l3 + 16)?.try_into().ok()?;
+            Some(Ipv4Addr::from(src).into())
+        }
+        0x86dd => {
+            let src: [u8; 16] = frame.get(l3 + 8..l3 + 24)?.try_into().ok()?;
+            Some(Ipv6Addr::from(src).into())
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,177 @@
+//! Thin client for the daemon's control socket, or its REST API with `--http`:
+//! `guardctl block 1.2.3.4`.
+
+use std::{
+    io::{self, IsTerminal as _, Read as _, Write as _},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use anyhow::{Context as _, bail};
+use clap::Parser;
+use xdp_api_guard_client::{GuardClient, HttpTransport, Transport, UnixTransport};
+use xdp_api_guard_common::DEFAULT_CONTROL_SOCKET;
+
+// The most entries the daemon puts on a page
+const EXPORT_PAGE: usize = 10_000;
+
+#[derive(Debug, Parser)]
+struct Opt {
+    /// Path of the daemon's control socket
+    #[clap(long, default_value = DEFAULT_CONTROL_SOCKET, env = "GUARD_CONTROL_SOCKET")]
+    socket: PathBuf,
+
+    /// Send the command to the REST API at HOST:PORT instead of the control socket
+    #[clap(long, value_name = "HOST:PORT", env = "GUARD_HTTP")]
+    http: Option<String>,
+
+    /// Bearer token for --http. Also read from the file GUARD_HTTP_TOKEN_FILE names
+    #[clap(long, requires = "http", env = "GUARD_HTTP_TOKEN")]
+    token: Option<String>,
+
+    /// Command and arguments, e.g. `tag 1.2.3.4 50`
+    #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    let mut opt = Opt::parse();
+    if opt.http.is_some()
+        && opt.token.is_none()
+        && let Some(path) = std::env::var_os("GUARD_HTTP_TOKEN_FILE")
+    {
+        let token = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read GUARD_HTTP_TOKEN_FILE {path:?}"))?;
+        opt.token = Some(token.trim().to_owned());
+    }
+
+    // `--yes` may appear anywhere in the command, it is ours and not the daemon's
+    let len = opt.command.len();
+    opt.command.retain(|word| word != "--yes" && word != "-y");
+    let yes = opt.command.len() != len;
+    // An idempotency key in front doesn't make a flush any less destructive
+    let verb = opt
+        .command
+        .iter()
+        .find(|word| !word.starts_with("req_id="))
+        .map(String::as_str);
+    if verb == Some("flush") && !yes && !confirm(&opt.command)? {
+        eprintln!("aborted");
+        return Ok(ExitCode::FAILURE);
+    }
+
+    if verb == Some("export") {
+        return export(&opt);
+    }
+
+    let response = match &opt.http {
+        Some(addr) => {
+            // Over HTTP the idempotency key is a header
+            let (key, words) = match opt.command[0].strip_prefix("req_id=") {
+                Some(key) => (Some(key), &opt.command[1..]),
+                None => (None, &opt.command[..]),
+            };
+            runtime()?.block_on(http(&opt, addr).command(&words.join(" "), key))?
+        }
+        None => {
+            let mut stream = UnixStream::connect(&opt.socket).with_context(|| {
+                let hint = match verb {
+                    // Pinned maps outlive the daemon, and can be flushed without it
+                    Some("flush") => ", `xdp-api-guard flush --pins DIR` flushes pinned maps",
+                    _ => "",
+                };
+                format!("failed to connect to {}{hint}", opt.socket.display())
+            })?;
+            writeln!(stream, "{}", opt.command.join(" "))?;
+            stream.shutdown(Shutdown::Write)?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            response
+        }
+    };
+    let response = response.trim_end();
+    println!("{response}");
+
+    Ok(if response.starts_with("err") {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// `export [--cursor CURSOR]`: every blocklist entry a line, paged so a huge blocklist
+/// neither has to fit in one reply nor ties up the daemon.
+fn export(opt: &Opt) -> anyhow::Result<ExitCode> {
+    let mut cursor = None;
+    let args: Vec<&str> = opt.command[1..].iter().map(String::as_str).collect();
+    match args[..] {
+        [] => {}
+        ["--cursor", from] => cursor = Some(from.to_owned()),
+        _ => bail!("expected export [--cursor CURSOR]"),
+    }
+    let runtime = runtime()?;
+    match &opt.http {
+        Some(addr) => runtime.block_on(pages(GuardClient::new(http(opt, addr)), cursor)),
+        None => {
+            let transport = UnixTransport::new(&opt.socket);
+            runtime.block_on(pages(GuardClient::new(transport), cursor))
+        }
+    }
+}
+
+async fn pages<T: Transport>(
+    client: GuardClient<T>,
+    mut cursor: Option<String>,
+) -> anyhow::Result<ExitCode> {
+    let mut out = io::stdout().lock();
+    loop {
+        let page = match client.export_page(cursor.as_deref(), EXPORT_PAGE).await {
+            Ok(page) => page,
+            Err(e) => {
+                eprintln!("export stopped: {e:#}");
+                if let Some(cursor) = cursor {
+                    eprintln!("carry on with: guardctl export --cursor {cursor}");
+                }
+                return Ok(ExitCode::FAILURE);
+            }
+        };
+        for entry in &page.entries {
+            writeln!(out, "{entry}")?;
+        }
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => return Ok(ExitCode::SUCCESS),
+        }
+    }
+}
+
+fn http(opt: &Opt, addr: &str) -> HttpTransport {
+    let transport = HttpTransport::new(addr);
+    match &opt.token {
+        Some(token) => transport.token(token),
+        None => transport,
+    }
+}
+
+fn runtime() -> anyhow::Result<tokio::runtime::Runtime> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?)
+}
+
+/// Asks before running a destructive command. Without a terminal to ask on, `--yes` is required.
+fn confirm(command: &[String]) -> anyhow::Result<bool> {
+    if !io::stdin().is_terminal() {
+        anyhow::bail!("refusing to run `{}` without --yes", command.join(" "));
+    }
+    eprint!(
+        "This will delete map entries (`{}`). Continue? [y/N] ",
+        command.join(" ")
+    );
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
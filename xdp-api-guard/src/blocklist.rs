@@ -0,0 +1,1138 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap as StdHashMap, HashSet},
+    fs,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Instant,
+};
+
+use anyhow::{Context as _, bail};
+use aya::maps::{
+    HashMap, IterableMap as _, MapData,
+    lpm_trie::{Key, LpmTrie},
+};
+use log::{debug, info, warn};
+use tokio::sync::broadcast;
+use xdp_api_guard_common::{BlockEntry, Origin};
+
+use crate::{
+    batch,
+    bloom::Bloom,
+    bounded::Bounded,
+    cidr::Ipv4Cidr,
+    journal::{self, Journal},
+    mask::Masked,
+    recidivist::{self, History, Recidivists},
+    timebase,
+    trace::{self, Span},
+};
+
+/// Outcome of a write through the [`BlocklistHandle`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Applied {
+    /// The entry was written to the kernel map.
+    Written,
+    /// An entry with the same origin already exists.
+    Unchanged,
+    /// A higher-priority decision covers the address, nothing was written.
+    Suppressed(Origin),
+    /// The origin already holds as many entries as its `--blocklist-quota`.
+    OverQuota(usize),
+}
+
+/// What userspace knows about one `BLOCKLIST` entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub origin: Origin,
+    /// Unix time the entry expires at, `None` for permanent entries.
+    pub expires: Option<u64>,
+    /// The node a cluster entry came from.
+    pub node: Option<String>,
+    /// Id of the `--feed` a feed entry came from, its position on the command line.
+    pub feed: u8,
+}
+
+impl Entry {
+    pub fn new(origin: Origin) -> Self {
+        Self {
+            origin,
+            expires: None,
+            node: None,
+            feed: 0,
+        }
+    }
+}
+
+/// Every change made through the handle, for whoever wants to follow along.
+#[derive(Clone, Debug)]
+pub enum BlocklistEvent {
+    Added(Ipv4Addr, Entry),
+    Removed {
+        ip: Ipv4Addr,
+        origin: Origin,
+        /// Removed because its TTL ran out rather than by a command.
+        expired: bool,
+    },
+    /// A source came back after its bans ran out `--recidivist-after` times in a row, and
+    /// `entry` replaces its latest ban.
+    Recidivist {
+        ip: Ipv4Addr,
+        entry: Entry,
+        history: History,
+    },
+}
+
+/// The only writer of `BLOCKLIST` and `MGMT_CIDRS`.
+///
+/// Keeps a copy of every entry so precedence can be checked without a map lookup, and refuses
+/// writes that would override a higher-priority decision (see [`Origin`]).
+pub struct BlocklistHandle {
+    blocklist: HashMap<MapData, u32, BlockEntry>,
+    mgmt: LpmTrie<MapData, u32, u8>,
+    entries: StdHashMap<Ipv4Addr, Entry>,
+    mgmt_cidrs: Vec<Ipv4Cidr>,
+    events: broadcast::Sender<BlocklistEvent>,
+    /// With `--state-file`, records every change to a persisted origin.
+    journal: Option<Journal>,
+    /// With `--blocklist-bloom`, the filter the kernel checks before `BLOCKLIST`.
+    bloom: Option<Bloom>,
+    /// With `--recidivist-after`, the TTL bans of each source that may still matter.
+    recidivists: Option<Recidivists>,
+    history: Bounded<Ipv4Addr, History>,
+    /// Whether the kernel takes `BPF_MAP_UPDATE_BATCH`, probed once.
+    batched: bool,
+    /// `--blocklist-quota`, and how full each origin and feed is.
+    quotas: Quotas,
+    held: StdHashMap<Slot, Held>,
+    /// For the origins that make room by evicting, their entries oldest first, and when each
+    /// was written.
+    ages: StdHashMap<Origin, BTreeMap<u64, Ipv4Addr>>,
+    stamps: StdHashMap<Ipv4Addr, u64>,
+    stamp: u64,
+}
+
+// What a quota is kept for: an origin, and which feed for feed entries
+type Slot = (Origin, u8);
+
+fn slot(entry: &Entry) -> Slot {
+    match entry.origin {
+        Origin::Feed => (Origin::Feed, entry.feed),
+        origin => (origin, 0),
+    }
+}
+
+// Whether an origin at its quota evicts its oldest entry for a new one, rather than refusing
+// it. Bans come and go on their own; a feed or an operator's entry is never dropped silently.
+fn evicts(origin: Origin) -> bool {
+    matches!(origin, Origin::AutoBan | Origin::Policy | Origin::Cluster)
+}
+
+/// The `limit` smallest of `addrs` after `after`, in order, see [`BlocklistHandle::page`].
+pub(crate) fn page(
+    addrs: impl Iterator<Item = Ipv4Addr>,
+    after: Option<Ipv4Addr>,
+    limit: usize,
+) -> Vec<Ipv4Addr> {
+    // The largest address kept on top, to be pushed out by a smaller one
+    let mut page = BinaryHeap::with_capacity(limit + 1);
+    for ip in addrs {
+        if after.is_some_and(|after| ip <= after) {
+            continue;
+        }
+        if page.len() < limit {
+            page.push(ip);
+        } else if page.peek().is_some_and(|&largest| ip < largest) {
+            page.pop();
+            page.push(ip);
+        }
+    }
+    page.into_sorted_vec()
+}
+
+// The decision that wins for an address whose entry is `held`, `managed` if a management
+// network covers it. The same order the kernel checks in.
+fn winner(held: Option<Origin>, managed: bool) -> Option<Origin> {
+    match held {
+        Some(Origin::ManualAllow) => held,
+        _ if managed => Some(Origin::Management),
+        _ => held,
+    }
+}
+
+// What writing `entry` for an address does, `held` its entry and `winner` the decision that
+// holds for it: the value the map gets, or why nothing is written
+fn decide(
+    winner: Option<Origin>,
+    held: Option<&Entry>,
+    entry: &Entry,
+) -> Result<BlockEntry, Applied> {
+    let origin = entry.origin;
+    if let Some(winner) = winner {
+        if winner == origin && held == Some(entry) {
+            return Err(Applied::Unchanged);
+        }
+        if winner > origin {
+            return Err(Applied::Suppressed(winner));
+        }
+    }
+    Ok(BlockEntry {
+        action: origin.action(),
+        origin: origin as u8,
+        feed: entry.feed,
+    })
+}
+
+#[derive(Debug, Default)]
+struct Held {
+    entries: usize,
+    rejected: u64,
+    evicted: u64,
+    // At its quota and warned about, until it has room again
+    full: bool,
+}
+
+impl BlocklistHandle {
+    pub fn new(
+        blocklist: HashMap<MapData, u32, BlockEntry>,
+        mgmt: LpmTrie<MapData, u32, u8>,
+    ) -> Self {
+        let batched = batch::supported(blocklist.map());
+        if !batched {
+            info!("the kernel has no batched map writes, writing blocklist entries one by one");
+        }
+        Self {
+            blocklist,
+            mgmt,
+            entries: StdHashMap::new(),
+            mgmt_cidrs: Vec::new(),
+            events: broadcast::channel(1024).0,
+            journal: None,
+            bloom: None,
+            recidivists: None,
+            history: Bounded::new("ban-history", recidivist::MAX_SOURCES),
+            batched,
+            quotas: Quotas::default(),
+            held: StdHashMap::new(),
+            ages: StdHashMap::new(),
+            stamps: StdHashMap::new(),
+            stamp: 0,
+        }
+    }
+
+    /// Enforces `quotas` from now on. Set it before anything is written, entries already in
+    /// the map count but aren't evicted for it.
+    pub fn set_quotas(&mut self, quotas: Quotas) {
+        self.quotas = quotas;
+    }
+
+    /// Keeps the `--blocklist-quota` of feed `id` out of `ips`: each address once, the last
+    /// ones of the file, the most recently published, first. The rest are counted as
+    /// rejected.
+    pub fn truncate_feed(&mut self, id: u8, ips: Vec<Ipv4Addr>) -> Vec<Ipv4Addr> {
+        let Some(max) = self.quotas.of((Origin::Feed, id)) else {
+            return ips;
+        };
+        let mut seen = HashSet::with_capacity(ips.len());
+        let mut kept: Vec<Ipv4Addr> = ips
+            .iter()
+            .rev()
+            .filter(|ip| seen.insert(**ip))
+            .copied()
+            .collect();
+        if kept.len() <= max {
+            return ips;
+        }
+        let rejected = kept.len() - max;
+        kept.truncate(max);
+        kept.reverse();
+        warn!(
+            "feed {} lists {} addresses, over its --blocklist-quota of {max}: loading the last \
+             {max} of the file, {rejected} rejected",
+            self.quotas.name(id),
+            seen.len()
+        );
+        self.held.entry((Origin::Feed, id)).or_default().rejected += rejected as u64;
+        kept
+    }
+
+    /// How full each origin, and each feed, is: every one with entries, a quota or
+    /// something rejected or evicted.
+    pub fn occupancy(&self) -> Vec<Occupancy> {
+        let mut slots: BTreeSet<Slot> = self.held.keys().copied().collect();
+        slots.extend(self.quotas.slots());
+        slots
+            .into_iter()
+            .map(|slot| {
+                let held = self.held.get(&slot);
+                Occupancy {
+                    origin: slot.0,
+                    feed: (slot.0 == Origin::Feed).then(|| self.quotas.name(slot.1).to_owned()),
+                    entries: held.map_or(0, |held| held.entries),
+                    quota: self.quotas.of(slot),
+                    rejected: held.map_or(0, |held| held.rejected),
+                    evicted: held.map_or(0, |held| held.evicted),
+                }
+            })
+            .collect()
+    }
+
+    /// Starts keeping ban histories. Set it once the state file is loaded, so the restored
+    /// bans don't count as new ones.
+    pub fn set_recidivists(&mut self, recidivists: Recidivists) {
+        self.recidivists = Some(recidivists);
+    }
+
+    /// The ban history of `ip`, with `--recidivist-after`.
+    pub fn history(&self, ip: Ipv4Addr) -> Option<&History> {
+        self.history.get(&ip)
+    }
+
+    pub fn histories(&self) -> impl Iterator<Item = (Ipv4Addr, &History)> + '_ {
+        self.history.iter().map(|(ip, history)| (*ip, history))
+    }
+
+    /// Puts back a history from the state file, or forgets it with `None`.
+    pub fn restore_history(&mut self, ip: Ipv4Addr, history: Option<History>) {
+        match history {
+            Some(history) => self.keep_history(ip, history),
+            None => {
+                self.history.remove(&ip);
+            }
+        }
+    }
+
+    // Adds a history, making room by forgetting those of sources that aren't banned first
+    fn keep_history(&mut self, ip: Ipv4Addr, history: History) {
+        let entries = &self.entries;
+        let evicted = self
+            .history
+            .insert(ip, history, |ip, _| entries.contains_key(ip));
+        for (ip, _) in evicted {
+            debug!(
+                "forgot the ban history of {} to stay within --state-memory",
+                Masked(ip)
+            );
+            if let Some(journal) = &mut self.journal {
+                journal.history(ip, None);
+            }
+        }
+    }
+
+    /// Keeps `bloom` in step with the entries from now on. Set it before the first insert.
+    pub fn set_bloom(&mut self, bloom: Bloom) {
+        self.bloom = Some(bloom);
+    }
+
+    pub fn bloom(&self) -> Option<&Bloom> {
+        self.bloom.as_ref()
+    }
+
+    /// Rebuilds the bloom filter without the bits of removed entries, see [`Bloom::rebuild`].
+    pub fn rebuild_bloom(
+        &mut self,
+        switch: impl FnOnce(u8) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Option<usize>> {
+        let Some(bloom) = &mut self.bloom else {
+            return Ok(None);
+        };
+        bloom.rebuild(self.entries.keys().map(|ip| u32::from(*ip)), switch)
+    }
+
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    pub fn journal(&mut self) -> Option<&mut Journal> {
+        self.journal.as_mut()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<BlocklistEvent> {
+        self.events.subscribe()
+    }
+
+    /// The decision that currently wins for `ip`, if any.
+    pub fn effective(&self, ip: Ipv4Addr) -> Option<Origin> {
+        winner(
+            self.entries.get(&ip).map(|entry| entry.origin),
+            self.in_management(ip),
+        )
+    }
+
+    pub fn in_management(&self, ip: Ipv4Addr) -> bool {
+        self.mgmt_cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Records `origin`'s decision for `ip`, unless something with a higher priority already
+    /// decided otherwise.
+    pub fn insert(&mut self, ip: Ipv4Addr, origin: Origin) -> anyhow::Result<Applied> {
+        self.insert_entry(ip, Entry::new(origin))
+    }
+
+    /// Like [`insert`](Self::insert), with a TTL or node attached. Inserting an entry of the
+    /// same origin again updates them.
+    pub fn insert_entry(&mut self, ip: Ipv4Addr, entry: Entry) -> anyhow::Result<Applied> {
+        let mut span = Span::child("blocklist insert");
+        span.set("client.address", Masked(ip));
+        span.set("origin", entry.origin.name());
+        let result = span.in_scope(|| self.write_entry(ip, entry));
+        match &result {
+            Ok(applied) => span.set("applied", format_args!("{applied:?}")),
+            Err(e) => span.fail(format_args!("{e:#}")),
+        }
+        result
+    }
+
+    fn write_entry(&mut self, ip: Ipv4Addr, entry: Entry) -> anyhow::Result<Applied> {
+        let value = match self.admit(ip, &entry) {
+            Ok(value) => value,
+            Err(applied) => return Ok(applied),
+        };
+        if let Err(applied) = self.make_room(ip, &entry)? {
+            return Ok(applied);
+        }
+        // Bits first: an entry the filter doesn't know of yet would be skipped
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(u32::from(ip))?;
+        }
+        trace::within("blocklist map write", || {
+            self.blocklist.insert(u32::from(ip), value, 0)
+        })?;
+        self.written(ip, entry)?;
+        Ok(Applied::Written)
+    }
+
+    // The value to write for `entry`, or why nothing is written
+    fn admit(&self, ip: Ipv4Addr, entry: &Entry) -> Result<BlockEntry, Applied> {
+        let admitted = decide(self.effective(ip), self.entries.get(&ip), entry);
+        if let Err(Applied::Suppressed(winner)) = admitted {
+            warn!(
+                "suppressed {} entry for {}: {} takes precedence",
+                entry.origin.name(),
+                Masked(ip),
+                winner.name()
+            );
+        }
+        admitted
+    }
+
+    // Whether `entry` fits in its origin's quota, if it has one. An origin that evicts has its
+    // oldest entry removed for it, any other is refused.
+    fn make_room(&mut self, ip: Ipv4Addr, entry: &Entry) -> anyhow::Result<Result<(), Applied>> {
+        let slot = slot(entry);
+        let Some(max) = self.quotas.of(slot) else {
+            return Ok(Ok(()));
+        };
+        // Replacing an entry of its own doesn't take more room
+        if self
+            .entries
+            .get(&ip)
+            .is_some_and(|old| self::slot(old) == slot)
+        {
+            return Ok(Ok(()));
+        }
+        let label = self.quotas.label(slot);
+        let held = self.held.entry(slot).or_default();
+        if held.entries < max {
+            return Ok(Ok(()));
+        }
+        let oldest = self
+            .ages
+            .get(&entry.origin)
+            .and_then(|ages| ages.first_key_value())
+            .map(|(_, ip)| *ip);
+        let Some(oldest) = oldest.filter(|_| evicts(entry.origin)) else {
+            held.rejected += 1;
+            if !std::mem::replace(&mut held.full, true) {
+                warn!("{label} is at its --blocklist-quota of {max} entries, refusing new ones");
+            }
+            return Ok(Err(Applied::OverQuota(max)));
+        };
+        let warned = held.full;
+        held.evicted += 1;
+        if !warned {
+            warn!(
+                "{label} is at its --blocklist-quota of {max} entries, evicting the oldest for \
+                 new ones"
+            );
+        }
+        debug!(
+            "{}: {label} entry evicted for {}",
+            Masked(oldest),
+            Masked(ip)
+        );
+        self.remove_inner(oldest, false)?;
+        // Still full once the new entry is in, the eviction doesn't count as room
+        self.held.entry(slot).or_default().full = true;
+        Ok(Ok(()))
+    }
+
+    // Counts `entry` against its quota
+    fn hold(&mut self, ip: Ipv4Addr, entry: &Entry) {
+        self.held.entry(slot(entry)).or_default().entries += 1;
+        if evicts(entry.origin) && self.quotas.of(slot(entry)).is_some() {
+            self.stamp += 1;
+            self.stamps.insert(ip, self.stamp);
+            self.ages
+                .entry(entry.origin)
+                .or_default()
+                .insert(self.stamp, ip);
+        }
+    }
+
+    // Takes `entry`, gone from the map, off its quota
+    fn release(&mut self, ip: Ipv4Addr, entry: &Entry) {
+        let slot = slot(entry);
+        if let Some(held) = self.held.get_mut(&slot) {
+            held.entries = held.entries.saturating_sub(1);
+            if self.quotas.of(slot).is_some_and(|max| held.entries < max) {
+                held.full = false;
+            }
+        }
+        if let Some(stamp) = self.stamps.remove(&ip)
+            && let Some(ages) = self.ages.get_mut(&entry.origin)
+        {
+            ages.remove(&stamp);
+        }
+    }
+
+    // Everything that follows an entry reaching the map
+    fn written(&mut self, ip: Ipv4Addr, entry: Entry) -> anyhow::Result<()> {
+        let origin = entry.origin;
+        if let Some(old) = self.entries.insert(ip, entry.clone()) {
+            self.release(ip, &old);
+            if old.origin != origin {
+                info!(
+                    "{}: {} replaced by {}",
+                    Masked(ip),
+                    old.origin.name(),
+                    origin.name()
+                );
+            }
+        }
+        self.hold(ip, &entry);
+        if let Some(journal) = &mut self.journal
+            && journal::persisted(origin)
+        {
+            let _span = Span::child("journal append");
+            journal.added(ip, &entry);
+        }
+        // Nobody listening is fine
+        let _ = self.events.send(BlocklistEvent::Added(ip, entry.clone()));
+        self.track_ban(ip, entry)
+    }
+
+    /// Inserts many entries, as [`insert_entry`](Self::insert_entry) one by one would, with
+    /// the map writes batched where the kernel can. Returns how many were written.
+    pub fn insert_bulk(&mut self, entries: Vec<(Ipv4Addr, Entry)>) -> anyhow::Result<usize> {
+        let mut span = Span::child("blocklist bulk insert");
+        span.set("entries", entries.len());
+        let started = Instant::now();
+        let mut written = 0;
+        let result = span.in_scope(|| -> anyhow::Result<()> {
+            for chunk in entries.chunks(batch::CHUNK) {
+                written += self.insert_chunk(chunk)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = &result {
+            span.fail(format_args!("{e:#}"));
+        }
+        result?;
+        let secs = started.elapsed().as_secs_f64();
+        let how = if self.batched {
+            "batched"
+        } else {
+            "one by one"
+        };
+        let rate = written as f64 / secs.max(1e-6);
+        if written >= batch::CHUNK {
+            info!("wrote {written} blocklist entries {how} in {secs:.2}s, {rate:.0}/s");
+        } else {
+            debug!("wrote {written} blocklist entries {how} in {secs:.3}s");
+        }
+        Ok(written)
+    }
+
+    fn insert_chunk(&mut self, chunk: &[(Ipv4Addr, Entry)]) -> anyhow::Result<usize> {
+        if !self.batched {
+            let mut written = 0;
+            for (ip, entry) in chunk {
+                if self.write_entry(*ip, entry.clone())? == Applied::Written {
+                    written += 1;
+                }
+            }
+            return Ok(written);
+        }
+        let mut staged = Vec::with_capacity(chunk.len());
+        let mut keys = Vec::with_capacity(chunk.len());
+        let mut values = Vec::with_capacity(chunk.len());
+        // An address given twice in one chunk is checked against its first entry
+        let mut seen = HashSet::with_capacity(chunk.len());
+        let mut again = Vec::new();
+        // Staged entries by quota, which count once written
+        let mut staging: StdHashMap<Slot, usize> = StdHashMap::new();
+        for (ip, entry) in chunk {
+            let key = u32::from(*ip);
+            if seen.contains(&key) {
+                again.push((*ip, entry.clone()));
+                continue;
+            }
+            if let Ok(value) = self.admit(*ip, entry) {
+                let slot = slot(entry);
+                if let Some(max) = self.quotas.of(slot)
+                    && !self.get(*ip).is_some_and(|old| self::slot(old) == slot)
+                {
+                    let staged = staging.entry(slot).or_default();
+                    // Evicted for or refused one by one, once the staged ones count
+                    if self.held.get(&slot).map_or(0, |held| held.entries) + *staged >= max {
+                        again.push((*ip, entry.clone()));
+                        continue;
+                    }
+                    *staged += 1;
+                }
+                seen.insert(key);
+                staged.push((*ip, entry.clone()));
+                keys.push(key);
+                values.push(value);
+            }
+        }
+        if let Some(bloom) = &mut self.bloom {
+            for key in &keys {
+                bloom.insert(*key)?;
+            }
+        }
+        let result = trace::within("blocklist map write", || {
+            batch::update(self.blocklist.map(), &keys, &values)
+        });
+        let done = match &result {
+            Ok(()) => staged.len(),
+            Err((done, _)) => *done,
+        };
+        let mut written = 0;
+        for (ip, entry) in staged.drain(..done) {
+            self.written(ip, entry)?;
+            written += 1;
+        }
+        if let Err((_, e)) = result {
+            let ip = staged[0].0;
+            return Err(anyhow::Error::new(e)
+                .context(format!("failed to write the entry for {}", Masked(ip))));
+        }
+        for (ip, entry) in again {
+            if self.write_entry(ip, entry)? == Applied::Written {
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    // Adds a written entry to the ban history of `ip`, and escalates the ban when the source
+    // keeps coming back
+    fn track_ban(&mut self, ip: Ipv4Addr, entry: Entry) -> anyhow::Result<()> {
+        let Some(settings) = self.recidivists else {
+            return Ok(());
+        };
+        let now = timebase::unix_now();
+        // An allow or a cluster ban replacing a ban ends it
+        if !recidivist::tracked(entry.origin) {
+            if self
+                .history
+                .update(&ip, |history| history.ended(now, false))
+                == Some(true)
+                && let Some(journal) = &mut self.journal
+            {
+                journal.history(ip, self.history.get(&ip));
+            }
+            return Ok(());
+        }
+        if !self.history.contains_key(&ip) {
+            if entry.expires.is_none() {
+                return Ok(());
+            }
+            self.keep_history(ip, History::default());
+        }
+        let (escalate, history) = self
+            .history
+            .update(&ip, |history| {
+                let escalate = history.banned(entry.origin, entry.expires, now, &settings);
+                (escalate, history.clone())
+            })
+            .expect("just added");
+        if let Some(journal) = &mut self.journal {
+            journal.history(ip, Some(&history));
+        }
+        if !escalate {
+            return Ok(());
+        }
+        let entry = Entry {
+            expires: settings.expires(now),
+            ..entry
+        };
+        match settings.ttl {
+            0 => warn!(
+                "RECIDIVIST {}: back within {}s of a ban running out {} times in a row, \
+                 blocked permanently. {history}",
+                Masked(ip),
+                settings.window,
+                history.returns
+            ),
+            ttl => warn!(
+                "RECIDIVIST {}: back within {}s of a ban running out {} times in a row, \
+                 blocked for {ttl}s. {history}",
+                Masked(ip),
+                settings.window,
+                history.returns
+            ),
+        }
+        let _ = self.events.send(BlocklistEvent::Recidivist {
+            ip,
+            entry: entry.clone(),
+            history,
+        });
+        // Holds, so this only updates the history
+        self.insert_entry(ip, entry).map(|_| ())
+    }
+
+    /// Inserts every entry, or none: when a write fails, the entries written before it are
+    /// put back the way they were. Returns how many were written.
+    pub fn insert_all(&mut self, entries: &[(Ipv4Addr, Entry)]) -> anyhow::Result<usize> {
+        let mut undo = Vec::new();
+        for (ip, entry) in entries {
+            let before = self.entries.get(ip).cloned();
+            match self.insert_entry(*ip, entry.clone()) {
+                Ok(Applied::Written) => undo.push((*ip, before)),
+                Ok(_) => {}
+                Err(e) => {
+                    for (ip, before) in undo.into_iter().rev() {
+                        let _ = self.remove(ip);
+                        if let Some(before) = before {
+                            let _ = self.insert_entry(ip, before);
+                        }
+                    }
+                    return Err(e.context(format!("failed to write the entry for {ip}")));
+                }
+            }
+        }
+        Ok(undo.len())
+    }
+
+    /// Removes the entry for `ip`. Returns the origin of the removed entry.
+    pub fn remove(&mut self, ip: Ipv4Addr) -> anyhow::Result<Option<Origin>> {
+        self.remove_inner(ip, false)
+    }
+
+    fn remove_inner(&mut self, ip: Ipv4Addr, expired: bool) -> anyhow::Result<Option<Origin>> {
+        let Some(entry) = self.entries.remove(&ip) else {
+            return Ok(None);
+        };
+        let mut span = Span::child("blocklist remove");
+        span.set("client.address", Masked(ip));
+        span.set("origin", entry.origin.name());
+        let removed = span.in_scope(|| {
+            trace::within("blocklist map write", || {
+                self.blocklist.remove(&u32::from(ip))
+            })
+        });
+        if let Err(e) = &removed {
+            span.fail(e);
+        }
+        removed?;
+        self.removed(ip, &entry, expired);
+        Ok(Some(entry.origin))
+    }
+
+    // Everything that follows an entry leaving the map
+    fn removed(&mut self, ip: Ipv4Addr, entry: &Entry, expired: bool) {
+        self.release(ip, entry);
+        if let Some(bloom) = &mut self.bloom {
+            bloom.removed();
+        }
+        if let Some(journal) = &mut self.journal
+            && journal::persisted(entry.origin)
+        {
+            let _span = Span::child("journal append");
+            journal.removed(ip, entry.origin);
+        }
+        let _ = self.events.send(BlocklistEvent::Removed {
+            ip,
+            origin: entry.origin,
+            expired,
+        });
+        if self.recidivists.is_some()
+            && self
+                .history
+                .update(&ip, |history| history.ended(timebase::unix_now(), expired))
+                == Some(true)
+            && let Some(journal) = &mut self.journal
+        {
+            journal.history(ip, self.history.get(&ip));
+        }
+    }
+
+    /// Removes entries whose TTL ended at or before `now` (unix seconds).
+    pub fn expire(&mut self, now: u64) -> anyhow::Result<usize> {
+        let doomed: Vec<Ipv4Addr> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires.is_some_and(|at| at <= now))
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in &doomed {
+            if let Some(origin) = self.remove_inner(*ip, true)? {
+                info!("{}: {} entry expired", Masked(*ip), origin.name());
+            }
+        }
+        if let Some(settings) = self.recidivists {
+            let forgotten: Vec<Ipv4Addr> = self
+                .history
+                .iter()
+                .filter(|(_, history)| !history.relevant(now, &settings))
+                .map(|(ip, _)| *ip)
+                .collect();
+            for ip in forgotten {
+                self.history.remove(&ip);
+                if let Some(journal) = &mut self.journal {
+                    journal.history(ip, None);
+                }
+            }
+        }
+        Ok(doomed.len())
+    }
+
+    /// Removes every entry whose origin matches `pred`, returning how many went.
+    pub fn remove_where(&mut self, pred: impl Fn(Origin) -> bool) -> anyhow::Result<usize> {
+        let doomed: Vec<Ipv4Addr> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| pred(entry.origin))
+            .map(|(ip, _)| *ip)
+            .collect();
+        if !self.batched {
+            for ip in &doomed {
+                self.remove(*ip)?;
+            }
+            return Ok(doomed.len());
+        }
+        let keys: Vec<u32> = doomed.iter().map(|ip| u32::from(*ip)).collect();
+        let result = trace::within("blocklist map write", || {
+            batch::delete(self.blocklist.map(), &keys)
+        });
+        let done = match &result {
+            Ok(()) => doomed.len(),
+            Err((done, _)) => *done,
+        };
+        for ip in &doomed[..done] {
+            if let Some(entry) = self.entries.remove(ip) {
+                self.removed(*ip, &entry, false);
+            }
+        }
+        if let Err((_, e)) = result {
+            return Err(anyhow::Error::new(e).context(format!(
+                "failed to remove the entry for {}",
+                Masked(doomed[done])
+            )));
+        }
+        Ok(done)
+    }
+
+    /// Adds a management network. Its members are never blocked, whatever else is in the
+    /// blocklist; only a manual allow sits above it.
+    pub fn add_management(&mut self, cidr: Ipv4Cidr) -> anyhow::Result<()> {
+        if self.mgmt_cidrs.contains(&cidr) {
+            return Ok(());
+        }
+        let key = Key::new(u32::from(cidr.prefix_len()), u32::from(cidr.addr()).to_be());
+        self.mgmt.insert(&key, 1, 0)?;
+        for (ip, entry) in &self.entries {
+            if entry.origin < Origin::Management && cidr.contains(*ip) {
+                warn!(
+                    "{} entry for {} is shadowed by management network {cidr}",
+                    entry.origin.name(),
+                    Masked(*ip)
+                );
+            }
+        }
+        self.mgmt_cidrs.push(cidr);
+        Ok(())
+    }
+
+    /// Removes a management network added with [`add_management`](Self::add_management).
+    pub fn remove_management(&mut self, cidr: Ipv4Cidr) -> anyhow::Result<bool> {
+        let Some(pos) = self.mgmt_cidrs.iter().position(|c| *c == cidr) else {
+            return Ok(false);
+        };
+        let key = Key::new(u32::from(cidr.prefix_len()), u32::from(cidr.addr()).to_be());
+        self.mgmt.remove(&key)?;
+        self.mgmt_cidrs.remove(pos);
+        Ok(true)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (Ipv4Addr, &Entry)> + '_ {
+        self.entries.iter().map(|(ip, entry)| (*ip, entry))
+    }
+
+    /// Up to `limit` entries with addresses after `after`, in order. One pass over the
+    /// entries that keeps no more than `limit` of them, so a page of a huge blocklist costs
+    /// the memory of the page.
+    pub fn page(&self, after: Option<Ipv4Addr>, limit: usize) -> Vec<(Ipv4Addr, Entry)> {
+        page(self.entries.keys().copied(), after, limit)
+            .into_iter()
+            .map(|ip| (ip, self.entries[&ip].clone()))
+            .collect()
+    }
+
+    pub fn get(&self, ip: Ipv4Addr) -> Option<&Entry> {
+        self.entries.get(&ip)
+    }
+
+    /// What `BLOCKLIST` itself holds under `key`, whatever this handle thinks it wrote.
+    pub fn kernel_entry(&self, key: u32) -> anyhow::Result<Option<BlockEntry>> {
+        match self.blocklist.get(&key, 0) {
+            Ok(value) => Ok(Some(value)),
+            Err(aya::maps::MapError::KeyNotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The map the handle writes, the one the program reads once resized.
+    pub fn kernel_map(&self) -> &HashMap<MapData, u32, BlockEntry> {
+        &self.blocklist
+    }
+
+    /// Writes to `blocklist` from now on, which has to hold every entry already. The map
+    /// before is closed.
+    pub fn replace_kernel_map(&mut self, blocklist: HashMap<MapData, u32, BlockEntry>) {
+        self.blocklist = blocklist;
+    }
+
+    pub fn management(&self) -> &[Ipv4Cidr] {
+        &self.mgmt_cidrs
+    }
+}
+
+/// `--blocklist-quota`: the most entries each origin, or each feed, may hold. Origins without
+/// one are unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct Quotas {
+    origins: StdHashMap<Origin, usize>,
+    /// By feed id.
+    feeds: Vec<Option<usize>>,
+    /// Feed names by id.
+    names: Vec<String>,
+}
+
+impl Quotas {
+    /// From `KEY=MAX` pairs, KEY an origin name, `feed:NAME` for one of `feeds` or `feed`
+    /// for each feed without one of its own.
+    pub fn new(quotas: &[(String, usize)], feeds: &[Feed]) -> anyhow::Result<Self> {
+        let mut every_feed = None;
+        let mut by_feed = vec![None; feeds.len()];
+        let mut origins = StdHashMap::new();
+        for (key, max) in quotas {
+            if key == "feed" {
+                every_feed = Some(*max);
+            } else if let Some(name) = key.strip_prefix("feed:") {
+                let id = feeds
+                    .iter()
+                    .position(|feed| feed.name == name)
+                    .with_context(|| format!("--blocklist-quota {key}: no --feed named {name}"))?;
+                by_feed[id] = Some(*max);
+            } else {
+                match Origin::from_name(key) {
+                    Some(Origin::Management) => {
+                        bail!("--blocklist-quota {key}: management networks have no quota")
+                    }
+                    Some(Origin::Feed) | None => bail!(
+                        "--blocklist-quota {key}: unknown origin, use feed, feed:NAME, \
+                         cluster, auto-ban, policy-module, manual-block or manual-allow"
+                    ),
+                    Some(origin) => {
+                        origins.insert(origin, *max);
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            origins,
+            feeds: by_feed.into_iter().map(|max| max.or(every_feed)).collect(),
+            names: feeds.iter().map(|feed| feed.name.clone()).collect(),
+        })
+    }
+
+    fn of(&self, (origin, feed): Slot) -> Option<usize> {
+        match origin {
+            Origin::Feed => self.feeds.get(usize::from(feed)).copied().flatten(),
+            origin => self.origins.get(&origin).copied(),
+        }
+    }
+
+    fn slots(&self) -> impl Iterator<Item = Slot> + '_ {
+        let feeds = (0..self.feeds.len() as u8).map(|id| (Origin::Feed, id));
+        let origins = self.origins.keys().map(|origin| (*origin, 0));
+        feeds.chain(origins).filter(|slot| self.of(*slot).is_some())
+    }
+
+    fn name(&self, feed: u8) -> &str {
+        self.names
+            .get(usize::from(feed))
+            .map_or("?", String::as_str)
+    }
+
+    // `auto-ban`, `feed:NAME`
+    fn label(&self, (origin, feed): Slot) -> String {
+        match origin {
+            Origin::Feed => format!("feed:{}", self.name(feed)),
+            origin => origin.name().to_owned(),
+        }
+    }
+}
+
+/// How full one origin, or one feed, of the blocklist is.
+#[derive(Clone, Debug)]
+pub struct Occupancy {
+    pub origin: Origin,
+    /// Name of the feed, for feed entries.
+    pub feed: Option<String>,
+    pub entries: usize,
+    pub quota: Option<usize>,
+    /// Writes refused and feed lines left out for the quota.
+    pub rejected: u64,
+    /// Entries removed to make room for newer ones of their origin.
+    pub evicted: u64,
+}
+
+impl Occupancy {
+    /// `auto-ban`, `feed:NAME`, ...
+    pub fn label(&self) -> String {
+        match &self.feed {
+            Some(feed) => format!("feed:{feed}"),
+            None => self.origin.name().to_owned(),
+        }
+    }
+}
+
+/// A `--feed`: a file of addresses and the name its drops are counted under.
+#[derive(Clone, Debug)]
+pub struct Feed {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl FromStr for Feed {
+    type Err = anyhow::Error;
+
+    /// `NAME=PATH`, or just `PATH` to name the feed after the file.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = match s.split_once('=') {
+            Some((name, path)) if !name.contains('/') => (name.to_owned(), PathBuf::from(path)),
+            _ => {
+                let path = PathBuf::from(s);
+                let stem = path.file_stem().context("feed path has no file name")?;
+                (stem.to_string_lossy().into_owned(), path)
+            }
+        };
+        let valid = name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.');
+        if name.is_empty() || !valid {
+            bail!("invalid feed name {name:?}, use letters, digits, -, _ and .");
+        }
+        Ok(Self { name, path })
+    }
+}
+
+/// Reads a feed file: one IPv4 address per line, `#` starts a comment.
+pub fn read_feed(path: &Path) -> anyhow::Result<Vec<Ipv4Addr>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read feed {}", path.display()))?;
+    let mut ips = Vec::new();
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let ip = line.parse().with_context(|| {
+            format!(
+                "{}:{}: invalid address {line:?}",
+                path.display(),
+                lineno + 1
+            )
+        })?;
+        ips.push(ip);
+    }
+    Ok(ips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The handle's state for one address: its entry, and whether a management network covers
+    // it. Writes take the handle's own decisions, only the maps are left out
+    #[derive(Default)]
+    struct Address {
+        held: Option<Entry>,
+        managed: bool,
+    }
+
+    impl Address {
+        fn winner(&self) -> Option<Origin> {
+            winner(self.held.as_ref().map(|entry| entry.origin), self.managed)
+        }
+
+        // As `write_entry` and `add_management` take it
+        fn apply(&mut self, origin: Origin) -> Result<(), Applied> {
+            if origin == Origin::Management {
+                self.managed = true;
+                return Ok(());
+            }
+            let entry = Entry::new(origin);
+            let value = decide(self.winner(), self.held.as_ref(), &entry)?;
+            assert_eq!(
+                (value.origin, value.action),
+                (origin as u8, origin.action())
+            );
+            self.held = Some(entry);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn the_higher_origin_wins_whichever_came_first() {
+        for first in Origin::ALL {
+            for second in Origin::ALL.into_iter().filter(|&o| o != first) {
+                let names = format!("{} then {}", first.name(), second.name());
+                let mut address = Address::default();
+                assert_eq!(address.apply(first), Ok(()), "{names}");
+                // Only management networks go in whatever holds the address
+                let expected = if second > first || second == Origin::Management {
+                    Ok(())
+                } else {
+                    Err(Applied::Suppressed(first))
+                };
+                assert_eq!(address.apply(second), expected, "{names}");
+                let winner = first.max(second);
+                assert_eq!(address.winner(), Some(winner), "{names}");
+                if winner != Origin::Management {
+                    assert_eq!(address.apply(winner), Err(Applied::Unchanged), "{names}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_manual_allow_beats_the_network_and_the_network_every_block() {
+        assert_eq!(winner(None, false), None);
+        assert_eq!(winner(None, true), Some(Origin::Management));
+        for held in Origin::ALL.into_iter().filter(|&o| o != Origin::Management) {
+            let managed = if held == Origin::ManualAllow {
+                held
+            } else {
+                Origin::Management
+            };
+            assert_eq!(winner(Some(held), true), Some(managed), "{}", held.name());
+            assert_eq!(winner(Some(held), false), Some(held), "{}", held.name());
+        }
+    }
+}
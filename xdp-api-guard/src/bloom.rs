@@ -0,0 +1,288 @@
+//! `--blocklist-bloom`: a Bloom filter of every `BLOCKLIST` key in front of it, so the common
+//! case of a source that isn't listed costs one array lookup however long the list is.
+//!
+//! A Bloom filter can't forget an address, so removed entries leave their bits behind and
+//! only cost false positives. `BLOOM` has two halves for that: now and then the spare one is
+//! rebuilt from the handle's entries while the kernel keeps reading the current one, and
+//! `Config::bloom_half` is switched over once it's complete. Bits are set before the entry
+//! they stand for is written and the old half is left alone until the next rebuild, so no
+//! listed address is ever missed on the way.
+
+use std::time::Duration;
+
+use anyhow::bail;
+use aya::maps::{Array, MapData};
+use log::debug;
+use xdp_api_guard_common::{BLOOM_WORDS, bloom_probe};
+
+use crate::control::ControlState;
+
+/// Default of `--bloom-rebuild-secs`.
+pub const DEFAULT_REBUILD: Duration = Duration::from_secs(300);
+
+pub struct Bloom {
+    map: Array<MapData, u64>,
+    halves: Halves,
+}
+
+impl Bloom {
+    /// Takes over `BLOOM`, fresh from the loader. Both halves are empty and half 0 is current.
+    pub fn new(map: Array<MapData, u64>) -> anyhow::Result<Self> {
+        if map.len() < 2 * BLOOM_WORDS {
+            bail!(
+                "BLOOM has {} entries, --blocklist-bloom needs {}",
+                map.len(),
+                2 * BLOOM_WORDS
+            );
+        }
+        Ok(Self {
+            map,
+            halves: Halves::new(),
+        })
+    }
+
+    /// Sets the bits of `addr` in the current half. Call before writing its entry.
+    pub fn insert(&mut self, addr: u32) -> anyhow::Result<()> {
+        let map = &mut self.map;
+        self.halves
+            .insert(addr, |index, value| Ok(map.set(index, value, 0)?))
+    }
+
+    pub fn removed(&mut self) {
+        self.halves.stale += 1;
+    }
+
+    /// Rebuilds the spare half from `addrs` and makes it current through `switch`, which
+    /// gets the half to put in `Config::bloom_half`. Returns the words written, or `None` if
+    /// nothing was removed since the last rebuild.
+    pub fn rebuild(
+        &mut self,
+        addrs: impl Iterator<Item = u32>,
+        switch: impl FnOnce(u8) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Option<usize>> {
+        let map = &mut self.map;
+        self.halves
+            .rebuild(addrs, |index, value| Ok(map.set(index, value, 0)?), switch)
+    }
+
+    /// One line for `status`: the current half and how full it is.
+    pub fn describe(&self) -> String {
+        let set: u32 = self.halves.words[usize::from(self.halves.half)]
+            .iter()
+            .map(|word| word.count_ones())
+            .sum();
+        let total = u64::from(BLOOM_WORDS) * 64;
+        format!(
+            "blocklist bloom filter: half {}, {:.1}% of bits set, {} removals since the last \
+             rebuild",
+            self.halves.half,
+            f64::from(set) * 100.0 / total as f64,
+            self.halves.stale
+        )
+    }
+}
+
+// What each half of the map holds, so a rebuild only writes the words that change. Writes go
+// through `write` with the index in the map, and are only taken as done once it returns Ok.
+struct Halves {
+    words: [Vec<u64>; 2],
+    half: u8,
+    // Entries removed since the last rebuild, each leaves bits behind
+    stale: usize,
+}
+
+impl Halves {
+    fn new() -> Self {
+        Self {
+            words: [vec![0; BLOOM_WORDS as usize], vec![0; BLOOM_WORDS as usize]],
+            half: 0,
+            stale: 0,
+        }
+    }
+
+    fn insert(
+        &mut self,
+        addr: u32,
+        mut write: impl FnMut(u32, u64) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let (word, bits) = bloom_probe(addr);
+        let value = &mut self.words[usize::from(self.half)][word as usize];
+        if *value & bits != bits {
+            write(u32::from(self.half) * BLOOM_WORDS + word, *value | bits)?;
+            *value |= bits;
+        }
+        Ok(())
+    }
+
+    fn rebuild(
+        &mut self,
+        addrs: impl Iterator<Item = u32>,
+        mut write: impl FnMut(u32, u64) -> anyhow::Result<()>,
+        switch: impl FnOnce(u8) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Option<usize>> {
+        if self.stale == 0 {
+            return Ok(None);
+        }
+        let mut fresh = vec![0; BLOOM_WORDS as usize];
+        for addr in addrs {
+            let (word, bits) = bloom_probe(addr);
+            fresh[word as usize] |= bits;
+        }
+        let spare = 1 - self.half;
+        let old = &mut self.words[usize::from(spare)];
+        let mut written = 0;
+        for (word, (bits, had)) in fresh.iter().zip(old.iter_mut()).enumerate() {
+            if bits != had {
+                write(u32::from(spare) * BLOOM_WORDS + word as u32, *bits)?;
+                *had = *bits;
+                written += 1;
+            }
+        }
+        switch(spare)?;
+        self.half = spare;
+        self.stale = 0;
+        Ok(Some(written))
+    }
+}
+
+/// Rebuilds the filter if entries were removed since the last time. The config lock is taken
+/// before the blocklist's, as everywhere else both are held.
+pub fn rebuild(control: &ControlState) -> anyhow::Result<()> {
+    let mut config = control.config.lock().unwrap();
+    let mut blocklist = control.blocklist.lock().unwrap();
+    let rebuilt = blocklist.rebuild_bloom(|half| {
+        config.update(|cfg| cfg.bloom_half = half)?;
+        Ok(())
+    })?;
+    if let Some(written) = rebuilt {
+        debug!("rebuilt the blocklist bloom filter, {written} words changed");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::BTreeSet};
+
+    use super::*;
+
+    // The kernel's side: the map, and the half `Config::bloom_half` names
+    struct Kernel {
+        words: Vec<u64>,
+        half: u8,
+    }
+
+    impl Kernel {
+        fn new() -> RefCell<Self> {
+            RefCell::new(Self {
+                words: vec![0; 2 * BLOOM_WORDS as usize],
+                half: 0,
+            })
+        }
+
+        // As `bloom_may_hold` in the program
+        fn may_hold(&self, addr: u32) -> bool {
+            let (word, bits) = bloom_probe(addr);
+            self.words[(u32::from(self.half) * BLOOM_WORDS + word) as usize] & bits == bits
+        }
+    }
+
+    fn write(kernel: &RefCell<Kernel>) -> impl FnMut(u32, u64) -> anyhow::Result<()> {
+        move |index, value| {
+            kernel.borrow_mut().words[index as usize] = value;
+            Ok(())
+        }
+    }
+
+    // Spread over the address space like the finalizer spreads them over words
+    fn addrs(seed: u32, n: u32) -> impl Iterator<Item = u32> {
+        (0..n).map(move |i| (seed.wrapping_mul(0x9e37_79b9) ^ i).wrapping_mul(0x85eb_ca6b))
+    }
+
+    #[test]
+    fn no_listed_address_is_missed_across_rebuilds() {
+        let kernel = Kernel::new();
+        let mut halves = Halves::new();
+        let mut listed = BTreeSet::new();
+        let mut gone = Vec::new();
+        for round in 0..6 {
+            for addr in addrs(round, 300) {
+                halves.insert(addr, write(&kernel)).unwrap();
+                listed.insert(addr);
+                assert!(kernel.borrow().may_hold(addr));
+            }
+            let doomed: Vec<u32> = listed.iter().copied().step_by(3).collect();
+            for addr in doomed {
+                listed.remove(&addr);
+                gone.push(addr);
+                halves.stale += 1;
+            }
+            // Every write lands in the spare half, the kernel reads the other one until the switch
+            let mut writes = 0;
+            let rebuilt = halves
+                .rebuild(
+                    listed.iter().copied(),
+                    |index, value| {
+                        write(&kernel)(index, value)?;
+                        writes += 1;
+                        if writes % 64 == 0 {
+                            let kernel = kernel.borrow();
+                            assert!(listed.iter().all(|&addr| kernel.may_hold(addr)));
+                        }
+                        Ok(())
+                    },
+                    |half| {
+                        kernel.borrow_mut().half = half;
+                        Ok(())
+                    },
+                )
+                .unwrap();
+            assert_eq!(rebuilt, Some(writes));
+            let kernel = kernel.borrow();
+            assert_eq!(kernel.half, halves.half);
+            assert!(listed.iter().all(|&addr| kernel.may_hold(addr)));
+            // What was removed before this rebuild has its bits cleared, a few may share them
+            let stale = gone.iter().filter(|&&addr| kernel.may_hold(addr)).count();
+            assert!(stale <= gone.len() / 100, "{stale} of {}", gone.len());
+        }
+    }
+
+    #[test]
+    fn without_removals_nothing_is_rebuilt() {
+        let kernel = Kernel::new();
+        let mut halves = Halves::new();
+        for addr in addrs(1, 100) {
+            halves.insert(addr, write(&kernel)).unwrap();
+        }
+        let rebuilt = halves
+            .rebuild(
+                addrs(1, 100),
+                |_, _| panic!("written"),
+                |_| panic!("switched"),
+            )
+            .unwrap();
+        assert_eq!(rebuilt, None);
+        assert_eq!(halves.half, 0);
+    }
+
+    #[test]
+    fn a_failed_write_is_tried_again() {
+        let kernel = Kernel::new();
+        let mut halves = Halves::new();
+        let failed = halves.insert(7, |_, _| anyhow::bail!("map full"));
+        assert!(failed.is_err());
+        halves.insert(7, write(&kernel)).unwrap();
+        assert!(kernel.borrow().may_hold(7));
+        // Nor is a half whose rebuild failed switched to
+        halves.stale += 1;
+        let failed = halves.rebuild(addrs(2, 10), |_, _| anyhow::bail!("map gone"), |_| Ok(()));
+        assert!(failed.is_err());
+        assert_eq!(halves.half, 0);
+        let rebuilt = halves.rebuild(addrs(2, 10), write(&kernel), |half| {
+            kernel.borrow_mut().half = half;
+            Ok(())
+        });
+        assert!(rebuilt.unwrap().is_some());
+        assert!(addrs(2, 10).all(|addr| kernel.borrow().may_hold(addr)));
+    }
+}
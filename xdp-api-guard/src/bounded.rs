@@ -0,0 +1,282 @@
+//! Limits on the state userspace keeps per source: ban histories and idempotency keys.
+//!
+//! A flood from spoofed sources bans new addresses faster than their histories are forgotten,
+//! and running out of memory then is the worst time to. Each store caps its entries, and all
+//! of them share `--state-memory`, counted from an estimate of every entry's size. A store at
+//! either limit evicts its least recently used entry the caller doesn't want kept, e.g. the
+//! history of a source that is banned right now; kept ones only go when nothing else is left.
+//! Evictions are counted per store on `/metrics`, and `/healthz` reports `state_pressure` for
+//! `PRESSURE_SECS` after one. Caches share the budget too, but what they evict only costs a
+//! lookup, so their evictions aren't pressure.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    net::{IpAddr, Ipv4Addr},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+use crate::timebase;
+
+/// `--state-memory` unless set.
+pub const DEFAULT_BUDGET: usize = 256 << 20;
+
+/// Seconds `/healthz` reports pressure for after an eviction.
+pub const PRESSURE_SECS: u64 = 60;
+
+static BUDGET: Budget = Budget {
+    limit: AtomicUsize::new(DEFAULT_BUDGET),
+    used: AtomicUsize::new(0),
+    last_eviction: AtomicU64::new(0),
+    stores: Mutex::new(Vec::new()),
+};
+
+struct Budget {
+    limit: AtomicUsize,
+    used: AtomicUsize,
+    // Unix time, 0 for never
+    last_eviction: AtomicU64,
+    stores: Mutex<Vec<Arc<StoreStats>>>,
+}
+
+/// Sets the budget all stores share, in bytes.
+pub fn set_budget(bytes: usize) {
+    BUDGET.limit.store(bytes, Ordering::Relaxed);
+}
+
+pub fn budget() -> usize {
+    BUDGET.limit.load(Ordering::Relaxed)
+}
+
+/// Estimated bytes of all stores together.
+pub fn used() -> usize {
+    BUDGET.used.load(Ordering::Relaxed)
+}
+
+/// Whether any store evicted an entry in the last `PRESSURE_SECS`.
+pub fn under_pressure() -> bool {
+    match BUDGET.last_eviction.load(Ordering::Relaxed) {
+        0 => false,
+        at => timebase::unix_now().saturating_sub(at) < PRESSURE_SECS,
+    }
+}
+
+pub fn stores() -> Vec<Arc<StoreStats>> {
+    BUDGET.stores.lock().unwrap().clone()
+}
+
+/// What `/metrics` shows of one store.
+#[derive(Debug)]
+pub struct StoreStats {
+    pub name: &'static str,
+    pub entries: AtomicUsize,
+    pub bytes: AtomicUsize,
+    pub evictions: AtomicU64,
+}
+
+/// Heap memory a key or value holds beyond its own size, for the budget. An estimate: a
+/// little under is fine, it's the entries by the million that matter.
+pub trait Weigh {
+    fn heap_bytes(&self) -> usize {
+        0
+    }
+}
+
+impl Weigh for Ipv4Addr {}
+
+impl Weigh for IpAddr {}
+
+impl Weigh for String {
+    fn heap_bytes(&self) -> usize {
+        self.capacity()
+    }
+}
+
+/// A map with at most `cap` entries and a share of the budget, least recently used first out.
+pub struct Bounded<K, V> {
+    cap: usize,
+    stats: Arc<StoreStats>,
+    map: HashMap<K, Slot<V>>,
+    // Stamps of the entries, oldest first
+    order: BTreeMap<u64, K>,
+    next: u64,
+    // Evicting doesn't count as pressure
+    cache: bool,
+}
+
+struct Slot<V> {
+    value: V,
+    weight: usize,
+    stamp: u64,
+}
+
+impl<K: Clone + Eq + Hash + Weigh, V: Weigh> Bounded<K, V> {
+    pub fn new(name: &'static str, cap: usize) -> Self {
+        let stats = Arc::new(StoreStats {
+            name,
+            entries: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            evictions: AtomicU64::new(0),
+        });
+        BUDGET.stores.lock().unwrap().push(stats.clone());
+        Self {
+            cap: cap.max(1),
+            stats,
+            map: HashMap::new(),
+            order: BTreeMap::new(),
+            next: 0,
+            cache: false,
+        }
+    }
+
+    /// A store of answers that can be had again, whose evictions aren't pressure.
+    pub fn cache(name: &'static str, cap: usize) -> Self {
+        let mut store = Self::new(name, cap);
+        store.cache = true;
+        store
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.map.get(key).map(|slot| &slot.value)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.map.iter().map(|(key, slot)| (key, &slot.value))
+    }
+
+    /// The least recently used entry.
+    pub fn oldest(&self) -> Option<(&K, &V)> {
+        let (_, key) = self.order.first_key_value()?;
+        self.map
+            .get_key_value(key)
+            .map(|(key, slot)| (key, &slot.value))
+    }
+
+    /// Changes the entry of `key`, if there is one, and makes it the most recently used. A
+    /// value that grows can take the store over its limits until the next insert.
+    pub fn update<R>(&mut self, key: &K, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        let stamp = self.next;
+        let slot = self.map.get_mut(key)?;
+        let result = f(&mut slot.value);
+        let weight = weight(key, &slot.value);
+        self.order.remove(&slot.stamp);
+        slot.stamp = stamp;
+        let before = std::mem::replace(&mut slot.weight, weight);
+        self.order.insert(stamp, key.clone());
+        self.next += 1;
+        self.account(weight as isize - before as isize, 0);
+        Some(result)
+    }
+
+    /// Inserts or replaces the entry of `key`. Making room for a new one evicts the least
+    /// recently used entries `keep` says no to first, and returns whatever it evicted.
+    pub fn insert(&mut self, key: K, value: V, keep: impl Fn(&K, &V) -> bool) -> Vec<(K, V)> {
+        if self.map.contains_key(&key) {
+            self.update(&key, |slot| *slot = value);
+            return Vec::new();
+        }
+        let weight = weight(&key, &value);
+        let evicted = self.make_room(weight, keep);
+        let stamp = self.next;
+        self.next += 1;
+        self.order.insert(stamp, key.clone());
+        self.map.insert(
+            key,
+            Slot {
+                value,
+                weight,
+                stamp,
+            },
+        );
+        self.account(weight as isize, 1);
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let slot = self.map.remove(key)?;
+        self.order.remove(&slot.stamp);
+        self.account(-(slot.weight as isize), -1);
+        Some(slot.value)
+    }
+
+    fn make_room(&mut self, weight: usize, keep: impl Fn(&K, &V) -> bool) -> Vec<(K, V)> {
+        let mut evicted = Vec::new();
+        // Kept entries passed over, once all were the oldest kept one goes too
+        let mut spared = 0;
+        while self.map.len() >= self.cap || used() + weight > budget() {
+            let Some((_, key)) = self.order.pop_first() else {
+                // Nothing of ours left, the budget went to other stores
+                break;
+            };
+            let slot = self.map.get_mut(&key).expect("ordered keys are in the map");
+            if spared < self.map.len() && keep(&key, &slot.value) {
+                spared += 1;
+                slot.stamp = self.next;
+                self.order.insert(self.next, key);
+                self.next += 1;
+                continue;
+            }
+            let slot = self.map.remove(&key).expect("ordered keys are in the map");
+            self.account(-(slot.weight as isize), -1);
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+            evicted.push((key, slot.value));
+        }
+        if !evicted.is_empty() && !self.cache {
+            BUDGET
+                .last_eviction
+                .store(timebase::unix_now(), Ordering::Relaxed);
+        }
+        evicted
+    }
+
+    fn account(&self, bytes: isize, entries: isize) {
+        if bytes >= 0 {
+            BUDGET.used.fetch_add(bytes as usize, Ordering::Relaxed);
+            self.stats
+                .bytes
+                .fetch_add(bytes as usize, Ordering::Relaxed);
+        } else {
+            BUDGET.used.fetch_sub(-bytes as usize, Ordering::Relaxed);
+            self.stats
+                .bytes
+                .fetch_sub(-bytes as usize, Ordering::Relaxed);
+        }
+        if entries >= 0 {
+            self.stats
+                .entries
+                .fetch_add(entries as usize, Ordering::Relaxed);
+        } else {
+            self.stats
+                .entries
+                .fetch_sub(-entries as usize, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<K, V> Drop for Bounded<K, V> {
+    fn drop(&mut self) {
+        let bytes = self.stats.bytes.load(Ordering::Relaxed);
+        BUDGET.used.fetch_sub(bytes, Ordering::Relaxed);
+        BUDGET
+            .stores
+            .lock()
+            .unwrap()
+            .retain(|stats| !Arc::ptr_eq(stats, &self.stats));
+    }
+}
+
+// The entry in the map and in the order, twice the key, plus what key and value point to
+fn weight<K: Weigh, V: Weigh>(key: &K, value: &V) -> usize {
+    2 * size_of::<K>()
+        + size_of::<Slot<V>>()
+        + 2 * size_of::<u64>()
+        + key.heap_bytes()
+        + value.heap_bytes()
+}
@@ -0,0 +1,109 @@
+//! Chaining with other XDP programs, both ways. The datapath tail-calls whatever sits in
+//! slot 0 of `NEXT_PROG` for every packet it passes; with the slot empty it just passes them.
+//! With `--attach-mode chained` the guard is the one chained into instead: the object's
+//! extension program (`BPF_PROG_TYPE_EXT`) replaces a function of another loader's XDP
+//! dispatcher, libxdp-style, and hands its verdict back to the dispatcher, which runs
+//! whatever comes after it. The dispatcher stays attached to the interface, and it owns it.
+
+use std::path::Path;
+
+use anyhow::{Context as _, bail};
+use aya::{
+    Ebpf,
+    maps::{MapData, ProgramArray},
+    programs::{Extension, ExtensionLinkId, Program, ProgramError, ProgramInfo, ProgramType},
+};
+use log::info;
+
+/// The guard as an extension, in the object next to the XDP programs.
+pub const EXTENSION: &str = "xdp_api_guard_ext";
+
+/// Points the chain at the XDP program pinned at `pin`, or clears it with `None`.
+pub fn set_next(map: &mut ProgramArray<MapData>, pin: Option<&Path>) -> anyhow::Result<()> {
+    let Some(pin) = pin else {
+        match map.clear_index(&0) {
+            Ok(()) | Err(aya::maps::MapError::KeyNotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+        info!("chaining disabled, passed packets go straight to the stack");
+        return Ok(());
+    };
+    let program = ProgramInfo::from_pin(pin)
+        .with_context(|| format!("no program pinned at {}", pin.display()))?;
+    let fd = program.fd()?;
+    // The kernel refuses programs of another type (or with incompatible attach flags)
+    map.set(0, &fd, 0)
+        .with_context(|| format!("cannot chain into {}", pin.display()))?;
+    info!(
+        "chaining passed packets into program {} ({})",
+        program.id(),
+        pin.display()
+    );
+    Ok(())
+}
+
+/// Loads the extension program to replace `func` of the XDP dispatcher pinned at `pin`, after
+/// checking the dispatcher can take it. The program is taken out of `ebpf`, so it can be
+/// attached while the rest of the object is borrowed.
+pub fn load_extension(ebpf: &mut Ebpf, pin: &Path, func: &str) -> anyhow::Result<Extension> {
+    let dispatcher = ProgramInfo::from_pin(pin)
+        .with_context(|| format!("no dispatcher program pinned at {}", pin.display()))?;
+    match dispatcher.program_type() {
+        Ok(ProgramType::Xdp) => {}
+        Ok(other) => bail!(
+            "{} is a {other:?} program, --attach-mode chained needs an XDP dispatcher",
+            pin.display()
+        ),
+        Err(e) => return Err(e).context("failed to read the dispatcher's program type"),
+    }
+    // The kernel finds the function to replace, and checks its signature, in the BTF
+    if dispatcher.btf_id().is_none() {
+        bail!(
+            "the dispatcher at {} has no BTF, which an extension needs to find {func}: load \
+             it with BTF (clang -g), as libxdp does",
+            pin.display()
+        );
+    }
+    let target = dispatcher.fd()?;
+    let Some(Program::Extension(mut extension)) = ebpf.take_program(EXTENSION) else {
+        bail!("the eBPF object has no extension program {EXTENSION}");
+    };
+    match extension.load(target, func) {
+        Ok(()) => {}
+        Err(ProgramError::LoadError { verifier_log, .. }) => bail!(
+            "the kernel refused {EXTENSION} as a replacement of {func} in the dispatcher at {}: \
+             {func} has to be a global function `int {func}(struct xdp_md *ctx)` of a \
+             single-buffer dispatcher.\n--- verifier log ---\n{verifier_log}",
+            pin.display()
+        ),
+        Err(e) => {
+            return Err(anyhow::Error::new(e).context(format!(
+                "failed to load {EXTENSION} in place of {func} of the dispatcher at {}, is \
+                 {func} one of its functions? libxdp's are prog0 to prog9",
+                pin.display()
+            )));
+        }
+    }
+    Ok(extension)
+}
+
+/// Puts the loaded extension in place of its function, the dispatcher runs it from the next
+/// packet on.
+pub fn attach_extension(
+    extension: &mut Extension,
+    pin: &Path,
+    func: &str,
+) -> anyhow::Result<ExtensionLinkId> {
+    let link = extension.attach().with_context(|| {
+        format!(
+            "failed to replace {func} of the dispatcher at {}, is another extension in its \
+             place?",
+            pin.display()
+        )
+    })?;
+    info!(
+        "chained into the dispatcher at {} in place of {func}",
+        pin.display()
+    );
+    Ok(link)
+}
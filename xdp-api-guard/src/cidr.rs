@@ -0,0 +1,67 @@
+use std::{fmt, net::Ipv4Addr, str::FromStr};
+
+use anyhow::{Context as _, anyhow};
+
+/// An IPv4 prefix such as `10.0.0.0/8`. A bare address parses as a /32.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Ipv4Cidr {
+    addr: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> anyhow::Result<Self> {
+        if prefix_len > 32 {
+            return Err(anyhow!("prefix length {prefix_len} is out of range"));
+        }
+        // Store the network address so equal prefixes compare equal.
+        let addr = Ipv4Addr::from(u32::from(addr) & mask(prefix_len));
+        Ok(Self { addr, prefix_len })
+    }
+
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Netmask as a host-order integer.
+    pub fn mask(&self) -> u32 {
+        mask(self.prefix_len)
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & mask(self.prefix_len) == u32::from(self.addr)
+    }
+}
+
+fn mask(prefix_len: u8) -> u32 {
+    if prefix_len == 0 {
+        0
+    } else {
+        u32::MAX << (32 - u32::from(prefix_len))
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, len.parse().context("invalid prefix length")?),
+            None => (s, 32),
+        };
+        let addr = addr
+            .parse()
+            .with_context(|| format!("invalid IPv4 address {addr:?}"))?;
+        Self::new(addr, prefix_len)
+    }
+}
+
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
@@ -0,0 +1,823 @@
+//! Blocklist sharing between nodes (the `cluster` feature).
+//!
+//! Nodes form an authenticated TCP mesh. Bans and unbans made locally are published as events;
+//! events received from a peer are applied with origin `cluster` and passed on to the other
+//! peers, so a partial mesh still reaches everyone. Every event carries the originating node id
+//! and a sequence number, and each node applies and forwards an id only once. Local precedence
+//! still holds: a remote ban loses to a local allow, management network or manual block.
+//!
+//! The wire format is one JSON message per line, of at most 4096 bytes. Both sides open with
+//! `hello` carrying their node id and a random nonce of 16 bytes in hex. Then each proves it knows
+//! the shared secret with an HMAC-SHA256 keyed with it over the whole handshake: a protocol label,
+//! its role and both hellos, every field prefixed with its length. A MAC thereby holds for one
+//! connection and one direction only, and can neither be moved to another connection nor reflected
+//! back to the side that sent it. The dialling side (the initiator) sends its MAC first; the
+//! accepting side (the responder) checks it before sending its own, so a dialler without the secret
+//! learns nothing. Nothing else is accepted before that.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Write as _,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context as _, anyhow, bail};
+use hmac::{Hmac, Mac as _};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader},
+    net::{
+        TcpListener, TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::broadcast,
+    time::{sleep, timeout},
+};
+use xdp_api_guard_common::Origin;
+
+use crate::{
+    blocklist::{BlocklistEvent, Entry},
+    control::ControlState,
+    mask::Masked,
+    timebase::unix_now,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// What the handshake MACs start with, so they can't be mistaken for any other use of the secret
+const PROTOCOL: &[u8] = b"xdp-api-guard cluster v1";
+// A nonce on the wire: 16 random bytes in hex
+const NONCE_HEX: usize = 32;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// Event ids remembered for loop prevention
+const SEEN_CAPACITY: usize = 16 * 1024;
+// Longest message line, past it the connection is closed. An event takes a few hundred bytes
+const MAX_LINE_BYTES: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Ban,
+    Unban,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClusterEvent {
+    /// Node the decision was made on. Together with `seq` this identifies the event.
+    pub node: String,
+    pub seq: u64,
+    pub kind: EventKind,
+    pub ip: Ipv4Addr,
+    /// Unix time the ban ends, so every node expires it at the same moment.
+    pub expires: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Message {
+    Hello { node: String, nonce: String },
+    Auth { mac: String },
+    Event(ClusterEvent),
+}
+
+/// Which end of a connection a node is. The handshake MACs are bound to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    /// Dialled the connection
+    Initiator,
+    /// Accepted it
+    Responder,
+}
+
+impl Role {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Role::Initiator => b"initiator",
+            Role::Responder => b"responder",
+        }
+    }
+
+    fn peer(self) -> Self {
+        match self {
+            Role::Initiator => Role::Responder,
+            Role::Responder => Role::Initiator,
+        }
+    }
+}
+
+pub struct ClusterConfig {
+    pub node: String,
+    pub secret: Vec<u8>,
+    pub listen: Option<SocketAddr>,
+    pub peers: Vec<String>,
+    /// Recent events sent to a peer when it (re)joins.
+    pub replay: usize,
+}
+
+pub struct Cluster {
+    node: String,
+    secret: Vec<u8>,
+    replay: usize,
+    state: Arc<ControlState>,
+    inner: Mutex<Inner>,
+    out: broadcast::Sender<ClusterEvent>,
+}
+
+struct Inner {
+    next_seq: u64,
+    seen: HashSet<(String, u64)>,
+    seen_order: VecDeque<(String, u64)>,
+    recent: VecDeque<ClusterEvent>,
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig, state: Arc<ControlState>) -> Arc<Self> {
+        // Sequence numbers start at the current time so a restarted node does not reuse ids its
+        // peers still remember
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        Arc::new(Self {
+            node: config.node.clone(),
+            secret: config.secret.clone(),
+            replay: config.replay,
+            state,
+            inner: Mutex::new(Inner {
+                next_seq: start,
+                seen: HashSet::new(),
+                seen_order: VecDeque::new(),
+                recent: VecDeque::new(),
+            }),
+            out: broadcast::channel(1024).0,
+        })
+    }
+
+    /// Starts the listener, the dialers and the local event pump.
+    pub fn start(self: &Arc<Self>, config: &ClusterConfig) {
+        let local = self.state.blocklist.lock().unwrap().subscribe();
+        tokio::spawn(self.clone().publish_local(local));
+        if let Some(listen) = config.listen {
+            let cluster = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = cluster.listen(listen).await {
+                    warn!("cluster listener stopped: {e:#}");
+                }
+            });
+        }
+        for peer in &config.peers {
+            tokio::spawn(self.clone().dial(peer.clone()));
+        }
+    }
+
+    async fn publish_local(self: Arc<Self>, mut events: broadcast::Receiver<BlocklistEvent>) {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("cluster: missed {n} local blocklist changes");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            // Feeds are loaded on every node, management and allows stay local, and entries
+            // that came from the cluster are not ours to announce. Expiry happens everywhere
+            // at the same time, so it is not announced either.
+            let (kind, ip, expires) = match event {
+                BlocklistEvent::Added(ip, entry) if shared(entry.origin) => {
+                    (EventKind::Ban, ip, entry.expires)
+                }
+                BlocklistEvent::Removed {
+                    ip,
+                    origin,
+                    expired: false,
+                } if shared(origin) => (EventKind::Unban, ip, None),
+                _ => continue,
+            };
+            let event = {
+                let mut inner = self.inner.lock().unwrap();
+                let event = ClusterEvent {
+                    node: self.node.clone(),
+                    seq: inner.next_seq,
+                    kind,
+                    ip,
+                    expires,
+                };
+                inner.next_seq += 1;
+                inner.remember(&event, self.replay);
+                event
+            };
+            debug!("cluster: publishing {kind:?} {}", Masked(ip));
+            let _ = self.out.send(event);
+        }
+    }
+
+    /// Applies an event from a peer and forwards it, unless it was seen before.
+    fn receive(&self, event: ClusterEvent) -> anyhow::Result<()> {
+        if event.node == self.node || !self.inner.lock().unwrap().remember(&event, self.replay) {
+            return Ok(());
+        }
+        if event.expires.is_some_and(|at| at <= unix_now()) {
+            return Ok(());
+        }
+        let mut blocklist = self.state.blocklist.lock().unwrap();
+        match event.kind {
+            EventKind::Ban => {
+                let entry = Entry {
+                    expires: event.expires,
+                    node: Some(event.node.clone()),
+                    ..Entry::new(Origin::Cluster)
+                };
+                // A suppressed write is already logged by the handle
+                blocklist.insert_entry(event.ip, entry)?;
+            }
+            EventKind::Unban => {
+                // Only lifts what the cluster put there, never a local decision
+                if blocklist
+                    .get(event.ip)
+                    .is_some_and(|entry| entry.origin == Origin::Cluster)
+                {
+                    blocklist.remove(event.ip)?;
+                    info!("{}: cluster ban lifted by {}", Masked(event.ip), event.node);
+                }
+            }
+        }
+        drop(blocklist);
+        let _ = self.out.send(event);
+        Ok(())
+    }
+
+    async fn listen(self: Arc<Self>, listen: SocketAddr) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("failed to bind {listen}"))?;
+        info!("cluster listening on {listen}");
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let cluster = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = cluster.connection(stream, Role::Responder).await {
+                    warn!("cluster peer {addr}: {e:#}");
+                }
+            });
+        }
+    }
+
+    /// Keeps a connection to `peer` up, reconnecting with backoff.
+    async fn dial(self: Arc<Self>, peer: String) {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            match TcpStream::connect(&peer).await {
+                Ok(stream) => {
+                    backoff = Duration::from_secs(1);
+                    match self.clone().connection(stream, Role::Initiator).await {
+                        Ok(()) => info!("cluster peer {peer} disconnected"),
+                        Err(e) => warn!("cluster peer {peer}: {e:#}"),
+                    }
+                }
+                Err(e) => debug!("cluster peer {peer}: {e}"),
+            }
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    async fn connection(self: Arc<Self>, stream: TcpStream, role: Role) -> anyhow::Result<()> {
+        stream.set_nodelay(true)?;
+        let (read, mut write) = stream.into_split();
+        let mut lines = Lines::new(read);
+
+        let handshake = handshake(&self.node, &self.secret, role, &mut lines, &mut write);
+        let peer = timeout(HANDSHAKE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| anyhow!("handshake timed out"))??;
+        info!("cluster peer {peer} joined");
+
+        // Subscribe before taking the replay so nothing falls in between; duplicates are
+        // dropped by the receiver
+        let mut out = self.out.subscribe();
+        let replay: Vec<ClusterEvent> = self.inner.lock().unwrap().recent.iter().cloned().collect();
+        let now = unix_now();
+        for event in replay {
+            if event.expires.is_none_or(|at| at > now) {
+                send(&mut write, &Message::Event(event)).await?;
+            }
+        }
+
+        loop {
+            tokio::select! {
+                line = lines.next_line() => {
+                    let Some(line) = line? else {
+                        return Ok(());
+                    };
+                    match serde_json::from_str(&line) {
+                        Ok(Message::Event(event)) => self.receive(event)?,
+                        Ok(other) => bail!("unexpected message {other:?}"),
+                        Err(e) => bail!("malformed message: {e}"),
+                    }
+                }
+                event = out.recv() => match event {
+                    // The peer already has its own events
+                    Ok(event) if event.node == peer => {}
+                    Ok(event) => send(&mut write, &Message::Event(event)).await?,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("cluster peer {peer}: fell behind, {n} events not sent");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+            }
+        }
+    }
+}
+
+/// A peer's messages a line at a time, none longer than `MAX_LINE_BYTES`.
+struct Lines {
+    read: BufReader<OwnedReadHalf>,
+    // What came of the line so far, kept when a read is cancelled
+    buf: Vec<u8>,
+}
+
+impl Lines {
+    fn new(read: OwnedReadHalf) -> Self {
+        Self {
+            read: BufReader::new(read),
+            buf: Vec::new(),
+        }
+    }
+
+    /// The next line, `None` once the peer closed the connection. Cancel safe, so it can wait
+    /// in a `select!`.
+    async fn next_line(&mut self) -> anyhow::Result<Option<String>> {
+        let limit = (MAX_LINE_BYTES + 1).saturating_sub(self.buf.len()) as u64;
+        let read = (&mut self.read)
+            .take(limit)
+            .read_until(b'\n', &mut self.buf)
+            .await?;
+        if self.buf.len() > MAX_LINE_BYTES {
+            bail!("peer sent a line over {MAX_LINE_BYTES} bytes");
+        }
+        if read == 0 {
+            return Ok(None);
+        }
+        let mut line = std::mem::take(&mut self.buf);
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        Ok(Some(String::from_utf8(line)?))
+    }
+}
+
+impl Inner {
+    /// Records `event` as seen. Returns false if it already was.
+    fn remember(&mut self, event: &ClusterEvent, replay: usize) -> bool {
+        let id = (event.node.clone(), event.seq);
+        if !self.seen.insert(id.clone()) {
+            return false;
+        }
+        self.seen_order.push_back(id);
+        if self.seen_order.len() > SEEN_CAPACITY
+            && let Some(old) = self.seen_order.pop_front()
+        {
+            self.seen.remove(&old);
+        }
+        if replay > 0 {
+            if self.recent.len() == replay {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(event.clone());
+        }
+        true
+    }
+}
+
+/// Proves both sides know the secret. Returns the peer's node id.
+async fn handshake(
+    node: &str,
+    secret: &[u8],
+    role: Role,
+    lines: &mut Lines,
+    write: &mut OwnedWriteHalf,
+) -> anyhow::Result<String> {
+    let nonce = hex(&random_nonce()?);
+    send(
+        write,
+        &Message::Hello {
+            node: node.to_owned(),
+            nonce: nonce.clone(),
+        },
+    )
+    .await?;
+    let (peer, peer_nonce) = match recv(lines).await? {
+        Message::Hello { node, nonce } => (node, nonce),
+        other => bail!("expected hello, got {other:?}"),
+    };
+    if peer == node {
+        bail!("connected to ourselves (or a peer using our node id)");
+    }
+    if peer_nonce.len() != NONCE_HEX || !peer_nonce.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("peer {peer} sent a malformed nonce");
+    }
+    if peer_nonce.eq_ignore_ascii_case(&nonce) {
+        bail!("peer {peer} sent our own nonce back");
+    }
+    let ours = (node, nonce.as_str());
+    let theirs = (peer.as_str(), peer_nonce.as_str());
+    let (initiator, responder) = match role {
+        Role::Initiator => (ours, theirs),
+        Role::Responder => (theirs, ours),
+    };
+    let auth = Message::Auth {
+        mac: hex(&mac(secret, role, initiator, responder)
+            .finalize()
+            .into_bytes()),
+    };
+    if role == Role::Initiator {
+        send(write, &auth).await?;
+    }
+    let proof = match recv(lines).await? {
+        Message::Auth { mac } => unhex(&mac).ok_or_else(|| anyhow!("malformed auth"))?,
+        other => bail!("expected auth, got {other:?}"),
+    };
+    mac(secret, role.peer(), initiator, responder)
+        .verify_slice(&proof)
+        .map_err(|_| anyhow!("peer {peer} failed authentication"))?;
+    if role == Role::Responder {
+        send(write, &auth).await?;
+    }
+    Ok(peer)
+}
+
+// The MAC `role` sends for the handshake between `initiator` and `responder`, each a node id
+// and the nonce it sent
+fn mac(secret: &[u8], role: Role, initiator: (&str, &str), responder: (&str, &str)) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    let fields = [
+        PROTOCOL,
+        role.label(),
+        initiator.0.as_bytes(),
+        initiator.1.as_bytes(),
+        responder.0.as_bytes(),
+        responder.1.as_bytes(),
+    ];
+    for field in fields {
+        mac.update(&(field.len() as u32).to_be_bytes());
+        mac.update(field);
+    }
+    mac
+}
+
+/// Origins whose bans and unbans are shared with the cluster.
+fn shared(origin: Origin) -> bool {
+    matches!(origin, Origin::AutoBan | Origin::ManualBlock)
+}
+
+async fn send(write: &mut OwnedWriteHalf, message: &Message) -> anyhow::Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+async fn recv(lines: &mut Lines) -> anyhow::Result<Message> {
+    let line = lines
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("peer closed the connection"))?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+fn random_nonce() -> anyhow::Result<[u8; 16]> {
+    let mut buf = [0u8; 16];
+    let ret = unsafe { libc::getrandom(buf.as_mut_ptr().cast(), buf.len(), 0) };
+    if ret != buf.len() as isize {
+        return Err(std::io::Error::last_os_error()).context("getrandom failed");
+    }
+    Ok(buf)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{b:02x}");
+    }
+    out
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::{blocklist::Applied, progtest};
+
+    const SECRET: &[u8] = b"shared secret";
+    const IP: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+    // Banned after another address to tell that the ban before it was handled
+    const MARKER: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 99);
+
+    type Halves = (Lines, OwnedWriteHalf);
+
+    // Both ends of a loopback connection, the dialled one first
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dialled, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (dialled.unwrap(), accepted.unwrap().0)
+    }
+
+    fn halves(stream: TcpStream) -> Halves {
+        let (read, write) = stream.into_split();
+        (Lines::new(read), write)
+    }
+
+    // A node shaking hands on `stream`, which it closes when done as a real connection would
+    fn node(
+        stream: TcpStream,
+        node: &'static str,
+        secret: &'static [u8],
+        role: Role,
+    ) -> JoinHandle<anyhow::Result<String>> {
+        tokio::spawn(async move {
+            let (mut lines, mut write) = halves(stream);
+            handshake(node, secret, role, &mut lines, &mut write).await
+        })
+    }
+
+    async fn hello(lines: &mut Lines) -> (String, String) {
+        match recv(lines).await.unwrap() {
+            Message::Hello { node, nonce } => (node, nonce),
+            other => panic!("expected hello, got {other:?}"),
+        }
+    }
+
+    fn fresh_hello(node: &str) -> Message {
+        Message::Hello {
+            node: node.to_owned(),
+            nonce: hex(&random_nonce().unwrap()),
+        }
+    }
+
+    // A node on maps of its own, following its blocklist
+    fn cluster(node: &str) -> Arc<Cluster> {
+        let config = ClusterConfig {
+            node: node.to_owned(),
+            secret: SECRET.to_vec(),
+            listen: None,
+            peers: Vec::new(),
+            replay: 16,
+        };
+        let cluster = Cluster::new(&config, progtest::control());
+        cluster.start(&config);
+        cluster
+    }
+
+    // Connects `a` to `b` and returns once both are past the handshake and follow the other's
+    // events
+    async fn connect(a: &Arc<Cluster>, b: &Arc<Cluster>) {
+        let (dialled, accepted) = pair().await;
+        tokio::spawn(a.clone().connection(dialled, Role::Initiator));
+        tokio::spawn(b.clone().connection(accepted, Role::Responder));
+        eventually(|| a.out.receiver_count() == 1 && b.out.receiver_count() == 1).await;
+    }
+
+    async fn eventually(mut done: impl FnMut() -> bool) {
+        let wait = async {
+            while !done() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), wait)
+            .await
+            .expect("timed out");
+    }
+
+    fn entry(node: &Cluster, ip: Ipv4Addr) -> Option<Entry> {
+        node.state.blocklist.lock().unwrap().get(ip).cloned()
+    }
+
+    fn ban(node: &Cluster, ip: Ipv4Addr, origin: Origin, expires: Option<u64>) {
+        let entry = Entry {
+            expires,
+            ..Entry::new(origin)
+        };
+        let mut blocklist = node.state.blocklist.lock().unwrap();
+        assert_eq!(blocklist.insert_entry(ip, entry).unwrap(), Applied::Written);
+    }
+
+    #[tokio::test]
+    async fn nodes_with_the_secret_meet() {
+        let (dialled, accepted) = pair().await;
+        let a = node(dialled, "a", SECRET, Role::Initiator);
+        let b = node(accepted, "b", SECRET, Role::Responder);
+        assert_eq!(a.await.unwrap().unwrap(), "b");
+        assert_eq!(b.await.unwrap().unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn nodes_with_different_secrets_dont() {
+        let (dialled, accepted) = pair().await;
+        let a = node(dialled, "a", SECRET, Role::Initiator);
+        let b = node(accepted, "b", b"another secret", Role::Responder);
+        assert!(b.await.unwrap().is_err());
+        assert!(a.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn initiator_mac_reflected_back_fails() {
+        let (dialled, accepted) = pair().await;
+        let a = node(dialled, "a", SECRET, Role::Initiator);
+        let (mut lines, mut write) = halves(accepted);
+        hello(&mut lines).await;
+        send(&mut write, &fresh_hello("mallory")).await.unwrap();
+        let auth = recv(&mut lines).await.unwrap();
+        assert!(matches!(auth, Message::Auth { .. }));
+        send(&mut write, &auth).await.unwrap();
+        assert!(a.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn own_hello_reflected_back_fails() {
+        let (dialled, accepted) = pair().await;
+        let a = node(dialled, "a", SECRET, Role::Initiator);
+        let (mut lines, mut write) = halves(accepted);
+        let (node, nonce) = hello(&mut lines).await;
+        send(&mut write, &Message::Hello { node, nonce })
+            .await
+            .unwrap();
+        assert!(a.await.unwrap().is_err());
+        assert!(lines.next_line().await.unwrap().is_none());
+    }
+
+    // The attack on the unframed MAC: learn a nonce of the responder on one connection and
+    // have it MAC a chosen split of it on another
+    #[tokio::test]
+    async fn responder_macs_nothing_for_an_unproven_peer() {
+        let (first, accepted) = pair().await;
+        let a1 = node(accepted, "a", SECRET, Role::Responder);
+        let (mut lines1, mut write1) = halves(first);
+        send(&mut write1, &fresh_hello("mallory")).await.unwrap();
+        let (_, nonce) = hello(&mut lines1).await;
+
+        // A truncated nonce is refused outright
+        let (second, accepted) = pair().await;
+        let a2 = node(accepted, "a", SECRET, Role::Responder);
+        let (mut lines2, mut write2) = halves(second);
+        let truncated = Message::Hello {
+            node: format!("mallory{}", &nonce[16..]),
+            nonce: nonce[..16].to_owned(),
+        };
+        send(&mut write2, &truncated).await.unwrap();
+        hello(&mut lines2).await;
+        assert!(a2.await.unwrap().is_err());
+        assert!(lines2.next_line().await.unwrap().is_none());
+
+        // And without a valid MAC of its own the peer gets none back
+        send(&mut write1, &Message::Auth { mac: hex(&[0; 32]) })
+            .await
+            .unwrap();
+        assert!(a1.await.unwrap().is_err());
+        assert!(lines1.next_line().await.unwrap().is_none());
+    }
+
+    #[test]
+    fn macs_are_bound_to_role_and_framing() {
+        fn tag(role: Role, initiator: (&str, &str), responder: (&str, &str)) -> Vec<u8> {
+            mac(SECRET, role, initiator, responder)
+                .finalize()
+                .into_bytes()
+                .to_vec()
+        }
+        let n1 = "00112233445566778899aabbccddeeff";
+        let n2 = "ffeeddccbbaa99887766554433221100";
+        let base = tag(Role::Initiator, ("a", n1), ("b", n2));
+        assert_ne!(base, tag(Role::Responder, ("a", n1), ("b", n2)));
+        assert_ne!(base, tag(Role::Initiator, ("b", n2), ("a", n1)));
+        // The same bytes split differently between the fields
+        let moved = format!("{}b", &n1[16..]);
+        assert_ne!(base, tag(Role::Initiator, ("a", &n1[..16]), (&moved, n2)));
+    }
+
+    #[tokio::test]
+    async fn lines_past_the_limit_end_the_connection() {
+        let (dialled, accepted) = pair().await;
+        let (mut lines, _) = halves(accepted);
+        let (_read, mut write) = dialled.into_split();
+        let fits = "x".repeat(MAX_LINE_BYTES - 1);
+        write.write_all(fits.as_bytes()).await.unwrap();
+        write.write_all(b"\n").await.unwrap();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), fits);
+        // Refused as soon as it is too long, not when the peer gets round to the newline
+        write.write_all(&[b'x'; MAX_LINE_BYTES + 1]).await.unwrap();
+        assert!(lines.next_line().await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "creates the maps, needs root"]
+    async fn events_are_applied_and_passed_on_once() {
+        let b = cluster("b");
+        let mut out = b.out.subscribe();
+        let event = ClusterEvent {
+            node: "a".to_owned(),
+            seq: 1,
+            kind: EventKind::Ban,
+            ip: IP,
+            expires: None,
+        };
+        b.receive(event.clone()).unwrap();
+        b.receive(event.clone()).unwrap();
+        // In a partial mesh a node's own events come back to it through the others
+        let own = ClusterEvent {
+            node: "b".to_owned(),
+            seq: 2,
+            ip: MARKER,
+            ..event
+        };
+        b.receive(own).unwrap();
+        let passed = out.try_recv().unwrap();
+        assert_eq!((passed.node.as_str(), passed.seq), ("a", 1));
+        assert!(out.try_recv().is_err());
+        let copy = entry(&b, IP).unwrap();
+        assert_eq!(copy.origin, Origin::Cluster);
+        assert_eq!(copy.node.as_deref(), Some("a"));
+        assert!(entry(&b, MARKER).is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "creates the maps, needs root"]
+    async fn bans_reach_the_other_node_and_end_on_both() {
+        let (a, b) = (cluster("a"), cluster("b"));
+        connect(&a, &b).await;
+        let until = unix_now() + 60;
+        ban(&a, IP, Origin::AutoBan, Some(until));
+        eventually(|| entry(&b, IP).is_some()).await;
+        let copy = entry(&b, IP).unwrap();
+        assert_eq!(copy.origin, Origin::Cluster);
+        assert_eq!(copy.node.as_deref(), Some("a"));
+        assert_eq!(copy.expires, Some(until));
+        // The TTL runs out on each node on its own, an expiry isn't sent as an unban
+        assert_eq!(a.state.blocklist.lock().unwrap().expire(until).unwrap(), 1);
+        ban(&a, MARKER, Origin::ManualBlock, None);
+        eventually(|| entry(&b, MARKER).is_some()).await;
+        assert!(entry(&b, IP).is_some());
+        assert_eq!(b.state.blocklist.lock().unwrap().expire(until).unwrap(), 1);
+        assert!(entry(&a, IP).is_none() && entry(&b, IP).is_none());
+        // And the peer's copy came back to no one
+        assert_eq!(entry(&a, MARKER).unwrap().origin, Origin::ManualBlock);
+    }
+
+    #[tokio::test]
+    #[ignore = "creates the maps, needs root"]
+    async fn a_local_allow_beats_a_remote_ban() {
+        let (a, b) = (cluster("a"), cluster("b"));
+        connect(&a, &b).await;
+        ban(&b, IP, Origin::ManualAllow, None);
+        ban(&a, IP, Origin::ManualBlock, None);
+        ban(&a, MARKER, Origin::ManualBlock, None);
+        eventually(|| entry(&b, MARKER).is_some()).await;
+        assert_eq!(entry(&b, IP).unwrap().origin, Origin::ManualAllow);
+        let kernel = b
+            .state
+            .blocklist
+            .lock()
+            .unwrap()
+            .kernel_entry(u32::from(IP));
+        assert_eq!(kernel.unwrap().unwrap().origin, Origin::ManualAllow as u8);
+        // Nor does lifting the ban lift the allow
+        assert_eq!(
+            a.state.blocklist.lock().unwrap().remove(IP).unwrap(),
+            Some(Origin::ManualBlock)
+        );
+        assert_eq!(
+            a.state.blocklist.lock().unwrap().remove(MARKER).unwrap(),
+            Some(Origin::ManualBlock)
+        );
+        eventually(|| entry(&b, MARKER).is_none()).await;
+        assert_eq!(entry(&b, IP).unwrap().origin, Origin::ManualAllow);
+    }
+
+    #[tokio::test]
+    #[ignore = "creates the maps, needs root"]
+    async fn a_joining_node_is_sent_the_recent_bans() {
+        let a = cluster("a");
+        ban(&a, IP, Origin::ManualBlock, None);
+        eventually(|| a.inner.lock().unwrap().recent.len() == 1).await;
+        let b = cluster("b");
+        connect(&a, &b).await;
+        eventually(|| entry(&b, IP).is_some()).await;
+        assert_eq!(entry(&b, IP).unwrap().node.as_deref(), Some("a"));
+    }
+}
@@ -0,0 +1,61 @@
+use aya::maps::{Array, MapData};
+use xdp_api_guard_common::{CONFIG_SLOTS, Config, config_slot};
+
+/// Owns the `CONFIG` map and the copy of the settings last written to it. Every write of
+/// the settings goes through here, behind the one lock `ControlState` keeps it in, so
+/// updates are applied one at a time.
+///
+/// An update writes the whole settings into the slot of its generation and only then
+/// stores the generation in `CONFIG_ACTIVE`. The program checks `CONFIG_ACTIVE` again after
+/// copying a slot, and takes the copy again if the slot may have been rewritten meanwhile,
+/// see `CONFIG_SLOTS`: each packet runs on the settings of one update.
+pub struct ConfigHandle {
+    map: Array<MapData, Config>,
+    active: Array<MapData, u64>,
+    current: Config,
+}
+
+impl ConfigHandle {
+    /// Writes `initial` into every slot right away so the program never runs on a zeroed
+    /// one.
+    pub fn new(
+        mut map: Array<MapData, Config>,
+        mut active: Array<MapData, u64>,
+        initial: Config,
+    ) -> anyhow::Result<Self> {
+        let initial = Config {
+            generation: 1,
+            ..initial
+        };
+        for slot in 0..CONFIG_SLOTS {
+            map.set(slot, initial, 0)?;
+        }
+        active.set(0, initial.generation, 0)?;
+        Ok(Self {
+            map,
+            active,
+            current: initial,
+        })
+    }
+
+    pub fn get(&self) -> Config {
+        self.current
+    }
+
+    /// Counts the updates since startup, the first write included.
+    pub fn generation(&self) -> u64 {
+        self.current.generation
+    }
+
+    /// Applies `f` to a copy of the current settings and writes the result.
+    pub fn update(&mut self, f: impl FnOnce(&mut Config)) -> anyhow::Result<Config> {
+        let mut next = self.current;
+        f(&mut next);
+        next.generation = self.current.generation + 1;
+        self.map.set(config_slot(next.generation), next, 0)?;
+        // Not before the slot is written: the program copies the slot CONFIG_ACTIVE names
+        self.active.set(0, next.generation, 0)?;
+        self.current = next;
+        Ok(next)
+    }
+}
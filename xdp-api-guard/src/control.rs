@@ -0,0 +1,1414 @@
+//! Line-oriented control socket. Each request is one line, each response is one or more lines
+//! followed by an empty line. The first response line starts with `ok` or `err`.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    io::ErrorKind,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context as _, anyhow, bail};
+use aya::{
+    maps::{HashMap, MapData, PerCpuArray, ProgramArray},
+    programs::ProgramFd,
+};
+use log::{LevelFilter, debug, info, warn};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader},
+    net::{UnixListener, UnixStream},
+};
+use xdp_api_guard_common::{
+    ACTION_ALLOW, ACTION_DROP, AbortRecord, Config, Flow, FlowKey, Origin, PREFIX_MASK, PacketLog,
+    TAG_MAX, abort, api::ListEntry, config_flags, feature, nat_source, tagged_limit,
+};
+
+use crate::{
+    admission::{Admission, MAX_LINE_BYTES, MAX_STRIKES},
+    blocklist::{Applied, BlocklistHandle, Entry},
+    chain,
+    cidr::Ipv4Cidr,
+    config::ConfigHandle,
+    conversion::Conversions,
+    export::{self, Cursor},
+    geoip::GeoIp,
+    groups::{self, Groups},
+    heatmap,
+    learn::Learner,
+    logpump, preset, profile,
+    relax::{self, Controller, Knob, Setting},
+    replay::{self, ReplayCache},
+    resize::{self, Resizable, Slots},
+    rules::{self, RuleFilter, Rules},
+    snapshot,
+    stats::StatsState,
+    tenants::Tenants,
+    timebase,
+    trace::Span,
+    verifier,
+    verify::{self, VerifyTarget},
+    version::{Build, Versions},
+};
+
+/// Everything control commands can touch.
+pub struct ControlState {
+    pub blocklist: Mutex<BlocklistHandle>,
+    pub tags: Mutex<HashMap<MapData, u32, u32>>,
+    pub rate_limit: Mutex<HashMap<MapData, u32, PacketLog>>,
+    pub rate_limit6: Mutex<HashMap<MapData, [u8; 16], PacketLog>>,
+    /// `RATE_LIMIT_MAP` entries per /16, for `--prefix-quota`. Locked after `rate_limit`.
+    pub prefix_sources: Mutex<HashMap<MapData, u32, u32>>,
+    /// The shared entries of /16s past their quota.
+    pub prefix: Mutex<HashMap<MapData, u32, PacketLog>>,
+    /// Per source port of the addresses in `nat_prefixes`, keyed by `nat_key`.
+    pub nat: Mutex<HashMap<MapData, u64, PacketLog>>,
+    /// `--nat-prefix`, fixed at startup.
+    pub nat_prefixes: Vec<Ipv4Cidr>,
+    pub quic_initial: Mutex<HashMap<MapData, u32, PacketLog>>,
+    /// TCP flows the datapath saw open, used with `--conntrack`. This and the three after it
+    /// are `None` in a program built without their subsystem.
+    pub conntrack: Option<Mutex<HashMap<MapData, FlowKey, Flow>>>,
+    pub ack: Option<Mutex<HashMap<MapData, u32, PacketLog>>>,
+    pub http: Option<Mutex<HashMap<MapData, u32, PacketLog>>>,
+    pub service: Option<Mutex<HashMap<MapData, u16, PacketLog>>>,
+    /// SYN conversion by source, with `--conntrack`. Locked on its own, before the blocklist.
+    pub conversions: Option<Mutex<Conversions>>,
+    pub rules: Mutex<Rules>,
+    pub groups: Mutex<Groups>,
+    /// `--tenant`, fixed at startup.
+    pub tenants: Mutex<Tenants>,
+    pub stats: Arc<Mutex<StatsState>>,
+    pub config: Mutex<ConfigHandle>,
+    /// With `--relax-after-secs`, the controller of the `--managed` knobs and the registry of
+    /// which are pinned. Locked before `stats` and `config`.
+    pub relax: Option<Mutex<Controller>>,
+    /// Present when running with `--learn`.
+    pub learner: Option<Mutex<Learner>>,
+    pub next_prog: Mutex<ProgramArray<MapData>>,
+    pub versions: Versions,
+    pub paths: Mutex<PerCpuArray<MapData, u64>>,
+    /// The last packet each CPU returned XDP_ABORTED for.
+    pub last_abort: Mutex<PerCpuArray<MapData, AbortRecord>>,
+    /// Set while a `profile` command is sampling.
+    pub profiling: AtomicBool,
+    /// Running on maps pinned by another loader (`--external-maps`), which owns the program.
+    pub external: bool,
+    /// The loaded program, for `verify`. `None` with `external`.
+    pub program: Option<ProgramFd>,
+    /// Where the program reads `BLOCKLIST` and `RATE_LIMIT_MAP` from, for `resize`. `None`
+    /// with `external`.
+    pub slots: Option<Slots>,
+    /// How the program fared with the verifier, for `status`.
+    pub verifier: verifier::Stats,
+    /// Whether the loaded program is the multi-buffer one, which sees frames past the first
+    /// page. Meaningless with `external`.
+    pub multi_buffer: bool,
+    /// With `--attach-mode chained`, what the program is chained into.
+    pub chained: Option<String>,
+    /// Responses to commands sent with an idempotency key.
+    pub replay: ReplayCache,
+    /// `--geoip-db` and `--asn-db`, `None` without either.
+    pub geo: Option<Arc<GeoIp>>,
+    /// `--snapshot-dir`, the snapshot commands are refused without it.
+    pub snapshot_dir: Option<PathBuf>,
+    /// `--env-file`, where `suggest --apply` keeps the limit for the next start.
+    pub env_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TagOp {
+    Set(u32),
+    Add(i64),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FlushTarget {
+    RateLimit,
+    Bans,
+    /// Entries of one origin, or every non-manual entry when `None`. `all` includes manual
+    /// entries too.
+    Blocklist {
+        origin: Option<Origin>,
+        all: bool,
+    },
+    Conntrack,
+    Stats,
+}
+
+impl FlushTarget {
+    /// Whether the flush removes blocklist entries of `origin`.
+    pub fn flushes(self, origin: Origin) -> bool {
+        match self {
+            FlushTarget::Bans => matches!(origin, Origin::AutoBan | Origin::Policy),
+            FlushTarget::Blocklist {
+                origin: Some(only), ..
+            } => origin == only,
+            FlushTarget::Blocklist { origin: None, all } => all || !origin.is_manual(),
+            FlushTarget::RateLimit | FlushTarget::Conntrack | FlushTarget::Stats => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GroupOp {
+    Add(String, Ipv4Cidr),
+    Del(String, Ipv4Cidr),
+    Rate(String, u64),
+    Action(String, u8),
+    List,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    /// Block, optionally for a number of seconds only.
+    Block(Ipv4Addr, Option<u64>),
+    Unblock(Ipv4Addr),
+    Allow(Ipv4Addr),
+    List,
+    /// A page of the blocklist, after the cursor.
+    Export {
+        cursor: Option<Cursor>,
+        limit: usize,
+    },
+    Tag(Ipv4Addr, TagOp),
+    Untag(Ipv4Addr),
+    Reset(Ipv4Addr),
+    Flush(FlushTarget),
+    Suggest {
+        percentile: f64,
+        safety: f64,
+        apply: bool,
+    },
+    /// The `n` sources with the highest counts in the limiter maps.
+    Offenders(usize),
+    /// The `--service-rate` and `--dscp-policy` rules with their hit counts.
+    Rules(RuleFilter),
+    /// Everything that decides how an address is treated right now.
+    Why(Ipv4Addr),
+    /// Run a synthetic packet through the live program and compare its verdict with the
+    /// blocklist's.
+    Verify(VerifyTarget),
+    /// Change the `--group` groups at runtime, or list them with their counters.
+    Group(GroupOp),
+    /// Chain passed packets into the program pinned at the path, or stop with `None`.
+    Chain(Option<PathBuf>),
+    /// Count code paths for this long, then report them.
+    Profile(Duration),
+    /// Write the blocklist, management networks and limits, plus limiter entries if asked, to
+    /// the snapshot file of that name in `--snapshot-dir`.
+    SnapshotSave {
+        name: String,
+        limiters: bool,
+    },
+    /// Restore a snapshot file of `--snapshot-dir`.
+    SnapshotLoad(String),
+    /// Switch one `feature` between enforcing and only counting what it would drop.
+    Enforce {
+        feature: u32,
+        observe: bool,
+    },
+    /// Pass everything that would be dropped, until `Resume`.
+    Pause,
+    Resume,
+    /// The knobs of the runtime-parameter registry, pinned or managed.
+    Params,
+    /// Pin a knob at a value or hand it back to the `--relax-after-secs` controller.
+    Param(Knob, Setting),
+    Status,
+    /// The last XDP_ABORTED verdict of each CPU, with the start of the packet.
+    LastAbort,
+    /// Grow a map to this many entries while the program runs.
+    Resize(Resizable, u32),
+    /// Show the log level and line counts of each program's log, or set the level of one
+    /// program (by name) or, without a name, of all.
+    LogLevel {
+        program: Option<String>,
+        level: Option<LevelFilter>,
+    },
+}
+
+impl Command {
+    pub fn parse(line: &str) -> anyhow::Result<Self> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let ip = |i: usize| -> anyhow::Result<Ipv4Addr> {
+            let word = words.get(i).ok_or_else(|| anyhow!("missing address"))?;
+            word.parse()
+                .with_context(|| format!("invalid address {word:?}"))
+        };
+        let cmd = match words.first().copied() {
+            Some("block") => {
+                let ttl = match words.get(2..) {
+                    Some(["--ttl", secs]) => Some(secs.parse().context("invalid --ttl")?),
+                    Some([]) | None => None,
+                    Some(rest) => bail!("unexpected arguments {rest:?}"),
+                };
+                Command::Block(ip(1)?, ttl)
+            }
+            Some("unblock") => Command::Unblock(ip(1)?),
+            Some("allow") => Command::Allow(ip(1)?),
+            Some("list") => Command::List,
+            Some("export") => parse_export(&words[1..])?,
+            Some("status") => Command::Status,
+            Some("last-abort") => Command::LastAbort,
+            Some("log-level") => parse_log_level(&words[1..])?,
+            Some("enforce") => parse_enforce(&words[1..])?,
+            Some("pause") => Command::Pause,
+            Some("resume") => Command::Resume,
+            Some("params") => Command::Params,
+            Some("param") => parse_param(&words[1..])?,
+            Some("tag") => {
+                let score = words.get(2).ok_or_else(|| anyhow!("missing score"))?;
+                let op = if score.starts_with(['+', '-']) {
+                    TagOp::Add(score.parse().context("invalid score")?)
+                } else {
+                    TagOp::Set(score.parse().context("invalid score")?)
+                };
+                Command::Tag(ip(1)?, op)
+            }
+            Some("untag") => Command::Untag(ip(1)?),
+            Some("reset") => Command::Reset(ip(1)?),
+            Some("flush") => Command::Flush(parse_flush(&words[1..])?),
+            Some("suggest") => parse_suggest(&words[1..])?,
+            Some("offenders") => match words.get(1) {
+                Some(n) => Command::Offenders(n.parse().context("invalid count")?),
+                None => Command::Offenders(20),
+            },
+            Some("why") => Command::Why(ip(1)?),
+            Some("verify") => Command::Verify(verify::parse(&words[1..])?),
+            Some("rules") => Command::Rules(match words.get(1..) {
+                Some(["--unused"]) => RuleFilter::Unused,
+                Some(["--idle", secs]) => RuleFilter::Idle(secs.parse().context("invalid --idle")?),
+                Some([]) | None => RuleFilter::All,
+                Some(rest) => bail!("unexpected arguments {rest:?}"),
+            }),
+            Some("profile") => {
+                let secs = match words.get(1) {
+                    Some(secs) => secs.parse().context("invalid duration")?,
+                    None => 10,
+                };
+                let duration = Duration::from_secs(secs);
+                if secs == 0 || duration > profile::MAX_DURATION {
+                    bail!(
+                        "duration must be 1 to {} seconds",
+                        profile::MAX_DURATION.as_secs()
+                    );
+                }
+                Command::Profile(duration)
+            }
+            Some("snapshot") => parse_snapshot(&words[1..])?,
+            Some("group") => Command::Group(parse_group(&words[1..])?),
+            Some("resize") => parse_resize(&words[1..])?,
+            Some("chain") => match words.get(1).copied() {
+                Some("off") => Command::Chain(None),
+                Some(path) => Command::Chain(Some(PathBuf::from(path))),
+                None => bail!("missing pinned program path (or \"off\")"),
+            },
+            Some(other) => bail!("unknown command {other:?}"),
+            None => bail!("empty command"),
+        };
+        Ok(cmd)
+    }
+}
+
+fn parse_enforce(words: &[&str]) -> anyhow::Result<Command> {
+    let [name, mode] = words else {
+        bail!("expected enforce FEATURE on|observe");
+    };
+    let feature = feature::from_name(name).ok_or_else(|| {
+        anyhow!(
+            "unknown feature {name:?}, one of {}",
+            feature::NAMES.join(", ")
+        )
+    })?;
+    let observe = match *mode {
+        "on" => false,
+        "observe" => true,
+        other => bail!("unknown mode {other:?}, expected on or observe"),
+    };
+    Ok(Command::Enforce { feature, observe })
+}
+
+fn parse_export(words: &[&str]) -> anyhow::Result<Command> {
+    let mut cursor = None;
+    let mut limit = export::DEFAULT_LIMIT;
+    for pair in words.chunks(2) {
+        match pair {
+            ["--cursor", c] => cursor = Some(c.parse()?),
+            ["--limit", n] => limit = export::limit(n.parse().context("invalid --limit")?)?,
+            _ => bail!("expected export [--cursor CURSOR] [--limit N]"),
+        }
+    }
+    Ok(Command::Export { cursor, limit })
+}
+
+fn parse_param(words: &[&str]) -> anyhow::Result<Command> {
+    let [name, value] = words else {
+        bail!("expected param KNOB VALUE|auto");
+    };
+    let knob = Knob::from_name(name).ok_or_else(|| {
+        let names: Vec<&str> = relax::KNOBS.iter().map(|knob| knob.name()).collect();
+        anyhow!("unknown knob {name:?}, one of {}", names.join(", "))
+    })?;
+    let setting = match *value {
+        "auto" => Setting::Auto,
+        value => Setting::Pin(value.parse().context("invalid value")?),
+    };
+    Ok(Command::Param(knob, setting))
+}
+
+fn parse_resize(words: &[&str]) -> anyhow::Result<Command> {
+    let [name, size] = words else {
+        bail!("expected resize blocklist|tracking ENTRIES");
+    };
+    let map = Resizable::from_name(name)
+        .ok_or_else(|| anyhow!("unknown map {name:?}, expected blocklist or tracking"))?;
+    Ok(Command::Resize(map, size.parse().context("invalid size")?))
+}
+
+fn parse_log_level(words: &[&str]) -> anyhow::Result<Command> {
+    let level = |name: &str| -> anyhow::Result<LevelFilter> {
+        name.parse().map_err(|_| {
+            anyhow!("unknown level {name:?}, expected off, error, warn, info, debug or trace")
+        })
+    };
+    let (program, level) = match words {
+        [] => (None, None),
+        [name] => (None, Some(level(name)?)),
+        [program, name] => (Some(program.to_string()), Some(level(name)?)),
+        _ => bail!("expected log-level [[PROGRAM] LEVEL]"),
+    };
+    Ok(Command::LogLevel { program, level })
+}
+
+fn parse_snapshot(words: &[&str]) -> anyhow::Result<Command> {
+    // Checked against --snapshot-dir when it runs, see snapshot::resolve
+    let name = |word: Option<&&str>| -> anyhow::Result<String> {
+        Ok(word
+            .ok_or_else(|| anyhow!("missing snapshot file"))?
+            .to_string())
+    };
+    match words.first().copied() {
+        Some("save") => {
+            let limiters = match words.get(2..) {
+                Some(["--limiters"]) => true,
+                Some([]) | None => false,
+                Some(rest) => bail!("unexpected arguments {rest:?}"),
+            };
+            Ok(Command::SnapshotSave {
+                name: name(words.get(1))?,
+                limiters,
+            })
+        }
+        Some("load") => match words.get(2..) {
+            Some([]) | None => Ok(Command::SnapshotLoad(name(words.get(1))?)),
+            Some(rest) => bail!("unexpected arguments {rest:?}"),
+        },
+        Some(other) => bail!("unknown snapshot command {other:?}, expected save or load"),
+        None => bail!("snapshot needs save or load"),
+    }
+}
+
+fn parse_group(words: &[&str]) -> anyhow::Result<GroupOp> {
+    let op = match words {
+        ["list"] => GroupOp::List,
+        ["add", name, cidr] => GroupOp::Add(name.to_string(), cidr.parse()?),
+        ["del", name, cidr] => GroupOp::Del(name.to_string(), cidr.parse()?),
+        ["rate", name, rate] => {
+            GroupOp::Rate(name.to_string(), rate.parse().context("invalid rate")?)
+        }
+        ["action", name, action] => GroupOp::Action(
+            name.to_string(),
+            groups::parse_action(action).ok_or_else(|| {
+                anyhow!("unknown action {action:?}, expected limit, pass or drop")
+            })?,
+        ),
+        _ => bail!("expected list, add|del NAME CIDR, rate NAME RATE or action NAME ACTION"),
+    };
+    Ok(op)
+}
+
+/// `flush` and its arguments, `blocklist --origin feed`, as the socket takes them.
+pub fn parse_flush(words: &[&str]) -> anyhow::Result<FlushTarget> {
+    let target = match words.first().copied() {
+        Some("rate-limit") => FlushTarget::RateLimit,
+        Some("bans") => FlushTarget::Bans,
+        Some("conntrack") => FlushTarget::Conntrack,
+        Some("stats") => FlushTarget::Stats,
+        Some("blocklist") => {
+            let mut origin = None;
+            let mut all = false;
+            let mut rest = words[1..].iter();
+            while let Some(word) = rest.next() {
+                match *word {
+                    "--all" => all = true,
+                    "--origin" => {
+                        let name = rest
+                            .next()
+                            .ok_or_else(|| anyhow!("--origin needs a value"))?;
+                        origin = Some(
+                            Origin::from_name(name)
+                                .ok_or_else(|| anyhow!("unknown origin {name:?}"))?,
+                        );
+                    }
+                    other => bail!("unexpected argument {other:?}"),
+                }
+            }
+            if let Some(origin) = origin {
+                if origin.is_manual() && !all {
+                    bail!("{} entries are only flushed with --all", origin.name());
+                }
+            }
+            FlushTarget::Blocklist { origin, all }
+        }
+        Some(other) => bail!("unknown flush target {other:?}"),
+        None => bail!("flush needs a target: rate-limit, bans, blocklist, conntrack or stats"),
+    };
+    Ok(target)
+}
+
+fn parse_suggest(words: &[&str]) -> anyhow::Result<Command> {
+    let mut percentile = 99.9;
+    let mut safety = 1.5;
+    let mut apply = false;
+    let mut rest = words.iter();
+    while let Some(word) = rest.next() {
+        let mut value = |name: &str| -> anyhow::Result<f64> {
+            let v = rest.next().ok_or_else(|| anyhow!("{name} needs a value"))?;
+            v.parse().with_context(|| format!("invalid {name} {v:?}"))
+        };
+        match *word {
+            "--percentile" => percentile = value("--percentile")?,
+            "--safety" => safety = value("--safety")?,
+            "--apply" => apply = true,
+            other => bail!("unexpected argument {other:?}"),
+        }
+    }
+    if !(0.0..=100.0).contains(&percentile) || safety <= 0.0 {
+        bail!("--percentile must be in 0..=100 and --safety positive");
+    }
+    Ok(Command::Suggest {
+        percentile,
+        safety,
+        apply,
+    })
+}
+
+/// Parses and runs one request line of the socket or the FIFO. A `req_id=KEY` prefix makes
+/// it idempotent, see `replay`.
+pub async fn execute_line(state: &ControlState, line: &str) -> String {
+    let (key, line) = replay::split_key(line);
+    let span = Span::root("control request");
+    span.scope(execute_keyed(state, key, line)).await
+}
+
+/// Parses and runs `line`, at most once per `key` when there is one.
+pub async fn execute_keyed(state: &ControlState, key: Option<&str>, line: &str) -> String {
+    let mut span = Span::child("command");
+    span.set("command", line.split_whitespace().next().unwrap_or(""));
+    let cmd = match Command::parse(line) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            span.fail(format_args!("{e:#}"));
+            return format!("err {e:#}");
+        }
+    };
+    let response = span
+        .scope(async {
+            match key {
+                Some(key) => state.replay.run(key, line, execute(state, cmd)).await,
+                None => execute(state, cmd).await,
+            }
+        })
+        .await;
+    if let Some(err) = response.strip_prefix("err ") {
+        span.fail(err);
+    }
+    response
+}
+
+/// Runs `cmd` and renders the response body (without the trailing empty line). Only
+/// `profile` takes a while, everything else completes right away.
+pub async fn execute(state: &ControlState, cmd: Command) -> String {
+    let result = match cmd {
+        Command::Profile(duration) => profile::run(state, duration).await,
+        cmd => run(state, cmd),
+    };
+    match result {
+        Ok(out) => out,
+        Err(e) => format!("err {e:#}"),
+    }
+}
+
+fn run(state: &ControlState, cmd: Command) -> anyhow::Result<String> {
+    let out = match cmd {
+        Command::Block(ip, ttl) => {
+            let entry = Entry {
+                expires: ttl.map(|secs| timebase::unix_now() + secs),
+                ..Entry::new(Origin::ManualBlock)
+            };
+            let mut blocklist = state.blocklist.lock().unwrap();
+            let result = blocklist.insert_entry(ip, entry)?;
+            applied(result, &entry_state(&blocklist, ip))
+        }
+        Command::Allow(ip) => {
+            let mut blocklist = state.blocklist.lock().unwrap();
+            let result = blocklist.insert(ip, Origin::ManualAllow)?;
+            applied(result, &entry_state(&blocklist, ip))
+        }
+        Command::Unblock(ip) => {
+            let mut blocklist = state.blocklist.lock().unwrap();
+            match blocklist.remove(ip)? {
+                Some(origin) => format!(
+                    "ok removed {} entry, {}",
+                    origin.name(),
+                    entry_state(&blocklist, ip)
+                ),
+                None => format!("ok {}", entry_state(&blocklist, ip)),
+            }
+        }
+        Command::List => {
+            let blocklist = state.blocklist.lock().unwrap();
+            let mut entries: Vec<_> = blocklist.entries().collect();
+            entries.sort_by_key(|(ip, _)| *ip);
+            let now = timebase::unix_now();
+            let mut out = format!("ok {} entries", entries.len());
+            // One `ListEntry` per line, the client parses them back
+            for (ip, entry) in entries {
+                let _ = write!(out, "\n{}", export::list_entry(ip, entry, now));
+            }
+            for cidr in blocklist.management() {
+                let line = ListEntry {
+                    target: cidr.to_string(),
+                    origin: Origin::Management,
+                    node: None,
+                    expires_in: None,
+                };
+                let _ = write!(out, "\n{line}");
+            }
+            out
+        }
+        Command::Export { cursor, limit } => format!("ok {}", export::page(state, cursor, limit)),
+        Command::Tag(ip, op) => {
+            // The lock makes read-modify-write increments atomic across clients
+            let mut tags = state.tags.lock().unwrap();
+            let key = u32::from(ip);
+            let score = match op {
+                TagOp::Set(score) => score,
+                TagOp::Add(delta) => {
+                    let current = match tags.get(&key, 0) {
+                        Ok(score) => score,
+                        Err(aya::maps::MapError::KeyNotFound) => 0,
+                        Err(e) => return Err(e.into()),
+                    };
+                    (i64::from(current) + delta).clamp(0, i64::from(u32::MAX)) as u32
+                }
+            };
+            tags.insert(key, score, 0)?;
+            let note = if score >= TAG_MAX { " (blocked)" } else { "" };
+            format!("ok {ip} score {score}{note}")
+        }
+        Command::Untag(ip) => remove_key(&mut state.tags.lock().unwrap(), ip)?,
+        Command::Reset(ip) => {
+            let reply = remove_key(&mut state.rate_limit.lock().unwrap(), ip)?;
+            if reply == "ok" {
+                release(state, ip)?;
+            }
+            match remove_ports(state, ip)? {
+                0 => reply,
+                ports => format!("ok, and {ports} source port entries"),
+            }
+        }
+        Command::Flush(target) => flush(state, target)?,
+        Command::Suggest {
+            percentile,
+            safety,
+            apply,
+        } => {
+            let learner = state
+                .learner
+                .as_ref()
+                .ok_or_else(|| anyhow!("not learning, start the daemon with --learn"))?;
+            let suggestion = learner.lock().unwrap().suggest(percentile, safety);
+            let mut out = suggestion.render();
+            if apply {
+                let limit = suggestion
+                    .ip_limit
+                    .ok_or_else(|| anyhow!("nothing observed yet, not applying"))?
+                    .max(1);
+                state
+                    .config
+                    .lock()
+                    .unwrap()
+                    .update(|cfg| cfg.rate_limit = limit)?;
+                info!("applied suggested per-ip limit {limit}");
+                let _ = write!(out, "\napplied per-ip limit {limit} to the running program");
+                // The program has it either way, a file that can't be written is only reported
+                match &state.env_file {
+                    Some(path) => {
+                        let why = "Per-source limit, from `guardctl suggest --apply`";
+                        match preset::set_flag(path, "rate", &limit.to_string(), why) {
+                            Ok(()) => {
+                                let _ = write!(out, " and to {}", path.display());
+                            }
+                            Err(e) => {
+                                warn!("failed to keep the suggested limit: {e:#}");
+                                let _ = write!(out, ", not to {}: {e:#}", path.display());
+                            }
+                        }
+                    }
+                    None => out.push_str(", set --rate to keep it across restarts"),
+                }
+            }
+            out
+        }
+        Command::Offenders(n) => {
+            let cfg = state.config.lock().unwrap().get();
+            let offenders = heatmap::collect(
+                &state.rate_limit.lock().unwrap(),
+                &state.rate_limit6.lock().unwrap(),
+                &state.nat.lock().unwrap(),
+                &state.tags.lock().unwrap(),
+                &state.groups.lock().unwrap(),
+                &cfg,
+                timebase::boot_ns(),
+            );
+            heatmap::render(&offenders, n, state.geo.as_deref())
+        }
+        Command::Why(ip) => why(state, ip)?,
+        Command::Verify(target) => verify::run(state, target)?,
+        Command::Group(op) => group(state, op)?,
+        Command::Rules(filter) => rules::render(&state.rules.lock().unwrap().stats(filter)?),
+        Command::Status => {
+            let versions = &state.versions;
+            let mut out = format!("ok\nbinary  {}", Build(&versions.binary));
+            let _ = write!(out, "\nloaded  {}", Build(&versions.loaded));
+            if let Some(mismatch) = versions.mismatch() {
+                let _ = write!(out, "\nWARNING {mismatch}");
+            }
+            let cfg = state.config.lock().unwrap().get();
+            let _ = write!(out, "\nconfig  generation {}", cfg.generation);
+            if cfg.has(config_flags::PAUSED) {
+                out.push_str("\nfiltering paused, nothing is dropped");
+            }
+            let observed: Vec<&str> = (0..feature::LEN)
+                .filter(|&f| cfg.observes(f))
+                .map(|f| feature::NAMES[f as usize])
+                .collect();
+            if !observed.is_empty() {
+                let _ = write!(
+                    out,
+                    "\nobserving {}, their drops are counted and passed",
+                    observed.join(", ")
+                );
+            }
+            let blocklist = state.blocklist.lock().unwrap();
+            if let Some(bloom) = blocklist.bloom() {
+                let _ = write!(out, "\n{}", bloom.describe());
+            }
+            for held in blocklist.occupancy() {
+                let Some(quota) = held.quota else {
+                    continue;
+                };
+                let _ = write!(out, "\nquota {} {}/{quota}", held.label(), held.entries);
+                if held.rejected > 0 {
+                    let _ = write!(out, ", {} rejected", held.rejected);
+                }
+                if held.evicted > 0 {
+                    let _ = write!(out, ", {} evicted", held.evicted);
+                }
+            }
+            drop(blocklist);
+            if state.external {
+                out.push_str("\nexternal maps, loading and attaching is up to another loader");
+            } else {
+                let _ = write!(out, "\nverifier {}", state.verifier);
+                if let Some(chained) = &state.chained {
+                    let _ = write!(out, "\n{chained}");
+                }
+                out.push_str(if state.multi_buffer {
+                    "\nmulti-buffer XDP, jumbo frames are seen whole"
+                } else {
+                    "\nsingle-buffer XDP, drivers in multi-buffer mode refuse the program"
+                });
+            }
+            let _ = write!(out, "\n{}", timebase::describe());
+            out
+        }
+        Command::LastAbort => last_abort(state)?,
+        Command::LogLevel { program, level } => log_level(program.as_deref(), level)?,
+        Command::SnapshotSave { name, limiters } => snapshot::save(state, &name, limiters)?,
+        Command::SnapshotLoad(name) => snapshot::load(state, &name)?,
+        Command::Enforce { feature, observe } => set_enforce(state, feature, observe)?,
+        Command::Pause => set_paused(state, true)?,
+        Command::Resume => set_paused(state, false)?,
+        Command::Params => relax::params(state),
+        Command::Param(knob, setting) => relax::set(state, knob, setting)?,
+        Command::Resize(map, size) => {
+            let copied = resize::resize(state, map, size)?;
+            info!("{map} map grown to {size} entries, {copied} entries copied");
+            format!("ok {map} {size} entries, {copied} copied")
+        }
+        Command::Chain(pin) => {
+            if state.chained.is_some() {
+                bail!("chained into a dispatcher, which runs what comes next");
+            }
+            chain::set_next(&mut state.next_prog.lock().unwrap(), pin.as_deref())?;
+            "ok".to_owned()
+        }
+        Command::Profile(_) => unreachable!("handled by execute"),
+    };
+    Ok(out)
+}
+
+fn log_level(program: Option<&str>, level: Option<LevelFilter>) -> anyhow::Result<String> {
+    let pumps: Vec<_> = logpump::pumps()
+        .into_iter()
+        .filter(|pump| {
+            program.is_none_or(|name| pump.origin().is_some_and(|origin| origin.program == name))
+        })
+        .collect();
+    if pumps.is_empty() {
+        match program {
+            Some(name) => bail!("no program {name:?} logs through the daemon"),
+            None => bail!("no program logs through the daemon"),
+        }
+    }
+    if let Some(level) = level {
+        for pump in &pumps {
+            pump.set_level(level);
+            info!("eBPF log level of {} set to {level}", pump.label());
+        }
+    }
+    let mut out = format!("ok {} programs", pumps.len());
+    for pump in &pumps {
+        let _ = write!(
+            out,
+            "\n{:<32} {:<5}  {} lines logged, {} held back",
+            pump.label(),
+            pump.level().as_str().to_lowercase(),
+            pump.logged.load(Ordering::Relaxed),
+            pump.suppressed.load(Ordering::Relaxed)
+        );
+    }
+    Ok(out)
+}
+
+fn last_abort(state: &ControlState) -> anyhow::Result<String> {
+    let records = state.last_abort.lock().unwrap().get(&0, 0)?;
+    let now = timebase::stamp_ns();
+    let total: u64 = records.iter().map(|record| record.count).sum();
+    let mut out = format!("ok {total} aborted");
+    for (cpu, record) in records.iter().enumerate() {
+        if record.count == 0 {
+            continue;
+        }
+        let _ = write!(
+            out,
+            "\ncpu {cpu}: {} aborted, last {}s ago: {} failed at offset {} on a {}-byte frame \
+             from ifindex {}",
+            record.count,
+            now.saturating_sub(record.at) / 1_000_000_000,
+            abort::name(record.reason),
+            record.offset,
+            record.len,
+            record.ifindex
+        );
+        let head = &record.head[..usize::from(record.captured).min(record.head.len())];
+        for (line, bytes) in head.chunks(16).enumerate() {
+            let _ = write!(out, "\n  {:04x} ", line * 16);
+            for byte in bytes {
+                let _ = write!(out, " {byte:02x}");
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn why(state: &ControlState, ip: Ipv4Addr) -> anyhow::Result<String> {
+    let cfg = state.config.lock().unwrap().get();
+    let key = u32::from(ip);
+    let mut out = format!("ok {ip}");
+    let (blocked, allowed);
+    {
+        let blocklist = state.blocklist.lock().unwrap();
+        match blocklist.get(ip) {
+            Some(entry) => {
+                let _ = write!(out, "\nblocklist   {}", entry.origin.name());
+                if let Some(node) = &entry.node {
+                    let _ = write!(out, " from {node}");
+                }
+                if let Some(at) = entry.expires {
+                    let now = timebase::unix_now();
+                    let _ = write!(out, ", expires in {}s", at.saturating_sub(now));
+                }
+            }
+            None => out.push_str("\nblocklist   not listed"),
+        }
+        if let Some(history) = blocklist.history(ip) {
+            let _ = write!(out, "\nbans        {history}");
+        }
+        if let Some(cidr) = blocklist.management().iter().find(|c| c.contains(ip)) {
+            let _ = write!(out, "\nmanagement  {cidr}, never blocked or limited");
+        }
+        let decision = blocklist.effective(ip);
+        if let Some(origin) = decision {
+            let _ = write!(out, "\ndecision    {}", origin.name());
+        }
+        blocked = decision.is_some_and(|origin| origin.action() == ACTION_DROP);
+        allowed = decision.is_some_and(|origin| origin.action() == ACTION_ALLOW);
+    }
+    let zone = if cfg.is_local(key) {
+        "local subnet"
+    } else {
+        "external"
+    };
+    let _ = write!(out, "\nzone        {zone}");
+    if let Some(found) = state.geo.as_ref().and_then(|geo| geo.lookup(ip.into())) {
+        let _ = write!(out, "\nnetwork     {found}");
+    }
+    let groups = state.groups.lock().unwrap();
+    if let Some((group, cidr)) = groups.lookup(ip) {
+        let _ = write!(
+            out,
+            "\ngroup       {} via {cidr}, action {} instead of the zone's",
+            group.name,
+            groups::action_name(group.policy.action)
+        );
+    }
+    let base = groups.limit(&cfg, key);
+    drop(groups);
+    let limit = match state.tags.lock().unwrap().get(&key, 0) {
+        Ok(score) => {
+            let _ = write!(out, "\ntag         score {score}");
+            tagged_limit(base, score)
+        }
+        Err(aya::maps::MapError::KeyNotFound) => base,
+        Err(e) => return Err(e.into()),
+    };
+    // Behind a NAT pool the limiter entry holds the whole address, each port has its own
+    let nat = state
+        .nat_prefixes
+        .iter()
+        .find(|cidr| cidr.contains(ip))
+        .filter(|_| cfg.nat_limit != 0);
+    let (limit, burst, all) = match nat {
+        Some(cidr) => {
+            let (ports, busiest) = nat_ports(state, key, &cfg)?;
+            let _ = write!(
+                out,
+                "\nnat         {cidr}, {ports} source ports in their current window, the busiest \
+                 with {busiest} of {limit} packets"
+            );
+            (cfg.nat_limit, 0, ", all ports")
+        }
+        None => (limit, cfg.burst, ""),
+    };
+    match state.rate_limit.lock().unwrap().get(&key, 0) {
+        Ok(log) => {
+            let now = timebase::boot_ns();
+            let count = log.current_count(now, cfg.window_ns);
+            let _ = write!(
+                out,
+                "\nlimiter     {count} of {limit} packets in the current window{all}"
+            );
+            // Blocked packets only reach the limiter with --account-blocked-in-tracking
+            if blocked {
+                out.push_str(match cfg.account_blocked {
+                    0 => ", not counted while blocked",
+                    _ => ", counted while blocked",
+                });
+            }
+            // Nor do allowed ones without --track-allowlisted
+            if allowed {
+                out.push_str(match cfg.track_allowed {
+                    0 => ", not counted while allowed",
+                    _ => ", counted while allowed",
+                });
+            }
+            if burst != 0 {
+                let _ = write!(out, "\nburst       {} of {burst} credit left", log.credit);
+            }
+            let divisor = cfg.retransmit_divisor;
+            let (packets, retransmits) = log.current_packets(now, cfg.window_ns, divisor);
+            if retransmits != 0 {
+                let _ = write!(
+                    out,
+                    "\nretransmits {retransmits} of {packets} packets in the current window \
+                     ({:.1}%)",
+                    retransmits as f64 / packets as f64 * 100.0
+                );
+                if divisor != 0 {
+                    let _ = write!(out, ", charged one in {divisor}");
+                }
+            }
+        }
+        Err(aya::maps::MapError::KeyNotFound) => {
+            let prefix = key & PREFIX_MASK;
+            let full = cfg.prefix_quota != 0
+                && state
+                    .prefix_sources
+                    .lock()
+                    .unwrap()
+                    .get(&prefix, 0)
+                    .is_ok_and(|sources| sources >= cfg.prefix_quota);
+            if !full {
+                let _ = write!(out, "\nlimiter     no entry, limit {limit}");
+            } else {
+                let count = state
+                    .prefix
+                    .lock()
+                    .unwrap()
+                    .get(&prefix, 0)
+                    .map_or(0, |log| {
+                        log.current_count(timebase::boot_ns(), cfg.window_ns)
+                    });
+                let _ = write!(
+                    out,
+                    "\nlimiter     no entry, its /16 is at --prefix-quota: {count} of {} \
+                     packets shared in the current window",
+                    cfg.prefix_limit
+                );
+            }
+        }
+        Err(e) => return Err(e.into()),
+    }
+    if let Some(conversions) = &state.conversions {
+        let conversions = conversions.lock().unwrap();
+        if let Some(total) = conversions.total(ip) {
+            let _ = write!(
+                out,
+                "\nhandshakes  {} of {} SYNs converted since tracked",
+                total.converted, total.syns
+            );
+            if let Some(recent) = conversions.recent(ip) {
+                let _ = write!(
+                    out,
+                    ", {} of {} in the last {}s ({:.1}%)",
+                    recent.converted,
+                    recent.syns,
+                    conversions.interval().as_secs(),
+                    recent.ratio() * 100.0
+                );
+                if let Some(rule) = conversions.rule()
+                    && recent.syns >= rule.min_syns
+                    && recent.ratio() < rule.below
+                {
+                    out.push_str(", below --syn-conversion-ban-below");
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn group(state: &ControlState, op: GroupOp) -> anyhow::Result<String> {
+    let mut config = state.config.lock().unwrap();
+    let mut groups = state.groups.lock().unwrap();
+    let out = match op {
+        GroupOp::List => {
+            let cfg = config.get();
+            return groups.render(
+                &state.rate_limit.lock().unwrap(),
+                &state.tags.lock().unwrap(),
+                &cfg,
+                timebase::boot_ns(),
+            );
+        }
+        GroupOp::Add(name, cidr) => {
+            if !groups.add(&name, cidr)? {
+                return Ok("ok unchanged".to_owned());
+            }
+            info!("added {cidr} to group {name}");
+            "ok".to_owned()
+        }
+        GroupOp::Del(name, cidr) => {
+            if !groups.remove(&name, cidr)? {
+                return Ok(format!("ok {cidr} not in group {name}"));
+            }
+            info!("removed {cidr} from group {name}");
+            "ok".to_owned()
+        }
+        GroupOp::Rate(name, rate) => {
+            groups.set_rate(&name, rate)?;
+            info!("group {name} rate set to {rate}");
+            "ok".to_owned()
+        }
+        GroupOp::Action(name, action) => {
+            groups.set_action(&name, action)?;
+            info!("group {name} action set to {}", groups::action_name(action));
+            "ok".to_owned()
+        }
+    };
+    // The kernel only looks sources up while some group has members
+    let members = groups.has_members();
+    if config.get().has(config_flags::GROUPS) != members {
+        config.update(|cfg| {
+            if members {
+                cfg.flags |= config_flags::GROUPS;
+            } else {
+                cfg.flags &= !config_flags::GROUPS;
+            }
+        })?;
+    }
+    Ok(out)
+}
+
+fn set_enforce(state: &ControlState, feature: u32, observe: bool) -> anyhow::Result<String> {
+    let name = feature::NAMES[feature as usize];
+    let mode = if observe { "observing" } else { "enforcing" };
+    let mut config = state.config.lock().unwrap();
+    if config.get().observes(feature) == observe {
+        return Ok(format!("ok {name} already {mode}"));
+    }
+    config.update(|cfg| {
+        if observe {
+            cfg.observe |= 1 << feature;
+        } else {
+            cfg.observe &= !(1 << feature);
+        }
+    })?;
+    if observe {
+        warn!("{name} observing, packets it would drop are passed");
+    } else {
+        info!("{name} enforcing");
+    }
+    Ok(format!("ok {name} {mode}"))
+}
+
+fn set_paused(state: &ControlState, paused: bool) -> anyhow::Result<String> {
+    let mut config = state.config.lock().unwrap();
+    if config.get().has(config_flags::PAUSED) == paused {
+        return Ok(format!(
+            "ok already {}",
+            if paused { "paused" } else { "running" }
+        ));
+    }
+    config.update(|cfg| {
+        if paused {
+            cfg.flags |= config_flags::PAUSED;
+        } else {
+            cfg.flags &= !config_flags::PAUSED;
+        }
+    })?;
+    if paused {
+        warn!("filtering paused, packets that would be dropped are passed");
+        Ok("ok paused".to_owned())
+    } else {
+        info!("filtering resumed");
+        Ok("ok resumed".to_owned())
+    }
+}
+
+fn flush(state: &ControlState, target: FlushTarget) -> anyhow::Result<String> {
+    let removed = match target {
+        FlushTarget::RateLimit => {
+            let removed = clear(&mut state.rate_limit.lock().unwrap())?
+                + clear(&mut state.prefix.lock().unwrap())?
+                + clear(&mut state.nat.lock().unwrap())?
+                + clear(&mut state.rate_limit6.lock().unwrap())?
+                + clear(&mut state.quic_initial.lock().unwrap())?
+                + clear_if(&state.ack)?
+                + clear_if(&state.http)?
+                + clear_if(&state.service)?;
+            // With the entries gone every /16 has its whole quota again
+            clear(&mut state.prefix_sources.lock().unwrap())?;
+            removed
+        }
+        FlushTarget::Bans | FlushTarget::Blocklist { .. } => state
+            .blocklist
+            .lock()
+            .unwrap()
+            .remove_where(|origin| target.flushes(origin))?,
+        FlushTarget::Conntrack => {
+            let cfg = state.config.lock().unwrap().get();
+            let Some(conntrack) = &state.conntrack else {
+                bail!("the eBPF program is built without connection tracking");
+            };
+            if !cfg.has(config_flags::CONNTRACK) {
+                bail!("connection tracking is not enabled");
+            }
+            clear(&mut conntrack.lock().unwrap())?
+        }
+        FlushTarget::Stats => {
+            state.stats.lock().unwrap().flush()?;
+            return Ok("ok stats reset".to_owned());
+        }
+    };
+    info!("flushed {target:?}: {removed} entries removed");
+    Ok(format!("ok removed {removed} entries"))
+}
+
+// Nothing to clear in a map the program was built without
+fn clear_if<K: aya::Pod, V: aya::Pod>(
+    map: &Option<Mutex<HashMap<MapData, K, V>>>,
+) -> anyhow::Result<usize> {
+    match map {
+        Some(map) => clear(&mut map.lock().unwrap()),
+        None => Ok(0),
+    }
+}
+
+/// Deletes every key of `map`, returning how many there were.
+pub fn clear<K: aya::Pod, V: aya::Pod>(map: &mut HashMap<MapData, K, V>) -> anyhow::Result<usize> {
+    let keys = map.keys().collect::<Result<Vec<K>, _>>()?;
+    for key in &keys {
+        match map.remove(key) {
+            // Raced with the kernel or another flush, either way it is gone
+            Ok(()) | Err(aya::maps::MapError::KeyNotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(keys.len())
+}
+
+// The reply to a blocklist write, with the state the write left behind
+fn applied(result: Applied, state: &str) -> String {
+    match result {
+        Applied::Written => format!("ok {state}"),
+        Applied::Unchanged => format!("ok unchanged, {state}"),
+        Applied::Suppressed(winner) => {
+            format!("err {} entry takes precedence, {state}", winner.name())
+        }
+        Applied::OverQuota(max) => {
+            format!("err the origin's --blocklist-quota of {max} entries is full, {state}")
+        }
+    }
+}
+
+/// What the blocklist holds for `ip`, e.g. `1.2.3.4 manual-block, expires in 300s`. Part of
+/// every blocklist reply, so callers can reconcile without a `why`.
+fn entry_state(blocklist: &BlocklistHandle, ip: Ipv4Addr) -> String {
+    let mut out = format!("{ip} ");
+    match blocklist.get(ip) {
+        Some(entry) => {
+            out.push_str(entry.origin.name());
+            if let Some(node) = &entry.node {
+                let _ = write!(out, " from {node}");
+            }
+            if let Some(at) = entry.expires {
+                let now = timebase::unix_now();
+                let _ = write!(out, ", expires in {}s", at.saturating_sub(now));
+            }
+        }
+        None => out.push_str("not listed"),
+    }
+    if blocklist.in_management(ip) {
+        out.push_str(", in a management network");
+    }
+    out
+}
+
+// Source ports of the NAT address `addr` in their current window, and the most packets one
+// of them sent in it
+fn nat_ports(state: &ControlState, addr: u32, cfg: &Config) -> anyhow::Result<(usize, u64)> {
+    let now = timebase::boot_ns();
+    let nat = state.nat.lock().unwrap();
+    let mut ports = 0;
+    let mut busiest = 0;
+    for entry in nat.iter() {
+        let (key, log) = entry?;
+        let count = log.current_count(now, cfg.window_ns);
+        if nat_source(key).0 != addr || count == 0 {
+            continue;
+        }
+        ports += 1;
+        busiest = busiest.max(count);
+    }
+    Ok((ports, busiest))
+}
+
+// Deletes the entries of every source port of `ip`, if it is a NAT address
+fn remove_ports(state: &ControlState, ip: Ipv4Addr) -> anyhow::Result<usize> {
+    if !state.nat_prefixes.iter().any(|cidr| cidr.contains(ip)) {
+        return Ok(0);
+    }
+    let mut nat = state.nat.lock().unwrap();
+    let keys = nat.keys().collect::<Result<Vec<u64>, _>>()?;
+    let mut removed = 0;
+    for key in keys
+        .into_iter()
+        .filter(|key| nat_source(*key).0 == u32::from(ip))
+    {
+        match nat.remove(&key) {
+            Ok(()) => removed += 1,
+            Err(aya::maps::MapError::KeyNotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(removed)
+}
+
+// Gives the slot of a deleted `RATE_LIMIT_MAP` entry back to its /16
+fn release(state: &ControlState, ip: Ipv4Addr) -> anyhow::Result<()> {
+    let prefix = u32::from(ip) & PREFIX_MASK;
+    let mut slots = state.prefix_sources.lock().unwrap();
+    match slots.get(&prefix, 0) {
+        Ok(sources) => slots.insert(prefix, sources.saturating_sub(1), 0)?,
+        Err(aya::maps::MapError::KeyNotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+fn remove_key<V: aya::Pod>(
+    map: &mut HashMap<MapData, u32, V>,
+    ip: Ipv4Addr,
+) -> anyhow::Result<String> {
+    match map.remove(&u32::from(ip)) {
+        Ok(()) => Ok("ok".to_owned()),
+        Err(aya::maps::MapError::KeyNotFound) => Ok("ok not present".to_owned()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn serve(
+    path: &Path,
+    state: Arc<ControlState>,
+    admission: Arc<Admission>,
+) -> anyhow::Result<()> {
+    // A socket left behind by a previous run would make bind fail
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            warn!("failed to remove stale socket {}: {e}", path.display())
+        }
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("failed to bind control socket {}", path.display()))?;
+    info!("control socket listening on {}", path.display());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let Ok(permit) = admission.connections.clone().try_acquire_owned() else {
+            // Best effort, a client that doesn't read its socket doesn't get to hold us up
+            let _ = stream.try_write(b"err too many control connections\n\n");
+            debug!("control client refused, all connections in use");
+            continue;
+        };
+        let state = state.clone();
+        let admission = admission.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &state, &admission).await {
+                debug!("control client: {e:#}");
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn handle(
+    stream: UnixStream,
+    state: &ControlState,
+    admission: &Admission,
+) -> anyhow::Result<()> {
+    let peer = stream.peer_cred()?;
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    let mut buf = Vec::new();
+    let mut strikes = 0;
+    loop {
+        buf.clear();
+        let limit = MAX_LINE_BYTES as u64 + 1;
+        if (&mut read).take(limit).read_until(b'\n', &mut buf).await? == 0 {
+            return Ok(());
+        }
+        if buf.len() > MAX_LINE_BYTES {
+            write
+                .write_all(
+                    format!("err requests are at most {MAX_LINE_BYTES} bytes\n\n").as_bytes(),
+                )
+                .await?;
+            bail!(
+                "uid {} sent a request over {MAX_LINE_BYTES} bytes",
+                peer.uid()
+            );
+        }
+        let Ok(line) = std::str::from_utf8(&buf) else {
+            write.write_all(b"err request is not UTF-8\n\n").await?;
+            continue;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (_, command) = replay::split_key(line);
+        let response = match admission.admit(&peer, Command::parse(command).ok().as_ref()) {
+            Ok(()) => {
+                strikes = 0;
+                execute_line(state, line).await
+            }
+            Err(refusal) => {
+                strikes += 1;
+                format!("err {refusal}")
+            }
+        };
+        write.write_all(response.as_bytes()).await?;
+        write.write_all(b"\n\n").await?;
+        if strikes == MAX_STRIKES {
+            warn!(
+                "control client uid {} pid {}: closed after {MAX_STRIKES} refused requests in a row",
+                peer.uid(),
+                peer.pid().unwrap_or(0)
+            );
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Management networks are in their own trie, never blocklist entries
+    fn origins() -> impl Iterator<Item = Origin> {
+        (1..=7)
+            .filter_map(Origin::from_u8)
+            .filter(|&origin| origin != Origin::Management)
+    }
+
+    fn flushed(words: &str) -> Vec<&'static str> {
+        let words: Vec<&str> = words.split_whitespace().collect();
+        let target = parse_flush(&words).unwrap();
+        origins()
+            .filter(|&origin| target.flushes(origin))
+            .map(Origin::name)
+            .collect()
+    }
+
+    #[test]
+    fn flushes_take_only_the_origins_they_name() {
+        assert_eq!(flushed("bans"), ["auto-ban", "policy-module"]);
+        assert_eq!(flushed("blocklist --origin feed"), ["feed"]);
+        assert_eq!(flushed("blocklist --origin cluster"), ["cluster"]);
+        assert_eq!(
+            flushed("blocklist"),
+            ["feed", "cluster", "auto-ban", "policy-module"]
+        );
+        assert_eq!(flushed("blocklist --all").len(), 6);
+        assert_eq!(
+            flushed("blocklist --origin manual-block --all"),
+            ["manual-block"]
+        );
+        for target in ["rate-limit", "conntrack", "stats"] {
+            assert!(flushed(target).is_empty(), "{target}");
+        }
+    }
+
+    #[test]
+    fn manual_entries_need_all() {
+        for origin in ["manual-block", "manual-allow"] {
+            let e = parse_flush(&["blocklist", "--origin", origin]).unwrap_err();
+            assert!(e.to_string().contains("--all"), "{e}");
+        }
+        assert!(parse_flush(&["blocklist", "--origin", "nobody"]).is_err());
+        assert!(parse_flush(&["blocklist", "--origin"]).is_err());
+        assert!(parse_flush(&["blocklist", "--everything"]).is_err());
+        assert!(parse_flush(&["tags"]).is_err());
+        assert!(parse_flush(&[]).is_err());
+    }
+}
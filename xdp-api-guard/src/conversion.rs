@@ -0,0 +1,210 @@
+//! SYN-to-established conversion per source, the flood signal `--syn-conversion-ban-below`
+//! bans on.
+//!
+//! Counting SYNs misfires on clients that open connections in bursts; what a flood has and
+//! they don't is SYNs that never go anywhere. With `--conntrack` the kernel counts, per
+//! source in `SYN_SOURCES`, the SYNs that opened a `CONNTRACK` flow and the flows that went
+//! on to a packet without SYN (`flow_flags::CONVERTED`). Every `--syn-conversion-interval`
+//! seconds the difference to the last read gives each source's SYNs and conversions in the
+//! interval, for `guardctl why` and the rule. A source with at least `--min-syns` SYNs in
+//! the interval and a smaller share of them converted than the threshold gets an auto-ban.
+//!
+//! A SYN late in one interval converts in the next, so a source's ratio is capped at 1.
+
+use std::{collections::HashMap as StdHashMap, net::Ipv4Addr, sync::Arc, time::Duration};
+
+use aya::maps::{HashMap, MapData};
+use log::{debug, warn};
+use xdp_api_guard_common::{Handshakes, Origin};
+
+use crate::{
+    blocklist::{Applied, Entry},
+    control::ControlState,
+    mask::Masked,
+    timebase,
+};
+
+/// From `--syn-conversion-ban-below`, `--min-syns` and `--syn-conversion-ban-ttl`.
+#[derive(Clone, Copy, Debug)]
+pub struct BanRule {
+    /// Share of a source's SYNs below which it is banned, in 0..1.
+    pub below: f64,
+    /// SYNs in an interval before a source is judged at all.
+    pub min_syns: u64,
+    /// Seconds of the ban, 0 for one until removed.
+    pub ttl: u64,
+}
+
+/// A source's SYNs and conversions in the last interval.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interval {
+    pub syns: u64,
+    pub converted: u64,
+}
+
+impl Interval {
+    /// Share of the SYNs that converted.
+    pub fn ratio(&self) -> f64 {
+        if self.syns == 0 {
+            return 1.0;
+        }
+        (self.converted as f64 / self.syns as f64).min(1.0)
+    }
+}
+
+pub struct Conversions {
+    map: HashMap<MapData, u32, Handshakes>,
+    interval: Duration,
+    rule: Option<BanRule>,
+    // The counters at the last read, `None` before the first, which only sets the baseline
+    last: Option<StdHashMap<u32, Handshakes>>,
+    // Sources with SYNs in the last interval
+    recent: StdHashMap<u32, Interval>,
+}
+
+impl Conversions {
+    pub fn new(
+        map: HashMap<MapData, u32, Handshakes>,
+        interval: Duration,
+        rule: Option<BanRule>,
+    ) -> Self {
+        Self {
+            map,
+            interval,
+            rule,
+            last: None,
+            recent: StdHashMap::new(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn rule(&self) -> Option<BanRule> {
+        self.rule
+    }
+
+    /// What `ip` did in the last interval, `None` without SYNs.
+    pub fn recent(&self, ip: Ipv4Addr) -> Option<Interval> {
+        self.recent.get(&u32::from(ip)).copied()
+    }
+
+    /// The kernel's counters of `ip` since its entry was created.
+    pub fn total(&self, ip: Ipv4Addr) -> Option<Handshakes> {
+        self.map.get(&u32::from(ip), 0).ok()
+    }
+
+    /// Reads the counters and takes the difference to the last read. Returns the sources the
+    /// rule finds below its threshold.
+    fn sample(&mut self) -> Vec<(Ipv4Addr, Interval)> {
+        // Entries the kernel evicts during the walk are skipped, the next read of their
+        // source starts it over
+        let now: StdHashMap<u32, Handshakes> = self.map.iter().filter_map(Result::ok).collect();
+        let Some(last) = self.last.replace(now) else {
+            return Vec::new();
+        };
+        let now = self.last.as_ref().unwrap();
+        self.recent = now
+            .iter()
+            .filter_map(|(&ip, counts)| {
+                // A source new to the map, or evicted and back, starts from nothing
+                let before = last
+                    .get(&ip)
+                    .filter(|b| b.syns <= counts.syns && b.converted <= counts.converted)
+                    .copied()
+                    .unwrap_or_default();
+                let interval = Interval {
+                    syns: counts.syns - before.syns,
+                    converted: counts.converted - before.converted,
+                };
+                (interval.syns != 0).then_some((ip, interval))
+            })
+            .collect();
+        let Some(rule) = self.rule else {
+            return Vec::new();
+        };
+        let mut below: Vec<_> = self
+            .recent
+            .iter()
+            .filter(|(_, interval)| interval.syns >= rule.min_syns && interval.ratio() < rule.below)
+            .map(|(&ip, &interval)| (Ipv4Addr::from(ip), interval))
+            .collect();
+        below.sort_unstable_by_key(|(ip, _)| *ip);
+        below
+    }
+}
+
+/// Samples every interval until the daemon stops, banning what the rule finds.
+pub fn start(control: Arc<ControlState>) {
+    let Some(conversions) = &control.conversions else {
+        return;
+    };
+    let interval = conversions.lock().unwrap().interval();
+    tokio::spawn(async move {
+        // The first tick is right away and only sets the baseline
+        let mut tick = tokio::time::interval(interval);
+        loop {
+            tick.tick().await;
+            let Some(conversions) = &control.conversions else {
+                return;
+            };
+            let (below, rule) = {
+                let mut conversions = conversions.lock().unwrap();
+                (conversions.sample(), conversions.rule())
+            };
+            let Some(rule) = rule else {
+                continue;
+            };
+            for (ip, interval) in below {
+                if let Err(e) = ban(&control, ip, interval, rule) {
+                    warn!("failed to ban {} for its SYNs: {e:#}", Masked(ip));
+                }
+            }
+        }
+    });
+}
+
+fn ban(
+    control: &ControlState,
+    ip: Ipv4Addr,
+    interval: Interval,
+    rule: BanRule,
+) -> anyhow::Result<()> {
+    let mut blocklist = control.blocklist.lock().unwrap();
+    // Its TTL runs from the first ban, as the policy module's do
+    if blocklist
+        .get(ip)
+        .is_some_and(|entry| entry.origin == Origin::AutoBan)
+    {
+        return Ok(());
+    }
+    let entry = Entry {
+        expires: (rule.ttl != 0).then(|| timebase::unix_now() + rule.ttl),
+        ..Entry::new(Origin::AutoBan)
+    };
+    match blocklist.insert_entry(ip, entry)? {
+        Applied::Written => {
+            // In the alerts' format, for whatever watches the log for them
+            let line = format!(
+                "ALERT syn-conversion: {} converted {} of {} SYNs ({:.1}%), below {:.1}%",
+                Masked(ip),
+                interval.converted,
+                interval.syns,
+                interval.ratio() * 100.0,
+                rule.below * 100.0
+            );
+            match rule.ttl {
+                0 => warn!("{line}, banned"),
+                ttl => warn!("{line}, banned for {ttl}s"),
+            }
+        }
+        Applied::Suppressed(origin) => debug!(
+            "{} converts too few SYNs, but its {} entry comes first",
+            Masked(ip),
+            origin.name()
+        ),
+        Applied::Unchanged | Applied::OverQuota(_) => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,2539 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, atomic::AtomicBool},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use aya::{
+    VerifierLogLevel,
+    maps::{IterableMap as _, lpm_trie::Key},
+    programs::{ProgramError, Xdp, XdpFlags},
+};
+use clap::{Parser, ValueEnum};
+#[rustfmt::skip]
+use log::{LevelFilter, debug, info, warn};
+use tokio::{
+    signal,
+    sync::{mpsc, oneshot},
+};
+use xdp_api_guard_common::{
+    BLOOM_WORDS, Config, DEFAULT_CONTROL_SOCKET, HTTP_PORTS, MALFORMED_KINDS, MAX_FEEDS, Origin,
+    cast_action, config_flags, dscp_action, feature, malformed_action, zone_action,
+};
+
+use crate::{
+    admission::{Admission, SocketLimits},
+    alert,
+    blocklist::{self, BlocklistHandle, Feed, Quotas},
+    bloom::{self, Bloom},
+    bounded, chain,
+    cidr::Ipv4Cidr,
+    config::ConfigHandle,
+    control::{self, ControlState},
+    conversion::{self, BanRule, Conversions},
+    dashboard,
+    decisions::{self, Alerts, DecisionLog, Settings},
+    fifo, flush,
+    geoip::GeoIp,
+    groups::{self, Groups},
+    health::Health,
+    http::{self, ApiState},
+    ifstats::Checker,
+    journal::{self, JournalStats},
+    learn::Learner,
+    link,
+    logpump::{self, LogOrigin, LogPump, PumpLogger},
+    malformed::{self, SampleStats},
+    maps::{Layout, Maps},
+    mask::{self, MaskMode},
+    neigh, normalize, preset,
+    recidivist::Recidivists,
+    relax::{self, Baselines, Controller, Knob, Range},
+    replay::ReplayCache,
+    report,
+    resize::{self, Resizable, Slots, Watch},
+    rules::Rules,
+    settings, setup,
+    stats::{Dataplane, StatsState},
+    statsd::{self, StatsdConfig},
+    sweep::{self, SweepStats},
+    tenants::{Tenants, Thresholds},
+    timebase,
+    tokens::Tokens,
+    trace::{self, Span},
+    trusted, verifier,
+    version::{Build, Versions},
+};
+
+/// Size of the `SERVICE_RATES` map.
+const MAX_SERVICES: usize = 64;
+
+/// Longest --window and --window6, in milliseconds. The kernel has them in nanoseconds.
+const MAX_WINDOW_MS: u64 = u64::MAX / 1_000_000;
+
+/// Longest --tracking-idle-secs, which the kernel has in nanoseconds too.
+const MAX_IDLE_SECS: u64 = u64::MAX / 1_000_000_000;
+
+#[derive(Debug, Parser)]
+#[clap(
+    after_help = "`xdp-api-guard init --preset PRESET --iface IFACE` writes a configuration \
+                     for a preset, see `xdp-api-guard init --help`; `xdp-api-guard setup` asks \
+                     for the same on a terminal; `xdp-api-guard print-config [OPTIONS]` shows \
+                     each option as it would be taken and where from; `xdp-api-guard flush \
+                     --pins DIR TARGET` flushes pinned maps while no daemon runs"
+)]
+pub(crate) struct Opt {
+    #[clap(short, long, default_value = "enp0s3", env = "GUARD_IFACE")]
+    iface: String,
+
+    /// IP address to block immediately at startup (Optional)
+    #[clap(long, env = "GUARD_BLOCK")]
+    block: Option<Ipv4Addr>,
+
+    /// IP address that is always let through, even if a feed lists it (repeatable)
+    #[clap(long, env = "GUARD_ALLOW", value_delimiter = ',')]
+    allow: Vec<Ipv4Addr>,
+
+    /// Management network that can never be blocked or rate limited (repeatable)
+    #[clap(long = "mgmt-cidr", env = "GUARD_MGMT_CIDR", value_delimiter = ',')]
+    mgmt_cidr: Vec<Ipv4Cidr>,
+
+    /// File with one IP address per line to block, as [NAME=]PATH (repeatable, at most 8).
+    /// Drops are counted per feed under NAME, by default the file name without extension
+    #[clap(long, env = "GUARD_FEED", value_delimiter = ',')]
+    feed: Vec<Feed>,
+
+    /// Refuse to start when startup entries conflict, e.g. a feed lists an allowed address,
+    /// instead of resolving them by precedence
+    #[clap(long, env = "GUARD_STRICT_CONFLICTS")]
+    strict_conflicts: bool,
+
+    /// Check IPv4 sources against a Bloom filter of the blocklist before the blocklist
+    /// itself, for lists of hundreds of thousands of entries. Takes 2 MiB of kernel memory
+    #[clap(long, env = "GUARD_BLOCKLIST_BLOOM")]
+    blocklist_bloom: bool,
+
+    /// Most entries an origin may hold in the blocklist, as KEY=MAX with KEY an origin
+    /// (auto-ban, policy-module, cluster, manual-block, manual-allow), feed:NAME for one feed
+    /// or feed for every feed without its own (repeatable) [default: unlimited]. Bans evict
+    /// their oldest entry at their quota, any other origin is refused; a feed over its quota
+    /// keeps its last MAX lines
+    #[clap(
+        long,
+        value_name = "KEY=MAX",
+        value_parser = parse_blocklist_quota,
+        value_delimiter = ',',
+        env = "GUARD_BLOCKLIST_QUOTA"
+    )]
+    blocklist_quota: Vec<(String, usize)>,
+
+    /// How often to rebuild the Bloom filter without the bits of removed entries, if there
+    /// were any
+    #[clap(
+        long,
+        value_name = "SECS",
+        default_value_t = bloom::DEFAULT_REBUILD.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "blocklist_bloom",
+        env = "GUARD_BLOOM_REBUILD_SECS"
+    )]
+    bloom_rebuild_secs: u64,
+
+    /// Packets allowed per IPv4 source per window
+    #[clap(long, default_value_t = 10, env = "GUARD_RATE")]
+    rate: u64,
+
+    /// Rate-limit window for IPv4 sources, in milliseconds
+    #[clap(
+        long,
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(u64).range(1..=MAX_WINDOW_MS),
+        env = "GUARD_WINDOW"
+    )]
+    window: u64,
+
+    /// Packets allowed per IPv6 source per window [default: --rate]
+    #[clap(long, env = "GUARD_RATE6")]
+    rate6: Option<u64>,
+
+    /// Rate-limit window for IPv6 sources, in milliseconds [default: --window]
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..=MAX_WINDOW_MS),
+        env = "GUARD_WINDOW6"
+    )]
+    window6: Option<u64>,
+
+    /// Packets a source may send over --rate/--rate6 in short bursts. The credit refills by
+    /// a tenth for every window the source stays within its limit (0 disables)
+    #[clap(long, default_value_t = 0, env = "GUARD_BURST")]
+    burst: u64,
+
+    /// Drop probabilistically near the limit (weighted random early drop) instead of all at
+    /// once past it
+    #[clap(long, env = "GUARD_WRED")]
+    wred: bool,
+
+    /// Percent of its limit where a source starts losing packets with --wred
+    #[clap(
+        long,
+        default_value_t = 80,
+        value_parser = clap::value_parser!(u8).range(0..100),
+        env = "GUARD_WRED_LOW"
+    )]
+    wred_low: u8,
+
+    /// Percent of its limit past which every packet is dropped with --wred
+    #[clap(
+        long,
+        default_value_t = 100,
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        env = "GUARD_WRED_HIGH"
+    )]
+    wred_high: u8,
+
+    /// Packets allowed per --window from every source together, each already within its own
+    /// limit (0 disables). Past it everything new is dropped
+    #[clap(long, default_value_t = 0, env = "GUARD_GLOBAL_RATE")]
+    global_rate: u64,
+
+    /// Percent of --global-rate past which packets of heavy sources are dropped early, more
+    /// likely the fuller the budget and the closer the source is to its own limit. Light
+    /// sources are spared (off by default)
+    #[clap(
+        long,
+        value_name = "PERCENT",
+        value_parser = parse_percent,
+        requires = "global_rate",
+        env = "GUARD_RED_START"
+    )]
+    red_start: Option<u8>,
+
+    /// Percent of --global-rate where early drops reach full strength
+    #[clap(
+        long,
+        value_name = "PERCENT",
+        default_value = "95",
+        value_parser = parse_percent,
+        env = "GUARD_RED_FULL"
+    )]
+    red_full: u8,
+
+    /// Local subnet, e.g. the LAN on a gateway. Its IPv4 sources get --local-rate and
+    /// --local-action, every other IPv4 source gets --rate and --external-action
+    #[clap(long, value_name = "CIDR", env = "GUARD_LOCAL_SUBNET")]
+    local_subnet: Option<Ipv4Cidr>,
+
+    /// Carrier-grade NAT pool (repeatable). Its addresses are limited per source port, each
+    /// port to --rate, and the address as a whole to --nat-rate
+    #[clap(
+        long,
+        value_name = "CIDR",
+        env = "GUARD_NAT_PREFIX",
+        value_delimiter = ','
+    )]
+    nat_prefix: Vec<Ipv4Cidr>,
+
+    /// Packets allowed per window to one --nat-prefix address, all its ports together
+    /// [default: 64 times --rate]
+    #[clap(long, requires = "nat_prefix", env = "GUARD_NAT_RATE")]
+    nat_rate: Option<u64>,
+
+    /// Packets allowed per local-subnet source per window [default: --rate]
+    #[clap(long, requires = "local_subnet", env = "GUARD_LOCAL_RATE")]
+    local_rate: Option<u64>,
+
+    /// Default action for sources in --local-subnet
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = ZoneAction::Limit,
+        requires = "local_subnet",
+        env = "GUARD_LOCAL_ACTION"
+    )]
+    local_action: ZoneAction,
+
+    /// Default action for IPv4 sources outside --local-subnet
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = ZoneAction::Limit,
+        env = "GUARD_EXTERNAL_ACTION"
+    )]
+    external_action: ZoneAction,
+
+    /// What packets to multicast addresses (224.0.0.0/4, ff00::/8) get: pass, drop or
+    /// ratelimit:N, N for all of them together per --window. Their senders aren't tracked.
+    /// Without it they are treated like unicast
+    #[clap(long, value_name = "POLICY", value_parser = parse_cast, env = "GUARD_MULTICAST")]
+    multicast: Option<CastPolicy>,
+
+    /// The same for broadcast: 255.255.255.255 and the broadcast address of --local-subnet
+    #[clap(long, value_name = "POLICY", value_parser = parse_cast, env = "GUARD_BROADCAST")]
+    broadcast: Option<CastPolicy>,
+
+    /// Start with filtering paused: verdicts are counted, nothing is dropped (see `guardctl
+    /// resume`)
+    #[clap(long, env = "GUARD_PAUSED")]
+    paused: bool,
+
+    /// Features that only count what they would drop and let it through, the others enforce
+    /// (comma separated: blocklist, bans, rate-limit, zone, service-rate, quic-initial,
+    /// ack-flood, http-flood, tiny-mss, icmp-inner, dscp, global, sni, multicast, broadcast).
+    /// See `guardctl enforce`
+    #[clap(
+        long,
+        value_name = "FEATURE",
+        value_delimiter = ',',
+        value_parser = parse_feature,
+        env = "GUARD_OBSERVE"
+    )]
+    observe: Vec<u32>,
+
+    /// Only apply the blocklist: no rate limiting of any kind, no limiter state kept
+    #[clap(long, env = "GUARD_NO_RATE_LIMIT")]
+    no_rate_limit: bool,
+
+    /// Keep counting blocklisted IPv4 sources in their limiter entry, so a source still
+    /// flooding when its block ends is over its limit right away
+    #[clap(
+        long,
+        conflicts_with = "no_rate_limit",
+        env = "GUARD_ACCOUNT_BLOCKED_IN_TRACKING"
+    )]
+    account_blocked_in_tracking: bool,
+
+    /// Count allowlisted and management IPv4 sources in the limiter too, without limiting
+    /// them, so `offenders` and the reports show them. By default they skip it
+    #[clap(
+        long,
+        conflicts_with = "no_rate_limit",
+        env = "GUARD_TRACK_ALLOWLISTED"
+    )]
+    track_allowlisted: bool,
+
+    /// Most limiter entries the IPv4 sources of one /16 may hold (0 for no limit). The rest
+    /// of the /16 shares a single entry, limited to --prefix-rate
+    #[clap(long, default_value_t = 64, env = "GUARD_PREFIX_QUOTA")]
+    prefix_quota: u32,
+
+    /// Packets allowed per --window to the sources of a /16 sharing its entry
+    /// [default: --prefix-quota times --rate]
+    #[clap(long, env = "GUARD_PREFIX_RATE")]
+    prefix_rate: Option<u64>,
+
+    /// QUIC long-header packets allowed per source per --window on --quic-port,
+    /// counted separately from --rate (0 disables QUIC inspection)
+    #[clap(long, default_value_t = 0, env = "GUARD_QUIC_INITIAL_LIMIT")]
+    quic_initial_limit: u64,
+
+    /// UDP port QUIC traffic arrives on
+    #[clap(long, default_value_t = 443, env = "GUARD_QUIC_PORT")]
+    quic_port: u16,
+
+    /// Track TCP flows whose SYN was passed, so `--ack-limit` can tell them apart
+    #[clap(long, env = "GUARD_CONNTRACK")]
+    conntrack: bool,
+
+    /// Bare ACKs allowed per source per --window for flows conntrack has not seen,
+    /// counted separately from --rate (0 disables, implies --conntrack)
+    #[clap(long, default_value_t = 0, env = "GUARD_ACK_LIMIT")]
+    ack_limit: u64,
+
+    /// Auto-ban sources with at least --min-syns SYNs in a --syn-conversion-interval of
+    /// which a smaller share than this, 0 to 1, became established flows. Needs --conntrack
+    #[clap(
+        long,
+        value_name = "RATIO",
+        value_parser = parse_ratio,
+        env = "GUARD_SYN_CONVERSION_BAN_BELOW"
+    )]
+    syn_conversion_ban_below: Option<f64>,
+
+    /// SYNs a source sends in an interval before --syn-conversion-ban-below judges it
+    #[clap(long, default_value_t = 100, env = "GUARD_MIN_SYNS")]
+    min_syns: u64,
+
+    /// Seconds over which SYN conversion is measured
+    #[clap(long, default_value_t = 10, env = "GUARD_SYN_CONVERSION_INTERVAL")]
+    syn_conversion_interval: u64,
+
+    /// Seconds of a --syn-conversion-ban-below ban, 0 for one until removed
+    #[clap(long, default_value_t = 600, env = "GUARD_SYN_CONVERSION_BAN_TTL")]
+    syn_conversion_ban_ttl: u64,
+
+    /// Charge TCP segments a tracked flow already sent at a fraction of a packet against
+    /// --rate, so clients on lossy links aren't limited for resending. Needs --conntrack
+    #[clap(long, env = "GUARD_FORGIVE_RETRANSMISSIONS")]
+    forgive_retransmissions: bool,
+
+    /// With --forgive-retransmissions, a source is charged one in this many of the
+    /// retransmissions it sends in a --window
+    #[clap(
+        long,
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u8).range(2..),
+        env = "GUARD_RETRANSMIT_DIVISOR"
+    )]
+    retransmit_divisor: u8,
+
+    /// HTTP requests allowed per source per second on --http-ports, counted from segments
+    /// that start with a request method (0 disables payload inspection)
+    #[clap(long, default_value_t = 0, env = "GUARD_HTTP_RPS_LIMIT")]
+    http_rps_limit: u64,
+
+    /// Plaintext HTTP ports for --http-rps-limit, at most 4
+    #[clap(
+        long,
+        default_value = "80",
+        value_delimiter = ',',
+        env = "GUARD_HTTP_PORTS"
+    )]
+    http_ports: Vec<u16>,
+
+    /// New TCP connections (SYNs) allowed per --window to a destination port, from all
+    /// sources together, as PORT=RATE (repeatable)
+    #[clap(
+        long,
+        value_name = "PORT=RATE",
+        value_parser = parse_service_rate,
+        value_delimiter = ',',
+        env = "GUARD_SERVICE_RATE"
+    )]
+    service_rate: Vec<(u16, u64)>,
+
+    /// Treatment of IPv4 packets by DSCP code point, as POINT=ACTION (repeatable). POINT is
+    /// ef, va, le, csN, afXY or 0-63; ACTION is normal, exempt (skip every limiter), tight
+    /// (--dscp-tight-rate) or drop. Also counts packets per DSCP class on /metrics
+    #[clap(
+        long,
+        value_name = "POINT=ACTION",
+        value_parser = parse_dscp_policy,
+        value_delimiter = ',',
+        env = "GUARD_DSCP_POLICY"
+    )]
+    dscp_policy: Vec<(u8, u8)>,
+
+    /// Packets allowed per source per --window for code points marked tight [default: a
+    /// tenth of --rate]
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), env = "GUARD_DSCP_TIGHT_RATE")]
+    dscp_tight_rate: Option<u64>,
+
+    /// What malformed packets of a kind get, as KIND=ACTION (repeatable). KIND is
+    /// truncated-l2, bad-ip-header, truncated-l4, bad-length or bad-checksum; ACTION is abort
+    /// (XDP_ABORTED, see `guardctl last-abort`), drop or pass. By default the first two
+    /// abort, and the rest pass on through the checks that don't need what's missing
+    #[clap(
+        long,
+        value_name = "KIND=ACTION",
+        value_parser = malformed::parse_action,
+        value_delimiter = ',',
+        env = "GUARD_MALFORMED_ACTION"
+    )]
+    malformed_action: Vec<(u32, u8)>,
+
+    /// Malformed packet kinds to copy the start of to --malformed-pcap
+    #[clap(
+        long,
+        value_name = "KIND",
+        value_parser = malformed::parse_kind,
+        value_delimiter = ',',
+        requires = "malformed_pcap",
+        env = "GUARD_MALFORMED_SAMPLE"
+    )]
+    malformed_sample: Vec<u32>,
+
+    /// Write the first 128 bytes of malformed packets of the --malformed-sample kinds to this
+    /// pcap file, at most 100 a second. Replaced at every start
+    #[clap(long, value_name = "PATH", env = "GUARD_MALFORMED_PCAP")]
+    malformed_pcap: Option<PathBuf>,
+
+    /// Bytes of the ring the samples go through, a power of two of at least a page. Samples
+    /// that find it full are lost, see xdp_api_guard_sample_loss_ratio
+    #[clap(
+        long,
+        default_value_t = 256 * 1024,
+        value_parser = malformed::parse_ring_size,
+        env = "GUARD_RINGBUF_SIZE"
+    )]
+    ringbuf_size: u32,
+
+    /// Percentage of a second's samples of a kind lost to a full ring past which the program
+    /// samples the kind half as often, twice as often again after a second without loss (0
+    /// samples every packet)
+    #[clap(
+        long,
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        env = "GUARD_SAMPLE_LOSS_PERCENT"
+    )]
+    sample_loss_percent: u8,
+
+    /// Network of a named group, as NAME=CIDR (repeatable). A source belongs to the group of
+    /// its longest matching network, whose --group-rate and --group-action replace the
+    /// defaults of its zone
+    #[clap(
+        long,
+        value_name = "NAME=CIDR",
+        value_parser = parse_group,
+        value_delimiter = ',',
+        env = "GUARD_GROUP"
+    )]
+    group: Vec<(String, Ipv4Cidr)>,
+
+    /// Packets allowed per member source per --window, as NAME=RATE (repeatable) [default:
+    /// the rate of the source's zone]
+    #[clap(
+        long,
+        value_name = "NAME=RATE",
+        value_parser = parse_group_rate,
+        value_delimiter = ',',
+        env = "GUARD_GROUP_RATE"
+    )]
+    group_rate: Vec<(String, u64)>,
+
+    /// Action for the members of a group, as NAME=ACTION with ACTION limit, pass or drop
+    /// (repeatable) [default: limit]
+    #[clap(
+        long,
+        value_name = "NAME=ACTION",
+        value_parser = parse_group_action,
+        value_delimiter = ',',
+        env = "GUARD_GROUP_ACTION"
+    )]
+    group_action: Vec<(String, u8)>,
+
+    /// Protected destination address of a tenant (repeatable). Traffic to it is counted on
+    /// its own for `GET /v1/tenants/{vip}/status`
+    #[clap(long, value_name = "VIP", value_delimiter = ',', env = "GUARD_TENANT")]
+    tenant: Vec<Ipv4Addr>,
+
+    /// Packets a second to a tenant from which its status is elevated
+    #[clap(long, default_value_t = 10_000, env = "GUARD_TENANT_ELEVATED_PPS")]
+    tenant_elevated_pps: u64,
+
+    /// Packets a second to a tenant dropped by auto-bans, limits and the other features
+    /// beyond the blocklist from which its status is mitigating
+    #[clap(long, default_value_t = 100, env = "GUARD_TENANT_MITIGATING_PPS")]
+    tenant_mitigating_pps: u64,
+
+    /// Drop ICMP errors that quote a packet not sent to their source, or one to or from a
+    /// blocked address
+    #[clap(long, env = "GUARD_ICMP_INNER_CHECK")]
+    icmp_inner_check: bool,
+
+    /// Filter the IPv4 and IPv6 packets inside PPPoE session frames, e.g. on a DSL uplink.
+    /// Without it they pass unfiltered
+    #[clap(long, env = "GUARD_PPPOE")]
+    pppoe: bool,
+
+    /// SYNs advertising a smaller TCP MSS are flagged (0 disables the check)
+    #[clap(long, default_value_t = 536, env = "GUARD_MIN_MSS")]
+    min_mss: u16,
+
+    /// What to do about a SYN below --min-mss
+    #[clap(long, value_enum, default_value_t = MssAction::Count, env = "GUARD_MIN_MSS_ACTION")]
+    min_mss_action: MssAction,
+
+    /// Limiter entries of sources idle for this many seconds are deleted (0 keeps them)
+    #[clap(
+        long,
+        default_value_t = 300,
+        value_parser = clap::value_parser!(u64).range(..=MAX_IDLE_SECS),
+        env = "GUARD_TRACKING_IDLE_SECS"
+    )]
+    tracking_idle_secs: u64,
+
+    /// Seconds between sweeps for idle limiter entries [default: a quarter of
+    /// --tracking-idle-secs, 5 to 60]. The program deletes the entries of sources that come
+    /// back itself, so on busy maps this can be minutes
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), env = "GUARD_SWEEP_INTERVAL")]
+    sweep_interval: Option<u64>,
+
+    /// Percent of its entries at which the blocklist or IPv4 limiter map is logged as filling
+    /// up, or grown with --auto-resize
+    #[clap(
+        long,
+        default_value_t = 90,
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        env = "GUARD_RESIZE_AT"
+    )]
+    resize_at: u8,
+
+    /// Grow the blocklist and IPv4 limiter map to twice their size at --resize-at, while the
+    /// program runs, instead of only warning
+    #[clap(long, conflicts_with = "external_maps", env = "GUARD_AUTO_RESIZE")]
+    auto_resize: bool,
+
+    /// Entries --auto-resize grows a map to at most. `guardctl resize` isn't held to it
+    #[clap(long, default_value_t = 1 << 22, env = "GUARD_RESIZE_LIMIT")]
+    resize_limit: u32,
+
+    /// Seconds of per-second stats kept in memory for history and sparklines
+    #[clap(long, default_value_t = 300, env = "GUARD_HISTORY")]
+    history: usize,
+
+    /// Smooth rates with an EWMA of this factor (0 < alpha <= 1, 1 means no smoothing).
+    /// Alert thresholds are compared against the smoothed rate.
+    #[clap(long, default_value_t = 1.0, value_parser = parse_alpha, env = "GUARD_SMOOTHING")]
+    smoothing: f64,
+
+    /// Log an alert while the (smoothed) drop rate is above this many packets per second
+    #[clap(long, env = "GUARD_ALERT_DROP_RATE")]
+    alert_drop_rate: Option<f64>,
+
+    /// Log an alert while more than this percentage of the (smoothed) passes are new flows,
+    /// e.g. 80. Needs --conntrack
+    #[clap(long, value_name = "PERCENT", env = "GUARD_ALERT_NEW_FLOWS")]
+    alert_new_flows: Option<f64>,
+
+    /// Relax the --managed knobs after this many seconds in a row of drops, passes and new
+    /// flows within their baselines, and tighten them when drops or new flows pass twice theirs
+    #[clap(
+        long,
+        value_name = "SECS",
+        requires = "managed",
+        env = "GUARD_RELAX_AFTER_SECS"
+    )]
+    relax_after_secs: Option<u64>,
+
+    /// Hand KNOB (global-rate, prefix-quota or red-start) to the --relax-after-secs
+    /// controller, which moves it between TIGHT and RELAXED in place of the knob's own flag
+    /// (repeatable)
+    #[clap(
+        long,
+        value_name = "KNOB=TIGHT:RELAXED",
+        value_parser = parse_managed,
+        value_delimiter = ',',
+        requires = "relax_after_secs",
+        env = "GUARD_MANAGED"
+    )]
+    managed: Vec<(Knob, Range)>,
+
+    /// Percent of each --managed range relaxed per second once calm
+    #[clap(
+        long,
+        value_name = "PERCENT",
+        default_value = "10",
+        value_parser = parse_percent,
+        env = "GUARD_RELAX_STEP"
+    )]
+    relax_step: u8,
+
+    /// Normal drops per second for --relax-after-secs [default: learned while calm]
+    #[clap(long, env = "GUARD_NORMAL_DROP_RATE")]
+    normal_drop_rate: Option<f64>,
+
+    /// Normal passes per second for --relax-after-secs [default: learned while calm]
+    #[clap(long, env = "GUARD_NORMAL_PASS_RATE")]
+    normal_pass_rate: Option<f64>,
+
+    /// Normal percentage of new flows in the passes for --relax-after-secs, with --conntrack
+    /// [default: learned while calm]
+    #[clap(long, value_name = "PERCENT", env = "GUARD_NORMAL_NEW_SHARE")]
+    normal_new_share: Option<f64>,
+
+    /// Compare the packets the program counted with what the interface says it received
+    /// this often, in seconds, and warn when they are more than --ifstats-tolerance apart
+    /// (0 never does)
+    #[clap(long, default_value_t = 60, env = "GUARD_IFSTATS_CHECK_SECS")]
+    ifstats_check_secs: u64,
+
+    /// Percent the program's and the interface's counts may be apart in one check
+    #[clap(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 5.0,
+        env = "GUARD_IFSTATS_TOLERANCE"
+    )]
+    ifstats_tolerance: f64,
+
+    /// Log the blocklist drops of each origin and feed this often, in seconds (0 never does)
+    #[clap(long, default_value_t = 3600, env = "GUARD_ORIGIN_SUMMARY_SECS")]
+    origin_summary_secs: u64,
+
+    /// Keep a file per UTC day of counters, top sources, blocklist changes and events in
+    /// DIR, and write each finished day's summary next to it (see `xdp-api-guard report`)
+    #[clap(long, value_name = "DIR", env = "GUARD_DAILY_REPORT_DIR")]
+    daily_report_dir: Option<PathBuf>,
+
+    /// Record what the alerts are decided on each second to a file per run in DIR, for
+    /// `xdp-api-guard replay DIR` to decide again offline
+    #[clap(long, value_name = "DIR", env = "GUARD_RECORD_DECISIONS")]
+    record_decisions: Option<PathBuf>,
+
+    /// MaxMind country (or city) database, to show the country of sources in the top
+    /// offenders, `guardctl why`, the daily report and /v1/stats. Read again on SIGHUP
+    #[clap(long, value_name = "FILE", env = "GUARD_GEOIP_DB")]
+    geoip_db: Option<PathBuf>,
+
+    /// MaxMind ASN database, to show the AS of sources where --geoip-db shows the country
+    #[clap(long, value_name = "FILE", env = "GUARD_ASN_DB")]
+    asn_db: Option<PathBuf>,
+
+    /// Show each CPU's drop and pass counters on the dashboard, not only their sums
+    #[clap(long, env = "GUARD_PER_CPU_STATS")]
+    per_cpu_stats: bool,
+
+    /// Print one stats JSON document per second instead of the dashboard
+    #[clap(long, env = "GUARD_JSON")]
+    json: bool,
+
+    /// Most verbose level of the eBPF program's log lines that is forwarded (off, error, warn,
+    /// info, debug or trace), on top of RUST_LOG. `guardctl log-level` changes it at runtime
+    #[clap(long, default_value_t = LevelFilter::Trace, env = "GUARD_EBPF_LOG_LEVEL")]
+    ebpf_log_level: LevelFilter,
+
+    /// Print neither the dashboard nor stats JSON, e.g. when running as a service
+    #[clap(long, conflicts_with = "json", env = "GUARD_QUIET")]
+    quiet: bool,
+
+    /// Path of the control socket used by `guardctl`
+    #[clap(long, default_value = DEFAULT_CONTROL_SOCKET, env = "GUARD_CONTROL_SOCKET")]
+    control_socket: PathBuf,
+
+    /// Control socket connections open at once, new ones past it are refused
+    #[clap(
+        long,
+        default_value_t = 16,
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "GUARD_CONTROL_MAX_CLIENTS"
+    )]
+    control_max_clients: u32,
+
+    /// Control socket requests a second per client uid. list, offenders, rules, suggest,
+    /// profile, flush and snapshots count as 10
+    #[clap(
+        long,
+        default_value_t = 50,
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "GUARD_CONTROL_RATE"
+    )]
+    control_rate: u32,
+
+    /// Only these uids (and root) may run commands that change anything over the control
+    /// socket (repeatable). Without it and --control-write-gid, everyone who can open it may
+    #[clap(
+        long,
+        value_name = "UID",
+        value_delimiter = ',',
+        env = "GUARD_CONTROL_WRITE_UID"
+    )]
+    control_write_uid: Vec<u32>,
+
+    /// Members of these groups may too, as their primary group or a supplementary one
+    /// (repeatable)
+    #[clap(
+        long,
+        value_name = "GID",
+        value_delimiter = ',',
+        env = "GUARD_CONTROL_WRITE_GID"
+    )]
+    control_write_gid: Vec<u32>,
+
+    /// Also read control commands from a FIFO at this path, created if missing.
+    /// Responses are logged
+    #[clap(long, value_name = "PATH", env = "GUARD_COMMAND_FIFO")]
+    command_fifo: Option<PathBuf>,
+
+    /// Address to serve the REST API on, e.g. 127.0.0.1:9100 (disabled by default)
+    #[clap(long, env = "GUARD_HTTP_LISTEN")]
+    http_listen: Option<SocketAddr>,
+
+    /// Require `Authorization: Bearer TOKEN` on every REST API request except /healthz. Also
+    /// read from the file GUARD_HTTP_TOKEN_FILE names
+    #[clap(long, value_name = "TOKEN", env = "GUARD_HTTP_TOKEN")]
+    http_token: Option<String>,
+
+    /// Named REST API tokens with a role each (read-only, operator or admin) and an optional
+    /// expiry, one `id role secret [expires]` per line. Read again on SIGHUP
+    #[clap(long, value_name = "PATH", env = "GUARD_HTTP_TOKENS")]
+    http_tokens: Option<PathBuf>,
+
+    /// How client addresses appear in logs and on the status page. Kernel maps, the control
+    /// socket and state files keep full addresses
+    #[clap(long, value_enum, default_value_t = MaskMode::None, env = "GUARD_MASK_IPS")]
+    mask_ips: MaskMode,
+
+    /// Key for --mask-ips hash, at least 16 bytes. Keep it to keep the tokens stable
+    #[clap(long, value_name = "PATH", env = "GUARD_MASK_KEY_FILE")]
+    mask_key_file: Option<PathBuf>,
+
+    /// Push stats to a statsd/DogStatsD agent at this host:port over UDP
+    #[clap(long, value_name = "HOST:PORT", env = "GUARD_STATSD")]
+    statsd: Option<String>,
+
+    /// Prefix of every statsd metric name
+    #[clap(long, default_value = "xdp_api_guard", env = "GUARD_STATSD_PREFIX")]
+    statsd_prefix: String,
+
+    /// Seconds between statsd pushes
+    #[clap(long, default_value_t = 10, env = "GUARD_STATSD_INTERVAL")]
+    statsd_interval: u64,
+
+    /// Extra tags on every statsd metric, e.g. env:prod,region:fra. iface and instance
+    /// (the hostname) are always added
+    #[clap(long, value_delimiter = ',', env = "GUARD_STATSD_TAGS")]
+    statsd_tags: Vec<String>,
+
+    /// Send traces of control requests, REST API calls, sweeps and reloads to an
+    /// OpenTelemetry collector's OTLP/HTTP receiver at this host:port
+    #[cfg(feature = "otel")]
+    #[clap(long, value_name = "HOST:PORT", env = "GUARD_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Learn normal per-source rates for this many seconds (0 or no value: keep learning)
+    /// so `guardctl suggest` can recommend limits
+    #[clap(
+        long,
+        value_name = "SECS",
+        num_args = 0..=1,
+        default_missing_value = "0",
+        env = "GUARD_LEARN"
+    )]
+    learn: Option<u64>,
+
+    /// Environment file the daemon's settings are in, `xdp-api-guard init` names the file it
+    /// writes. `guardctl suggest --apply` also writes the limit it applies there
+    #[clap(long, value_name = "PATH", env = "GUARD_ENV_FILE")]
+    env_file: Option<PathBuf>,
+
+    /// Pinned XDP program to tail-call for every packet the guard passes
+    #[clap(long, value_name = "PIN", env = "GUARD_NEXT_PROG")]
+    next_prog: Option<PathBuf>,
+
+    /// Pinned hash map of TCP flows verified upstream (key: src, dst, sport, dport as host
+    /// order u32, u32, u16, u16). Flows in it skip rate limiting, not the blocklist
+    #[clap(long, value_name = "PIN", env = "GUARD_TRUSTED_FLOW_MAP")]
+    trusted_flow_map: Option<PathBuf>,
+
+    /// Don't load or attach the program, use the maps another loader pinned in this directory
+    /// (one file per map, named like the map). The program's lifecycle stays with that loader
+    #[clap(
+        long,
+        value_name = "PIN_DIR",
+        conflicts_with = "trusted_flow_map",
+        env = "GUARD_EXTERNAL_MAPS"
+    )]
+    external_maps: Option<PathBuf>,
+
+    /// Empty the pinned maps of `--external-maps` and stamp them with this binary's layout,
+    /// instead of refusing maps of another layout. What they held is lost
+    #[clap(long, requires = "external_maps", env = "GUARD_FRESH")]
+    fresh: bool,
+
+    /// Load the program without multi-buffer support even where the kernel has it, e.g. to
+    /// chain into a program that lacks it. Drivers in multi-buffer mode (jumbo MTUs) refuse it
+    #[clap(long, env = "GUARD_SINGLE_BUFFER")]
+    single_buffer: bool,
+
+    /// How the program is attached: native in the driver where it can be, falling back to
+    /// generic (`auto`), or only one of `skb`, `drv` and `hw`
+    #[clap(long, value_enum, default_value_t = XdpMode::Auto, env = "GUARD_XDP_MODE")]
+    xdp_mode: XdpMode,
+
+    /// How the program reaches the interface: attached to it (`direct`), or as an extension
+    /// in place of a function of another loader's XDP dispatcher (`chained`, with
+    /// --dispatcher-pin), so both run on the interface without --replace wars
+    #[clap(long, value_enum, default_value_t = AttachMode::Direct, env = "GUARD_ATTACH_MODE")]
+    attach_mode: AttachMode,
+
+    /// Pinned XDP dispatcher to chain into with --attach-mode chained
+    #[clap(long, value_name = "PIN", env = "GUARD_DISPATCHER_PIN")]
+    dispatcher_pin: Option<PathBuf>,
+
+    /// Function of the dispatcher to take the place of, `int FUNC(struct xdp_md *ctx)`
+    #[clap(long, default_value = "prog0", env = "GUARD_DISPATCHER_FUNC")]
+    dispatcher_func: String,
+
+    /// Warn at startup when the program uses this much of a verifier limit, in percent
+    #[clap(long, default_value_t = 80, env = "GUARD_VERIFIER_WARN_PERCENT")]
+    verifier_warn_percent: u64,
+
+    /// Load the program with the full verifier log, report how close it is to the limits and
+    /// exit without attaching
+    #[clap(long, conflicts_with = "external_maps")]
+    check_verifier: bool,
+
+    /// Keep the blocklist across restarts and crashes in this file, with a journal of every
+    /// change next to it (PATH.journal). Feed entries are not kept
+    #[clap(long, value_name = "PATH", env = "GUARD_STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Directory of `guardctl snapshot save` and `load`, which name a file in it. Without it
+    /// the snapshot commands are refused
+    #[clap(long, value_name = "DIR", env = "GUARD_SNAPSHOT_DIR")]
+    snapshot_dir: Option<PathBuf>,
+
+    /// Block a source for --recidivist-ttl once it came back this many times in a row within
+    /// --recidivist-window of a TTL ban running out
+    #[clap(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "GUARD_RECIDIVIST_AFTER"
+    )]
+    recidivist_after: Option<u32>,
+
+    /// Seconds after a ban ran out within which a new ban counts as a return
+    #[clap(
+        long,
+        value_name = "SECS",
+        default_value_t = 86400,
+        requires = "recidivist_after",
+        env = "GUARD_RECIDIVIST_WINDOW"
+    )]
+    recidivist_window: u64,
+
+    /// Seconds of the ban a recidivist gets, 0 for a permanent one
+    #[clap(
+        long,
+        value_name = "SECS",
+        default_value_t = 0,
+        requires = "recidivist_after",
+        env = "GUARD_RECIDIVIST_TTL"
+    )]
+    recidivist_ttl: u64,
+
+    /// MiB of memory the ban histories and idempotency keys may take together, past it the
+    /// least recently used are evicted
+    #[clap(
+        long,
+        value_name = "MIB",
+        default_value_t = (bounded::DEFAULT_BUDGET >> 20) as u64,
+        value_parser = clap::value_parser!(u64).range(1..),
+        env = "GUARD_STATE_MEMORY"
+    )]
+    state_memory: u64,
+
+    /// Don't exempt the default gateway and the next hops of other routes out of --iface from
+    /// blocking and rate limiting
+    #[clap(long, env = "GUARD_NO_AUTO_NEIGHBOR_EXEMPT")]
+    no_auto_neighbor_exempt: bool,
+
+    /// Seconds a next hop stays exempt after the last route through it went away
+    #[clap(long, default_value_t = 300, env = "GUARD_NEIGHBOR_GRACE")]
+    neighbor_grace: u64,
+
+    /// Name of this node in the cluster [default: hostname]
+    #[cfg(feature = "cluster")]
+    #[clap(long, env = "GUARD_NODE_ID")]
+    node_id: Option<String>,
+
+    /// Address to accept cluster peers on, e.g. 0.0.0.0:7946
+    #[cfg(feature = "cluster")]
+    #[clap(long, env = "GUARD_CLUSTER_LISTEN")]
+    cluster_listen: Option<SocketAddr>,
+
+    /// Cluster peer to connect to, as host:port (repeatable)
+    #[cfg(feature = "cluster")]
+    #[clap(long, env = "GUARD_CLUSTER_PEER", value_delimiter = ',')]
+    cluster_peer: Vec<String>,
+
+    /// File holding the secret shared by every node of the cluster
+    #[cfg(feature = "cluster")]
+    #[clap(long, env = "GUARD_CLUSTER_SECRET_FILE")]
+    cluster_secret_file: Option<PathBuf>,
+
+    /// Recent ban events replayed to a peer when it joins
+    #[cfg(feature = "cluster")]
+    #[clap(long, default_value_t = 256, env = "GUARD_CLUSTER_REPLAY")]
+    cluster_replay: usize,
+
+    /// TLS ClientHellos allowed per server name per --window, whichever sources they come
+    /// from (0 disables). Read from the first segment only
+    #[cfg(feature = "sni")]
+    #[clap(long, default_value_t = 0, env = "GUARD_SNI_RATE")]
+    sni_rate: u64,
+
+    /// ClientHellos allowed per --window whose name can't be read: split across segments or
+    /// encrypted (0 only counts them)
+    #[cfg(feature = "sni")]
+    #[clap(
+        long,
+        default_value_t = 0,
+        requires = "sni_rate",
+        env = "GUARD_SNI_UNKNOWN_RATE"
+    )]
+    sni_unknown_rate: u64,
+
+    /// TCP port whose ClientHellos --sni-rate applies to
+    #[cfg(feature = "sni")]
+    #[clap(long, default_value_t = 443, env = "GUARD_TLS_PORT")]
+    tls_port: u16,
+
+    /// WASM module (binary or text) deciding on the active sources from their counters.
+    /// Reloaded on SIGHUP
+    #[cfg(feature = "wasm-policy")]
+    #[clap(long, value_name = "PATH", env = "GUARD_POLICY_MODULE")]
+    policy_module: Option<PathBuf>,
+
+    /// Seconds between passes of --policy-module over the sources
+    #[cfg(feature = "wasm-policy")]
+    #[clap(long, default_value_t = 5, env = "GUARD_POLICY_INTERVAL")]
+    policy_interval: u64,
+
+    /// Fuel one call into --policy-module may burn, about one unit per instruction
+    #[cfg(feature = "wasm-policy")]
+    #[clap(long, default_value_t = 100_000, env = "GUARD_POLICY_FUEL")]
+    policy_fuel: u64,
+
+    /// Megabytes of memory an instance of --policy-module may grow to
+    #[cfg(feature = "wasm-policy")]
+    #[clap(
+        long,
+        default_value_t = 16,
+        value_parser = clap::value_parser!(u64).range(1..=4096),
+        env = "GUARD_POLICY_MEMORY_MB"
+    )]
+    policy_memory_mb: u64,
+
+    /// Milliseconds one pass of --policy-module may take, the next pass carries on from there
+    #[cfg(feature = "wasm-policy")]
+    #[clap(long, default_value_t = 200, env = "GUARD_POLICY_BUDGET_MS")]
+    policy_budget_ms: u64,
+}
+
+/// What a zone's sources get once they are past the blocklist and management networks.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ZoneAction {
+    /// Rate limit them
+    Limit,
+    /// Pass everything
+    Pass,
+    /// Drop everything
+    Drop,
+}
+
+impl ZoneAction {
+    fn kernel(self) -> u8 {
+        match self {
+            ZoneAction::Limit => zone_action::LIMIT,
+            ZoneAction::Pass => zone_action::PASS,
+            ZoneAction::Drop => zone_action::DROP,
+        }
+    }
+}
+
+/// `--multicast` and `--broadcast`.
+#[derive(Clone, Copy, Debug)]
+enum CastPolicy {
+    Pass,
+    Drop,
+    RateLimit(u64),
+}
+
+impl CastPolicy {
+    // `cast_action` and limit, untouched destinations go the way of unicast
+    fn kernel(policy: Option<Self>) -> (u8, u64) {
+        match policy {
+            None => (cast_action::SOURCE, 0),
+            Some(CastPolicy::Pass) => (cast_action::PASS, 0),
+            Some(CastPolicy::Drop) => (cast_action::DROP, 0),
+            Some(CastPolicy::RateLimit(limit)) => (cast_action::LIMIT, limit),
+        }
+    }
+}
+
+/// `--attach-mode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum AttachMode {
+    /// Attached to the interface
+    Direct,
+    /// An extension of another loader's dispatcher, see `chain`
+    Chained,
+}
+
+/// `--xdp-mode`.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum XdpMode {
+    /// Whatever the kernel picks, native if the driver has it
+    Auto,
+    /// Generic XDP, on the kernel's socket buffers
+    Skb,
+    /// Native XDP in the driver
+    Drv,
+    /// Offloaded to the NIC
+    Hw,
+}
+
+impl XdpMode {
+    fn flags(self) -> XdpFlags {
+        match self {
+            XdpMode::Auto => XdpFlags::default(),
+            XdpMode::Skb => XdpFlags::SKB_MODE,
+            XdpMode::Drv => XdpFlags::DRV_MODE,
+            XdpMode::Hw => XdpFlags::HW_MODE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum MssAction {
+    /// Only count it
+    Count,
+    /// Raise the source's tag score, shrinking its rate limit
+    Score,
+    /// Drop the SYN
+    Drop,
+}
+
+fn parse_service_rate(s: &str) -> Result<(u16, u64), String> {
+    let (port, rate) = s.split_once('=').ok_or("expected PORT=RATE")?;
+    let port: u16 = port
+        .parse()
+        .map_err(|e| format!("bad port {port:?}: {e}"))?;
+    let rate: u64 = rate
+        .parse()
+        .map_err(|e| format!("bad rate {rate:?}: {e}"))?;
+    if port == 0 || rate == 0 {
+        return Err("port and rate must be at least 1".to_owned());
+    }
+    Ok((port, rate))
+}
+
+fn parse_managed(s: &str) -> Result<(Knob, Range), String> {
+    let (name, range) = s.split_once('=').ok_or("expected KNOB=TIGHT:RELAXED")?;
+    let knob = Knob::from_name(name).ok_or_else(|| {
+        format!("unknown knob {name:?}, expected global-rate, prefix-quota or red-start")
+    })?;
+    let (tight, relaxed) = range.split_once(':').ok_or("expected KNOB=TIGHT:RELAXED")?;
+    let value =
+        |s: &str| -> Result<u64, String> { s.parse().map_err(|e| format!("bad value {s:?}: {e}")) };
+    let range = Range {
+        tight: value(tight)?,
+        relaxed: value(relaxed)?,
+    };
+    if range.tight == 0 || range.tight >= range.relaxed {
+        return Err("TIGHT must be at least 1 and below RELAXED".to_owned());
+    }
+    let most = match knob {
+        Knob::GlobalRate => u64::MAX,
+        Knob::PrefixQuota => u64::from(u32::MAX),
+        Knob::RedStart => 99,
+    };
+    if range.relaxed > most {
+        return Err(format!("{name} is at most {most}"));
+    }
+    Ok((knob, range))
+}
+
+fn parse_dscp_policy(s: &str) -> Result<(u8, u8), String> {
+    let (point, action) = s.split_once('=').ok_or("expected POINT=ACTION")?;
+    let code = dscp_code_point(point).ok_or_else(|| format!("unknown code point {point:?}"))?;
+    let action = match action {
+        "normal" => dscp_action::NORMAL,
+        "exempt" => dscp_action::EXEMPT,
+        "tight" => dscp_action::TIGHT,
+        "drop" => dscp_action::DROP,
+        _ => return Err(format!("unknown action {action:?}")),
+    };
+    Ok((code, action))
+}
+
+// Names from RFC 4594 and RFC 8622, or the number itself
+fn dscp_code_point(point: &str) -> Option<u8> {
+    let point = point.to_ascii_lowercase();
+    let code = if let Some(class) = point.strip_prefix("cs") {
+        match class.as_bytes() {
+            [class @ b'0'..=b'7'] => (class - b'0') * 8,
+            _ => return None,
+        }
+    } else if let Some(af) = point.strip_prefix("af") {
+        match af.as_bytes() {
+            [class @ b'1'..=b'4', drop @ b'1'..=b'3'] => (class - b'0') * 8 + (drop - b'0') * 2,
+            _ => return None,
+        }
+    } else {
+        match point.as_str() {
+            "ef" => 46,
+            "va" => 44,
+            "le" => 1,
+            number => number.parse().ok()?,
+        }
+    };
+    (code < 64).then_some(code)
+}
+
+fn parse_blocklist_quota(s: &str) -> Result<(String, usize), String> {
+    let (key, max) = s.split_once('=').ok_or("expected KEY=MAX")?;
+    let max = max.parse().map_err(|e| format!("bad quota {max:?}: {e}"))?;
+    Ok((key.to_owned(), max))
+}
+
+fn parse_group(s: &str) -> Result<(String, Ipv4Cidr), String> {
+    let (name, cidr) = s.split_once('=').ok_or("expected NAME=CIDR")?;
+    let cidr = cidr.parse().map_err(|e| format!("{e:#}"))?;
+    Ok((name.to_owned(), cidr))
+}
+
+fn parse_group_rate(s: &str) -> Result<(String, u64), String> {
+    let (name, rate) = s.split_once('=').ok_or("expected NAME=RATE")?;
+    let rate = rate
+        .parse()
+        .map_err(|e| format!("bad rate {rate:?}: {e}"))?;
+    Ok((name.to_owned(), rate))
+}
+
+fn parse_group_action(s: &str) -> Result<(String, u8), String> {
+    let (name, action) = s.split_once('=').ok_or("expected NAME=ACTION")?;
+    let action =
+        groups::parse_action(action).ok_or_else(|| format!("unknown action {action:?}"))?;
+    Ok((name.to_owned(), action))
+}
+
+fn parse_feature(s: &str) -> Result<u32, String> {
+    feature::from_name(s)
+        .ok_or_else(|| format!("unknown feature, one of {}", feature::NAMES.join(", ")))
+}
+
+fn parse_cast(s: &str) -> Result<CastPolicy, String> {
+    match s {
+        "pass" => Ok(CastPolicy::Pass),
+        "drop" => Ok(CastPolicy::Drop),
+        _ => {
+            let limit = s
+                .strip_prefix("ratelimit:")
+                .ok_or("expected pass, drop or ratelimit:N")?;
+            let limit = limit
+                .parse()
+                .map_err(|e| format!("bad rate {limit:?}: {e}"))?;
+            Ok(CastPolicy::RateLimit(limit))
+        }
+    }
+}
+
+// 80 or 80%
+fn parse_percent(s: &str) -> Result<u8, String> {
+    match s.strip_suffix('%').unwrap_or(s).parse() {
+        Ok(percent) if percent <= 100 => Ok(percent),
+        _ => Err("must be a percentage, 0 to 100".to_owned()),
+    }
+}
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    let ratio: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if (0.0..=1.0).contains(&ratio) {
+        Ok(ratio)
+    } else {
+        Err("must be in [0, 1]".to_owned())
+    }
+}
+
+pub(crate) fn parse_alpha(s: &str) -> Result<f64, String> {
+    let alpha: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if alpha > 0.0 && alpha <= 1.0 {
+        Ok(alpha)
+    } else {
+        Err("must be in (0, 1]".to_owned())
+    }
+}
+
+impl Opt {
+    /// What the alerts decide with.
+    pub(crate) fn decision_settings(&self) -> Settings {
+        Settings {
+            smoothing: self.smoothing,
+            alert_drop_rate: self.alert_drop_rate,
+            alert_new_flows: self.alert_new_flows,
+            conntrack: self.conntrack || self.ack_limit != 0,
+        }
+    }
+
+    pub(crate) fn kernel_config(&self) -> Config {
+        let ms = 1_000_000;
+        let (multicast, multicast_limit) = CastPolicy::kernel(self.multicast);
+        let (broadcast, broadcast_limit) = CastPolicy::kernel(self.broadcast);
+        let mut cfg = Config {
+            rate_limit: self.rate,
+            window_ns: self.window * ms,
+            rate_limit6: self.rate6.unwrap_or(self.rate),
+            window_ns6: self.window6.unwrap_or(self.window) * ms,
+            quic_initial_limit: self.quic_initial_limit,
+            quic_port: self.quic_port,
+            ack_limit: self.ack_limit,
+            http_rps_limit: self.http_rps_limit,
+            burst: self.burst,
+            dscp_tight_limit: self.dscp_tight_rate.unwrap_or((self.rate / 10).max(1)),
+            http_ports: self.http_ports(),
+            min_mss: self.min_mss,
+            local_rate_limit: self.local_rate.unwrap_or(self.rate),
+            idle_ns: self.tracking_idle_secs * 1_000_000_000,
+            local_net: self.local_subnet.map_or(0, |net| u32::from(net.addr())),
+            local_mask: self.local_subnet.map_or(0, |net| net.mask()),
+            local_action: self.local_action.kernel(),
+            external_action: self.external_action.kernel(),
+            cast_action: [multicast, broadcast],
+            cast_limit: [multicast_limit, broadcast_limit],
+            wred_low: self.wred_low,
+            wred_high: self.wred_high,
+            global_limit: self.global_rate,
+            red_start: self.red_start.unwrap_or(Config::DEFAULT.red_start),
+            red_full: self.red_full,
+            #[cfg(feature = "sni")]
+            sni_limit: self.sni_rate,
+            #[cfg(feature = "sni")]
+            sni_unknown_limit: self.sni_unknown_rate,
+            #[cfg(feature = "sni")]
+            tls_port: self.tls_port,
+            flags: self.kernel_flags(),
+            observe: self.observe.iter().fold(0, |bits, f| bits | 1 << f),
+            account_blocked: u8::from(self.account_blocked_in_tracking),
+            track_allowed: u8::from(self.track_allowlisted),
+            tenants: u8::from(!self.tenant.is_empty()),
+            nat_limit: if self.nat_prefix.is_empty() {
+                0
+            } else {
+                self.nat_rate.unwrap_or(self.rate.saturating_mul(64)).max(1)
+            },
+            prefix_quota: self.prefix_quota,
+            prefix_limit: self
+                .prefix_rate
+                .unwrap_or(u64::from(self.prefix_quota).saturating_mul(self.rate)),
+            malformed_action: self.malformed_action(),
+            malformed_sample: self
+                .malformed_sample
+                .iter()
+                .fold(0, |bits, kind| bits | 1 << kind),
+            sample_loss_pct: self.sample_loss_percent,
+            retransmit_divisor: if self.forgive_retransmissions {
+                self.retransmit_divisor
+            } else {
+                0
+            },
+            ..Config::DEFAULT
+        };
+        // The controller starts them relaxed
+        for &(knob, range) in &self.managed {
+            knob.set(&mut cfg, range.relaxed);
+        }
+        cfg
+    }
+
+    // Later --managed flags for a knob win
+    fn managed_range(&self, knob: Knob) -> Option<Range> {
+        self.managed
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == knob)
+            .map(|(_, range)| *range)
+    }
+
+    // Later pairs for a kind win
+    fn malformed_action(&self) -> [u8; MALFORMED_KINDS] {
+        let mut actions = malformed_action::DEFAULT;
+        for &(kind, action) in &self.malformed_action {
+            actions[kind as usize] = action;
+        }
+        actions
+    }
+
+    fn http_ports(&self) -> [u16; HTTP_PORTS] {
+        let mut ports = [0; HTTP_PORTS];
+        for (slot, port) in ports.iter_mut().zip(&self.http_ports) {
+            *slot = *port;
+        }
+        ports
+    }
+
+    /// Secrets not given directly, from the files their `_FILE` variables name.
+    fn read_secrets(&mut self) -> anyhow::Result<()> {
+        if self.http_token.is_none() {
+            self.http_token = settings::secret_file("http-token")?;
+        }
+        Ok(())
+    }
+
+    /// What clap can't check about the flags on its own.
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.wred_low < self.wred_high,
+            "--wred-low must be below --wred-high"
+        );
+        anyhow::ensure!(
+            self.red_start.is_none_or(|start| start < self.red_full),
+            "--red-start must be below --red-full"
+        );
+        anyhow::ensure!(
+            self.managed_range(Knob::RedStart)
+                .is_none_or(|range| range.relaxed < u64::from(self.red_full)),
+            "--managed red-start must stay below --red-full"
+        );
+        anyhow::ensure!(
+            self.managed_range(Knob::RedStart).is_none()
+                || self.global_rate != 0
+                || self.managed_range(Knob::GlobalRate).is_some(),
+            "--managed red-start needs --global-rate"
+        );
+        anyhow::ensure!(
+            self.ifstats_tolerance >= 0.0,
+            "--ifstats-tolerance can't be negative"
+        );
+        anyhow::ensure!(
+            self.relax_step != 0,
+            "--relax-step must be at least 1 percent"
+        );
+        anyhow::ensure!(
+            self.http_ports.len() <= HTTP_PORTS,
+            "at most {HTTP_PORTS} --http-ports"
+        );
+        anyhow::ensure!(
+            self.service_rate.len() <= MAX_SERVICES,
+            "at most {MAX_SERVICES} --service-rate ports"
+        );
+        anyhow::ensure!(
+            self.feed.len() <= MAX_FEEDS as usize,
+            "at most {MAX_FEEDS} --feed files"
+        );
+        anyhow::ensure!(
+            self.alert_new_flows.is_none() || self.conntrack || self.ack_limit != 0,
+            "--alert-new-flows needs --conntrack"
+        );
+        anyhow::ensure!(
+            self.syn_conversion_ban_below.is_none() || self.conntrack || self.ack_limit != 0,
+            "--syn-conversion-ban-below needs --conntrack"
+        );
+        anyhow::ensure!(
+            !self.forgive_retransmissions || self.conntrack || self.ack_limit != 0,
+            "--forgive-retransmissions needs --conntrack"
+        );
+        anyhow::ensure!(
+            self.syn_conversion_interval > 0,
+            "--syn-conversion-interval must be at least 1"
+        );
+        anyhow::ensure!(
+            self.malformed_pcap.is_none() || self.mask_ips == MaskMode::None,
+            "--malformed-pcap keeps whole packet headers, which --mask-ips doesn't allow"
+        );
+        let chained = self.attach_mode == AttachMode::Chained;
+        anyhow::ensure!(
+            chained == self.dispatcher_pin.is_some(),
+            "--attach-mode chained and --dispatcher-pin go together"
+        );
+        anyhow::ensure!(
+            !chained || self.external_maps.is_none(),
+            "--attach-mode chained loads the program, --external-maps leaves it to another loader"
+        );
+        anyhow::ensure!(
+            !chained || self.next_prog.is_none(),
+            "--next-prog doesn't go with --attach-mode chained, the dispatcher runs what comes next"
+        );
+        for (i, feed) in self.feed.iter().enumerate() {
+            anyhow::ensure!(
+                self.feed[..i].iter().all(|other| other.name != feed.name),
+                "two feeds are named {:?}, name them with NAME=PATH",
+                feed.name
+            );
+        }
+        Ok(())
+    }
+
+    /// Turns off what the eBPF program was built without, found by its missing maps. The
+    /// subsystems without maps of their own are known from our features when the object is
+    /// ours; a program of another loader is taken to have them.
+    fn fit(&mut self, maps: &Maps, own_object: bool) {
+        let built = |flags: &str, feature: &str, present: bool| {
+            if !present {
+                warn!("{flags} ignored: the eBPF program is built without `{feature}`");
+            }
+            present
+        };
+        if (self.conntrack || self.ack_limit != 0)
+            && !built(
+                "--conntrack and --ack-limit",
+                "conntrack",
+                maps.conntrack.is_some() && maps.ack.is_some() && maps.syn_sources.is_some(),
+            )
+        {
+            self.conntrack = false;
+            self.ack_limit = 0;
+            self.alert_new_flows = None;
+            self.syn_conversion_ban_below = None;
+            self.forgive_retransmissions = false;
+        }
+        if !self.service_rate.is_empty()
+            && !built(
+                "--service-rate",
+                "port-rules",
+                maps.service_rates.is_some() && maps.service.is_some(),
+            )
+        {
+            self.service_rate.clear();
+        }
+        if self.http_rps_limit != 0 && !built("--http-rps-limit", "http", maps.http.is_some()) {
+            self.http_rps_limit = 0;
+        }
+        if !self.dscp_policy.is_empty()
+            && !built("--dscp-policy", "dscp", maps.dscp_policy.is_some())
+        {
+            self.dscp_policy.clear();
+        }
+        if self.blocklist_bloom && !built("--blocklist-bloom", "bloom", maps.bloom.is_some()) {
+            self.blocklist_bloom = false;
+        }
+        let decap = !own_object || cfg!(feature = "decap");
+        if (self.pppoe || self.icmp_inner_check)
+            && !built("--pppoe and --icmp-inner-check", "decap", decap)
+        {
+            self.pppoe = false;
+            self.icmp_inner_check = false;
+        }
+        // On by default, so only asking for more than counting is worth a warning
+        if self.min_mss != 0 && own_object && !cfg!(feature = "syn-checks") {
+            match self.min_mss_action {
+                MssAction::Count => info!("tiny-MSS SYNs aren't counted without `syn-checks`"),
+                MssAction::Score | MssAction::Drop => {
+                    built("--min-mss-action", "syn-checks", false);
+                }
+            }
+            self.min_mss = 0;
+            self.min_mss_action = MssAction::Count;
+        }
+    }
+
+    fn kernel_flags(&self) -> u16 {
+        let mut flags = 0;
+        if self.paused {
+            flags |= config_flags::PAUSED;
+        }
+        if self.no_rate_limit {
+            flags |= config_flags::NO_RATE_LIMIT;
+        }
+        if self.wred {
+            flags |= config_flags::WRED;
+        }
+        let global = self.global_rate != 0 || self.managed_range(Knob::GlobalRate).is_some();
+        if global && (self.red_start.is_some() || self.managed_range(Knob::RedStart).is_some()) {
+            flags |= config_flags::RED;
+        }
+        if self.pppoe {
+            flags |= config_flags::PPPOE;
+        }
+        if self.blocklist_bloom {
+            flags |= config_flags::BLOOM;
+        }
+        if !self.service_rate.is_empty() {
+            flags |= config_flags::SERVICE_RATE;
+        }
+        if !self.dscp_policy.is_empty() {
+            flags |= config_flags::DSCP_POLICY;
+        }
+        if !self.group.is_empty() {
+            flags |= config_flags::GROUPS;
+        }
+        if self.icmp_inner_check {
+            flags |= config_flags::ICMP_INNER;
+        }
+        if self.local_subnet.is_some() {
+            flags |= config_flags::LOCAL_SUBNET;
+        }
+        if self.conntrack || self.ack_limit != 0 {
+            flags |= config_flags::CONNTRACK;
+        }
+        match self.min_mss_action {
+            MssAction::Count => {}
+            MssAction::Score => flags |= config_flags::MSS_SCORE,
+            MssAction::Drop => flags |= config_flags::MSS_DROP,
+        }
+        flags
+    }
+}
+
+/// The `xdp-api-guard` binary.
+#[tokio::main]
+pub async fn main() -> anyhow::Result<()> {
+    if std::env::args().nth(1).as_deref() == Some("init") {
+        return preset::init(preset::InitOpt::parse_from(std::env::args().skip(1)));
+    }
+    if std::env::args().nth(1).as_deref() == Some("setup") {
+        return setup::run(setup::SetupOpt::parse_from(std::env::args().skip(1)));
+    }
+    if std::env::args().nth(1).as_deref() == Some("report") {
+        return report::run(report::ReportOpt::parse_from(std::env::args().skip(1)));
+    }
+    if std::env::args().nth(1).as_deref() == Some("replay") {
+        return decisions::run(decisions::ReplayOpt::parse_from(std::env::args().skip(1)));
+    }
+    if std::env::args().nth(1).as_deref() == Some("flush") {
+        return flush::run(flush::FlushOpt::parse_from(std::env::args().skip(1)));
+    }
+    if std::env::args().nth(1).as_deref() == Some("print-config") {
+        return settings::run(std::env::args().skip(1));
+    }
+    let opt = Opt::parse();
+
+    env_logger::init();
+    let stop = async {
+        let _ = signal::ctrl_c().await;
+    };
+    let lifecycle = Lifecycle {
+        stop: Box::pin(stop),
+        ready: None,
+        reloads: None,
+        daemon: true,
+        control_socket: true,
+    };
+    run(opt, lifecycle).await
+}
+
+/// A reload an embedder asks for, the outcome comes back on it.
+pub(crate) type Reload = oneshot::Sender<Result<(), String>>;
+
+/// How [`run`] is driven: by the signals of its own process as the daemon, or by the program
+/// it is embedded in.
+pub(crate) struct Lifecycle {
+    /// Detaches and returns once it completes.
+    pub stop: Pin<Box<dyn Future<Output = ()> + Send>>,
+    /// Gets the control state once the program is attached.
+    pub ready: Option<oneshot::Sender<Arc<ControlState>>>,
+    /// Asks for what SIGHUP does for the daemon, and takes back why it failed if it did. The
+    /// embedder's signals are its own, it has `guard_reload` for this.
+    pub reloads: Option<mpsc::UnboundedReceiver<Reload>>,
+    /// Whether the process is ours: SIGHUP reloads the token file, and the console says how
+    /// to stop. An embedder's stdout is its own, the console lines go to the log instead and
+    /// the dashboard stays off.
+    pub daemon: bool,
+    /// Whether to serve `--control-socket`. An embedded guard only serves one it was given,
+    /// the default path is the daemon's and binding it would unlink the daemon's socket.
+    pub control_socket: bool,
+}
+
+/// Everything after parsing the command line, until `lifecycle.stop` completes.
+pub(crate) async fn run(mut opt: Opt, mut lifecycle: Lifecycle) -> anyhow::Result<()> {
+    if !lifecycle.daemon {
+        opt.quiet = true;
+        opt.json = false;
+    }
+    let daemon = lifecycle.daemon;
+    timebase::refresh();
+    mask::init(opt.mask_ips, opt.mask_key_file.as_deref())?;
+    opt.read_secrets()?;
+    opt.check()?;
+    bounded::set_budget((opt.state_memory as usize) << 20);
+    let geo = GeoIp::open(opt.geoip_db.as_deref(), opt.asn_db.as_deref())?;
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = &opt.otlp_endpoint {
+        trace::init(endpoint.clone(), hostname()?);
+    }
+
+    // Bump the memlock rlimit. This is needed for older kernels that don't use the
+    // new memcg based accounting, see https://lwn.net/Articles/837122/
+    let rlim = libc::rlimit {
+        rlim_cur: libc::RLIM_INFINITY,
+        rlim_max: libc::RLIM_INFINITY,
+    };
+    let ret = unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
+    if ret != 0 {
+        debug!("remove limit on locked memory failed, ret is: {ret}");
+    }
+
+    // This will include the eBPF object file as raw bytes at compile-time and load it at
+    // runtime.
+    let object = aya::include_bytes_aligned!(concat!(env!("OUT_DIR"), "/xdp-api-guard"));
+    if opt.check_verifier {
+        return check_verifier(object, &opt);
+    }
+    let (mut ebpf, trusted, mut maps, slots, pump) = match &opt.external_maps {
+        Some(dir) => {
+            info!(
+                "using maps pinned in {}, the program belongs to another loader",
+                dir.display()
+            );
+            let mut maps = Maps::from_pins(dir)?;
+            adopt(&mut maps, dir, opt.fresh)?;
+            (None, false, maps, None, None)
+        }
+        None => {
+            let (mut ebpf, trusted, slots, pump) = load(object, &opt)?;
+            // From here on the object only holds the program
+            let mut maps = Maps::take(&mut ebpf)?;
+            maps.stamp(1)?;
+            slots.point(Resizable::Blocklist, maps.blocklist.map())?;
+            slots.point(Resizable::Tracking, maps.rate_limit.map())?;
+            (Some(ebpf), trusted, maps, Some(slots), Some(pump))
+        }
+    };
+
+    opt.fit(&maps, opt.external_maps.is_none());
+
+    // Limits have to be in place before the first packet is seen
+    let mut initial = opt.kernel_config();
+    if trusted {
+        initial.flags |= config_flags::TRUSTED_FLOWS;
+    }
+    let rules = Rules::install(
+        maps.service_rates,
+        maps.dscp_policy,
+        &opt.service_rate,
+        &opt.dscp_policy,
+    )?;
+    for (port, rate) in &opt.service_rate {
+        info!("port {port} takes at most {rate} new connections per window");
+    }
+    // Policies before members, so no member sees its group half set up
+    let mut groups = Groups::new(maps.group_cidrs, maps.group_policy, maps.group_stats);
+    for (name, rate) in &opt.group_rate {
+        groups.set_rate(name, *rate)?;
+    }
+    for (name, action) in &opt.group_action {
+        groups.set_action(name, *action)?;
+    }
+    for (name, cidr) in &opt.group {
+        groups.add(name, *cidr)?;
+    }
+    for group in groups.groups() {
+        if group.members.is_empty() {
+            warn!("group {} has no networks yet", group.name);
+        }
+    }
+    let thresholds = Thresholds {
+        elevated_pps: opt.tenant_elevated_pps,
+        mitigating_pps: opt.tenant_mitigating_pps,
+    };
+    let mut tenants = Tenants::new(maps.protected_dsts, maps.tenant_stats, thresholds);
+    for vip in &opt.tenant {
+        if !tenants.add(*vip)? {
+            warn!("tenant {vip} given twice");
+        }
+    }
+    for cidr in &opt.nat_prefix {
+        let key = Key::new(u32::from(cidr.prefix_len()), u32::from(cidr.addr()).to_be());
+        maps.nat_prefixes.insert(&key, 1, 0)?;
+        info!("{cidr} is a NAT pool, its addresses are limited per source port");
+    }
+    let config = ConfigHandle::new(maps.config, maps.config_active, initial)?;
+    debug!("kernel config: {:?}", config.get());
+    if opt.no_rate_limit && opt.learn.is_some() {
+        warn!("--learn has nothing to learn from with --no-rate-limit");
+    }
+
+    let embedded = maps
+        .build_id
+        .as_ref()
+        .map(|build_id| build_id.get(&0, 0))
+        .transpose()?;
+    let versions = Versions::install(&mut maps.version_info, object, embedded)?;
+    // Our own maps are fresh and can't be off, pinned ones can
+    if opt.external_maps.is_some() && versions.loaded.schema != versions.binary.schema {
+        anyhow::bail!(
+            "pinned maps use schema {}, this binary expects {}",
+            versions.loaded.schema,
+            versions.binary.schema
+        );
+    }
+    info!("xdp-api-guard {}", Build(&versions.binary));
+    info!("eBPF object {}", Build(&versions.loaded));
+    if let Some(mismatch) = versions.mismatch() {
+        warn!("{mismatch}");
+    }
+
+    // 1. Hand the blocklist maps over to the handle, it is the only writer from here on
+    let mut blocklist = BlocklistHandle::new(maps.blocklist, maps.mgmt_cidrs);
+    blocklist.set_quotas(Quotas::new(&opt.blocklist_quota, &opt.feed)?);
+    if opt.blocklist_bloom
+        && let Some(bloom) = maps.bloom
+    {
+        blocklist.set_bloom(Bloom::new(bloom)?);
+    }
+
+    let recorder = match &opt.daily_report_dir {
+        Some(dir) => Some(Arc::new(Mutex::new(report::Recorder::new(dir.clone())?))),
+        None => None,
+    };
+    let note = |event: &str| {
+        if let Some(recorder) = &recorder {
+            recorder.lock().unwrap().event(event);
+        }
+    };
+    note("started");
+
+    // 2. Read the feeds, nothing is written before everything is resolved
+    let mut feeds = Vec::with_capacity(opt.feed.len());
+    for (id, feed) in opt.feed.iter().enumerate() {
+        // Not made current, a span per entry would bury the trace
+        let mut span = Span::root("feed load");
+        span.set("feed", &feed.name);
+        let ips = blocklist::read_feed(&feed.path)?;
+        span.set("entries", ips.len());
+        info!(
+            "loaded {} entries from feed {} at {}",
+            ips.len(),
+            feed.name,
+            feed.path.display()
+        );
+        let ips = blocklist.truncate_feed(id as u8, ips);
+        if let Some(recorder) = &recorder {
+            recorder.lock().unwrap().feed(&feed.name, ips.len());
+        }
+        feeds.push(ips);
+    }
+
+    // 3. Add IP from CLI args (if provided)
+    let mut block = Vec::from_iter(opt.block);
+    // 4. Adding a hardcoded test IP (Google DNS) just to be sure
+    block.push(Ipv4Addr::new(8, 8, 8, 8));
+
+    // 5. One entry per address, decided by precedence rather than by the order of the writes
+    let plan = normalize::resolve(&opt.allow, &opt.mgmt_cidr, &block, &feeds);
+    drop(feeds);
+    plan.conflicts.log();
+    if opt.strict_conflicts && !plan.conflicts.shadowed.is_empty() {
+        anyhow::bail!("conflicting startup entries: {}", plan.conflicts);
+    }
+    for cidr in &plan.mgmt {
+        console(daemon, &format!("Adding management network {cidr}..."));
+        blocklist.add_management(*cidr)?;
+    }
+    if let Some(ip) = opt.block
+        && plan
+            .entries
+            .get(&ip)
+            .is_some_and(|entry| entry.origin == Origin::ManualBlock)
+    {
+        console(daemon, &format!("Adding {} to Blocklist...", ip));
+    }
+    let written = blocklist.insert_bulk(plan.entries.into_iter().collect())?;
+    debug!("startup blocklist: {written} entries written");
+
+    let mut stats = StatsState::new(
+        maps.stats,
+        maps.drop_buckets,
+        opt.history,
+        opt.smoothing,
+        opt.feed.iter().map(|feed| feed.name.clone()).collect(),
+    );
+    stats.set_conntrack(opt.decision_settings().conntrack);
+    // Only pinned maps can have counted anything yet
+    match stats.seed() {
+        Ok(0) => {}
+        Ok(seconds) => info!("history seeded with {seconds}s the program counted before startup"),
+        Err(e) => warn!("can't read the drop buckets, history starts empty: {e:#}"),
+    }
+    let stats = Arc::new(Mutex::new(stats));
+
+    // Like the limits, the chain has to be in place before the first packet
+    if let Some(pin) = &opt.next_prog {
+        chain::set_next(&mut maps.next_prog, Some(pin))?;
+    }
+
+    // Verified here so `status` can tell, attached once everything else is in place
+    let mut verifier_stats = verifier::Stats::default();
+    let mut multi_buffer = false;
+    let mut program_fd = None;
+    // Chained, the XDP program is still loaded, unattached, for `verify` to run packets
+    // through. The extension is the same code and shares its maps.
+    let mut extension = match (&mut ebpf, &opt.dispatcher_pin) {
+        (Some(ebpf), Some(pin)) => Some(chain::load_extension(ebpf, pin, &opt.dispatcher_func)?),
+        _ => None,
+    };
+    let program = match &mut ebpf {
+        Some(ebpf) => {
+            // As single-buffer as the extension, which can't be anything else
+            let frags = !opt.single_buffer && extension.is_none();
+            let (program, frags) = load_program(ebpf, frags)?;
+            multi_buffer = frags;
+            if let Some(pump) = &pump {
+                pump.set_origin(LogOrigin {
+                    iface: opt.iface.clone(),
+                    ifindex: link::ifindex(&opt.iface)?,
+                    program: if extension.is_some() {
+                        chain::EXTENSION.to_owned()
+                    } else {
+                        program_name(frags).to_owned()
+                    },
+                });
+            }
+            verifier_stats = verifier::Stats::of(program);
+            verifier_stats.log(opt.verifier_warn_percent);
+            program_fd = Some(program.fd()?.try_clone()?);
+            Some(program)
+        }
+        None => None,
+    };
+
+    let conversion_interval = Duration::from_secs(opt.syn_conversion_interval);
+    let conversion_rule = opt.syn_conversion_ban_below.map(|below| BanRule {
+        below,
+        min_syns: opt.min_syns,
+        ttl: opt.syn_conversion_ban_ttl,
+    });
+    let control = Arc::new(ControlState {
+        blocklist: Mutex::new(blocklist),
+        tags: Mutex::new(maps.tags),
+        rate_limit: Mutex::new(maps.rate_limit),
+        rate_limit6: Mutex::new(maps.rate_limit6),
+        prefix_sources: Mutex::new(maps.prefix_sources),
+        prefix: Mutex::new(maps.prefix),
+        nat: Mutex::new(maps.nat),
+        nat_prefixes: opt.nat_prefix.clone(),
+        quic_initial: Mutex::new(maps.quic_initial),
+        conntrack: maps.conntrack.map(Mutex::new),
+        ack: maps.ack.map(Mutex::new),
+        http: maps.http.map(Mutex::new),
+        service: maps.service.map(Mutex::new),
+        conversions: maps
+            .syn_sources
+            .filter(|_| opt.conntrack || opt.ack_limit != 0)
+            .map(|map| Mutex::new(Conversions::new(map, conversion_interval, conversion_rule))),
+        rules: Mutex::new(rules),
+        groups: Mutex::new(groups),
+        tenants: Mutex::new(tenants),
+        stats: stats.clone(),
+        config: Mutex::new(config),
+        relax: opt.relax_after_secs.map(|secs| {
+            let baselines = Baselines {
+                drop_rate: opt.normal_drop_rate,
+                pass_rate: opt.normal_pass_rate,
+                new_share: opt.normal_new_share,
+            };
+            let step = f64::from(opt.relax_step) / 100.0;
+            Mutex::new(Controller::new(&opt.managed, secs, step, baselines))
+        }),
+        learner: opt.learn.map(|secs| {
+            let duration = (secs > 0).then(|| Duration::from_secs(secs));
+            Mutex::new(Learner::new(duration))
+        }),
+        next_prog: Mutex::new(maps.next_prog),
+        versions,
+        paths: Mutex::new(maps.paths),
+        last_abort: Mutex::new(maps.last_abort),
+        profiling: AtomicBool::new(false),
+        external: opt.external_maps.is_some(),
+        program: program_fd,
+        slots,
+        verifier: verifier_stats,
+        multi_buffer,
+        chained: opt.dispatcher_pin.as_ref().map(|pin| {
+            format!(
+                "chained into the dispatcher at {} in place of {}",
+                pin.display(),
+                opt.dispatcher_func
+            )
+        }),
+        replay: ReplayCache::default(),
+        geo: geo.map(Arc::new),
+        snapshot_dir: opt.snapshot_dir.clone(),
+        env_file: opt.env_file.clone(),
+    });
+
+    let journal_stats = Arc::new(JournalStats::default());
+    if let Some(path) = &opt.state_file {
+        journal::open(&control, path, journal_stats.clone())?;
+        tokio::spawn(journal::run(
+            control.clone(),
+            path.clone(),
+            journal_stats.clone(),
+        ));
+    }
+    if let Some(after) = opt.recidivist_after {
+        control
+            .blocklist
+            .lock()
+            .unwrap()
+            .set_recidivists(Recidivists {
+                after,
+                window: opt.recidivist_window,
+                ttl: opt.recidivist_ttl,
+            });
+    }
+
+    // Without an object of our own, or chained into another loader's dispatcher, attachment
+    // is only watched
+    let mut program = match (program, &mut extension) {
+        (Some(_), Some(extension)) => {
+            let pin = opt.dispatcher_pin.as_deref().unwrap();
+            chain::attach_extension(extension, pin, &opt.dispatcher_func)?;
+            None
+        }
+        (Some(program), None) => {
+            let link_id = program
+                .attach(&opt.iface, opt.xdp_mode.flags())
+                .context("failed to attach the XDP program")?;
+            Some((program, Some(link_id)))
+        }
+        (None, _) => None,
+    };
+    let health = Arc::new(Health::default());
+    health.set_attached(true);
+    health.set_link_up(true);
+
+    if lifecycle.control_socket {
+        let control = control.clone();
+        let path = opt.control_socket.clone();
+        let admission = Arc::new(Admission::new(SocketLimits {
+            max_clients: opt.control_max_clients as usize,
+            rate: opt.control_rate,
+            write_uids: opt.control_write_uid.clone(),
+            write_gids: opt.control_write_gid.clone(),
+        }));
+        tokio::spawn(async move {
+            if let Err(e) = control::serve(&path, control, admission).await {
+                warn!("control socket stopped: {e:#}");
+            }
+        });
+    }
+
+    let sample_stats = Arc::new(SampleStats::default());
+    if let Some(path) = opt.malformed_pcap.clone() {
+        let ring = maps.malformed_samples;
+        let rates = maps.sample_rates;
+        let sample_stats = sample_stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = malformed::run(ring, rates, &path, sample_stats).await {
+                warn!("malformed packet sampling stopped: {e:#}");
+            }
+        });
+    }
+
+    if let Some(path) = opt.command_fifo.clone() {
+        let control = control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = fifo::serve(&path, control).await {
+                warn!("command FIFO stopped: {e:#}");
+            }
+        });
+    }
+
+    let sweep_stats = Arc::new(SweepStats::default());
+    if opt.tracking_idle_secs != 0 {
+        let idle = Duration::from_secs(opt.tracking_idle_secs);
+        let every = opt.sweep_interval.map(Duration::from_secs);
+        tokio::spawn(sweep::run(
+            control.clone(),
+            sweep_stats.clone(),
+            idle,
+            every,
+        ));
+    }
+
+    tokio::spawn(resize::watch(
+        control.clone(),
+        Watch {
+            percent: opt.resize_at,
+            auto: opt.auto_resize,
+            limit: opt.resize_limit,
+        },
+    ));
+
+    let tokens = Arc::new(Tokens::new(
+        opt.http_token.clone(),
+        opt.http_tokens.clone(),
+    )?);
+    if let Some(listen) = opt.http_listen {
+        let state = Arc::new(ApiState {
+            stats: stats.clone(),
+            versions,
+            sweep: sweep_stats.clone(),
+            samples: sample_stats.clone(),
+            health: health.clone(),
+            journal: journal_stats.clone(),
+            control: control.clone(),
+            iface: opt.iface.clone(),
+            tokens: tokens.clone(),
+        });
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(listen, state).await {
+                warn!("REST API stopped: {e:#}");
+            }
+        });
+    }
+
+    if let Some(addr) = opt.statsd.clone() {
+        anyhow::ensure!(
+            opt.statsd_interval > 0,
+            "--statsd-interval must be at least 1"
+        );
+        let mut tags = vec![
+            format!("iface:{}", opt.iface),
+            format!("instance:{}", hostname()?),
+        ];
+        tags.extend(opt.statsd_tags.iter().cloned());
+        let config = StatsdConfig {
+            addr,
+            prefix: opt.statsd_prefix.clone(),
+            interval: Duration::from_secs(opt.statsd_interval),
+            tags,
+        };
+        let stats = stats.clone();
+        let sweep = sweep_stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = statsd::run(config, stats, sweep).await {
+                warn!("statsd push stopped: {e:#}");
+            }
+        });
+    }
+
+    #[cfg(feature = "cluster")]
+    if opt.cluster_listen.is_some() || !opt.cluster_peer.is_empty() {
+        let path = opt
+            .cluster_secret_file
+            .as_ref()
+            .context("--cluster-secret-file is required to join a cluster")?;
+        let secret = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        anyhow::ensure!(!secret.trim().is_empty(), "{} is empty", path.display());
+        let config = crate::cluster::ClusterConfig {
+            node: match &opt.node_id {
+                Some(node) => node.clone(),
+                None => hostname()?,
+            },
+            secret: secret.trim().as_bytes().to_vec(),
+            listen: opt.cluster_listen,
+            peers: opt.cluster_peer.clone(),
+            replay: opt.cluster_replay,
+        };
+        info!("joining cluster as {}", config.node);
+        crate::cluster::Cluster::new(&config, control.clone()).start(&config);
+    }
+
+    conversion::start(control.clone());
+
+    #[cfg(feature = "wasm-policy")]
+    if let Some(path) = &opt.policy_module {
+        anyhow::ensure!(
+            opt.policy_interval > 0,
+            "--policy-interval must be at least 1"
+        );
+        let policy = crate::policy::Policy::new(crate::policy::PolicyConfig {
+            path: path.clone(),
+            fuel: opt.policy_fuel,
+            memory: (opt.policy_memory_mb << 20) as usize,
+            budget: Duration::from_millis(opt.policy_budget_ms),
+            interval: Duration::from_secs(opt.policy_interval),
+        })?;
+        policy.start(control.clone(), health.clone())?;
+    }
+
+    if let Some(recorder) = &recorder {
+        report::follow(recorder.clone(), &control);
+        if let Some(geo) = &control.geo {
+            report::enrich(recorder.clone(), geo.clone());
+        }
+    }
+
+    // Link up/down notifications, so a lost attachment is repaired right away
+    let (link_tx, mut link_rx) = mpsc::channel(16);
+    let ifindex = link::ifindex(&opt.iface)?;
+    if !opt.no_auto_neighbor_exempt {
+        let control = control.clone();
+        let iface = opt.iface.clone();
+        let grace = Duration::from_secs(opt.neighbor_grace);
+        tokio::spawn(async move {
+            if let Err(e) = neigh::watch(&iface, ifindex, control, grace).await {
+                warn!("neighbor watcher stopped: {e:#}");
+            }
+        });
+    }
+    tokio::spawn(async move {
+        if let Err(e) = link::watch(ifindex, link_tx).await {
+            warn!("link watcher stopped: {e:#}");
+        }
+    });
+    let mut link_up = true;
+
+    if let Some(ready) = lifecycle.ready.take() {
+        let _ = ready.send(control.clone());
+    }
+    let mut hangup = None;
+    let mut reloads = lifecycle.reloads.take();
+    if lifecycle.daemon {
+        println!("Waiting for Ctrl-C...");
+        hangup = Some(signal::unix::signal(signal::unix::SignalKind::hangup())?);
+    }
+    // 2. Run the sampler, the link watcher AND the Ctrl-C listener together
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut stop = lifecycle.stop;
+    let mut bloom_tick = tokio::time::interval(Duration::from_secs(opt.bloom_rebuild_secs));
+    let settings = opt.decision_settings();
+    let mut alerts = Alerts::new(&settings);
+    let mut decision_log = match &opt.record_decisions {
+        Some(dir) => Some(DecisionLog::create(dir, &opt.iface, settings)?),
+        None => None,
+    };
+    let mut crosscheck = (opt.ifstats_check_secs != 0)
+        .then(|| Checker::new(&opt.iface, opt.ifstats_check_secs, opt.ifstats_tolerance));
+    loop {
+        tokio::select! {
+            _ = &mut stop => {
+                console(daemon, "Exiting...");
+                if program.is_none() {
+                    info!("leaving the XDP program attached, it belongs to its loader");
+                }
+                break;
+            }
+            _ = hung_up(&mut hangup) => {
+                note("reload (SIGHUP)");
+                let _ = reload(&tokens, &control);
+            }
+            Some(done) = asked(&mut reloads) => {
+                note("reload");
+                let _ = done.send(reload(&tokens, &control));
+            }
+            _ = bloom_tick.tick(), if opt.blocklist_bloom => {
+                if let Err(e) = bloom::rebuild(&control) {
+                    warn!("failed to rebuild the blocklist bloom filter: {e:#}");
+                }
+            }
+            _ = tick.tick() => {
+                timebase::refresh();
+                let cfg = control.config.lock().unwrap().get();
+                sample(
+                    &stats,
+                    &opt,
+                    health.dataplane(),
+                    cfg.observe,
+                    &mut alerts,
+                    decision_log.as_mut(),
+                );
+                if let Some(checker) = &mut crosscheck {
+                    let seen = stats.lock().unwrap().seen();
+                    if let Some(check) = checker.tick(seen, health.dataplane()) {
+                        health.set_crosscheck(check);
+                    }
+                }
+                if let Some(recorder) = &recorder {
+                    recorder.lock().unwrap().tick(&stats, &control, &cfg);
+                }
+                learn(&control);
+                relax::tick(&control);
+                if let Err(e) = control.tenants.lock().unwrap().sample() {
+                    warn!("failed to sample the tenant counters: {e:#}");
+                }
+                if let Err(e) = control.blocklist.lock().unwrap().expire(timebase::unix_now()) {
+                    warn!("failed to expire blocklist entries: {e:#}");
+                }
+            }
+            Some(event) = link_rx.recv() => {
+                if event.removed {
+                    warn!("{}: interface removed", opt.iface);
+                    note("interface removed");
+                    health.set_attached(false);
+                    continue;
+                }
+                if event.up != link_up {
+                    info!("{}: link {}", opt.iface, if event.up { "up" } else { "down" });
+                    link_up = event.up;
+                    note(if link_up { "link up" } else { "link down" });
+                    health.set_link_up(link_up);
+                }
+                let Some((program, link_id)) = &mut program else {
+                    if let Some(attached) = event.xdp_attached {
+                        if !attached {
+                            warn!(
+                                "{}: no XDP program attached, its loader has to re-attach it",
+                                opt.iface
+                            );
+                        }
+                        health.set_attached(attached);
+                    }
+                    continue;
+                };
+                if event.up && event.xdp_attached == Some(false) {
+                    warn!("{}: XDP program is no longer attached, re-attaching", opt.iface);
+                    note("XDP program detached");
+                    health.set_attached(false);
+                    // The old link may or may not still exist; detaching is best effort
+                    if let Some(id) = link_id.take() {
+                        let _ = program.detach(id);
+                    }
+                    match program.attach(&opt.iface, opt.xdp_mode.flags()) {
+                        Ok(id) => {
+                            *link_id = Some(id);
+                            health.set_attached(true);
+                            info!("{}: XDP program re-attached", opt.iface);
+                            note("XDP program re-attached");
+                        }
+                        Err(e) => warn!("{}: re-attach failed: {e}", opt.iface),
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The token file and the geoip databases read again. What fails to read keeps what it had,
+// the reasons are what the embedder is told
+fn reload(tokens: &Tokens, control: &ControlState) -> Result<(), String> {
+    let mut span = Span::root("reload");
+    let mut failed = Vec::new();
+    match tokens.reload() {
+        Ok(Some(count)) => {
+            info!("reloaded the token file, {count} tokens");
+            span.set("tokens", count);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            warn!("token file not reloaded, the previous tokens stay: {e:#}");
+            failed.push(format!("{e:#}"));
+        }
+    }
+    if let Some(geo) = &control.geo {
+        match geo.reload() {
+            Ok(()) => info!("reloaded the geoip databases"),
+            Err(e) => {
+                warn!("geoip databases not reloaded, the previous ones stay: {e:#}");
+                failed.push(format!("{e:#}"));
+            }
+        }
+    }
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let failed = failed.join("; ");
+    span.fail(&failed);
+    Err(failed)
+}
+
+// A reload an embedder asked for, never without one
+async fn asked(reloads: &mut Option<mpsc::UnboundedReceiver<Reload>>) -> Option<Reload> {
+    match reloads {
+        Some(reloads) => reloads.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// SIGHUP, never without a handler of our own
+async fn hung_up(hangup: &mut Option<signal::unix::Signal>) {
+    match hangup {
+        Some(signal) => {
+            signal.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Takes over maps another loader pinned in `dir` if `MAP_META` says they are in this
+/// binary's layout or new, and counts the start. With `fresh` they are emptied and stamped
+/// whatever their layout, as long as they have a `MAP_META`.
+fn adopt(maps: &mut Maps, dir: &Path, fresh: bool) -> anyhow::Result<()> {
+    let starts = match maps.layout()? {
+        // Nowhere to stamp, so not even --fresh helps
+        Layout::Other(why) if maps.meta.is_none() => anyhow::bail!(
+            "refusing the maps pinned in {}: {why}, the loader has to load this binary's object",
+            dir.display()
+        ),
+        _ if fresh => {
+            let removed = maps.clear_state()?;
+            warn!(
+                "--fresh: emptied the maps pinned in {} of {removed} entries",
+                dir.display()
+            );
+            0
+        }
+        Layout::Other(why) => anyhow::bail!(
+            "refusing the maps pinned in {}: {why}; run with --fresh to empty them and start over",
+            dir.display()
+        ),
+        Layout::Ours { starts } => starts,
+        Layout::New => 0,
+    };
+    let starts = starts.saturating_add(1);
+    maps.stamp(starts)?;
+    info!(
+        "{starts} daemons have started on the maps pinned in {}",
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Loads the program built into this binary and starts forwarding its log. Returns the object,
+/// whether a trusted flow map was found, the slots the program reads its resizable maps from
+/// and the pump its log goes through.
+fn load(object: &[u8], opt: &Opt) -> anyhow::Result<(aya::Ebpf, bool, Slots, Arc<LogPump>)> {
+    let trusted = trusted::prepare(opt.trusted_flow_map.as_deref())?;
+    let slots = Slots::prepare()?;
+    let log_level = if opt.check_verifier {
+        VerifierLogLevel::VERBOSE | VerifierLogLevel::STATS
+    } else {
+        VerifierLogLevel::default()
+    };
+    // Without the filter one entry is enough, the program only reads it with the flag
+    let bloom_entries = if opt.blocklist_bloom {
+        2 * BLOOM_WORDS
+    } else {
+        1
+    };
+    let boot_clock = timebase::kernel_has_boot_ns();
+    timebase::set_boot_stamps(boot_clock);
+    let loaded = aya::EbpfLoader::new()
+        .map_pin_path(trusted::PIN_DIR)
+        .set_max_entries("BLOOM", bloom_entries)
+        .set_max_entries("MALFORMED_SAMPLES", opt.ringbuf_size)
+        .set_global("BOOT_CLOCK", &u8::from(boot_clock), true)
+        .verifier_log_level(log_level)
+        // The slots, arrays of maps aya has no type for
+        .allow_unsupported_maps()
+        .load(object);
+    trusted::unpin();
+    resize::unpin();
+    let mut ebpf = loaded?;
+    // Kernel log records go through the pump, which coalesces repeats during drop storms
+    let pump = logpump::register(opt.ebpf_log_level);
+    match aya_log::EbpfLogger::init_with_logger(&mut ebpf, PumpLogger(pump.clone())) {
+        Err(e) => {
+            // This can happen if you remove all log statements from your eBPF program.
+            warn!("failed to initialize eBPF logger: {e}");
+        }
+        Ok(logger) => {
+            let mut logger =
+                tokio::io::unix::AsyncFd::with_interest(logger, tokio::io::Interest::READABLE)?;
+            tokio::task::spawn(async move {
+                loop {
+                    let mut guard = logger.readable_mut().await.unwrap();
+                    guard.get_inner_mut().flush();
+                    guard.clear_ready();
+                }
+            });
+            let pump = pump.clone();
+            tokio::task::spawn(async move {
+                let mut tick = tokio::time::interval(logpump::WINDOW);
+                loop {
+                    tick.tick().await;
+                    pump.tick();
+                }
+            });
+        }
+    }
+    Ok((ebpf, trusted, slots, pump))
+}
+
+/// Loads the multi-buffer variant of the program if `frags` and the kernel takes it, the
+/// single-buffer one otherwise. Returns the program and whether it is the multi-buffer one.
+fn load_program(ebpf: &mut aya::Ebpf, frags: bool) -> anyhow::Result<(&mut Xdp, bool)> {
+    let mut multi_buffer = false;
+    if frags {
+        let program: &mut Xdp = ebpf
+            .program_mut("xdp_api_guard_frags")
+            .unwrap()
+            .try_into()?;
+        // Kernels before 5.18 don't know the flag, and a chained program without it makes
+        // the two incompatible
+        match program.load() {
+            Ok(()) => multi_buffer = true,
+            Err(e) => {
+                info!("multi-buffer XDP unavailable ({e}), loading the single-buffer program")
+            }
+        }
+    }
+    let program: &mut Xdp = ebpf
+        .program_mut(program_name(multi_buffer))
+        .unwrap()
+        .try_into()?;
+    if !multi_buffer {
+        program.load().map_err(verifier::explain)?;
+    }
+    Ok((program, multi_buffer))
+}
+
+fn program_name(multi_buffer: bool) -> &'static str {
+    if multi_buffer {
+        "xdp_api_guard_frags"
+    } else {
+        "xdp_api_guard"
+    }
+}
+
+// A line for the daemon's console, or for the log of the program the guard is embedded in
+fn console(daemon: bool, line: &str) {
+    match daemon {
+        true => println!("{line}"),
+        false => info!("{line}"),
+    }
+}
+
+/// `--check-verifier`: loads and verifies the program, nothing else.
+fn check_verifier(object: &[u8], opt: &Opt) -> anyhow::Result<()> {
+    let (mut ebpf, ..) = load(object, opt)?;
+    let program: &mut Xdp = ebpf.program_mut("xdp_api_guard").unwrap().try_into()?;
+    match program.load() {
+        Ok(()) => {
+            let stats = verifier::Stats::of(program);
+            println!("verifier accepted the program: {stats}");
+            for warning in stats.warnings(opt.verifier_warn_percent) {
+                println!("WARNING {warning}");
+            }
+            Ok(())
+        }
+        Err(ProgramError::LoadError { verifier_log, .. }) => {
+            let log = verifier_log.to_string();
+            println!("{log}");
+            anyhow::bail!(
+                "verifier refused the program: {}",
+                verifier::Stats::parse(&log)
+            )
+        }
+        Err(e) => Err(e).context("failed to load the XDP program"),
+    }
+}
+
+fn learn(control: &ControlState) {
+    let Some(learner) = &control.learner else {
+        return;
+    };
+    let window_ns = control.config.lock().unwrap().get().window_ns;
+    let map = control.rate_limit.lock().unwrap();
+    learner
+        .lock()
+        .unwrap()
+        .observe(&map, timebase::boot_ns(), window_ns);
+}
+
+fn sample(
+    stats: &Mutex<StatsState>,
+    opt: &Opt,
+    dataplane: Dataplane,
+    observe: u16,
+    alerts: &mut Alerts,
+    decision_log: Option<&mut DecisionLog>,
+) {
+    let mut stats = stats.lock().unwrap();
+    let before = stats.dataplane();
+    if dataplane != before {
+        alert::dataplane(&opt.iface, before, dataplane);
+        stats.set_dataplane(dataplane);
+    }
+    if stats.sample().is_err() {
+        // Logged by the sampler, the report says so until a read succeeds again
+        return;
+    }
+    if let Some(log) = decision_log
+        && let Some(counts) = stats.take_rate_sample()
+    {
+        log.sample(stats.dataplane(), counts);
+    }
+    alerts.check(stats.rates());
+    if opt.origin_summary_secs != 0
+        && let Some(line) = stats.origin_summary(Duration::from_secs(opt.origin_summary_secs))
+    {
+        info!("{line}");
+    }
+
+    if opt.quiet {
+        return;
+    }
+    if opt.json {
+        match serde_json::to_string(&stats.report(None)) {
+            Ok(line) => println!("{line}"),
+            Err(e) => warn!("failed to encode stats: {e}"),
+        }
+    } else {
+        // --- THE UI RENDERING ---
+        dashboard::render(&stats, opt.per_cpu_stats, observe);
+    }
+}
+
+/// This machine's hostname, the default cluster node id and statsd instance tag.
+fn hostname() -> anyhow::Result<String> {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error()).context("gethostname failed");
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
@@ -0,0 +1,235 @@
+use std::io::Write as _;
+
+use crate::stats::{CpuCounters, Dataplane, StatsState};
+
+// How many seconds of history the sparkline covers
+const SPARK_WIDTH: usize = 40;
+
+/// `per_cpu` adds a table of each CPU's counters below the totals. `observe` is
+/// `Config::observe`, for the mode of each feature.
+pub fn render(stats: &StatsState, per_cpu: bool, observe: u16) {
+    let report = stats.report(None);
+    let drops = stats.history().window(Some(SPARK_WIDTH)).drop_rate;
+
+    // \x1B[2J = Clear Screen
+    // \x1B[1;1H = Move Cursor to Top-Left
+    print!("\x1B[2J\x1B[1;1H");
+
+    println!("╔═══════════════════════════════════════════╗");
+    println!("║             XDP AI GUARD DASHBOARD        ║");
+    // Counters that stand still look like a quiet interface otherwise
+    let banner = match report.dataplane {
+        Dataplane::Up => None,
+        Dataplane::Down => Some("LINK DOWN"),
+        Dataplane::Detached => Some("PROGRAM DETACHED"),
+    };
+    if let Some(banner) = banner {
+        println!("╠═══════════════════════════════════════════╣");
+        println!("║ \x1B[1;31m{:^41}\x1B[0m ║", format!("!! {banner} !!"));
+    }
+    println!("╠══════════════════════════╤════════════════╣");
+    println!("║  METRIC                  │  COUNT         ║");
+    println!("╟──────────────────────────┼────────────────╢");
+    println!(
+        "║     Dropped Packets      │  {:<13} ║",
+        report.totals.dropped
+    );
+    println!(
+        "║     Passed Packets       │  {:<13} ║",
+        report.totals.passed
+    );
+    // Only conntrack knows which flows are established
+    let new_flows = match &report.flows {
+        Some(flows) => match flows.new_share {
+            Some(share) => format!("{share:.1}% new"),
+            None => "-".to_owned(),
+        },
+        None => "n/a".to_owned(),
+    };
+    println!("║     New Flow Share       │  {:<13} ║", new_flows);
+    println!(
+        "║     QUIC Initials        │  {:<13} ║",
+        report.totals.quic_initials
+    );
+    println!(
+        "║     QUIC Initial Drops   │  {:<13} ║",
+        report.totals.quic_initial_drops
+    );
+    println!(
+        "║     ACK Flood Drops      │  {:<13} ║",
+        report.totals.ack_flood_drops
+    );
+    println!(
+        "║     Tiny-MSS SYNs        │  {:<13} ║",
+        report.totals.tiny_mss
+    );
+    println!(
+        "║     Tiny-MSS SYN Drops   │  {:<13} ║",
+        report.totals.tiny_mss_drops
+    );
+    println!(
+        "║     Zone Policy Drops    │  {:<13} ║",
+        report.totals.zone_drops
+    );
+    println!(
+        "║     ICMP Error Drops     │  {:<13} ║",
+        report.totals.icmp_inner_drops
+    );
+    println!(
+        "║     Passed While Paused  │  {:<13} ║",
+        report.totals.paused_drops
+    );
+    println!(
+        "║     WRED Early Drops     │  {:<13} ║",
+        report.totals.wred_drops
+    );
+    println!(
+        "║     HTTP Flood Drops     │  {:<13} ║",
+        report.totals.http_flood_drops
+    );
+    println!(
+        "║     Service Rate Drops   │  {:<13} ║",
+        report.totals.service_rate_drops
+    );
+    println!(
+        "║     SNI Drops            │  {:<13} ║",
+        report.totals.sni_drops
+    );
+    println!(
+        "║     Multicast Drops      │  {:<13} ║",
+        report.totals.multicast_drops
+    );
+    println!(
+        "║     Broadcast Drops      │  {:<13} ║",
+        report.totals.broadcast_drops
+    );
+    println!(
+        "║     DSCP Drops           │  {:<13} ║",
+        report.totals.dscp_drops
+    );
+    println!(
+        "║     Aggregated Packets   │  {:<13} ║",
+        report.totals.aggregated
+    );
+    println!(
+        "║     /16 Shared Drops     │  {:<13} ║",
+        report.totals.prefix_drops
+    );
+    println!(
+        "║     NAT Port Drops       │  {:<13} ║",
+        report.totals.nat_port_drops
+    );
+    println!(
+        "║     Global Rate Drops    │  {:<13} ║",
+        report.totals.global_drops
+    );
+    println!(
+        "║     Pressure Drops       │  {:<13} ║",
+        report.totals.pressure_drops
+    );
+    println!(
+        "║     Past Linear Part     │  {:<13} ║",
+        report.totals.truncated
+    );
+    println!(
+        "║     Aborted              │  {:<13} ║",
+        report.totals.aborted
+    );
+    // Kinds only get a row once there is one
+    let malformed: Vec<_> = report.malformed.iter().filter(|m| m.packets != 0).collect();
+    if !malformed.is_empty() {
+        println!("╟──────────────────────────┼────────────────╢");
+        println!("║  MALFORMED PACKETS       │                ║");
+        for m in malformed {
+            println!("║     {:<20} │  {:<13} ║", m.kind, m.packets);
+        }
+    }
+    println!("╟──────────────────────────┼────────────────╢");
+    println!("║  BLOCKLIST DROPS BY      │                ║");
+    for origin in &report.drops_by_origin {
+        println!("║     {:<20} │  {:<13} ║", origin.label(), origin.packets);
+    }
+    // Features that never dropped anything only get a row while observing
+    let features: Vec<_> = report
+        .drops_by_feature
+        .iter()
+        .filter(|f| observe >> f.index & 1 != 0 || f.enforced != 0 || f.observed != 0)
+        .collect();
+    if !features.is_empty() {
+        println!("╟──────────────────────────┼────────────────╢");
+        println!("║  FEATURE         MODE    │  DROPS / WOULD ║");
+        for f in features {
+            let mode = if observe >> f.index & 1 != 0 {
+                "observe"
+            } else {
+                "enforce"
+            };
+            let drops = format!("{} / {}", f.enforced, f.observed);
+            println!("║     {:<12} {:<7} │  {:<13} ║", f.feature, mode, drops);
+        }
+    }
+    println!("╚══════════════════════════╧════════════════╝");
+    if report.rates_live {
+        println!(
+            " Drops/s {:>8} (avg {:>8.1})  {}",
+            report.drop_rate,
+            report.drop_rate_smoothed,
+            sparkline(&drops)
+        );
+    } else {
+        println!(
+            " Drops/s   paused ({})   {}",
+            report.dataplane.name(),
+            sparkline(&drops)
+        );
+    }
+    if per_cpu {
+        match stats.per_cpu() {
+            Ok(cpus) => render_per_cpu(&cpus),
+            Err(e) => println!("\n per-CPU counters unavailable: {e}"),
+        }
+    }
+    println!("\n (Press Ctrl+C to exit firewall)");
+    let _ = std::io::stdout().flush();
+}
+
+fn render_per_cpu(cpus: &[CpuCounters]) {
+    let total: u64 = cpus.iter().map(|c| c.dropped + c.passed).sum();
+    println!(
+        "\n {:>4} {:>14} {:>14} {:>7}",
+        "CPU", "Dropped", "Passed", "Share"
+    );
+    // Possible CPUs can far outnumber the ones the NIC queues are steered to
+    let mut idle = 0;
+    for c in cpus {
+        if c.dropped == 0 && c.passed == 0 {
+            idle += 1;
+            continue;
+        }
+        let share = (c.dropped + c.passed) as f64 * 100.0 / total as f64;
+        println!(
+            " {:>4} {:>14} {:>14} {:>6.1}%",
+            c.cpu, c.dropped, c.passed, share
+        );
+    }
+    // Read after the totals above, so these can be a few packets ahead of them
+    println!(
+        " {:>4} {:>14} {:>14}",
+        "sum",
+        cpus.iter().map(|c| c.dropped).sum::<u64>(),
+        cpus.iter().map(|c| c.passed).sum::<u64>()
+    );
+    if idle > 0 {
+        println!(" ({idle} CPUs without packets not shown)");
+    }
+}
+
+// A blank for each second the counters couldn't be read
+fn sparkline(values: &[Option<u64>]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().flatten().copied().max().unwrap_or(0).max(1);
+    values
+        .iter()
+        .map(|v| v.map_or(' ', |v| BARS[(v * (BARS.len() as u64 - 1) / max) as usize]))
+        .collect()
+}
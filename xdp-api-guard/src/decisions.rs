@@ -0,0 +1,403 @@
+//! `--record-decisions DIR` and `xdp-api-guard replay DIR`: the inputs of the daemon's alerts,
+//! recorded as they are consumed, and the same alerts decided again on them offline.
+//!
+//! Every second the daemon feeds what the sample counted, after the dataplane state, to the
+//! smoothed [`Rates`] and checks its [`Alerts`] on them. With `--record-decisions` each of
+//! those inputs is a line of a session file, `DIR/<unix time>.jsonl` for the run started
+//! then, after a header with the format's `VERSION`, the interface and the settings the
+//! alerts decided with. That's about 10 MB a day. Neither the rates nor the alerts read a
+//! clock or draw a random number, the sample's clock readings are recorded with it, so
+//! `replay` makes the decisions the daemon made, to the bit. `--smoothing`,
+//! `--alert-drop-rate`, `--alert-new-flows` and `--with-config`, an environment file like
+//! `xdp-api-guard init` writes, try other settings on the same counts.
+//!
+//! Bans aren't decided here: the datapath bans on its own counters, and `--policy-module` is
+//! handed more than is recorded.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, bail};
+use clap::{CommandFactory as _, Parser};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alert::{self, Alert, Transition},
+    daemon::{self, Opt},
+    report,
+    stats::{Dataplane, RateSample, Rates},
+    timebase,
+};
+
+/// Version of the session files. A replay refuses files of any other.
+pub const VERSION: u32 = 1;
+
+/// What the alerts decide with.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    pub smoothing: f64,
+    pub alert_drop_rate: Option<f64>,
+    pub alert_new_flows: Option<f64>,
+    /// Whether passes are split into established and new flows, which the new-flows alert
+    /// needs. Up to the datapath, a replay can't change it.
+    pub conntrack: bool,
+}
+
+// `smoothing 0.3, drop-rate above 1000/s, new-flows off`
+impl std::fmt::Display for Settings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "smoothing {}", self.smoothing)?;
+        match self.alert_drop_rate {
+            Some(rate) => write!(f, ", drop-rate above {rate}/s")?,
+            None => f.write_str(", drop-rate off")?,
+        }
+        match self.alert_new_flows {
+            Some(share) if self.conntrack => write!(f, ", new-flows above {share}%"),
+            Some(_) => f.write_str(", new-flows off (no --conntrack)"),
+            None => f.write_str(", new-flows off"),
+        }
+    }
+}
+
+/// The alerts the daemon checks each second.
+pub struct Alerts {
+    drop: Option<Alert>,
+    flows: Option<Alert>,
+}
+
+impl Alerts {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            drop: settings.alert_drop_rate.map(|t| Alert::new("drop-rate", t)),
+            // Without the split there is no share to alert on
+            flows: settings
+                .alert_new_flows
+                .filter(|_| settings.conntrack)
+                .map(|t| Alert::percent("new-flows", t)),
+        }
+    }
+
+    /// Checks the rates after a sample, returning the alerts raised or cleared.
+    pub fn check(&mut self, rates: &Rates) -> Vec<Transition> {
+        let mut transitions = Vec::new();
+        // Paused rates read zero, which would clear alerts the state says nothing about
+        if !rates.live() {
+            return transitions;
+        }
+        if let Some(alert) = &mut self.drop {
+            transitions.extend(alert.check(rates.drop_rate()));
+        }
+        // Nothing passing has no share to alert on
+        if let Some(alert) = &mut self.flows
+            && let Some(share) = rates.new_share()
+        {
+            transitions.extend(alert.check(share));
+        }
+        transitions
+    }
+}
+
+/// One line of a session file.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "kebab-case")]
+enum Record {
+    /// The first line.
+    Session {
+        version: u32,
+        unix: u64,
+        iface: String,
+        settings: Settings,
+    },
+    /// What one sample fed the rates.
+    Sample {
+        unix: u64,
+        boot_ns: u64,
+        dataplane: Dataplane,
+        counts: RateSample,
+    },
+}
+
+/// The session file of this run, with `--record-decisions`.
+pub struct DecisionLog {
+    file: File,
+    // So a full disk is reported once, not every second
+    failing: bool,
+}
+
+impl DecisionLog {
+    pub fn create(dir: &Path, iface: &str, settings: Settings) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        let unix = timebase::unix_now();
+        let path = dir.join(format!("{unix}.jsonl"));
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        let mut log = Self {
+            file,
+            failing: false,
+        };
+        log.append(&Record::Session {
+            version: VERSION,
+            unix,
+            iface: iface.to_owned(),
+            settings,
+        })
+        .with_context(|| format!("failed to write {}", path.display()))?;
+        info!("recording the inputs of the alerts to {}", path.display());
+        Ok(log)
+    }
+
+    /// Records what a sample fed the rates, and the dataplane state they were fed in.
+    pub fn sample(&mut self, dataplane: Dataplane, counts: RateSample) {
+        let record = Record::Sample {
+            unix: timebase::unix_now(),
+            boot_ns: timebase::boot_ns(),
+            dataplane,
+            counts,
+        };
+        match self.append(&record) {
+            Ok(()) => self.failing = false,
+            Err(e) => {
+                if !self.failing {
+                    warn!("--record-decisions: {e:#}");
+                }
+                self.failing = true;
+            }
+        }
+    }
+
+    fn append(&mut self, record: &Record) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Decide the alerts of a `--record-decisions` recording again
+#[derive(Debug, Parser)]
+#[clap(name = "xdp-api-guard replay")]
+pub struct ReplayOpt {
+    /// Directory the daemon recorded to
+    dir: PathBuf,
+
+    /// Decide with the settings of an environment file like `xdp-api-guard init` writes,
+    /// instead of the recorded ones. A setting the file doesn't set is the daemon's default
+    #[clap(long, value_name = "FILE")]
+    with_config: Option<PathBuf>,
+
+    /// EWMA factor of the smoothed rates, instead of the recorded or the file's
+    #[clap(long, value_parser = daemon::parse_alpha)]
+    smoothing: Option<f64>,
+
+    /// Drop rate alert threshold, instead of the recorded or the file's
+    #[clap(long)]
+    alert_drop_rate: Option<f64>,
+
+    /// New flows alert threshold in percent, instead of the recorded or the file's
+    #[clap(long, value_name = "PERCENT")]
+    alert_new_flows: Option<f64>,
+
+    /// Print a JSON object per decision instead of text
+    #[clap(long)]
+    json: bool,
+}
+
+/// A decision of a replay, and the sample it was made on.
+#[derive(Debug, Serialize)]
+struct Decision<'a> {
+    /// The session file's name.
+    session: &'a str,
+    unix: u64,
+    boot_ns: u64,
+    #[serde(flatten)]
+    made: Made<'a>,
+    counts: RateSample,
+    drop_rate_smoothed: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_flows_share: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "decision", rename_all = "kebab-case")]
+enum Made<'a> {
+    Alert(&'a Transition),
+    Dataplane { before: Dataplane, now: Dataplane },
+}
+
+/// Runs `xdp-api-guard replay`.
+pub fn run(opt: ReplayOpt) -> anyhow::Result<()> {
+    let file_settings = match &opt.with_config {
+        Some(path) => Some(from_env_file(path)?),
+        None => None,
+    };
+    let mut sessions: Vec<PathBuf> = fs::read_dir(&opt.dir)
+        .with_context(|| format!("failed to read {}", opt.dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "jsonl"))
+        .collect();
+    if sessions.is_empty() {
+        bail!("no session files in {}", opt.dir.display());
+    }
+    // Named by their start, the same width for the next few centuries
+    sessions.sort();
+    let mut decisions = 0;
+    for path in &sessions {
+        decisions += replay(&opt, file_settings.as_ref(), path)
+            .with_context(|| format!("failed to replay {}", path.display()))?;
+    }
+    if !opt.json {
+        println!("{decisions} decisions in {} sessions", sessions.len());
+    }
+    Ok(())
+}
+
+// One session through a fresh pipeline, as a restarted daemon would, returning how many
+// decisions it made
+fn replay(opt: &ReplayOpt, file: Option<&Settings>, path: &Path) -> anyhow::Result<usize> {
+    let name = path
+        .file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+    let contents = fs::read_to_string(path)?;
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty());
+    let Some((_, first)) = lines.next() else {
+        return Ok(0);
+    };
+    let Record::Session {
+        version,
+        unix: started,
+        iface,
+        settings: recorded,
+    } = serde_json::from_str(first).context("line 1")?
+    else {
+        bail!("line 1 isn't a session header");
+    };
+    if version != VERSION {
+        bail!("version {version} of the format, this replay reads version {VERSION}");
+    }
+    let mut settings = file.copied().unwrap_or(recorded);
+    settings.conntrack = recorded.conntrack;
+    settings.smoothing = opt.smoothing.unwrap_or(settings.smoothing);
+    settings.alert_drop_rate = opt.alert_drop_rate.or(settings.alert_drop_rate);
+    settings.alert_new_flows = opt.alert_new_flows.or(settings.alert_new_flows);
+    if !opt.json {
+        println!(
+            "session {name}, started {} on {iface}",
+            report::utc(started)
+        );
+        println!("  recorded with {recorded}");
+        if settings != recorded {
+            println!("  replayed with {settings}");
+        }
+    }
+
+    let mut rates = Rates::new(settings.smoothing);
+    let mut alerts = Alerts::new(&settings);
+    let mut decisions = 0;
+    for (lineno, line) in lines {
+        let record: Record =
+            serde_json::from_str(line).with_context(|| format!("line {}", lineno + 1))?;
+        let Record::Sample {
+            unix,
+            boot_ns,
+            dataplane,
+            counts,
+        } = record
+        else {
+            bail!("line {}: a second session header", lineno + 1);
+        };
+        let mut made = Vec::new();
+        let before = rates.dataplane();
+        if dataplane != before {
+            rates.set_dataplane(dataplane);
+            made.push((
+                Made::Dataplane {
+                    before,
+                    now: dataplane,
+                },
+                alert::dataplane_line(&iface, before, dataplane),
+            ));
+        }
+        rates.update(counts);
+        let transitions = alerts.check(&rates);
+        for transition in &transitions {
+            made.push((Made::Alert(transition), transition.to_string()));
+        }
+        for (made, line) in made {
+            let decision = Decision {
+                session: &name,
+                unix,
+                boot_ns,
+                made,
+                counts,
+                drop_rate_smoothed: rates.drop_rate(),
+                new_flows_share: settings.conntrack.then(|| rates.new_share()).flatten(),
+            };
+            decisions += 1;
+            if opt.json {
+                println!("{}", serde_json::to_string(&decision)?);
+            } else {
+                println!("  {}  {line}", report::utc(unix));
+                print!(
+                    "    on {} dropped, {} passed, smoothed {:.1} drops/s",
+                    counts.dropped,
+                    counts.passed,
+                    rates.drop_rate()
+                );
+                match decision.new_flows_share {
+                    Some(share) => println!(", {share:.1}% new flows"),
+                    None => println!(),
+                }
+            }
+        }
+    }
+    Ok(decisions)
+}
+
+// The settings an environment file gives the daemon, parsed by the daemon's own flags
+fn from_env_file(path: &Path) -> anyhow::Result<Settings> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let command = Opt::command();
+    let mut args = vec!["xdp-api-guard".to_owned()];
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((var, value)) = line.split_once('=') else {
+            bail!("{}:{}: expected VAR=VALUE", path.display(), lineno + 1);
+        };
+        // Anybody's variables may be in the file, only ours are checked
+        if !var.starts_with("GUARD_") {
+            continue;
+        }
+        let value = value.trim_matches(|c| c == '"' || c == '\'');
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_env().and_then(|env| env.to_str()) == Some(var))
+        else {
+            bail!("{}:{}: no flag reads {var}", path.display(), lineno + 1);
+        };
+        let flag = format!("--{}", arg.get_long().unwrap_or_default());
+        if arg.get_action().takes_values() {
+            args.push(flag);
+            args.push(value.to_owned());
+        } else if value == "true" {
+            args.push(flag);
+        }
+    }
+    let opt = Opt::try_parse_from(args)
+        .with_context(|| format!("{} doesn't parse as the daemon's settings", path.display()))?;
+    Ok(opt.decision_settings())
+}
@@ -0,0 +1,239 @@
+//! The blocklist a page at a time, for `GET /v1/blocklist` and the `export` command, which
+//! a blocklist of hundreds of thousands of entries can't be read in one go through.
+//!
+//! A cursor is the last address of the page it came with, and the next page starts after
+//! it, so pages come in address order and no entry is in two of them. Each page is taken
+//! under the blocklist lock on its own, in one pass over the entries, and nothing is held
+//! between pages. So while a client pages through, an entry added behind its cursor is
+//! missed and one added ahead of it shows up; an entry removed before its page came is
+//! missing, and one changed in between is as it was when its page was taken. Management
+//! networks aren't paged, `list` has them.
+//!
+//! Cursors are opaque to clients. Today one is the address in hex, which doesn't change
+//! between restarts, so an export can carry on against a restarted daemon.
+
+use std::{fmt, net::Ipv4Addr, str::FromStr};
+
+use anyhow::anyhow;
+use serde_json::Value;
+use tokio::io::{AsyncWrite, AsyncWriteExt as _};
+use xdp_api_guard_common::api::{BlocklistPage, ListEntry};
+
+use crate::{blocklist::Entry, control::ControlState, timebase};
+
+/// Entries of a page when the client doesn't ask for a number.
+pub const DEFAULT_LIMIT: usize = 1000;
+
+/// The most entries a page may have.
+pub const MAX_LIMIT: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cursor(Ipv4Addr);
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", u32::from(self.0))
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        // from_str_radix would take a sign too
+        if s.len() != 8 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(anyhow!("invalid cursor {s:?}"));
+        }
+        let key = u32::from_str_radix(s, 16).map_err(|_| anyhow!("invalid cursor {s:?}"))?;
+        Ok(Self(Ipv4Addr::from(key)))
+    }
+}
+
+/// Checks a page size given by a client.
+pub fn limit(limit: usize) -> anyhow::Result<usize> {
+    if (1..=MAX_LIMIT).contains(&limit) {
+        Ok(limit)
+    } else {
+        Err(anyhow!("limit must be 1 to {MAX_LIMIT}"))
+    }
+}
+
+/// How `list` and `export` show an entry.
+pub fn list_entry(ip: Ipv4Addr, entry: &Entry, now: u64) -> ListEntry {
+    ListEntry {
+        target: ip.to_string(),
+        origin: entry.origin,
+        node: entry.node.clone(),
+        expires_in: entry.expires.map(|at| at.saturating_sub(now)),
+    }
+}
+
+/// Up to `limit` entries after `cursor`, and the cursor of the next page. A page short
+/// of `limit` is the last.
+pub fn page(control: &ControlState, cursor: Option<Cursor>, limit: usize) -> BlocklistPage {
+    let (entries, next) = take(control, cursor, limit);
+    BlocklistPage {
+        entries,
+        next_cursor: next.map(|cursor| cursor.to_string()),
+    }
+}
+
+fn take(
+    control: &ControlState,
+    cursor: Option<Cursor>,
+    limit: usize,
+) -> (Vec<ListEntry>, Option<Cursor>) {
+    let entries = control
+        .blocklist
+        .lock()
+        .unwrap()
+        .page(cursor.map(|cursor| cursor.0), limit);
+    let next = next(&entries, limit);
+    let now = timebase::unix_now();
+    let entries = entries
+        .iter()
+        .map(|(ip, entry)| list_entry(*ip, entry, now))
+        .collect();
+    (entries, next)
+}
+
+// The cursor to carry on after `page`, taken `limit` entries long. None after a short page.
+fn next<T>(page: &[(Ipv4Addr, T)], limit: usize) -> Option<Cursor> {
+    page.last()
+        .filter(|_| page.len() == limit)
+        .map(|(ip, _)| Cursor(*ip))
+}
+
+/// Writes every entry after `cursor` to `out` as NDJSON, a `ListEntry` a line with the
+/// `cursor` that resumes after it. A page is taken only once the last one is written, so a
+/// slow client holds up its own export and the memory of one page, not the blocklist.
+pub async fn stream(
+    out: &mut (impl AsyncWrite + Unpin),
+    control: &ControlState,
+    mut cursor: Option<Cursor>,
+) -> anyhow::Result<()> {
+    loop {
+        let (entries, next) = take(control, cursor, MAX_LIMIT);
+        let mut chunk = Vec::new();
+        for entry in entries {
+            let resume = Cursor(entry.target.parse()?);
+            let mut line = serde_json::to_value(entry)?;
+            if let Value::Object(fields) = &mut line {
+                fields.insert("cursor".to_owned(), resume.to_string().into());
+            }
+            serde_json::to_writer(&mut chunk, &line)?;
+            chunk.push(b'\n');
+        }
+        out.write_all(&chunk).await?;
+        let Some(next) = next else {
+            return Ok(());
+        };
+        cursor = Some(next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::blocklist;
+
+    #[test]
+    fn cursors_read_back_as_written() {
+        for ip in ["0.0.0.0", "198.51.100.7", "255.255.255.255"] {
+            let cursor = Cursor(ip.parse().unwrap());
+            assert_eq!(cursor.to_string().parse::<Cursor>().unwrap(), cursor);
+        }
+        assert_eq!(
+            Cursor(Ipv4Addr::new(198, 51, 100, 7)).to_string(),
+            "c6336407"
+        );
+        assert_eq!(
+            "C6336407".parse::<Cursor>().unwrap().to_string(),
+            "c6336407"
+        );
+    }
+
+    #[test]
+    fn other_cursors_are_refused() {
+        for bad in [
+            "",
+            "c633640",
+            "c63364070",
+            "c633640g",
+            "+6336407",
+            " 6336407",
+            "c633 407",
+        ] {
+            assert!(bad.parse::<Cursor>().is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn limits_are_one_to_the_maximum() {
+        assert_eq!(limit(1).unwrap(), 1);
+        assert_eq!(limit(MAX_LIMIT).unwrap(), MAX_LIMIT);
+        assert!(limit(0).is_err());
+        assert!(limit(MAX_LIMIT + 1).is_err());
+    }
+
+    // Pages of `listed` the way `take` pages the blocklist, through the text of each cursor.
+    // Handed over backwards, the handle's hash map has no order either
+    fn pages(listed: &BTreeSet<Ipv4Addr>, limit: usize) -> Vec<Vec<Ipv4Addr>> {
+        let mut pages = Vec::new();
+        let mut cursor: Option<Cursor> = None;
+        loop {
+            let page: Vec<(Ipv4Addr, ())> =
+                blocklist::page(listed.iter().rev().copied(), cursor.map(|c| c.0), limit)
+                    .into_iter()
+                    .map(|ip| (ip, ()))
+                    .collect();
+            let next = next(&page, limit);
+            pages.push(page.into_iter().map(|(ip, ())| ip).collect());
+            match next {
+                Some(next) => cursor = Some(next.to_string().parse().unwrap()),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn pages_cover_every_entry_once_in_order() {
+        let listed: BTreeSet<Ipv4Addr> = (0..250u32)
+            .map(|i| Ipv4Addr::from(i.wrapping_mul(0x9e37_79b9)))
+            .collect();
+        for limit in [1, 7, 50, 249, 250, 251, MAX_LIMIT] {
+            let pages = pages(&listed, limit);
+            assert!(pages.iter().all(|page| page.len() <= limit));
+            // Only the last is short, and only it may be empty
+            assert!(
+                pages[..pages.len() - 1]
+                    .iter()
+                    .all(|page| page.len() == limit)
+            );
+            assert_eq!(pages.len(), listed.len() / limit + 1, "{limit}");
+            let all: Vec<Ipv4Addr> = pages.into_iter().flatten().collect();
+            assert_eq!(all, listed.iter().copied().collect::<Vec<_>>(), "{limit}");
+        }
+        let none: Vec<Ipv4Addr> = Vec::new();
+        assert_eq!(pages(&BTreeSet::new(), 10), [none]);
+    }
+
+    #[test]
+    fn entries_added_ahead_of_the_cursor_show_up_and_behind_it_dont() {
+        let mut listed: BTreeSet<Ipv4Addr> =
+            (1..=10).map(|i| Ipv4Addr::new(10, 0, 0, i * 2)).collect();
+        let first = blocklist::page(listed.iter().copied(), None, 4);
+        let cursor = next(&first.iter().map(|&ip| (ip, ())).collect::<Vec<_>>(), 4).unwrap();
+        assert_eq!(cursor.0, Ipv4Addr::new(10, 0, 0, 8));
+        listed.insert(Ipv4Addr::new(10, 0, 0, 3));
+        listed.insert(Ipv4Addr::new(10, 0, 0, 9));
+        listed.remove(&Ipv4Addr::new(10, 0, 0, 10));
+        let rest = blocklist::page(listed.iter().copied(), Some(cursor.0), 100);
+        let expected: Vec<Ipv4Addr> = [9, 12, 14, 16, 18, 20]
+            .map(|i| Ipv4Addr::new(10, 0, 0, i))
+            .to_vec();
+        assert_eq!(rest, expected);
+    }
+}
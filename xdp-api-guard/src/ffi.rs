@@ -0,0 +1,538 @@
+//! C ABI for programs that embed the guard instead of running the daemon, with the `ffi`
+//! feature. The build writes the header, `xdp_api_guard.h`, into its `OUT_DIR`.
+//!
+//! A `Guard` is made from a JSON object of daemon options, `{"rate": 100, "allow":
+//! ["10.0.0.1"], "conntrack": true}` for `--rate 100 --allow 10.0.0.1 --conntrack`, and runs
+//! the daemon's own startup and sampler on a thread of its own between `guard_attach` and
+//! `guard_detach`. Whatever the options switch on comes with it, the REST API or the journal,
+//! say, but the control socket only when `control_socket` names its path: the default is the
+//! daemon's. Nothing is written to stdout, what the daemon would print goes to the log and
+//! the dashboard stays off. Process-wide settings, `--mask-ips` and tracing, stay as the first
+//! attach set them, and one guard at a time per interface is all the kernel takes.
+//!
+//! Functions returning `int` give `GUARD_OK` or one of the negative `GUARD_ERR_*` codes;
+//! those returning pointers give NULL on failure. Either way `guard_last_error_message`
+//! then says why. No panic crosses the boundary, one is `GUARD_ERR_PANIC`.
+//!
+//! A handle is `Send`: calls may come from any thread, and on one handle they are
+//! serialized by a lock inside, a block waiting for an attach to finish, say. Only
+//! `guard_free` must not race with other calls on its handle.
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char, c_int},
+    net::Ipv4Addr,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use clap::Parser as _;
+use serde_json::Value;
+use tokio::{
+    runtime,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    control::{self, ControlState},
+    daemon::{self, Lifecycle, Opt, Reload},
+};
+
+pub const GUARD_OK: c_int = 0;
+/// An argument was NULL, not UTF-8 or not what the function takes.
+pub const GUARD_ERR_ARGUMENT: c_int = -1;
+/// The guard isn't attached, or already is.
+pub const GUARD_ERR_STATE: c_int = -2;
+/// The guard tried and failed, or refused, like the daemon would.
+pub const GUARD_ERR_FAILED: c_int = -3;
+pub const GUARD_ERR_PANIC: c_int = -4;
+
+/// `mode` of `guard_attach`, as `--xdp-mode`.
+pub const GUARD_MODE_AUTO: c_int = 0;
+pub const GUARD_MODE_SKB: c_int = 1;
+pub const GUARD_MODE_DRV: c_int = 2;
+pub const GUARD_MODE_HW: c_int = 3;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// An embedded guard, opaque to C.
+pub struct Guard {
+    // Daemon arguments from the configuration, without --iface and --xdp-mode
+    args: Vec<String>,
+    running: Mutex<Option<Running>>,
+}
+
+struct Running {
+    control: Arc<ControlState>,
+    runtime: runtime::Handle,
+    stop: oneshot::Sender<()>,
+    reloads: mpsc::UnboundedSender<Reload>,
+    thread: JoinHandle<anyhow::Result<()>>,
+}
+
+struct Error {
+    code: c_int,
+    message: String,
+}
+
+impl Error {
+    fn argument(message: impl Into<String>) -> Self {
+        Self {
+            code: GUARD_ERR_ARGUMENT,
+            message: message.into(),
+        }
+    }
+
+    fn state(message: impl Into<String>) -> Self {
+        Self {
+            code: GUARD_ERR_STATE,
+            message: message.into(),
+        }
+    }
+
+    fn failed(message: impl Into<String>) -> Self {
+        Self {
+            code: GUARD_ERR_FAILED,
+            message: message.into(),
+        }
+    }
+}
+
+impl Guard {
+    fn attach(&self, iface: &str, mode: c_int) -> Result<(), Error> {
+        let mode = match mode {
+            GUARD_MODE_AUTO => "auto",
+            GUARD_MODE_SKB => "skb",
+            GUARD_MODE_DRV => "drv",
+            GUARD_MODE_HW => "hw",
+            _ => return Err(Error::argument(format!("unknown mode {mode}"))),
+        };
+        let mut running = self.running.lock().unwrap();
+        if running.is_some() {
+            return Err(Error::state("already attached"));
+        }
+        let mut args = vec!["xdp-api-guard".to_owned()];
+        args.extend(self.args.iter().cloned());
+        args.extend(["--iface", iface, "--xdp-mode", mode].map(str::to_owned));
+        let opt = Opt::try_parse_from(args).map_err(|e| Error::argument(e.to_string()))?;
+
+        let rt = runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| Error::failed(format!("failed to start a runtime: {e}")))?;
+        let handle = rt.handle().clone();
+        let (ready, attached) = oneshot::channel();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let (reloads, asked) = mpsc::unbounded_channel();
+        let lifecycle = Lifecycle {
+            stop: Box::pin(async {
+                let _ = stopped.await;
+            }),
+            ready: Some(ready),
+            reloads: Some(asked),
+            daemon: false,
+            control_socket: self.args.iter().any(|arg| arg == "--control-socket"),
+        };
+        let thread = thread::Builder::new()
+            .name("xdp-api-guard".to_owned())
+            .spawn(move || rt.block_on(daemon::run(opt, lifecycle)))
+            .map_err(|e| Error::failed(format!("failed to start the guard thread: {e}")))?;
+        match attached.blocking_recv() {
+            Ok(control) => {
+                *running = Some(Running {
+                    control,
+                    runtime: handle,
+                    stop,
+                    reloads,
+                    thread,
+                });
+                Ok(())
+            }
+            // The startup gave up before attaching, the thread says why
+            Err(_) => Err(Error::failed(match thread.join() {
+                Ok(Err(e)) => format!("{e:#}"),
+                Ok(Ok(())) => "stopped before attaching".to_owned(),
+                Err(_) => "panicked while attaching".to_owned(),
+            })),
+        }
+    }
+
+    fn detach(&self) -> Result<(), Error> {
+        let running = self.running.lock().unwrap().take();
+        let Some(running) = running else {
+            return Err(Error::state("not attached"));
+        };
+        let _ = running.stop.send(());
+        match running.thread.join() {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(Error::failed(format!("{e:#}"))),
+            Err(_) => Err(Error::failed("panicked while detaching")),
+        }
+    }
+
+    // A control command like `guardctl` sends it, the reply as `Ok` unless it begins `err`
+    fn command(&self, line: &str) -> Result<String, Error> {
+        let running = self.running.lock().unwrap();
+        let running = running
+            .as_ref()
+            .ok_or_else(|| Error::state("not attached"))?;
+        let reply = running
+            .runtime
+            .block_on(control::execute_keyed(&running.control, None, line));
+        match reply.strip_prefix("err") {
+            Some(reason) => Err(Error::failed(reason.trim())),
+            None => Ok(reply),
+        }
+    }
+
+    // What SIGHUP does for the daemon, once the guard has done it
+    fn reload(&self) -> Result<(), Error> {
+        let running = self.running.lock().unwrap();
+        let running = running
+            .as_ref()
+            .ok_or_else(|| Error::state("not attached"))?;
+        let (done, outcome) = oneshot::channel();
+        running
+            .reloads
+            .send(done)
+            .map_err(|_| Error::failed("the guard stopped"))?;
+        match outcome.blocking_recv() {
+            Ok(outcome) => outcome.map_err(Error::failed),
+            Err(_) => Err(Error::failed("the guard stopped")),
+        }
+    }
+
+    fn stats_json(&self) -> Result<String, Error> {
+        let running = self.running.lock().unwrap();
+        let running = running
+            .as_ref()
+            .ok_or_else(|| Error::state("not attached"))?;
+        let report = running.control.stats.lock().unwrap().report(None);
+        serde_json::to_string(&report).map_err(|e| Error::failed(e.to_string()))
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.running.get_mut().unwrap().is_some() {
+            let _ = self.detach();
+        }
+    }
+}
+
+// The arguments of a JSON object of options: `true` is a flag, `false` and `null` leave an
+// option out, arrays repeat it
+fn args(config: &str) -> Result<Vec<String>, Error> {
+    let config: Value = serde_json::from_str(config)
+        .map_err(|e| Error::argument(format!("config is not JSON: {e}")))?;
+    let Value::Object(options) = config else {
+        return Err(Error::argument("config must be a JSON object"));
+    };
+    let mut args = Vec::new();
+    for (name, value) in options {
+        let flag = format!("--{}", name.replace('_', "-"));
+        if flag == "--iface" || flag == "--xdp-mode" {
+            return Err(Error::argument(format!(
+                "{name} is an argument of guard_attach"
+            )));
+        }
+        if flag == "--check-verifier" {
+            return Err(Error::argument(format!("{name} is for the daemon only")));
+        }
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::Bool(true) => args.push(flag.clone()),
+                Value::Bool(false) | Value::Null => {}
+                Value::String(value) => args.extend([flag.clone(), value]),
+                Value::Number(value) => args.extend([flag.clone(), value.to_string()]),
+                _ => return Err(Error::argument(format!("{name} takes no objects"))),
+            }
+        }
+    }
+    Ok(args)
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+// Runs `f` for an `int` result, with its error or panic as the last error
+fn status(f: impl FnOnce() -> Result<(), Error>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => GUARD_OK,
+        Ok(Err(e)) => {
+            set_error(e.message);
+            e.code
+        }
+        Err(_) => {
+            set_error("panicked".to_owned());
+            GUARD_ERR_PANIC
+        }
+    }
+}
+
+// The same for a pointer result, NULL on failure
+fn pointer<T>(f: impl FnOnce() -> Result<*mut T, Error>) -> *mut T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(ptr)) => ptr,
+        Ok(Err(e)) => {
+            set_error(e.message);
+            ptr::null_mut()
+        }
+        Err(_) => {
+            set_error("panicked".to_owned());
+            ptr::null_mut()
+        }
+    }
+}
+
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if ptr.is_null() {
+        return Err(Error::argument(format!("{name} is NULL")));
+    }
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map_err(|_| Error::argument(format!("{name} is not UTF-8")))
+}
+
+unsafe fn guard_arg<'a>(g: *mut Guard) -> Result<&'a Guard, Error> {
+    unsafe { g.as_ref() }.ok_or_else(|| Error::argument("guard is NULL"))
+}
+
+fn ip_arg(ip: &str) -> Result<Ipv4Addr, Error> {
+    ip.parse()
+        .map_err(|_| Error::argument(format!("{ip:?} is not an IPv4 address")))
+}
+
+/// Makes a guard from a JSON object of daemon options, NULL for the defaults. Nothing is
+/// loaded until `guard_attach`, but the options are checked here.
+///
+/// # Safety
+///
+/// `config_json` is NULL or a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn guard_new(config_json: *const c_char) -> *mut Guard {
+    pointer(|| {
+        let args = match config_json.is_null() {
+            true => Vec::new(),
+            false => args(unsafe { str_arg(config_json, "config_json") }?)?,
+        };
+        // Checked with a placeholder interface, so a bad option fails here and not at attach
+        let mut check = vec!["xdp-api-guard".to_owned()];
+        check.extend(args.iter().cloned());
+        Opt::try_parse_from(check).map_err(|e| Error::argument(e.to_string()))?;
+        let _ = env_logger::try_init();
+        Ok(Box::into_raw(Box::new(Guard {
+            args,
+            running: Mutex::new(None),
+        })))
+    })
+}
+
+/// Loads the program, attaches it to `iface` in `mode`, one of `GUARD_MODE_*`, and starts
+/// the guard. Returns once it is attached, or failed to.
+///
+/// # Safety
+///
+/// `g` comes from `guard_new` and isn't freed; `iface` is a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn guard_attach(g: *mut Guard, iface: *const c_char, mode: c_int) -> c_int {
+    status(|| unsafe { guard_arg(g)?.attach(str_arg(iface, "iface")?, mode) })
+}
+
+/// Blocks IPv4 address `ip` for `ttl_secs` seconds, 0 for good, like `guardctl block`.
+///
+/// # Safety
+///
+/// As for `guard_attach`, with `ip` a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn guard_block(g: *mut Guard, ip: *const c_char, ttl_secs: u64) -> c_int {
+    status(|| {
+        let guard = unsafe { guard_arg(g) }?;
+        let ip = ip_arg(unsafe { str_arg(ip, "ip") }?)?;
+        let line = match ttl_secs {
+            0 => format!("block {ip}"),
+            ttl => format!("block {ip} --ttl {ttl}"),
+        };
+        guard.command(&line).map(drop)
+    })
+}
+
+/// Takes `ip` off the blocklist, like `guardctl unblock`.
+///
+/// # Safety
+///
+/// As for `guard_block`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn guard_unblock(g: *mut Guard, ip: *const c_char) -> c_int {
+    status(|| {
+        let guard = unsafe { guard_arg(g) }?;
+        let ip = ip_arg(unsafe { str_arg(ip, "ip") }?)?;
+        guard.command(&format!("unblock {ip}")).map(drop)
+    })
+}
+
+/// Reads the `http_tokens` file and the geoip databases again, as SIGHUP does for the daemon,
+/// so a removed token stops working. The guard leaves the process's signals alone: call this
+/// from the embedder's own SIGHUP handling. What fails to read keeps what it had before.
+///
+/// # Safety
+///
+/// `g` comes from `guard_new` and isn't freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn guard_reload(g: *mut Guard) -> c_int {
+    status(|| unsafe { guard_arg(g) }?.reload())
+}
+
+/// The stats document of `/v1/stats`, to be freed with `guard_string_free`.
+///
+/// # Safety
+///
+/// `g` comes from `guard_new` and isn't freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn guard_stats_json(g: *mut Guard) -> *mut c_char {
+    pointer(|| {
+        let json = unsafe { guard_arg(g) }?.stats_json()?;
+        let json = CString::new(json).map_err(|e| Error::failed(e.to_string()))?;
+        Ok(json.into_raw())
+    })
+}
+
+/// Frees a string from `guard_stats_json`. NULL is ignored.
+///
+/// # Safety
+///
+/// `s` is NULL or came from `guard_stats_json` and isn't freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn guard_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
+
+/// Stops the guard and detaches the program. The guard can be attached again.
+///
+/// # Safety
+///
+/// `g` comes from `guard_new` and isn't freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn guard_detach(g: *mut Guard) -> c_int {
+    status(|| unsafe { guard_arg(g) }?.detach())
+}
+
+/// Frees a guard, detaching it first if it is attached. NULL is ignored.
+///
+/// # Safety
+///
+/// `g` is NULL or came from `guard_new`, isn't freed yet and no other call uses it.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn guard_free(g: *mut Guard) {
+    if !g.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(unsafe { Box::from_raw(g) })));
+    }
+}
+
+/// Why the last call on this thread failed, NULL if none did. Valid until the next failing
+/// call on the thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn guard_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn options_become_arguments() {
+        let args = args(
+            r#"{"rate": 100, "allow": ["10.0.0.1", "10.0.0.2"], "conntrack": true,
+            "mask_ips": false, "geoip_db": null, "control_socket": "/run/x.sock"}"#,
+        )
+        .map_err(|e| e.message)
+        .unwrap();
+        assert_eq!(
+            args,
+            // In the order of the keys, serde_json sorts them
+            strings(&[
+                "--allow",
+                "10.0.0.1",
+                "--allow",
+                "10.0.0.2",
+                "--conntrack",
+                "--control-socket",
+                "/run/x.sock",
+                "--rate",
+                "100",
+            ])
+        );
+    }
+
+    #[test]
+    fn attach_and_daemon_only_options_are_refused() {
+        for config in [
+            r#"{"iface": "eth0"}"#,
+            r#"{"xdp_mode": "skb"}"#,
+            r#"{"check_verifier": true}"#,
+            r#"{"rate": {"per": 1}}"#,
+            r#"[1]"#,
+            "not json",
+        ] {
+            let e = args(config).err().unwrap();
+            assert_eq!(e.code, GUARD_ERR_ARGUMENT, "{config}");
+        }
+    }
+
+    #[test]
+    fn no_control_socket_unless_named() {
+        let guard = |config: &str| unsafe {
+            let config = CString::new(config).unwrap();
+            Box::from_raw(guard_new(config.as_ptr()))
+        };
+        let named = |guard: &Guard| guard.args.iter().any(|arg| arg == "--control-socket");
+        assert!(!named(&guard("{}")));
+        assert!(named(&guard(r#"{"control_socket": "/run/x.sock"}"#)));
+    }
+
+    #[test]
+    fn errors_are_per_thread_and_null_safe() {
+        assert_eq!(
+            unsafe { guard_attach(ptr::null_mut(), c"eth0".as_ptr(), GUARD_MODE_AUTO) },
+            GUARD_ERR_ARGUMENT
+        );
+        let message = unsafe { CStr::from_ptr(guard_last_error_message()) };
+        assert_eq!(message.to_str().unwrap(), "guard is NULL");
+        thread::spawn(|| assert!(guard_last_error_message().is_null()))
+            .join()
+            .unwrap();
+        unsafe { guard_free(ptr::null_mut()) };
+        unsafe { guard_string_free(ptr::null_mut()) };
+    }
+
+    #[test]
+    fn unattached_guards_refuse_commands() {
+        let guard = unsafe { guard_new(ptr::null()) };
+        assert!(!guard.is_null());
+        assert_eq!(
+            unsafe { guard_block(guard, c"10.0.0.1".as_ptr(), 0) },
+            GUARD_ERR_STATE
+        );
+        assert!(unsafe { guard_stats_json(guard) }.is_null());
+        assert_eq!(unsafe { guard_detach(guard) }, GUARD_ERR_STATE);
+        assert_eq!(unsafe { guard_reload(guard) }, GUARD_ERR_STATE);
+        unsafe { guard_free(guard) };
+    }
+}
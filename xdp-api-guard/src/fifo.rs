@@ -0,0 +1,197 @@
+//! Control commands from a named pipe, for scripts that would rather `echo` than talk to the
+//! socket. Same line commands as the control socket; since a FIFO has no way back to the
+//! writer, responses go to the log.
+
+use std::{
+    ffi::CString,
+    fs, io,
+    os::unix::{
+        ffi::OsStrExt as _,
+        fs::{FileTypeExt as _, MetadataExt as _},
+    },
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context as _, bail};
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt as _, BufReader},
+    net::unix::pipe,
+};
+
+use crate::{
+    control::{self, ControlState},
+    mask,
+};
+
+/// Creates the FIFO at `path` unless one is already there, then executes every line written
+/// to it. Fails only when the FIFO can't be opened or is no longer one only we can write;
+/// a read that fails is logged and the FIFO opened again.
+pub async fn serve(path: &Path, state: Arc<ControlState>) -> anyhow::Result<()> {
+    create(path)?;
+    info!("reading commands from {}", path.display());
+    loop {
+        // Checked again at every open, as whoever can write the directory can swap the file
+        check(path, &lstat(path)?)?;
+        // Reads see end of file once the last writer closes, and keep seeing it. A fresh open
+        // waits for the next writer instead.
+        let receiver = pipe::OpenOptions::new()
+            .open_receiver(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let mut lines = BufReader::new(receiver);
+        loop {
+            match next_command(&mut lines).await {
+                Ok(Some(line)) => {
+                    let response = control::execute_line(&state, &line).await;
+                    let (line, out) = (mask::words(&line), mask::words(&response));
+                    if response.starts_with("err") {
+                        warn!("fifo: {line}: {out}");
+                    } else {
+                        info!("fifo: {line}: {out}");
+                    }
+                }
+                Ok(None) => {
+                    debug!("fifo: writers closed, reopening");
+                    break;
+                }
+                Err(e) => {
+                    warn!("fifo: failed to read {}: {e}, reopening", path.display());
+                    // Not to spin on an error that comes back at once
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// The next line that holds a command, trimmed. Lines that aren't UTF-8 are logged and
+// skipped; None at end of file.
+async fn next_command(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Option<String>> {
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf).await? == 0 {
+            return Ok(None);
+        }
+        let Ok(line) = std::str::from_utf8(&buf) else {
+            warn!("fifo: skipped a line that isn't UTF-8");
+            continue;
+        };
+        let line = line.trim();
+        if !line.is_empty() {
+            return Ok(Some(line.to_owned()));
+        }
+    }
+}
+
+fn create(path: &Path) -> anyhow::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) => return check(path, &meta),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("failed to stat {}", path.display())),
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // Commands are privileged, only root gets to write them
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } < 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("failed to create FIFO {}", path.display()));
+    }
+    Ok(())
+}
+
+fn lstat(path: &Path) -> anyhow::Result<fs::Metadata> {
+    fs::symlink_metadata(path).with_context(|| format!("failed to stat {}", path.display()))
+}
+
+// Commands are privileged: the FIFO has to be ours and closed to everyone else. `meta` is of
+// the path itself, so a symlink to a FIFO is refused like any other file.
+fn check(path: &Path, meta: &fs::Metadata) -> anyhow::Result<()> {
+    if !meta.file_type().is_fifo() {
+        bail!("{} exists and is not a FIFO", path.display());
+    }
+    let uid = unsafe { libc::geteuid() };
+    if meta.uid() != uid {
+        bail!(
+            "FIFO {} belongs to uid {}, not to us ({uid})",
+            path.display(),
+            meta.uid()
+        );
+    }
+    if meta.mode() & 0o077 != 0 {
+        bail!(
+            "FIFO {} has mode {:o}, others mustn't have access (chmod 600)",
+            path.display(),
+            meta.mode() & 0o777
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{PermissionsExt as _, symlink};
+
+    use super::*;
+
+    fn dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("guard-fifo-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn only_a_fifo_closed_to_others_is_taken() {
+        let dir = dir("check");
+        let fifo = dir.join("commands");
+        create(&fifo).unwrap();
+        assert_eq!(fs::metadata(&fifo).unwrap().mode() & 0o777, 0o600);
+        // Taken as it is the next time
+        create(&fifo).unwrap();
+
+        fs::set_permissions(&fifo, fs::Permissions::from_mode(0o620)).unwrap();
+        let e = create(&fifo).unwrap_err().to_string();
+        assert!(e.contains("mode 620"), "{e}");
+        fs::set_permissions(&fifo, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let link = dir.join("link");
+        symlink(&fifo, &link).unwrap();
+        assert!(create(&link).is_err());
+        let file = dir.join("file");
+        fs::write(&file, "").unwrap();
+        assert!(create(&file).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lines_are_trimmed_and_bad_ones_skipped() {
+        let mut input: &[u8] = b"  status \n\n\xff\xfe block\nlist\nunterminated";
+        let mut commands = Vec::new();
+        while let Some(line) = next_command(&mut input).await.unwrap() {
+            commands.push(line);
+        }
+        assert_eq!(commands, ["status", "list", "unterminated"]);
+    }
+
+    // A reader whose reads fail
+    struct Failing;
+
+    impl tokio::io::AsyncRead for Failing {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Err(io::Error::other("gone")))
+        }
+    }
+
+    #[tokio::test]
+    async fn read_errors_reach_the_caller() {
+        let mut reader = BufReader::new(Failing);
+        assert!(next_command(&mut reader).await.is_err());
+    }
+}
@@ -0,0 +1,137 @@
+//! `xdp-api-guard flush`: the flushes of `guardctl flush`, straight on the maps another loader
+//! pinned (`--external-maps`), for when no daemon is running to take the command. The maps of
+//! a program the daemon loaded itself go away with it, there is nothing to flush then.
+
+use std::{
+    io::{self, IsTerminal as _, Write as _},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use anyhow::bail;
+use clap::Parser;
+use xdp_api_guard_common::{DEFAULT_CONTROL_SOCKET, Origin, stat};
+
+use crate::{
+    control::{self, FlushTarget},
+    maps::{Layout, Maps},
+};
+
+/// Flush state in pinned maps while no daemon is running
+#[derive(Debug, Parser)]
+#[clap(
+    name = "xdp-api-guard flush",
+    after_help = "TARGET is rate-limit, bans, blocklist [--origin ORIGIN] [--all], conntrack or \
+                  stats, as for `guardctl flush`. With the daemon running use that instead: \
+                  the daemon keeps its own copy of the blocklist"
+)]
+pub struct FlushOpt {
+    /// Directory the maps are pinned in, as given to --external-maps
+    #[clap(long, value_name = "PIN_DIR", env = "GUARD_EXTERNAL_MAPS")]
+    pins: PathBuf,
+
+    /// Control socket a running daemon would answer on
+    #[clap(long, default_value = DEFAULT_CONTROL_SOCKET, env = "GUARD_CONTROL_SOCKET")]
+    socket: PathBuf,
+
+    /// Don't ask before deleting entries
+    #[clap(short, long)]
+    yes: bool,
+
+    /// What to flush, and its arguments
+    #[clap(
+        value_name = "TARGET",
+        required = true,
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    target: Vec<String>,
+}
+
+pub fn run(opt: FlushOpt) -> anyhow::Result<()> {
+    let words: Vec<&str> = opt.target.iter().map(String::as_str).collect();
+    let target = control::parse_flush(&words)?;
+    // It would put back what it holds, and miss what went
+    if UnixStream::connect(&opt.socket).is_ok() {
+        bail!(
+            "a daemon answers on {}, use `guardctl flush {}`",
+            opt.socket.display(),
+            words.join(" ")
+        );
+    }
+    let mut maps = Maps::from_pins(&opt.pins)?;
+    if let Layout::Other(why) = maps.layout()? {
+        bail!(
+            "refusing the maps pinned in {}: {why}; a daemon with --fresh empties them",
+            opt.pins.display()
+        );
+    }
+    if !opt.yes && !confirm(&words, &opt.pins)? {
+        bail!("aborted");
+    }
+    let removed = match target {
+        FlushTarget::RateLimit => {
+            let mut removed = control::clear(&mut maps.rate_limit)?
+                + control::clear(&mut maps.prefix)?
+                + control::clear(&mut maps.nat)?
+                + control::clear(&mut maps.rate_limit6)?
+                + control::clear(&mut maps.quic_initial)?;
+            for map in [&mut maps.ack, &mut maps.http].into_iter().flatten() {
+                removed += control::clear(map)?;
+            }
+            if let Some(service) = &mut maps.service {
+                removed += control::clear(service)?;
+            }
+            control::clear(&mut maps.prefix_sources)?;
+            removed
+        }
+        FlushTarget::Bans | FlushTarget::Blocklist { .. } => {
+            // Only the entry that won is in the map, the ones it hid were the daemon's
+            let entries = maps.blocklist.iter().collect::<Result<Vec<_>, _>>()?;
+            let doomed: Vec<u32> = entries
+                .into_iter()
+                .filter(|(_, entry)| {
+                    Origin::from_u8(entry.origin).is_some_and(|origin| target.flushes(origin))
+                })
+                .map(|(ip, _)| ip)
+                .collect();
+            for ip in &doomed {
+                match maps.blocklist.remove(ip) {
+                    Ok(()) | Err(aya::maps::MapError::KeyNotFound) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            doomed.len()
+        }
+        FlushTarget::Conntrack => match &mut maps.conntrack {
+            Some(conntrack) => control::clear(conntrack)?,
+            None => bail!("the pinned program is built without connection tracking"),
+        },
+        FlushTarget::Stats => {
+            let cpus = maps.stats.get(&stat::DROP, 0)?.len();
+            for index in 0..stat::LEN {
+                let zeros = aya::maps::PerCpuValues::try_from(vec![0u64; cpus])?;
+                maps.stats.set(index, zeros, 0)?;
+            }
+            println!("ok stats reset");
+            return Ok(());
+        }
+    };
+    println!("ok removed {removed} entries");
+    Ok(())
+}
+
+fn confirm(words: &[&str], pins: &Path) -> anyhow::Result<bool> {
+    if !io::stdin().is_terminal() {
+        bail!("refusing to flush {} without --yes", words.join(" "));
+    }
+    eprint!(
+        "This will delete entries of the maps pinned in {} (`flush {}`). Continue? [y/N] ",
+        pins.display(),
+        words.join(" ")
+    );
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
@@ -0,0 +1,296 @@
+//! `--geoip-db` and `--asn-db`: the country and the network of a source, for the people
+//! reading the top offenders, `guardctl why`, the status page, the day files and `/v1/stats`.
+//!
+//! Only userspace presentations look sources up, never the packet path. Lookups go through a
+//! cache of the last `CACHE_PREFIXES` /24s (/48s for IPv6) looked up, networks smaller than
+//! that being rare among offenders, so a flood from one prefix costs one lookup. Either
+//! database is optional; without both there is no `GeoIp` and nothing is added to any output,
+//! not even empty fields. SIGHUP reads both files again.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
+
+use anyhow::{Context as _, bail};
+use log::debug;
+use maxminddb::{MaxMindDBError, Reader, geoip2};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bounded::{Bounded, Weigh},
+    heatmap::Offender,
+};
+
+/// Prefixes whose answers are kept.
+pub const CACHE_PREFIXES: usize = 65_536;
+
+/// Entries of the aggregations in `/v1/stats` and the daily report.
+pub const TOP: usize = 10;
+
+/// What the databases know of a source. A field the databases don't have is left out.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Geo {
+    /// ISO 3166-1 code of the country the address is registered in, e.g. `DE`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u32>,
+    /// The AS's organization, e.g. `Example Hosting GmbH`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub as_name: Option<String>,
+}
+
+impl Weigh for Option<Geo> {
+    fn heap_bytes(&self) -> usize {
+        self.as_ref().map_or(0, |geo| {
+            geo.country.as_ref().map_or(0, String::capacity)
+                + geo.as_name.as_ref().map_or(0, String::capacity)
+        })
+    }
+}
+
+// `DE AS64500 Example Hosting GmbH`, with whatever is known
+impl std::fmt::Display for Geo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(country) = &self.country {
+            parts.push(country.clone());
+        }
+        if let Some(asn) = self.asn {
+            parts.push(format!("AS{asn}"));
+        }
+        if let Some(name) = &self.as_name {
+            parts.push(name.clone());
+        }
+        f.write_str(&parts.join(" "))
+    }
+}
+
+pub struct GeoIp {
+    country_path: Option<PathBuf>,
+    asn_path: Option<PathBuf>,
+    dbs: RwLock<Dbs>,
+    // Answers by prefix, `None` for one the databases don't know. Locked on its own, after
+    // anything else.
+    cache: Mutex<Bounded<IpAddr, Option<Geo>>>,
+}
+
+struct Dbs {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// Opens the databases given, `None` if neither is.
+    pub fn open(country: Option<&Path>, asn: Option<&Path>) -> anyhow::Result<Option<Self>> {
+        if country.is_none() && asn.is_none() {
+            return Ok(None);
+        }
+        let geo = Self {
+            country_path: country.map(Path::to_owned),
+            asn_path: asn.map(Path::to_owned),
+            dbs: RwLock::new(Dbs {
+                country: None,
+                asn: None,
+            }),
+            cache: Mutex::new(Bounded::cache("geoip", CACHE_PREFIXES)),
+        };
+        geo.reload()?;
+        Ok(Some(geo))
+    }
+
+    /// Reads the files again and forgets every answer. A file that fails to read leaves both
+    /// databases as they were.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let country = match &self.country_path {
+            Some(path) => Some(read(path, &["Country", "City"])?),
+            None => None,
+        };
+        let asn = match &self.asn_path {
+            Some(path) => Some(read(path, &["ASN"])?),
+            None => None,
+        };
+        *self.dbs.write().unwrap() = Dbs { country, asn };
+        let mut cache = self.cache.lock().unwrap();
+        let cached: Vec<IpAddr> = cache.iter().map(|(prefix, _)| *prefix).collect();
+        for prefix in cached {
+            cache.remove(&prefix);
+        }
+        Ok(())
+    }
+
+    /// What the databases know of `ip`, `None` if nothing.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Geo> {
+        let prefix = prefix(ip);
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .update(&prefix, |geo| geo.clone());
+        if let Some(geo) = cached {
+            return geo;
+        }
+        // Looked up on the prefix, whose answer is the one every address in it gets
+        let geo = self.resolve(prefix);
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(prefix, geo.clone(), |_, _| false);
+        geo
+    }
+
+    fn resolve(&self, ip: IpAddr) -> Option<Geo> {
+        let dbs = self.dbs.read().unwrap();
+        let country = dbs.country.as_ref().and_then(|db| {
+            let found: geoip2::Country = found(db.lookup(ip))?;
+            found.country?.iso_code.map(str::to_owned)
+        });
+        let (asn, as_name) = dbs
+            .asn
+            .as_ref()
+            .and_then(|db| {
+                let found: geoip2::Asn = found(db.lookup(ip))?;
+                Some((
+                    found.autonomous_system_number,
+                    found.autonomous_system_organization.map(str::to_owned),
+                ))
+            })
+            .unwrap_or_default();
+        (country.is_some() || asn.is_some() || as_name.is_some()).then_some(Geo {
+            country,
+            asn,
+            as_name,
+        })
+    }
+}
+
+fn read(path: &Path, kinds: &[&str]) -> anyhow::Result<Reader<Vec<u8>>> {
+    let db = Reader::open_readfile(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let kind = &db.metadata.database_type;
+    if !kinds.iter().any(|k| kind.contains(k)) {
+        bail!(
+            "{} is a {kind} database, expected one of {}",
+            path.display(),
+            kinds.join(", ")
+        );
+    }
+    Ok(db)
+}
+
+// An address the database has no network for is an answer too
+fn found<T>(result: Result<T, MaxMindDBError>) -> Option<T> {
+    match result {
+        Ok(found) => Some(found),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+        Err(e) => {
+            debug!("geoip lookup failed: {e}");
+            None
+        }
+    }
+}
+
+// The /24 or /48 `ip` is in
+fn prefix(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => Ipv4Addr::from(u32::from(ip) & !0xff).into(),
+        IpAddr::V6(ip) => Ipv6Addr::from(u128::from(ip) & !((1u128 << 80) - 1)).into(),
+    }
+}
+
+/// Sources and packets over their limit, added up by country and by AS.
+#[derive(Debug, Default)]
+pub struct Tally {
+    countries: HashMap<String, Sum>,
+    networks: HashMap<u32, Sum>,
+}
+
+#[derive(Debug, Default)]
+struct Sum {
+    name: Option<String>,
+    sources: u64,
+    packets: u64,
+}
+
+/// The largest entries of a `Tally`, most packets first.
+#[derive(Debug, Serialize)]
+pub struct GeoTop {
+    pub by_country: Vec<GeoCount>,
+    pub by_asn: Vec<GeoCount>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GeoCount {
+    /// The country code, or `AS64500`.
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub sources: u64,
+    pub packets: u64,
+}
+
+impl Tally {
+    /// Counts one source with `packets` over its limit. Sources the databases know nothing of
+    /// aren't counted.
+    pub fn add(&mut self, geo: Option<&Geo>, packets: u64) {
+        let Some(geo) = geo else {
+            return;
+        };
+        if let Some(country) = &geo.country {
+            let sum = self.countries.entry(country.clone()).or_default();
+            sum.sources += 1;
+            sum.packets += packets;
+        }
+        if let Some(asn) = geo.asn {
+            let sum = self.networks.entry(asn).or_default();
+            if sum.name.is_none() {
+                sum.name.clone_from(&geo.as_name);
+            }
+            sum.sources += 1;
+            sum.packets += packets;
+        }
+    }
+
+    pub fn top(self, n: usize) -> GeoTop {
+        GeoTop {
+            by_country: largest(self.countries.into_iter().collect(), n),
+            by_asn: largest(
+                self.networks
+                    .into_iter()
+                    .map(|(asn, sum)| (format!("AS{asn}"), sum))
+                    .collect(),
+                n,
+            ),
+        }
+    }
+}
+
+// Ties by key, so the order doesn't change from one call to the next
+fn largest(mut sums: Vec<(String, Sum)>, n: usize) -> Vec<GeoCount> {
+    sums.sort_by(|(a_key, a), (b_key, b)| {
+        (b.packets, b.sources)
+            .cmp(&(a.packets, a.sources))
+            .then(a_key.cmp(b_key))
+    });
+    sums.into_iter()
+        .take(n)
+        .map(|(key, sum)| GeoCount {
+            key,
+            name: sum.name,
+            sources: sum.sources,
+            packets: sum.packets,
+        })
+        .collect()
+}
+
+/// The sources over their limit among `offenders`, by country and AS.
+pub fn over_limit(geo: &GeoIp, offenders: &[Offender]) -> GeoTop {
+    let mut tally = Tally::default();
+    for o in offenders.iter().filter(|o| o.count > o.limit) {
+        tally.add(geo.lookup(o.addr).as_ref(), o.count - o.limit);
+    }
+    tally.top(TOP)
+}
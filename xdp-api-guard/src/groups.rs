@@ -0,0 +1,261 @@
+//! `--group`: named sets of IPv4 networks whose members share one policy, a rate limit per
+//! source and a zone action, in place of the defaults of their zone.
+//!
+//! The kernel resolves a source in two lookups: `GROUP_CIDRS` gives the id of the group with
+//! the longest matching network, `GROUP_POLICY` the policy of that id. Names only exist here.
+//! Members change at runtime with `group add` and `group del`; like the other runtime changes
+//! outside the blocklist, they are gone after a restart.
+
+use std::{collections::BTreeMap, fmt::Write as _, net::Ipv4Addr};
+
+use anyhow::{Context as _, bail};
+use aya::maps::{Array, HashMap, MapData, PerCpuArray, lpm_trie::Key, lpm_trie::LpmTrie};
+use xdp_api_guard_common::{
+    Config, GroupPolicy, MAX_GROUPS, PacketLog, group_stat, tagged_limit, zone_action,
+};
+
+use crate::cidr::Ipv4Cidr;
+
+pub struct Groups {
+    cidrs: LpmTrie<MapData, u32, u8>,
+    policy: Array<MapData, GroupPolicy>,
+    stats: PerCpuArray<MapData, u64>,
+    // Index + 1 is the group id
+    groups: Vec<Group>,
+}
+
+pub struct Group {
+    pub name: String,
+    pub policy: GroupPolicy,
+    pub members: Vec<Ipv4Cidr>,
+}
+
+/// Counters of one group, summed over the CPUs.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GroupCounts {
+    pub packets: u64,
+    pub drops: u64,
+}
+
+impl Groups {
+    pub fn new(
+        cidrs: LpmTrie<MapData, u32, u8>,
+        policy: Array<MapData, GroupPolicy>,
+        stats: PerCpuArray<MapData, u64>,
+    ) -> Self {
+        Self {
+            cidrs,
+            policy,
+            stats,
+            groups: Vec::new(),
+        }
+    }
+
+    pub fn groups(&self) -> &[Group] {
+        &self.groups
+    }
+
+    /// Whether any group has members, i.e. the kernel has something to look up.
+    pub fn has_members(&self) -> bool {
+        self.groups.iter().any(|group| !group.members.is_empty())
+    }
+
+    /// Sets the per-source rate of group `name`, 0 for the limit of the member's zone. The
+    /// group is created if it doesn't exist yet.
+    pub fn set_rate(&mut self, name: &str, rate: u64) -> anyhow::Result<()> {
+        self.update(name, |policy| policy.rate = rate)
+    }
+
+    /// Sets the `zone_action` of group `name`, creating it if needed.
+    pub fn set_action(&mut self, name: &str, action: u8) -> anyhow::Result<()> {
+        self.update(name, |policy| policy.action = action)
+    }
+
+    fn update(&mut self, name: &str, f: impl FnOnce(&mut GroupPolicy)) -> anyhow::Result<()> {
+        let id = self.id(name)?;
+        let group = &mut self.groups[usize::from(id) - 1];
+        let mut policy = group.policy;
+        f(&mut policy);
+        self.policy.set(u32::from(id), policy, 0)?;
+        group.policy = policy;
+        Ok(())
+    }
+
+    // Id of the group called `name`, created with the default policy if there is none
+    fn id(&mut self, name: &str) -> anyhow::Result<u8> {
+        if let Some(pos) = self.groups.iter().position(|group| group.name == name) {
+            return Ok(pos as u8 + 1);
+        }
+        check_name(name)?;
+        if self.groups.len() + 1 >= MAX_GROUPS as usize {
+            bail!("at most {} groups", MAX_GROUPS - 1);
+        }
+        self.groups.push(Group {
+            name: name.to_owned(),
+            policy: GroupPolicy::default(),
+            members: Vec::new(),
+        });
+        let id = self.groups.len() as u8;
+        self.policy.set(u32::from(id), GroupPolicy::default(), 0)?;
+        Ok(id)
+    }
+
+    /// Makes `cidr` a network of group `name`, creating the group if needed. A network
+    /// belongs to one group at most; a more specific network inside it may belong to another,
+    /// and wins for its addresses. Returns false if it already was a member.
+    pub fn add(&mut self, name: &str, cidr: Ipv4Cidr) -> anyhow::Result<bool> {
+        if let Some(owner) = self
+            .groups
+            .iter()
+            .find(|group| group.members.contains(&cidr))
+        {
+            if owner.name == name {
+                return Ok(false);
+            }
+            bail!("{cidr} is already in group {}", owner.name);
+        }
+        let id = self.id(name)?;
+        self.cidrs.insert(&key(cidr), id, 0)?;
+        self.groups[usize::from(id) - 1].members.push(cidr);
+        Ok(true)
+    }
+
+    /// Takes `cidr` out of group `name`. Returns false if it wasn't a member.
+    pub fn remove(&mut self, name: &str, cidr: Ipv4Cidr) -> anyhow::Result<bool> {
+        let group = self
+            .groups
+            .iter_mut()
+            .find(|group| group.name == name)
+            .with_context(|| format!("no group {name:?}"))?;
+        let Some(pos) = group.members.iter().position(|c| *c == cidr) else {
+            return Ok(false);
+        };
+        self.cidrs.remove(&key(cidr))?;
+        group.members.remove(pos);
+        Ok(true)
+    }
+
+    /// The group of `ip` and the network that matched, the longest one as in the kernel.
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<(&Group, Ipv4Cidr)> {
+        self.groups
+            .iter()
+            .flat_map(|group| group.members.iter().map(move |cidr| (group, *cidr)))
+            .filter(|(_, cidr)| cidr.contains(ip))
+            .max_by_key(|(_, cidr)| cidr.prefix_len())
+    }
+
+    /// Rate limit of the untagged host-order IPv4 source `addr`: its group's rate if it has
+    /// one, otherwise the limit of its zone.
+    pub fn limit(&self, cfg: &Config, addr: u32) -> u64 {
+        match self.lookup(Ipv4Addr::from(addr)) {
+            Some((group, _)) if group.policy.rate != 0 => group.policy.rate,
+            _ => cfg.ipv4_limit(addr),
+        }
+    }
+
+    /// Counters of every group, in the order of [`groups`](Self::groups).
+    pub fn counts(&self) -> anyhow::Result<Vec<GroupCounts>> {
+        let mut counts = Vec::new();
+        for id in 1..=self.groups.len() as u32 {
+            let slot = |slot| -> anyhow::Result<u64> {
+                let index = id * group_stat::PER_GROUP + slot;
+                Ok(self.stats.get(&index, 0)?.iter().sum())
+            };
+            counts.push(GroupCounts {
+                packets: slot(group_stat::PACKETS)?,
+                drops: slot(group_stat::DROPS)?,
+            });
+        }
+        Ok(counts)
+    }
+
+    /// The reply of `group list`: each group with its policy, counters and networks, and how
+    /// many of its sources sent how much in their current window.
+    pub fn render(
+        &self,
+        rate_limit: &HashMap<MapData, u32, PacketLog>,
+        tags: &HashMap<MapData, u32, u32>,
+        cfg: &Config,
+        now: u64,
+    ) -> anyhow::Result<String> {
+        // By group name: sources, packets this window, sources over their limit
+        let mut window: BTreeMap<&str, (u64, u64, u64)> = BTreeMap::new();
+        for (ip, log) in rate_limit.iter().filter_map(Result::ok) {
+            let count = log.current_count(now, cfg.window_ns);
+            if count == 0 {
+                continue;
+            }
+            let Some((group, _)) = self.lookup(Ipv4Addr::from(ip)) else {
+                continue;
+            };
+            let limit = match tags.get(&ip, 0) {
+                Ok(score) => tagged_limit(self.limit(cfg, ip), score),
+                Err(_) => self.limit(cfg, ip),
+            };
+            let entry = window.entry(group.name.as_str()).or_default();
+            entry.0 += 1;
+            entry.1 += count;
+            entry.2 += u64::from(count > limit);
+        }
+        let counts = self.counts()?;
+        let mut out = format!("ok {} groups", self.groups.len());
+        for (group, counts) in self.groups.iter().zip(counts) {
+            let rate = match group.policy.rate {
+                0 => "zone rate".to_owned(),
+                rate => format!("rate {rate}"),
+            };
+            let _ = write!(
+                out,
+                "\n{} {rate}, action {}, {} packets, {} dropped",
+                group.name,
+                action_name(group.policy.action),
+                counts.packets,
+                counts.drops
+            );
+            let (sources, packets, limited) =
+                window.get(group.name.as_str()).copied().unwrap_or_default();
+            let _ = write!(
+                out,
+                "\n  current window: {sources} sources, {packets} packets, {limited} limited"
+            );
+            for cidr in &group.members {
+                let _ = write!(out, "\n  {cidr}");
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn key(cidr: Ipv4Cidr) -> Key<u32> {
+    Key::new(u32::from(cidr.prefix_len()), u32::from(cidr.addr()).to_be())
+}
+
+// Names are single words, they go into command lines and metric labels
+fn check_name(name: &str) -> anyhow::Result<()> {
+    let valid = name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if name.is_empty() || !valid {
+        bail!("invalid group name {name:?}, use letters, digits, - and _");
+    }
+    Ok(())
+}
+
+/// `limit`, `pass` or `drop`, as in `--external-action`.
+pub fn parse_action(name: &str) -> Option<u8> {
+    match name {
+        "limit" => Some(zone_action::LIMIT),
+        "pass" => Some(zone_action::PASS),
+        "drop" => Some(zone_action::DROP),
+        _ => None,
+    }
+}
+
+pub fn action_name(action: u8) -> &'static str {
+    match action {
+        zone_action::LIMIT => "limit",
+        zone_action::PASS => "pass",
+        zone_action::DROP => "drop",
+        _ => "unknown",
+    }
+}
@@ -0,0 +1,115 @@
+//! Whether the guard is doing its job, for `/healthz` and orchestration probes.
+//!
+//! Healthy means the program is attached to an interface whose link is up and the last read
+//! of the counters worked. The main loop records attachment and the link as the link watcher
+//! reports them; the sampler records its own failures. A link that is down isn't our fault,
+//! but it passes nothing and the counters standing still look just like a quiet night, so
+//! whoever gets paged for the guard should hear about it.
+//!
+//! Aborted packets are reported but don't make the guard unhealthy: any sender can get a
+//! truncated header aborted, and restarting the guard wouldn't change that. Neither does
+//! state pressure: evicting old histories is the limit working, not the guard failing, nor
+//! samples lost to a full ring, which only cost evidence. Nor does a gap between the
+//! program's counters and the interface's, which `ifstats` explains.
+
+use std::sync::{
+    Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+
+use serde::Serialize;
+
+use crate::{
+    bounded,
+    ifstats::Crosscheck,
+    stats::{Dataplane, StatsState},
+};
+
+#[derive(Debug, Default)]
+pub struct Health {
+    attached: AtomicBool,
+    link_up: AtomicBool,
+    policy_error: Mutex<Option<String>>,
+    crosscheck: Mutex<Option<Crosscheck>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub attached: bool,
+    pub link_up: bool,
+    /// `up`, `down` or `detached`, the two above in one.
+    pub dataplane: Dataplane,
+    pub stats_readable: bool,
+    /// Informational: XDP_ABORTED verdicts in the last second, see `guardctl last-abort`.
+    pub aborted_rate: u64,
+    /// Informational: share of the last second's malformed packet samples that found the
+    /// ring full, see `--ringbuf-size`.
+    pub sample_loss: f64,
+    /// Informational: userspace evicted state to stay within `--state-memory` lately.
+    pub state_pressure: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats_error: Option<String>,
+    /// Informational: why the `--policy-module` stopped deciding, until it is reloaded. The
+    /// filter itself carries on without it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy_error: Option<String>,
+    /// Informational: how the program's counters compared with the interface's in the last
+    /// window judged, with `--ifstats-check-secs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crosscheck: Option<Crosscheck>,
+}
+
+impl Health {
+    pub fn set_attached(&self, attached: bool) {
+        self.attached.store(attached, Ordering::Relaxed);
+    }
+
+    pub fn set_link_up(&self, up: bool) {
+        self.link_up.store(up, Ordering::Relaxed);
+    }
+
+    /// Detached wins over a link that is down: something has to re-attach the program
+    /// whatever the link does.
+    pub fn dataplane(&self) -> Dataplane {
+        if !self.attached.load(Ordering::Relaxed) {
+            Dataplane::Detached
+        } else if !self.link_up.load(Ordering::Relaxed) {
+            Dataplane::Down
+        } else {
+            Dataplane::Up
+        }
+    }
+
+    #[cfg(feature = "wasm-policy")]
+    pub fn set_policy_error(&self, error: Option<String>) {
+        *self.policy_error.lock().unwrap() = error;
+    }
+
+    pub fn set_crosscheck(&self, check: Crosscheck) {
+        *self.crosscheck.lock().unwrap() = Some(check);
+    }
+
+    pub fn crosscheck(&self) -> Option<Crosscheck> {
+        *self.crosscheck.lock().unwrap()
+    }
+
+    pub fn report(&self, stats: &StatsState) -> HealthReport {
+        let dataplane = self.dataplane();
+        // Not ready before the first sample either
+        let stats_readable = stats.error().is_none() && stats.sampled();
+        HealthReport {
+            healthy: dataplane == Dataplane::Up && stats_readable,
+            attached: self.attached.load(Ordering::Relaxed),
+            link_up: self.link_up.load(Ordering::Relaxed),
+            dataplane,
+            stats_readable,
+            aborted_rate: stats.aborted_rate(),
+            sample_loss: stats.sample_loss(),
+            state_pressure: bounded::under_pressure(),
+            stats_error: stats.error().map(str::to_owned),
+            policy_error: self.policy_error.lock().unwrap().clone(),
+            crosscheck: self.crosscheck(),
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Read-only view of the limiter maps: which sources are closest to their limit right now.
+//!
+//! Unlike traffic counters this reflects limiter state only, with the same window
+//! interpretation as the datapath, so a source whose window has expired shows as 0.
+
+use std::{
+    fmt::Write as _,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use aya::maps::{HashMap, MapData};
+use xdp_api_guard_common::{Config, PacketLog, nat_source, tagged_limit};
+
+use crate::{geoip::GeoIp, groups::Groups, timebase};
+
+// Width of the bar drawn for a source at 100% of its limit
+const BAR_WIDTH: u64 = 20;
+
+pub struct Offender {
+    pub addr: IpAddr,
+    pub count: u64,
+    /// Budget for the current window, after tags. 0 means the source is blocked by its tag.
+    pub limit: u64,
+    /// Seconds since the limiter first saw the source. On CLOCK_MONOTONIC like the entry, so
+    /// time spent suspended isn't counted.
+    pub age: u64,
+    /// Source port of an address behind `--nat-prefix`, which the datapath limits per port.
+    pub port: Option<u16>,
+    /// A NAT address as a whole, limited by `--nat-rate`.
+    pub nat: bool,
+}
+
+/// Every source with packets in its current window, highest count first.
+pub fn collect(
+    v4: &HashMap<MapData, u32, PacketLog>,
+    v6: &HashMap<MapData, [u8; 16], PacketLog>,
+    nat: &HashMap<MapData, u64, PacketLog>,
+    tags: &HashMap<MapData, u32, u32>,
+    groups: &Groups,
+    cfg: &Config,
+    now: u64,
+) -> Vec<Offender> {
+    let limit = |ip: u32| match tags.get(&ip, 0) {
+        Ok(score) => tagged_limit(groups.limit(cfg, ip), score),
+        Err(_) => groups.limit(cfg, ip),
+    };
+    let mut offenders = Vec::new();
+    let mut pools = std::collections::HashSet::new();
+    for (key, log) in nat.iter().filter_map(Result::ok) {
+        let count = log.current_count(now, cfg.window_ns);
+        if count == 0 {
+            continue;
+        }
+        let (ip, port) = nat_source(key);
+        pools.insert(ip);
+        offenders.push(Offender {
+            addr: Ipv4Addr::from(ip).into(),
+            count,
+            limit: limit(ip),
+            age: age(&log),
+            port: Some(port),
+            nat: false,
+        });
+    }
+    for (ip, log) in v4.iter().filter_map(Result::ok) {
+        let count = log.current_count(now, cfg.window_ns);
+        if count == 0 {
+            continue;
+        }
+        let nat = cfg.nat_limit != 0 && pools.contains(&ip);
+        offenders.push(Offender {
+            addr: Ipv4Addr::from(ip).into(),
+            count,
+            limit: if nat { cfg.nat_limit } else { limit(ip) },
+            age: age(&log),
+            port: None,
+            nat,
+        });
+    }
+    for (ip, log) in v6.iter().filter_map(Result::ok) {
+        let count = log.current_count(now, cfg.window_ns6);
+        if count == 0 {
+            continue;
+        }
+        offenders.push(Offender {
+            addr: Ipv6Addr::from(ip).into(),
+            count,
+            limit: cfg.rate_limit6,
+            age: age(&log),
+            port: None,
+            nat: false,
+        });
+    }
+    offenders.sort_by(|a, b| {
+        (b.count.cmp(&a.count))
+            .then(a.addr.cmp(&b.addr))
+            .then(a.port.cmp(&b.port))
+    });
+    offenders
+}
+
+impl Offender {
+    /// The address, with `:port` for one source port of a NAT address.
+    pub fn source(&self) -> String {
+        match self.port {
+            Some(port) => format!("{}:{port}", self.addr),
+            None => self.addr.to_string(),
+        }
+    }
+}
+
+fn age(log: &PacketLog) -> u64 {
+    timebase::boot_ns().saturating_sub(log.first_seen) / 1_000_000_000
+}
+
+/// The first `top` offenders, one line each with a bar of the count relative to the limit,
+/// and with `geo` what the databases know of the source.
+pub fn render(offenders: &[Offender], top: usize, geo: Option<&GeoIp>) -> String {
+    let mut out = format!(
+        "ok {} sources in their current window, showing {}",
+        offenders.len(),
+        offenders.len().min(top)
+    );
+    for o in offenders.iter().take(top) {
+        let (bar, state) = if o.limit == 0 {
+            ("█".repeat(BAR_WIDTH as usize), "blocked by tag")
+        } else {
+            let filled = (o.count * BAR_WIDTH / o.limit).min(BAR_WIDTH);
+            let bar = "█".repeat(filled as usize) + &"░".repeat((BAR_WIDTH - filled) as usize);
+            let state = match (o.count > o.limit, o.nat) {
+                (true, true) => "limited, all ports",
+                (true, false) => "limited",
+                (false, true) => "all ports",
+                (false, false) => "",
+            };
+            (bar, state)
+        };
+        let _ = write!(
+            out,
+            "\n{:<39} {:>8}/{:<8} {:>7} {bar} {state:<18}",
+            o.source(),
+            o.count,
+            o.limit,
+            format_age(o.age)
+        );
+        if let Some(found) = geo.and_then(|geo| geo.lookup(o.addr)) {
+            let _ = write!(out, " {found}");
+        }
+        out.truncate(out.trim_end().len());
+    }
+    out
+}
+
+/// `45s`, `12m`, `3h05m`, `2d04h`
+pub fn format_age(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{:02}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
@@ -0,0 +1,649 @@
+//! A deliberately small HTTP/1.1 server for the REST API. One request per connection, no
+//! keep-alive, no chunked bodies: enough for dashboards, probes and scripts. A streamed
+//! response has no length and ends when the connection closes.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context as _, anyhow};
+use log::{debug, info, warn};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use xdp_api_guard_common::api::{CommandReply, ErrorReply};
+
+use crate::{
+    control::{self, Command, ControlState},
+    export::{self, Cursor},
+    geoip,
+    health::Health,
+    heatmap,
+    journal::JournalStats,
+    malformed::SampleStats,
+    metrics,
+    rules::RuleFilter,
+    stats::StatsState,
+    status,
+    sweep::SweepStats,
+    timebase,
+    tokens::{Caller, Role, Tokens},
+    trace::Span,
+    version::Versions,
+};
+
+// The request line and the headers together
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+const MAX_BODY_BYTES: usize = 64 * 1024;
+// For the whole request, so a client trickling it in doesn't hold the connection
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Daemon state reachable from request handlers.
+pub struct ApiState {
+    pub stats: Arc<Mutex<StatsState>>,
+    pub versions: Versions,
+    pub sweep: Arc<SweepStats>,
+    pub samples: Arc<SampleStats>,
+    pub journal: Arc<JournalStats>,
+    pub health: Arc<Health>,
+    pub control: Arc<ControlState>,
+    /// --iface, the label of the per-interface metrics.
+    pub iface: String,
+    /// Bearer tokens from --http-token and --http-tokens.
+    pub tokens: Arc<Tokens>,
+}
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+    /// Written after `body`, for what is too large to hold in memory.
+    pub stream: Option<Stream>,
+}
+
+pub enum Stream {
+    /// The blocklist as NDJSON, from the cursor on.
+    Blocklist(Option<Cursor>),
+}
+
+impl Response {
+    pub fn json(status: u16, value: &impl Serialize) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec(value).unwrap_or_default(),
+            stream: None,
+        }
+    }
+
+    pub fn text(status: u16, content_type: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.into_bytes(),
+            stream: None,
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::json(
+            status,
+            &ErrorReply {
+                error: message.to_owned(),
+            },
+        )
+    }
+}
+
+pub async fn serve(listen: SocketAddr, state: Arc<ApiState>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("failed to bind REST API on {listen}"))?;
+    info!("REST API listening on http://{listen}");
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &state).await {
+                debug!("http {peer}: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle(stream: TcpStream, state: &ApiState) -> anyhow::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| anyhow!("timed out"));
+    let response = match request {
+        Ok(Ok(req)) => {
+            // Joins the caller's trace when it sent a traceparent
+            let mut span = Span::server("http request", req.header("traceparent"));
+            span.set("http.request.method", &req.method);
+            span.set("url.path", &req.path);
+            let response = span.scope(route(&req, state)).await;
+            span.set("http.response.status_code", response.status);
+            if response.status >= 500 {
+                span.fail(reason(response.status));
+            }
+            response
+        }
+        Ok(Err(e)) => Response::error(400, &format!("{e:#}")),
+        Err(e) => Response::error(408, &format!("{e:#}")),
+    };
+    let length = match response.stream {
+        Some(_) => String::new(),
+        None => format!("Content-Length: {}\r\n", response.body.len()),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n{length}Connection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    if let Some(Stream::Blocklist(cursor)) = response.stream {
+        export::stream(stream, &state.control, cursor).await?;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn read_request(stream: &mut (impl AsyncBufRead + Unpin)) -> anyhow::Result<Request> {
+    // A byte past the limit, to tell a head that fits from one that doesn't
+    let mut head = (&mut *stream).take(MAX_HEADER_BYTES as u64 + 1);
+    let mut line = String::new();
+    head.read_line(&mut line).await?;
+    if head.limit() == 0 {
+        anyhow::bail!("headers too large");
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("malformed request line");
+    };
+    let method = method.to_owned();
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), parse_query(query)),
+        None => (target.to_owned(), Vec::new()),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        head.read_line(&mut line).await?;
+        if head.limit() == 0 {
+            anyhow::bail!("headers too large");
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+
+    let mut req = Request {
+        method,
+        path,
+        query,
+        headers,
+        body: Vec::new(),
+    };
+    let len: usize = match req.header("content-length") {
+        Some(len) => len.parse().context("invalid Content-Length")?,
+        None => 0,
+    };
+    if len > MAX_BODY_BYTES {
+        anyhow::bail!("body too large");
+    }
+    req.body.resize(len, 0);
+    stream.read_exact(&mut req.body).await?;
+    Ok(req)
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((k, v)) => (k.to_owned(), v.to_owned()),
+            None => (pair.to_owned(), String::new()),
+        })
+        .collect()
+}
+
+async fn route(req: &Request, state: &ApiState) -> Response {
+    // Probes don't carry credentials
+    let caller = if req.path == "/healthz" {
+        None
+    } else {
+        match authorized(req, state) {
+            Ok(caller) => caller,
+            Err(()) => return Response::error(401, "missing, wrong or expired bearer token"),
+        }
+    };
+    if let Some(refused) = beyond_tenant(caller.as_ref(), req) {
+        return refused;
+    }
+    match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/") => {
+            let stats = state.stats.lock().unwrap();
+            let report = stats.report(Some(0));
+            let health = state.health.report(&stats);
+            drop(stats);
+            Response::text(
+                200,
+                "text/html; charset=utf-8",
+                status::render(&report, &health, &state.control),
+            )
+        }
+        ("GET", "/v1/stats") => get_stats(req, state),
+        ("GET", "/v1/status") => Response::json(
+            200,
+            &serde_json::json!({
+                "version": state.versions.report(),
+                "multi_buffer": (!state.control.external).then_some(state.control.multi_buffer),
+            }),
+        ),
+        ("GET", "/healthz") => {
+            let report = state.health.report(&state.stats.lock().unwrap());
+            Response::json(if report.healthy { 200 } else { 503 }, &report)
+        }
+        ("GET", "/v1/rules") => get_rules(req, state),
+        ("GET", "/v1/blocklist") => get_blocklist(req, state),
+        ("GET", path) if path.starts_with("/v1/tenants/") => get_tenant(path, state),
+        ("POST", "/v1/command") => post_command(req, state, caller).await,
+        ("GET", "/metrics") => {
+            let report = state.stats.lock().unwrap().report(Some(0));
+            let observe = state.control.config.lock().unwrap().get().observe;
+            let blocklist = state.control.blocklist.lock().unwrap().occupancy();
+            Response::text(
+                200,
+                "text/plain; version=0.0.4",
+                metrics::render(
+                    &report,
+                    &state.versions,
+                    &state.sweep,
+                    &state.samples,
+                    &state.journal,
+                    &state.control.groups.lock().unwrap(),
+                    &blocklist,
+                    &state.iface,
+                    observe,
+                    state.health.crosscheck(),
+                ),
+            )
+        }
+        _ => Response::error(404, "not found"),
+    }
+}
+
+// Who sent the request, `None` while no token is configured. Every token but a tenant's may
+// read, what commands it may send is up to `post_command`. A browser can't set the header on a plain
+// page load, so `/` also takes `?token=`.
+fn authorized(req: &Request, state: &ApiState) -> Result<Option<Caller>, ()> {
+    if !state.tokens.enabled() {
+        return Ok(None);
+    }
+    let given = match req.header("authorization") {
+        Some(value) => value.strip_prefix("Bearer "),
+        None if req.path == "/" => req.query("token"),
+        None => None,
+    };
+    let given = given.ok_or(())?;
+    state
+        .tokens
+        .check(given, timebase::unix_now())
+        .map(Some)
+        .ok_or(())
+}
+
+// `?window=`, the seconds of history to report
+fn window(req: &Request) -> Result<Option<usize>, Response> {
+    match req.query("window").map(str::parse::<usize>) {
+        None => Ok(None),
+        Some(Ok(window)) => Ok(Some(window)),
+        Some(Err(_)) => Err(Response::error(400, "window must be a number of seconds")),
+    }
+}
+
+fn get_stats(req: &Request, state: &ApiState) -> Response {
+    let window = match window(req) {
+        Ok(window) => window,
+        Err(response) => return response,
+    };
+    let mut report = state.stats.lock().unwrap().report(window);
+    if let Some(geo) = &state.control.geo {
+        let cfg = state.control.config.lock().unwrap().get();
+        // Groups before the limiter, as in `group list`
+        let groups = state.control.groups.lock().unwrap();
+        let offenders = heatmap::collect(
+            &state.control.rate_limit.lock().unwrap(),
+            &state.control.rate_limit6.lock().unwrap(),
+            &state.control.nat.lock().unwrap(),
+            &state.control.tags.lock().unwrap(),
+            &groups,
+            &cfg,
+            timebase::boot_ns(),
+        );
+        drop(groups);
+        report.over_limit = Some(geoip::over_limit(geo, &offenders));
+    }
+    Response::json(200, &report)
+}
+
+fn get_rules(req: &Request, state: &ApiState) -> Response {
+    let filter = match (
+        req.query("unused"),
+        req.query("idle").map(str::parse::<u64>),
+    ) {
+        (None, None) => RuleFilter::All,
+        (Some(_), None) => RuleFilter::Unused,
+        (None, Some(Ok(secs))) => RuleFilter::Idle(secs),
+        (None, Some(Err(_))) => return Response::error(400, "idle must be a number of seconds"),
+        (Some(_), Some(_)) => return Response::error(400, "unused and idle don't go together"),
+    };
+    match state.control.rules.lock().unwrap().stats(filter) {
+        Ok(rules) => Response::json(200, &rules),
+        Err(e) => Response::error(503, &format!("{e:#}")),
+    }
+}
+
+fn get_blocklist(req: &Request, state: &ApiState) -> Response {
+    let cursor = match req.query("cursor").map(str::parse::<Cursor>) {
+        None => None,
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(e)) => return Response::error(400, &format!("{e:#}")),
+    };
+    let ndjson = req
+        .header("accept")
+        .is_some_and(|accept| accept.contains("application/x-ndjson"));
+    if ndjson {
+        return Response {
+            status: 200,
+            content_type: "application/x-ndjson",
+            body: Vec::new(),
+            stream: Some(Stream::Blocklist(cursor)),
+        };
+    }
+    let limit = match req.query("limit").map(str::parse::<usize>) {
+        None => export::DEFAULT_LIMIT,
+        Some(Ok(limit)) if export::limit(limit).is_ok() => limit,
+        Some(_) => {
+            let message = format!("limit must be 1 to {}", export::MAX_LIMIT);
+            return Response::error(400, &message);
+        }
+    };
+    Response::json(200, &export::page(&state.control, cursor, limit))
+}
+
+// The address of `/v1/tenants/{vip}/status`
+// Tenant tokens see the status of their own addresses and nothing else
+fn beyond_tenant(caller: Option<&Caller>, req: &Request) -> Option<Response> {
+    let Some(Caller {
+        id,
+        tenants: Some(vips),
+        ..
+    }) = caller
+    else {
+        return None;
+    };
+    let own = tenant_vip(&req.path).is_some_and(|vip| vips.contains(&vip));
+    (req.method != "GET" || !own)
+        .then(|| Response::error(403, &format!("token {id} only reads its tenants' status")))
+}
+
+fn tenant_vip(path: &str) -> Option<Ipv4Addr> {
+    let vip = path.strip_prefix("/v1/tenants/")?.strip_suffix("/status")?;
+    vip.parse().ok()
+}
+
+fn get_tenant(path: &str, state: &ApiState) -> Response {
+    let Some(vip) = tenant_vip(path) else {
+        return Response::error(404, "not found");
+    };
+    match state.control.tenants.lock().unwrap().report(vip) {
+        Ok(Some(status)) => Response::json(200, &status),
+        Ok(None) => Response::error(404, &format!("{vip} is not a --tenant")),
+        Err(e) => Response::error(503, &format!("{e:#}")),
+    }
+}
+
+// One control command as the body, replied to like on the socket. Only with a token: without
+// one, anyone who can reach the port could change the blocklist. Commands that change
+// something are logged with the id of the token that sent them.
+async fn post_command(req: &Request, state: &ApiState, caller: Option<Caller>) -> Response {
+    let Some(caller) = caller else {
+        return Response::error(403, "commands over HTTP need --http-token or --http-tokens");
+    };
+    let Ok(line) = std::str::from_utf8(&req.body) else {
+        return Response::error(400, "command is not UTF-8");
+    };
+    let line = line.trim();
+    if line.is_empty() || line.contains('\n') {
+        return Response::error(400, "expected one command line");
+    }
+    let cmd = match Command::parse(line) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            let response = format!("err {e:#}");
+            return Response::json(400, &CommandReply { response });
+        }
+    };
+    let needed = Role::needed(&cmd);
+    let verb = line.split_whitespace().next().unwrap_or_default();
+    if !caller.role.may(&cmd) {
+        warn!(
+            "http: token {} ({}) refused {verb}, it needs {needed}",
+            caller.id, caller.role
+        );
+        return Response::error(
+            403,
+            &format!(
+                "token {} is {}, {verb} needs {needed}",
+                caller.id, caller.role
+            ),
+        );
+    }
+    if needed > Role::ReadOnly {
+        info!("http: token {} ({}) sent {verb}", caller.id, caller.role);
+    }
+    let key = req.header("idempotency-key");
+    let response = control::execute_keyed(&state.control, key, line).await;
+    let status = if response.starts_with("err") {
+        400
+    } else {
+        200
+    };
+    Response::json(status, &CommandReply { response })
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::{History, StatsDelta};
+
+    fn request(method: &str, path: &str, query: &[(&str, &str)]) -> Request {
+        Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            query: query
+                .iter()
+                .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn get(query: &[(&str, &str)]) -> Request {
+        request("GET", "/v1/stats", query)
+    }
+
+    fn history(seconds: u64) -> History {
+        let mut history = History::new(300);
+        for ts in 0..seconds {
+            history.push(StatsDelta {
+                ts: 1_700_000_000 + ts,
+                dropped: ts,
+                passed: 1,
+                feature_drops: std::array::from_fn(|_| 0),
+            });
+        }
+        history
+    }
+
+    #[test]
+    fn the_window_trims_the_history_arrays() {
+        let history = history(100);
+        let report = |query: &[(&str, &str)]| {
+            let window = window(&get(query)).ok().unwrap();
+            serde_json::to_value(history.window(window)).unwrap()
+        };
+        let all = report(&[]);
+        assert_eq!(all["drop_rate"].as_array().unwrap().len(), 100);
+        let last = report(&[("window", "60")]);
+        let drops = last["drop_rate"].as_array().unwrap();
+        assert_eq!(drops.len(), 60);
+        assert_eq!(
+            (drops[0].as_u64(), drops[59].as_u64()),
+            (Some(40), Some(99))
+        );
+        assert_eq!(last["pass_rate"].as_array().unwrap().len(), 60);
+        // More than is held is all of it, none is nothing
+        assert_eq!(report(&[("window", "1000")]), all);
+        assert_eq!(
+            report(&[("window", "0")])["drop_rate"],
+            serde_json::json!([])
+        );
+    }
+
+    #[test]
+    fn a_window_that_isnt_a_number_is_refused() {
+        for bad in ["-1", "1.5", "sixty", "", "99999999999999999999999"] {
+            let Err(response) = window(&get(&[("window", bad)])) else {
+                panic!("?window={bad} was taken");
+            };
+            assert_eq!(response.status, 400, "?window={bad}");
+        }
+    }
+
+    #[test]
+    fn tenant_tokens_read_their_own_status_and_nothing_else() {
+        let caller = |role, tenants| Caller {
+            id: "t".to_owned(),
+            role,
+            tenants,
+        };
+        let acme = caller(Role::ReadOnly, Some(vec![Ipv4Addr::new(203, 0, 113, 10)]));
+        let own = "/v1/tenants/203.0.113.10/status";
+        let endpoints = [
+            ("GET", own),
+            ("GET", "/v1/tenants/203.0.113.11/status"),
+            ("GET", "/v1/tenants/203.0.113.10"),
+            ("GET", "/"),
+            ("GET", "/v1/stats"),
+            ("GET", "/v1/status"),
+            ("GET", "/v1/rules"),
+            ("GET", "/v1/blocklist"),
+            ("GET", "/metrics"),
+            ("POST", "/v1/command"),
+            ("POST", own),
+        ];
+        for (method, path) in endpoints {
+            let req = request(method, path, &[]);
+            let refused = beyond_tenant(Some(&acme), &req).map(|response| response.status);
+            let expected = (path != own || method != "GET").then_some(403);
+            assert_eq!(refused, expected, "{method} {path}");
+            // Every other token, and none while no token is configured, gets past
+            for role in [Role::ReadOnly, Role::Operator, Role::Admin] {
+                assert!(beyond_tenant(Some(&caller(role, None)), &req).is_none());
+            }
+            assert!(beyond_tenant(None, &req).is_none());
+        }
+    }
+
+    // A request whose line and headers come to `len` bytes, and its body
+    fn sized(len: usize) -> Vec<u8> {
+        let end = b"\r\nContent-Length: 2\r\n\r\n";
+        let mut request = b"GET /v1/stats?window=60 HTTP/1.1\r\nX-Pad: ".to_vec();
+        request.resize(len - end.len(), b'a');
+        request.extend_from_slice(end);
+        request.extend_from_slice(b"{}");
+        request
+    }
+
+    #[tokio::test]
+    async fn requests_are_read_up_to_the_header_limit() {
+        let request = sized(MAX_HEADER_BYTES);
+        let req = read_request(&mut request.as_slice()).await.unwrap();
+        assert_eq!(
+            (req.method.as_str(), req.path.as_str()),
+            ("GET", "/v1/stats")
+        );
+        assert_eq!(req.query, [("window".to_owned(), "60".to_owned())]);
+        assert_eq!(
+            req.header("x-pad").map(str::len),
+            Some(MAX_HEADER_BYTES - 64)
+        );
+        assert_eq!(req.body, b"{}");
+        let over = sized(MAX_HEADER_BYTES + 1);
+        let e = read_request(&mut over.as_slice()).await.err().unwrap();
+        assert_eq!(e.to_string(), "headers too large");
+    }
+
+    #[tokio::test]
+    async fn the_request_line_counts_toward_the_limit() {
+        let mut request = b"GET /".to_vec();
+        request.resize(MAX_HEADER_BYTES + 1, b'a');
+        request.extend_from_slice(b" HTTP/1.1\r\n\r\n");
+        let e = read_request(&mut request.as_slice()).await.err().unwrap();
+        assert_eq!(e.to_string(), "headers too large");
+    }
+}
@@ -0,0 +1,231 @@
+//! `--ifstats-check-secs`: the program's own counters checked against the interface's.
+//!
+//! Every so many seconds the packets the program counted (passed, dropped and aborted) are
+//! compared with what `/sys/class/net/IFACE/statistics` says the interface received over the
+//! same window. A gap past `--ifstats-tolerance` is logged and shown on `/healthz` and the
+//! status page; it doesn't make the guard unhealthy, the filter may be fine and the counters
+//! wrong, or the other way around.
+//!
+//! The two never agree exactly, and some gaps are no fault of ours:
+//!
+//! - The reads aren't taken at the same instant. The program's counters are the sampler's,
+//!   up to a second older than the interface's, which is why a window that saw fewer than
+//!   `MIN_PACKETS` isn't judged and the tolerance is a band rather than a line.
+//! - Drivers disagree on whether a frame XDP dropped is a received packet. Some count it in
+//!   `rx_packets`, some in `rx_dropped`, some in neither; the closer of `rx_packets` with and
+//!   without `rx_dropped` is taken, so only the third kind shows a gap, as large as the drops.
+//! - Frames the NIC rejects (bad FCS, runts) or drops before the ring when it is full never
+//!   reach XDP, and are in `rx_dropped` on some drivers.
+//! - With `--attach-mode chained` the programs before ours in the dispatcher see packets
+//!   first, and what they drop or redirect is never counted by ours.
+//! - With `--external-maps` the counters may be shared with the same program on other
+//!   interfaces.
+//!
+//! Anything else, a second XDP program taking traffic, frames the program mishandles or a
+//! counter that is wrong, is what the check is for.
+//!
+//! A counter lower than in the last read is a reset (the interface or its driver restarted,
+//! or `guardctl flush stats`), and the window is skipped. Drivers that keep 32-bit counters
+//! wrap them at 2^32 instead, which is told apart from a reset by how close to the top the
+//! counter was.
+
+use std::{fs, path::PathBuf};
+
+use anyhow::Context as _;
+use log::{debug, info, warn};
+use serde::Serialize;
+
+use crate::stats::Dataplane;
+
+/// Packets a window has to see, on either side, to be judged.
+pub const MIN_PACKETS: u64 = 1000;
+
+/// The interface's receive counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IfCounters {
+    pub rx_packets: u64,
+    pub rx_dropped: u64,
+}
+
+impl IfCounters {
+    /// From `/sys/class/net/IFACE/statistics`.
+    pub fn read(iface: &str) -> anyhow::Result<Self> {
+        let dir = PathBuf::from("/sys/class/net")
+            .join(iface)
+            .join("statistics");
+        let counter = |name: &str| -> anyhow::Result<u64> {
+            let path = dir.join(name);
+            let text = fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            text.trim()
+                .parse()
+                .with_context(|| format!("failed to parse {}", path.display()))
+        };
+        Ok(Self {
+            rx_packets: counter("rx_packets")?,
+            rx_dropped: counter("rx_dropped")?,
+        })
+    }
+}
+
+/// What the last judged window found.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Crosscheck {
+    /// Packets the program counted: passed, dropped and aborted.
+    pub seen: u64,
+    /// Packets the interface received, its drops added if that came closer.
+    pub received: u64,
+    /// How far apart the two are, in percent of the larger.
+    pub discrepancy: f64,
+    pub over_tolerance: bool,
+    /// Seconds the window spanned.
+    pub secs: u64,
+    /// Windows skipped since startup because a counter was reset.
+    pub resets: u64,
+}
+
+pub struct Checker {
+    iface: String,
+    every: u64,
+    tolerance: f64,
+    // Seconds since the window started
+    ticks: u64,
+    // Both sides' counters when the window started
+    start: Option<(IfCounters, u64)>,
+    resets: u64,
+    last: Option<Crosscheck>,
+    // Whether the last read of the interface failed, so it is logged once
+    failing: bool,
+}
+
+impl Checker {
+    /// Judges a window every `every` seconds, `tolerance` being the discrepancy in percent
+    /// past which it warns.
+    pub fn new(iface: &str, every: u64, tolerance: f64) -> Self {
+        Self {
+            iface: iface.to_owned(),
+            every,
+            tolerance,
+            ticks: 0,
+            start: None,
+            resets: 0,
+            last: None,
+            failing: false,
+        }
+    }
+
+    /// Called every second after the sampler with the program's total of packets, `None`
+    /// while the counters can't be read. Returns a judged window when one ends.
+    pub fn tick(&mut self, seen: Option<u64>, dataplane: Dataplane) -> Option<Crosscheck> {
+        // A link that goes down takes the interface's counters with it on some drivers
+        let Some(seen) = seen.filter(|_| dataplane == Dataplane::Up) else {
+            self.start = None;
+            return None;
+        };
+        self.ticks += 1;
+        if self.start.is_some() && self.ticks < self.every {
+            return None;
+        }
+        let counters = match IfCounters::read(&self.iface) {
+            Ok(counters) => counters,
+            Err(e) => {
+                if !self.failing {
+                    warn!("{}: can't check the counters: {e:#}", self.iface);
+                    self.failing = true;
+                }
+                self.start = None;
+                return None;
+            }
+        };
+        self.failing = false;
+        let secs = self.ticks;
+        self.ticks = 0;
+        let start = self.start.replace((counters, seen))?;
+        let check = self.judge(start, (counters, seen), secs);
+        if let Some(check) = check {
+            self.report(check);
+        }
+        check
+    }
+
+    fn judge(
+        &mut self,
+        (before, seen_before): (IfCounters, u64),
+        (after, seen_after): (IfCounters, u64),
+        secs: u64,
+    ) -> Option<Crosscheck> {
+        let deltas = (
+            seen_after.checked_sub(seen_before),
+            delta(before.rx_packets, after.rx_packets),
+            delta(before.rx_dropped, after.rx_dropped),
+        );
+        let (Some(seen), Some(rx_packets), Some(rx_dropped)) = deltas else {
+            self.resets += 1;
+            info!("{}: counters reset, the window is skipped", self.iface);
+            return None;
+        };
+        if seen.max(rx_packets) < MIN_PACKETS {
+            debug!(
+                "{}: {seen} packets counted in {secs}s, too few to judge",
+                self.iface
+            );
+            return None;
+        }
+        let (discrepancy, received) = [rx_packets, rx_packets.saturating_add(rx_dropped)]
+            .into_iter()
+            .map(|received| (discrepancy(seen, received), received))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap();
+        Some(Crosscheck {
+            seen,
+            received,
+            discrepancy,
+            over_tolerance: discrepancy > self.tolerance,
+            secs,
+            resets: self.resets,
+        })
+    }
+
+    // Logs a window that crossed the tolerance either way
+    fn report(&mut self, check: Crosscheck) {
+        let was_over = self.last.is_some_and(|last| last.over_tolerance);
+        self.last = Some(check);
+        if check.over_tolerance && !was_over {
+            warn!(
+                "{}: the program counted {} packets in {}s and the interface received {}, \
+                 {:.1}% apart, more than --ifstats-tolerance {}%",
+                self.iface,
+                check.seen,
+                check.secs,
+                check.received,
+                check.discrepancy,
+                self.tolerance
+            );
+        } else if !check.over_tolerance && was_over {
+            info!(
+                "{}: the counters agree again, {:.1}% apart",
+                self.iface, check.discrepancy
+            );
+        }
+    }
+}
+
+// From one read of an interface counter to the next, `None` for a reset
+fn delta(before: u64, after: u64) -> Option<u64> {
+    const WRAP: u64 = 1 << 32;
+    if after >= before {
+        return Some(after - before);
+    }
+    // A 32-bit counter in the top half that comes back in the bottom half wrapped. A reset
+    // from that high is taken for a wrap, and shows as one window's discrepancy.
+    (before < WRAP && before >= WRAP / 2 && after < WRAP / 2).then(|| WRAP - before + after)
+}
+
+// In percent of the larger
+fn discrepancy(seen: u64, received: u64) -> f64 {
+    let larger = seen.max(received);
+    if larger == 0 {
+        return 0.0;
+    }
+    seen.abs_diff(received) as f64 / larger as f64 * 100.0
+}
@@ -0,0 +1,328 @@
+//! `--state-file`: the blocklist survives restarts and crashes.
+//!
+//! The state file is a snapshot (see `snapshot`). Every change made through the
+//! `BlocklistHandle` after startup is also appended to a journal next to it, one checksummed
+//! line per change, written before the command returns and fsynced in batches. At startup the
+//! snapshot is loaded, the journal replayed on top of it, and both compacted into a fresh
+//! snapshot with an empty journal; the same happens whenever the journal grows too large.
+//!
+//! With `--recidivist-after` the ban history of a source is journaled too, as a whole after
+//! every change to it.
+//!
+//! A crash can only cut the last line short, and a short or damaged line fails its checksum
+//! and is skipped. A crash between writing the new snapshot and truncating the journal is
+//! harmless too: replaying the old journal over the new snapshot ends in the same state.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write as _},
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use log::{info, warn};
+use sha2::{Digest as _, Sha256};
+use xdp_api_guard_common::{Config, Origin};
+
+use crate::{
+    blocklist::{Applied, BlocklistHandle, Entry},
+    control::ControlState,
+    mask::Masked,
+    recidivist::History,
+    snapshot, timebase,
+};
+
+/// How often appended records are fsynced. A power loss can take this much with it, a crash
+/// of the daemon nothing.
+pub const SYNC_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Journal size that triggers a compaction.
+pub const COMPACT_BYTES: u64 = 1 << 20;
+
+/// Self-metrics of the journal, exported on `/metrics`. All zero without `--state-file`.
+#[derive(Debug, Default)]
+pub struct JournalStats {
+    pub bytes: AtomicU64,
+    /// Unix time of the last compaction, 0 before the first.
+    pub last_compaction: AtomicU64,
+    /// Damaged records skipped by the replay at startup.
+    pub skipped: AtomicU64,
+}
+
+/// Whether entries of `origin` are kept. Feed entries are not, feeds are read again at every
+/// start and an entry dropped from a feed should go away with it.
+pub fn persisted(origin: Origin) -> bool {
+    origin != Origin::Feed
+}
+
+/// The journal belonging to a state file.
+pub fn path(state_file: &Path) -> PathBuf {
+    let mut path = state_file.as_os_str().to_owned();
+    path.push(".journal");
+    PathBuf::from(path)
+}
+
+/// Append side of the journal, owned by the `BlocklistHandle`.
+pub struct Journal {
+    file: File,
+    stats: Arc<JournalStats>,
+}
+
+impl Journal {
+    fn open(path: &Path, stats: Arc<JournalStats>) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open journal {}", path.display()))?;
+        stats.bytes.store(file.metadata()?.len(), Ordering::Relaxed);
+        Ok(Self { file, stats })
+    }
+
+    pub fn added(&mut self, ip: Ipv4Addr, entry: &Entry) {
+        let mut line = format!("+ {} {ip} {} ", timebase::unix_now(), entry.origin.name());
+        match entry.expires {
+            Some(at) => {
+                let _ = write!(line, "{at} ");
+            }
+            None => line.push_str("- "),
+        }
+        // Node names are single words, see `node_field`
+        line.push_str(entry.node.as_deref().map_or("-", node_field));
+        self.append(line);
+    }
+
+    pub fn removed(&mut self, ip: Ipv4Addr, origin: Origin) {
+        self.append(format!("- {} {ip} {}", timebase::unix_now(), origin.name()));
+    }
+
+    /// The whole history of `ip`, or `None` once it is forgotten. Compact JSON has no spaces
+    /// outside strings, and the origin names in it have none either.
+    pub fn history(&mut self, ip: Ipv4Addr, history: Option<&History>) {
+        let json = match history.map(serde_json::to_string).transpose() {
+            Ok(json) => json.unwrap_or_else(|| "-".to_owned()),
+            Err(e) => {
+                warn!("journal: can't encode the history of {}: {e}", Masked(ip));
+                return;
+            }
+        };
+        self.append(format!("h {} {ip} {json}", timebase::unix_now()));
+    }
+
+    // A failed append loses durability, not the change itself
+    fn append(&mut self, mut line: String) {
+        let sum = checksum(&line);
+        let _ = writeln!(line, " {sum}");
+        match self.file.write_all(line.as_bytes()) {
+            Ok(()) => {
+                self.stats
+                    .bytes
+                    .fetch_add(line.len() as u64, Ordering::Relaxed);
+            }
+            Err(e) => warn!("journal: append failed: {e}"),
+        }
+    }
+
+    fn truncate(&mut self) -> anyhow::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.stats.bytes.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn node_field(node: &str) -> &str {
+    if node.is_empty() || node.contains(char::is_whitespace) {
+        "?"
+    } else {
+        node
+    }
+}
+
+fn checksum(line: &str) -> String {
+    let digest = Sha256::digest(line.as_bytes());
+    digest[..8].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+enum Record {
+    Added(Ipv4Addr, Entry),
+    Removed(Ipv4Addr, Origin),
+    History(Ipv4Addr, Option<History>),
+}
+
+// One line without its newline. `None` for a damaged or cut-off record.
+fn parse(line: &str) -> Option<Record> {
+    let (body, sum) = line.rsplit_once(' ')?;
+    if checksum(body) != sum {
+        return None;
+    }
+    let words: Vec<&str> = body.split(' ').collect();
+    let ip = words.get(2)?.parse().ok()?;
+    if words[0] == "h" {
+        return match words[3..] {
+            ["-"] => Some(Record::History(ip, None)),
+            [json] => Some(Record::History(ip, Some(serde_json::from_str(json).ok()?))),
+            _ => None,
+        };
+    }
+    let origin = Origin::from_name(words.get(3)?)?;
+    match (words[0], &words[4..]) {
+        ("+", [expires, node]) => Some(Record::Added(
+            ip,
+            Entry {
+                expires: match *expires {
+                    "-" => None,
+                    at => Some(at.parse().ok()?),
+                },
+                node: (*node != "-").then(|| node.to_string()),
+                ..Entry::new(origin)
+            },
+        )),
+        ("-", []) => Some(Record::Removed(ip, origin)),
+        _ => None,
+    }
+}
+
+/// Records of the journal at `path`, oldest first, and how many were damaged. A missing
+/// journal is an empty one.
+fn read(path: &Path) -> anyhow::Result<(Vec<Record>, u64)> {
+    // Lossy: a cut-off record may end in the middle of a character
+    let data = match fs::read(path) {
+        Ok(data) => String::from_utf8_lossy(&data).into_owned(),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    let complete = data.ends_with('\n');
+    let lines: Vec<&str> = data.lines().collect();
+    for (i, line) in lines.iter().enumerate() {
+        // Without its newline the last record was cut off mid-write
+        let torn = i + 1 == lines.len() && !complete;
+        match parse(line).filter(|_| !torn) {
+            Some(record) => records.push(record),
+            None => {
+                skipped += 1;
+                warn!(
+                    "journal {}: skipped damaged record {}",
+                    path.display(),
+                    i + 1
+                );
+            }
+        }
+    }
+    Ok((records, skipped))
+}
+
+/// Loads the state file and its journal into the blocklist, compacts them and starts
+/// journaling. Runs before the program is attached.
+pub fn open(
+    state: &ControlState,
+    state_file: &Path,
+    stats: Arc<JournalStats>,
+) -> anyhow::Result<()> {
+    let cfg = state.config.lock().unwrap().get();
+    let mut blocklist = state.blocklist.lock().unwrap();
+    let mut restored = 0;
+    if state_file.exists() {
+        let (entries, histories) = snapshot::read_blocklist(state_file)?;
+        for (ip, entry) in entries {
+            if blocklist.insert_entry(ip, entry)? == Applied::Written {
+                restored += 1;
+            }
+        }
+        for (ip, history) in histories {
+            blocklist.restore_history(ip, Some(history));
+        }
+    }
+    let journal_path = path(state_file);
+    let (records, skipped) = read(&journal_path)?;
+    let replayed = records.len();
+    let now = timebase::unix_now();
+    for record in records {
+        match record {
+            Record::Added(ip, entry) => {
+                if entry.expires.is_none_or(|at| at > now) {
+                    blocklist.insert_entry(ip, entry)?;
+                }
+            }
+            // Only if nothing replaced it since
+            Record::Removed(ip, origin) => {
+                if blocklist
+                    .get(ip)
+                    .is_some_and(|entry| entry.origin == origin)
+                {
+                    blocklist.remove(ip)?;
+                }
+            }
+            Record::History(ip, history) => blocklist.restore_history(ip, history),
+        }
+    }
+    stats.skipped.store(skipped, Ordering::Relaxed);
+    info!(
+        "restored {restored} blocklist entries from {} and replayed {replayed} journal records \
+         ({skipped} damaged)",
+        state_file.display()
+    );
+
+    blocklist.set_journal(Journal::open(&journal_path, stats.clone())?);
+    compact(&mut blocklist, state_file, &cfg, &stats)
+}
+
+/// Fsyncs the journal every `SYNC_INTERVAL` and compacts it once it passes `COMPACT_BYTES`,
+/// until the task is dropped.
+pub async fn run(state: Arc<ControlState>, state_file: PathBuf, stats: Arc<JournalStats>) {
+    // Separate handle, so syncing doesn't hold the blocklist lock
+    let file = match File::open(path(&state_file)) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("journal: can't sync, {e}");
+            return;
+        }
+    };
+    let mut synced = stats.bytes.load(Ordering::Relaxed);
+    let mut tick = tokio::time::interval(SYNC_INTERVAL);
+    loop {
+        tick.tick().await;
+        let bytes = stats.bytes.load(Ordering::Relaxed);
+        if bytes != synced {
+            if let Err(e) = file.sync_data() {
+                warn!("journal: fsync failed: {e}");
+            }
+            synced = bytes;
+        }
+        if bytes >= COMPACT_BYTES {
+            let cfg = state.config.lock().unwrap().get();
+            let mut blocklist = state.blocklist.lock().unwrap();
+            if let Err(e) = compact(&mut blocklist, &state_file, &cfg, &stats) {
+                warn!("journal: compaction failed: {e:#}");
+            }
+        }
+    }
+}
+
+// Writes a fresh state file and empties the journal. The caller holds the blocklist lock,
+// so no record can slip in between.
+fn compact(
+    blocklist: &mut BlocklistHandle,
+    state_file: &Path,
+    cfg: &Config,
+    stats: &JournalStats,
+) -> anyhow::Result<()> {
+    let entries = snapshot::save_blocklist(state_file, cfg, blocklist)?;
+    if let Some(journal) = blocklist.journal() {
+        journal.truncate()?;
+    }
+    stats
+        .last_compaction
+        .store(timebase::unix_now(), Ordering::Relaxed);
+    info!("compacted {entries} entries into {}", state_file.display());
+    Ok(())
+}
@@ -0,0 +1,152 @@
+//! Learning mode: watches normal traffic through the limiter's own map and recommends limits.
+//!
+//! Every tick the learner reads each source's count for its current window from
+//! `RATE_LIMIT_MAP` and keeps the per-source (and per-/24) peak. At the end of each epoch the
+//! peaks go into streaming quantile sketches, so memory stays bounded however long it runs.
+
+use std::{
+    collections::HashMap as StdHashMap,
+    fmt::Write as _,
+    time::{Duration, Instant},
+};
+
+use aya::maps::{HashMap, MapData};
+use xdp_api_guard_common::PacketLog;
+
+use crate::tdigest::TDigest;
+
+const EPOCH: Duration = Duration::from_secs(60);
+// Sources tracked per epoch, the rest are ignored until the next one
+const MAX_SOURCES: usize = 100_000;
+
+pub struct Learner {
+    deadline: Option<Instant>,
+    epoch_start: Instant,
+    per_ip: TDigest,
+    per_subnet: TDigest,
+    peaks: StdHashMap<u32, u64>,
+    subnet_peaks: StdHashMap<u32, u64>,
+    // Peaks of the last complete epoch, to count who a suggestion would affect
+    last_peaks: Vec<u64>,
+    last_subnet_peaks: Vec<u64>,
+}
+
+pub struct Suggestion {
+    pub percentile: f64,
+    pub safety: f64,
+    pub sources: u64,
+    pub ip_limit: Option<u64>,
+    pub subnet_limit: Option<u64>,
+    pub ips_affected: usize,
+    pub subnets_affected: usize,
+}
+
+impl Learner {
+    /// Learns until `duration` has passed, or forever when `None`.
+    pub fn new(duration: Option<Duration>) -> Self {
+        let now = Instant::now();
+        Self {
+            deadline: duration.map(|d| now + d),
+            epoch_start: now,
+            per_ip: TDigest::new(100.0),
+            per_subnet: TDigest::new(100.0),
+            peaks: StdHashMap::new(),
+            subnet_peaks: StdHashMap::new(),
+            last_peaks: Vec::new(),
+            last_subnet_peaks: Vec::new(),
+        }
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.deadline
+            .is_none_or(|deadline| Instant::now() < deadline)
+    }
+
+    /// Samples the limiter map. Windows that ended before `now_ns - window_ns` are stale and
+    /// skipped, exactly as the datapath would reset them.
+    pub fn observe(&mut self, map: &HashMap<MapData, u32, PacketLog>, now_ns: u64, window_ns: u64) {
+        if !self.is_learning() {
+            return;
+        }
+        let mut subnets: StdHashMap<u32, u64> = StdHashMap::new();
+        for (ip, log) in map.iter().filter_map(Result::ok) {
+            if log.current_count(now_ns, window_ns) == 0 {
+                continue;
+            }
+            if self.peaks.len() < MAX_SOURCES || self.peaks.contains_key(&ip) {
+                let peak = self.peaks.entry(ip).or_default();
+                *peak = (*peak).max(log.count);
+            }
+            *subnets.entry(ip & 0xffff_ff00).or_default() += log.count;
+        }
+        for (subnet, count) in subnets {
+            if self.subnet_peaks.len() < MAX_SOURCES || self.subnet_peaks.contains_key(&subnet) {
+                let peak = self.subnet_peaks.entry(subnet).or_default();
+                *peak = (*peak).max(count);
+            }
+        }
+        if self.epoch_start.elapsed() >= EPOCH {
+            self.end_epoch();
+        }
+    }
+
+    fn end_epoch(&mut self) {
+        self.last_peaks = self.peaks.drain().map(|(_, peak)| peak).collect();
+        self.last_subnet_peaks = self.subnet_peaks.drain().map(|(_, peak)| peak).collect();
+        for peak in &self.last_peaks {
+            self.per_ip.add(*peak as f64);
+        }
+        for peak in &self.last_subnet_peaks {
+            self.per_subnet.add(*peak as f64);
+        }
+        self.epoch_start = Instant::now();
+    }
+
+    /// Recommends limits at `percentile` (e.g. 99.9) of observed peaks, times `safety`.
+    pub fn suggest(&mut self, percentile: f64, safety: f64) -> Suggestion {
+        // Include the epoch in progress, so a short --learn still has data
+        if !self.peaks.is_empty() {
+            self.end_epoch();
+        }
+        let q = percentile / 100.0;
+        let limit = |digest: &mut TDigest| digest.quantile(q).map(|v| (v * safety).ceil() as u64);
+        let ip_limit = limit(&mut self.per_ip);
+        let subnet_limit = limit(&mut self.per_subnet);
+        let affected = |peaks: &[u64], limit: Option<u64>| {
+            limit.map_or(0, |limit| peaks.iter().filter(|p| **p > limit).count())
+        };
+        Suggestion {
+            percentile,
+            safety,
+            sources: self.per_ip.count(),
+            ip_limit,
+            subnet_limit,
+            ips_affected: affected(&self.last_peaks, ip_limit),
+            subnets_affected: affected(&self.last_subnet_peaks, subnet_limit),
+        }
+    }
+}
+
+impl Suggestion {
+    pub fn render(&self) -> String {
+        let fmt = |limit: Option<u64>| limit.map_or("n/a".to_owned(), |l| l.to_string());
+        let mut out = format!(
+            "ok p{} of {} observed source peaks x {}",
+            self.percentile, self.sources, self.safety
+        );
+        let _ = write!(
+            out,
+            "\nper-ip limit     {:>10} per window ({} recent sources above it)",
+            fmt(self.ip_limit),
+            self.ips_affected
+        );
+        let _ = write!(
+            out,
+            "\nper-/24 limit    {:>10} per window ({} recent subnets above it)",
+            fmt(self.subnet_limit),
+            self.subnets_affected
+        );
+        out.push_str("\nper-port budgets        n/a (the limiter does not track ports)");
+        out
+    }
+}
@@ -0,0 +1,71 @@
+//! The daemon as a library: `xdp-api-guard` is [`main`] and nothing else, and with the `ffi`
+//! feature the same guard runs inside another program through the C ABI of `ffi`.
+
+mod admission;
+mod alert;
+mod batch;
+mod blocklist;
+mod bloom;
+mod bounded;
+mod chain;
+mod cidr;
+#[cfg(feature = "cluster")]
+mod cluster;
+mod config;
+mod control;
+mod conversion;
+mod daemon;
+mod dashboard;
+mod decisions;
+mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod fifo;
+mod flush;
+mod geoip;
+mod groups;
+mod health;
+mod heatmap;
+mod http;
+mod ifstats;
+mod journal;
+mod learn;
+mod link;
+mod logpump;
+mod malformed;
+mod maps;
+mod mask;
+mod metrics;
+mod neigh;
+mod netlink;
+mod normalize;
+#[cfg(feature = "wasm-policy")]
+mod policy;
+mod preset;
+mod profile;
+#[cfg(test)]
+mod progtest;
+mod recidivist;
+mod relax;
+mod replay;
+mod report;
+mod resize;
+mod rules;
+mod settings;
+mod setup;
+mod snapshot;
+mod stats;
+mod statsd;
+mod status;
+mod sweep;
+mod tdigest;
+mod tenants;
+mod timebase;
+mod tokens;
+mod trace;
+mod trusted;
+mod verifier;
+mod verify;
+mod version;
+
+pub use daemon::main;
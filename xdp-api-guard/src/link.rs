@@ -0,0 +1,98 @@
+//! Watches rtnetlink link notifications so a lost XDP attachment is noticed (and repaired) the
+//! moment the interface comes back, instead of on some later poll.
+
+use std::ffi::CString;
+
+use anyhow::{Context as _, anyhow};
+use log::warn;
+use tokio::{io::unix::AsyncFd, sync::mpsc};
+
+use crate::netlink::{self, attrs, u32_at};
+
+// Not all of these are exported by the libc crate
+const IFLA_IFNAME: u16 = 3;
+const IFLA_XDP: u16 = 43;
+const IFLA_XDP_ATTACHED: u16 = 2;
+const IFINFOMSG_LEN: usize = 16;
+
+#[derive(Clone, Debug)]
+pub struct LinkEvent {
+    pub ifindex: u32,
+    pub name: Option<String>,
+    /// Administratively up and carrier present.
+    pub up: bool,
+    /// Whether the kernel reports an XDP program on the link, when it says.
+    pub xdp_attached: Option<bool>,
+    pub removed: bool,
+}
+
+pub fn ifindex(iface: &str) -> anyhow::Result<u32> {
+    let name = CString::new(iface)?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(anyhow!("no such interface {iface}")),
+        index => Ok(index),
+    }
+}
+
+/// Forwards link events for `ifindex` until the receiver goes away.
+pub async fn watch(ifindex: u32, tx: mpsc::Sender<LinkEvent>) -> anyhow::Result<()> {
+    let fd = AsyncFd::new(
+        netlink::open(libc::RTMGRP_LINK as u32, true).context("failed to open rtnetlink socket")?,
+    )?;
+    let mut buf = vec![0u8; 32 * 1024];
+    loop {
+        let len = match netlink::recv(&fd, &mut buf).await {
+            Ok(len) => len,
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                // The kernel dropped notifications; the next one carries full state anyway
+                warn!("rtnetlink receive buffer overflowed, some link events were lost");
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        for event in parse(&buf[..len]) {
+            if event.ifindex == ifindex && tx.send(event).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Decodes the RTM_NEWLINK/RTM_DELLINK messages in one datagram.
+fn parse(buf: &[u8]) -> Vec<LinkEvent> {
+    let mut events = Vec::new();
+    for (kind, msg) in netlink::messages(buf) {
+        if kind != libc::RTM_NEWLINK && kind != libc::RTM_DELLINK {
+            continue;
+        }
+        let (Some(index), Some(flags)) = (u32_at(msg, 4), u32_at(msg, 8)) else {
+            continue;
+        };
+        let running = (libc::IFF_UP | libc::IFF_RUNNING) as u32;
+        let mut event = LinkEvent {
+            ifindex: index,
+            name: None,
+            up: flags & running == running,
+            xdp_attached: None,
+            removed: kind == libc::RTM_DELLINK,
+        };
+        for (attr, payload) in attrs(msg.get(IFINFOMSG_LEN..).unwrap_or(&[])) {
+            match attr {
+                IFLA_IFNAME => {
+                    let name = payload.split(|b| *b == 0).next().unwrap_or(&[]);
+                    event.name = Some(String::from_utf8_lossy(name).into_owned());
+                }
+                IFLA_XDP => {
+                    for (attr, payload) in attrs(payload) {
+                        if attr == IFLA_XDP_ATTACHED {
+                            event.xdp_attached = payload.first().map(|mode| *mode != 0);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        events.push(event);
+    }
+    events
+}
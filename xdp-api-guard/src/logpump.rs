@@ -0,0 +1,225 @@
+//! Keeps what the eBPF program logs from flooding the terminal during a drop storm.
+//!
+//! Records from the kernel pass through here on their way to the normal logger. Within each
+//! `WINDOW`, the first `PER_MESSAGE` copies of a message are logged as they come and the rest
+//! are counted; when the window ends, each message that had copies held back is logged once
+//! more with a `×N` suffix. A flood of distinct messages (say one per source address) is
+//! capped by `PER_WINDOW` lines overall and by tracking at most `MAX_MESSAGES` at a time.
+//!
+//! There is one pump per loaded program. Once the program is attached, every line it
+//! forwards starts with `[IFACE/PROGRAM]`, and each pump counts its own lines and can be
+//! given a level of its own (`--ebpf-log-level`, `guardctl log-level`).
+//!
+//! The kernel side writes into a ring buffer and gives up on records that don't fit; those
+//! never reach userspace and are not counted here. aya-log records carry no kernel timestamp,
+//! so lines are timed when they are read.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::mask;
+
+pub const WINDOW: Duration = Duration::from_secs(1);
+/// Copies of one message logged per window before the rest are coalesced.
+pub const PER_MESSAGE: u64 = 5;
+/// Lines logged per window over all messages, summaries not included.
+pub const PER_WINDOW: u64 = 100;
+/// Distinct messages tracked per window. Others beyond the caps are only counted.
+pub const MAX_MESSAGES: usize = 1024;
+
+/// Kernel log records held back so far, for `/metrics`.
+pub static SUPPRESSED: AtomicU64 = AtomicU64::new(0);
+
+// Every pump made, for `/metrics` and `guardctl log-level`
+static PUMPS: Mutex<Vec<Arc<LogPump>>> = Mutex::new(Vec::new());
+
+/// Where the records of a pump come from.
+#[derive(Clone, Debug)]
+pub struct LogOrigin {
+    pub iface: String,
+    pub ifindex: u32,
+    pub program: String,
+}
+
+pub struct LogPump {
+    window: Mutex<Window>,
+    origin: Mutex<Option<LogOrigin>>,
+    /// A `LevelFilter` as usize: records above it are dropped before they are counted.
+    level: AtomicUsize,
+    /// Records forwarded to the logger and held back, summaries not included.
+    pub logged: AtomicU64,
+    pub suppressed: AtomicU64,
+}
+
+#[derive(Default)]
+struct Window {
+    started: Option<Instant>,
+    logged: u64,
+    messages: HashMap<(Level, String, String), u64>,
+    // Held back without being tracked, past MAX_MESSAGES or PER_WINDOW
+    untracked: u64,
+}
+
+/// A new pump for one program's records, listed by `pumps` from now on.
+pub fn register(level: LevelFilter) -> Arc<LogPump> {
+    let pump = Arc::new(LogPump {
+        window: Mutex::default(),
+        origin: Mutex::new(None),
+        level: AtomicUsize::new(level as usize),
+        logged: AtomicU64::new(0),
+        suppressed: AtomicU64::new(0),
+    });
+    PUMPS.lock().unwrap().push(pump.clone());
+    pump
+}
+
+pub fn pumps() -> Vec<Arc<LogPump>> {
+    PUMPS.lock().unwrap().clone()
+}
+
+impl LogPump {
+    pub fn set_origin(&self, origin: LogOrigin) {
+        *self.origin.lock().unwrap() = Some(origin);
+    }
+
+    pub fn origin(&self) -> Option<LogOrigin> {
+        self.origin.lock().unwrap().clone()
+    }
+
+    pub fn level(&self) -> LevelFilter {
+        match self.level.load(Ordering::Relaxed) {
+            0 => LevelFilter::Off,
+            1 => LevelFilter::Error,
+            2 => LevelFilter::Warn,
+            3 => LevelFilter::Info,
+            4 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    pub fn set_level(&self, level: LevelFilter) {
+        self.level.store(level as usize, Ordering::Relaxed);
+    }
+
+    /// `IFACE/PROGRAM`, `unattached` before the program is.
+    pub fn label(&self) -> String {
+        match &*self.origin.lock().unwrap() {
+            Some(origin) => format!("{}/{}", origin.iface, origin.program),
+            None => "unattached".to_owned(),
+        }
+    }
+
+    /// Ends the window if it is over and logs what it held back. Also called periodically,
+    /// so the summary of a storm that stopped isn't left waiting for the next record.
+    pub fn tick(&self) {
+        let mut window = self.window.lock().unwrap();
+        if window.started.is_some_and(|at| at.elapsed() >= WINDOW) {
+            window.flush();
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        let mut window = self.window.lock().unwrap();
+        match window.started {
+            Some(at) if at.elapsed() >= WINDOW => window.flush(),
+            Some(_) => {}
+            None => window.started = Some(Instant::now()),
+        }
+        // Masked before anything else, so repeats of one client still coalesce
+        let message = mask::words(&record.args().to_string());
+        let message = match &*self.origin.lock().unwrap() {
+            Some(origin) => format!("[{}/{}] {message}", origin.iface, origin.program),
+            None => message,
+        };
+        let key = (record.level(), record.target().to_owned(), message);
+        let message = key.2.clone();
+        let tracked = window.messages.len() < MAX_MESSAGES || window.messages.contains_key(&key);
+        let seen = if tracked {
+            let seen = window.messages.entry(key).or_insert(0);
+            *seen += 1;
+            *seen
+        } else {
+            u64::MAX
+        };
+        if seen <= PER_MESSAGE && window.logged < PER_WINDOW {
+            window.logged += 1;
+            self.logged.fetch_add(1, Ordering::Relaxed);
+            log::logger().log(
+                &Record::builder()
+                    .metadata(record.metadata().clone())
+                    .args(format_args!("{message}"))
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            );
+            return;
+        }
+        SUPPRESSED.fetch_add(1, Ordering::Relaxed);
+        self.suppressed.fetch_add(1, Ordering::Relaxed);
+        if !tracked || seen <= PER_MESSAGE {
+            // Held back by the overall cap rather than as a repeat
+            window.untracked += 1;
+        }
+    }
+}
+
+impl Window {
+    fn flush(&mut self) {
+        for ((level, target, message), seen) in self.messages.drain() {
+            if seen > PER_MESSAGE {
+                let repeats = seen - PER_MESSAGE;
+                log::logger().log(
+                    &Record::builder()
+                        .level(level)
+                        .target(&target)
+                        .args(format_args!("{message} ×{repeats}"))
+                        .build(),
+                );
+            }
+        }
+        if self.untracked != 0 {
+            log::logger().log(
+                &Record::builder()
+                    .level(Level::Warn)
+                    .target(module_path!())
+                    .args(format_args!(
+                        "{} more eBPF log lines suppressed in the last {WINDOW:?}",
+                        self.untracked
+                    ))
+                    .build(),
+            );
+        }
+        *self = Window {
+            started: Some(Instant::now()),
+            ..Window::default()
+        };
+    }
+}
+
+/// What `EbpfLogger` gets; the pump itself stays shared for `tick`.
+pub struct PumpLogger(pub Arc<LogPump>);
+
+impl Log for PumpLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.0.level() && log::logger().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        log::logger().flush();
+    }
+}
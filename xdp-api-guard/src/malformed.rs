@@ -0,0 +1,165 @@
+//! `--malformed-pcap`: the start of malformed packets of the `--malformed-sample` kinds,
+//! written to a classic pcap file as the program copies them to `MALFORMED_SAMPLES`.
+//!
+//! Each packet keeps its first `MALFORMED_HEAD` bytes, with its full length in the record
+//! header. A storm of malformed packets would fill the disk, so at most `MAX_PER_SECOND`
+//! samples a second are written and the rest only counted in the log.
+//!
+//! The program samples less itself when the ring fills faster than this drains it: each CPU
+//! halves how often it samples a kind after a second that lost too many of its samples to a
+//! full ring (`--sample-loss-percent`), and doubles it again after a second that lost none,
+//! see `SampleRate`. `SampleStats` has what `/metrics` shows of it.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as _;
+use aya::maps::{MapData, PerCpuArray, RingBuf};
+use log::{info, warn};
+use tokio::io::unix::AsyncFd;
+use xdp_api_guard_common::{
+    MALFORMED_HEAD, MALFORMED_KINDS, MalformedSample, SampleRate, malformed, malformed_action,
+};
+
+use crate::timebase;
+
+/// Samples written per second of the wall clock, the rest are skipped.
+pub const MAX_PER_SECOND: u32 = 100;
+
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Kept up to date by `run`.
+#[derive(Debug, Default)]
+pub struct SampleStats {
+    /// Samples taken off the ring, written or not.
+    pub read: AtomicU64,
+    /// Per kind, the largest `SampleRate::divisor` of any CPU, refreshed every second.
+    pub divisors: [AtomicU32; MALFORMED_KINDS],
+}
+
+impl SampleStats {
+    fn refresh(&self, rates: &PerCpuArray<MapData, SampleRate>) -> anyhow::Result<()> {
+        for (kind, divisor) in self.divisors.iter().enumerate() {
+            let copies = rates.get(&(kind as u32), 0)?;
+            let largest = copies.iter().map(|rate| rate.divisor).max().unwrap_or(0);
+            divisor.store(largest.max(1), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// Writes samples from `ring` to `path`, replacing it, until an error.
+pub async fn run(
+    ring: RingBuf<MapData>,
+    rates: PerCpuArray<MapData, SampleRate>,
+    path: &Path,
+    stats: Arc<SampleStats>,
+) -> anyhow::Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    // Magic, version 2.4, no zone or accuracy, snap length, link type
+    out.write_all(&0xa1b2_c3d4u32.to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&4u16.to_le_bytes())?;
+    out.write_all(&[0; 8])?;
+    out.write_all(&(MALFORMED_HEAD as u32).to_le_bytes())?;
+    out.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+    out.flush()?;
+    info!("writing malformed packet samples to {}", path.display());
+
+    let mut fd = AsyncFd::new(ring)?;
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut second = 0;
+    let mut written = 0;
+    let mut skipped = 0u64;
+    loop {
+        let mut guard = tokio::select! {
+            guard = fd.readable_mut() => guard?,
+            _ = tick.tick() => {
+                stats.refresh(&rates)?;
+                continue;
+            }
+        };
+        let ring = guard.get_inner_mut();
+        while let Some(item) = ring.next() {
+            stats.read.fetch_add(1, Ordering::Relaxed);
+            if item.len() < size_of::<MalformedSample>() {
+                continue;
+            }
+            let sample: MalformedSample =
+                unsafe { item.as_ptr().cast::<MalformedSample>().read_unaligned() };
+            let at = timebase::stamp_to_wallclock(sample.at);
+            let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            if secs != second {
+                if skipped != 0 {
+                    warn!("skipped {skipped} malformed packet samples over {MAX_PER_SECOND}/s");
+                }
+                second = secs;
+                written = 0;
+                skipped = 0;
+            }
+            if written == MAX_PER_SECOND {
+                skipped += 1;
+                continue;
+            }
+            written += 1;
+            write_record(&mut out, &sample, at)?;
+        }
+        guard.clear_ready();
+        out.flush()?;
+    }
+}
+
+fn write_record(
+    out: &mut impl Write,
+    sample: &MalformedSample,
+    at: SystemTime,
+) -> anyhow::Result<()> {
+    let at = at.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    let captured = usize::from(sample.captured).min(MALFORMED_HEAD);
+    out.write_all(&(at.as_secs() as u32).to_le_bytes())?;
+    out.write_all(&at.subsec_micros().to_le_bytes())?;
+    out.write_all(&(captured as u32).to_le_bytes())?;
+    out.write_all(&u32::from(sample.len).max(captured as u32).to_le_bytes())?;
+    out.write_all(&sample.head[..captured])?;
+    Ok(())
+}
+
+/// `kind=action` pairs for `--malformed-action`, the kinds named in `malformed::NAMES`.
+pub fn parse_action(s: &str) -> Result<(u32, u8), String> {
+    let (kind, action) = s.split_once('=').ok_or("expected KIND=ACTION")?;
+    let kind = parse_kind(kind)?;
+    let action = malformed_action::NAMES
+        .iter()
+        .position(|name| *name == action)
+        .ok_or_else(|| format!("unknown action {action:?}, expected abort, drop or pass"))?;
+    Ok((kind, action as u8))
+}
+
+pub fn parse_kind(s: &str) -> Result<u32, String> {
+    malformed::from_name(s).ok_or_else(|| {
+        format!(
+            "unknown kind {s:?}, expected one of {}",
+            malformed::NAMES.join(", ")
+        )
+    })
+}
+
+/// `--ringbuf-size` in bytes: the kernel takes a power of two no smaller than a page.
+pub fn parse_ring_size(s: &str) -> Result<u32, String> {
+    let bytes: u32 = s.parse().map_err(|e| format!("{e}"))?;
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(4096) as u32;
+    if !bytes.is_power_of_two() || bytes < page {
+        return Err(format!("expected a power of two of at least {page}"));
+    }
+    Ok(bytes)
+}
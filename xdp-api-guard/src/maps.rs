@@ -0,0 +1,257 @@
+//! Every map of the eBPF object, taken out of it in one go right after loading.
+//!
+//! Taking a map hands its handle over for good, so from then on nothing borrows the `Ebpf`
+//! object and the program can be loaded and attached in any order. The handles are plain
+//! fields; main moves each into the subsystem that owns it (`ControlState`, `StatsState`,
+//! `ConfigHandle`, ...), which is what gets shared between tasks.
+//!
+//! With `--external-maps` the same maps come from pins left by another loader instead, and
+//! `MAP_META` says whether they are in the layout this binary writes.
+//!
+//! The maps of the program's optional subsystems (`conntrack`, `port-rules`, `http`, `dscp`
+//! and `bloom`) are `None` when the object was built without them.
+
+use std::path::Path;
+
+use anyhow::Context as _;
+use aya::{
+    Ebpf,
+    maps::{
+        Array, HashMap, Map, MapData, MapError, PerCpuArray, PerCpuHashMap, ProgramArray, RingBuf,
+        lpm_trie::LpmTrie,
+    },
+};
+use xdp_api_guard_common::{
+    AbortRecord, BlockEntry, Config, Flow, FlowKey, GroupPolicy, Handshakes, MAP_MAGIC, MapMeta,
+    PacketLog, Rule, SCHEMA_VERSION, SampleRate, Tally, VersionInfo,
+};
+
+use crate::control;
+
+pub struct Maps {
+    pub config: Array<MapData, Config>,
+    pub config_active: Array<MapData, u64>,
+    pub version_info: Array<MapData, VersionInfo>,
+    /// `BUILD_ID` of the object, its `.rodata.build_id` section. Another loader may not have
+    /// pinned it.
+    pub build_id: Option<Array<MapData, [u8; 16]>>,
+    /// `MAP_META`, the layout of the maps. Pins of an object older than it don't have it.
+    pub meta: Option<Array<MapData, MapMeta>>,
+    pub blocklist: HashMap<MapData, u32, BlockEntry>,
+    pub mgmt_cidrs: LpmTrie<MapData, u32, u8>,
+    pub stats: PerCpuArray<MapData, u64>,
+    pub drop_buckets: PerCpuArray<MapData, Tally>,
+    pub paths: PerCpuArray<MapData, u64>,
+    pub next_prog: ProgramArray<MapData>,
+    pub tags: HashMap<MapData, u32, u32>,
+    pub rate_limit: HashMap<MapData, u32, PacketLog>,
+    pub rate_limit6: HashMap<MapData, [u8; 16], PacketLog>,
+    pub prefix_sources: HashMap<MapData, u32, u32>,
+    pub prefix: HashMap<MapData, u32, PacketLog>,
+    pub nat_prefixes: LpmTrie<MapData, u32, u8>,
+    pub nat: HashMap<MapData, u64, PacketLog>,
+    pub quic_initial: HashMap<MapData, u32, PacketLog>,
+    pub conntrack: Option<HashMap<MapData, FlowKey, Flow>>,
+    pub syn_sources: Option<HashMap<MapData, u32, Handshakes>>,
+    pub ack: Option<HashMap<MapData, u32, PacketLog>>,
+    pub http: Option<HashMap<MapData, u32, PacketLog>>,
+    pub service_rates: Option<PerCpuHashMap<MapData, u16, Rule>>,
+    pub service: Option<HashMap<MapData, u16, PacketLog>>,
+    pub dscp_policy: Option<PerCpuArray<MapData, Rule>>,
+    pub group_cidrs: LpmTrie<MapData, u32, u8>,
+    pub group_policy: Array<MapData, GroupPolicy>,
+    pub group_stats: PerCpuArray<MapData, u64>,
+    pub protected_dsts: HashMap<MapData, u32, u8>,
+    pub tenant_stats: PerCpuArray<MapData, u64>,
+    pub last_abort: PerCpuArray<MapData, AbortRecord>,
+    pub malformed_samples: RingBuf<MapData>,
+    pub sample_rates: PerCpuArray<MapData, SampleRate>,
+    pub bloom: Option<Array<MapData, u64>>,
+}
+
+impl Maps {
+    /// Takes every map the program declares. `TRUSTED_FLOWS` and `TENANT_SCRATCH` stay
+    /// behind: only the kernel looks at them. So do the slots of `resize`, which has its own
+    /// handles.
+    pub fn take(ebpf: &mut Ebpf) -> anyhow::Result<Self> {
+        Self::open(|name| Ok(ebpf.take_map(name)))
+    }
+
+    /// Opens the maps another loader pinned in `dir`, one file per map named like the map,
+    /// for `--external-maps`. Types and key and value sizes are checked against ours; the
+    /// schema version is up to the caller. A missing pin of an optional map is taken for a
+    /// program built without it.
+    pub fn from_pins(dir: &Path) -> anyhow::Result<Self> {
+        Self::open(|name| {
+            let path = dir.join(name);
+            if !path.exists() {
+                return Ok(None);
+            }
+            let data = MapData::from_pin(&path)
+                .with_context(|| format!("failed to open pinned map {}", path.display()))?;
+            Map::from_map_data(data)
+                .map(Some)
+                .with_context(|| format!("map {name} is of an unknown type"))
+        })
+    }
+
+    fn open(mut get: impl FnMut(&str) -> anyhow::Result<Option<Map>>) -> anyhow::Result<Self> {
+        Ok(Self {
+            config: typed(&mut get, "CONFIG")?,
+            config_active: typed(&mut get, "CONFIG_ACTIVE")?,
+            version_info: typed(&mut get, "VERSION_INFO")?,
+            build_id: optional(&mut get, ".rodata.build_id")?,
+            meta: optional(&mut get, "MAP_META")?,
+            blocklist: typed(&mut get, "BLOCKLIST")?,
+            mgmt_cidrs: typed(&mut get, "MGMT_CIDRS")?,
+            stats: typed(&mut get, "STATS")?,
+            drop_buckets: typed(&mut get, "DROP_BUCKETS")?,
+            paths: typed(&mut get, "PATH_STATS")?,
+            next_prog: typed(&mut get, "NEXT_PROG")?,
+            tags: typed(&mut get, "TAGS")?,
+            rate_limit: typed(&mut get, "RATE_LIMIT_MAP")?,
+            rate_limit6: typed(&mut get, "RATE_LIMIT_MAP6")?,
+            prefix_sources: typed(&mut get, "PREFIX_SOURCES")?,
+            prefix: typed(&mut get, "PREFIX_MAP")?,
+            nat_prefixes: typed(&mut get, "NAT_PREFIXES")?,
+            nat: typed(&mut get, "NAT_MAP")?,
+            quic_initial: typed(&mut get, "QUIC_INITIAL_MAP")?,
+            conntrack: optional(&mut get, "CONNTRACK")?,
+            syn_sources: optional(&mut get, "SYN_SOURCES")?,
+            ack: optional(&mut get, "ACK_MAP")?,
+            http: optional(&mut get, "HTTP_MAP")?,
+            service_rates: optional(&mut get, "SERVICE_RATES")?,
+            service: optional(&mut get, "SERVICE_MAP")?,
+            dscp_policy: optional(&mut get, "DSCP_POLICY")?,
+            group_cidrs: typed(&mut get, "GROUP_CIDRS")?,
+            group_policy: typed(&mut get, "GROUP_POLICY")?,
+            group_stats: typed(&mut get, "GROUP_STATS")?,
+            protected_dsts: typed(&mut get, "PROTECTED_DSTS")?,
+            tenant_stats: typed(&mut get, "TENANT_STATS")?,
+            last_abort: typed(&mut get, "LAST_ABORT")?,
+            malformed_samples: typed(&mut get, "MALFORMED_SAMPLES")?,
+            sample_rates: typed(&mut get, "SAMPLE_RATES")?,
+            bloom: optional(&mut get, "BLOOM")?,
+        })
+    }
+
+    /// The layout `MAP_META` says the maps are in.
+    pub fn layout(&self) -> anyhow::Result<Layout> {
+        let meta = self.meta.as_ref().map(|meta| meta.get(&0, 0)).transpose()?;
+        Ok(layout(meta))
+    }
+
+    /// Stamps `MAP_META` with this binary's layout, `starts` daemons in.
+    pub fn stamp(&mut self, starts: u32) -> anyhow::Result<()> {
+        let meta = self
+            .meta
+            .as_mut()
+            .context("the maps have no MAP_META to stamp")?;
+        meta.set(0, MapMeta::current(starts), 0)?;
+        Ok(())
+    }
+
+    /// Empties every hash map the program and the daemon fill as they go, for `--fresh`,
+    /// returning how many entries there were. Counters, `CONFIG` and the tries the daemon
+    /// writes from its flags are rewritten at startup anyway.
+    pub fn clear_state(&mut self) -> anyhow::Result<usize> {
+        let mut removed = control::clear(&mut self.blocklist)?
+            + control::clear(&mut self.tags)?
+            + control::clear(&mut self.rate_limit)?
+            + control::clear(&mut self.rate_limit6)?
+            + control::clear(&mut self.prefix_sources)?
+            + control::clear(&mut self.prefix)?
+            + control::clear(&mut self.nat)?
+            + control::clear(&mut self.quic_initial)?
+            + control::clear(&mut self.protected_dsts)?;
+        if let Some(conntrack) = &mut self.conntrack {
+            removed += control::clear(conntrack)?;
+        }
+        if let Some(syn_sources) = &mut self.syn_sources {
+            removed += control::clear(syn_sources)?;
+        }
+        for map in [&mut self.ack, &mut self.http].into_iter().flatten() {
+            removed += control::clear(map)?;
+        }
+        if let Some(service) = &mut self.service {
+            removed += control::clear(service)?;
+        }
+        Ok(removed)
+    }
+}
+
+/// What `MAP_META` says about the maps.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// Stamped in this binary's layout, with the daemons started on them so far.
+    Ours { starts: u32 },
+    /// Never stamped, as a loader creates them.
+    New,
+    /// Anything else, and why it isn't ours.
+    Other(String),
+}
+
+fn layout(meta: Option<MapMeta>) -> Layout {
+    match meta {
+        None => Layout::Other("they have no MAP_META, the object is older than it".to_owned()),
+        Some(meta) if meta == MapMeta::default() => Layout::New,
+        Some(meta) if meta.magic != MAP_MAGIC => {
+            Layout::Other(format!("MAP_META has magic {:#018x}, not ours", meta.magic))
+        }
+        Some(meta) if meta.schema != SCHEMA_VERSION => Layout::Other(format!(
+            "they are in schema {}, this binary expects {SCHEMA_VERSION}",
+            meta.schema
+        )),
+        Some(meta) => Layout::Ours {
+            starts: meta.starts,
+        },
+    }
+}
+
+// A map of another type or layout means it was built from other sources
+fn typed<T: TryFrom<Map, Error = MapError>>(
+    get: &mut impl FnMut(&str) -> anyhow::Result<Option<Map>>,
+    name: &str,
+) -> anyhow::Result<T> {
+    optional(get, name)?.with_context(|| format!("map {name} is missing"))
+}
+
+// The same for a map of a subsystem the program may be built without
+fn optional<T: TryFrom<Map, Error = MapError>>(
+    get: &mut impl FnMut(&str) -> anyhow::Result<Option<Map>>,
+    name: &str,
+) -> anyhow::Result<Option<T>> {
+    get(name)?
+        .map(|map| T::try_from(map).with_context(|| format!("map {name} has an unexpected type")))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_maps_stamped_in_this_schema_are_ours() {
+        assert_eq!(
+            layout(Some(MapMeta::current(3))),
+            Layout::Ours { starts: 3 }
+        );
+        assert_eq!(layout(Some(MapMeta::default())), Layout::New);
+        let older = MapMeta {
+            schema: SCHEMA_VERSION - 1,
+            ..MapMeta::current(3)
+        };
+        let foreign = MapMeta {
+            magic: 1,
+            ..MapMeta::current(3)
+        };
+        // Zeros but for the count aren't a new map either
+        let counted = MapMeta {
+            starts: 1,
+            ..MapMeta::default()
+        };
+        for meta in [None, Some(older), Some(foreign), Some(counted)] {
+            assert!(matches!(layout(meta), Layout::Other(_)), "{meta:?}");
+        }
+    }
+}
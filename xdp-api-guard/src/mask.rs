@@ -0,0 +1,117 @@
+//! `--mask-ips`: client addresses as logs and the status page show them, for deployments that
+//! may not keep full addresses.
+//!
+//! Masking happens here and only here. Whatever prints a client address wraps it in [`Masked`]
+//! (or runs free text through [`words`]), so enforcement keeps working on the real addresses:
+//! kernel maps, the control socket, snapshots and the `--state-file` journal hold them unmasked.
+
+use std::{
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::OnceLock,
+};
+
+use anyhow::{Context as _, bail};
+use clap::ValueEnum;
+use sha2::{Digest as _, Sha256};
+
+/// Shortest key `--mask-key-file` may hold.
+pub const MIN_KEY_BYTES: usize = 16;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MaskMode {
+    /// Full addresses
+    #[default]
+    None,
+    /// Zero the last octet of IPv4 addresses and all but the /64 of IPv6 ones
+    LastOctet,
+    /// Replace addresses by a keyed hash, stable for as long as the key is
+    Hash,
+}
+
+struct Mask {
+    mode: MaskMode,
+    key: Vec<u8>,
+}
+
+static MASK: OnceLock<Mask> = OnceLock::new();
+
+/// Sets the mode for the rest of the run. `hash` needs a key file, so tokens stay the same
+/// across restarts and hosts sharing the key.
+pub fn init(mode: MaskMode, key_file: Option<&Path>) -> anyhow::Result<()> {
+    let key = match (mode, key_file) {
+        (MaskMode::Hash, None) => bail!("--mask-ips hash needs --mask-key-file"),
+        (MaskMode::Hash, Some(path)) => {
+            let key =
+                fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+            if key.len() < MIN_KEY_BYTES {
+                bail!(
+                    "{} holds {} bytes, the mask key needs at least {MIN_KEY_BYTES}",
+                    path.display(),
+                    key.len()
+                );
+            }
+            key
+        }
+        _ => Vec::new(),
+    };
+    let _ = MASK.set(Mask { mode, key });
+    Ok(())
+}
+
+/// Shows a client address the way `--mask-ips` allows.
+pub struct Masked<T>(pub T);
+
+impl<T: Copy + Into<IpAddr>> fmt::Display for Masked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addr = self.0.into();
+        let Some(mask) = MASK.get() else {
+            return write!(f, "{addr}");
+        };
+        match (mask.mode, addr) {
+            (MaskMode::None, _) => write!(f, "{addr}"),
+            (MaskMode::LastOctet, IpAddr::V4(ip)) => {
+                write!(f, "{}", Ipv4Addr::from(u32::from(ip) & !0xff))
+            }
+            (MaskMode::LastOctet, IpAddr::V6(ip)) => {
+                write!(
+                    f,
+                    "{}",
+                    Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))
+                )
+            }
+            (MaskMode::Hash, _) => {
+                let octets = match addr {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                };
+                let digest = Sha256::new()
+                    .chain_update(&mask.key)
+                    .chain_update(&octets)
+                    .finalize();
+                f.write_str("ip-")?;
+                digest[..6].iter().try_for_each(|b| write!(f, "{b:02x}"))
+            }
+        }
+    }
+}
+
+/// `text` with every word that is an address masked, for messages that weren't put together
+/// here (commands read from the FIFO, log lines from the kernel).
+pub fn words(text: &str) -> String {
+    if MASK.get().is_none_or(|mask| mask.mode == MaskMode::None) {
+        return text.to_owned();
+    }
+    text.split(' ')
+        .map(|word| {
+            // `1.2.3.4:` and `1.2.3.4,` as well
+            let addr = word.trim_end_matches([':', ',', ';', ')']);
+            match addr.parse::<IpAddr>() {
+                Ok(ip) => format!("{}{}", Masked(ip), &word[addr.len()..]),
+                Err(_) => word.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
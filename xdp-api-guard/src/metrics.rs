@@ -0,0 +1,749 @@
+//! Prometheus text exposition for `/metrics`.
+
+use std::{fmt::Write as _, sync::atomic::Ordering};
+
+use crate::{
+    blocklist::Occupancy,
+    bounded,
+    groups::Groups,
+    ifstats::Crosscheck,
+    journal::JournalStats,
+    logpump,
+    malformed::SampleStats,
+    stats::{Dataplane, OriginDrops, StatsReport},
+    sweep::SweepStats,
+    version::{BuildReport, Versions},
+};
+
+pub fn render(
+    report: &StatsReport,
+    versions: &Versions,
+    sweep: &SweepStats,
+    samples: &SampleStats,
+    journal: &JournalStats,
+    groups: &Groups,
+    blocklist: &[Occupancy],
+    iface: &str,
+    observe: u16,
+    crosscheck: Option<Crosscheck>,
+) -> String {
+    let mut out = String::new();
+    let versions = versions.report();
+
+    out.push_str(
+        "# HELP xdp_api_guard_build_info Build of the daemon and of the loaded eBPF object.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_build_info gauge\n");
+    build_info(&mut out, "binary", &versions.binary);
+    build_info(&mut out, "ebpf", &versions.loaded);
+    out.push_str(
+        "# HELP xdp_api_guard_version_mismatch 1 if the loaded eBPF object is not the one built into the daemon.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_version_mismatch gauge\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_version_mismatch {}",
+        u8::from(versions.mismatch.is_some())
+    );
+
+    out.push_str(
+        "# HELP xdp_api_guard_up 1 while the program is attached to the interface and its link is up.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_up gauge\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_up{{iface=\"{iface}\"}} {}",
+        u8::from(report.dataplane == Dataplane::Up)
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_dataplane_state 1 for the state the interface is in: up, down or detached.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_dataplane_state gauge\n");
+    for state in Dataplane::ALL {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_dataplane_state{{iface=\"{iface}\",state=\"{}\"}} {}",
+            state.name(),
+            u8::from(report.dataplane == state)
+        );
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_rates_live 0 while the rates are paused: the dataplane isn't up or just changed.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_rates_live gauge\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_rates_live {}",
+        u8::from(report.rates_live)
+    );
+    // Left out before the first window judged, and without --ifstats-check-secs
+    if let Some(check) = crosscheck {
+        out.push_str(
+            "# HELP xdp_api_guard_ifstats_discrepancy_percent How far the program's packet count and the interface's were apart in the last window.\n",
+        );
+        out.push_str("# TYPE xdp_api_guard_ifstats_discrepancy_percent gauge\n");
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_ifstats_discrepancy_percent{{iface=\"{iface}\"}} {}",
+            check.discrepancy
+        );
+        out.push_str(
+            "# HELP xdp_api_guard_ifstats_over_tolerance 1 if the last window was further apart than --ifstats-tolerance.\n",
+        );
+        out.push_str("# TYPE xdp_api_guard_ifstats_over_tolerance gauge\n");
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_ifstats_over_tolerance{{iface=\"{iface}\"}} {}",
+            u8::from(check.over_tolerance)
+        );
+        out.push_str(
+            "# HELP xdp_api_guard_ifstats_resets_total Windows skipped because a counter was reset.\n",
+        );
+        out.push_str("# TYPE xdp_api_guard_ifstats_resets_total counter\n");
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_ifstats_resets_total{{iface=\"{iface}\"}} {}",
+            check.resets
+        );
+    }
+
+    out.push_str("# HELP xdp_api_guard_packets_total Packets seen, by verdict.\n");
+    out.push_str("# TYPE xdp_api_guard_packets_total counter\n");
+    let totals = &report.totals;
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_packets_total{{verdict=\"drop\"}} {}",
+        totals.dropped
+    );
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_packets_total{{verdict=\"pass\"}} {}",
+        totals.passed
+    );
+    // Left out without conntrack, zeros would read as no established flows
+    if let Some(flows) = &report.flows {
+        out.push_str(
+            "# HELP xdp_api_guard_flow_passes_total Packets passed at the end of the checks, \
+             by whether conntrack knew their flow.\n",
+        );
+        out.push_str("# TYPE xdp_api_guard_flow_passes_total counter\n");
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_flow_passes_total{{flow=\"established\"}} {}",
+            flows.established
+        );
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_flow_passes_total{{flow=\"new\"}} {}",
+            flows.new
+        );
+        if let Some(share) = flows.new_share {
+            out.push_str(
+                "# HELP xdp_api_guard_new_flow_share_percent Smoothed percentage of those \
+                 passes that were new flows.\n",
+            );
+            out.push_str("# TYPE xdp_api_guard_new_flow_share_percent gauge\n");
+            let _ = writeln!(out, "xdp_api_guard_new_flow_share_percent {share}");
+        }
+        out.push_str(
+            "# HELP xdp_api_guard_syns_total SYNs that opened a tracked flow, and the flows \
+             that went on to be established.\n",
+        );
+        out.push_str("# TYPE xdp_api_guard_syns_total counter\n");
+        for (outcome, count) in [
+            ("tracked", totals.syns_tracked),
+            ("converted", totals.syns_converted),
+        ] {
+            let _ = writeln!(
+                out,
+                "xdp_api_guard_syns_total{{outcome=\"{outcome}\"}} {count}"
+            );
+        }
+        out.push_str(
+            "# HELP xdp_api_guard_retransmits_total TCP segments of tracked flows that carried \
+             nothing new, by whether the limiter charged them.\n",
+        );
+        out.push_str("# TYPE xdp_api_guard_retransmits_total counter\n");
+        for (outcome, count) in [
+            (
+                "charged",
+                totals
+                    .retransmits
+                    .saturating_sub(totals.retransmits_forgiven),
+            ),
+            ("forgiven", totals.retransmits_forgiven),
+        ] {
+            let _ = writeln!(
+                out,
+                "xdp_api_guard_retransmits_total{{outcome=\"{outcome}\"}} {count}"
+            );
+        }
+    }
+    out.push_str("# HELP xdp_api_guard_quic_initials_total QUIC long-header packets seen.\n");
+    out.push_str("# TYPE xdp_api_guard_quic_initials_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_quic_initials_total {}",
+        totals.quic_initials
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_quic_initial_drops_total QUIC long-header packets dropped.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_quic_initial_drops_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_quic_initial_drops_total {}",
+        totals.quic_initial_drops
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_ack_flood_drops_total Bare ACKs of untracked TCP flows dropped.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_ack_flood_drops_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_ack_flood_drops_total {}",
+        totals.ack_flood_drops
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_tiny_mss_syns_total SYNs advertising an MSS below --min-mss.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_tiny_mss_syns_total counter\n");
+    let _ = writeln!(out, "xdp_api_guard_tiny_mss_syns_total {}", totals.tiny_mss);
+    out.push_str("# HELP xdp_api_guard_tiny_mss_drops_total Tiny-MSS SYNs dropped.\n");
+    out.push_str("# TYPE xdp_api_guard_tiny_mss_drops_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_tiny_mss_drops_total {}",
+        totals.tiny_mss_drops
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_zone_drops_total Packets dropped by a zone's drop action.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_zone_drops_total counter\n");
+    let _ = writeln!(out, "xdp_api_guard_zone_drops_total {}", totals.zone_drops);
+    out.push_str(
+        "# HELP xdp_api_guard_icmp_inner_drops_total ICMP errors dropped for what they quote.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_icmp_inner_drops_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_icmp_inner_drops_total {}",
+        totals.icmp_inner_drops
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_paused_drops_total Packets passed that would have been dropped.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_paused_drops_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_paused_drops_total {}",
+        totals.paused_drops
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_wred_drops_total Packets dropped early by WRED, below the limit.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_wred_drops_total counter\n");
+    let _ = writeln!(out, "xdp_api_guard_wred_drops_total {}", totals.wred_drops);
+    out.push_str(
+        "# HELP xdp_api_guard_http_flood_drops_total HTTP request lines over --http-rps-limit.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_http_flood_drops_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_http_flood_drops_total {}",
+        totals.http_flood_drops
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_service_rate_drops_total SYNs over the --service-rate of their port.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_service_rate_drops_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_service_rate_drops_total {}",
+        totals.service_rate_drops
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_client_hellos_total TLS ClientHellos charged to --sni-rate.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_client_hellos_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_client_hellos_total {}",
+        totals.client_hellos
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_sni_unknown_total ClientHellos whose server name couldn't be \
+         read, split or encrypted.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_sni_unknown_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_sni_unknown_total {}",
+        totals.sni_unknown
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_sni_drops_total ClientHellos over the budget of their server \
+         name.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_sni_drops_total counter\n");
+    let _ = writeln!(out, "xdp_api_guard_sni_drops_total {}", totals.sni_drops);
+    out.push_str(
+        "# HELP xdp_api_guard_cast_packets_total Packets to multicast and broadcast addresses \
+         handled by --multicast and --broadcast.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_cast_packets_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_cast_packets_total{{class=\"multicast\"}} {}",
+        totals.multicast
+    );
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_cast_packets_total{{class=\"broadcast\"}} {}",
+        totals.broadcast
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_cast_drops_total Multicast and broadcast packets dropped by \
+         their policy.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_cast_drops_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_cast_drops_total{{class=\"multicast\"}} {}",
+        totals.multicast_drops
+    );
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_cast_drops_total{{class=\"broadcast\"}} {}",
+        totals.broadcast_drops
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_aggregated_packets_total Packets of sources without an entry of \
+         their own, their /16 being at --prefix-quota.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_aggregated_packets_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_aggregated_packets_total {}",
+        totals.aggregated
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_prefix_drops_total Of those, dropped over the --prefix-rate of \
+         their /16.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_prefix_drops_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_prefix_drops_total {}",
+        totals.prefix_drops
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_nat_port_drops_total Packets dropped over the limit of one source \
+         port of an address behind --nat-prefix.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_nat_port_drops_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_nat_port_drops_total {}",
+        totals.nat_port_drops
+    );
+    out.push_str("# HELP xdp_api_guard_dscp_drops_total Packets dropped by --dscp-policy.\n");
+    out.push_str("# TYPE xdp_api_guard_dscp_drops_total counter\n");
+    let _ = writeln!(out, "xdp_api_guard_dscp_drops_total {}", totals.dscp_drops);
+    out.push_str(
+        "# HELP xdp_api_guard_global_drops_total Packets over the --global-rate of all sources.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_global_drops_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_global_drops_total {}",
+        totals.global_drops
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_pressure_drops_total Packets of heavy sources dropped early as \
+         the --global-rate budget ran low.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_pressure_drops_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_pressure_drops_total {}",
+        totals.pressure_drops
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_truncated_packets_total Frames longer than their linear part, \
+         checked on that part only.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_truncated_packets_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_truncated_packets_total {}",
+        totals.truncated
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_aborted_total Packets the program returned XDP_ABORTED for, see \
+         `guardctl last-abort`.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_aborted_total counter\n");
+    let _ = writeln!(out, "xdp_api_guard_aborted_total {}", totals.aborted);
+    out.push_str(
+        "# HELP xdp_api_guard_malformed_packets_total Malformed packets by kind, whatever \
+         --malformed-action did with them.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_malformed_packets_total counter\n");
+    for kind in &report.malformed {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_malformed_packets_total{{kind=\"{}\"}} {}",
+            kind.kind, kind.packets
+        );
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_dscp_packets_total IPv4 packets by DSCP class, with --dscp-policy.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_dscp_packets_total counter\n");
+    for (class, packets) in totals.dscp_classes.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_dscp_packets_total{{class=\"cs{class}\"}} {packets}"
+        );
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_blocklist_drops_total Blocklist drops, by origin (and feed) of \
+         the matching entry.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_blocklist_drops_total counter\n");
+    for origin in &report.drops_by_origin {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_blocklist_drops_total{{{}}} {}",
+            origin_labels(origin),
+            origin.packets
+        );
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_blocklist_drop_bytes_total Bytes of blocklist drops, by origin \
+         (and feed) of the matching entry.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_blocklist_drop_bytes_total counter\n");
+    for origin in &report.drops_by_origin {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_blocklist_drop_bytes_total{{{}}} {}",
+            origin_labels(origin),
+            origin.bytes
+        );
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_blocklist_entries Blocklist entries, by origin (and feed).\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_blocklist_entries gauge\n");
+    for held in blocklist {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_blocklist_entries{{{}}} {}",
+            occupancy_labels(held),
+            held.entries
+        );
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_blocklist_quota --blocklist-quota of an origin (or feed), the most \
+         entries it may hold.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_blocklist_quota gauge\n");
+    for held in blocklist {
+        if let Some(quota) = held.quota {
+            let _ = writeln!(
+                out,
+                "xdp_api_guard_blocklist_quota{{{}}} {quota}",
+                occupancy_labels(held)
+            );
+        }
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_blocklist_quota_rejected_total Entries refused, and feed lines \
+         left out, for the --blocklist-quota of their origin (or feed).\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_blocklist_quota_rejected_total counter\n");
+    for held in blocklist.iter().filter(|held| held.quota.is_some()) {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_blocklist_quota_rejected_total{{{}}} {}",
+            occupancy_labels(held),
+            held.rejected
+        );
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_blocklist_quota_evictions_total Entries evicted to make room for \
+         newer ones of their origin at its --blocklist-quota.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_blocklist_quota_evictions_total counter\n");
+    for held in blocklist.iter().filter(|held| held.quota.is_some()) {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_blocklist_quota_evictions_total{{{}}} {}",
+            occupancy_labels(held),
+            held.evicted
+        );
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_feature_drops_total Drops by feature. mode=\"observed\" counts what \
+         an observing feature would have dropped and let through.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_feature_drops_total counter\n");
+    for feature in &report.drops_by_feature {
+        for (mode, packets) in [
+            ("enforced", feature.enforced),
+            ("observed", feature.observed),
+        ] {
+            let _ = writeln!(
+                out,
+                "xdp_api_guard_feature_drops_total{{feature=\"{}\",mode=\"{mode}\"}} {packets}",
+                feature.feature
+            );
+        }
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_feature_observing 1 while the feature only counts what it would \
+         drop.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_feature_observing gauge\n");
+    for feature in &report.drops_by_feature {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_feature_observing{{feature=\"{}\"}} {}",
+            feature.feature,
+            observe >> feature.index & 1
+        );
+    }
+    out.push_str("# HELP xdp_api_guard_group_packets_total IPv4 packets from --group members.\n");
+    out.push_str("# TYPE xdp_api_guard_group_packets_total counter\n");
+    let counts = groups.counts().unwrap_or_default();
+    for (group, counts) in groups.groups().iter().zip(&counts) {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_group_packets_total{{group=\"{}\"}} {}",
+            group.name, counts.packets
+        );
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_group_drops_total Packets from --group members dropped, for any \
+         reason.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_group_drops_total counter\n");
+    for (group, counts) in groups.groups().iter().zip(&counts) {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_group_drops_total{{group=\"{}\"}} {}",
+            group.name, counts.drops
+        );
+    }
+
+    out.push_str("# HELP xdp_api_guard_tracking_entries Limiter entries at the last sweep.\n");
+    out.push_str("# TYPE xdp_api_guard_tracking_entries gauge\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_tracking_entries {}",
+        sweep.entries.load(Ordering::Relaxed)
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_tracking_evicted_total Idle limiter entries deleted by the sweeper.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_tracking_evicted_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_tracking_evicted_total {}",
+        sweep.evicted_total.load(Ordering::Relaxed)
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_tracking_expired_inline_total Idle limiter entries the program \
+         deleted itself when their source came back.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_tracking_expired_inline_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_tracking_expired_inline_total {}",
+        totals.expired_inline
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_config_torn_total Copies of the settings the program took \
+         again, updates having come too fast during one.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_config_torn_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_config_torn_total {}",
+        totals.config_torn
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_samples_total Malformed packet samples, by what came of them.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_samples_total counter\n");
+    for (outcome, count) in [
+        ("sent", totals.samples),
+        ("lost", totals.samples_lost),
+        ("thinned", totals.samples_thinned),
+    ] {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_samples_total{{outcome=\"{outcome}\"}} {count}"
+        );
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_samples_read_total Malformed packet samples read off the ring.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_samples_read_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_samples_read_total {}",
+        samples.read.load(Ordering::Relaxed)
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_sample_loss_ratio Share of the last second's samples lost to a full ring.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_sample_loss_ratio gauge\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_sample_loss_ratio {}",
+        report.sample_loss
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_sample_divisor One packet in this many of the kind is sampled, the largest of any CPU.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_sample_divisor gauge\n");
+    let kinds = xdp_api_guard_common::malformed::NAMES;
+    for (kind, divisor) in kinds.iter().zip(&samples.divisors) {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_sample_divisor{{kind=\"{kind}\"}} {}",
+            divisor.load(Ordering::Relaxed).max(1)
+        );
+    }
+    out.push_str("# HELP xdp_api_guard_tracking_sweep_seconds Duration of the last sweep.\n");
+    out.push_str("# TYPE xdp_api_guard_tracking_sweep_seconds gauge\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_tracking_sweep_seconds {}",
+        sweep.last_duration_us.load(Ordering::Relaxed) as f64 / 1e6
+    );
+    out.push_str("# HELP xdp_api_guard_journal_bytes Size of the --state-file journal.\n");
+    out.push_str("# TYPE xdp_api_guard_journal_bytes gauge\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_journal_bytes {}",
+        journal.bytes.load(Ordering::Relaxed)
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_journal_last_compaction_timestamp_seconds Unix time the journal \
+         was last compacted into the state file.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_journal_last_compaction_timestamp_seconds gauge\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_journal_last_compaction_timestamp_seconds {}",
+        journal.last_compaction.load(Ordering::Relaxed)
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_journal_skipped_records Damaged journal records skipped at startup.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_journal_skipped_records gauge\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_journal_skipped_records {}",
+        journal.skipped.load(Ordering::Relaxed)
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_kernel_log_suppressed_total eBPF log records coalesced or dropped \
+         by the log pump.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_kernel_log_suppressed_total counter\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_kernel_log_suppressed_total {}",
+        logpump::SUPPRESSED.load(Ordering::Relaxed)
+    );
+    out.push_str(
+        "# HELP xdp_api_guard_kernel_log_records_total eBPF log records of each attached \
+         program, logged or held back by the log pump.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_kernel_log_records_total counter\n");
+    for pump in logpump::pumps() {
+        let Some(origin) = pump.origin() else {
+            continue;
+        };
+        for (outcome, count) in [("logged", &pump.logged), ("suppressed", &pump.suppressed)] {
+            let _ = writeln!(
+                out,
+                "xdp_api_guard_kernel_log_records_total{{iface=\"{}\",program=\"{}\",\
+                 outcome=\"{outcome}\"}} {}",
+                origin.iface,
+                origin.program,
+                count.load(Ordering::Relaxed)
+            );
+        }
+    }
+    let stores = bounded::stores();
+    out.push_str(
+        "# HELP xdp_api_guard_state_entries Entries of each userspace store of per-source state.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_state_entries gauge\n");
+    for store in &stores {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_state_entries{{store=\"{}\"}} {}",
+            store.name,
+            store.entries.load(Ordering::Relaxed)
+        );
+    }
+    out.push_str("# HELP xdp_api_guard_state_bytes Estimated memory of each store.\n");
+    out.push_str("# TYPE xdp_api_guard_state_bytes gauge\n");
+    for store in &stores {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_state_bytes{{store=\"{}\"}} {}",
+            store.name,
+            store.bytes.load(Ordering::Relaxed)
+        );
+    }
+    out.push_str(
+        "# HELP xdp_api_guard_state_evictions_total Entries evicted to stay within the caps.\n",
+    );
+    out.push_str("# TYPE xdp_api_guard_state_evictions_total counter\n");
+    for store in &stores {
+        let _ = writeln!(
+            out,
+            "xdp_api_guard_state_evictions_total{{store=\"{}\"}} {}",
+            store.name,
+            store.evictions.load(Ordering::Relaxed)
+        );
+    }
+    out.push_str("# HELP xdp_api_guard_state_budget_bytes The --state-memory of all stores.\n");
+    out.push_str("# TYPE xdp_api_guard_state_budget_bytes gauge\n");
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_state_budget_bytes {}",
+        bounded::budget()
+    );
+    out
+}
+
+fn origin_labels(origin: &OriginDrops) -> String {
+    match &origin.feed {
+        Some(feed) => format!("origin=\"{}\",feed=\"{feed}\"", origin.origin),
+        None => format!("origin=\"{}\"", origin.origin),
+    }
+}
+
+fn occupancy_labels(held: &Occupancy) -> String {
+    match &held.feed {
+        Some(feed) => format!("origin=\"{}\",feed=\"{feed}\"", held.origin.name()),
+        None => format!("origin=\"{}\"", held.origin.name()),
+    }
+}
+
+fn build_info(out: &mut String, component: &str, build: &BuildReport) {
+    let _ = writeln!(
+        out,
+        "xdp_api_guard_build_info{{component=\"{component}\",git_hash=\"{}\",build_time=\"{}\",schema=\"{}\",build_id=\"{}\",object_sha256=\"{}\"}} 1",
+        build.git_hash, build.build_time, build.schema, build.build_id, build.object_sha256
+    );
+}
@@ -0,0 +1,674 @@
+//! Keeps the default gateway and neighboring routers out of reach of the limiter.
+//!
+//! All north-south traffic arrives through the gateway, and on some topologies tunneled traffic
+//! carries its address as the source, so limiting it throttles everyone at once. The watcher
+//! follows the kernel's IPv4 routes out of the interface and keeps their next hops in the
+//! management trie: the gateway of the default route, and the routers other routes go
+//! through. IPv4 neighbor entries can't say whether a neighbor is a router (`NTF_ROUTER` is
+//! only ever set by IPv6 neighbor discovery), so the routes are what tells. An address whose
+//! last route goes away stays exempt for a grace period, routes come and go with flaps.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::Ipv4Addr,
+    os::fd::OwnedFd,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use log::{debug, info, warn};
+use tokio::io::unix::AsyncFd;
+
+use crate::{
+    cidr::Ipv4Cidr,
+    control::ControlState,
+    mask::Masked,
+    netlink::{self, attrs, u16_at, u32_at},
+};
+
+// Not all of these are exported by the libc crate
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_PRIORITY: u16 = 6;
+const RTA_MULTIPATH: u16 = 9;
+const RTA_TABLE: u16 = 15;
+const RTMSG_LEN: usize = 12;
+// rtnexthop: len, flags, hops, ifindex, then the attributes of the hop
+const RTNH_LEN: usize = 8;
+// Dumps the kernel interrupted, because the table changed under them, are asked for again
+const DUMP_TRIES: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Reason {
+    /// Next hop of a default route out of the interface.
+    Gateway,
+    /// Next hop of another route out of the interface.
+    Router,
+}
+
+impl Reason {
+    fn name(self) -> &'static str {
+        match self {
+            Reason::Gateway => "gateway",
+            Reason::Router => "router",
+        }
+    }
+}
+
+/// What identifies a route to the kernel: a new route of the same key replaces the old one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct RouteKey {
+    table: u32,
+    dst: Ipv4Addr,
+    dst_len: u8,
+    tos: u8,
+    priority: u32,
+}
+
+/// A route added, replaced or deleted, with its next hops out of the interface.
+#[derive(Debug)]
+struct Route {
+    key: RouteKey,
+    hops: Vec<(Ipv4Addr, Reason)>,
+    present: bool,
+}
+
+/// Which addresses are exempt and why. An address stays exempt while any route goes through
+/// it, and for the grace period after the last one went away.
+#[derive(Default)]
+struct Exemptions {
+    routes: HashMap<RouteKey, Vec<(Ipv4Addr, Reason)>>,
+    exempt: HashSet<Ipv4Addr>,
+    leaving: HashMap<Ipv4Addr, Instant>,
+}
+
+impl Exemptions {
+    /// Returns the next hops that just became exempt.
+    fn apply(&mut self, route: Route, now: Instant) -> Vec<(Ipv4Addr, Reason)> {
+        let old = if route.present && !route.hops.is_empty() {
+            self.routes.insert(route.key, route.hops.clone())
+        } else {
+            self.routes.remove(&route.key)
+        };
+        let mut new = Vec::new();
+        if route.present {
+            for &(ip, reason) in &route.hops {
+                self.leaving.remove(&ip);
+                if self.exempt.insert(ip) {
+                    new.push((ip, reason));
+                }
+            }
+        }
+        for (ip, _) in old.unwrap_or_default() {
+            if self.exempt.contains(&ip) && !self.held(ip) {
+                self.leaving.entry(ip).or_insert(now);
+            }
+        }
+        new
+    }
+
+    /// Takes `routes`, a fresh dump, for the whole table: the routes missing from it are gone.
+    fn resync(&mut self, routes: Vec<Route>, now: Instant) -> Vec<(Ipv4Addr, Reason)> {
+        let dumped: HashSet<RouteKey> = routes.iter().map(|route| route.key).collect();
+        let gone: Vec<RouteKey> = self
+            .routes
+            .keys()
+            .filter(|key| !dumped.contains(key))
+            .copied()
+            .collect();
+        let mut new = Vec::new();
+        for key in gone {
+            let deleted = Route {
+                key,
+                hops: Vec::new(),
+                present: false,
+            };
+            new.extend(self.apply(deleted, now));
+        }
+        for route in routes {
+            new.extend(self.apply(route, now));
+        }
+        new
+    }
+
+    fn held(&self, ip: Ipv4Addr) -> bool {
+        self.routes.values().flatten().any(|(held, _)| *held == ip)
+    }
+
+    /// Ends exemptions whose grace period is over, returning the addresses.
+    fn sweep(&mut self, now: Instant, grace: Duration) -> Vec<Ipv4Addr> {
+        let expired: Vec<Ipv4Addr> = self
+            .leaving
+            .iter()
+            .filter(|(_, since)| now.duration_since(**since) >= grace)
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in &expired {
+            self.leaving.remove(ip);
+            self.exempt.remove(ip);
+        }
+        expired
+    }
+}
+
+/// Where the watcher learns about routes: the kernel, or the script of a test.
+trait Routes {
+    /// Every IPv4 route, as netlink messages.
+    async fn dump(&mut self) -> anyhow::Result<Vec<(u16, Vec<u8>)>>;
+
+    /// The next batch of route changes. `ENOBUFS` when some were lost, None when there won't
+    /// be any more.
+    async fn changes(&mut self) -> io::Result<Option<Vec<(u16, Vec<u8>)>>>;
+}
+
+/// The management trie the exemptions go into.
+trait Management {
+    /// Whether a --mgmt-cidr already covers `ip`.
+    fn covers(&self, ip: Ipv4Addr) -> bool;
+    fn add(&self, ip: Ipv4Addr) -> anyhow::Result<()>;
+    fn remove(&self, ip: Ipv4Addr) -> anyhow::Result<()>;
+}
+
+struct Kernel {
+    fd: AsyncFd<OwnedFd>,
+    buf: Vec<u8>,
+}
+
+impl Routes for Kernel {
+    async fn dump(&mut self) -> anyhow::Result<Vec<(u16, Vec<u8>)>> {
+        let mut tries = 1;
+        loop {
+            // The dump blocks on the socket until the kernel is done
+            let dump =
+                tokio::task::spawn_blocking(|| netlink::dump(libc::RTM_GETROUTE, AF_INET)).await?;
+            match dump {
+                Err(e) if e.raw_os_error() == Some(libc::EINTR) && tries < DUMP_TRIES => tries += 1,
+                dump => return dump.context("failed to dump the routing tables"),
+            }
+        }
+    }
+
+    async fn changes(&mut self) -> io::Result<Option<Vec<(u16, Vec<u8>)>>> {
+        let len = netlink::recv(&self.fd, &mut self.buf).await?;
+        let messages = netlink::messages(&self.buf[..len]);
+        Ok(Some(
+            messages.map(|(kind, msg)| (kind, msg.to_vec())).collect(),
+        ))
+    }
+}
+
+const AF_INET: u8 = libc::AF_INET as u8;
+
+impl Management for ControlState {
+    fn covers(&self, ip: Ipv4Addr) -> bool {
+        self.blocklist.lock().unwrap().in_management(ip)
+    }
+
+    fn add(&self, ip: Ipv4Addr) -> anyhow::Result<()> {
+        let cidr = Ipv4Cidr::new(ip, 32)?;
+        self.blocklist.lock().unwrap().add_management(cidr)
+    }
+
+    fn remove(&self, ip: Ipv4Addr) -> anyhow::Result<()> {
+        let cidr = Ipv4Cidr::new(ip, 32)?;
+        self.blocklist.lock().unwrap().remove_management(cidr)?;
+        Ok(())
+    }
+}
+
+/// Follows the gateway and routers of `ifindex` until an error.
+pub async fn watch(
+    iface: &str,
+    ifindex: u32,
+    state: Arc<ControlState>,
+    grace: Duration,
+) -> anyhow::Result<()> {
+    // Subscribe before dumping so no change falls in between
+    let groups = libc::RTMGRP_IPV4_ROUTE as u32;
+    let fd = AsyncFd::new(netlink::open(groups, true).context("failed to open rtnetlink socket")?)?;
+    let kernel = Kernel {
+        fd,
+        buf: vec![0u8; 32 * 1024],
+    };
+    Watcher::new(iface, ifindex, &*state)
+        .run(kernel, grace)
+        .await
+}
+
+struct Watcher<'a, M> {
+    iface: &'a str,
+    ifindex: u32,
+    management: &'a M,
+    exemptions: Exemptions,
+    // Addresses we put in the trie, as opposed to ones a --mgmt-cidr already covers
+    owned: HashSet<Ipv4Addr>,
+}
+
+impl<'a, M: Management> Watcher<'a, M> {
+    fn new(iface: &'a str, ifindex: u32, management: &'a M) -> Self {
+        Self {
+            iface,
+            ifindex,
+            management,
+            exemptions: Exemptions::default(),
+            owned: HashSet::new(),
+        }
+    }
+
+    async fn run(&mut self, mut routes: impl Routes, grace: Duration) -> anyhow::Result<()> {
+        self.resync(&routes.dump().await?)?;
+        if self.exemptions.exempt.is_empty() {
+            info!("{}: no gateway or routers found to exempt", self.iface);
+        }
+        let mut sweep = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            tokio::select! {
+                changes = routes.changes() => {
+                    let changes = match changes {
+                        Ok(Some(changes)) => changes,
+                        Ok(None) => return Ok(()),
+                        Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                            warn!("{}: missed route changes, reading the tables again", self.iface);
+                            self.resync(&routes.dump().await?)?;
+                            continue;
+                        }
+                        Err(e) => return Err(e.into()),
+                    };
+                    let now = Instant::now();
+                    for (kind, msg) in &changes {
+                        if let Some(route) = parse(*kind, msg, self.ifindex) {
+                            let new = self.exemptions.apply(route, now);
+                            self.exempt(new)?;
+                        }
+                    }
+                }
+                _ = sweep.tick() => self.sweep(Instant::now(), grace)?,
+            }
+        }
+    }
+
+    fn resync(&mut self, dump: &[(u16, Vec<u8>)]) -> anyhow::Result<()> {
+        let routes = dump
+            .iter()
+            .filter_map(|(kind, msg)| parse(*kind, msg, self.ifindex))
+            .collect();
+        let new = self.exemptions.resync(routes, Instant::now());
+        self.exempt(new)
+    }
+
+    fn exempt(&mut self, hops: Vec<(Ipv4Addr, Reason)>) -> anyhow::Result<()> {
+        let iface = self.iface;
+        for (ip, reason) in hops {
+            if self.management.covers(ip) {
+                debug!(
+                    "{iface}: {} {} is already in a management network",
+                    reason.name(),
+                    Masked(ip)
+                );
+                continue;
+            }
+            self.management.add(ip)?;
+            self.owned.insert(ip);
+            info!(
+                "{iface}: exempting {} {} from blocking and rate limiting",
+                reason.name(),
+                Masked(ip)
+            );
+        }
+        Ok(())
+    }
+
+    fn sweep(&mut self, now: Instant, grace: Duration) -> anyhow::Result<()> {
+        for ip in self.exemptions.sweep(now, grace) {
+            if self.owned.remove(&ip) {
+                self.management.remove(ip)?;
+                info!(
+                    "{}: no route has gone through {} for {}s, no longer exempt",
+                    self.iface,
+                    Masked(ip),
+                    grace.as_secs()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes a route message into the route and its next hops out of `ifindex`. None for
+/// anything but an IPv4 unicast route.
+fn parse(kind: u16, msg: &[u8], ifindex: u32) -> Option<Route> {
+    if kind != libc::RTM_NEWROUTE && kind != libc::RTM_DELROUTE {
+        return None;
+    }
+    // rtmsg: family, dst_len, src_len, tos, table, protocol, scope, type, flags
+    let (&family, &dst_len, &tos, &table, &rtype) = (
+        msg.first()?,
+        msg.get(1)?,
+        msg.get(3)?,
+        msg.get(4)?,
+        msg.get(7)?,
+    );
+    if i32::from(family) != libc::AF_INET || rtype != libc::RTN_UNICAST {
+        return None;
+    }
+    let mut key = RouteKey {
+        table: u32::from(table),
+        dst: Ipv4Addr::UNSPECIFIED,
+        dst_len,
+        tos,
+        priority: 0,
+    };
+    let reason = if dst_len == 0 {
+        Reason::Gateway
+    } else {
+        Reason::Router
+    };
+    let (mut oif, mut gateway) = (None, None);
+    let mut hops = Vec::new();
+    for (attr, payload) in attrs(msg.get(RTMSG_LEN..).unwrap_or(&[])) {
+        match attr {
+            RTA_DST => key.dst = ipv4(payload)?,
+            RTA_TABLE => key.table = u32_at(payload, 0)?,
+            RTA_PRIORITY => key.priority = u32_at(payload, 0)?,
+            RTA_OIF => oif = u32_at(payload, 0),
+            RTA_GATEWAY => gateway = ipv4(payload),
+            RTA_MULTIPATH => {
+                for (index, gateway) in next_hops(payload) {
+                    if index == ifindex {
+                        hops.push((gateway, reason));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if let (Some(ip), Some(oif)) = (gateway, oif)
+        && oif == ifindex
+    {
+        hops.push((ip, reason));
+    }
+    Some(Route {
+        key,
+        hops,
+        present: kind == libc::RTM_NEWROUTE,
+    })
+}
+
+// `(ifindex, gateway)` of the hops of a multipath route that have a gateway
+fn next_hops(mut buf: &[u8]) -> impl Iterator<Item = (u32, Ipv4Addr)> {
+    std::iter::from_fn(move || {
+        let len = usize::from(u16_at(buf, 0)?);
+        let ifindex = u32_at(buf, 4)?;
+        if len < RTNH_LEN || len > buf.len() {
+            return None;
+        }
+        let gateway = attrs(&buf[RTNH_LEN..len])
+            .find(|(attr, _)| *attr == RTA_GATEWAY)
+            .and_then(|(_, payload)| ipv4(payload));
+        buf = buf.get((len + 3) & !3..).unwrap_or(&[]);
+        Some(gateway.map(|gateway| (ifindex, gateway)))
+    })
+    .flatten()
+}
+
+fn ipv4(payload: &[u8]) -> Option<Ipv4Addr> {
+    let octets: [u8; 4] = payload.get(..4)?.try_into().ok()?;
+    Some(Ipv4Addr::from(octets))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use super::*;
+
+    const IFINDEX: u32 = 2;
+    const GATEWAY: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 254);
+
+    fn attr(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut attr = Vec::new();
+        attr.extend_from_slice(&(4 + payload.len() as u16).to_ne_bytes());
+        attr.extend_from_slice(&kind.to_ne_bytes());
+        attr.extend_from_slice(payload);
+        attr.resize((attr.len() + 3) & !3, 0);
+        attr
+    }
+
+    // An rtmsg of the main table, then the attributes
+    fn route(dst: Option<(Ipv4Addr, u8)>, attrs: &[Vec<u8>]) -> Vec<u8> {
+        let dst_len = dst.map_or(0, |(_, len)| len);
+        let mut msg = vec![
+            libc::AF_INET as u8,
+            dst_len,
+            0,
+            0,
+            254,
+            3,
+            0,
+            libc::RTN_UNICAST,
+        ];
+        msg.resize(RTMSG_LEN, 0);
+        if let Some((dst, _)) = dst {
+            msg.extend(attr(RTA_DST, &dst.octets()));
+        }
+        for attr in attrs {
+            msg.extend_from_slice(attr);
+        }
+        msg
+    }
+
+    fn via(gateway: Ipv4Addr, oif: u32) -> [Vec<u8>; 2] {
+        [
+            attr(RTA_GATEWAY, &gateway.octets()),
+            attr(RTA_OIF, &oif.to_ne_bytes()),
+        ]
+    }
+
+    fn default_route() -> Vec<u8> {
+        route(None, &via(GATEWAY, IFINDEX))
+    }
+
+    fn routed(dst: Ipv4Addr, gateway: Ipv4Addr) -> Vec<u8> {
+        route(Some((dst, 24)), &via(gateway, IFINDEX))
+    }
+
+    fn hops(msg: &[u8]) -> Vec<(Ipv4Addr, Reason)> {
+        parse(libc::RTM_NEWROUTE, msg, IFINDEX).unwrap().hops
+    }
+
+    #[test]
+    fn routes_name_their_next_hops() {
+        assert_eq!(hops(&default_route()), [(GATEWAY, Reason::Gateway)]);
+        let subnet = Ipv4Addr::new(198, 51, 100, 0);
+        assert_eq!(hops(&routed(subnet, ROUTER)), [(ROUTER, Reason::Router)]);
+        // Out of another interface, or without a gateway: a route with nothing to exempt
+        assert!(hops(&route(None, &via(GATEWAY, IFINDEX + 1))).is_empty());
+        let connected = route(Some((subnet, 24)), &[attr(RTA_OIF, &IFINDEX.to_ne_bytes())]);
+        assert!(hops(&connected).is_empty());
+        // Other families and types aren't routes of ours at all
+        let mut local = default_route();
+        local[7] = libc::RTN_LOCAL;
+        assert!(parse(libc::RTM_NEWROUTE, &local, IFINDEX).is_none());
+        assert!(parse(libc::RTM_NEWNEIGH, &default_route(), IFINDEX).is_none());
+    }
+
+    #[test]
+    fn multipath_hops_out_of_the_interface_count() {
+        let hop = |ifindex: u32, gateway: Ipv4Addr| {
+            let gateway = attr(RTA_GATEWAY, &gateway.octets());
+            let mut hop = ((RTNH_LEN + gateway.len()) as u16).to_ne_bytes().to_vec();
+            hop.extend_from_slice(&[0, 0]);
+            hop.extend_from_slice(&ifindex.to_ne_bytes());
+            hop.extend(gateway);
+            hop
+        };
+        let other = Ipv4Addr::new(203, 0, 113, 1);
+        let multipath = [
+            hop(IFINDEX, GATEWAY),
+            hop(IFINDEX + 1, other),
+            hop(IFINDEX, ROUTER),
+        ];
+        let msg = route(None, &[attr(RTA_MULTIPATH, &multipath.concat())]);
+        assert_eq!(
+            hops(&msg),
+            [(GATEWAY, Reason::Gateway), (ROUTER, Reason::Gateway)]
+        );
+        // A hop claiming to be longer than what's left ends the list
+        let mut cut = hop(IFINDEX, GATEWAY);
+        cut[0] = 200;
+        assert_eq!(next_hops(&cut).count(), 0);
+    }
+
+    #[test]
+    fn routes_of_other_tables_and_metrics_are_other_routes() {
+        let key = |msg: &[u8]| parse(libc::RTM_NEWROUTE, msg, IFINDEX).unwrap().key;
+        let [gateway, oif] = via(GATEWAY, IFINDEX);
+        let table = attr(RTA_TABLE, &100u32.to_ne_bytes());
+        let metric = attr(RTA_PRIORITY, &50u32.to_ne_bytes());
+        let main = key(&default_route());
+        let other = key(&route(None, &[gateway.clone(), oif.clone(), table]));
+        let backup = key(&route(None, &[gateway, oif, metric]));
+        assert_eq!((main.table, other.table, backup.priority), (254, 100, 50));
+        assert!(main != other && main != backup);
+    }
+
+    fn update(msg: &[u8], kind: u16) -> Route {
+        parse(kind, msg, IFINDEX).unwrap()
+    }
+
+    #[test]
+    fn replaced_routes_release_their_old_hop() {
+        let mut exemptions = Exemptions::default();
+        let now = Instant::now();
+        let first = exemptions.apply(update(&default_route(), libc::RTM_NEWROUTE), now);
+        assert_eq!(first, [(GATEWAY, Reason::Gateway)]);
+        // The same route again changes nothing
+        assert!(
+            exemptions
+                .apply(update(&default_route(), libc::RTM_NEWROUTE), now)
+                .is_empty()
+        );
+        // Replaced by one through another gateway
+        let replaced = route(None, &via(ROUTER, IFINDEX));
+        exemptions.apply(update(&replaced, libc::RTM_NEWROUTE), now);
+        assert!(exemptions.leaving.contains_key(&GATEWAY));
+        assert!(!exemptions.leaving.contains_key(&ROUTER));
+        let grace = Duration::from_secs(300);
+        assert!(exemptions.sweep(now + grace / 2, grace).is_empty());
+        assert_eq!(exemptions.sweep(now + grace, grace), [GATEWAY]);
+        assert_eq!(exemptions.exempt, HashSet::from([ROUTER]));
+    }
+
+    #[test]
+    fn a_hop_stays_while_any_route_goes_through_it() {
+        let mut exemptions = Exemptions::default();
+        let now = Instant::now();
+        let subnet = Ipv4Addr::new(198, 51, 100, 0);
+        exemptions.apply(update(&default_route(), libc::RTM_NEWROUTE), now);
+        exemptions.apply(update(&routed(subnet, GATEWAY), libc::RTM_NEWROUTE), now);
+        exemptions.apply(update(&default_route(), libc::RTM_DELROUTE), now);
+        assert!(exemptions.leaving.is_empty());
+        exemptions.apply(update(&routed(subnet, GATEWAY), libc::RTM_DELROUTE), now);
+        assert!(exemptions.leaving.contains_key(&GATEWAY));
+        // Back before the grace period is over
+        exemptions.apply(update(&default_route(), libc::RTM_NEWROUTE), now);
+        assert!(exemptions.leaving.is_empty());
+    }
+
+    #[test]
+    fn a_resync_drops_what_the_dump_no_longer_has() {
+        let mut exemptions = Exemptions::default();
+        let now = Instant::now();
+        let subnet = Ipv4Addr::new(198, 51, 100, 0);
+        exemptions.apply(update(&default_route(), libc::RTM_NEWROUTE), now);
+        exemptions.apply(update(&routed(subnet, ROUTER), libc::RTM_NEWROUTE), now);
+        let new = exemptions.resync(vec![update(&default_route(), libc::RTM_NEWROUTE)], now);
+        assert!(new.is_empty());
+        assert_eq!(exemptions.leaving.keys().collect::<Vec<_>>(), [&ROUTER]);
+    }
+
+    // The kernel side, played from a script
+    struct Script {
+        dumps: VecDeque<Vec<(u16, Vec<u8>)>>,
+        changes: VecDeque<io::Result<Vec<(u16, Vec<u8>)>>>,
+    }
+
+    impl Routes for Script {
+        async fn dump(&mut self) -> anyhow::Result<Vec<(u16, Vec<u8>)>> {
+            Ok(self.dumps.pop_front().expect("no dump left"))
+        }
+
+        async fn changes(&mut self) -> io::Result<Option<Vec<(u16, Vec<u8>)>>> {
+            self.changes.pop_front().transpose()
+        }
+    }
+
+    #[derive(Default)]
+    struct Trie {
+        configured: Vec<Ipv4Cidr>,
+        added: Mutex<Vec<Ipv4Addr>>,
+        removed: Mutex<Vec<Ipv4Addr>>,
+    }
+
+    impl Management for Trie {
+        fn covers(&self, ip: Ipv4Addr) -> bool {
+            self.configured.iter().any(|cidr| cidr.contains(ip))
+        }
+
+        fn add(&self, ip: Ipv4Addr) -> anyhow::Result<()> {
+            self.added.lock().unwrap().push(ip);
+            Ok(())
+        }
+
+        fn remove(&self, ip: Ipv4Addr) -> anyhow::Result<()> {
+            self.removed.lock().unwrap().push(ip);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn the_watcher_follows_the_table_and_resyncs_after_lost_changes() {
+        let subnet = Ipv4Addr::new(198, 51, 100, 0);
+        let lost = io::Error::from_raw_os_error(libc::ENOBUFS);
+        let script = Script {
+            dumps: VecDeque::from([
+                vec![(libc::RTM_NEWROUTE, default_route())],
+                // After the lost changes: the default route went, a router came
+                vec![(libc::RTM_NEWROUTE, routed(subnet, ROUTER))],
+            ]),
+            changes: VecDeque::from([Ok(vec![(libc::RTM_NEWNEIGH, vec![0; 12])]), Err(lost)]),
+        };
+        let trie = Trie::default();
+        let mut watcher = Watcher::new("eth0", IFINDEX, &trie);
+        let grace = Duration::from_secs(300);
+        watcher.run(script, grace).await.unwrap();
+        assert_eq!(*trie.added.lock().unwrap(), [GATEWAY, ROUTER]);
+        assert!(trie.removed.lock().unwrap().is_empty());
+        // The default route went while the changes were lost
+        assert!(watcher.exemptions.leaving.contains_key(&GATEWAY));
+        watcher.sweep(Instant::now() + grace, grace).unwrap();
+        assert_eq!(*trie.removed.lock().unwrap(), [GATEWAY]);
+    }
+
+    #[tokio::test]
+    async fn hops_a_mgmt_cidr_covers_are_left_to_it() {
+        let script = Script {
+            dumps: VecDeque::from([vec![(libc::RTM_NEWROUTE, default_route())]]),
+            changes: VecDeque::new(),
+        };
+        let trie = Trie {
+            configured: vec![Ipv4Cidr::new(Ipv4Addr::new(192, 0, 2, 0), 24).unwrap()],
+            ..Trie::default()
+        };
+        let grace = Duration::from_secs(300);
+        Watcher::new("eth0", IFINDEX, &trie)
+            .run(script, grace)
+            .await
+            .unwrap();
+        assert!(trie.added.lock().unwrap().is_empty());
+    }
+}
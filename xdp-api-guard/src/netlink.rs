@@ -0,0 +1,311 @@
+//! Just enough rtnetlink to follow link, route and neighbor changes without pulling in a
+//! netlink crate, and enough generic netlink to ask what XDP a driver supports.
+
+use std::{
+    collections::HashMap,
+    io, mem,
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+};
+
+use tokio::io::unix::AsyncFd;
+
+const NLA_TYPE_MASK: u16 = 0x3fff;
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_DONE: u16 = 3;
+const NLMSG_ERROR: u16 = 2;
+// The table changed while it was dumped
+const NLM_F_DUMP_INTR: u16 = 0x10;
+
+// Generic netlink: the controller resolves family names, `netdev` (Linux 6.3 and later)
+// describes devices
+const GENL_ID_CTRL: u16 = 0x10;
+const GENL_HDRLEN: usize = 4;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+const NETDEV_CMD_DEV_GET: u8 = 1;
+const NETDEV_A_DEV_IFINDEX: u16 = 1;
+const NETDEV_A_DEV_XDP_FEATURES: u16 = 3;
+
+/// Bits of what `xdp_features` returns for a device.
+pub mod xdp_feature {
+    /// XDP_PASS, XDP_DROP, XDP_ABORTED and XDP_TX in the driver: native XDP.
+    pub const BASIC: u64 = 1 << 0;
+    /// Frames larger than a page, in fragments.
+    pub const RX_SG: u64 = 1 << 5;
+}
+
+/// Opens a netlink route socket subscribed to the `RTMGRP_*` bits in `groups`.
+pub fn open(groups: u32, nonblocking: bool) -> io::Result<OwnedFd> {
+    socket(libc::NETLINK_ROUTE, groups, nonblocking)
+}
+
+fn socket(protocol: i32, groups: u32, nonblocking: bool) -> io::Result<OwnedFd> {
+    let mut kind = libc::SOCK_RAW | libc::SOCK_CLOEXEC;
+    if nonblocking {
+        kind |= libc::SOCK_NONBLOCK;
+    }
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, kind, protocol) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    addr.nl_groups = groups;
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            (&addr as *const libc::sockaddr_nl).cast(),
+            mem::size_of::<libc::sockaddr_nl>() as u32,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// Waits for the next datagram. `ENOBUFS` means the kernel dropped notifications.
+pub async fn recv(fd: &AsyncFd<OwnedFd>, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let mut guard = fd.readable().await?;
+        match guard.try_io(|fd| recv_raw(fd.get_ref(), buf)) {
+            Ok(result) => return result,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+fn recv_raw(fd: &OwnedFd, buf: &mut [u8]) -> io::Result<usize> {
+    let ret = unsafe { libc::recv(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Requests a full dump of `kind` (`RTM_GETROUTE` or `RTM_GETNEIGH`) for `family` and returns
+/// every message of the reply as `(type, payload)`. Blocks until the kernel is done, so async
+/// code calls it through `spawn_blocking`. `EINTR` when the table changed during the dump and
+/// it should be asked for again.
+pub fn dump(kind: u16, family: u8) -> io::Result<Vec<(u16, Vec<u8>)>> {
+    let fd = open(0, false)?;
+    // A zeroed rtmsg/ndmsg (both 12 bytes) with only the family set
+    let mut msg = [0u8; 12];
+    msg[0] = family;
+    request(&fd, kind, libc::NLM_F_DUMP as u16, &msg)
+}
+
+/// What each device's driver supports of XDP, by ifindex, as `xdp_feature` bits. Kernels
+/// before 6.3 don't say, and get `Ok(None)`.
+pub fn xdp_features() -> io::Result<Option<HashMap<u32, u64>>> {
+    let fd = socket(libc::NETLINK_GENERIC, 0, false)?;
+    let mut name = genl(CTRL_CMD_GETFAMILY);
+    push_attr(&mut name, CTRL_ATTR_FAMILY_NAME, b"netdev\0");
+    let family = match request(&fd, GENL_ID_CTRL, 0, &name) {
+        Ok(reply) => reply
+            .iter()
+            .flat_map(|(_, msg)| attrs(msg.get(GENL_HDRLEN..).unwrap_or(&[])))
+            .find(|(attr, _)| *attr == CTRL_ATTR_FAMILY_ID)
+            .and_then(|(_, payload)| u16_at(payload, 0)),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => None,
+        Err(e) => return Err(e),
+    };
+    let Some(family) = family else {
+        return Ok(None);
+    };
+    let mut features = HashMap::new();
+    let reply = request(
+        &fd,
+        family,
+        libc::NLM_F_DUMP as u16,
+        &genl(NETDEV_CMD_DEV_GET),
+    )?;
+    for (_, msg) in reply {
+        let (mut ifindex, mut bits) = (None, None);
+        for (attr, payload) in attrs(msg.get(GENL_HDRLEN..).unwrap_or(&[])) {
+            match attr {
+                NETDEV_A_DEV_IFINDEX => ifindex = u32_at(payload, 0),
+                NETDEV_A_DEV_XDP_FEATURES => {
+                    bits = payload.try_into().ok().map(u64::from_ne_bytes);
+                }
+                _ => {}
+            }
+        }
+        if let (Some(ifindex), Some(bits)) = (ifindex, bits) {
+            features.insert(ifindex, bits);
+        }
+    }
+    Ok(Some(features))
+}
+
+// genlmsghdr: command, version, reserved
+fn genl(cmd: u8) -> Vec<u8> {
+    vec![cmd, 1, 0, 0]
+}
+
+fn push_attr(msg: &mut Vec<u8>, kind: u16, payload: &[u8]) {
+    let len = 4 + payload.len();
+    msg.extend_from_slice(&(len as u16).to_ne_bytes());
+    msg.extend_from_slice(&kind.to_ne_bytes());
+    msg.extend_from_slice(payload);
+    msg.resize(align4(msg.len()), 0);
+}
+
+// Sends one request and collects the messages of the reply, up to the end of a dump or the
+// acknowledgement of anything else
+fn request(fd: &OwnedFd, kind: u16, flags: u16, msg: &[u8]) -> io::Result<Vec<(u16, Vec<u8>)>> {
+    let len = NLMSG_HDRLEN + msg.len();
+    let mut req = vec![0u8; NLMSG_HDRLEN];
+    req[0..4].copy_from_slice(&(len as u32).to_ne_bytes());
+    req[4..6].copy_from_slice(&kind.to_ne_bytes());
+    let mut flags = flags | libc::NLM_F_REQUEST as u16;
+    if flags & libc::NLM_F_DUMP as u16 == 0 {
+        flags |= libc::NLM_F_ACK as u16;
+    }
+    req[6..8].copy_from_slice(&flags.to_ne_bytes());
+    req[8..12].copy_from_slice(&1u32.to_ne_bytes());
+    req.extend_from_slice(msg);
+    let ret = unsafe { libc::send(fd.as_raw_fd(), req.as_ptr().cast(), req.len(), 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut out = Vec::new();
+    let mut buf = vec![0u8; 32 * 1024];
+    let mut interrupted = false;
+    loop {
+        let len = recv_raw(fd, &mut buf)?;
+        for (kind, flags, payload) in frames(&buf[..len]) {
+            interrupted |= flags & NLM_F_DUMP_INTR != 0;
+            if kind != NLMSG_DONE && kind != NLMSG_ERROR {
+                out.push((kind, payload.to_vec()));
+                continue;
+            }
+            status(kind, payload)?;
+            if interrupted {
+                return Err(io::Error::from_raw_os_error(libc::EINTR));
+            }
+            return Ok(out);
+        }
+    }
+}
+
+// What the message ending a reply says. An error message carries the errno, negated, and
+// one of 0 is the acknowledgement. The end of a dump carries one too, where the dump itself
+// failed. Either cut short is a broken reply, not a success
+fn status(kind: u16, payload: &[u8]) -> io::Result<()> {
+    let errno = match (kind, u32_at(payload, 0)) {
+        (_, Some(errno)) => errno as i32,
+        (NLMSG_DONE, None) if payload.is_empty() => 0,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated netlink status message",
+            ));
+        }
+    };
+    match errno {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno.saturating_neg())),
+    }
+}
+
+pub fn u16_at(buf: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(buf.get(off..off + 2)?.try_into().ok()?))
+}
+
+pub fn u32_at(buf: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(buf.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Iterates `(type, payload)` over the netlink messages in one datagram.
+pub fn messages(buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    frames(buf).map(|(kind, _, payload)| (kind, payload))
+}
+
+// The same with the flags of each message
+fn frames(mut buf: &[u8]) -> impl Iterator<Item = (u16, u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let len = u32_at(buf, 0)? as usize;
+        let kind = u16_at(buf, 4)?;
+        let flags = u16_at(buf, 6)?;
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            return None;
+        }
+        let payload = &buf[NLMSG_HDRLEN..len];
+        buf = buf.get(align4(len)..).unwrap_or(&[]);
+        Some((kind, flags, payload))
+    })
+}
+
+/// Iterates `(type, payload)` over a run of netlink attributes.
+pub fn attrs(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let len = usize::from(u16_at(buf, 0)?);
+        let kind = u16_at(buf, 2)? & NLA_TYPE_MASK;
+        if len < 4 || len > buf.len() {
+            return None;
+        }
+        let payload = &buf[4..len];
+        buf = buf.get(align4(len)..).unwrap_or(&[]);
+        Some((kind, payload))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(kind: u16, flags: u16, payload: &[u8]) -> Vec<u8> {
+        let mut msg = vec![0u8; NLMSG_HDRLEN];
+        msg[0..4].copy_from_slice(&((NLMSG_HDRLEN + payload.len()) as u32).to_ne_bytes());
+        msg[4..6].copy_from_slice(&kind.to_ne_bytes());
+        msg[6..8].copy_from_slice(&flags.to_ne_bytes());
+        msg.extend_from_slice(payload);
+        msg.resize(align4(msg.len()), 0);
+        msg
+    }
+
+    #[test]
+    fn an_error_of_zero_is_an_acknowledgement() {
+        assert!(status(NLMSG_ERROR, &0i32.to_ne_bytes()).is_ok());
+        assert!(status(NLMSG_DONE, &0i32.to_ne_bytes()).is_ok());
+        assert!(status(NLMSG_DONE, &[]).is_ok());
+        let e = status(NLMSG_ERROR, &(-libc::EPERM).to_ne_bytes()).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EPERM));
+        // A dump that failed half way
+        let e = status(NLMSG_DONE, &(-libc::ENOBUFS).to_ne_bytes()).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOBUFS));
+    }
+
+    #[test]
+    fn truncated_status_messages_are_errors() {
+        let e = status(NLMSG_ERROR, &[]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(status(NLMSG_ERROR, &[0, 0]).is_err());
+        assert!(status(NLMSG_DONE, &[0, 0]).is_err());
+    }
+
+    #[test]
+    fn messages_and_their_flags_are_split() {
+        let mut buf = message(libc::RTM_NEWROUTE, NLM_F_DUMP_INTR, &[1, 2, 3]);
+        buf.extend(message(NLMSG_DONE, 0, &0i32.to_ne_bytes()));
+        let frames: Vec<_> = frames(&buf).collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(
+            frames[0],
+            (libc::RTM_NEWROUTE, NLM_F_DUMP_INTR, &[1, 2, 3][..])
+        );
+        assert_eq!(frames[1].0, NLMSG_DONE);
+        // A length past the end stops the walk
+        buf[0..4].copy_from_slice(&1000u32.to_ne_bytes());
+        assert_eq!(messages(&buf).count(), 0);
+    }
+}
@@ -0,0 +1,134 @@
+//! The blocklist given at startup, `--allow`, `--mgmt-cidr`, `--block` and the `--feed`
+//! files, resolved into one entry per address before any of it is written.
+//!
+//! Written one by one, each losing entry was refused and logged on its own, and which feed
+//! got an address depended on the order of the writes. Resolving up front gives the same
+//! entries for the same inputs whatever order the lines come in, and sums up the conflicts
+//! in a few lines. The precedence is the blocklist's own, see [`Origin`]: an allow beats a
+//! management network, which beats a manual block, which beats a feed. Between two feeds
+//! the later one on the command line gets the address.
+
+use std::{collections::BTreeMap, fmt, net::Ipv4Addr};
+
+use log::{info, warn};
+use xdp_api_guard_common::Origin;
+
+use crate::{blocklist::Entry, cidr::Ipv4Cidr, mask::Masked};
+
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// Management networks without duplicates, in the order given.
+    pub mgmt: Vec<Ipv4Cidr>,
+    /// The entry of every address, in address order.
+    pub entries: BTreeMap<Ipv4Addr, Entry>,
+    pub conflicts: Conflicts,
+}
+
+#[derive(Debug, Default)]
+pub struct Conflicts {
+    /// Addresses and networks given again by a source of the same origin.
+    pub duplicates: usize,
+    /// Entries dropped for a higher-priority one, by their origin and the winner's, with
+    /// the count and the lowest address.
+    pub shadowed: BTreeMap<(Origin, Origin), (usize, Ipv4Addr)>,
+}
+
+impl Conflicts {
+    /// One line per pair of origins, so a feed full of management addresses logs once.
+    pub fn log(&self) {
+        if self.duplicates != 0 {
+            let duplicates = self.duplicates;
+            info!("startup blocklist: {duplicates} duplicate entries ignored");
+        }
+        for (&(loser, winner), &(count, first)) in &self.shadowed {
+            warn!(
+                "startup blocklist: {count} {} entries shadowed by {}, e.g. {}",
+                loser.name(),
+                winner.name(),
+                Masked(first)
+            );
+        }
+    }
+}
+
+impl fmt::Display for Conflicts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (&(loser, winner), &(count, first))) in self.shadowed.iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+            write!(
+                f,
+                "{count} {} entries under {} (e.g. {})",
+                loser.name(),
+                winner.name(),
+                Masked(first)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolves the startup entries. `feeds` are the addresses of each `--feed`, in command line
+/// order.
+pub fn resolve(
+    allow: &[Ipv4Addr],
+    mgmt: &[Ipv4Cidr],
+    block: &[Ipv4Addr],
+    feeds: &[Vec<Ipv4Addr>],
+) -> Plan {
+    let mut plan = Plan::default();
+    for cidr in mgmt {
+        if plan.mgmt.contains(cidr) {
+            plan.conflicts.duplicates += 1;
+        } else {
+            plan.mgmt.push(*cidr);
+        }
+    }
+    // Highest priority first, so every entry only has to check what is already there
+    for ip in allow {
+        plan.offer(*ip, Entry::new(Origin::ManualAllow));
+    }
+    for ip in block {
+        plan.offer(*ip, Entry::new(Origin::ManualBlock));
+    }
+    for (id, ips) in feeds.iter().enumerate().rev() {
+        for ip in ips {
+            let entry = Entry {
+                feed: id as u8,
+                ..Entry::new(Origin::Feed)
+            };
+            plan.offer(*ip, entry);
+        }
+    }
+    plan
+}
+
+impl Plan {
+    fn offer(&mut self, ip: Ipv4Addr, entry: Entry) {
+        let winner = match self.entries.get(&ip) {
+            Some(existing) => Some(existing.origin),
+            None if entry.origin < Origin::Management
+                && self.mgmt.iter().any(|cidr| cidr.contains(ip)) =>
+            {
+                Some(Origin::Management)
+            }
+            None => None,
+        };
+        match winner {
+            None => {
+                self.entries.insert(ip, entry);
+            }
+            Some(winner) if winner == entry.origin => self.conflicts.duplicates += 1,
+            Some(winner) => {
+                let shadowed = self
+                    .conflicts
+                    .shadowed
+                    .entry((entry.origin, winner))
+                    .or_insert((0, ip));
+                shadowed.0 += 1;
+                shadowed.1 = shadowed.1.min(ip);
+            }
+        }
+    }
+}
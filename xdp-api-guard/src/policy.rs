@@ -0,0 +1,582 @@
+//! `--policy-module` (the `wasm-policy` feature): a WASM module deciding on sources from their
+//! counters, for rules too particular to be flags of their own.
+//!
+//! Every `--policy-interval` seconds each source with packets in its current window, and each
+//! source under an auto or policy ban, is described as a JSON object and handed to the module.
+//! Its answers are applied through the [`BlocklistHandle`](crate::blocklist::BlocklistHandle)
+//! with origin `policy-module`, so the module never sees a packet and can't override a manual
+//! entry or a management network.
+//!
+//! The module exports its `memory`, `alloc(len: i32) -> i32`, returning where `len` bytes may
+//! be written, and `evaluate(ptr: i32, len: i32) -> i64`. The low byte of the answer is the
+//! action, the upper 32 bits its argument:
+//!
+//! - 0: nothing
+//! - 1: ban, for the argument in seconds (0: until removed)
+//! - 2: greylist, raise the source's tag score to the argument, below `TAG_MAX`
+//! - 3: trust, lift the source's auto or policy ban and clear the tag score the module gave it,
+//!   unless the operator or the kernel changed it since
+//!
+//! A pass runs in a fresh instance, so nothing a module does lasts longer than one pass. Each
+//! call gets `--policy-fuel`, the instance's memory is capped at `--policy-memory-mb`, and a
+//! pass that takes longer than `--policy-budget-ms` is cut short; the next one carries on from
+//! the source it stopped at. Passes run on the blocking pool, off the runtime's workers. A
+//! module that traps, runs out of fuel or answers something else is switched off, with a
+//! warning in `/healthz`, until SIGHUP loads it again.
+
+use std::{
+    collections::HashMap as StdHashMap,
+    net::Ipv4Addr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, bail};
+use aya::maps::{HashMap, MapData, MapError};
+use log::{debug, info, warn};
+use serde::Serialize;
+use tokio::signal::unix::{SignalKind, signal};
+use wasmtime::{Engine, Instance, Module, Store, StoreLimitsBuilder, TypedFunc};
+use xdp_api_guard_common::{Origin, PacketLog, TAG_MAX, tagged_limit};
+
+use crate::{
+    blocklist::{Applied, Entry},
+    control::ControlState,
+    health::Health,
+    mask::Masked,
+    timebase,
+};
+
+// The window of `--http-rps-limit`, fixed in the kernel
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+pub struct PolicyConfig {
+    pub path: PathBuf,
+    pub fuel: u64,
+    /// Bytes of linear memory an instance may grow to.
+    pub memory: usize,
+    pub budget: Duration,
+    pub interval: Duration,
+}
+
+/// What the module is told about one source.
+#[derive(Debug, Serialize)]
+struct Source {
+    addr: Ipv4Addr,
+    /// Unix time of the pass, for rules that depend on the time of day.
+    unix: u64,
+    /// Packets in the current window and the budget they count against, after tags.
+    packets: u64,
+    limit: u64,
+    window_ms: u64,
+    /// HTTP requests in the current second, with `--http-rps-limit`.
+    http_requests: u64,
+    /// Bare ACKs of untracked flows in the current window, with `--ack-limit`.
+    bare_acks: u64,
+    /// QUIC Initials in the current window, with `--quic-initial-limit`.
+    quic_initials: u64,
+    tag: u32,
+    group: Option<String>,
+    /// The origin of the entry deciding for the source, if any.
+    decision: Option<&'static str>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    None,
+    /// Seconds, 0 for a ban without a TTL.
+    Ban(u64),
+    Greylist(u32),
+    Trust,
+}
+
+impl Action {
+    fn decode(answer: i64) -> anyhow::Result<Self> {
+        let arg = (answer as u64 >> 32) as u32;
+        match answer & 0xff {
+            0 => Ok(Action::None),
+            1 => Ok(Action::Ban(u64::from(arg))),
+            2 => Ok(Action::Greylist(arg)),
+            3 => Ok(Action::Trust),
+            action => bail!("unknown action {action}"),
+        }
+    }
+}
+
+pub struct Policy {
+    config: PolicyConfig,
+    engine: Engine,
+    /// `None` while switched off by a failure.
+    module: Option<Module>,
+    // Sources go in address order, a pass cut short resumes after the last one evaluated
+    resume: u32,
+    // The tag scores the module set, so a trust clears only those
+    greylisted: StdHashMap<u32, u32>,
+}
+
+impl Policy {
+    /// Compiles the module, so one that doesn't load stops the daemon at startup.
+    pub fn new(config: PolicyConfig) -> anyhow::Result<Self> {
+        let mut wasm = wasmtime::Config::new();
+        wasm.consume_fuel(true);
+        let engine = Engine::new(&wasm)?;
+        let module = load(&engine, &config)?;
+        info!(
+            "policy module {} loaded, a pass every {}s",
+            config.path.display(),
+            config.interval.as_secs()
+        );
+        Ok(Self {
+            config,
+            engine,
+            module: Some(module),
+            resume: 0,
+            greylisted: StdHashMap::new(),
+        })
+    }
+
+    /// Runs the passes, and a reload on every SIGHUP, until the daemon stops.
+    pub fn start(self, control: Arc<ControlState>, health: Arc<Health>) -> anyhow::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(self.config.interval);
+            let mut policy = self;
+            loop {
+                let reload = tokio::select! {
+                    _ = hangup.recv() => true,
+                    _ = tick.tick() => false,
+                };
+                // Compiling and running the module take their time, keep them off the workers
+                let (control, health) = (control.clone(), health.clone());
+                let task = tokio::task::spawn_blocking(move || {
+                    if reload {
+                        policy.reload(&health);
+                    } else {
+                        policy.pass(&control, &health);
+                    }
+                    policy
+                });
+                policy = match task.await {
+                    Ok(policy) => policy,
+                    Err(e) => {
+                        warn!("policy module stopped: {e}");
+                        return;
+                    }
+                };
+            }
+        });
+        Ok(())
+    }
+
+    fn reload(&mut self, health: &Health) {
+        match load(&self.engine, &self.config) {
+            Ok(module) => {
+                info!("reloaded policy module {}", self.config.path.display());
+                self.module = Some(module);
+                health.set_policy_error(None);
+            }
+            Err(e) if self.module.is_some() => {
+                warn!("policy module not reloaded, the previous one stays: {e:#}")
+            }
+            Err(e) => warn!("policy module not reloaded, it stays off: {e:#}"),
+        }
+    }
+
+    fn pass(&mut self, control: &ControlState, health: &Health) {
+        let Some(module) = self.module.clone() else {
+            return;
+        };
+        let sources = match sources(control) {
+            Ok(sources) => sources,
+            Err(e) => {
+                warn!("policy module: failed to read the sources: {e:#}");
+                return;
+            }
+        };
+        let decisions = match self.evaluate(&module, &sources) {
+            Ok(decisions) => decisions,
+            Err(e) => {
+                let error = format!("{e:#}");
+                warn!("policy module switched off until SIGHUP: {error}");
+                health.set_policy_error(Some(error));
+                self.module = None;
+                return;
+            }
+        };
+        for (ip, action) in decisions {
+            if let Err(e) = apply(control, &mut self.greylisted, ip, action) {
+                warn!(
+                    "policy module: failed to apply {action:?} to {}: {e:#}",
+                    Masked(ip)
+                );
+            }
+        }
+        // Forget the scores that were changed or removed since
+        let tags = control.tags.lock().unwrap();
+        self.greylisted
+            .retain(|ip, score| tags.get(ip, 0).is_ok_and(|current| current == *score));
+    }
+
+    /// The answers of `module` for `sources`, sorted by address, other than `Action::None`.
+    /// An error is the module's fault.
+    fn evaluate(
+        &mut self,
+        module: &Module,
+        sources: &[Source],
+    ) -> anyhow::Result<Vec<(Ipv4Addr, Action)>> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.config.memory)
+            .instances(1)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.config.fuel)?;
+        let instance = Instance::new(&mut store, module, &[]).context("failed to instantiate")?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("no memory exported")?;
+        let alloc: TypedFunc<i32, i32> = instance.get_typed_func(&mut store, "alloc")?;
+        let evaluate: TypedFunc<(i32, i32), i64> =
+            instance.get_typed_func(&mut store, "evaluate")?;
+
+        let started = Instant::now();
+        let first = sources.partition_point(|source| u32::from(source.addr) <= self.resume);
+        let mut decisions = Vec::new();
+        for source in sources[first..].iter().chain(&sources[..first]) {
+            if started.elapsed() >= self.config.budget {
+                debug!(
+                    "policy pass out of time, resuming after {}",
+                    Masked(source.addr)
+                );
+                break;
+            }
+            let json = serde_json::to_vec(source)?;
+            let len = i32::try_from(json.len())?;
+            store.set_fuel(self.config.fuel)?;
+            let ptr = alloc.call(&mut store, len).context("alloc failed")?;
+            memory
+                .write(&mut store, ptr as u32 as usize, &json)
+                .context("alloc returned memory out of bounds")?;
+            let answer = evaluate
+                .call(&mut store, (ptr, len))
+                .context("evaluate failed")?;
+            let action =
+                Action::decode(answer).with_context(|| format!("evaluate answered {answer:#x}"))?;
+            if action != Action::None {
+                decisions.push((source.addr, action));
+            }
+            self.resume = u32::from(source.addr);
+        }
+        Ok(decisions)
+    }
+}
+
+fn load(engine: &Engine, config: &PolicyConfig) -> anyhow::Result<Module> {
+    let path = &config.path;
+    let module = Module::from_file(engine, path)
+        .with_context(|| format!("failed to load policy module {}", path.display()))?;
+    for export in ["memory", "alloc", "evaluate"] {
+        if module.get_export(export).is_none() {
+            bail!("policy module {} doesn't export {export}", path.display());
+        }
+    }
+    Ok(module)
+}
+
+/// Every source the module decides on, in address order. The blocklist is read on its own,
+/// the other maps after the groups as `group list` does.
+fn sources(control: &ControlState) -> anyhow::Result<Vec<Source>> {
+    let cfg = control.config.lock().unwrap().get();
+    let now = timebase::boot_ns();
+    let unix = timebase::unix_now();
+    let banned: Vec<u32> = control
+        .blocklist
+        .lock()
+        .unwrap()
+        .entries()
+        .filter(|(_, entry)| matches!(entry.origin, Origin::AutoBan | Origin::Policy))
+        .map(|(ip, _)| u32::from(ip))
+        .collect();
+
+    let groups = control.groups.lock().unwrap();
+    let rate_limit = control.rate_limit.lock().unwrap();
+    let tags = control.tags.lock().unwrap();
+    let http = control.http.as_ref().map(|http| http.lock().unwrap());
+    let ack = control.ack.as_ref().map(|ack| ack.lock().unwrap());
+    let quic = control.quic_initial.lock().unwrap();
+    let mut addrs: Vec<u32> = rate_limit
+        .iter()
+        .filter_map(Result::ok)
+        .filter(|(_, log)| log.current_count(now, cfg.window_ns) != 0)
+        .map(|(ip, _)| ip)
+        .chain(banned)
+        .collect();
+    addrs.sort_unstable();
+    addrs.dedup();
+
+    let count = |map: &HashMap<MapData, u32, PacketLog>, ip: u32, window_ns: u64| {
+        map.get(&ip, 0)
+            .map_or(0, |log| log.current_count(now, window_ns))
+    };
+    let mut sources = Vec::with_capacity(addrs.len());
+    for ip in addrs {
+        let addr = Ipv4Addr::from(ip);
+        let tag = match tags.get(&ip, 0) {
+            Ok(score) => score,
+            Err(MapError::KeyNotFound) => 0,
+            Err(e) => return Err(e.into()),
+        };
+        sources.push(Source {
+            addr,
+            unix,
+            packets: count(&rate_limit, ip, cfg.window_ns),
+            limit: tagged_limit(groups.limit(&cfg, ip), tag),
+            window_ms: cfg.window_ns / 1_000_000,
+            http_requests: http.as_ref().map_or(0, |http| count(http, ip, NS_PER_SEC)),
+            bare_acks: ack.as_ref().map_or(0, |ack| count(ack, ip, cfg.window_ns)),
+            quic_initials: count(&quic, ip, cfg.window_ns),
+            tag,
+            group: groups.lookup(addr).map(|(group, _)| group.name.clone()),
+            decision: None,
+        });
+    }
+    drop((groups, rate_limit, tags, http, ack, quic));
+
+    let blocklist = control.blocklist.lock().unwrap();
+    for source in &mut sources {
+        source.decision = blocklist.effective(source.addr).map(Origin::name);
+    }
+    Ok(sources)
+}
+
+fn apply(
+    control: &ControlState,
+    greylisted: &mut StdHashMap<u32, u32>,
+    ip: Ipv4Addr,
+    action: Action,
+) -> anyhow::Result<()> {
+    match action {
+        Action::None => {}
+        Action::Ban(ttl) => {
+            let mut blocklist = control.blocklist.lock().unwrap();
+            // A ban is answered again every pass, its TTL runs from the first
+            if blocklist
+                .get(ip)
+                .is_some_and(|entry| entry.origin == Origin::Policy)
+            {
+                return Ok(());
+            }
+            let entry = Entry {
+                expires: (ttl != 0).then(|| timebase::unix_now() + ttl),
+                ..Entry::new(Origin::Policy)
+            };
+            if blocklist.insert_entry(ip, entry)? == Applied::Written {
+                match ttl {
+                    0 => info!("policy module banned {}", Masked(ip)),
+                    _ => info!("policy module banned {} for {ttl}s", Masked(ip)),
+                }
+            }
+        }
+        Action::Greylist(score) => {
+            let score = score.min(TAG_MAX - 1);
+            let mut tags = control.tags.lock().unwrap();
+            let key = u32::from(ip);
+            let current = match tags.get(&key, 0) {
+                Ok(score) => score,
+                Err(MapError::KeyNotFound) => 0,
+                Err(e) => return Err(e.into()),
+            };
+            if current < score {
+                tags.insert(key, score, 0)?;
+                greylisted.insert(key, score);
+                info!("policy module greylisted {}, tag score {score}", Masked(ip));
+            }
+        }
+        Action::Trust => {
+            let mut blocklist = control.blocklist.lock().unwrap();
+            if let Some(entry) = blocklist.get(ip)
+                && matches!(entry.origin, Origin::AutoBan | Origin::Policy)
+            {
+                let origin = entry.origin;
+                blocklist.remove(ip)?;
+                info!(
+                    "policy module trusts {}, {} entry removed",
+                    Masked(ip),
+                    origin.name()
+                );
+            }
+            drop(blocklist);
+            // Only a score of the module's own, one set by `tag` or the kernel's scoring stays
+            let key = u32::from(ip);
+            let mut tags = control.tags.lock().unwrap();
+            let Some(score) = greylisted.remove(&key) else {
+                return Ok(());
+            };
+            match tags.get(&key, 0) {
+                Ok(current) if current == score => tags.remove(&key)?,
+                Ok(_) | Err(MapError::KeyNotFound) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::progtest;
+
+    const EXAMPLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../docs/policy/ban-heavy.wat");
+
+    fn policy(path: PathBuf) -> Policy {
+        Policy::new(PolicyConfig {
+            path,
+            fuel: 100_000,
+            memory: 1 << 20,
+            budget: Duration::from_secs(10),
+            interval: Duration::from_secs(5),
+        })
+        .unwrap()
+    }
+
+    fn source(
+        last: u8,
+        packets: u64,
+        group: Option<&str>,
+        decision: Option<&'static str>,
+    ) -> Source {
+        Source {
+            addr: Ipv4Addr::new(198, 51, 100, last),
+            unix: 1_700_000_000,
+            packets,
+            limit: 100,
+            window_ms: 1000,
+            http_requests: 0,
+            bare_acks: 0,
+            quic_initials: 0,
+            tag: 0,
+            group: group.map(str::to_owned),
+            decision,
+        }
+    }
+
+    #[test]
+    fn the_example_bans_sources_at_twice_their_budget() {
+        let mut policy = policy(EXAMPLE.into());
+        let module = policy.module.clone().unwrap();
+        let sources = [
+            source(1, 80, None, None),
+            source(2, 150, None, None),
+            source(3, 200, None, None),
+            source(4, 5000, Some("partners"), None),
+            source(5, 10, Some("partners"), Some("auto-ban")),
+        ];
+        let decisions = policy.evaluate(&module, &sources).unwrap();
+        let at = |last| Ipv4Addr::new(198, 51, 100, last);
+        assert_eq!(
+            decisions,
+            [
+                (at(2), Action::Greylist(50)),
+                (at(3), Action::Ban(600)),
+                (at(5), Action::Trust),
+            ]
+        );
+    }
+
+    fn module(name: &str, wat: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("guard-policy-{}-{name}.wat", std::process::id()));
+        fs::write(&path, wat).unwrap();
+        path
+    }
+
+    #[test]
+    fn memory_doesnt_grow_past_the_cap() {
+        // Bans when it got the 64 MiB it asked for
+        let path = module(
+            "grow",
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) (i32.const 0))
+                (func (export "evaluate") (param i32 i32) (result i64)
+                  (if (result i64) (i32.eq (memory.grow (i32.const 1024)) (i32.const -1))
+                    (then (i64.const 0))
+                    (else (i64.const 1)))))"#,
+        );
+        let mut policy = policy(path.clone());
+        fs::remove_file(path).unwrap();
+        let module = policy.module.clone().unwrap();
+        let decisions = policy
+            .evaluate(&module, &[source(1, 0, None, None)])
+            .unwrap();
+        assert!(decisions.is_empty(), "{decisions:?}");
+    }
+
+    #[test]
+    fn running_out_of_fuel_is_the_modules_fault() {
+        let path = module(
+            "spin",
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) (i32.const 0))
+                (func (export "evaluate") (param i32 i32) (result i64)
+                  (loop $forever (br $forever))
+                  (i64.const 0)))"#,
+        );
+        let mut policy = policy(path.clone());
+        fs::remove_file(path).unwrap();
+        let module = policy.module.clone().unwrap();
+        assert!(
+            policy
+                .evaluate(&module, &[source(1, 0, None, None)])
+                .is_err()
+        );
+    }
+
+    #[test]
+    #[ignore = "creates the maps, needs root"]
+    fn answers_go_in_as_policy_entries_below_the_manual_ones() {
+        let control = progtest::control();
+        let mut greylisted = StdHashMap::new();
+        let mut answer = |ip, action| apply(&control, &mut greylisted, ip, action).unwrap();
+        let entry = |ip| control.blocklist.lock().unwrap().get(ip).cloned();
+        let kernel = |ip: Ipv4Addr| {
+            let blocklist = control.blocklist.lock().unwrap();
+            blocklist.kernel_entry(u32::from(ip)).unwrap()
+        };
+
+        let banned = Ipv4Addr::new(198, 51, 100, 1);
+        answer(banned, Action::Ban(600));
+        let ban = entry(banned).unwrap();
+        assert_eq!(ban.origin, Origin::Policy);
+        let expires = ban.expires.unwrap();
+        assert!(expires.abs_diff(timebase::unix_now() + 600) <= 1);
+        assert_eq!(kernel(banned).unwrap().origin, Origin::Policy as u8);
+        // Answered again the next pass, the TTL still runs from the first
+        answer(banned, Action::Ban(0));
+        assert_eq!(entry(banned).unwrap().expires, Some(expires));
+        answer(banned, Action::Trust);
+        assert_eq!(entry(banned), None);
+        assert!(kernel(banned).is_none());
+
+        for (last, origin) in [(2, Origin::ManualBlock), (3, Origin::ManualAllow)] {
+            let ip = Ipv4Addr::new(198, 51, 100, last);
+            let applied = control
+                .blocklist
+                .lock()
+                .unwrap()
+                .insert_entry(ip, Entry::new(origin))
+                .unwrap();
+            assert_eq!(applied, Applied::Written);
+            for action in [Action::Ban(600), Action::Trust] {
+                answer(ip, action);
+                assert_eq!(entry(ip), Some(Entry::new(origin)), "{action:?}");
+                assert_eq!(kernel(ip).unwrap().origin, origin as u8, "{action:?}");
+            }
+        }
+    }
+}
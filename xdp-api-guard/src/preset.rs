@@ -0,0 +1,541 @@
+//! `xdp-api-guard init`: writes a complete environment file for one of a few presets, so a
+//! first install protects something without reading every flag.
+//!
+//! The daemon takes its whole configuration from flags or their `GUARD_*` variables, and a
+//! systemd unit reads the variables from an `EnvironmentFile`. A preset is a list of flags with
+//! the reason for each. Variable names come from `Opt` itself, and the preset is parsed and
+//! checked as the daemon would before anything is written, so the presets can't drift from the
+//! flags they set.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, DirBuilder, OpenOptions},
+    io::Write as _,
+    os::unix::fs::{DirBuilderExt as _, OpenOptionsExt as _, PermissionsExt as _},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, bail};
+use clap::{CommandFactory as _, Parser, ValueEnum};
+
+use crate::{cidr::Ipv4Cidr, daemon::Opt};
+
+// Created by `--install`, the presets keep `--state-file` in it
+const STATE_DIR: &str = "/var/lib/xdp-api-guard";
+const UNIT_PATH: &str = "/etc/systemd/system/xdp-api-guard.service";
+
+/// Write a configuration for a preset
+#[derive(Debug, Parser)]
+#[clap(name = "xdp-api-guard init")]
+pub struct InitOpt {
+    /// What the host does
+    #[clap(long, value_enum)]
+    pub(crate) preset: Preset,
+
+    /// Interface to protect
+    #[clap(short, long)]
+    pub(crate) iface: String,
+
+    /// Network the host is managed from, never blocked or limited (repeatable)
+    #[clap(long = "mgmt-cidr", value_delimiter = ',')]
+    pub(crate) mgmt_cidr: Vec<Ipv4Cidr>,
+
+    /// Environment file to write
+    #[clap(long, default_value = DEFAULT_OUTPUT)]
+    pub(crate) output: PathBuf,
+
+    /// Replace an existing environment file
+    #[clap(long)]
+    pub(crate) force: bool,
+
+    /// Also create the state directory and install a systemd unit reading the file
+    #[clap(long)]
+    pub(crate) install: bool,
+}
+
+pub(crate) const DEFAULT_OUTPUT: &str = "/etc/xdp-api-guard/guard.env";
+
+impl InitOpt {
+    /// The command line that writes the same, for running it again without questions.
+    pub(crate) fn command(&self) -> String {
+        let mut out = format!(
+            "xdp-api-guard init --preset {} --iface {}",
+            self.preset.name(),
+            self.iface
+        );
+        if !self.mgmt_cidr.is_empty() {
+            let _ = write!(out, " --mgmt-cidr {}", join(&self.mgmt_cidr));
+        }
+        if self.output != Path::new(DEFAULT_OUTPUT) {
+            let _ = write!(out, " --output {}", self.output.display());
+        }
+        if self.force {
+            out.push_str(" --force");
+        }
+        if self.install {
+            out.push_str(" --install");
+        }
+        out
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Preset {
+    /// Public web server or reverse proxy: HTTP, HTTPS and QUIC from many clients
+    WebEdge,
+    /// API endpoint on HTTPS with fewer clients sending more each
+    ApiGateway,
+    /// DNS server, mostly UDP queries
+    Dns,
+    /// Drop nothing: count what would be dropped and learn normal rates for a day
+    ObserveOnly,
+}
+
+/// One flag of a preset. `value` is `None` for switches.
+struct Setting {
+    flag: &'static str,
+    value: Option<&'static str>,
+    why: &'static str,
+}
+
+const fn set(flag: &'static str, value: &'static str, why: &'static str) -> Setting {
+    Setting {
+        flag,
+        value: Some(value),
+        why,
+    }
+}
+
+const fn on(flag: &'static str, why: &'static str) -> Setting {
+    Setting {
+        flag,
+        value: None,
+        why,
+    }
+}
+
+// In every preset: what an unattended daemon needs apart from the policy
+const COMMON: &[Setting] = &[
+    set(
+        "state-file",
+        "/var/lib/xdp-api-guard/state.json",
+        "keep the blocklist across restarts",
+    ),
+    set(
+        "http-listen",
+        "127.0.0.1:9100",
+        "/metrics, /healthz and the status page, on localhost only",
+    ),
+    on("quiet", "no dashboard on a service's stdout"),
+];
+
+const WEB_EDGE: &[Setting] = &[
+    set("rate", "200", "packets per source per second"),
+    set(
+        "burst",
+        "200",
+        "room for page loads fetching many assets at once",
+    ),
+    on(
+        "wred",
+        "start dropping near the limit rather than all at once",
+    ),
+    set(
+        "service-rate",
+        "443=5000,80=2000",
+        "new connections per second per port",
+    ),
+    set(
+        "http-rps-limit",
+        "20",
+        "plaintext HTTP requests per source per second",
+    ),
+    set(
+        "quic-initial-limit",
+        "20",
+        "QUIC connection attempts per source per second",
+    ),
+    set(
+        "ack-limit",
+        "200",
+        "bare ACKs per source for flows never seen starting",
+    ),
+    set(
+        "min-mss-action",
+        "score",
+        "tighten the limit of sources sending tiny-MSS SYNs",
+    ),
+    on("icmp-inner-check", "drop spoofed ICMP errors"),
+];
+
+const API_GATEWAY: &[Setting] = &[
+    set(
+        "rate",
+        "1000",
+        "packets per source per second, clients are busy",
+    ),
+    set("burst", "500", "room for request bursts"),
+    on(
+        "wred",
+        "start dropping near the limit rather than all at once",
+    ),
+    set(
+        "service-rate",
+        "443=10000",
+        "new connections per second to HTTPS",
+    ),
+    set(
+        "ack-limit",
+        "1000",
+        "bare ACKs per source for flows never seen starting",
+    ),
+    set(
+        "min-mss-action",
+        "score",
+        "tighten the limit of sources sending tiny-MSS SYNs",
+    ),
+    on("icmp-inner-check", "drop spoofed ICMP errors"),
+];
+
+const DNS: &[Setting] = &[
+    set(
+        "rate",
+        "100",
+        "packets per source per second, queries are small",
+    ),
+    set("burst", "50", "room for resolvers catching up"),
+    on(
+        "wred",
+        "start dropping near the limit rather than all at once",
+    ),
+    set(
+        "service-rate",
+        "53=2000",
+        "new DNS-over-TCP connections per second",
+    ),
+    on("icmp-inner-check", "drop spoofed ICMP errors"),
+];
+
+const OBSERVE_ONLY: &[Setting] = &[
+    on("paused", "count verdicts, drop nothing"),
+    set("rate", "200", "the limit whose verdicts are counted"),
+    set(
+        "learn",
+        "86400",
+        "learn normal rates for a day, then see `guardctl suggest`",
+    ),
+];
+
+impl Preset {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Preset::WebEdge => "web-edge",
+            Preset::ApiGateway => "api-gateway",
+            Preset::Dns => "dns",
+            Preset::ObserveOnly => "observe-only",
+        }
+    }
+
+    fn settings(self) -> impl Iterator<Item = &'static Setting> {
+        let own = match self {
+            Preset::WebEdge => WEB_EDGE,
+            Preset::ApiGateway => API_GATEWAY,
+            Preset::Dns => DNS,
+            Preset::ObserveOnly => OBSERVE_ONLY,
+        };
+        own.iter().chain(COMMON)
+    }
+
+    /// The preset as command-line arguments for `iface`.
+    fn args(self, iface: &str, mgmt: &[Ipv4Cidr]) -> Vec<String> {
+        let mut args = vec![
+            "xdp-api-guard".to_owned(),
+            "--iface".to_owned(),
+            iface.to_owned(),
+        ];
+        if !mgmt.is_empty() {
+            args.extend(["--mgmt-cidr".to_owned(), join(mgmt)]);
+        }
+        for setting in self.settings() {
+            args.push(format!("--{}", setting.flag));
+            args.extend(setting.value.map(str::to_owned));
+        }
+        args
+    }
+
+    /// The preset parsed and checked like a command line, so a preset the daemon would refuse
+    /// is never written.
+    fn check(self, iface: &str, mgmt: &[Ipv4Cidr]) -> anyhow::Result<()> {
+        let opt = Opt::try_parse_from(self.args(iface, mgmt))
+            .with_context(|| format!("preset {} doesn't parse", self.name()))?;
+        opt.check()
+            .with_context(|| format!("preset {} is invalid", self.name()))
+    }
+
+    /// The environment file at `path`, every variable with the reason above it.
+    fn render(self, iface: &str, mgmt: &[Ipv4Cidr], path: &Path) -> anyhow::Result<String> {
+        let mut out = format!(
+            "# xdp-api-guard, {} preset, written by `xdp-api-guard init`.\n\
+             # Every other flag keeps its default, see `xdp-api-guard --help`.\n\n",
+            self.name()
+        );
+        let _ = writeln!(
+            out,
+            "# This file, `guardctl suggest --apply` writes the limits it applies here\n{}={}\n",
+            env_name("env-file")?,
+            path.display()
+        );
+        let _ = writeln!(
+            out,
+            "# Interface to protect\n{}={iface}\n",
+            env_name("iface")?
+        );
+        if !mgmt.is_empty() {
+            let _ = writeln!(
+                out,
+                "# Where the host is managed from, never blocked or limited\n{}={}\n",
+                env_name("mgmt-cidr")?,
+                join(mgmt)
+            );
+        }
+        for setting in self.settings() {
+            let _ = writeln!(
+                out,
+                "# {}\n{}={}\n",
+                setting.why,
+                env_name(setting.flag)?,
+                setting.value.unwrap_or("true")
+            );
+        }
+        Ok(out)
+    }
+
+    /// What the preset enforces, for the terminal.
+    fn summary(self, iface: &str, mgmt: &[Ipv4Cidr]) -> String {
+        let mut out = format!("{} preset on {iface}:", self.name());
+        if !mgmt.is_empty() {
+            let flag = format!("--mgmt-cidr {}", join(mgmt));
+            let _ = write!(out, "\n  {flag:<40} never blocked or limited");
+        }
+        for setting in self.settings() {
+            let flag = match setting.value {
+                Some(value) => format!("--{} {value}", setting.flag),
+                None => format!("--{}", setting.flag),
+            };
+            let _ = write!(out, "\n  {flag:<40} {}", setting.why);
+        }
+        out
+    }
+}
+
+fn join(cidrs: &[Ipv4Cidr]) -> String {
+    cidrs
+        .iter()
+        .map(Ipv4Cidr::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// The variable clap reads `--flag` from
+fn env_name(flag: &str) -> anyhow::Result<String> {
+    let command = Opt::command();
+    let arg = command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(flag))
+        .with_context(|| format!("no flag --{flag}"))?;
+    let env = arg
+        .get_env()
+        .with_context(|| format!("--{flag} has no variable"))?;
+    Ok(env.to_string_lossy().into_owned())
+}
+
+/// Runs `xdp-api-guard init`.
+pub fn init(opt: InitOpt) -> anyhow::Result<()> {
+    opt.preset.check(&opt.iface, &opt.mgmt_cidr)?;
+    let output = std::path::absolute(&opt.output)?;
+    let env = opt.preset.render(&opt.iface, &opt.mgmt_cidr, &output)?;
+    if opt.output.exists() && !opt.force {
+        bail!(
+            "{} exists, pass --force to replace it",
+            opt.output.display()
+        );
+    }
+    if let Some(dir) = opt
+        .output
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+    {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    write(&opt.output, &env, 0o600)?;
+    println!("{}", opt.preset.summary(&opt.iface, &opt.mgmt_cidr));
+    println!("\nwrote {}", opt.output.display());
+
+    if opt.install {
+        // Blocklist state holds client addresses, nobody else needs to read it
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(STATE_DIR)
+            .with_context(|| format!("failed to create {STATE_DIR}"))?;
+        let exe = std::env::current_exe().context("failed to find this binary")?;
+        let output = fs::canonicalize(&opt.output)?;
+        write(
+            Path::new(UNIT_PATH),
+            &unit(opt.preset, &exe, &output),
+            0o644,
+        )?;
+        println!("installed {UNIT_PATH}, start it with: systemctl enable --now xdp-api-guard");
+    } else {
+        println!("--install creates {STATE_DIR} and a systemd unit reading the file");
+    }
+    Ok(())
+}
+
+fn unit(preset: Preset, exe: &Path, env: &Path) -> String {
+    format!(
+        "[Unit]\n\
+         Description=XDP API guard ({} preset)\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\n\
+         [Service]\n\
+         EnvironmentFile={}\n\
+         Environment=RUST_LOG=info\n\
+         ExecStart={}\n\
+         Restart=on-failure\n\n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        preset.name(),
+        env.display(),
+        exe.display()
+    )
+}
+
+/// Sets the variable of `--flag` to `value` in the environment file at `path`, where the file
+/// set it or, with `why` above it, at the end. The new file is written next to the old one and
+/// renamed over it, with its mode.
+pub fn set_flag(path: &Path, flag: &str, value: &str, why: &str) -> anyhow::Result<()> {
+    let old =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mode = fs::metadata(path)?.permissions().mode() & 0o7777;
+    let contents = with_variable(&old, &env_name(flag)?, value, why);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".new");
+    let tmp = PathBuf::from(tmp);
+    // A leftover would keep its own mode
+    let _ = fs::remove_file(&tmp);
+    write(&tmp, &contents, mode)?;
+    // Past the umask
+    fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to replace {}", path.display()))
+}
+
+// `contents` with `var` set to `value`. systemd takes the last assignment, so the first one
+// gets the value and the others go.
+fn with_variable(contents: &str, var: &str, value: &str, why: &str) -> String {
+    let assignment = format!("{var}=");
+    let mut out = String::with_capacity(contents.len() + var.len() + value.len());
+    let mut set = false;
+    for line in contents.lines() {
+        if line.trim_start().starts_with(&assignment) {
+            if !set {
+                let _ = writeln!(out, "{var}={value}");
+                set = true;
+            }
+            continue;
+        }
+        let _ = writeln!(out, "{line}");
+    }
+    if !set {
+        if !out.is_empty() && !out.ends_with("\n\n") {
+            out.push('\n');
+        }
+        let _ = writeln!(out, "# {why}\n{var}={value}");
+    }
+    out
+}
+
+fn write(path: &Path, contents: &str, mode: u32) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_variable_is_set_where_the_file_set_it() {
+        let file = "# Interface\nGUARD_IFACE=eth0\n\n# Limit\nGUARD_RATE=50\n\nGUARD_PAUSED=true\n";
+        let out = with_variable(file, "GUARD_RATE", "120", "unused");
+        assert_eq!(
+            out,
+            "# Interface\nGUARD_IFACE=eth0\n\n# Limit\nGUARD_RATE=120\n\nGUARD_PAUSED=true\n"
+        );
+        // Later assignments would win, they go
+        let out = with_variable(
+            "GUARD_RATE=1\nGUARD_X=2\n  GUARD_RATE=3\n",
+            "GUARD_RATE",
+            "9",
+            "",
+        );
+        assert_eq!(out, "GUARD_RATE=9\nGUARD_X=2\n");
+        // A name that only starts the same is another variable
+        let out = with_variable("GUARD_RATE_LIMIT6=7\n", "GUARD_RATE", "9", "Suggested");
+        assert_eq!(out, "GUARD_RATE_LIMIT6=7\n\n# Suggested\nGUARD_RATE=9\n");
+    }
+
+    #[test]
+    fn a_variable_the_file_lacks_is_added_at_the_end() {
+        assert_eq!(
+            with_variable("", "GUARD_RATE", "9", "Why"),
+            "# Why\nGUARD_RATE=9\n"
+        );
+        let out = with_variable("GUARD_IFACE=eth0\n\n", "GUARD_RATE", "9", "Why");
+        assert_eq!(out, "GUARD_IFACE=eth0\n\n# Why\nGUARD_RATE=9\n");
+        // Commented out is not set
+        let out = with_variable("# GUARD_RATE=5\n", "GUARD_RATE", "9", "Why");
+        assert_eq!(out, "# GUARD_RATE=5\n\n# Why\nGUARD_RATE=9\n");
+    }
+
+    #[test]
+    fn presets_name_their_own_file_and_parse() {
+        let mgmt: [Ipv4Cidr; 1] = ["10.0.0.0/8".parse().unwrap()];
+        let path = Path::new("/etc/xdp-api-guard/guard.env");
+        for preset in Preset::value_variants() {
+            preset.check("eth0", &mgmt).unwrap();
+            let env = preset.render("eth0", &mgmt, path).unwrap();
+            assert!(env.contains("\nGUARD_ENV_FILE=/etc/xdp-api-guard/guard.env\n"));
+            assert!(env.contains("\nGUARD_IFACE=eth0\n"));
+            assert!(env.contains("\nGUARD_MGMT_CIDR=10.0.0.0/8\n"));
+            // Every line a comment, a blank or an assignment of a variable of Opt
+            for line in env.lines().filter(|l| !l.is_empty() && !l.starts_with('#')) {
+                let (var, _) = line.split_once('=').unwrap();
+                assert!(var.starts_with("GUARD_"), "{line}");
+            }
+        }
+    }
+
+    #[test]
+    fn set_flag_rewrites_the_file_and_keeps_its_mode() {
+        let path = std::env::temp_dir().join(format!("guard-env-{}-set", std::process::id()));
+        write(&path, "GUARD_IFACE=eth0\nGUARD_RATE=10\n", 0o640).unwrap();
+        set_flag(&path, "rate", "75", "unused").unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "GUARD_IFACE=eth0\nGUARD_RATE=75\n"
+        );
+        let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode, 0o640);
+        assert!(set_flag(&path, "rate", "75", "").is_err());
+        assert!(set_flag(Path::new("/dev/null"), "no-such-flag", "1", "").is_err());
+    }
+}
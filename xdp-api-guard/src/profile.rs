@@ -0,0 +1,95 @@
+//! `guardctl profile`: which way packets go through the program, counted for a few seconds.
+//!
+//! The datapath only counts paths while `config_flags::PROFILE` is set, so the counters are
+//! read before and after a sampling period and the difference is what gets reported.
+
+use std::{fmt::Write as _, sync::atomic::Ordering, time::Duration};
+
+use anyhow::bail;
+use aya::maps::{MapData, PerCpuArray};
+use log::{info, warn};
+use xdp_api_guard_common::{config_flags, path};
+
+use crate::control::ControlState;
+
+/// Upper bound for one profile, clients wait for the whole duration.
+pub const MAX_DURATION: Duration = Duration::from_secs(300);
+
+/// Turns sampling on for `duration` and renders the branch frequencies seen meanwhile.
+pub async fn run(state: &ControlState, duration: Duration) -> anyhow::Result<String> {
+    let before = read(&state.paths.lock().unwrap())?;
+    let _sampling = Sampling::start(state)?;
+    tokio::time::sleep(duration).await;
+    let after = read(&state.paths.lock().unwrap())?;
+    let counts: Vec<u64> = after
+        .iter()
+        .zip(&before)
+        .map(|(a, b)| a.saturating_sub(*b))
+        .collect();
+    Ok(render(&counts, duration))
+}
+
+/// Keeps `PROFILE` set while alive. Clearing it on drop covers a read of the counters that
+/// fails and the daemon shutting down mid-way. A client that goes away doesn't end a
+/// profile: the command runs its whole duration and the reply goes nowhere.
+pub struct Sampling<'a> {
+    state: &'a ControlState,
+}
+
+impl<'a> Sampling<'a> {
+    pub fn start(state: &'a ControlState) -> anyhow::Result<Self> {
+        // Two overlapping profiles would switch sampling off under each other
+        if state.profiling.swap(true, Ordering::AcqRel) {
+            bail!("a profile is already running");
+        }
+        let sampling = Sampling { state };
+        state
+            .config
+            .lock()
+            .unwrap()
+            .update(|cfg| cfg.flags |= config_flags::PROFILE)?;
+        info!("path profiling on");
+        Ok(sampling)
+    }
+}
+
+impl Drop for Sampling<'_> {
+    fn drop(&mut self) {
+        let result = self
+            .state
+            .config
+            .lock()
+            .unwrap()
+            .update(|cfg| cfg.flags &= !config_flags::PROFILE);
+        match result {
+            Ok(_) => info!("path profiling off"),
+            Err(e) => warn!("failed to switch path profiling off: {e:#}"),
+        }
+        self.state.profiling.store(false, Ordering::Release);
+    }
+}
+
+/// Per-path totals, summed across CPUs.
+fn read(map: &PerCpuArray<MapData, u64>) -> anyhow::Result<Vec<u64>> {
+    (0..path::LEN)
+        .map(|index| -> anyhow::Result<u64> { Ok(map.get(&index, 0)?.iter().sum()) })
+        .collect()
+}
+
+fn render(counts: &[u64], duration: Duration) -> String {
+    let packets = counts[path::PACKET as usize];
+    let mut out = format!("ok {packets} packets in {}s", duration.as_secs());
+    let _ = write!(out, "\n{:<20} {:>12} {:>7}", "path", "packets", "share");
+    for (index, (name, &count)) in path::NAMES.iter().zip(counts).enumerate() {
+        if index == path::PACKET as usize {
+            continue;
+        }
+        let share = if packets == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / packets as f64
+        };
+        let _ = write!(out, "\n{name:<20} {count:>12} {share:>6.1}%");
+    }
+    out
+}
@@ -0,0 +1,939 @@
+//! For the tests of the datapath: the program loaded the way the daemon loads it, with the
+//! settings of a command line, and frames run through it with `BPF_PROG_TEST_RUN` as
+//! `guardctl verify` does.
+//!
+//! Loading takes root and the BPF filesystem, so the tests using this are `#[ignore]`d; run
+//! them as root with `cargo test -- --ignored`. They take turns, the maps' pins are shared.
+
+use std::{
+    net::Ipv4Addr,
+    os::fd::AsFd as _,
+    sync::{Mutex, MutexGuard},
+};
+
+use aya::{
+    Ebpf, EbpfLoader,
+    maps::{Array, HashMap, MapData, PerCpuArray},
+    programs::Xdp,
+};
+use clap::Parser as _;
+use xdp_api_guard_common::{BLOOM_WORDS, PacketLog, VersionInfo, path};
+
+use crate::{
+    blocklist::BlocklistHandle,
+    config::ConfigHandle,
+    daemon::Opt,
+    maps::Maps,
+    resize::{self, Resizable, Slots},
+    timebase, trusted, verify,
+};
+
+pub const XDP_ABORTED: u32 = 0;
+pub const XDP_DROP: u32 = 1;
+pub const XDP_PASS: u32 = 2;
+
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// The loaded program and the maps tests look at.
+pub struct Program {
+    pub config: ConfigHandle,
+    pub blocklist: BlocklistHandle,
+    pub tags: HashMap<MapData, u32, u32>,
+    pub rate_limit: HashMap<MapData, u32, PacketLog>,
+    pub quic_initial: HashMap<MapData, u32, PacketLog>,
+    pub version_info: Array<MapData, VersionInfo>,
+    pub build_id: Option<Array<MapData, [u8; 16]>>,
+    pub protected_dsts: HashMap<MapData, u32, u8>,
+    pub tenant_stats: PerCpuArray<MapData, u64>,
+    stats: PerCpuArray<MapData, u64>,
+    paths: PerCpuArray<MapData, u64>,
+    ebpf: Ebpf,
+    /// What the program reads the blocklist and the tracking map through.
+    pub slots: Slots,
+    _serial: MutexGuard<'static, ()>,
+}
+
+impl Program {
+    /// Loads the program with the settings of the daemon's `args`, `--iface` aside.
+    pub fn load(args: &[&str]) -> Self {
+        let serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let mut argv = vec!["xdp-api-guard", "--iface", "lo"];
+        argv.extend_from_slice(args);
+        let opt = Opt::try_parse_from(argv).unwrap();
+
+        let (mut ebpf, slots) = open();
+        let maps = Maps::take(&mut ebpf).unwrap();
+        slots
+            .point(Resizable::Blocklist, maps.blocklist.map())
+            .unwrap();
+        slots
+            .point(Resizable::Tracking, maps.rate_limit.map())
+            .unwrap();
+        let program: &mut Xdp = ebpf
+            .program_mut("xdp_api_guard")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        program.load().unwrap();
+
+        let Maps {
+            config,
+            config_active,
+            version_info,
+            blocklist,
+            mgmt_cidrs,
+            stats,
+            paths,
+            tags,
+            rate_limit,
+            quic_initial,
+            build_id,
+            protected_dsts,
+            tenant_stats,
+            ..
+        } = maps;
+        Self {
+            config: ConfigHandle::new(config, config_active, opt.kernel_config()).unwrap(),
+            blocklist: BlocklistHandle::new(blocklist, mgmt_cidrs),
+            tags,
+            rate_limit,
+            quic_initial,
+            version_info,
+            build_id,
+            protected_dsts,
+            tenant_stats,
+            stats,
+            paths,
+            ebpf,
+            slots,
+            _serial: serial,
+        }
+    }
+
+    /// The verdict on `frame`.
+    pub fn run(&self, frame: &[u8]) -> u32 {
+        let program: &Xdp = self
+            .ebpf
+            .program("xdp_api_guard")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        verify::test_run(program.fd().unwrap().as_fd(), frame).unwrap()
+    }
+
+    /// `stat::*` counter `index`, summed over the CPUs.
+    pub fn stat(&self, index: u32) -> u64 {
+        self.stats.get(&index, 0).unwrap().iter().sum()
+    }
+
+    /// How often `path::*` `index` was taken, summed over the CPUs. Only counted while
+    /// `config_flags::PROFILE` is set.
+    pub fn path(&self, index: u32) -> u64 {
+        assert!(index < path::LEN);
+        self.paths.get(&index, 0).unwrap().iter().sum()
+    }
+}
+
+/// The daemon's state on maps of its own, for the tests of what drives it: the cluster, the
+/// policy modules. The maps are created as for `Program::load`, the program isn't loaded, and
+/// everything optional is off.
+#[cfg(any(feature = "cluster", feature = "wasm-policy"))]
+pub fn control() -> std::sync::Arc<crate::control::ControlState> {
+    use std::sync::{Arc, atomic::AtomicBool};
+
+    use crate::{
+        control::ControlState,
+        groups::Groups,
+        replay::ReplayCache,
+        rules::Rules,
+        stats::StatsState,
+        tenants::{Tenants, Thresholds},
+        verifier,
+        version::Versions,
+    };
+
+    let opt = Opt::try_parse_from(["xdp-api-guard", "--iface", "lo"]).unwrap();
+    let (mut ebpf, _) = {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        open()
+    };
+    let mut maps = Maps::take(&mut ebpf).unwrap();
+    let embedded = maps.build_id.as_ref().map(|id| id.get(&0, 0).unwrap());
+    let versions = Versions::install(&mut maps.version_info, object(), embedded).unwrap();
+    let thresholds = Thresholds {
+        elevated_pps: 10_000,
+        mitigating_pps: 100,
+    };
+    Arc::new(ControlState {
+        blocklist: Mutex::new(BlocklistHandle::new(maps.blocklist, maps.mgmt_cidrs)),
+        tags: Mutex::new(maps.tags),
+        rate_limit: Mutex::new(maps.rate_limit),
+        rate_limit6: Mutex::new(maps.rate_limit6),
+        prefix_sources: Mutex::new(maps.prefix_sources),
+        prefix: Mutex::new(maps.prefix),
+        nat: Mutex::new(maps.nat),
+        nat_prefixes: Vec::new(),
+        quic_initial: Mutex::new(maps.quic_initial),
+        conntrack: maps.conntrack.map(Mutex::new),
+        ack: maps.ack.map(Mutex::new),
+        http: maps.http.map(Mutex::new),
+        service: maps.service.map(Mutex::new),
+        conversions: None,
+        rules: Mutex::new(Rules::install(maps.service_rates, maps.dscp_policy, &[], &[]).unwrap()),
+        groups: Mutex::new(Groups::new(
+            maps.group_cidrs,
+            maps.group_policy,
+            maps.group_stats,
+        )),
+        tenants: Mutex::new(Tenants::new(
+            maps.protected_dsts,
+            maps.tenant_stats,
+            thresholds,
+        )),
+        stats: Arc::new(Mutex::new(StatsState::new(
+            maps.stats,
+            maps.drop_buckets,
+            300,
+            1.0,
+            Vec::new(),
+        ))),
+        config: Mutex::new(
+            ConfigHandle::new(maps.config, maps.config_active, opt.kernel_config()).unwrap(),
+        ),
+        relax: None,
+        learner: None,
+        next_prog: Mutex::new(maps.next_prog),
+        versions,
+        paths: Mutex::new(maps.paths),
+        last_abort: Mutex::new(maps.last_abort),
+        profiling: AtomicBool::new(false),
+        external: false,
+        program: None,
+        slots: None,
+        verifier: verifier::Stats::default(),
+        multi_buffer: false,
+        chained: None,
+        replay: ReplayCache::default(),
+        geo: None,
+        snapshot_dir: None,
+        env_file: None,
+    })
+}
+
+fn object() -> &'static [u8] {
+    aya::include_bytes_aligned!(concat!(env!("OUT_DIR"), "/xdp-api-guard"))
+}
+
+// The object with its maps created the way the daemon creates them, and the slots they are
+// read through
+fn open() -> (Ebpf, Slots) {
+    trusted::prepare(None).unwrap();
+    let slots = Slots::prepare().unwrap();
+    let boot_clock = timebase::kernel_has_boot_ns();
+    timebase::set_boot_stamps(boot_clock);
+    let loaded = EbpfLoader::new()
+        .map_pin_path(trusted::PIN_DIR)
+        .set_max_entries("BLOOM", 2 * BLOOM_WORDS)
+        .set_max_entries("MALFORMED_SAMPLES", 4096)
+        .set_global("BOOT_CLOCK", &u8::from(boot_clock), true)
+        .allow_unsupported_maps()
+        .load(object());
+    trusted::unpin();
+    resize::unpin();
+    (loaded.unwrap(), slots)
+}
+
+/// An Ethernet frame with an IPv4 packet, built up a field at a time.
+#[derive(Clone)]
+pub struct Frame {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub proto: u8,
+    /// Padded to whole words.
+    pub ip_options: Vec<u8>,
+    /// Flags and fragment offset, as in the header.
+    pub frag: u16,
+    pub l4: Vec<u8>,
+}
+
+impl Frame {
+    pub fn udp(src: Ipv4Addr, dport: u16, payload: &[u8]) -> Self {
+        let mut udp = Vec::with_capacity(8 + payload.len());
+        udp.extend_from_slice(&40000u16.to_be_bytes());
+        udp.extend_from_slice(&dport.to_be_bytes());
+        udp.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        udp.extend_from_slice(&[0; 2]);
+        udp.extend_from_slice(payload);
+        Self::ipv4(src, 17, udp)
+    }
+
+    /// A segment with `flags` (byte 13) and `options`, padded to whole words.
+    pub fn tcp(src: Ipv4Addr, dport: u16, flags: u8, options: &[u8]) -> Self {
+        let mut options = options.to_vec();
+        options.resize(options.len().div_ceil(4) * 4, 0);
+        let mut tcp = Vec::with_capacity(20 + options.len());
+        tcp.extend_from_slice(&40000u16.to_be_bytes());
+        tcp.extend_from_slice(&dport.to_be_bytes());
+        tcp.extend_from_slice(&1u32.to_be_bytes());
+        tcp.extend_from_slice(&0u32.to_be_bytes());
+        tcp.extend_from_slice(&[((20 + options.len()) / 4 << 4) as u8, flags]);
+        tcp.extend_from_slice(&64240u16.to_be_bytes());
+        tcp.extend_from_slice(&[0; 4]);
+        tcp.extend_from_slice(&options);
+        Self::ipv4(src, 6, tcp)
+    }
+
+    fn ipv4(src: Ipv4Addr, proto: u8, l4: Vec<u8>) -> Self {
+        Self {
+            src,
+            dst: Ipv4Addr::new(192, 0, 2, 1),
+            proto,
+            ip_options: Vec::new(),
+            frag: 0,
+            l4,
+        }
+    }
+
+    pub fn ip_options(mut self, options: &[u8]) -> Self {
+        self.ip_options = options.to_vec();
+        self.ip_options.resize(options.len().div_ceil(4) * 4, 0);
+        self
+    }
+
+    /// A fragment `offset` eight-byte units in.
+    pub fn fragment(mut self, offset: u16) -> Self {
+        self.frag = offset & 0x1fff;
+        self
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        let hdr_len = 20 + self.ip_options.len();
+        let mut ip = vec![0u8; hdr_len];
+        ip[0] = 0x40 | (hdr_len / 4) as u8;
+        ip[2..4].copy_from_slice(&((hdr_len + self.l4.len()) as u16).to_be_bytes());
+        ip[6..8].copy_from_slice(&self.frag.to_be_bytes());
+        ip[8] = 64;
+        ip[9] = self.proto;
+        ip[12..16].copy_from_slice(&self.src.octets());
+        ip[16..20].copy_from_slice(&self.dst.octets());
+        ip[20..].copy_from_slice(&self.ip_options);
+        let mut sum: u32 = ip
+            .chunks(2)
+            .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+            .sum();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        ip[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+
+        // Locally administered addresses, then IPv4
+        let mut frame = vec![0x02, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0x02, 0x08, 0x00];
+        frame.extend_from_slice(&ip);
+        frame.extend_from_slice(&self.l4);
+        frame
+    }
+}
+
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    use aya::maps::Map;
+    use xdp_api_guard_common::{
+        ACTION_ALLOW, AbortRecord, Origin, abort, config_flags, feature, malformed,
+        malformed_action, stat, tenant_stat,
+    };
+
+    use super::*;
+
+    use crate::{
+        blocklist::Applied,
+        cidr::Ipv4Cidr,
+        sweep,
+        version::{self, Versions},
+    };
+
+    const SRC: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+    // A long header's first byte, then the version
+    const INITIAL: &[u8] = &[0xc0, 0, 0, 0, 1];
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn quic_initials_over_their_budget_drop() {
+        let mut program = Program::load(&["--rate", "100", "--quic-initial-limit", "2"]);
+        let initial = Frame::udp(SRC, 443, INITIAL).bytes();
+        assert_eq!(program.run(&initial), XDP_PASS);
+        assert_eq!(program.run(&initial), XDP_PASS);
+        assert_eq!(program.run(&initial), XDP_DROP);
+        assert_eq!(program.stat(stat::QUIC_INITIAL), 3);
+        assert_eq!(program.stat(stat::DROP_QUIC_INITIAL), 1);
+        // Short headers are left to the normal limiter
+        assert_eq!(
+            program.run(&Frame::udp(SRC, 443, &[0x40, 0]).bytes()),
+            XDP_PASS
+        );
+        assert_eq!(program.stat(stat::QUIC_INITIAL), 3);
+        // Off, the payload isn't looked at
+        program
+            .config
+            .update(|cfg| cfg.quic_initial_limit = 0)
+            .unwrap();
+        assert_eq!(program.run(&initial), XDP_PASS);
+        assert_eq!(program.stat(stat::QUIC_INITIAL), 3);
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn quic_initials_within_their_budget_are_charged_to_the_source() {
+        let program = Program::load(&["--rate", "3", "--quic-initial-limit", "100"]);
+        let initial = Frame::udp(SRC, 443, INITIAL).bytes();
+        for _ in 0..3 {
+            assert_eq!(program.run(&initial), XDP_PASS);
+        }
+        assert_eq!(program.run(&initial), XDP_DROP);
+        assert_eq!(program.stat(stat::DROP_QUIC_INITIAL), 0);
+        let key = u32::from(SRC);
+        assert!(program.rate_limit.get(&key, 0).unwrap().count >= 3);
+        assert!(program.quic_initial.get(&key, 0).is_ok());
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn quic_initials_of_tagged_sources_get_the_tagged_limit() {
+        let mut program = Program::load(&["--rate", "10", "--quic-initial-limit", "100"]);
+        // Half the score, half the limit
+        program.tags.insert(u32::from(SRC), 50, 0).unwrap();
+        let initial = Frame::udp(SRC, 443, INITIAL).bytes();
+        for _ in 0..5 {
+            assert_eq!(program.run(&initial), XDP_PASS);
+        }
+        assert_eq!(program.run(&initial), XDP_DROP);
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn quic_check_passes_what_it_cant_read() {
+        let program = Program::load(&["--rate", "100", "--quic-initial-limit", "1"]);
+        // A UDP header cut short, consistent with the IP header's length
+        let mut short = Frame::udp(SRC, 443, &[]);
+        short.l4.truncate(4);
+        // A later fragment whose payload happens to start like a long header
+        let mut fragment = Frame::udp(SRC, 443, INITIAL).fragment(1);
+        fragment.l4 = [&[0u8; 8][..], INITIAL].concat();
+        for frame in [short, fragment] {
+            let frame = frame.bytes();
+            assert_ne!(program.run(&frame), XDP_ABORTED);
+            assert_eq!(program.run(&frame), XDP_PASS);
+        }
+        assert_eq!(program.stat(stat::QUIC_INITIAL), 0);
+        assert_eq!(program.stat(stat::ABORTED), 0);
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn quic_header_is_found_past_ip_options() {
+        let program = Program::load(&["--rate", "100", "--quic-initial-limit", "1"]);
+        // Two NOPs and an end of options list, padded to a word
+        let initial = Frame::udp(SRC, 443, INITIAL).ip_options(&[1, 1, 0]).bytes();
+        assert_eq!(program.run(&initial), XDP_PASS);
+        assert_eq!(program.run(&initial), XDP_DROP);
+        assert_eq!(program.stat(stat::QUIC_INITIAL), 2);
+    }
+
+    const SYN: u8 = 0x02;
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn syns_below_the_minimum_mss_drop() {
+        let program = Program::load(&[
+            "--rate",
+            "100",
+            "--min-mss",
+            "536",
+            "--min-mss-action",
+            "drop",
+        ]);
+        let syn = |options: &[u8]| Frame::tcp(SRC, 80, SYN, options).bytes();
+        assert_eq!(program.run(&syn(&[2, 4, 0x05, 0xb4])), XDP_PASS);
+        assert_eq!(program.run(&syn(&[2, 4, 0, 64])), XDP_DROP);
+        assert_eq!(program.stat(stat::DROP_TINY_MSS), 1);
+        // Without an MSS option, or with one of the wrong length, there's nothing to judge
+        assert_eq!(program.run(&syn(&[])), XDP_PASS);
+        assert_eq!(program.run(&syn(&[2, 3, 0, 64])), XDP_PASS);
+        assert_eq!(program.run(&syn(&[1, 2, 0])), XDP_PASS);
+        assert_eq!(program.stat(stat::TINY_MSS), 1);
+        assert_eq!(program.stat(stat::ABORTED), 0);
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn cut_short_tcp_headers_pass_on_to_the_limits() {
+        let mut program = Program::load(&["--rate", "2"]);
+        let mut short = Frame::tcp(SRC, 80, SYN, &[]);
+        short.l4.truncate(10);
+        let short = short.bytes();
+        assert_eq!(program.run(&short), XDP_PASS);
+        assert_eq!(program.run(&short), XDP_PASS);
+        assert_eq!(program.run(&short), XDP_DROP);
+        assert_eq!(program.stat(stat::MALFORMED + malformed::TRUNCATED_L4), 3);
+        assert_eq!(program.stat(stat::ABORTED), 0);
+        // Unless asked to abort them
+        program
+            .config
+            .update(|cfg| {
+                cfg.malformed_action[malformed::TRUNCATED_L4 as usize] = malformed_action::ABORT
+            })
+            .unwrap();
+        assert_eq!(program.run(&short), XDP_ABORTED);
+    }
+
+    const ACK: u8 = 0x10;
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn cut_short_acks_pass_and_arent_charged() {
+        let program = Program::load(&["--rate", "100", "--ack-limit", "2"]);
+        let ack = Frame::tcp(SRC, 80, ACK, &[]);
+        // Cut inside the fixed header, options past the end, a data offset inside the header
+        let mut short = ack.clone();
+        short.l4.truncate(10);
+        let mut long = ack.clone();
+        long.l4[12] = 15 << 4;
+        let mut low = ack.clone();
+        low.l4[12] = 4 << 4;
+        for frame in [short, long, low] {
+            let frame = frame.bytes();
+            for _ in 0..5 {
+                assert_eq!(program.run(&frame), XDP_PASS);
+            }
+        }
+        assert_eq!(program.stat(stat::MALFORMED + malformed::TRUNCATED_L4), 15);
+        assert_eq!(program.stat(stat::DROP_ACK_FLOOD), 0);
+        assert_eq!(program.stat(stat::ABORTED), 0);
+        // Whole ones still are
+        let ack = ack.bytes();
+        assert_eq!(program.run(&ack), XDP_PASS);
+        assert_eq!(program.run(&ack), XDP_PASS);
+        assert_eq!(program.run(&ack), XDP_DROP);
+        assert_eq!(program.stat(stat::DROP_ACK_FLOOD), 1);
+    }
+
+    // The newest record of any CPU, the run was on one of them
+    fn last_abort(program: &Program) -> AbortRecord {
+        let map = program.ebpf.map("LAST_ABORT").unwrap();
+        let records: PerCpuArray<&MapData, AbortRecord> = PerCpuArray::try_from(map).unwrap();
+        let records = records.get(&0, 0).unwrap();
+        *records.iter().max_by_key(|record| record.at).unwrap()
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn aborts_point_at_the_header_that_didnt_fit() {
+        let mut program = Program::load(&[]);
+        program
+            .config
+            .update(|cfg| {
+                cfg.malformed_action[malformed::TRUNCATED_L4 as usize] = malformed_action::ABORT
+            })
+            .unwrap();
+        let udp = Frame::udp(SRC, 53, &[]).bytes();
+        let mut low_ihl = udp.clone();
+        low_ihl[14] = 0x44;
+        // With IP options, so the TCP header starts past the fixed IPv4 one
+        let tcp = Frame::tcp(SRC, 80, ACK, &[]).ip_options(&[1, 1, 1, 1]);
+        let mut short = tcp.clone();
+        short.l4.truncate(19);
+        let mut long = tcp.clone();
+        long.l4[12] = 6 << 4;
+        let mut low = tcp.clone();
+        low.l4[12] = 4 << 4;
+        // BPF_PROG_TEST_RUN takes no frame shorter than an Ethernet header
+        let cases = [
+            (udp[..14 + 19].to_vec(), abort::IPV4, 14),
+            (low_ihl, abort::IPV4, 14),
+            (short.bytes(), abort::TCP, 38),
+            (long.bytes(), abort::TCP, 38),
+            (low.bytes(), abort::TCP, 38),
+        ];
+        let aborts = cases.len() as u64;
+        for (i, (frame, reason, offset)) in cases.into_iter().enumerate() {
+            assert_eq!(program.run(&frame), XDP_ABORTED, "{i}");
+            let record = last_abort(&program);
+            assert_eq!((record.reason, record.offset), (reason, offset), "{i}");
+            assert_eq!(usize::from(record.len), frame.len(), "{i}");
+        }
+        assert_eq!(program.stat(stat::ABORTED), aborts);
+        // A whole header right up to the end of the frame is no abort
+        assert_eq!(program.run(&tcp.bytes()), XDP_PASS);
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn later_tcp_fragments_arent_read_as_headers() {
+        let program = Program::load(&["--rate", "100", "--min-mss-action", "drop"]);
+        // Payload that would read as a SYN with a tiny MSS
+        let mut fragment = Frame::tcp(SRC, 80, SYN, &[2, 4, 0, 64]).fragment(1);
+        fragment.l4.truncate(12);
+        let fragment = fragment.bytes();
+        assert_eq!(program.run(&fragment), XDP_PASS);
+        assert_eq!(program.stat(stat::MALFORMED + malformed::TRUNCATED_L4), 0);
+        assert_eq!(program.stat(stat::TINY_MSS), 0);
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn the_object_carries_the_build_id_of_the_binary() {
+        let mut program = Program::load(&[]);
+        let embedded = program.build_id.as_ref().unwrap().get(&0, 0).unwrap();
+        assert_ne!(version::BUILD_ID, [0; 16]);
+        assert_eq!(embedded, version::BUILD_ID);
+        let versions = Versions::install(&mut program.version_info, &[], Some(embedded)).unwrap();
+        assert_eq!(versions.mismatch(), None);
+    }
+
+    // As `guardctl profile` switches it
+    fn profiling(program: &mut Program, on: bool) {
+        program
+            .config
+            .update(|cfg| {
+                if on {
+                    cfg.flags |= config_flags::PROFILE
+                } else {
+                    cfg.flags &= !config_flags::PROFILE
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn paths_are_counted_only_while_profiling() {
+        let mut program = Program::load(&["--rate", "100"]);
+        let packet = Frame::udp(SRC, 53, &[0; 12]).bytes();
+        assert_eq!(program.run(&packet), XDP_PASS);
+        assert_eq!(program.path(path::PACKET), 0);
+        assert_eq!(program.path(path::LIMIT_INSERT), 0);
+        profiling(&mut program, true);
+        for _ in 0..3 {
+            assert_eq!(program.run(&packet), XDP_PASS);
+        }
+        assert_eq!(program.path(path::PACKET), 3);
+        // The source's entry from before, in its window
+        assert_eq!(program.path(path::LIMIT_FAST), 3);
+        assert_eq!(program.path(path::LIMIT_INSERT), 0);
+        profiling(&mut program, false);
+        assert_eq!(program.run(&packet), XDP_PASS);
+        assert_eq!(program.path(path::PACKET), 3);
+        assert_eq!(program.path(path::LIMIT_FAST), 3);
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn limiter_paths_are_only_the_per_source_limiters() {
+        let mut program = Program::load(&["--rate", "100", "--quic-initial-limit", "100"]);
+        profiling(&mut program, true);
+        let initial = Frame::udp(SRC, 443, INITIAL).bytes();
+        assert_eq!(program.run(&initial), XDP_PASS);
+        assert_eq!(program.run(&initial), XDP_PASS);
+        assert_eq!(program.path(path::PACKET), 2);
+        assert_eq!(program.path(path::QUIC_INITIAL), 2);
+        // Both budgets were charged, the path counts are the source entry's alone
+        assert!(program.quic_initial.get(&u32::from(SRC), 0).is_ok());
+        assert_eq!(program.path(path::LIMIT_INSERT), 1);
+        assert_eq!(program.path(path::LIMIT_FAST), 1);
+        assert_eq!(program.path(path::LIMIT_RESET), 0);
+    }
+
+    // The verdicts of every pair of origins deciding on SRC, in both orders, and what the
+    // handle then holds
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn the_higher_origin_wins_in_the_kernel_too() {
+        let mut program = Program::load(&["--rate", "1000000"]);
+        let packet = Frame::udp(SRC, 53, &[0; 12]).bytes();
+        let network = Ipv4Cidr::new(Ipv4Addr::new(198, 51, 100, 0), 24).unwrap();
+        let decide = |program: &mut Program, origin| {
+            if origin == Origin::Management {
+                program.blocklist.add_management(network).unwrap();
+                Applied::Written
+            } else {
+                program.blocklist.insert(SRC, origin).unwrap()
+            }
+        };
+        for first in Origin::ALL {
+            for second in Origin::ALL.into_iter().filter(|&o| o != first) {
+                let pair = format!("{} then {}", first.name(), second.name());
+                assert_eq!(decide(&mut program, first), Applied::Written, "{pair}");
+                let applied = decide(&mut program, second);
+                let winner = first.max(second);
+                if second < first {
+                    assert_eq!(applied, Applied::Suppressed(first), "{pair}");
+                }
+                assert_eq!(program.blocklist.effective(SRC), Some(winner), "{pair}");
+                let verdict = if winner.action() == ACTION_ALLOW {
+                    XDP_PASS
+                } else {
+                    XDP_DROP
+                };
+                assert_eq!(program.run(&packet), verdict, "{pair}");
+                program.blocklist.remove(SRC).unwrap();
+                program.blocklist.remove_management(network).unwrap();
+            }
+        }
+        assert_eq!(program.run(&packet), XDP_PASS);
+    }
+
+    // Sources keep sending while both maps are grown twice: every verdict stays what it was
+    // and every entry makes it into the new maps
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn verdicts_dont_flap_across_resizes() {
+        // A window that doesn't end during the test
+        let mut program = Program::load(&["--rate", "50", "--window", "600000"]);
+        let sources = |group, n: u8| (1..=n).map(move |n| Ipv4Addr::new(198, 18, group, n));
+        let blocked: Vec<Ipv4Addr> = sources(1, 100).collect();
+        let heavy: Vec<Ipv4Addr> = sources(2, 50).collect();
+        let light: Vec<Ipv4Addr> = sources(3, 100).collect();
+        let packet = |ip| Frame::udp(ip, 53, &[0; 12]).bytes();
+        for &ip in &blocked {
+            let applied = program.blocklist.insert(ip, Origin::ManualBlock).unwrap();
+            assert_eq!(applied, Applied::Written);
+        }
+        // Over their limit from here on
+        for &ip in &heavy {
+            let passed = (0..1000)
+                .take_while(|_| program.run(&packet(ip)) == XDP_PASS)
+                .count();
+            assert_eq!(passed, 50, "{ip}");
+        }
+        let max_entries = |map: &MapData| map.info().unwrap().max_entries();
+        for round in 0..12 {
+            for (ips, verdict) in [(&blocked, XDP_DROP), (&heavy, XDP_DROP), (&light, XDP_PASS)] {
+                for &ip in ips {
+                    assert_eq!(program.run(&packet(ip)), verdict, "{ip} in round {round}");
+                }
+            }
+            match round {
+                2 | 8 => {
+                    let size = max_entries(program.blocklist.kernel_map().map());
+                    let copied =
+                        resize::grow_blocklist(&program.slots, &mut program.blocklist, size * 2)
+                            .unwrap();
+                    assert_eq!(copied, blocked.len());
+                    let grown = max_entries(program.blocklist.kernel_map().map());
+                    assert_eq!(grown, size * 2);
+                }
+                4 | 10 => {
+                    let size = max_entries(program.rate_limit.map());
+                    let copied =
+                        resize::grow_tracking(&program.slots, &mut program.rate_limit, size * 2)
+                            .unwrap();
+                    assert_eq!(copied, heavy.len() + light.len());
+                    assert_eq!(max_entries(program.rate_limit.map()), size * 2);
+                }
+                _ => {}
+            }
+            for &ip in &blocked {
+                let entry = program.blocklist.kernel_entry(u32::from(ip)).unwrap();
+                assert!(entry.is_some(), "{ip} left the blocklist in round {round}");
+            }
+            for &ip in heavy.iter().chain(&light) {
+                let log = program.rate_limit.get(&u32::from(ip), 0);
+                assert!(log.is_ok(), "{ip} left the tracking map in round {round}");
+            }
+        }
+    }
+
+    // The daemon's tasks at once on the same maps, each through its own handle as the daemon
+    // has them: commands writing the blocklist, the sampler reading the counters, the
+    // sweeper deleting limiter entries, and packets coming in all the while
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn four_tasks_share_the_maps() {
+        const ROUNDS: u64 = 20;
+        let mut program = Program::load(&["--rate", "1000000"]);
+        let xdp: &Xdp = program
+            .ebpf
+            .program("xdp_api_guard")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let fd = xdp.fd().unwrap().as_fd().try_clone_to_owned().unwrap();
+        let tracking = program.rate_limit.map().fd().as_fd().try_clone_to_owned();
+        let tracking = MapData::from_fd(tracking.unwrap()).unwrap();
+        let tracking = Some(Mutex::new(
+            HashMap::<_, u32, PacketLog>::try_from(Map::HashMap(tracking)).unwrap(),
+        ));
+        let blocklist = Mutex::new(&mut program.blocklist);
+        let stats = &program.stats;
+        let done = AtomicBool::new(false);
+        let sources: Vec<Ipv4Addr> = (1..=200).map(|n| Ipv4Addr::new(198, 18, 0, n)).collect();
+        let banned = Ipv4Addr::new(198, 18, 1, 1);
+
+        thread::scope(|scope| {
+            let control = scope.spawn(|| {
+                for _ in 0..500 {
+                    let mut blocklist = blocklist.lock().unwrap();
+                    let applied = blocklist.insert(banned, Origin::ManualBlock).unwrap();
+                    assert_eq!(applied, Applied::Written);
+                    assert_eq!(blocklist.remove(banned).unwrap(), Some(Origin::ManualBlock));
+                }
+            });
+            let sampler = scope.spawn(|| {
+                let (mut last, mut samples) = (0, 0);
+                while !done.load(Ordering::Relaxed) {
+                    let seen: u64 = [stat::PASS, stat::DROP]
+                        .iter()
+                        .map(|index| stats.get(index, 0).unwrap().iter().sum::<u64>())
+                        .sum();
+                    assert!(seen >= last, "the counters went back from {last} to {seen}");
+                    (last, samples) = (seen, samples + 1);
+                }
+                samples
+            });
+            // Everything is idle for longer than no time at all
+            let sweeper = scope.spawn(|| {
+                let mut sweeps = 0;
+                while !done.load(Ordering::Relaxed) {
+                    sweep::sweep_if(&tracking, 0).unwrap();
+                    sweeps += 1;
+                }
+                sweeps
+            });
+            for _ in 0..ROUNDS {
+                for &ip in sources.iter().chain([&banned]) {
+                    let frame = Frame::udp(ip, 53, &[0; 12]).bytes();
+                    let verdict = verify::test_run(fd.as_fd(), &frame).unwrap();
+                    // The banned source comes and goes, the others always pass
+                    if ip == banned {
+                        assert_ne!(verdict, XDP_ABORTED);
+                    } else {
+                        assert_eq!(verdict, XDP_PASS, "{ip}");
+                    }
+                }
+            }
+            control.join().unwrap();
+            done.store(true, Ordering::Relaxed);
+            assert!(sampler.join().unwrap() > 0);
+            assert!(sweeper.join().unwrap() > 0);
+        });
+        drop(blocklist);
+        let verdicts = ROUNDS * (sources.len() as u64 + 1);
+        assert_eq!(
+            program.stat(stat::PASS) + program.stat(stat::DROP),
+            verdicts
+        );
+        assert!(
+            program
+                .blocklist
+                .kernel_entry(u32::from(banned))
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(program.blocklist.effective(banned), None);
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn packets_to_a_protected_address_count_toward_its_tenant() {
+        let vip = Ipv4Addr::new(192, 0, 2, 1);
+        let mut program = Program::load(&["--rate", "2", "--tenant", "192.0.2.1"]);
+        // As `Tenants::add` puts the first one there
+        program.protected_dsts.insert(u32::from(vip), 1, 0).unwrap();
+        let to_vip = Frame::udp(SRC, 53, &[0; 10]).bytes();
+        let mut elsewhere = Frame::udp(SRC, 53, &[]);
+        elsewhere.dst = Ipv4Addr::new(192, 0, 2, 2);
+        assert_eq!(program.run(&elsewhere.bytes()), XDP_PASS);
+        assert_eq!(program.run(&to_vip), XDP_PASS);
+        assert_eq!(program.run(&to_vip), XDP_DROP);
+        let blocked = Ipv4Addr::new(198, 51, 100, 8);
+        program
+            .blocklist
+            .insert(blocked, Origin::ManualBlock)
+            .unwrap();
+        let from_blocked = Frame::udp(blocked, 53, &[]).bytes();
+        assert_eq!(program.run(&from_blocked), XDP_DROP);
+
+        let tenant = |slot| -> u64 {
+            let index = tenant_stat::PER_TENANT + slot;
+            program.tenant_stats.get(&index, 0).unwrap().iter().sum()
+        };
+        assert_eq!(tenant(tenant_stat::PACKETS), 3);
+        let bytes = 2 * to_vip.len() + from_blocked.len();
+        assert_eq!(tenant(tenant_stat::BYTES), bytes as u64);
+        for f in 0..feature::LEN {
+            let expected = u64::from(f == feature::RATE_LIMIT || f == feature::BLOCKLIST);
+            let drops = tenant(tenant_stat::FEATURE_DROPS + f);
+            assert_eq!(drops, expected, "{}", feature::NAMES[f as usize]);
+        }
+        let sources: u32 = (0..tenant_stat::SOURCE_WORDS)
+            .map(|word| tenant(tenant_stat::SOURCES + word).count_ones())
+            .sum();
+        assert!((1..=2).contains(&sources), "{sources}");
+        // Nothing went to the slots of no tenant
+        let none: u64 = (0..tenant_stat::PER_TENANT)
+            .map(|slot| {
+                program
+                    .tenant_stats
+                    .get(&slot, 0)
+                    .unwrap()
+                    .iter()
+                    .sum::<u64>()
+            })
+            .sum();
+        assert_eq!(none, 0);
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn blocked_sources_keep_their_window_only_when_accounted() {
+        let frame = Frame::udp(SRC, 53, &[]).bytes();
+        for accounted in [true, false] {
+            let mut args = vec!["--rate", "2"];
+            if accounted {
+                args.push("--account-blocked-in-tracking");
+            }
+            let mut program = Program::load(&args);
+            program.blocklist.insert(SRC, Origin::ManualBlock).unwrap();
+            for _ in 0..3 {
+                assert_eq!(program.run(&frame), XDP_DROP);
+            }
+            // Counted by the limiter is not dropped by it as well
+            assert_eq!(program.stat(stat::DROP), 3);
+            let count = program
+                .rate_limit
+                .get(&u32::from(SRC), 0)
+                .ok()
+                .map(|log| log.count);
+            assert_eq!(count, accounted.then_some(3), "{accounted}");
+
+            program.blocklist.remove(SRC).unwrap();
+            let verdict = if accounted { XDP_DROP } else { XDP_PASS };
+            assert_eq!(program.run(&frame), verdict, "{accounted}");
+            assert_eq!(program.stat(stat::DROP), 3 + u64::from(accounted));
+        }
+    }
+
+    #[test]
+    #[ignore = "loads the program, needs root"]
+    fn idle_entries_are_replaced_by_the_packet_that_finds_them() {
+        let mut program = Program::load(&["--rate", "2", "--tracking-idle-secs", "1"]);
+        // Well over the limit, but last seen a moment after boot
+        let stale = PacketLog {
+            count: 100,
+            last_seen: 1,
+            first_seen: 1,
+            credit: 0,
+            retransmits: 0,
+        };
+        let key = u32::from(SRC);
+        program.rate_limit.insert(key, stale, 0).unwrap();
+        assert_eq!(program.run(&Frame::udp(SRC, 53, &[]).bytes()), XDP_PASS);
+        assert_eq!(program.stat(stat::EXPIRED_INLINE), 1);
+        let fresh = program.rate_limit.get(&key, 0).unwrap();
+        assert_eq!(fresh.count, 1);
+        assert!(fresh.first_seen > 1 && fresh.first_seen == fresh.last_seen);
+    }
+}
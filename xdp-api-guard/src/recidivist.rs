@@ -0,0 +1,179 @@
+//! `--recidivist-after`: sources that wait out their ban and come straight back.
+//!
+//! The `BlocklistHandle` keeps the TTL bans of every source, and a source banned again
+//! within `--recidivist-window` of its last ban running out has returned. After
+//! `--recidivist-after` returns in a row the new ban is replaced by one of
+//! `--recidivist-ttl`, permanent by default. Histories go into the state file with the
+//! blocklist, and are forgotten once the last ban is over for longer than the window.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use xdp_api_guard_common::Origin;
+
+use crate::bounded::Weigh;
+
+/// Bans kept per source, the oldest go first.
+pub const MAX_BANS: usize = 32;
+
+/// Sources with a history, past it those not banned right now are forgotten first.
+pub const MAX_SOURCES: usize = 100_000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Recidivists {
+    /// Returns in a row that escalate.
+    pub after: u32,
+    /// Seconds after a ban ran out within which a new one counts as a return.
+    pub window: u64,
+    /// Seconds of the escalated ban, 0 for a permanent one.
+    pub ttl: u64,
+}
+
+impl Recidivists {
+    /// When an escalated ban placed at `now` runs out.
+    pub fn expires(&self, now: u64) -> Option<u64> {
+        (self.ttl != 0).then(|| now + self.ttl)
+    }
+}
+
+/// Whether bans of `origin` count. Cluster bans are the other nodes' to escalate, and feeds
+/// don't have a TTL.
+pub fn tracked(origin: Origin) -> bool {
+    matches!(
+        origin,
+        Origin::AutoBan | Origin::Policy | Origin::ManualBlock
+    )
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct History {
+    /// Oldest first, at most `MAX_BANS`.
+    pub bans: Vec<Ban>,
+    /// Bans in a row that came within the window of the one before running out.
+    pub returns: u32,
+    /// Unix time of the last escalation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalated: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    /// Unix time the ban was placed.
+    pub at: u64,
+    pub origin: String,
+    /// Unix time it runs out at, `None` once escalated to a permanent ban.
+    pub until: Option<u64>,
+    /// Unix time it ended, `None` while it holds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ended: Option<u64>,
+    /// Ended by running out rather than by a command.
+    #[serde(default)]
+    pub expired: bool,
+}
+
+impl Weigh for History {
+    fn heap_bytes(&self) -> usize {
+        self.bans.capacity() * size_of::<Ban>()
+            + self
+                .bans
+                .iter()
+                .map(|ban| ban.origin.capacity())
+                .sum::<usize>()
+    }
+}
+
+impl History {
+    /// Records a ban of `origin` placed at `now`. A ban that still holds is only updated.
+    /// Returns whether the source has now returned often enough to be escalated.
+    pub fn banned(
+        &mut self,
+        origin: Origin,
+        until: Option<u64>,
+        now: u64,
+        settings: &Recidivists,
+    ) -> bool {
+        if let Some(last) = self.bans.last_mut()
+            && last.ended.is_none()
+        {
+            last.until = until;
+            return false;
+        }
+        // A permanent block is no TTL ban to wait out
+        if until.is_none() {
+            return false;
+        }
+        let returned = self.bans.last().is_some_and(|last| {
+            last.expired
+                && last
+                    .ended
+                    .is_some_and(|ended| now.saturating_sub(ended) <= settings.window)
+        });
+        self.returns = if returned { self.returns + 1 } else { 0 };
+        if self.bans.len() == MAX_BANS {
+            self.bans.remove(0);
+        }
+        self.bans.push(Ban {
+            at: now,
+            origin: origin.name().to_owned(),
+            until,
+            ended: None,
+            expired: false,
+        });
+        let escalate = returned && self.returns >= settings.after;
+        if escalate {
+            self.escalated = Some(now);
+        }
+        escalate
+    }
+
+    /// Records the end of the ban that holds, if one does. Returns whether one did.
+    pub fn ended(&mut self, now: u64, expired: bool) -> bool {
+        match self.bans.last_mut() {
+            Some(last) if last.ended.is_none() => {
+                last.ended = Some(now);
+                last.expired = expired;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the history can still matter at `now`: a ban holds, or one ended within the
+    /// window.
+    pub fn relevant(&self, now: u64, settings: &Recidivists) -> bool {
+        self.bans.last().is_some_and(|last| {
+            last.ended
+                .is_none_or(|ended| now.saturating_sub(ended) <= settings.window)
+        })
+    }
+}
+
+impl fmt::Display for History {
+    /// `3 bans, 2 returns in a row: 1700000000 auto-ban until 1700000300 (ran out), ...`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bans, {} returns in a row",
+            self.bans.len(),
+            self.returns
+        )?;
+        if let Some(at) = self.escalated {
+            write!(f, ", escalated at {at}")?;
+        }
+        f.write_str(":")?;
+        for (i, ban) in self.bans.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(f, "{sep}{} {}", ban.at, ban.origin)?;
+            match ban.until {
+                Some(until) => write!(f, " until {until}")?,
+                None => f.write_str(" permanent")?,
+            }
+            match (ban.ended, ban.expired) {
+                (None, _) => f.write_str(" (holds)")?,
+                (Some(_), true) => f.write_str(" (ran out)")?,
+                (Some(ended), false) => write!(f, " (removed at {ended})")?,
+            }
+        }
+        Ok(())
+    }
+}
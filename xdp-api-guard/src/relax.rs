@@ -0,0 +1,442 @@
+//! `--relax-after-secs`: the controller that tightens the knobs handed to it with `--managed`
+//! while traffic is off its baselines, and relaxes them again once it has been back for long
+//! enough.
+//!
+//! Every knob is in the registry, pinned (the value the operator's flags or `guardctl param`
+//! gave it, which the controller never writes) or managed (moved between the two ends of its
+//! `--managed` range). All managed knobs move together, at one level from 0 (tight) to 1
+//! (relaxed). Each second of live rates:
+//!
+//! - a drop rate or new-flow share over `TIGHTEN_ABOVE` times its baseline puts the level at
+//!   0 at once;
+//! - every rate at most `CALM_BELOW` times its baseline counts a calm second, and after
+//!   `--relax-after-secs` of them in a row the level rises one `--relax-step` a second;
+//! - anything in between holds the level and starts the calm seconds over.
+//!
+//! A baseline is `--normal-drop-rate`, `--normal-pass-rate` or `--normal-new-share`, or
+//! without one a slow EWMA of the calm seconds, started from the first. A daemon that may
+//! start under attack should be given its baselines.
+
+use std::fmt::Write as _;
+
+use anyhow::{anyhow, bail};
+use log::{info, warn};
+use xdp_api_guard_common::{Config, config_flags};
+
+use crate::{control::ControlState, stats::Rates};
+
+/// Times its baseline a drop rate or new-flow share tightens at.
+pub const TIGHTEN_ABOVE: f64 = 2.0;
+
+/// Times its baseline every rate stays within for a second to count as calm.
+pub const CALM_BELOW: f64 = 1.25;
+
+// How fast a learned baseline follows the calm seconds, about a time constant of 100s
+const LEARN_ALPHA: f64 = 0.01;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Knob {
+    /// `--global-rate`.
+    GlobalRate,
+    /// `--prefix-quota`.
+    PrefixQuota,
+    /// `--red-start`.
+    RedStart,
+}
+
+pub const KNOBS: [Knob; 3] = [Knob::GlobalRate, Knob::PrefixQuota, Knob::RedStart];
+
+impl Knob {
+    pub fn name(self) -> &'static str {
+        match self {
+            Knob::GlobalRate => "global-rate",
+            Knob::PrefixQuota => "prefix-quota",
+            Knob::RedStart => "red-start",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        KNOBS.into_iter().find(|knob| knob.name() == name)
+    }
+
+    pub fn get(self, cfg: &Config) -> u64 {
+        match self {
+            Knob::GlobalRate => cfg.global_limit,
+            Knob::PrefixQuota => u64::from(cfg.prefix_quota),
+            Knob::RedStart => u64::from(cfg.red_start),
+        }
+    }
+
+    /// Only called with values `check` let through.
+    pub fn set(self, cfg: &mut Config, value: u64) {
+        match self {
+            Knob::GlobalRate => cfg.global_limit = value,
+            Knob::PrefixQuota => cfg.prefix_quota = value.min(u64::from(u32::MAX)) as u32,
+            Knob::RedStart => cfg.red_start = value.min(100) as u8,
+        }
+    }
+
+    /// Whether `value` can be written next to the rest of `cfg`. 0 turns the ceiling or the
+    /// quota off, which an operator may pin but a `--managed` range can't reach.
+    pub fn check(self, value: u64, cfg: &Config) -> anyhow::Result<()> {
+        match self {
+            Knob::GlobalRate => {}
+            Knob::PrefixQuota => anyhow::ensure!(
+                value <= u64::from(u32::MAX),
+                "prefix-quota is at most {}",
+                u32::MAX
+            ),
+            Knob::RedStart => {
+                anyhow::ensure!(
+                    cfg.has(config_flags::RED),
+                    "early drops are off, start the daemon with --red-start"
+                );
+                anyhow::ensure!(
+                    value < u64::from(cfg.red_full),
+                    "red-start must be below --red-full ({}%)",
+                    cfg.red_full
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn unit(self) -> &'static str {
+        match self {
+            Knob::GlobalRate => " packets per window",
+            Knob::PrefixQuota => " entries per /16",
+            Knob::RedStart => "%",
+        }
+    }
+}
+
+/// The ends of a `--managed` knob's range. Tight is below relaxed for every knob: a lower
+/// ceiling, fewer entries per /16, early drops starting sooner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Range {
+    pub tight: u64,
+    pub relaxed: u64,
+}
+
+impl Range {
+    /// The value at `level`, 0 being tight and 1 relaxed.
+    pub fn at(&self, level: f64) -> u64 {
+        self.tight + ((self.relaxed - self.tight) as f64 * level.clamp(0.0, 1.0)).round() as u64
+    }
+}
+
+/// `guardctl param KNOB VALUE|auto`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Setting {
+    Pin(u64),
+    Auto,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Param {
+    knob: Knob,
+    range: Option<Range>,
+    pinned: bool,
+}
+
+// A rate the controller compares with its normal value
+#[derive(Clone, Copy, Debug)]
+struct Baseline {
+    configured: Option<f64>,
+    learned: Option<f64>,
+    // Below this the rate is noise, whatever was learned
+    floor: f64,
+}
+
+impl Baseline {
+    fn new(configured: Option<f64>, floor: f64) -> Self {
+        Self {
+            configured,
+            learned: None,
+            floor,
+        }
+    }
+
+    fn value(&self) -> Option<f64> {
+        self.configured
+            .or(self.learned)
+            .map(|value| value.max(self.floor))
+    }
+
+    fn learn(&mut self, value: f64) {
+        if self.configured.is_some() {
+            return;
+        }
+        self.learned = Some(match self.learned {
+            None => value,
+            Some(learned) => learned + LEARN_ALPHA * (value - learned),
+        });
+    }
+
+    fn above(&self, value: Option<f64>, factor: f64) -> bool {
+        match (value, self.value()) {
+            (Some(value), Some(baseline)) => value > baseline * factor,
+            _ => false,
+        }
+    }
+}
+
+/// The baselines from the flags, `None` for one to learn.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Baselines {
+    pub drop_rate: Option<f64>,
+    pub pass_rate: Option<f64>,
+    pub new_share: Option<f64>,
+}
+
+/// A move of the level and the values it takes the managed knobs to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub tightened: bool,
+    pub level: f64,
+    pub values: Vec<(Knob, u64)>,
+}
+
+pub struct Controller {
+    params: Vec<Param>,
+    relax_after: u64,
+    step: f64,
+    drop: Baseline,
+    pass: Baseline,
+    new: Baseline,
+    level: f64,
+    // Calm seconds in a row
+    calm: u64,
+}
+
+impl Controller {
+    /// `managed` are the `--managed` knobs, every other knob is pinned. `step` is the share of
+    /// the range relaxed per second, in (0, 1]. The knobs start relaxed.
+    pub fn new(
+        managed: &[(Knob, Range)],
+        relax_after: u64,
+        step: f64,
+        baselines: Baselines,
+    ) -> Self {
+        let params = KNOBS
+            .into_iter()
+            .map(|knob| {
+                let range = managed
+                    .iter()
+                    .rev()
+                    .find(|(k, _)| *k == knob)
+                    .map(|(_, range)| *range);
+                Param {
+                    knob,
+                    range,
+                    pinned: range.is_none(),
+                }
+            })
+            .collect();
+        Self {
+            params,
+            relax_after,
+            step,
+            drop: Baseline::new(baselines.drop_rate, 1.0),
+            pass: Baseline::new(baselines.pass_rate, 1.0),
+            new: Baseline::new(baselines.new_share, 1.0),
+            level: 1.0,
+            calm: 0,
+        }
+    }
+
+    /// Takes one second's rates. Returns the move they call for, `None` if the level stays.
+    pub fn tick(&mut self, rates: &Rates) -> Option<Step> {
+        if !rates.live() {
+            return None;
+        }
+        let (drop, pass, new) = (
+            Some(rates.drop_rate()),
+            Some(rates.pass_rate()),
+            rates.new_share(),
+        );
+        // More passes alone is more customers, only drops and new flows tighten
+        if self.drop.above(drop, TIGHTEN_ABOVE) || self.new.above(new, TIGHTEN_ABOVE) {
+            self.calm = 0;
+            return self.move_to(0.0, true);
+        }
+        let calm = !self.drop.above(drop, CALM_BELOW)
+            && !self.pass.above(pass, CALM_BELOW)
+            && !self.new.above(new, CALM_BELOW);
+        if !calm {
+            self.calm = 0;
+            return None;
+        }
+        self.drop.learn(rates.drop_rate());
+        self.pass.learn(rates.pass_rate());
+        if let Some(new) = new {
+            self.new.learn(new);
+        }
+        self.calm += 1;
+        if self.calm < self.relax_after {
+            return None;
+        }
+        self.move_to((self.level + self.step).min(1.0), false)
+    }
+
+    fn move_to(&mut self, level: f64, tightened: bool) -> Option<Step> {
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        Some(Step {
+            tightened,
+            level,
+            values: self.targets(),
+        })
+    }
+
+    // What the managed knobs are at the current level
+    fn targets(&self) -> Vec<(Knob, u64)> {
+        self.params
+            .iter()
+            .filter(|param| !param.pinned)
+            .filter_map(|param| Some((param.knob, param.range?.at(self.level))))
+            .collect()
+    }
+
+    fn param(&mut self, knob: Knob) -> &mut Param {
+        self.params
+            .iter_mut()
+            .find(|param| param.knob == knob)
+            .expect("every knob is in the registry")
+    }
+}
+
+/// Feeds the controller the last second's rates and writes what it asks for.
+pub fn tick(control: &ControlState) {
+    let Some(relax) = &control.relax else {
+        return;
+    };
+    let mut relax = relax.lock().unwrap();
+    let Some(step) = relax.tick(control.stats.lock().unwrap().rates()) else {
+        return;
+    };
+    if let Err(e) = write(control, &step) {
+        warn!("failed to adjust the managed knobs: {e:#}");
+    }
+}
+
+fn write(control: &ControlState, step: &Step) -> anyhow::Result<()> {
+    let mut config = control.config.lock().unwrap();
+    let before = config.get();
+    let changed: Vec<(Knob, u64)> = step
+        .values
+        .iter()
+        .copied()
+        .filter(|&(knob, value)| knob.get(&before) != value)
+        .collect();
+    if changed.is_empty() {
+        return Ok(());
+    }
+    config.update(|cfg| {
+        for &(knob, value) in &changed {
+            knob.set(cfg, value);
+        }
+    })?;
+    for (knob, value) in changed {
+        let line = format!(
+            "{} {} -> {value}{}, level {:.0}%",
+            knob.name(),
+            knob.get(&before),
+            knob.unit(),
+            step.level * 100.0
+        );
+        if step.tightened {
+            warn!("traffic off its baselines, {line}");
+        } else {
+            info!("traffic calm, {line}");
+        }
+    }
+    Ok(())
+}
+
+/// `guardctl params`.
+pub fn params(control: &ControlState) -> String {
+    let relax = control.relax.as_ref().map(|relax| relax.lock().unwrap());
+    let cfg = control.config.lock().unwrap().get();
+    let mut out = match &relax {
+        Some(relax) => format!(
+            "ok level {:.0}%, calm for {}s of {}s",
+            relax.level * 100.0,
+            relax.calm,
+            relax.relax_after
+        ),
+        None => "ok no --relax-after-secs, every knob is pinned".to_owned(),
+    };
+    for knob in KNOBS {
+        let param = relax.as_ref().and_then(|relax| {
+            relax
+                .params
+                .iter()
+                .find(|param| param.knob == knob)
+                .copied()
+        });
+        let _ = write!(out, "\n{:<13} {:>10}  ", knob.name(), knob.get(&cfg));
+        match param {
+            Some(Param {
+                range: Some(range),
+                pinned: false,
+                ..
+            }) => {
+                let _ = write!(out, "managed {}..{}", range.tight, range.relaxed);
+            }
+            Some(Param {
+                range: Some(range), ..
+            }) => {
+                let _ = write!(
+                    out,
+                    "pinned, managed {}..{} with auto",
+                    range.tight, range.relaxed
+                );
+            }
+            _ => out.push_str("pinned"),
+        }
+    }
+    out
+}
+
+/// `guardctl param KNOB VALUE|auto`.
+pub fn set(control: &ControlState, knob: Knob, setting: Setting) -> anyhow::Result<String> {
+    let mut relax = control.relax.as_ref().map(|relax| relax.lock().unwrap());
+    let mut config = control.config.lock().unwrap();
+    let before = knob.get(&config.get());
+    let value = match setting {
+        Setting::Pin(value) => {
+            knob.check(value, &config.get())?;
+            if let Some(relax) = &mut relax {
+                relax.param(knob).pinned = true;
+            }
+            value
+        }
+        Setting::Auto => {
+            let relax = relax.as_mut().ok_or_else(|| {
+                anyhow!("no controller, start the daemon with --relax-after-secs")
+            })?;
+            let level = relax.level;
+            let param = relax.param(knob);
+            let Some(range) = param.range else {
+                bail!(
+                    "{} isn't managed, give it a range with --managed",
+                    knob.name()
+                );
+            };
+            param.pinned = false;
+            range.at(level)
+        }
+    };
+    if value != before {
+        config.update(|cfg| knob.set(cfg, value))?;
+    }
+    let how = match setting {
+        Setting::Pin(_) => "pinned",
+        Setting::Auto => "managed",
+    };
+    info!("{} {how}, {before} -> {value}{}", knob.name(), knob.unit());
+    Ok(format!("ok {} {value} {how}", knob.name()))
+}
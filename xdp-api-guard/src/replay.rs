@@ -0,0 +1,113 @@
+//! Idempotency keys for control commands, so automation that retries a command it didn't get
+//! an answer to can't apply it twice.
+//!
+//! A command line prefixed with `req_id=KEY` (or sent to `POST /v1/command` with an
+//! `Idempotency-Key` header) runs once per key. Repeats within `TTL` get the first response
+//! back without running again; a repeat arriving while the first is still running waits for
+//! it. The daemon keeps the last `MAX_KEYS` keys within `--state-memory`, oldest dropped
+//! first unless still running. Keys are not kept across restarts.
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::OnceCell;
+
+use crate::bounded::{Bounded, Weigh};
+
+/// How long a response is kept for repeats of its key.
+pub const TTL: Duration = Duration::from_secs(600);
+/// Keys remembered at most.
+pub const MAX_KEYS: usize = 1024;
+/// Longest key accepted, keys are meant to be UUIDs or similar.
+pub const MAX_KEY_LEN: usize = 128;
+
+const PREFIX: &str = "req_id=";
+
+pub struct ReplayCache {
+    inner: Mutex<Bounded<String, Arc<Cached>>>,
+}
+
+struct Cached {
+    command: String,
+    response: OnceCell<String>,
+    at: Instant,
+}
+
+impl Weigh for Arc<Cached> {
+    fn heap_bytes(&self) -> usize {
+        size_of::<Cached>()
+            + self.command.capacity()
+            + self.response.get().map_or(0, String::capacity)
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Bounded::new("idempotency-keys", MAX_KEYS)),
+        }
+    }
+}
+
+impl ReplayCache {
+    /// Runs `command` through `run` unless `key` was seen with it before, in which case the
+    /// response of that first run is returned. A key reused for another command is refused.
+    pub async fn run(&self, key: &str, command: &str, run: impl Future<Output = String>) -> String {
+        if key.is_empty() || key.len() > MAX_KEY_LEN || key.contains(char::is_whitespace) {
+            return format!("err idempotency keys are 1 to {MAX_KEY_LEN} characters, no spaces");
+        }
+        // Retries may differ in spacing, not in words
+        let command = command.split_whitespace().collect::<Vec<_>>().join(" ");
+        let cached = get(&mut self.inner.lock().unwrap(), key, &command);
+        if cached.command != command {
+            return "err idempotency key already used for another command".to_owned();
+        }
+        let first = !cached.response.initialized();
+        let response = cached.response.get_or_init(|| run).await.clone();
+        if first {
+            // Now that the response is known, count it against the budget
+            self.inner.lock().unwrap().update(&key.to_owned(), |_| ());
+        }
+        response
+    }
+}
+
+fn get(keys: &mut Bounded<String, Arc<Cached>>, key: &str, command: &str) -> Arc<Cached> {
+    while let Some((oldest, cached)) = keys.oldest() {
+        if cached.at.elapsed() < TTL {
+            break;
+        }
+        let oldest = oldest.clone();
+        keys.remove(&oldest);
+    }
+    // Reweighing moves an entry to the back, so an expired one may not have been reached
+    if let Some(cached) = keys.get(&key.to_owned())
+        && cached.at.elapsed() < TTL
+    {
+        return cached.clone();
+    }
+    let cached = Arc::new(Cached {
+        command: command.to_owned(),
+        response: OnceCell::new(),
+        at: Instant::now(),
+    });
+    // One still running has a caller waiting on it, and a repeat would run again
+    keys.insert(key.to_owned(), cached.clone(), |_, cached| {
+        !cached.response.initialized()
+    });
+    cached
+}
+
+/// Splits a `req_id=KEY` prefix off a command line.
+pub fn split_key(line: &str) -> (Option<&str>, &str) {
+    match line.trim_start().strip_prefix(PREFIX) {
+        Some(rest) => {
+            let (key, command) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            (Some(key), command.trim_start())
+        }
+        None => (None, line),
+    }
+}
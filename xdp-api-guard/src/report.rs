@@ -0,0 +1,887 @@
+//! Daily roll-ups. With `--daily-report-dir` the daemon keeps a file per UTC day of what it
+//! saw, and `xdp-api-guard report` sums one up as Markdown or JSON.
+//!
+//! The day file (`DIR/YYYY-MM-DD.jsonl`) has one JSON record per line: the counters of every
+//! minute, the sources furthest over their limit in it, every blocklist addition and removal,
+//! the feeds loaded at startup and notable events (pauses, reloads, attachment and link
+//! changes). It holds everything the report needs, so the report runs from the files alone,
+//! on a host without the guard. When a day ends the daemon writes its report next to it as
+//! `DIR/YYYY-MM-DD.md`. Addresses are written as `--mask-ips` shows them, with what
+//! `--geoip-db` and `--asn-db` know of them, which is looked up on a task of its own rather
+//! than on the sampler's.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::Write as _,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, bail};
+use clap::Parser;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use xdp_api_guard_common::{Config, config_flags};
+
+use crate::{
+    blocklist::BlocklistEvent,
+    control::ControlState,
+    geoip::{self, Geo, GeoIp, GeoTop, Tally},
+    heatmap,
+    mask::Masked,
+    stats::StatsState,
+    timebase,
+};
+
+const DAY: u64 = 86_400;
+const MINUTE: Duration = Duration::from_secs(60);
+// Sources kept per minute, and listed in the report
+const TOP: usize = 20;
+
+/// Sum up one day of `--daily-report-dir`
+#[derive(Debug, Parser)]
+#[clap(name = "xdp-api-guard report")]
+pub struct ReportOpt {
+    /// Directory the daemon writes its day files to
+    #[clap(long, env = "GUARD_DAILY_REPORT_DIR")]
+    dir: PathBuf,
+
+    /// UTC day to report on, as YYYY-MM-DD [default: yesterday]
+    #[clap(long)]
+    date: Option<String>,
+
+    /// Print JSON instead of Markdown
+    #[clap(long)]
+    json: bool,
+}
+
+/// One line of a day file.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "kebab-case")]
+enum Record {
+    /// What was counted in the minute before `ts`.
+    Counters {
+        ts: u64,
+        passed: u64,
+        dropped: u64,
+        /// Would have been dropped, passed while filtering was paused.
+        paused: u64,
+        /// Drops by the feature that dropped them.
+        reasons: BTreeMap<String, u64>,
+        /// Blocklist drops by origin, or `feed:NAME`.
+        origins: BTreeMap<String, u64>,
+    },
+    /// Sources over their limit at `ts`, most packets first.
+    Top {
+        ts: u64,
+        sources: Vec<TopSource>,
+    },
+    Added {
+        ts: u64,
+        addr: String,
+        origin: String,
+        expires: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        geo: Option<Geo>,
+    },
+    Removed {
+        ts: u64,
+        addr: String,
+        origin: String,
+        expired: bool,
+    },
+    Feed {
+        ts: u64,
+        name: String,
+        entries: usize,
+    },
+    Event {
+        ts: u64,
+        event: String,
+    },
+}
+
+impl Record {
+    fn ts(&self) -> u64 {
+        match self {
+            Record::Counters { ts, .. }
+            | Record::Top { ts, .. }
+            | Record::Added { ts, .. }
+            | Record::Removed { ts, .. }
+            | Record::Feed { ts, .. }
+            | Record::Event { ts, .. } => *ts,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TopSource {
+    addr: String,
+    packets: u64,
+    limit: u64,
+    /// The blocklist entry deciding for the source, if any.
+    origin: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    geo: Option<Geo>,
+}
+
+// Running totals as the stats report has them, to take each minute's difference
+#[derive(Clone, Debug, Default)]
+struct Totals {
+    passed: u64,
+    dropped: u64,
+    paused: u64,
+    reasons: BTreeMap<String, u64>,
+    origins: BTreeMap<String, u64>,
+}
+
+impl Totals {
+    fn of(stats: &StatsState) -> Self {
+        let report = stats.report(Some(0));
+        Self {
+            passed: report.totals.passed,
+            dropped: report.totals.dropped,
+            paused: report.totals.paused_drops,
+            reasons: report
+                .drops_by_feature
+                .iter()
+                .map(|f| (f.feature.to_owned(), f.enforced))
+                .collect(),
+            origins: report
+                .drops_by_origin
+                .iter()
+                .map(|o| (o.label(), o.packets))
+                .collect(),
+        }
+    }
+
+    // Counts since `before`. After `flush stats` a count is lower than before, and all of it
+    // is new.
+    fn since(&self, before: &Totals) -> Record {
+        let diff = |now: u64, then: u64| if now >= then { now - then } else { now };
+        let map = |now: &BTreeMap<String, u64>, then: &BTreeMap<String, u64>| {
+            now.iter()
+                .map(|(key, n)| (key.clone(), diff(*n, then.get(key).copied().unwrap_or(0))))
+                .filter(|(_, n)| *n != 0)
+                .collect()
+        };
+        Record::Counters {
+            ts: timebase::unix_now(),
+            passed: diff(self.passed, before.passed),
+            dropped: diff(self.dropped, before.dropped),
+            paused: diff(self.paused, before.paused),
+            reasons: map(&self.reasons, &before.reasons),
+            origins: map(&self.origins, &before.origins),
+        }
+    }
+}
+
+/// Writes the day files.
+pub struct Recorder {
+    dir: PathBuf,
+    /// The day the open file is for, in days since the epoch.
+    file: Option<(u64, File)>,
+    totals: Option<Totals>,
+    minute: Instant,
+    paused: Option<bool>,
+    // So a full disk is reported once, not every minute
+    failing: bool,
+    // Where the top sources go to be looked up, see `enrich`
+    lookups: Option<mpsc::UnboundedSender<Vec<(IpAddr, TopSource)>>>,
+}
+
+impl Recorder {
+    pub fn new(dir: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            file: None,
+            totals: None,
+            minute: Instant::now(),
+            paused: None,
+            failing: false,
+            lookups: None,
+        })
+    }
+
+    pub fn event(&mut self, event: &str) {
+        self.write(&Record::Event {
+            ts: timebase::unix_now(),
+            event: event.to_owned(),
+        });
+    }
+
+    pub fn feed(&mut self, name: &str, entries: usize) {
+        self.write(&Record::Feed {
+            ts: timebase::unix_now(),
+            name: name.to_owned(),
+            entries,
+        });
+    }
+
+    /// Called every second by the main loop: notes pauses and resumes, and once a minute
+    /// records the counters and the top sources.
+    pub fn tick(&mut self, stats: &Mutex<StatsState>, control: &ControlState, cfg: &Config) {
+        let paused = cfg.has(config_flags::PAUSED);
+        if self.paused.is_some_and(|was| was != paused) {
+            self.event(if paused {
+                "filtering paused"
+            } else {
+                "filtering resumed"
+            });
+        }
+        self.paused = Some(paused);
+
+        let totals = {
+            let stats = stats.lock().unwrap();
+            if !stats.sampled() {
+                return;
+            }
+            Totals::of(&stats)
+        };
+        let Some(before) = &self.totals else {
+            self.totals = Some(totals);
+            self.minute = Instant::now();
+            return;
+        };
+        if self.minute.elapsed() < MINUTE {
+            return;
+        }
+        let counters = totals.since(before);
+        self.totals = Some(totals);
+        self.minute = Instant::now();
+        self.write(&counters);
+        let sources = top(control, cfg);
+        if sources.is_empty() {
+            return;
+        }
+        match &self.lookups {
+            // Written once looked up
+            Some(lookups) => {
+                let _ = lookups.send(sources);
+            }
+            None => self.write(&Record::Top {
+                ts: timebase::unix_now(),
+                sources: sources.into_iter().map(|(_, source)| source).collect(),
+            }),
+        }
+    }
+
+    fn blocklist(&mut self, event: BlocklistEvent, geo: Option<&GeoIp>) {
+        let ts = timebase::unix_now();
+        self.write(&match event {
+            BlocklistEvent::Added(ip, entry) => Record::Added {
+                ts,
+                addr: Masked(ip).to_string(),
+                origin: entry.origin.name().to_owned(),
+                expires: entry.expires,
+                geo: geo.and_then(|geo| geo.lookup(ip.into())),
+            },
+            BlocklistEvent::Removed {
+                ip,
+                origin,
+                expired,
+            } => Record::Removed {
+                ts,
+                addr: Masked(ip).to_string(),
+                origin: origin.name().to_owned(),
+                expired,
+            },
+            BlocklistEvent::Recidivist { ip, history, .. } => Record::Event {
+                ts,
+                event: format!(
+                    "recidivist {} escalated after {} returns in a row, {} bans",
+                    Masked(ip),
+                    history.returns,
+                    history.bans.len()
+                ),
+            },
+        });
+    }
+
+    fn write(&mut self, record: &Record) {
+        match self.append(record) {
+            Ok(()) => self.failing = false,
+            Err(e) => {
+                if !self.failing {
+                    warn!("daily report: {e:#}");
+                }
+                self.failing = true;
+            }
+        }
+    }
+
+    fn append(&mut self, record: &Record) -> anyhow::Result<()> {
+        let day = record.ts() / DAY;
+        if self.file.as_ref().is_none_or(|(open, _)| *open != day) {
+            let path = self.dir.join(format!("{}.jsonl", date(day)));
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            // The first record of a day closes the day before
+            if let Some((ended, _)) = self.file.replace((day, file)) {
+                let path = self.dir.join(format!("{}.md", date(ended)));
+                let report = Report::read(&self.dir, ended)?;
+                fs::write(&path, report.markdown())
+                    .with_context(|| format!("failed to write {}", path.display()))?;
+            }
+        }
+        let (_, file) = self.file.as_mut().unwrap();
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Records every change to the blocklist, until the daemon stops.
+pub fn follow(recorder: Arc<Mutex<Recorder>>, control: &ControlState) {
+    let mut events = control.blocklist.lock().unwrap().subscribe();
+    let geo = control.geo.clone();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => recorder.lock().unwrap().blocklist(event, geo.as_deref()),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("daily report: {missed} blocklist changes missed")
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Looks the top sources up in `geo` before they are written, on a task of its own so the
+/// sampler doesn't wait for the databases.
+pub fn enrich(recorder: Arc<Mutex<Recorder>>, geo: Arc<GeoIp>) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<(IpAddr, TopSource)>>();
+    recorder.lock().unwrap().lookups = Some(tx);
+    tokio::spawn(async move {
+        while let Some(sources) = rx.recv().await {
+            let sources = sources
+                .into_iter()
+                .map(|(ip, source)| TopSource {
+                    geo: geo.lookup(ip),
+                    ..source
+                })
+                .collect();
+            // Stamped when written, a record of the day before would open its file again
+            recorder.lock().unwrap().write(&Record::Top {
+                ts: timebase::unix_now(),
+                sources,
+            });
+        }
+    });
+}
+
+// The sources furthest over their limit right now, with their addresses for a lookup. Groups
+// are locked before the limiter, as in `group list`.
+fn top(control: &ControlState, cfg: &Config) -> Vec<(IpAddr, TopSource)> {
+    let offenders = {
+        let groups = control.groups.lock().unwrap();
+        heatmap::collect(
+            &control.rate_limit.lock().unwrap(),
+            &control.rate_limit6.lock().unwrap(),
+            &control.nat.lock().unwrap(),
+            &control.tags.lock().unwrap(),
+            &groups,
+            cfg,
+            timebase::boot_ns(),
+        )
+    };
+    let blocklist = control.blocklist.lock().unwrap();
+    offenders
+        .into_iter()
+        .filter(|o| o.count > o.limit)
+        .take(TOP)
+        .map(|o| {
+            let source = TopSource {
+                addr: match o.port {
+                    Some(port) => format!("{}:{port}", Masked(o.addr)),
+                    None => Masked(o.addr).to_string(),
+                },
+                packets: o.count,
+                limit: o.limit,
+                origin: match o.addr {
+                    IpAddr::V4(ip) => blocklist
+                        .effective(ip)
+                        .map(|origin| origin.name().to_owned()),
+                    IpAddr::V6(_) => None,
+                },
+                geo: None,
+            };
+            (o.addr, source)
+        })
+        .collect()
+}
+
+/// A count of one day, with the day before's when there is one.
+#[derive(Debug, Serialize)]
+struct Count {
+    name: String,
+    count: u64,
+    previous: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Offender {
+    addr: String,
+    /// Most packets in one window, and the limit then.
+    peak: u64,
+    limit: u64,
+    /// Minutes the source was among the top sources.
+    minutes: u64,
+    origin: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geo: Option<Geo>,
+    // Packets over the limit, summed over those minutes
+    #[serde(skip)]
+    over: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Bans {
+    origin: String,
+    added: u64,
+    expired: u64,
+    removed: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct FeedLoad {
+    name: String,
+    entries: usize,
+    ts: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Notable {
+    ts: u64,
+    event: String,
+}
+
+/// The summary of one day.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    date: String,
+    /// Minutes the day file has counters for.
+    minutes: u64,
+    totals: Vec<Count>,
+    reasons: Vec<Count>,
+    origins: Vec<Count>,
+    top_sources: Vec<Offender>,
+    /// What the top sources sent over their limit, summed over the minutes they were among
+    /// them, by country and AS. Left out unless the day file knows the networks of sources.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    over_limit: Option<GeoTop>,
+    blocklist: Vec<Bans>,
+    feeds: Vec<FeedLoad>,
+    events: Vec<Notable>,
+    /// Lines of the day file that couldn't be read.
+    skipped: usize,
+}
+
+// What the counters of one day add up to
+#[derive(Default)]
+struct Sums {
+    minutes: u64,
+    totals: BTreeMap<String, u64>,
+    reasons: BTreeMap<String, u64>,
+    origins: BTreeMap<String, u64>,
+}
+
+impl Report {
+    /// Reads the day file of `day` and, for the comparison, the one of the day before.
+    fn read(dir: &Path, day: u64) -> anyhow::Result<Self> {
+        let path = dir.join(format!("{}.jsonl", date(day)));
+        let (records, skipped) =
+            read_day(&path)?.with_context(|| format!("no day file {}", path.display()))?;
+        let previous = match day.checked_sub(1) {
+            Some(before) => read_day(&dir.join(format!("{}.jsonl", date(before))))?
+                .map(|(records, _)| sums(&records)),
+            None => None,
+        };
+        Ok(Self::build(day, &records, previous.as_ref(), skipped))
+    }
+
+    fn build(day: u64, records: &[Record], previous: Option<&Sums>, skipped: usize) -> Self {
+        let today = sums(records);
+        let compare = |now: &BTreeMap<String, u64>, then: Option<&BTreeMap<String, u64>>| {
+            let mut counts: Vec<Count> = now
+                .iter()
+                .map(|(name, count)| Count {
+                    name: name.clone(),
+                    count: *count,
+                    previous: then.map(|then| then.get(name).copied().unwrap_or(0)),
+                })
+                .collect();
+            counts.sort_by(|a, b| b.count.cmp(&a.count).then(a.name.cmp(&b.name)));
+            counts
+        };
+        let mut totals = compare(&today.totals, previous.map(|p| &p.totals));
+        // Always in this order, and whatever the counts
+        totals.sort_by_key(|c| {
+            ["passed", "dropped", "paused"]
+                .iter()
+                .position(|n| *n == c.name)
+        });
+
+        let mut offenders: BTreeMap<&str, Offender> = BTreeMap::new();
+        let mut blocklist: BTreeMap<&str, Bans> = BTreeMap::new();
+        let mut feeds = Vec::new();
+        let mut events = Vec::new();
+        for record in records {
+            match record {
+                Record::Top { sources, .. } => {
+                    for source in sources {
+                        let o = offenders.entry(&source.addr).or_insert(Offender {
+                            addr: source.addr.clone(),
+                            peak: 0,
+                            limit: 0,
+                            minutes: 0,
+                            origin: None,
+                            geo: None,
+                            over: 0,
+                        });
+                        if source.packets >= o.peak {
+                            o.peak = source.packets;
+                            o.limit = source.limit;
+                        }
+                        o.minutes += 1;
+                        o.over += source.packets.saturating_sub(source.limit);
+                        if source.origin.is_some() {
+                            o.origin.clone_from(&source.origin);
+                        }
+                        if source.geo.is_some() {
+                            o.geo.clone_from(&source.geo);
+                        }
+                    }
+                }
+                Record::Added { origin, .. } => {
+                    bans(&mut blocklist, origin).added += 1;
+                }
+                Record::Removed {
+                    origin, expired, ..
+                } => {
+                    let bans = bans(&mut blocklist, origin);
+                    if *expired {
+                        bans.expired += 1;
+                    } else {
+                        bans.removed += 1;
+                    }
+                }
+                Record::Feed { ts, name, entries } => feeds.push(FeedLoad {
+                    name: name.clone(),
+                    entries: *entries,
+                    ts: *ts,
+                }),
+                Record::Event { ts, event } => events.push(Notable {
+                    ts: *ts,
+                    event: event.clone(),
+                }),
+                Record::Counters { .. } => {}
+            }
+        }
+        let mut top_sources: Vec<Offender> = offenders.into_values().collect();
+        // Every source of the day, not only those listed
+        let over_limit = top_sources.iter().any(|o| o.geo.is_some()).then(|| {
+            let mut tally = Tally::default();
+            for o in &top_sources {
+                tally.add(o.geo.as_ref(), o.over);
+            }
+            tally.top(geoip::TOP)
+        });
+        top_sources.sort_by(|a, b| b.peak.cmp(&a.peak).then(a.addr.cmp(&b.addr)));
+        top_sources.truncate(TOP);
+
+        Self {
+            date: date(day),
+            minutes: today.minutes,
+            totals,
+            reasons: compare(&today.reasons, previous.map(|p| &p.reasons)),
+            origins: compare(&today.origins, previous.map(|p| &p.origins)),
+            top_sources,
+            over_limit,
+            blocklist: blocklist.into_values().collect(),
+            feeds,
+            events,
+            skipped,
+        }
+    }
+
+    pub fn markdown(&self) -> String {
+        let mut out = format!("# xdp-api-guard daily report, {} (UTC)\n", self.date);
+        let _ = writeln!(
+            out,
+            "\n{} minutes recorded{}.",
+            self.minutes,
+            match self.skipped {
+                0 => String::new(),
+                n => format!(", {n} unreadable lines skipped"),
+            }
+        );
+        table(&mut out, "Packets", "", &self.totals);
+        table(&mut out, "Drops by reason", "reason", &self.reasons);
+        table(
+            &mut out,
+            "Blocklist drops by origin",
+            "origin",
+            &self.origins,
+        );
+
+        out.push_str("\n## Top sources over their limit\n\n");
+        if self.top_sources.is_empty() {
+            out.push_str("None.\n");
+        } else if self.over_limit.is_some() {
+            out.push_str("| source | peak per window | limit | minutes | blocklist | network |\n");
+            out.push_str("|---|---:|---:|---:|---|---|\n");
+            for o in &self.top_sources {
+                let _ = writeln!(
+                    out,
+                    "| {} | {} | {} | {} | {} | {} |",
+                    o.addr,
+                    o.peak,
+                    o.limit,
+                    o.minutes,
+                    o.origin.as_deref().unwrap_or(""),
+                    o.geo.as_ref().map(Geo::to_string).unwrap_or_default()
+                );
+            }
+        } else {
+            out.push_str("| source | peak per window | limit | minutes | blocklist |\n");
+            out.push_str("|---|---:|---:|---:|---|\n");
+            for o in &self.top_sources {
+                let _ = writeln!(
+                    out,
+                    "| {} | {} | {} | {} | {} |",
+                    o.addr,
+                    o.peak,
+                    o.limit,
+                    o.minutes,
+                    o.origin.as_deref().unwrap_or("")
+                );
+            }
+        }
+        if let Some(over) = &self.over_limit {
+            geo_table(
+                &mut out,
+                "Over the limit by country",
+                "country",
+                &over.by_country,
+            );
+            geo_table(&mut out, "Over the limit by AS", "AS", &over.by_asn);
+        }
+
+        out.push_str("\n## Blocklist changes\n\n");
+        if self.blocklist.is_empty() {
+            out.push_str("None.\n");
+        } else {
+            out.push_str("| origin | added | expired | removed |\n|---|---:|---:|---:|\n");
+            for b in &self.blocklist {
+                let _ = writeln!(
+                    out,
+                    "| {} | {} | {} | {} |",
+                    b.origin, b.added, b.expired, b.removed
+                );
+            }
+        }
+
+        out.push_str("\n## Feeds\n\n");
+        if self.feeds.is_empty() {
+            out.push_str("No feed loaded this day.\n");
+        } else {
+            out.push_str("| feed | entries | loaded at |\n|---|---:|---|\n");
+            for feed in &self.feeds {
+                let _ = writeln!(
+                    out,
+                    "| {} | {} | {} |",
+                    feed.name,
+                    feed.entries,
+                    time(feed.ts)
+                );
+            }
+        }
+
+        out.push_str("\n## Events\n\n");
+        if self.events.is_empty() {
+            out.push_str("None.\n");
+        }
+        for event in &self.events {
+            let _ = writeln!(out, "- {} {}", time(event.ts), event.event);
+        }
+        out
+    }
+}
+
+// A table of counts with the day before's and the change
+fn table(out: &mut String, title: &str, what: &str, counts: &[Count]) {
+    let _ = write!(out, "\n## {title}\n\n");
+    if counts.is_empty() {
+        out.push_str("None.\n");
+        return;
+    }
+    let _ = writeln!(
+        out,
+        "| {what} | count | day before | change |\n|---|---:|---:|---:|"
+    );
+    for c in counts {
+        let (before, change) = match c.previous {
+            None => (String::new(), String::new()),
+            Some(0) if c.count == 0 => ("0".to_owned(), String::new()),
+            Some(0) => ("0".to_owned(), "new".to_owned()),
+            Some(before) => {
+                let change = (c.count as f64 - before as f64) * 100.0 / before as f64;
+                (before.to_string(), format!("{change:+.1}%"))
+            }
+        };
+        let _ = writeln!(out, "| {} | {} | {before} | {change} |", c.name, c.count);
+    }
+}
+
+// The top countries or ASes of the day's sources over their limit
+fn geo_table(out: &mut String, title: &str, what: &str, counts: &[geoip::GeoCount]) {
+    let _ = write!(out, "\n## {title}\n\n");
+    if counts.is_empty() {
+        out.push_str("None.\n");
+        return;
+    }
+    let _ = writeln!(
+        out,
+        "| {what} | name | sources | packets over |\n|---|---|---:|---:|"
+    );
+    for c in counts {
+        let _ = writeln!(
+            out,
+            "| {} | {} | {} | {} |",
+            c.key,
+            c.name.as_deref().unwrap_or(""),
+            c.sources,
+            c.packets
+        );
+    }
+}
+
+fn bans<'m, 'a>(blocklist: &'m mut BTreeMap<&'a str, Bans>, origin: &'a str) -> &'m mut Bans {
+    blocklist.entry(origin).or_insert_with(|| Bans {
+        origin: origin.to_owned(),
+        ..Bans::default()
+    })
+}
+
+fn sums(records: &[Record]) -> Sums {
+    let mut sums = Sums::default();
+    for record in records {
+        let Record::Counters {
+            passed,
+            dropped,
+            paused,
+            reasons,
+            origins,
+            ..
+        } = record
+        else {
+            continue;
+        };
+        sums.minutes += 1;
+        for (name, count) in [("passed", passed), ("dropped", dropped), ("paused", paused)] {
+            *sums.totals.entry(name.to_owned()).or_default() += count;
+        }
+        for (reason, count) in reasons {
+            *sums.reasons.entry(reason.clone()).or_default() += count;
+        }
+        for (origin, count) in origins {
+            *sums.origins.entry(origin.clone()).or_default() += count;
+        }
+    }
+    sums
+}
+
+// The records of a day file and how many lines were skipped, `None` if there is no file. A
+// line cut short by a crash is skipped like any other damaged one.
+fn read_day(path: &Path) -> anyhow::Result<Option<(Vec<Record>, usize)>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    for line in data.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(_) => skipped += 1,
+        }
+    }
+    Ok(Some((records, skipped)))
+}
+
+/// Runs `xdp-api-guard report`.
+pub fn run(opt: ReportOpt) -> anyhow::Result<()> {
+    let day = match &opt.date {
+        Some(date) => parse_date(date)?,
+        None => (timebase::unix_now() / DAY).saturating_sub(1),
+    };
+    let report = Report::read(&opt.dir, day)?;
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report.markdown());
+    }
+    Ok(())
+}
+
+/// `YYYY-MM-DD` of `day`, in days since the epoch.
+fn date(day: u64) -> String {
+    // Days to the civil calendar, after Howard Hinnant's `civil_from_days`
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!("{y:04}-{m:02}-{d:02}")
+}
+
+// And back
+fn parse_date(s: &str) -> anyhow::Result<u64> {
+    let parts: Vec<i64> = s
+        .split('-')
+        .map(|part| part.parse().with_context(|| format!("bad date {s:?}")))
+        .collect::<anyhow::Result<_>>()?;
+    let [y, m, d] = parts[..] else {
+        bail!("bad date {s:?}, expected YYYY-MM-DD");
+    };
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) || y < 1970 {
+        bail!("bad date {s:?}, expected YYYY-MM-DD");
+    }
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let day = (era * 146_097 + doe - 719_468) as u64;
+    if date(day) != s {
+        bail!("no such day {s:?}");
+    }
+    Ok(day)
+}
+
+// `HH:MM` of a unix time, UTC
+fn time(ts: u64) -> String {
+    format!("{:02}:{:02}", ts % DAY / 3600, ts % 3600 / 60)
+}
+
+/// `YYYY-MM-DD HH:MM:SS` of a unix time, UTC.
+pub(crate) fn utc(ts: u64) -> String {
+    format!("{} {}:{:02}", date(ts / DAY), time(ts), ts % 60)
+}
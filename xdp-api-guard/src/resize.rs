@@ -0,0 +1,440 @@
+//! Growing `BLOCKLIST` and `RATE_LIMIT_MAP` while the program runs: `guardctl resize` and
+//! `--auto-resize`.
+//!
+//! The program doesn't use either map directly. On every packet it reads the map to work
+//! with from a one-entry array of maps, `BLOCKLIST_SLOT` or `RATE_LIMIT_SLOT`, so a resize
+//! creates a larger map, copies the entries over and switches the slot with one update: the
+//! next packet sees the new map, and none sees a map half filled. The kernel needs the
+//! layout of the inner maps when a slot is created, which the loader can't give it, so the
+//! slots are created here and pinned for the loader like `TRUSTED_FLOWS`.
+//!
+//! Nothing but its handle writes the blocklist, so with its lock held the entries don't
+//! change during the copy and no verdict changes with the switch. The limiter map is written
+//! by the kernel all along. Its entries are copied once before the switch, and those of
+//! sources the kernel gave an entry in the meantime once more after it, so the only packets
+//! lost to the counts are those of the moment between the copy and the switch. Maps only
+//! grow. A slot takes inner maps of another size than its first from kernel 5.10 on.
+
+use std::{
+    ffi::CString,
+    fmt, fs, io, mem,
+    os::fd::{AsFd as _, AsRawFd as _, FromRawFd as _, OwnedFd},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context as _, anyhow, bail};
+use aya::{
+    Pod,
+    maps::{HashMap, IterableMap as _, Map, MapData, MapError},
+};
+use log::{info, warn};
+use xdp_api_guard_common::{BlockEntry, PacketLog};
+
+use crate::{batch, blocklist::BlocklistHandle, control::ControlState, trusted};
+
+/// How often `--resize-at` is checked.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_OBJ_PIN: libc::c_long = 6;
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_MAP_TYPE_ARRAY_OF_MAPS: u32 = 12;
+const BPF_NOEXIST: u64 = 1;
+
+/// A map that can be resized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resizable {
+    Blocklist,
+    /// `RATE_LIMIT_MAP`, the IPv4 limiter entries.
+    Tracking,
+}
+
+impl Resizable {
+    pub const ALL: [Resizable; 2] = [Resizable::Blocklist, Resizable::Tracking];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|map| map.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Resizable::Blocklist => "blocklist",
+            Resizable::Tracking => "tracking",
+        }
+    }
+
+    fn map_name(self) -> &'static str {
+        match self {
+            Resizable::Blocklist => "BLOCKLIST",
+            Resizable::Tracking => "RATE_LIMIT_MAP",
+        }
+    }
+
+    fn slot_name(self) -> &'static str {
+        match self {
+            Resizable::Blocklist => "BLOCKLIST_SLOT",
+            Resizable::Tracking => "RATE_LIMIT_SLOT",
+        }
+    }
+
+    // Key and value sizes, which the kernel checks every map put in the slot against
+    fn layout(self) -> (usize, usize) {
+        match self {
+            Resizable::Blocklist => (mem::size_of::<u32>(), mem::size_of::<BlockEntry>()),
+            Resizable::Tracking => (mem::size_of::<u32>(), mem::size_of::<PacketLog>()),
+        }
+    }
+}
+
+impl fmt::Display for Resizable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The slots of the program the daemon loaded.
+pub struct Slots {
+    blocklist: OwnedFd,
+    tracking: OwnedFd,
+}
+
+/// `--resize-at`, `--auto-resize` and `--resize-limit`.
+#[derive(Debug, Clone, Copy)]
+pub struct Watch {
+    pub percent: u8,
+    pub auto: bool,
+    pub limit: u32,
+}
+
+// The start of the `BPF_MAP_CREATE` member of `union bpf_attr`, the rest stays zero
+#[repr(C)]
+#[derive(Default)]
+struct CreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+    inner_map_fd: u32,
+    numa_node: u32,
+    map_name: [u8; 16],
+}
+
+// The members of `union bpf_attr` for element updates and pins
+#[repr(C)]
+#[derive(Default)]
+struct ElemAttr {
+    map_fd: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct PinAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+impl Slots {
+    /// Creates both slots and pins them in `trusted::PIN_DIR`, which has to exist, for the
+    /// loader. Call `unpin` once the object is loaded.
+    pub fn prepare() -> anyhow::Result<Self> {
+        // Left over from a daemon that died while loading, would be reused otherwise
+        unpin();
+        let slot = |which: Resizable| -> anyhow::Result<OwnedFd> {
+            let (key_size, value_size) = which.layout();
+            let template = create(BPF_MAP_TYPE_HASH, key_size, value_size, 1, None, "template")
+                .context("failed to create an inner map template")?;
+            let fd = create(
+                BPF_MAP_TYPE_ARRAY_OF_MAPS,
+                mem::size_of::<u32>(),
+                mem::size_of::<u32>(),
+                1,
+                Some(&template),
+                which.slot_name(),
+            )
+            .with_context(|| format!("failed to create {}", which.slot_name()))?;
+            pin(&fd, &Path::new(trusted::PIN_DIR).join(which.slot_name()))?;
+            Ok(fd)
+        };
+        Ok(Self {
+            blocklist: slot(Resizable::Blocklist)?,
+            tracking: slot(Resizable::Tracking)?,
+        })
+    }
+
+    /// Points the program at `map` for `which`.
+    pub fn point(&self, which: Resizable, map: &MapData) -> io::Result<()> {
+        let slot = match which {
+            Resizable::Blocklist => &self.blocklist,
+            Resizable::Tracking => &self.tracking,
+        };
+        let key = 0u32;
+        let fd = map.fd().as_fd().as_raw_fd() as u32;
+        let mut attr = ElemAttr {
+            map_fd: slot.as_raw_fd() as u32,
+            key: &key as *const u32 as u64,
+            value: &fd as *const u32 as u64,
+            flags: 0,
+        };
+        sys_bpf(BPF_MAP_UPDATE_ELEM, &mut attr).map(drop)
+    }
+}
+
+/// Removes the loader's pins. The program keeps the slots it was loaded with.
+pub fn unpin() {
+    for which in Resizable::ALL {
+        let path = Path::new(trusted::PIN_DIR).join(which.slot_name());
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("failed to unpin {}: {e}", path.display()),
+        }
+    }
+}
+
+/// Grows `which` to `max_entries`. Returns how many entries were copied.
+pub fn resize(state: &ControlState, which: Resizable, max_entries: u32) -> anyhow::Result<usize> {
+    let slots = state.slots.as_ref().ok_or_else(|| {
+        anyhow!("resize needs the daemon's own program, not one of --external-maps")
+    })?;
+    match which {
+        Resizable::Blocklist => {
+            grow_blocklist(slots, &mut state.blocklist.lock().unwrap(), max_entries)
+        }
+        Resizable::Tracking => {
+            grow_tracking(slots, &mut state.rate_limit.lock().unwrap(), max_entries)
+        }
+    }
+}
+
+/// Grows the blocklist `blocklist` writes to, which the program reads through `slots`.
+pub fn grow_blocklist(
+    slots: &Slots,
+    blocklist: &mut BlocklistHandle,
+    max_entries: u32,
+) -> anyhow::Result<usize> {
+    let which = Resizable::Blocklist;
+    let old = blocklist.kernel_map();
+    grows(which, old, max_entries)?;
+    let mut new = create_like(which, max_entries)?;
+    let copied = copy(old, &mut new, 0)?;
+    slots.point(which, new.map())?;
+    blocklist.replace_kernel_map(new);
+    Ok(copied)
+}
+
+/// Grows `tracking`, which the program reads through `slots`, and replaces it with the new map.
+pub fn grow_tracking(
+    slots: &Slots,
+    tracking: &mut HashMap<MapData, u32, PacketLog>,
+    max_entries: u32,
+) -> anyhow::Result<usize> {
+    let which = Resizable::Tracking;
+    grows(which, tracking, max_entries)?;
+    let mut new = create_like(which, max_entries)?;
+    let copied = copy(tracking, &mut new, 0)?;
+    slots.point(which, new.map())?;
+    // Sources that got an entry in the old map during the copy
+    let late = copy(tracking, &mut new, BPF_NOEXIST)?;
+    *tracking = new;
+    Ok(copied + late)
+}
+
+/// Checks how full both maps are every `CHECK_INTERVAL`, until the task is dropped. A map
+/// at `--resize-at` is grown to twice its size with `--auto-resize`, up to the limit, and
+/// is warned about once otherwise.
+pub async fn watch(state: Arc<ControlState>, watch: Watch) {
+    let mut tick = tokio::time::interval(CHECK_INTERVAL);
+    let mut warned = [false; Resizable::ALL.len()];
+    loop {
+        tick.tick().await;
+        let state = state.clone();
+        // A resize copies every entry, keep it off the runtime threads
+        let result = tokio::task::spawn_blocking(move || {
+            let result = check(&state, watch, &mut warned);
+            (result, warned)
+        })
+        .await;
+        match result {
+            Ok((Ok(()), checked)) => warned = checked,
+            Ok((Err(e), checked)) => {
+                warned = checked;
+                warn!("map occupancy check failed: {e:#}");
+            }
+            Err(e) => warn!("map occupancy task failed: {e}"),
+        }
+    }
+}
+
+fn check(state: &ControlState, watch: Watch, warned: &mut [bool; 2]) -> anyhow::Result<()> {
+    for (which, warned) in Resizable::ALL.into_iter().zip(warned) {
+        let (entries, max_entries) = match which {
+            Resizable::Blocklist => {
+                let blocklist = state.blocklist.lock().unwrap();
+                let max_entries = max_entries_of(blocklist.kernel_map())?;
+                (blocklist.entries().count() as u64, max_entries)
+            }
+            Resizable::Tracking => {
+                let tracking = state.rate_limit.lock().unwrap();
+                (tracking.keys().count() as u64, max_entries_of(&tracking)?)
+            }
+        };
+        if entries * 100 < u64::from(max_entries) * u64::from(watch.percent) {
+            *warned = false;
+            continue;
+        }
+        let grown = max_entries.saturating_mul(2).min(watch.limit);
+        if watch.auto && grown > max_entries && state.slots.is_some() {
+            let copied = resize(state, which, grown)?;
+            info!(
+                "{which} map had {entries} of {max_entries} entries, grown to {grown} \
+                 ({copied} entries copied)"
+            );
+            *warned = false;
+        } else if !*warned {
+            *warned = true;
+            if watch.auto {
+                warn!(
+                    "{which} map holds {entries} of {max_entries} entries and is at \
+                     --resize-limit"
+                );
+            } else if state.slots.is_some() {
+                warn!(
+                    "{which} map holds {entries} of {max_entries} entries, grow it with \
+                     `guardctl resize {which} {grown}` or --auto-resize"
+                );
+            } else {
+                warn!("{which} map holds {entries} of {max_entries} entries");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn max_entries_of<K: Pod, V: Pod>(map: &HashMap<MapData, K, V>) -> anyhow::Result<u32> {
+    Ok(map.map().info()?.max_entries())
+}
+
+fn grows<K: Pod, V: Pod>(
+    which: Resizable,
+    map: &HashMap<MapData, K, V>,
+    max_entries: u32,
+) -> anyhow::Result<()> {
+    let current = max_entries_of(map)?;
+    if max_entries <= current {
+        bail!("the {which} map holds up to {current} entries already, maps only grow");
+    }
+    Ok(())
+}
+
+// An empty map with the layout of `which`, for `max_entries`
+fn create_like<K: Pod, V: Pod>(
+    which: Resizable,
+    max_entries: u32,
+) -> anyhow::Result<HashMap<MapData, K, V>> {
+    let fd = create(
+        BPF_MAP_TYPE_HASH,
+        mem::size_of::<K>(),
+        mem::size_of::<V>(),
+        max_entries,
+        None,
+        which.map_name(),
+    )
+    .with_context(|| format!("failed to create a {which} map of {max_entries} entries"))?;
+    let map = MapData::from_fd(fd)?;
+    Ok(HashMap::try_from(Map::HashMap(map))?)
+}
+
+// Copies the entries of `from` into `to`, with `BPF_NOEXIST` only those it doesn't have.
+// Returns how many were written.
+fn copy<K: Pod, V: Pod>(
+    from: &HashMap<MapData, K, V>,
+    to: &mut HashMap<MapData, K, V>,
+    flags: u64,
+) -> anyhow::Result<usize> {
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    for entry in from.iter() {
+        match entry {
+            Ok((key, value)) => {
+                keys.push(key);
+                values.push(value);
+            }
+            // Deleted by the kernel between the key and the lookup
+            Err(MapError::KeyNotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if flags == 0 && batch::supported(to.map()) {
+        batch::update(to.map(), &keys, &values)
+            .map_err(|(done, e)| anyhow!("failed after {done} entries: {e}"))?;
+        return Ok(keys.len());
+    }
+    let mut written = 0;
+    for (key, value) in keys.iter().zip(&values) {
+        match to.insert(key, value, flags) {
+            Ok(()) => written += 1,
+            Err(MapError::SyscallError(e)) if e.io_error.raw_os_error() == Some(libc::EEXIST) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(written)
+}
+
+fn create(
+    map_type: u32,
+    key_size: usize,
+    value_size: usize,
+    max_entries: u32,
+    inner: Option<&OwnedFd>,
+    name: &str,
+) -> io::Result<OwnedFd> {
+    let mut map_name = [0; 16];
+    // The kernel takes 15 characters and the NUL
+    let len = name.len().min(map_name.len() - 1);
+    map_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    let mut attr = CreateAttr {
+        map_type,
+        key_size: key_size as u32,
+        value_size: value_size as u32,
+        max_entries,
+        inner_map_fd: inner.map_or(0, |fd| fd.as_raw_fd() as u32),
+        map_name,
+        ..CreateAttr::default()
+    };
+    let fd = sys_bpf(BPF_MAP_CREATE, &mut attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+fn pin(fd: &OwnedFd, path: &Path) -> anyhow::Result<()> {
+    let pathname = CString::new(path.as_os_str().as_encoded_bytes())?;
+    let mut attr = PinAttr {
+        pathname: pathname.as_ptr() as u64,
+        bpf_fd: fd.as_raw_fd() as u32,
+        file_flags: 0,
+    };
+    sys_bpf(BPF_OBJ_PIN, &mut attr).with_context(|| format!("failed to pin {}", path.display()))?;
+    Ok(())
+}
+
+fn sys_bpf<T>(cmd: libc::c_long, attr: &mut T) -> io::Result<libc::c_long> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T,
+            mem::size_of::<T>() as u32,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
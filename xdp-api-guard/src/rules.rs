@@ -0,0 +1,174 @@
+//! Hit counters of the `--service-rate` and `--dscp-policy` rules, to find the ones that never
+//! match.
+//!
+//! The kernel counts on the rule entry it fetched for the decision anyway, one copy per CPU;
+//! this sums the copies. Counts start at zero with every load of the program.
+
+use std::{fmt::Write as _, time::UNIX_EPOCH};
+
+use anyhow::bail;
+use aya::{
+    maps::{MapData, PerCpuArray, PerCpuHashMap, PerCpuValues},
+    util::nr_cpus,
+};
+use serde::Serialize;
+use xdp_api_guard_common::{Rule, dscp_action};
+
+use crate::timebase;
+
+pub struct Rules {
+    // `None` in a program built without port rules or DSCP classes
+    service: Option<PerCpuHashMap<MapData, u16, Rule>>,
+    dscp: Option<PerCpuArray<MapData, Rule>>,
+    // Only the code points given on the command line are rules, the rest are normal
+    dscp_points: Vec<u8>,
+}
+
+/// One rule as `rules` and `/v1/rules` show it.
+#[derive(Debug, Serialize)]
+pub struct RuleStats {
+    /// `service` or `dscp`.
+    pub kind: &'static str,
+    /// Port of a service rule, code point of a DSCP rule.
+    pub key: u16,
+    /// `rate N` or the DSCP action.
+    pub rule: String,
+    pub hits: u64,
+    /// Unix time of the last match, absent if the rule never matched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_match: Option<u64>,
+}
+
+/// Which rules to list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuleFilter {
+    All,
+    /// Rules that never matched.
+    Unused,
+    /// Rules that didn't match in this many seconds, never-matched ones included.
+    Idle(u64),
+}
+
+impl Rules {
+    /// Writes the rules into their maps. Called before the program is attached. Rules for a
+    /// map the program lacks are an error; the caller drops them first.
+    pub fn install(
+        mut service: Option<PerCpuHashMap<MapData, u16, Rule>>,
+        mut dscp: Option<PerCpuArray<MapData, Rule>>,
+        service_rate: &[(u16, u64)],
+        dscp_policy: &[(u8, u8)],
+    ) -> anyhow::Result<Self> {
+        let cpus = nr_cpus().map_err(|(_, e)| e)?;
+        let copies = |value| PerCpuValues::try_from(vec![Rule::new(value); cpus]);
+        if !service_rate.is_empty() {
+            let Some(service) = &mut service else {
+                bail!("the eBPF program is built without port rules");
+            };
+            for (port, rate) in service_rate {
+                service.insert(port, copies(*rate)?, 0)?;
+            }
+        }
+        if !dscp_policy.is_empty() {
+            let Some(dscp) = &mut dscp else {
+                bail!("the eBPF program is built without DSCP classes");
+            };
+            for (code, action) in dscp_policy {
+                dscp.set(u32::from(*code), copies(u64::from(*action))?, 0)?;
+            }
+        }
+        let mut dscp_points: Vec<u8> = dscp_policy.iter().map(|(code, _)| *code).collect();
+        dscp_points.sort_unstable();
+        dscp_points.dedup();
+        Ok(Self {
+            service,
+            dscp,
+            dscp_points,
+        })
+    }
+
+    /// Service rules by port, then DSCP rules by code point.
+    pub fn stats(&self, filter: RuleFilter) -> anyhow::Result<Vec<RuleStats>> {
+        let mut rules = Vec::new();
+        let mut ports = Vec::new();
+        for entry in self.service.iter().flat_map(|service| service.iter()) {
+            let (port, copies) = entry?;
+            ports.push((port, copies));
+        }
+        ports.sort_by_key(|(port, _)| *port);
+        for (port, copies) in ports {
+            let (value, hits, last_match) = sum(&copies);
+            rules.push(RuleStats {
+                kind: "service",
+                key: port,
+                rule: format!("rate {value}"),
+                hits,
+                last_match,
+            });
+        }
+        // Without the map there are no code points either, `install` saw to that
+        if let Some(dscp) = &self.dscp {
+            for code in &self.dscp_points {
+                let (value, hits, last_match) = sum(&dscp.get(&u32::from(*code), 0)?);
+                rules.push(RuleStats {
+                    kind: "dscp",
+                    key: u16::from(*code),
+                    rule: action_name(value).to_owned(),
+                    hits,
+                    last_match,
+                });
+            }
+        }
+        let now = timebase::unix_now();
+        rules.retain(|rule| match filter {
+            RuleFilter::All => true,
+            RuleFilter::Unused => rule.last_match.is_none(),
+            RuleFilter::Idle(secs) => rule
+                .last_match
+                .is_none_or(|at| now.saturating_sub(at) >= secs),
+        });
+        Ok(rules)
+    }
+}
+
+// The rule, its hits over all CPUs and the latest match of any of them
+fn sum(copies: &PerCpuValues<Rule>) -> (u64, u64, Option<u64>) {
+    let value = copies.first().map_or(0, |rule| rule.value);
+    let hits = copies.iter().map(|rule| rule.hits).sum();
+    let last = copies.iter().map(|rule| rule.last_match).max().unwrap_or(0);
+    let last_match = (last != 0).then(|| {
+        timebase::to_wallclock(last)
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    });
+    (value, hits, last_match)
+}
+
+fn action_name(action: u64) -> &'static str {
+    match action as u8 {
+        dscp_action::NORMAL => "normal",
+        dscp_action::EXEMPT => "exempt",
+        dscp_action::TIGHT => "tight",
+        dscp_action::DROP => "drop",
+        _ => "unknown",
+    }
+}
+
+/// The reply of `rules`, one line per rule.
+pub fn render(rules: &[RuleStats]) -> String {
+    let now = timebase::unix_now();
+    let mut out = format!("ok {} rules", rules.len());
+    for rule in rules {
+        let _ = write!(
+            out,
+            "\n{:<8} {:<6} {:<12} {:>10} hits  ",
+            rule.kind, rule.key, rule.rule, rule.hits
+        );
+        match rule.last_match {
+            Some(at) => {
+                let _ = write!(out, "last match {}s ago", now.saturating_sub(at));
+            }
+            None => out.push_str("never matched"),
+        }
+    }
+    out
+}
@@ -0,0 +1,336 @@
+//! Where the daemon's settings come from. Each option is taken from the command line, else
+//! from its `GUARD_*` variable, else from its default. Secrets can also be given as
+//! `GUARD_<NAME>_FILE`, the path of a file holding the value, for secret stores that hand out
+//! files (Docker and Kubernetes secrets). `xdp-api-guard print-config` shows what each option
+//! ends up as and where that came from.
+
+use std::{ffi::OsString, fmt, fs, path::Path};
+
+use anyhow::{Context as _, bail};
+use clap::{CommandFactory as _, parser::ValueSource};
+
+use crate::daemon::Opt;
+
+/// Flags of the options that hold secrets. Each also reads `GUARD_<NAME>_FILE`, and
+/// print-config never shows its value.
+pub const SECRETS: &[&str] = &["http-token"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Source {
+    CommandLine,
+    /// The variable's name.
+    Env(String),
+    /// The name of the `_FILE` variable.
+    File(String),
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::CommandLine => f.write_str("command line"),
+            Source::Env(var) => write!(f, "environment, {var}"),
+            Source::File(var) => write!(f, "file named by {var}"),
+            Source::Default => f.write_str("default"),
+        }
+    }
+}
+
+/// One option as the daemon would run with it.
+#[derive(Debug)]
+pub struct Setting {
+    pub flag: String,
+    /// As given, one per occurrence. `<secret>` for secrets.
+    pub values: Vec<String>,
+    pub source: Source,
+}
+
+/// Every option the command line `args` (program name first) and the environment set or
+/// leave at a default, in the order of `--help`. Options that are unset and have no default
+/// are left out.
+pub fn resolve<I, T>(args: I) -> anyhow::Result<Vec<Setting>>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let command = Opt::command();
+    let matches = command.clone().try_get_matches_from(args)?;
+    let mut settings = Vec::new();
+    for arg in command.get_arguments() {
+        let Some(flag) = arg.get_long() else {
+            continue;
+        };
+        let id = arg.get_id().as_str();
+        let env = arg
+            .get_env()
+            .map(|env| env.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let secret = SECRETS.contains(&flag);
+        let source = match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => Source::CommandLine,
+            Some(ValueSource::EnvVariable) => Source::Env(env),
+            Some(ValueSource::DefaultValue) => Source::Default,
+            _ if secret && std::env::var_os(format!("{env}_FILE")).is_some() => {
+                Source::File(format!("{env}_FILE"))
+            }
+            _ => continue,
+        };
+        let values = if secret {
+            vec!["<secret>".to_owned()]
+        } else {
+            matches
+                .get_raw(id)
+                .into_iter()
+                .flatten()
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect()
+        };
+        settings.push(Setting {
+            flag: flag.to_owned(),
+            values,
+            source,
+        });
+    }
+    Ok(settings)
+}
+
+/// The secret of `flag`, one of [`SECRETS`], from the file its `_FILE` variable names, with
+/// the whitespace around it trimmed. None without the variable.
+pub fn secret_file(flag: &str) -> anyhow::Result<Option<String>> {
+    let var = format!("GUARD_{}_FILE", flag.to_uppercase().replace('-', "_"));
+    let Some(path) = std::env::var_os(&var) else {
+        return Ok(None);
+    };
+    let path = Path::new(&path);
+    let secret = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {} from {var}", path.display()))?;
+    let secret = secret.trim();
+    if secret.is_empty() {
+        bail!("{} from {var} is empty", path.display());
+    }
+    // Most likely a file of named tokens, which is --http-tokens
+    if secret.contains(char::is_whitespace) {
+        bail!(
+            "{} from {var} holds more than one word, not a --{flag}",
+            path.display()
+        );
+    }
+    Ok(Some(secret.to_owned()))
+}
+
+/// Runs `xdp-api-guard print-config [OPTIONS]`: the options as the daemon would take them
+/// with `OPTIONS` and this environment, one per line with where it came from.
+pub fn run(args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let settings = resolve(args)?;
+    for flag in SECRETS {
+        secret_file(flag)?;
+    }
+    for setting in settings {
+        let option = format!("--{} {}", setting.flag, setting.values.join(","));
+        println!("{option:<48} {}", setting.source);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    // Set in the process a test runs itself in
+    const CHILD: &str = "GUARD_TEST_SETTINGS_CHILD";
+
+    // clap reads the process's environment, which the tests share. So a test that needs
+    // variables runs itself again in a process of its own with `env` and nothing else of
+    // GUARD_* set: true there, false in the test that started it once the other passed
+    fn in_child(test: &str, env: &[(&str, &str)]) -> bool {
+        if std::env::var_os(CHILD).is_some() {
+            return true;
+        }
+        let mut command = Command::new(std::env::current_exe().unwrap());
+        command.args([
+            "--exact",
+            &format!("settings::tests::{test}"),
+            "--test-threads=1",
+        ]);
+        for (var, _) in std::env::vars_os() {
+            if var.to_string_lossy().starts_with("GUARD_") {
+                command.env_remove(var);
+            }
+        }
+        let output = command
+            .env(CHILD, "1")
+            .envs(env.iter().copied())
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{test} failed:\n{stdout}");
+        assert!(stdout.contains("1 passed"), "{test} didn't run:\n{stdout}");
+        false
+    }
+
+    fn setting<'a>(settings: &'a [Setting], flag: &str) -> &'a Setting {
+        settings.iter().find(|s| s.flag == flag).unwrap()
+    }
+
+    fn from(settings: &[Setting], flag: &str) -> (Vec<&str>, &Source) {
+        let setting = setting(settings, flag);
+        let values = setting.values.iter().map(String::as_str).collect();
+        (values, &setting.source)
+    }
+
+    fn env(var: &str) -> Source {
+        Source::Env(var.to_owned())
+    }
+
+    #[test]
+    fn the_command_line_beats_the_environment_beats_defaults() {
+        let env_vars = [
+            ("GUARD_IFACE", "eth1"),
+            ("GUARD_RATE", "50"),
+            ("GUARD_WINDOW", "500"),
+            ("GUARD_MIN_MSS", "600"),
+            ("GUARD_MGMT_CIDR", "10.0.0.0/8,192.168.0.0/16"),
+            ("GUARD_PPPOE", "true"),
+            ("GUARD_TRACKING_IDLE_SECS", "60"),
+        ];
+        if !in_child(
+            "the_command_line_beats_the_environment_beats_defaults",
+            &env_vars,
+        ) {
+            return;
+        }
+        let settings = resolve([
+            "xdp-api-guard",
+            "--rate",
+            "70",
+            "--mgmt-cidr",
+            "172.16.0.0/12",
+            "--tracking-idle-secs",
+            "90",
+        ])
+        .unwrap();
+        let cli = &Source::CommandLine;
+        assert_eq!(from(&settings, "rate"), (vec!["70"], cli));
+        assert_eq!(from(&settings, "mgmt-cidr"), (vec!["172.16.0.0/12"], cli));
+        assert_eq!(from(&settings, "tracking-idle-secs"), (vec!["90"], cli));
+        assert_eq!(
+            from(&settings, "iface"),
+            (vec!["eth1"], &env("GUARD_IFACE"))
+        );
+        assert_eq!(
+            from(&settings, "window"),
+            (vec!["500"], &env("GUARD_WINDOW"))
+        );
+        assert_eq!(
+            from(&settings, "min-mss"),
+            (vec!["600"], &env("GUARD_MIN_MSS"))
+        );
+        assert_eq!(
+            from(&settings, "pppoe"),
+            (vec!["true"], &env("GUARD_PPPOE"))
+        );
+        let default = &Source::Default;
+        assert_eq!(from(&settings, "min-mss-action"), (vec!["count"], default));
+        assert_eq!(from(&settings, "control-socket").1, default);
+        // Nothing set and no default
+        assert!(settings.iter().all(|s| s.flag != "http-listen"));
+    }
+
+    #[test]
+    fn without_the_environment_everything_is_a_default() {
+        if !in_child("without_the_environment_everything_is_a_default", &[]) {
+            return;
+        }
+        let settings = resolve(["xdp-api-guard"]).unwrap();
+        assert!(settings.iter().all(|s| s.source == Source::Default));
+        assert_eq!(from(&settings, "rate").0, ["10"]);
+        assert_eq!(from(&settings, "iface").0, ["enp0s3"]);
+    }
+
+    #[test]
+    fn windows_too_long_for_nanoseconds_are_refused() {
+        if !in_child("windows_too_long_for_nanoseconds_are_refused", &[]) {
+            return;
+        }
+        let longest = (u64::MAX / 1_000_000).to_string();
+        let over = (u64::MAX / 1_000_000 + 1).to_string();
+        for flag in ["--window", "--window6"] {
+            let settings = resolve(["xdp-api-guard", flag, &longest]).unwrap();
+            assert_eq!(from(&settings, &flag[2..]).0, [longest.as_str()]);
+            assert!(resolve(["xdp-api-guard", flag, &over]).is_err(), "{flag}");
+            assert!(resolve(["xdp-api-guard", flag, "0"]).is_err(), "{flag}");
+        }
+        let over = (u64::MAX / 1_000_000_000 + 1).to_string();
+        assert!(resolve(["xdp-api-guard", "--tracking-idle-secs", &over]).is_err());
+    }
+
+    fn token_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("guard-token-{}-{name}", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn secrets_are_read_from_files() {
+        let path = token_file("one", "  s3cr3t0123456789abcd\n");
+        let env = [("GUARD_HTTP_TOKEN_FILE", path.as_str())];
+        if !in_child("secrets_are_read_from_files", &env) {
+            fs::remove_file(path).unwrap();
+            return;
+        }
+        let secret = secret_file("http-token").unwrap();
+        assert_eq!(secret.as_deref(), Some("s3cr3t0123456789abcd"));
+        let settings = resolve(["xdp-api-guard"]).unwrap();
+        let file = Source::File("GUARD_HTTP_TOKEN_FILE".to_owned());
+        assert_eq!(from(&settings, "http-token"), (vec!["<secret>"], &file));
+    }
+
+    #[test]
+    fn a_secret_given_directly_wins_and_is_never_shown() {
+        let path = token_file("direct", "s3cr3t0123456789abcd");
+        let env = [
+            ("GUARD_HTTP_TOKEN_FILE", path.as_str()),
+            ("GUARD_HTTP_TOKEN", "fromtheenvironment01"),
+        ];
+        if !in_child("a_secret_given_directly_wins_and_is_never_shown", &env) {
+            fs::remove_file(path).unwrap();
+            return;
+        }
+        let settings = resolve(["xdp-api-guard"]).unwrap();
+        let token = env("GUARD_HTTP_TOKEN");
+        assert_eq!(from(&settings, "http-token"), (vec!["<secret>"], &token));
+        let settings = resolve(["xdp-api-guard", "--http-token", "onthecommandline01"]).unwrap();
+        assert_eq!(
+            from(&settings, "http-token"),
+            (vec!["<secret>"], &Source::CommandLine)
+        );
+        assert!(
+            settings
+                .iter()
+                .flat_map(|s| &s.values)
+                .all(|value| !value.contains("onthecommandline"))
+        );
+    }
+
+    #[test]
+    fn token_files_are_refused_as_a_secret() {
+        let path = token_file("named", "noc read-only 3d1f0c9a6b2e47d8a1c4\n");
+        let env = [("GUARD_HTTP_TOKEN_FILE", path.as_str())];
+        if !in_child("token_files_are_refused_as_a_secret", &env) {
+            fs::remove_file(path).unwrap();
+            return;
+        }
+        let e = secret_file("http-token").unwrap_err().to_string();
+        assert!(e.contains("more than one word"), "{e}");
+    }
+
+    #[test]
+    fn secrets_without_a_file_are_none() {
+        if !in_child("secrets_without_a_file_are_none", &[]) {
+            return;
+        }
+        assert_eq!(secret_file("http-token").unwrap(), None);
+    }
+}
@@ -0,0 +1,533 @@
+//! `xdp-api-guard setup`: the questions of a first install, asked on the terminal, answered
+//! with what `init` writes.
+//!
+//! It lists the interfaces with their addresses and what their drivers support of XDP, asks
+//! which one to protect, which networks the host is managed from, whether to start observing
+//! only and otherwise which preset's limits to enforce, and hands the answers to `init`. The
+//! suggested answers come from the host: the interface of the default route, and the SSH
+//! session the wizard runs in as a management network. The default gateway needs no answer,
+//! the daemon exempts it itself (see `neigh.rs`).
+//!
+//! XDP support is taken from the netdev netlink family rather than from attaching a program
+//! to see whether it sticks: a native attach resets the rings of some drivers, and the link
+//! with them, which is the SSH session asking. Kernels before 6.3 don't say, and show
+//! "unknown".
+//!
+//! Without a terminal on both stdin and stdout there is nobody to ask, so the `init` command
+//! the suggested answers make is printed instead and nothing is written.
+
+use std::{
+    collections::HashMap,
+    env,
+    ffi::CStr,
+    fs,
+    io::{self, IsTerminal as _, Write as _},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, bail};
+use clap::{Parser, ValueEnum as _};
+use xdp_api_guard_common::DEFAULT_CONTROL_SOCKET;
+
+use crate::{
+    cidr::Ipv4Cidr,
+    netlink::{self, xdp_feature},
+    preset::{self, InitOpt, Preset},
+};
+
+// How long the self-test waits for a started daemon's control socket
+const START_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Write a configuration by answering questions
+#[derive(Debug, Parser)]
+#[clap(name = "xdp-api-guard setup")]
+pub struct SetupOpt {
+    /// Environment file to write
+    #[clap(long, default_value = preset::DEFAULT_OUTPUT)]
+    output: PathBuf,
+}
+
+struct Interface {
+    name: String,
+    index: u32,
+    up: bool,
+    // With their prefix lengths
+    addrs: Vec<(IpAddr, u32)>,
+    // `None` for virtual devices
+    driver: Option<String>,
+}
+
+impl Interface {
+    fn v4(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.addrs.iter().filter_map(|(addr, _)| match addr {
+            IpAddr::V4(addr) => Some(*addr),
+            IpAddr::V6(_) => None,
+        })
+    }
+}
+
+// What the wizard knows of the host before asking anything
+struct Host {
+    interfaces: Vec<Interface>,
+    // By ifindex, `None` when the kernel doesn't say
+    xdp: Option<HashMap<u32, u64>>,
+    // Interface and gateway of the default route
+    route: Option<(String, Ipv4Addr)>,
+    // Where the SSH sessions on the host come from
+    peers: Vec<Ipv4Addr>,
+}
+
+impl Host {
+    fn detect() -> anyhow::Result<Self> {
+        let interfaces = interfaces()?;
+        if interfaces.is_empty() {
+            bail!("no network interfaces other than loopback");
+        }
+        Ok(Self {
+            interfaces,
+            // Only shown, a failure is as good as an old kernel
+            xdp: netlink::xdp_features().ok().flatten(),
+            route: default_route(),
+            peers: ssh_peers(),
+        })
+    }
+
+    // The interface of the default route, or the first one up with an IPv4 address
+    fn suggested(&self) -> usize {
+        let route = self.route.as_ref().and_then(|(iface, _)| {
+            self.interfaces
+                .iter()
+                .position(|interface| interface.name == *iface)
+        });
+        route
+            .or_else(|| {
+                self.interfaces
+                    .iter()
+                    .position(|interface| interface.up && interface.v4().next().is_some())
+            })
+            .unwrap_or(0)
+    }
+
+    fn xdp(&self, interface: &Interface) -> &'static str {
+        let Some(xdp) = &self.xdp else {
+            return "XDP unknown";
+        };
+        match xdp.get(&interface.index).copied().unwrap_or(0) {
+            bits if bits & xdp_feature::RX_SG != 0 => "native XDP, multi-buffer",
+            bits if bits & xdp_feature::BASIC != 0 => "native XDP",
+            _ => "generic XDP only",
+        }
+    }
+
+    fn native(&self, interface: &Interface) -> Option<bool> {
+        let xdp = self.xdp.as_ref()?;
+        Some(xdp.get(&interface.index).copied().unwrap_or(0) & xdp_feature::BASIC != 0)
+    }
+}
+
+/// Runs `xdp-api-guard setup`.
+pub fn run(opt: SetupOpt) -> anyhow::Result<()> {
+    let host = Host::detect()?;
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        let init = InitOpt {
+            preset: Preset::ObserveOnly,
+            iface: host.interfaces[host.suggested()].name.clone(),
+            mgmt_cidr: host.peers.iter().copied().map(host_cidr).collect(),
+            output: opt.output,
+            force: false,
+            install: false,
+        };
+        eprintln!("setup asks its questions on a terminal, this writes what it would suggest:");
+        println!("{}", init.command());
+        bail!("no terminal, nothing written");
+    }
+    println!("Interfaces:");
+    for (n, interface) in host.interfaces.iter().enumerate() {
+        let addrs = interface
+            .addrs
+            .iter()
+            .map(|(addr, len)| format!("{addr}/{len}"))
+            .collect::<Vec<_>>();
+        println!(
+            "  {}) {:<12} {:<4} {:<18} {:<26} {}",
+            n + 1,
+            interface.name,
+            if interface.up { "up" } else { "down" },
+            interface.driver.as_deref().unwrap_or("virtual"),
+            host.xdp(interface),
+            if addrs.is_empty() {
+                "no addresses".to_owned()
+            } else {
+                addrs.join(" ")
+            }
+        );
+    }
+    if host.xdp.is_none() {
+        println!("  (this kernel doesn't say what its drivers support of XDP)");
+    }
+    let interface = loop {
+        let answer = ask("Interface to protect", &(host.suggested() + 1).to_string())?;
+        let found = match answer.parse::<usize>() {
+            Ok(n) => n.checked_sub(1).and_then(|n| host.interfaces.get(n)),
+            Err(_) => host.interfaces.iter().find(|i| i.name == answer),
+        };
+        match found {
+            Some(interface) => break interface,
+            None => println!("  no interface {answer}, give its number or name"),
+        }
+    };
+    if host.native(interface) == Some(false) {
+        println!(
+            "  {}'s driver has no native XDP, the guard runs as generic XDP on it, after the \
+             kernel has allocated each packet, and costs more CPU a packet",
+            interface.name
+        );
+    }
+
+    println!();
+    if let Some((_, gateway)) = host
+        .route
+        .as_ref()
+        .filter(|(iface, _)| *iface == interface.name)
+    {
+        println!(
+            "The default gateway {gateway} is exempt without asking, as are other routers on \
+             the link"
+        );
+    }
+    match host.peers.as_slice() {
+        [] => println!("No SSH session found to suggest as a management network"),
+        peers => println!(
+            "SSH sessions come from {}",
+            peers
+                .iter()
+                .map(Ipv4Addr::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+    let suggested: Vec<_> = host.peers.iter().copied().map(host_cidr).collect();
+    let mgmt = loop {
+        let default = match suggested.as_slice() {
+            [] => "none".to_owned(),
+            cidrs => cidrs
+                .iter()
+                .map(Ipv4Cidr::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        let answer = ask(
+            "Networks the host is managed from, never blocked or limited (comma-separated, \
+             or none)",
+            &default,
+        )?;
+        if answer == "none" {
+            break Vec::new();
+        }
+        match answer
+            .split(',')
+            .map(|cidr| cidr.trim().parse::<Ipv4Cidr>())
+            .collect::<anyhow::Result<Vec<_>>>()
+        {
+            Ok(cidrs) => break cidrs,
+            Err(e) => println!("  {e:#}"),
+        }
+    };
+    let unexempt: Vec<_> = host
+        .peers
+        .iter()
+        .filter(|peer| !mgmt.iter().any(|cidr| cidr.contains(**peer)))
+        .collect();
+    if !unexempt.is_empty()
+        && !yes(
+            &format!(
+                "SSH from {} isn't exempt and can be limited like any client. Continue?",
+                unexempt
+                    .iter()
+                    .map(|peer| peer.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            false,
+        )?
+    {
+        bail!("nothing written");
+    }
+
+    println!();
+    let preset = if yes(
+        "Start in observe-only mode, dropping nothing and learning normal rates for a day?",
+        true,
+    )? {
+        Preset::ObserveOnly
+    } else {
+        let presets: Vec<_> = Preset::value_variants()
+            .iter()
+            .copied()
+            .filter(|preset| *preset != Preset::ObserveOnly)
+            .collect();
+        println!("Limits:");
+        for (n, preset) in presets.iter().enumerate() {
+            let help = preset
+                .to_possible_value()
+                .and_then(|value| value.get_help().map(ToString::to_string))
+                .unwrap_or_default();
+            println!("  {}) {:<12} {help}", n + 1, preset.name());
+        }
+        loop {
+            let answer = ask("Limits to enforce", "1")?;
+            let found = match answer.parse::<usize>() {
+                Ok(n) => n.checked_sub(1).and_then(|n| presets.get(n)),
+                Err(_) => presets.iter().find(|preset| preset.name() == answer),
+            };
+            match found {
+                Some(preset) => break *preset,
+                None => println!("  no preset {answer}, give its number or name"),
+            }
+        }
+    };
+
+    println!();
+    let force = opt.output.exists();
+    if force
+        && !yes(
+            &format!("{} exists. Replace it?", opt.output.display()),
+            false,
+        )?
+    {
+        bail!("kept {}, nothing written", opt.output.display());
+    }
+    let install = if unsafe { libc::geteuid() } == 0 {
+        yes("Install a systemd unit reading it?", true)?
+    } else {
+        println!("Not root, the systemd unit isn't offered");
+        false
+    };
+    let init = InitOpt {
+        preset,
+        iface: interface.name.clone(),
+        mgmt_cidr: mgmt.clone(),
+        output: opt.output,
+        force,
+        install,
+    };
+    let command = init.command();
+    println!();
+    preset::init(init)?;
+    println!("\nThe same without questions:\n  {command}");
+
+    if install && yes("Start the guard now and test it?", true)? {
+        let probe = host
+            .peers
+            .first()
+            .copied()
+            .or_else(|| mgmt.first().map(|cidr| cidr.addr()));
+        self_test(probe)?;
+    }
+    Ok(())
+}
+
+// Starts the unit and asks the daemon what it does with a packet from `probe`
+fn self_test(probe: Option<Ipv4Addr>) -> anyhow::Result<()> {
+    let status = Command::new("systemctl")
+        .args(["enable", "--now", "xdp-api-guard"])
+        .status()
+        .context("failed to run systemctl")?;
+    if !status.success() {
+        bail!("systemctl enable --now xdp-api-guard failed, see journalctl -u xdp-api-guard");
+    }
+    // The socket is there once the program is attached
+    let started = Instant::now();
+    while !Path::new(DEFAULT_CONTROL_SOCKET).exists() {
+        if started.elapsed() > START_TIMEOUT {
+            bail!(
+                "no {DEFAULT_CONTROL_SOCKET} after {}s, see journalctl -u xdp-api-guard",
+                START_TIMEOUT.as_secs()
+            );
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+    let guardctl = env::current_exe()
+        .context("failed to find this binary")?
+        .with_file_name("guardctl");
+    let mut tests = vec![vec!["status".to_owned()]];
+    tests.extend(probe.map(|ip| vec!["verify".to_owned(), ip.to_string()]));
+    for args in tests {
+        println!("\n$ guardctl {}", args.join(" "));
+        let status = Command::new(&guardctl)
+            .args(&args)
+            .status()
+            .with_context(|| format!("failed to run {}", guardctl.display()))?;
+        if !status.success() {
+            bail!("guardctl {} failed", args.join(" "));
+        }
+    }
+    Ok(())
+}
+
+// A question on stdout, the answer from stdin, `default` for an empty one
+fn ask(question: &str, default: &str) -> anyhow::Result<String> {
+    print!("{question} [{default}]: ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer)? == 0 {
+        bail!("no answer, nothing written");
+    }
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_owned())
+}
+
+fn yes(question: &str, default: bool) -> anyhow::Result<bool> {
+    let hint = if default { "Y/n" } else { "y/N" };
+    loop {
+        match ask(question, hint)?.as_str() {
+            "Y/n" => return Ok(true),
+            "y/N" => return Ok(false),
+            "y" | "Y" | "yes" => return Ok(true),
+            "n" | "N" | "no" => return Ok(false),
+            _ => println!("  y or n"),
+        }
+    }
+}
+
+fn host_cidr(ip: Ipv4Addr) -> Ipv4Cidr {
+    // Any address makes a valid /32
+    Ipv4Cidr::new(ip, 32).unwrap()
+}
+
+// Every interface but loopback, with its addresses, in the kernel's order
+fn interfaces() -> anyhow::Result<Vec<Interface>> {
+    let mut head = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut head) } != 0 {
+        return Err(io::Error::last_os_error()).context("failed to list the interfaces");
+    }
+    let mut found: Vec<Interface> = Vec::new();
+    let mut next = head;
+    while !next.is_null() {
+        // Valid until freeifaddrs below
+        let ifa = unsafe { &*next };
+        next = ifa.ifa_next;
+        if ifa.ifa_flags & libc::IFF_LOOPBACK as u32 != 0 {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        let at = match found.iter().position(|interface| interface.name == name) {
+            Some(at) => at,
+            None => {
+                found.push(Interface {
+                    index: unsafe { libc::if_nametoindex(ifa.ifa_name) },
+                    up: ifa.ifa_flags & libc::IFF_UP as u32 != 0,
+                    driver: driver(&name),
+                    addrs: Vec::new(),
+                    name,
+                });
+                found.len() - 1
+            }
+        };
+        found[at].addrs.extend(address(ifa));
+    }
+    unsafe { libc::freeifaddrs(head) };
+    Ok(found)
+}
+
+// An IPv4 or IPv6 address of `ifa` and its prefix length
+fn address(ifa: &libc::ifaddrs) -> Option<(IpAddr, u32)> {
+    if ifa.ifa_addr.is_null() {
+        return None;
+    }
+    let mask = (!ifa.ifa_netmask.is_null()).then_some(ifa.ifa_netmask);
+    match i32::from(unsafe { (*ifa.ifa_addr).sa_family }) {
+        libc::AF_INET => {
+            let addr = unsafe { &*ifa.ifa_addr.cast::<libc::sockaddr_in>() };
+            let len = mask.map_or(32, |mask| {
+                let mask = unsafe { &*mask.cast::<libc::sockaddr_in>() };
+                mask.sin_addr.s_addr.count_ones()
+            });
+            let addr = Ipv4Addr::from(addr.sin_addr.s_addr.to_ne_bytes());
+            Some((IpAddr::V4(addr), len))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*ifa.ifa_addr.cast::<libc::sockaddr_in6>() };
+            let len = mask.map_or(128, |mask| {
+                let mask = unsafe { &*mask.cast::<libc::sockaddr_in6>() };
+                u128::from_ne_bytes(mask.sin6_addr.s6_addr).count_ones()
+            });
+            Some((IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)), len))
+        }
+        _ => None,
+    }
+}
+
+fn driver(iface: &str) -> Option<String> {
+    let link = fs::read_link(format!("/sys/class/net/{iface}/device/driver")).ok()?;
+    Some(link.file_name()?.to_string_lossy().into_owned())
+}
+
+// The default route of the lowest metric from /proc/net/route, whose addresses are
+// network-order words printed as native integers
+fn default_route() -> Option<(String, Ipv4Addr)> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let [iface, dest, gateway, _, _, _, metric, mask, ..] = fields[..] else {
+                return None;
+            };
+            if dest != "00000000" || mask != "00000000" {
+                return None;
+            }
+            let gateway = u32::from_str_radix(gateway, 16).ok()?;
+            let metric = metric.parse::<u32>().ok()?;
+            Some((
+                metric,
+                iface.to_owned(),
+                Ipv4Addr::from(gateway.to_ne_bytes()),
+            ))
+        })
+        .min_by_key(|(metric, ..)| *metric)
+        .map(|(_, iface, gateway)| (iface, gateway))
+}
+
+// The peer of this SSH session, or under sudo, which drops `SSH_CONNECTION`, the peers of
+// every established connection to port 22
+fn ssh_peers() -> Vec<Ipv4Addr> {
+    if let Some(peer) = env::var("SSH_CONNECTION")
+        .ok()
+        .and_then(|conn| conn.split_whitespace().next()?.parse().ok())
+    {
+        return vec![peer];
+    }
+    let Ok(table) = fs::read_to_string("/proc/net/tcp") else {
+        return Vec::new();
+    };
+    let mut peers: Vec<Ipv4Addr> = table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let [_, local, remote, state, ..] = fields[..] else {
+                return None;
+            };
+            // 01 is TCP_ESTABLISHED
+            let (_, port) = local.split_once(':')?;
+            if state != "01" || u16::from_str_radix(port, 16).ok()? != 22 {
+                return None;
+            }
+            let (addr, _) = remote.split_once(':')?;
+            let addr = u32::from_str_radix(addr, 16).ok()?;
+            Some(Ipv4Addr::from(addr.to_ne_bytes()))
+        })
+        .filter(|peer| !peer.is_loopback())
+        .collect();
+    peers.sort_unstable();
+    peers.dedup();
+    peers
+}
@@ -0,0 +1,657 @@
+//! `snapshot save` and `snapshot load`: the blocklist, management networks and limits of a
+//! running daemon as one versioned JSON file, for backups and for moving the guard to another
+//! host. Limiter entries are included on request.
+//!
+//! Limiter timestamps are kernel clock readings and mean nothing on another host or after a
+//! reboot, so entries are stored by age and rebased onto the clock of the loading daemon.
+//! TTLs are wall-clock times already and carry over as they are.
+//!
+//! The commands name a file in `--snapshot-dir` and nothing else: no paths, no `..`, and no
+//! symlinks, which would let a control client read or replace any file the daemon can. A save
+//! goes to a new temporary file next to the target, created exclusively, and replaces the
+//! target once it is complete.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, OpenOptions},
+    io::{Read as _, Write as _},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::unix::fs::OpenOptionsExt as _,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{Context as _, bail};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use xdp_api_guard_common::{Config, Origin, PacketLog, config_flags};
+
+use crate::{
+    blocklist::{BlocklistHandle, Entry},
+    cidr::Ipv4Cidr,
+    control::ControlState,
+    journal,
+    mask::Masked,
+    recidivist::History,
+    timebase,
+};
+
+/// Bumped when the file format changes. Files of another version are refused.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    /// Unix time the snapshot was taken.
+    created: u64,
+    limits: Limits,
+    blocklist: Vec<BlockRecord>,
+    management: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    limiters: Vec<LimiterRecord>,
+    /// Ban histories for `--recidivist-after`, in state files only.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    history: Vec<HistoryRecord>,
+}
+
+/// The settings commands can change at runtime. Everything else comes from the command line
+/// of the loading daemon.
+#[derive(Debug, Serialize, Deserialize)]
+struct Limits {
+    rate_limit: u64,
+    window_ns: u64,
+    rate_limit6: u64,
+    window_ns6: u64,
+    local_rate_limit: u64,
+    quic_initial_limit: u64,
+    ack_limit: u64,
+    http_rps_limit: u64,
+    paused: bool,
+    /// `Config::observe`, absent from snapshots taken before features could observe.
+    #[serde(default)]
+    observe: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockRecord {
+    addr: Ipv4Addr,
+    origin: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    node: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryRecord {
+    addr: Ipv4Addr,
+    #[serde(flatten)]
+    history: History,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LimiterRecord {
+    addr: String,
+    count: u64,
+    /// Milliseconds since the entry's window started.
+    age_ms: u64,
+    /// Seconds since the limiter first saw the source.
+    tracked_secs: u64,
+    #[serde(default)]
+    credit: u64,
+}
+
+impl Limits {
+    fn of(cfg: &Config) -> Self {
+        Self {
+            rate_limit: cfg.rate_limit,
+            window_ns: cfg.window_ns,
+            rate_limit6: cfg.rate_limit6,
+            window_ns6: cfg.window_ns6,
+            local_rate_limit: cfg.local_rate_limit,
+            quic_initial_limit: cfg.quic_initial_limit,
+            ack_limit: cfg.ack_limit,
+            http_rps_limit: cfg.http_rps_limit,
+            paused: cfg.has(config_flags::PAUSED),
+            observe: cfg.observe,
+        }
+    }
+
+    fn apply(&self, cfg: &mut Config) {
+        cfg.rate_limit = self.rate_limit;
+        cfg.window_ns = self.window_ns;
+        cfg.rate_limit6 = self.rate_limit6;
+        cfg.window_ns6 = self.window_ns6;
+        cfg.local_rate_limit = self.local_rate_limit;
+        cfg.quic_initial_limit = self.quic_initial_limit;
+        cfg.ack_limit = self.ack_limit;
+        cfg.http_rps_limit = self.http_rps_limit;
+        cfg.observe = self.observe;
+        if self.paused {
+            cfg.flags |= config_flags::PAUSED;
+        } else {
+            cfg.flags &= !config_flags::PAUSED;
+        }
+    }
+}
+
+impl Snapshot {
+    /// The limits in `cfg` and the blocklist entries whose origin `keep` accepts, plus the
+    /// management networks if asked.
+    fn new(
+        cfg: &Config,
+        blocklist: &BlocklistHandle,
+        keep: impl Fn(Origin) -> bool,
+        management: bool,
+    ) -> Self {
+        let mut records: Vec<BlockRecord> = blocklist
+            .entries()
+            .filter(|(_, entry)| keep(entry.origin))
+            .map(|(addr, entry)| BlockRecord {
+                addr,
+                origin: entry.origin.name().to_owned(),
+                expires: entry.expires,
+                node: entry.node.clone(),
+            })
+            .collect();
+        records.sort_by_key(|record| record.addr);
+        let management = if management {
+            blocklist
+                .management()
+                .iter()
+                .map(|c| c.to_string())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Self {
+            version: SNAPSHOT_VERSION,
+            created: timebase::unix_now(),
+            limits: Limits::of(cfg),
+            blocklist: records,
+            management,
+            limiters: Vec::new(),
+            history: Vec::new(),
+        }
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(path, &data)
+    }
+
+    // The same without following a symlink at `path`
+    fn read_nofollow(path: &Path) -> anyhow::Result<Self> {
+        let mut data = Vec::new();
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(path, &data)
+    }
+
+    fn parse(path: &Path, data: &[u8]) -> anyhow::Result<Self> {
+        let snapshot: Snapshot = serde_json::from_slice(data)
+            .with_context(|| format!("{} is not a snapshot", path.display()))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            bail!(
+                "snapshot version {} is not supported, expected {SNAPSHOT_VERSION}",
+                snapshot.version
+            );
+        }
+        if snapshot.limits.window_ns == 0 || snapshot.limits.window_ns6 == 0 {
+            bail!("snapshot has a zero rate-limit window");
+        }
+        Ok(snapshot)
+    }
+
+    // Replaces `path` only once the whole file is on disk. The temporary file is new, never
+    // one that was there before, so nothing planted under its name gets written through.
+    fn write(&self, path: &Path) -> anyhow::Result<()> {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let json = serde_json::to_vec_pretty(self)?;
+        let name = path
+            .file_name()
+            .with_context(|| format!("{} is not a file", path.display()))?;
+        let tmp = path.with_file_name(format!(
+            ".{}.{}.{}.tmp",
+            name.to_string_lossy(),
+            process::id(),
+            SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&tmp)
+            .with_context(|| format!("failed to create {}", tmp.display()))?;
+        let written = file
+            .write_all(&json)
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("failed to write {}", tmp.display()))
+            .and_then(|()| {
+                fs::rename(&tmp, path)
+                    .with_context(|| format!("failed to replace {}", path.display()))
+            });
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        written
+    }
+
+    /// The blocklist entries, checked, without those whose TTL ran out since.
+    fn entries(&self) -> anyhow::Result<Vec<(Ipv4Addr, Entry)>> {
+        let unix_now = timebase::unix_now();
+        let mut entries = Vec::new();
+        for record in &self.blocklist {
+            let origin = Origin::from_name(&record.origin)
+                .with_context(|| format!("{}: unknown origin {:?}", record.addr, record.origin))?;
+            if origin == Origin::Management {
+                bail!(
+                    "{}: management entries go in the management list",
+                    record.addr
+                );
+            }
+            if record.expires.is_some_and(|at| at <= unix_now) {
+                continue;
+            }
+            let entry = Entry {
+                expires: record.expires,
+                node: record.node.clone(),
+                ..Entry::new(origin)
+            };
+            entries.push((record.addr, entry));
+        }
+        Ok(entries)
+    }
+}
+
+/// Where snapshot `name` is in `dir`, `--snapshot-dir`. Only a plain file name is taken, and
+/// a symlink in its place is refused.
+pub fn resolve(dir: Option<&Path>, name: &str) -> anyhow::Result<PathBuf> {
+    let dir = dir.context("snapshots are off, start the daemon with --snapshot-dir")?;
+    // A leading dot would also take the temporary files of a save
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\0') {
+        bail!("snapshot {name:?} must be a file name in --snapshot-dir, without a path");
+    }
+    let path = dir.join(name);
+    match fs::symlink_metadata(&path) {
+        Ok(meta) if meta.file_type().is_symlink() => {
+            bail!("snapshot {} is a symlink", path.display())
+        }
+        Ok(meta) if !meta.is_file() => bail!("snapshot {} is not a file", path.display()),
+        _ => Ok(path),
+    }
+}
+
+/// Writes the daemon's state to snapshot `name`.
+pub fn save(state: &ControlState, name: &str, limiters: bool) -> anyhow::Result<String> {
+    let path = &resolve(state.snapshot_dir.as_deref(), name)?;
+    let cfg = state.config.lock().unwrap().get();
+    let mut snapshot = Snapshot::new(&cfg, &state.blocklist.lock().unwrap(), |_| true, true);
+    if limiters {
+        let now = timebase::boot_ns();
+        let v4 = state.rate_limit.lock().unwrap();
+        for (ip, log) in v4.iter().filter_map(Result::ok) {
+            if log.current_count(now, cfg.window_ns) != 0 {
+                snapshot.limiters.push(LimiterRecord::of(
+                    Ipv4Addr::from(ip).to_string(),
+                    &log,
+                    now,
+                ));
+            }
+        }
+        let v6 = state.rate_limit6.lock().unwrap();
+        for (ip, log) in v6.iter().filter_map(Result::ok) {
+            if log.current_count(now, cfg.window_ns6) != 0 {
+                snapshot.limiters.push(LimiterRecord::of(
+                    Ipv6Addr::from(ip).to_string(),
+                    &log,
+                    now,
+                ));
+            }
+        }
+    }
+    snapshot.write(path)?;
+    info!("saved snapshot to {}", path.display());
+    Ok(format!(
+        "ok saved {} blocklist entries, {} management networks, {} limiter entries",
+        snapshot.blocklist.len(),
+        snapshot.management.len(),
+        snapshot.limiters.len()
+    ))
+}
+
+/// Writes the entries `--state-file` keeps, see [`journal::persisted`], as a snapshot.
+/// Returns how many were written.
+pub fn save_blocklist(
+    path: &Path,
+    cfg: &Config,
+    blocklist: &BlocklistHandle,
+) -> anyhow::Result<usize> {
+    let mut snapshot = Snapshot::new(cfg, blocklist, journal::persisted, false);
+    snapshot.history = blocklist
+        .histories()
+        .map(|(addr, history)| HistoryRecord {
+            addr,
+            history: history.clone(),
+        })
+        .collect();
+    snapshot.history.sort_by_key(|record| record.addr);
+    snapshot.write(path)?;
+    Ok(snapshot.blocklist.len())
+}
+
+/// The blocklist entries and ban histories of a snapshot, for `--state-file`. Its limits and
+/// management networks are left alone, the command line decides those.
+pub fn read_blocklist(path: &Path) -> anyhow::Result<BlocklistState> {
+    let snapshot = Snapshot::read(path)?;
+    let entries = snapshot.entries()?;
+    let history = snapshot
+        .history
+        .into_iter()
+        .map(|record| (record.addr, record.history))
+        .collect();
+    Ok((entries, history))
+}
+
+/// What [`read_blocklist`] returns.
+pub type BlocklistState = (Vec<(Ipv4Addr, Entry)>, Vec<(Ipv4Addr, History)>);
+
+/// Restores snapshot `name`, written by [`save`]. The file is checked in full before anything
+/// is applied, and the blocklist and management networks go in all or nothing. Limiter
+/// entries that don't fit are skipped.
+pub fn load(state: &ControlState, name: &str) -> anyhow::Result<String> {
+    let path = &resolve(state.snapshot_dir.as_deref(), name)?;
+    let snapshot = Snapshot::read_nofollow(path)?;
+    let entries = snapshot.entries()?;
+    let management = snapshot
+        .management
+        .iter()
+        .map(|cidr| cidr.parse::<Ipv4Cidr>())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut limiters = Vec::new();
+    for record in &snapshot.limiters {
+        let addr: IpAddr = record
+            .addr
+            .parse()
+            .with_context(|| format!("invalid limiter address {:?}", record.addr))?;
+        limiters.push((addr, record));
+    }
+
+    // Held throughout, so no command sees a half-loaded snapshot
+    let mut config = state.config.lock().unwrap();
+    let mut blocklist = state.blocklist.lock().unwrap();
+    let previous = config.get();
+
+    let mut next = previous;
+    snapshot.limits.apply(&mut next);
+    config.update(|cfg| *cfg = next)?;
+
+    let mut added = Vec::new();
+    let written = (|| {
+        for cidr in &management {
+            if !blocklist.management().contains(cidr) {
+                blocklist.add_management(*cidr)?;
+                added.push(*cidr);
+            }
+        }
+        blocklist.insert_all(&entries)
+    })();
+    let written = match written {
+        Ok(written) => written,
+        Err(e) => {
+            for cidr in &added {
+                let _ = blocklist.remove_management(*cidr);
+            }
+            let _ = config.update(|cfg| *cfg = previous);
+            return Err(e.context("snapshot not loaded, nothing was changed"));
+        }
+    };
+    drop(blocklist);
+
+    let now = timebase::boot_ns();
+    let mut skipped = 0;
+    for (addr, record) in &limiters {
+        let log = record.rebase(now);
+        let result = match addr {
+            IpAddr::V4(ip) => state
+                .rate_limit
+                .lock()
+                .unwrap()
+                .insert(u32::from(*ip), log, 0),
+            IpAddr::V6(ip) => state
+                .rate_limit6
+                .lock()
+                .unwrap()
+                .insert(ip.octets(), log, 0),
+        };
+        if let Err(e) = result {
+            skipped += 1;
+            warn!(
+                "snapshot: limiter entry for {} not restored: {e}",
+                Masked(*addr)
+            );
+        }
+    }
+
+    info!(
+        "loaded snapshot {} taken at {}",
+        path.display(),
+        snapshot.created
+    );
+    let mut out = format!(
+        "ok loaded {written} blocklist entries, {} management networks, {} limiter entries",
+        added.len(),
+        limiters.len() - skipped
+    );
+    if skipped != 0 {
+        let _ = write!(out, " ({skipped} skipped)");
+    }
+    Ok(out)
+}
+
+impl LimiterRecord {
+    fn of(addr: String, log: &PacketLog, now: u64) -> Self {
+        Self {
+            addr,
+            count: log.count,
+            age_ms: now.saturating_sub(log.last_seen) / 1_000_000,
+            tracked_secs: now.saturating_sub(log.first_seen) / 1_000_000_000,
+            credit: log.credit,
+        }
+    }
+
+    fn rebase(&self, now: u64) -> PacketLog {
+        PacketLog {
+            count: self.count,
+            last_seen: now.saturating_sub(self.age_ms * 1_000_000),
+            first_seen: now.saturating_sub(self.tracked_secs * 1_000_000_000),
+            credit: self.credit,
+            // Not kept, the window's packets are taken as all charged
+            retransmits: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{PermissionsExt as _, symlink};
+
+    use super::*;
+
+    // A directory of its own under the system's temporary one
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("guard-snapshot-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn snapshot(blocklist: Vec<BlockRecord>) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            created: 1,
+            limits: Limits {
+                rate_limit: 100,
+                window_ns: 1_000_000_000,
+                rate_limit6: 100,
+                window_ns6: 1_000_000_000,
+                local_rate_limit: 0,
+                quic_initial_limit: 0,
+                ack_limit: 0,
+                http_rps_limit: 0,
+                paused: false,
+                observe: 0,
+            },
+            blocklist,
+            management: Vec::new(),
+            limiters: Vec::new(),
+            history: Vec::new(),
+        }
+    }
+
+    fn record(addr: [u8; 4], origin: &str, expires: Option<u64>) -> BlockRecord {
+        BlockRecord {
+            addr: Ipv4Addr::from(addr),
+            origin: origin.to_owned(),
+            expires,
+            node: None,
+        }
+    }
+
+    #[test]
+    fn only_file_names_in_the_directory() {
+        let dir = dir("names");
+        assert!(resolve(None, "state.json").is_err());
+        for name in [
+            "",
+            ".",
+            "..",
+            "../state.json",
+            "a/b",
+            "/etc/passwd",
+            ".hidden",
+        ] {
+            assert!(resolve(Some(&dir), name).is_err(), "{name:?}");
+        }
+        assert_eq!(
+            resolve(Some(&dir), "state.json").unwrap(),
+            dir.join("state.json")
+        );
+        fs::create_dir(dir.join("sub")).unwrap();
+        assert!(resolve(Some(&dir), "sub").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn symlinks_are_refused() {
+        let dir = dir("symlinks");
+        let victim = dir.join("victim");
+        fs::write(&victim, b"not a snapshot").unwrap();
+        symlink(&victim, dir.join("link")).unwrap();
+        assert!(resolve(Some(&dir), "link").is_err());
+        // Even when one appears after the check
+        assert!(Snapshot::read_nofollow(&dir.join("link")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_replace_the_file_and_leave_nothing_else() {
+        let dir = dir("write");
+        let path = dir.join("state.json");
+        // Where the temporary file used to go, a planted symlink must not be written through
+        let victim = dir.join("victim");
+        fs::write(&victim, b"untouched").unwrap();
+        symlink(&victim, dir.join("state.tmp")).unwrap();
+
+        snapshot(vec![record([10, 0, 0, 1], "manual-block", None)])
+            .write(&path)
+            .unwrap();
+        snapshot(vec![record([10, 0, 0, 2], "manual-block", None)])
+            .write(&path)
+            .unwrap();
+        let read = Snapshot::read_nofollow(&path).unwrap();
+        assert_eq!(read.blocklist.len(), 1);
+        assert_eq!(read.blocklist[0].addr, Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(fs::read(&victim).unwrap(), b"untouched");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["state.json", "state.tmp", "victim"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_versions_and_zero_windows_are_refused() {
+        let dir = dir("versions");
+        let path = dir.join("state.json");
+        let mut old = snapshot(Vec::new());
+        old.version = SNAPSHOT_VERSION + 1;
+        old.write(&path).unwrap();
+        assert!(Snapshot::read(&path).is_err());
+        let mut zero = snapshot(Vec::new());
+        zero.limits.window_ns6 = 0;
+        zero.write(&path).unwrap();
+        assert!(Snapshot::read(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expired_entries_are_dropped_and_management_refused() {
+        let past = timebase::unix_now() - 1;
+        let future = timebase::unix_now() + 3600;
+        let entries = snapshot(vec![
+            record([10, 0, 0, 1], "manual-block", None),
+            record([10, 0, 0, 2], "auto-ban", Some(past)),
+            record([10, 0, 0, 3], "auto-ban", Some(future)),
+        ])
+        .entries()
+        .unwrap();
+        let addrs: Vec<_> = entries.iter().map(|(addr, _)| addr.octets()[3]).collect();
+        assert_eq!(addrs, [1, 3]);
+        assert_eq!(entries[1].1.expires, Some(future));
+
+        assert!(
+            snapshot(vec![record([10, 0, 0, 1], "management", None)])
+                .entries()
+                .is_err()
+        );
+        assert!(
+            snapshot(vec![record([10, 0, 0, 1], "no-such-origin", None)])
+                .entries()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn limiter_entries_keep_their_age() {
+        let log = PacketLog {
+            count: 7,
+            last_seen: 50_000_000_000,
+            first_seen: 10_000_000_000,
+            credit: 2,
+            retransmits: 1,
+        };
+        let record = LimiterRecord::of("10.0.0.1".to_owned(), &log, 60_000_000_000);
+        assert_eq!(record.age_ms, 10_000);
+        assert_eq!(record.tracked_secs, 50);
+        let rebased = record.rebase(1_000_000_000_000);
+        assert_eq!(rebased.count, 7);
+        assert_eq!(rebased.last_seen, 990_000_000_000);
+        assert_eq!(rebased.first_seen, 950_000_000_000);
+        assert_eq!(rebased.credit, 2);
+        // On a host booted more recently the ages are cut off at its boot
+        assert_eq!(record.rebase(1_000_000).first_seen, 0);
+    }
+}
@@ -0,0 +1,1143 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write as _,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use aya::maps::{MapData, PerCpuArray, PerCpuValues};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use xdp_api_guard_common::{
+    MALFORMED_KINDS, Origin, Tally, bucket, cast, feature, malformed, stat,
+};
+
+use crate::{
+    geoip::GeoTop,
+    timebase::{self, unix_now},
+};
+
+/// Running totals, summed across CPUs.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Counters {
+    pub dropped: u64,
+    pub passed: u64,
+    pub quic_initials: u64,
+    pub quic_initial_drops: u64,
+    pub ack_flood_drops: u64,
+    pub tiny_mss: u64,
+    pub tiny_mss_drops: u64,
+    pub zone_drops: u64,
+    pub icmp_inner_drops: u64,
+    /// Would have been dropped, passed because filtering is paused.
+    pub paused_drops: u64,
+    /// Dropped early by WRED, also counted by the limiter that dropped them.
+    pub wred_drops: u64,
+    pub http_flood_drops: u64,
+    pub service_rate_drops: u64,
+    pub dscp_drops: u64,
+    /// IPv4 packets by DSCP class, only counted with --dscp-policy.
+    pub dscp_classes: [u64; stat::DSCP_CLASSES as usize],
+    /// Packets and bytes dropped by blocklist entries, by attribution slot. See
+    /// `StatsReport::drops_by_origin` for the named version.
+    #[serde(skip)]
+    pub origin_drops: [u64; stat::ORIGIN_SLOTS as usize],
+    #[serde(skip)]
+    pub origin_bytes: [u64; stat::ORIGIN_SLOTS as usize],
+    /// Frames with data past the linear part, which the checks only saw the start of.
+    pub truncated: u64,
+    pub global_drops: u64,
+    /// Dropped early from heavy sources as the global budget ran low (--red-start).
+    pub pressure_drops: u64,
+    /// Packets the program returned XDP_ABORTED for, `guardctl last-abort` shows the last.
+    pub aborted: u64,
+    /// TLS ClientHellos charged to --sni-rate, those whose name couldn't be read and those
+    /// dropped. Only counted by programs built with the `sni` feature.
+    pub client_hellos: u64,
+    pub sni_unknown: u64,
+    pub sni_drops: u64,
+    /// Packets to multicast and broadcast addresses handled by --multicast and --broadcast,
+    /// and the drops among them.
+    pub multicast: u64,
+    pub broadcast: u64,
+    pub multicast_drops: u64,
+    pub broadcast_drops: u64,
+    /// Packets of sources whose /16 was at --prefix-quota, limited together with the rest of
+    /// the /16, and the drops among them.
+    pub aggregated: u64,
+    pub prefix_drops: u64,
+    /// Drops of single source ports of an address behind --nat-prefix.
+    pub nat_port_drops: u64,
+    /// Drops of each `feature` while it enforced and what it would have dropped while it
+    /// observed, by feature index. See `StatsReport::drops_by_feature` for the named version.
+    #[serde(skip)]
+    pub feature_drops: [u64; feature::LEN as usize],
+    #[serde(skip)]
+    pub feature_observed: [u64; feature::LEN as usize],
+    /// Malformed packets by kind, whatever --malformed-action did with them. See
+    /// `StatsReport::malformed` for the named version.
+    #[serde(skip)]
+    pub malformed: [u64; MALFORMED_KINDS],
+    /// Idle limiter entries the program deleted when their source came back, before the
+    /// sweeper got to them.
+    pub expired_inline: u64,
+    /// Packets passed at the end of the checks by whether conntrack knew their flow, only
+    /// counted with --conntrack. See `StatsReport::flows`.
+    pub established_passes: u64,
+    pub new_passes: u64,
+    /// Malformed packet samples written to the ring, lost to a full ring and skipped by
+    /// the adaptive sampling divisor. Only counted with --malformed-sample.
+    pub samples: u64,
+    pub samples_lost: u64,
+    pub samples_thinned: u64,
+    /// SYNs that opened a conntrack flow and the flows that converted, per source in
+    /// `conversion`. Only counted with --conntrack.
+    pub syns_tracked: u64,
+    pub syns_converted: u64,
+    /// Copies of the settings the program took again, too many updates having come while
+    /// it took one.
+    pub config_torn: u64,
+    /// TCP segments of tracked flows sent before, and those --forgive-retransmissions
+    /// didn't charge. Only counted with --conntrack.
+    pub retransmits: u64,
+    pub retransmits_forgiven: u64,
+}
+
+impl Counters {
+    /// Counters with the value `f` gives for each `stat` slot.
+    fn from_slots(f: impl Fn(u32) -> u64) -> Self {
+        Self {
+            dropped: f(stat::DROP),
+            passed: f(stat::PASS),
+            quic_initials: f(stat::QUIC_INITIAL),
+            quic_initial_drops: f(stat::DROP_QUIC_INITIAL),
+            ack_flood_drops: f(stat::DROP_ACK_FLOOD),
+            tiny_mss: f(stat::TINY_MSS),
+            tiny_mss_drops: f(stat::DROP_TINY_MSS),
+            zone_drops: f(stat::DROP_ZONE),
+            icmp_inner_drops: f(stat::DROP_ICMP_INNER),
+            paused_drops: f(stat::PAUSED_DROP),
+            wred_drops: f(stat::DROP_WRED),
+            http_flood_drops: f(stat::DROP_HTTP_FLOOD),
+            service_rate_drops: f(stat::DROP_SERVICE_RATE),
+            dscp_drops: f(stat::DROP_DSCP),
+            dscp_classes: std::array::from_fn(|class| f(stat::DSCP_CLASS + class as u32)),
+            origin_drops: std::array::from_fn(|slot| f(stat::ORIGIN_DROP + slot as u32)),
+            origin_bytes: std::array::from_fn(|slot| f(stat::ORIGIN_BYTES + slot as u32)),
+            truncated: f(stat::TRUNCATED),
+            global_drops: f(stat::DROP_GLOBAL),
+            pressure_drops: f(stat::DROP_PRESSURE),
+            aborted: f(stat::ABORTED),
+            client_hellos: f(stat::CLIENT_HELLO),
+            sni_unknown: f(stat::SNI_UNKNOWN),
+            sni_drops: f(stat::DROP_SNI),
+            multicast: f(stat::CAST + cast::MULTICAST),
+            broadcast: f(stat::CAST + cast::BROADCAST),
+            multicast_drops: f(stat::DROP_CAST + cast::MULTICAST),
+            broadcast_drops: f(stat::DROP_CAST + cast::BROADCAST),
+            aggregated: f(stat::AGGREGATED),
+            prefix_drops: f(stat::DROP_PREFIX),
+            nat_port_drops: f(stat::DROP_NAT_PORT),
+            feature_drops: std::array::from_fn(|i| f(stat::ENFORCED + i as u32)),
+            feature_observed: std::array::from_fn(|i| f(stat::OBSERVED + i as u32)),
+            malformed: std::array::from_fn(|kind| f(stat::MALFORMED + kind as u32)),
+            expired_inline: f(stat::EXPIRED_INLINE),
+            established_passes: f(stat::PASS_ESTABLISHED),
+            new_passes: f(stat::PASS_NEW),
+            samples: f(stat::SAMPLES),
+            samples_lost: f(stat::SAMPLES_LOST),
+            samples_thinned: f(stat::SAMPLES_THINNED),
+            syns_tracked: f(stat::SYNS_TRACKED),
+            syns_converted: f(stat::SYNS_CONVERTED),
+            config_torn: f(stat::CONFIG_TORN),
+            retransmits: f(stat::RETRANSMITS),
+            retransmits_forgiven: f(stat::RETRANSMITS_FORGIVEN),
+        }
+    }
+
+    fn add(&self, other: &Counters) -> Counters {
+        Counters::from_slots(|index| self.slot(index) + other.slot(index))
+    }
+
+    fn sub(&self, other: &Counters) -> Counters {
+        Counters::from_slots(|index| self.slot(index).saturating_sub(other.slot(index)))
+    }
+
+    fn slot(&self, index: u32) -> u64 {
+        match index {
+            stat::DROP => self.dropped,
+            stat::PASS => self.passed,
+            stat::QUIC_INITIAL => self.quic_initials,
+            stat::DROP_QUIC_INITIAL => self.quic_initial_drops,
+            stat::DROP_ACK_FLOOD => self.ack_flood_drops,
+            stat::TINY_MSS => self.tiny_mss,
+            stat::DROP_TINY_MSS => self.tiny_mss_drops,
+            stat::DROP_ZONE => self.zone_drops,
+            stat::DROP_ICMP_INNER => self.icmp_inner_drops,
+            stat::PAUSED_DROP => self.paused_drops,
+            stat::DROP_WRED => self.wred_drops,
+            stat::DROP_HTTP_FLOOD => self.http_flood_drops,
+            stat::DROP_SERVICE_RATE => self.service_rate_drops,
+            stat::DROP_DSCP => self.dscp_drops,
+            index if (stat::DSCP_CLASS..stat::ORIGIN_DROP).contains(&index) => {
+                self.dscp_classes[(index - stat::DSCP_CLASS) as usize]
+            }
+            index if (stat::ORIGIN_DROP..stat::ORIGIN_BYTES).contains(&index) => {
+                self.origin_drops[(index - stat::ORIGIN_DROP) as usize]
+            }
+            index if (stat::ORIGIN_BYTES..stat::TRUNCATED).contains(&index) => {
+                self.origin_bytes[(index - stat::ORIGIN_BYTES) as usize]
+            }
+            stat::TRUNCATED => self.truncated,
+            stat::DROP_GLOBAL => self.global_drops,
+            stat::DROP_PRESSURE => self.pressure_drops,
+            stat::ABORTED => self.aborted,
+            stat::CLIENT_HELLO => self.client_hellos,
+            stat::SNI_UNKNOWN => self.sni_unknown,
+            stat::DROP_SNI => self.sni_drops,
+            index if (stat::ENFORCED..stat::OBSERVED).contains(&index) => {
+                self.feature_drops[(index - stat::ENFORCED) as usize]
+            }
+            index if (stat::OBSERVED..stat::CAST).contains(&index) => {
+                self.feature_observed[(index - stat::OBSERVED) as usize]
+            }
+            index if index == stat::CAST + cast::MULTICAST => self.multicast,
+            index if index == stat::CAST + cast::BROADCAST => self.broadcast,
+            index if index == stat::DROP_CAST + cast::MULTICAST => self.multicast_drops,
+            index if index == stat::DROP_CAST + cast::BROADCAST => self.broadcast_drops,
+            stat::AGGREGATED => self.aggregated,
+            stat::DROP_PREFIX => self.prefix_drops,
+            stat::DROP_NAT_PORT => self.nat_port_drops,
+            index if (stat::MALFORMED..stat::EXPIRED_INLINE).contains(&index) => {
+                self.malformed[(index - stat::MALFORMED) as usize]
+            }
+            stat::EXPIRED_INLINE => self.expired_inline,
+            stat::PASS_ESTABLISHED => self.established_passes,
+            stat::PASS_NEW => self.new_passes,
+            stat::SAMPLES => self.samples,
+            stat::SAMPLES_LOST => self.samples_lost,
+            stat::SAMPLES_THINNED => self.samples_thinned,
+            stat::SYNS_TRACKED => self.syns_tracked,
+            stat::SYNS_CONVERTED => self.syns_converted,
+            stat::CONFIG_TORN => self.config_torn,
+            stat::RETRANSMITS => self.retransmits,
+            stat::RETRANSMITS_FORGIVEN => self.retransmits_forgiven,
+            _ => 0,
+        }
+    }
+}
+
+/// Every `stat` slot as each CPU holds it. The number of CPUs is whatever the map read
+/// returned, which changes when CPUs are hot-added to a VM.
+#[derive(Clone, Debug)]
+struct Snapshot {
+    // By slot, then by CPU
+    slots: Vec<Vec<u64>>,
+}
+
+impl Snapshot {
+    fn read(map: &PerCpuArray<MapData, u64>) -> anyhow::Result<Self> {
+        let slots = (0..stat::LEN)
+            .map(|index| -> anyhow::Result<Vec<u64>> { Ok(map.get(&index, 0)?.to_vec()) })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { slots })
+    }
+
+    /// What the counters read right after they were zeroed, the baseline after a flush.
+    fn zeroed(cpus: usize) -> Self {
+        Self {
+            slots: vec![vec![0; cpus]; stat::LEN as usize],
+        }
+    }
+
+    fn cpus(&self) -> usize {
+        self.slots.iter().map(Vec::len).max().unwrap_or(0)
+    }
+
+    fn totals(&self) -> Counters {
+        Counters::from_slots(|index| self.slots[index as usize].iter().sum())
+    }
+
+    /// What each slot counted since `prev`, CPU by CPU. A CPU missing from `prev` counts from
+    /// zero and one missing now adds nothing, so a changed CPU count never makes a delta
+    /// negative or loses the other CPUs' packets.
+    fn since(&self, prev: &Snapshot) -> Counters {
+        Counters::from_slots(|index| {
+            let now = &self.slots[index as usize];
+            let prev = prev.slots.get(index as usize);
+            now.iter()
+                .enumerate()
+                .map(|(cpu, &v)| {
+                    let before = prev.and_then(|p| p.get(cpu)).copied().unwrap_or(0);
+                    v.saturating_sub(before)
+                })
+                .sum()
+        })
+    }
+}
+
+/// Blocklist drops charged to one origin, or to one `--feed`.
+#[derive(Clone, Debug, Serialize)]
+pub struct OriginDrops {
+    pub origin: &'static str,
+    /// Name of the feed, for feed entries.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feed: Option<String>,
+    pub packets: u64,
+    pub bytes: u64,
+    /// Index into `Counters::origin_drops` and `origin_bytes`.
+    #[serde(skip)]
+    pub slot: usize,
+}
+
+impl OriginDrops {
+    /// `manual-block`, `feed:NAME`, ...
+    pub fn label(&self) -> String {
+        match &self.feed {
+            Some(feed) => format!("feed:{feed}"),
+            None => self.origin.to_owned(),
+        }
+    }
+}
+
+/// The blocklist drops in `counters` by origin, every origin whose entries drop and every
+/// feed in `feeds` (by feed id) listed, counted or not.
+pub fn by_origin(counters: &Counters, feeds: &[String]) -> Vec<OriginDrops> {
+    let origins = [
+        Origin::ManualBlock,
+        Origin::Policy,
+        Origin::AutoBan,
+        Origin::Cluster,
+    ]
+    .into_iter()
+    .map(|origin| (origin.name(), None, origin as usize));
+    let feeds = feeds.iter().enumerate().map(|(id, name)| {
+        let slot = stat::FEED_SLOT as usize + id;
+        (Origin::Feed.name(), Some(name.clone()), slot)
+    });
+    origins
+        .chain(feeds)
+        .map(|(origin, feed, slot)| OriginDrops {
+            origin,
+            feed,
+            packets: counters.origin_drops[slot],
+            bytes: counters.origin_bytes[slot],
+            slot,
+        })
+        .collect()
+}
+
+/// What one `feature` dropped while enforcing and would have dropped while observing.
+#[derive(Debug, Serialize)]
+pub struct FeatureDrops {
+    pub feature: &'static str,
+    pub enforced: u64,
+    pub observed: u64,
+    /// Index into `Counters::feature_drops` and `feature_observed`, and the feature's bit in
+    /// `Config::observe`.
+    #[serde(skip)]
+    pub index: u32,
+}
+
+/// The drops in `counters` of every feature, counted or not.
+pub fn by_feature(counters: &Counters) -> Vec<FeatureDrops> {
+    feature::NAMES
+        .iter()
+        .enumerate()
+        .map(|(i, name)| FeatureDrops {
+            feature: name,
+            enforced: counters.feature_drops[i],
+            observed: counters.feature_observed[i],
+            index: i as u32,
+        })
+        .collect()
+}
+
+/// Malformed packets of one `malformed` kind.
+#[derive(Debug, Serialize)]
+pub struct MalformedPackets {
+    pub kind: &'static str,
+    pub packets: u64,
+}
+
+/// The malformed packets in `counters` of every kind, counted or not.
+pub fn by_malformed(counters: &Counters) -> Vec<MalformedPackets> {
+    malformed::NAMES
+        .iter()
+        .zip(counters.malformed)
+        .map(|(kind, packets)| MalformedPackets { kind, packets })
+        .collect()
+}
+
+/// One CPU's share of the drop and pass counters.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuCounters {
+    pub cpu: usize,
+    pub dropped: u64,
+    pub passed: u64,
+}
+
+/// Packets counted during one sampling tick (one second).
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct StatsDelta {
+    pub ts: u64,
+    pub dropped: u64,
+    pub passed: u64,
+    /// Enforced drops by feature index.
+    #[serde(skip)]
+    pub feature_drops: [u64; feature::LEN as usize],
+}
+
+/// One sampling tick of the history.
+#[derive(Clone, Copy, Debug)]
+enum Tick {
+    Counted(StatsDelta),
+    /// A tick the counters couldn't be read in, or whose delta spans the ones that failed
+    /// before it. Its timestamp.
+    Missed(u64),
+}
+
+impl Tick {
+    fn ts(&self) -> u64 {
+        match self {
+            Tick::Counted(delta) => delta.ts,
+            Tick::Missed(ts) => *ts,
+        }
+    }
+
+    fn delta(&self) -> Option<&StatsDelta> {
+        match self {
+            Tick::Counted(delta) => Some(delta),
+            Tick::Missed(_) => None,
+        }
+    }
+}
+
+/// Fixed-size ring of the most recent ticks, oldest first.
+pub struct History {
+    capacity: usize,
+    ticks: VecDeque<Tick>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ticks: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, delta: StatsDelta) {
+        self.push_tick(Tick::Counted(delta));
+    }
+
+    /// A tick at `ts` that counted nothing anyone knows of.
+    pub fn push_gap(&mut self, ts: u64) {
+        self.push_tick(Tick::Missed(ts));
+    }
+
+    fn push_tick(&mut self, tick: Tick) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.ticks.len() == self.capacity {
+            self.ticks.pop_front();
+        }
+        self.ticks.push_back(tick);
+    }
+
+    /// The newest `len` ticks (all of them if `len` is `None`) as per-reason arrays, `None`
+    /// for the missed ones.
+    pub fn window(&self, len: Option<usize>) -> HistoryWindow {
+        let len = len.unwrap_or(self.ticks.len()).min(self.ticks.len());
+        let recent = self.ticks.range(self.ticks.len() - len..);
+        let rates = |rate: &dyn Fn(&StatsDelta) -> u64| -> Vec<Option<u64>> {
+            recent.clone().map(|tick| tick.delta().map(rate)).collect()
+        };
+        let mut drop_rate_by_feature = BTreeMap::new();
+        for (i, name) in feature::NAMES.iter().enumerate() {
+            let rates = rates(&|d| d.feature_drops[i]);
+            if rates.iter().any(|rate| rate.is_some_and(|rate| rate != 0)) {
+                drop_rate_by_feature.insert(*name, rates);
+            }
+        }
+        HistoryWindow {
+            start_ts: recent.clone().next().map(Tick::ts),
+            drop_rate: rates(&|d| d.dropped),
+            pass_rate: rates(&|d| d.passed),
+            drop_rate_by_feature,
+        }
+    }
+}
+
+/// The history as arrays one tick apart, `None` (`null` in JSON) where a tick was missed.
+#[derive(Debug, Serialize)]
+pub struct HistoryWindow {
+    #[serde(skip)]
+    pub start_ts: Option<u64>,
+    pub drop_rate: Vec<Option<u64>>,
+    pub pass_rate: Vec<Option<u64>>,
+    /// Enforced drops per second of each feature that dropped anything in the window.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub drop_rate_by_feature: BTreeMap<&'static str, Vec<Option<u64>>>,
+}
+
+/// Everything the sampler knows, shared with the dashboard, the control socket and the REST
+/// API. Owns the `STATS` map so reads and resets are serialized by the same lock.
+pub struct StatsState {
+    map: PerCpuArray<MapData, u64>,
+    /// `DROP_BUCKETS`, read once by `seed`.
+    buckets: PerCpuArray<MapData, Tally>,
+    snapshot: Option<Snapshot>,
+    totals: Option<Counters>,
+    /// Why the last sample failed, `None` while sampling works.
+    error: Option<String>,
+    // Whether a sample failed since the last one that read the counters
+    missed: bool,
+    last: Option<StatsDelta>,
+    // Aborts in the last sample
+    aborted_rate: u64,
+    // Share of the malformed packet samples the last sample lost to a full ring
+    sample_loss: f64,
+    // Established and new passes in the last sample
+    last_flows: (u64, u64),
+    rates: Rates,
+    // What the last sample fed `rates`, for --record-decisions
+    rate_sample: Option<RateSample>,
+    // `None` flows without --conntrack
+    conntrack: bool,
+    history: History,
+    /// `--feed` names by feed id.
+    feeds: Vec<String>,
+    // When the last origin summary was logged and the totals it was taken from
+    summary: Option<(Instant, Counters)>,
+}
+
+impl StatsState {
+    /// `smoothing` is the EWMA factor for the smoothed rates, 1.0 disables smoothing.
+    pub fn new(
+        map: PerCpuArray<MapData, u64>,
+        buckets: PerCpuArray<MapData, Tally>,
+        history_len: usize,
+        smoothing: f64,
+        feeds: Vec<String>,
+    ) -> Self {
+        Self {
+            feeds,
+            summary: None,
+            map,
+            buckets,
+            snapshot: None,
+            totals: None,
+            error: None,
+            missed: false,
+            last: None,
+            aborted_rate: 0,
+            sample_loss: 0.0,
+            last_flows: (0, 0),
+            rates: Rates::new(smoothing),
+            rate_sample: None,
+            conntrack: false,
+            history: History::new(history_len),
+        }
+    }
+
+    /// Fills the history with the seconds `DROP_BUCKETS` still holds, from the first one
+    /// anything was counted in to the last full one, and returns how many that were. Maps
+    /// the daemon loaded itself are fresh and hold none; pinned ones (`--external-maps`)
+    /// hold what the program counted while no daemon was sampling. Call it before the first
+    /// sample, so the history stays in order.
+    pub fn seed(&mut self) -> anyhow::Result<usize> {
+        let now = timebase::boot_ns() / 1_000_000_000;
+        let first = now.saturating_sub(u64::from(bucket::SECONDS) - 1);
+        // By second from `first`, then by counter. The current second is still counting.
+        let mut seconds = vec![[0u64; bucket::LEN as usize]; (now - first) as usize];
+        for (second, counts) in (first..now).zip(&mut seconds) {
+            for (counter, count) in (0..bucket::LEN).zip(counts.iter_mut()) {
+                // A tally of another second is stale, the bucket was last used a lap ago
+                *count = self
+                    .buckets
+                    .get(&bucket::index(second, counter), 0)?
+                    .iter()
+                    .filter(|tally| tally.second == second)
+                    .map(|tally| tally.count)
+                    .sum();
+            }
+        }
+        let Some(start) = seconds
+            .iter()
+            .position(|counts| counts.iter().any(|&n| n != 0))
+        else {
+            return Ok(0);
+        };
+        for (second, counts) in (first..now).zip(&seconds).skip(start) {
+            self.history.push(StatsDelta {
+                ts: timebase::to_wallclock(second * 1_000_000_000)
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+                dropped: counts[bucket::DROP as usize],
+                passed: counts[bucket::PASS as usize],
+                feature_drops: std::array::from_fn(|i| counts[bucket::ENFORCED as usize + i]),
+            });
+        }
+        Ok(seconds.len() - start)
+    }
+
+    /// Reads the kernel counters and records the delta since the previous sample.
+    pub fn sample(&mut self) -> anyhow::Result<()> {
+        let snapshot = match Snapshot::read(&self.map) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                if self.error.is_none() {
+                    warn!("stats unavailable: {e:#}");
+                }
+                self.error = Some(format!("{e:#}"));
+                self.history.push_gap(unix_now());
+                self.missed = true;
+                return Err(e);
+            }
+        };
+        if self.error.take().is_some() {
+            info!("stats available again");
+        }
+        match self.snapshot.take() {
+            Some(prev) => {
+                if prev.cpus() != snapshot.cpus() {
+                    info!(
+                        "per-CPU stats cover {} CPUs now, {} before",
+                        snapshot.cpus(),
+                        prev.cpus()
+                    );
+                }
+                let counted = snapshot.since(&prev);
+                if self.missed {
+                    // The delta spans the missed ticks too, there's no telling which second
+                    // counted what
+                    self.totals = Some(self.totals.unwrap_or_default().add(&counted));
+                    self.history.push_gap(unix_now());
+                } else {
+                    self.record(counted);
+                }
+            }
+            None => self.totals = Some(snapshot.totals()),
+        }
+        self.snapshot = Some(snapshot);
+        self.missed = false;
+        Ok(())
+    }
+
+    /// Why the counters can't be read, if they can't.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// Packets the program returned a verdict for since the counters were last zeroed,
+    /// `None` while they can't be read.
+    pub fn seen(&self) -> Option<u64> {
+        let totals = self.totals.filter(|_| self.error.is_none())?;
+        Some(totals.passed + totals.dropped + totals.aborted)
+    }
+
+    /// Whether a sample has succeeded since startup.
+    pub fn sampled(&self) -> bool {
+        self.totals.is_some()
+    }
+
+    /// Zeroes the kernel counters. The baseline is reset with them, otherwise the next delta
+    /// would come out hugely negative.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        // As many values as the map hands out, which is what it takes back
+        let cpus = self.map.get(&stat::DROP, 0)?.len();
+        for index in 0..stat::LEN {
+            self.map
+                .set(index, PerCpuValues::try_from(vec![0u64; cpus])?, 0)?;
+        }
+        self.snapshot = Some(Snapshot::zeroed(cpus));
+        self.totals = Some(Counters::default());
+        self.summary = None;
+        Ok(())
+    }
+
+    /// The drop and pass counters as each CPU holds them, by CPU id. Shows how the NIC queues
+    /// spread traffic, which the summed totals hide.
+    pub fn per_cpu(&self) -> anyhow::Result<Vec<CpuCounters>> {
+        let dropped = self.map.get(&stat::DROP, 0)?;
+        let passed = self.map.get(&stat::PASS, 0)?;
+        // The two reads may straddle a CPU count change, a missing CPU has counted nothing
+        Ok((0..dropped.len().max(passed.len()))
+            .map(|cpu| CpuCounters {
+                cpu,
+                dropped: dropped.get(cpu).copied().unwrap_or(0),
+                passed: passed.get(cpu).copied().unwrap_or(0),
+            })
+            .collect())
+    }
+
+    /// What the interface is doing, from the link watcher, set before each sample. A sample
+    /// spanning a change, and every sample while the interface isn't up, counts towards the
+    /// totals but not the rates: a second of nothing or of a link's backlog coming in would
+    /// drag the averages a long way from what the traffic does.
+    pub fn set_dataplane(&mut self, dataplane: Dataplane) {
+        self.rates.set_dataplane(dataplane);
+    }
+
+    pub fn dataplane(&self) -> Dataplane {
+        self.rates.dataplane()
+    }
+
+    /// Whether the last sample's rates were taken. Alerts on rates don't fire while they
+    /// weren't, the state says more than a rate of zero.
+    pub fn rates_live(&self) -> bool {
+        self.rates.live()
+    }
+
+    /// The rates alerts are decided on.
+    pub fn rates(&self) -> &Rates {
+        &self.rates
+    }
+
+    /// What the last sample fed the rates, once. `None` after a failed or the first sample,
+    /// which have no delta.
+    pub fn take_rate_sample(&mut self) -> Option<RateSample> {
+        self.rate_sample.take()
+    }
+
+    fn record(&mut self, counted: Counters) {
+        self.totals = Some(self.totals.unwrap_or_default().add(&counted));
+        let sample = RateSample {
+            dropped: counted.dropped,
+            passed: counted.passed,
+            established: counted.established_passes,
+            new: counted.new_passes,
+        };
+        self.rate_sample = Some(sample);
+        if !self.rates.update(sample) {
+            // Zeros keep the history one entry a second
+            let delta = StatsDelta {
+                ts: unix_now(),
+                dropped: 0,
+                passed: 0,
+                feature_drops: [0; feature::LEN as usize],
+            };
+            self.history.push(delta);
+            self.last = Some(delta);
+            self.last_flows = (0, 0);
+            self.aborted_rate = 0;
+            self.sample_loss = 0.0;
+            return;
+        }
+        let delta = StatsDelta {
+            ts: unix_now(),
+            dropped: counted.dropped,
+            passed: counted.passed,
+            feature_drops: counted.feature_drops,
+        };
+        self.history.push(delta);
+        self.last = Some(delta);
+        self.last_flows = (counted.established_passes, counted.new_passes);
+        self.aborted_rate = counted.aborted;
+        let tried = counted.samples + counted.samples_lost;
+        self.sample_loss = if tried == 0 {
+            0.0
+        } else {
+            counted.samples_lost as f64 / tried as f64
+        };
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// XDP_ABORTED verdicts per second, unsmoothed.
+    pub fn aborted_rate(&self) -> u64 {
+        self.aborted_rate
+    }
+
+    /// Share of the last second's malformed packet samples lost to a full ring, 0 to 1.
+    pub fn sample_loss(&self) -> f64 {
+        self.sample_loss
+    }
+
+    /// Counts the split of passes into established and new flows, see `flows`.
+    pub fn set_conntrack(&mut self, on: bool) {
+        self.conntrack = on;
+    }
+
+    /// The split of passes into established and new flows, `None` without --conntrack.
+    pub fn flows(&self) -> Option<FlowSplit> {
+        if !self.conntrack {
+            return None;
+        }
+        let totals = self.totals.unwrap_or_default();
+        let (established_rate, new_rate) = self.last_flows;
+        Some(FlowSplit {
+            established: totals.established_passes,
+            new: totals.new_passes,
+            established_rate,
+            new_rate,
+            new_share: self.rates.new_share(),
+        })
+    }
+
+    /// A line with the blocklist drops of each origin since the previous summary, once
+    /// `every` has passed since it. The first call only starts the period.
+    pub fn origin_summary(&mut self, every: Duration) -> Option<String> {
+        let totals = self.totals?;
+        let since = match self.summary {
+            Some((at, _)) if at.elapsed() < every => return None,
+            Some((_, before)) => totals.sub(&before),
+            None => {
+                self.summary = Some((Instant::now(), totals));
+                return None;
+            }
+        };
+        self.summary = Some((Instant::now(), totals));
+        let mut line = format!("blocklist drops in the last {}s:", every.as_secs());
+        for origin in by_origin(&since, &self.feeds) {
+            let _ = write!(
+                line,
+                " {} {} ({} bytes),",
+                origin.label(),
+                origin.packets,
+                origin.bytes
+            );
+        }
+        line.pop();
+        Some(line)
+    }
+
+    pub fn report(&self, window: Option<usize>) -> StatsReport {
+        let totals = self.totals.unwrap_or_default();
+        let history = self.history.window(window);
+        StatsReport {
+            ts: unix_now(),
+            drops_by_origin: by_origin(&totals, &self.feeds),
+            drops_by_feature: by_feature(&totals),
+            malformed: by_malformed(&totals),
+            flows: self.flows(),
+            over_limit: None,
+            totals,
+            drop_rate: self.last.map_or(0, |d| d.dropped),
+            pass_rate: self.last.map_or(0, |d| d.passed),
+            aborted_rate: self.aborted_rate,
+            sample_loss: self.sample_loss,
+            dataplane: self.rates.dataplane(),
+            rates_live: self.rates.live(),
+            drop_rate_smoothed: self.rates.drop_rate(),
+            pass_rate_smoothed: self.rates.pass_rate(),
+            history_start_ts: history.start_ts,
+            history,
+            error: self.error.clone(),
+        }
+    }
+}
+
+/// What one sample counted that the rates are taken from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateSample {
+    pub dropped: u64,
+    pub passed: u64,
+    /// Passes of established and of new flows, with --conntrack.
+    pub established: u64,
+    pub new: u64,
+}
+
+/// The smoothed rates alerts are decided on, fed a sample a second: by `StatsState` from the
+/// kernel counters, and by `xdp-api-guard replay` from a recording, so both decide alike.
+/// Nothing in here reads a clock.
+#[derive(Clone, Copy, Debug)]
+pub struct Rates {
+    dataplane: Dataplane,
+    // The dataplane changed since the last sample, which spans the change
+    settling: bool,
+    // Whether the last sample's rates were taken, see `live`
+    live: bool,
+    drop: Ewma,
+    pass: Ewma,
+    established: Ewma,
+    new: Ewma,
+}
+
+impl Rates {
+    /// `smoothing` is the EWMA factor, 1.0 disables smoothing.
+    pub fn new(smoothing: f64) -> Self {
+        Self {
+            dataplane: Dataplane::Up,
+            settling: false,
+            live: true,
+            drop: Ewma::new(smoothing),
+            pass: Ewma::new(smoothing),
+            established: Ewma::new(smoothing),
+            new: Ewma::new(smoothing),
+        }
+    }
+
+    /// See [`StatsState::set_dataplane`].
+    pub fn set_dataplane(&mut self, dataplane: Dataplane) {
+        if dataplane == self.dataplane {
+            return;
+        }
+        self.dataplane = dataplane;
+        self.settling = true;
+        for ewma in [
+            &mut self.drop,
+            &mut self.pass,
+            &mut self.established,
+            &mut self.new,
+        ] {
+            ewma.reset();
+        }
+    }
+
+    pub fn dataplane(&self) -> Dataplane {
+        self.dataplane
+    }
+
+    /// Takes one sample, returning whether its rates were, see `live`.
+    pub fn update(&mut self, sample: RateSample) -> bool {
+        self.live = self.dataplane == Dataplane::Up && !self.settling;
+        self.settling = false;
+        if self.live {
+            self.drop.update(sample.dropped as f64);
+            self.pass.update(sample.passed as f64);
+            self.established.update(sample.established as f64);
+            self.new.update(sample.new as f64);
+        }
+        self.live
+    }
+
+    /// Whether the last sample's rates were taken.
+    pub fn live(&self) -> bool {
+        self.live
+    }
+
+    /// Drops per second, smoothed.
+    pub fn drop_rate(&self) -> f64 {
+        self.drop.value()
+    }
+
+    pub fn pass_rate(&self) -> f64 {
+        self.pass.value()
+    }
+
+    /// Percentage of new flows in the smoothed passes, `None` while nothing passes.
+    pub fn new_share(&self) -> Option<f64> {
+        share(self.new.value(), self.established.value())
+    }
+}
+
+/// Where the program is, as the link watcher sees it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dataplane {
+    /// Attached to an interface whose link is up.
+    Up,
+    /// Attached, but the link is down: nothing arrives to count.
+    Down,
+    /// Not attached, detached from outside or the interface gone.
+    Detached,
+}
+
+impl Dataplane {
+    pub const ALL: [Dataplane; 3] = [Dataplane::Up, Dataplane::Down, Dataplane::Detached];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Dataplane::Up => "up",
+            Dataplane::Down => "down",
+            Dataplane::Detached => "detached",
+        }
+    }
+}
+
+/// The stats JSON document, printed by `--json` and served on `/v1/stats`.
+#[derive(Debug, Serialize)]
+pub struct StatsReport {
+    pub ts: u64,
+    #[serde(flatten)]
+    pub totals: Counters,
+    pub drop_rate: u64,
+    pub pass_rate: u64,
+    pub aborted_rate: u64,
+    /// Share of the last second's malformed packet samples lost to a full ring, 0 to 1.
+    pub sample_loss: f64,
+    /// `up`, `down` while the link is down, `detached` while the program isn't attached.
+    pub dataplane: Dataplane,
+    /// False while the rates below weren't taken, the interface being down or detached or
+    /// having just changed. They read zero then, which isn't what the traffic does.
+    pub rates_live: bool,
+    pub drop_rate_smoothed: f64,
+    pub pass_rate_smoothed: f64,
+    /// Timestamp of the first element of every `history` array.
+    pub history_start_ts: Option<u64>,
+    pub history: HistoryWindow,
+    /// Blocklist drops by the origin of the matching entry, since startup or the last flush.
+    pub drops_by_origin: Vec<OriginDrops>,
+    /// Drops by feature, enforced and observed, since startup or the last flush. Which
+    /// features observe right now is in `guardctl status`.
+    pub drops_by_feature: Vec<FeatureDrops>,
+    /// Malformed packets by kind, since startup or the last flush. What each kind gets is
+    /// --malformed-action's.
+    pub malformed: Vec<MalformedPackets>,
+    /// Passes by established and new flows, `null` without --conntrack rather than zeros.
+    pub flows: Option<FlowSplit>,
+    /// The sources over their limit right now, by country and AS, the `geoip::TOP` with the
+    /// most packets over it. Only on `/v1/stats`, and only with --geoip-db or --asn-db.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub over_limit: Option<GeoTop>,
+    /// Set while the counters can't be read, the numbers above are from the last good read.
+    #[serde(rename = "stats_error", skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Packets passed at the end of the checks, by whether their TCP flow was known. Passes
+/// before them, allowlisted sources or paused filtering say, are in neither.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct FlowSplit {
+    /// Since startup or the last flush.
+    pub established: u64,
+    pub new: u64,
+    /// In the last second.
+    pub established_rate: u64,
+    pub new_rate: u64,
+    /// Percentage of new flows in the smoothed rates, `None` while nothing passes. A flood
+    /// turns it from mostly established to mostly new.
+    pub new_share: Option<f64>,
+}
+
+fn share(part: f64, rest: f64) -> Option<f64> {
+    let total = part + rest;
+    (total > 0.0).then(|| part * 100.0 / total)
+}
+
+/// Exponentially weighted moving average. Each sample moves the average `alpha` of the way
+/// towards it, so a single-second spike only moves it part of the way.
+#[derive(Clone, Copy, Debug)]
+pub struct Ewma {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Ewma {
+    pub fn new(alpha: f64) -> Self {
+        Self { alpha, value: None }
+    }
+
+    pub fn update(&mut self, sample: f64) -> f64 {
+        let next = match self.value {
+            // Seed with the first sample rather than ramping up from zero
+            None => sample,
+            Some(prev) => prev + self.alpha * (sample - prev),
+        };
+        self.value = Some(next);
+        next
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value.unwrap_or(0.0)
+    }
+
+    /// Forgets the average, the next sample seeds it again.
+    pub fn reset(&mut self) {
+        self.value = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every slot at `value` on each of two CPUs
+    fn snapshot(value: u64) -> Snapshot {
+        Snapshot {
+            slots: vec![vec![value; 2]; stat::LEN as usize],
+        }
+    }
+
+    #[test]
+    fn a_flush_starts_the_deltas_from_zero() {
+        let before = snapshot(1_000_000);
+        let after = snapshot(7);
+        // Against the counters as they were, the packets after the flush would be lost
+        assert_eq!(after.since(&before).dropped, 0);
+        let delta = after.since(&Snapshot::zeroed(2));
+        assert_eq!((delta.dropped, delta.passed), (14, 14));
+        assert_eq!(Snapshot::zeroed(2).totals().dropped, 0);
+    }
+
+    #[test]
+    fn cpus_coming_and_going_never_go_negative() {
+        let two = snapshot(10);
+        let four = Snapshot {
+            slots: vec![vec![12, 11, 5, 5]; stat::LEN as usize],
+        };
+        assert_eq!(four.since(&two).passed, 2 + 1 + 5 + 5);
+        assert_eq!(two.since(&four).passed, 0);
+    }
+
+    fn delta(ts: u64, dropped: u64) -> StatsDelta {
+        let mut feature_drops = [0; feature::LEN as usize];
+        feature_drops[0] = dropped;
+        StatsDelta {
+            ts,
+            dropped,
+            passed: 100 - dropped,
+            feature_drops,
+        }
+    }
+
+    #[test]
+    fn the_ring_keeps_the_newest_in_order() {
+        let mut history = History::new(3);
+        for ts in 1..=5 {
+            history.push(delta(ts, ts));
+        }
+        let all = history.window(None);
+        assert_eq!(all.start_ts, Some(3));
+        assert_eq!(all.drop_rate, [Some(3), Some(4), Some(5)]);
+        assert_eq!(all.pass_rate, [Some(97), Some(96), Some(95)]);
+        let last = history.window(Some(2));
+        assert_eq!(
+            (last.start_ts, last.drop_rate),
+            (Some(4), vec![Some(4), Some(5)])
+        );
+        // Asking for more than the ring holds gets what it holds
+        assert_eq!(history.window(Some(10)).drop_rate, all.drop_rate);
+        let none = history.window(Some(0));
+        assert_eq!((none.start_ts, none.drop_rate), (None, vec![]));
+    }
+
+    #[test]
+    fn a_ring_of_nothing_keeps_nothing() {
+        let mut history = History::new(0);
+        history.push(delta(1, 1));
+        history.push_gap(2);
+        let window = history.window(None);
+        assert_eq!((window.start_ts, window.drop_rate), (None, vec![]));
+    }
+
+    #[test]
+    fn missed_ticks_are_gaps_not_zeros() {
+        let mut history = History::new(4);
+        history.push(delta(10, 5));
+        history.push_gap(11);
+        history.push_gap(12);
+        history.push(delta(13, 0));
+        let window = history.window(None);
+        assert_eq!(window.start_ts, Some(10));
+        assert_eq!(window.drop_rate, [Some(5), None, None, Some(0)]);
+        let name = feature::NAMES[0];
+        assert_eq!(
+            window.drop_rate_by_feature[name],
+            [Some(5), None, None, Some(0)]
+        );
+        let json = serde_json::to_value(&window).unwrap();
+        assert_eq!(json["drop_rate"], serde_json::json!([5, null, null, 0]));
+        // A window that starts on a gap still starts at its tick
+        history.push_gap(14);
+        let window = history.window(Some(3));
+        assert_eq!(window.start_ts, Some(12));
+        assert_eq!(window.pass_rate, [None, Some(100), None]);
+        // Gaps and zeros aren't drops
+        assert!(window.drop_rate_by_feature.is_empty());
+    }
+}
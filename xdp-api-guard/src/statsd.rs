@@ -0,0 +1,322 @@
+//! Pushes stats to a statsd or DogStatsD agent over UDP, for setups that collect by push
+//! rather than by scraping `/metrics`.
+//!
+//! Every interval the counters go out as deltas since the previous push (`|c`), and rates and
+//! map occupancy as gauges (`|g`), with DogStatsD tags. Sends never wait: a datagram the socket
+//! can't take right away is counted as failed and dropped, like statsd itself would.
+
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex, atomic::Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context as _, bail};
+use log::{debug, info, warn};
+use tokio::net::{UdpSocket, lookup_host};
+
+use crate::{
+    stats::{Counters, Dataplane, StatsReport, StatsState},
+    sweep::SweepStats,
+};
+
+/// Payload limit of one datagram: a 1500-byte MTU minus IP, UDP and tunnel headers.
+pub const MAX_DATAGRAM: usize = 1432;
+
+pub struct StatsdConfig {
+    /// Agent address as host:port.
+    pub addr: String,
+    pub prefix: String,
+    pub interval: Duration,
+    /// Tags on every metric, `key:value` or bare.
+    pub tags: Vec<String>,
+}
+
+/// Sends one push every `config.interval` until the task is dropped.
+pub async fn run(
+    config: StatsdConfig,
+    stats: Arc<Mutex<StatsState>>,
+    sweep: Arc<SweepStats>,
+) -> anyhow::Result<()> {
+    for tag in &config.tags {
+        if tag.is_empty() || tag.contains(['|', '#', '@', ',', ' ', '\n']) {
+            bail!("invalid statsd tag {tag:?}");
+        }
+    }
+    let addr = lookup_host(&config.addr)
+        .await
+        .with_context(|| format!("failed to resolve {}", config.addr))?
+        .next()
+        .with_context(|| format!("{} has no address", config.addr))?;
+    let socket = UdpSocket::bind(if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await?;
+    socket.connect(addr).await?;
+    info!(
+        "pushing stats to statsd at {addr} every {:?}",
+        config.interval
+    );
+
+    let mut failures = 0;
+    let mut pusher = Pusher::new(&config);
+    let mut tick = tokio::time::interval(config.interval);
+    loop {
+        tick.tick().await;
+        let report = stats.lock().unwrap().report(Some(0));
+        let lines = pusher.lines(&report, &sweep, failures);
+        for datagram in batch(&lines, MAX_DATAGRAM) {
+            if let Err(e) = socket.try_send(datagram.as_bytes()) {
+                // Warn once, an agent that is down would otherwise log every interval
+                failures += 1;
+                if failures == 1 {
+                    warn!("statsd: send to {addr} failed: {e}, counting further failures");
+                } else {
+                    debug!("statsd: send to {addr} failed: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Turns reports into metric lines, remembering what was pushed so counters go out as deltas.
+struct Pusher {
+    prefix: String,
+    // Joined tags shared by every line, empty without tags
+    tags: String,
+    last: Option<(Counters, u64)>,
+}
+
+impl Pusher {
+    fn new(config: &StatsdConfig) -> Self {
+        Self {
+            prefix: config.prefix.trim_end_matches('.').to_owned(),
+            tags: config.tags.join(","),
+            last: None,
+        }
+    }
+
+    fn lines(&mut self, report: &StatsReport, sweep: &SweepStats, failures: u64) -> Vec<String> {
+        let now = report.totals;
+        let mut lines = Vec::new();
+        // The first push only sets the baseline, the totals so far are not one interval
+        if let Some((prev, prev_failures)) = self.last {
+            // Flushed counters start over below the baseline, nothing was lost
+            let delta = |now: u64, prev: u64| now.saturating_sub(prev);
+            let counters = [
+                ("packets", delta(now.dropped, prev.dropped), "verdict:drop"),
+                ("packets", delta(now.passed, prev.passed), "verdict:pass"),
+                (
+                    "drops",
+                    delta(now.quic_initial_drops, prev.quic_initial_drops),
+                    "reason:quic_initial,proto:udp",
+                ),
+                (
+                    "drops",
+                    delta(now.ack_flood_drops, prev.ack_flood_drops),
+                    "reason:ack_flood,proto:tcp",
+                ),
+                (
+                    "drops",
+                    delta(now.tiny_mss_drops, prev.tiny_mss_drops),
+                    "reason:tiny_mss,proto:tcp",
+                ),
+                (
+                    "drops",
+                    delta(now.zone_drops, prev.zone_drops),
+                    "reason:zone",
+                ),
+                (
+                    "drops",
+                    delta(now.icmp_inner_drops, prev.icmp_inner_drops),
+                    "reason:icmp_inner,proto:icmp",
+                ),
+                (
+                    "drops",
+                    delta(now.http_flood_drops, prev.http_flood_drops),
+                    "reason:http_flood,proto:tcp",
+                ),
+                (
+                    "drops",
+                    delta(now.service_rate_drops, prev.service_rate_drops),
+                    "reason:service_rate,proto:tcp",
+                ),
+                (
+                    "drops",
+                    delta(now.sni_drops, prev.sni_drops),
+                    "reason:sni,proto:tcp",
+                ),
+                (
+                    "drops",
+                    delta(now.multicast_drops, prev.multicast_drops),
+                    "reason:multicast",
+                ),
+                (
+                    "drops",
+                    delta(now.broadcast_drops, prev.broadcast_drops),
+                    "reason:broadcast",
+                ),
+                (
+                    "drops",
+                    delta(now.dscp_drops, prev.dscp_drops),
+                    "reason:dscp",
+                ),
+                (
+                    "drops",
+                    delta(now.prefix_drops, prev.prefix_drops),
+                    "reason:prefix",
+                ),
+                (
+                    "drops",
+                    delta(now.nat_port_drops, prev.nat_port_drops),
+                    "reason:nat_port",
+                ),
+                (
+                    "drops",
+                    delta(now.global_drops, prev.global_drops),
+                    "reason:global",
+                ),
+                (
+                    "drops",
+                    delta(now.pressure_drops, prev.pressure_drops),
+                    "reason:pressure",
+                ),
+                ("wred_drops", delta(now.wred_drops, prev.wred_drops), ""),
+                (
+                    "paused_drops",
+                    delta(now.paused_drops, prev.paused_drops),
+                    "",
+                ),
+                (
+                    "quic_initials",
+                    delta(now.quic_initials, prev.quic_initials),
+                    "proto:udp",
+                ),
+                (
+                    "tiny_mss_syns",
+                    delta(now.tiny_mss, prev.tiny_mss),
+                    "proto:tcp",
+                ),
+                (
+                    "truncated_packets",
+                    delta(now.truncated, prev.truncated),
+                    "",
+                ),
+                ("aborted", delta(now.aborted, prev.aborted), ""),
+                (
+                    "aggregated_packets",
+                    delta(now.aggregated, prev.aggregated),
+                    "",
+                ),
+                (
+                    "client_hellos",
+                    delta(now.client_hellos, prev.client_hellos),
+                    "proto:tcp",
+                ),
+                (
+                    "sni_unknown",
+                    delta(now.sni_unknown, prev.sni_unknown),
+                    "proto:tcp",
+                ),
+                (
+                    "cast_packets",
+                    delta(now.multicast, prev.multicast),
+                    "class:multicast",
+                ),
+                (
+                    "cast_packets",
+                    delta(now.broadcast, prev.broadcast),
+                    "class:broadcast",
+                ),
+                ("statsd.send_failures", delta(failures, prev_failures), ""),
+            ];
+            for (name, value, tags) in counters {
+                lines.push(self.line(name, value, "c", tags));
+            }
+            let classes = now.dscp_classes.iter().zip(&prev.dscp_classes);
+            for (class, (now, prev)) in classes.enumerate() {
+                let tags = format!("class:cs{class}");
+                lines.push(self.line("dscp_packets", delta(*now, *prev), "c", &tags));
+            }
+            for (i, kind) in report.malformed.iter().enumerate() {
+                let tags = format!("kind:{}", kind.kind);
+                let packets = delta(now.malformed[i], prev.malformed[i]);
+                lines.push(self.line("malformed_packets", packets, "c", &tags));
+            }
+            if report.flows.is_some() {
+                let established = delta(now.established_passes, prev.established_passes);
+                lines.push(self.line("flow_passes", established, "c", "flow:established"));
+                let new = delta(now.new_passes, prev.new_passes);
+                lines.push(self.line("flow_passes", new, "c", "flow:new"));
+            }
+            for origin in &report.drops_by_origin {
+                let tags = match &origin.feed {
+                    Some(feed) => format!("origin:{},feed:{feed}", origin.origin),
+                    None => format!("origin:{}", origin.origin),
+                };
+                let slot = origin.slot;
+                let packets = delta(now.origin_drops[slot], prev.origin_drops[slot]);
+                lines.push(self.line("blocklist_drops", packets, "c", &tags));
+                let bytes = delta(now.origin_bytes[slot], prev.origin_bytes[slot]);
+                lines.push(self.line("blocklist_drop_bytes", bytes, "c", &tags));
+            }
+            for feature in &report.drops_by_feature {
+                let i = feature.index as usize;
+                let enforced = delta(now.feature_drops[i], prev.feature_drops[i]);
+                let tags = format!("feature:{},mode:enforced", feature.feature);
+                lines.push(self.line("feature_drops", enforced, "c", &tags));
+                let observed = delta(now.feature_observed[i], prev.feature_observed[i]);
+                let tags = format!("feature:{},mode:observed", feature.feature);
+                lines.push(self.line("feature_drops", observed, "c", &tags));
+            }
+        }
+        self.last = Some((now, failures));
+
+        // The interface is in the default tags
+        let up = u8::from(report.dataplane == Dataplane::Up);
+        lines.push(self.line("up", up, "g", ""));
+        lines.push(self.line("drop_rate", report.drop_rate_smoothed, "g", ""));
+        lines.push(self.line("pass_rate", report.pass_rate_smoothed, "g", ""));
+        if let Some(share) = report.flows.and_then(|flows| flows.new_share) {
+            lines.push(self.line("new_flow_share", share, "g", ""));
+        }
+        let entries = sweep.entries.load(Ordering::Relaxed);
+        lines.push(self.line("tracking_entries", entries, "g", ""));
+        lines
+    }
+
+    fn line(&self, name: &str, value: impl Display, kind: &str, tags: &str) -> String {
+        let line = format!("{}.{name}:{value}|{kind}", self.prefix);
+        let tags: Vec<&str> = [self.tags.as_str(), tags]
+            .into_iter()
+            .filter(|t| !t.is_empty())
+            .collect();
+        if tags.is_empty() {
+            line
+        } else {
+            format!("{line}|#{}", tags.join(","))
+        }
+    }
+}
+
+/// Joins lines into newline-separated datagrams of at most `max` bytes. A line longer than
+/// that on its own still gets a datagram, agents cope better with that than with a cut line.
+fn batch(lines: &[String], max: usize) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > max {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
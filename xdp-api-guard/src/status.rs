@@ -0,0 +1,212 @@
+//! `GET /` on the REST API listener: the terminal dashboard as a plain HTML page, for people
+//! without access to the metrics stack.
+//!
+//! The page refreshes itself with a meta refresh, so it works without JavaScript. Counters come
+//! from the same serialization as `/v1/stats`; a counter added there shows up here too.
+
+use std::fmt::Write as _;
+
+use serde_json::Value;
+use xdp_api_guard_common::config_flags;
+
+use crate::{
+    control::ControlState,
+    geoip::GeoIp,
+    health::HealthReport,
+    heatmap::{self, Offender},
+    mask::Masked,
+    stats::{Dataplane, StatsReport},
+    timebase,
+};
+
+const TEMPLATE: &str = include_str!("status.html");
+
+/// Seconds between reloads of the page.
+pub const REFRESH_SECS: u64 = 5;
+
+/// Sources listed under top offenders.
+pub const TOP: usize = 10;
+
+pub fn render(report: &StatsReport, health: &HealthReport, control: &ControlState) -> String {
+    let cfg = control.config.lock().unwrap().get();
+    let offenders = heatmap::collect(
+        &control.rate_limit.lock().unwrap(),
+        &control.rate_limit6.lock().unwrap(),
+        &control.nat.lock().unwrap(),
+        &control.tags.lock().unwrap(),
+        &control.groups.lock().unwrap(),
+        &cfg,
+        timebase::boot_ns(),
+    );
+
+    let mut body = String::new();
+    let mode = if cfg.has(config_flags::PAUSED) {
+        "<span class=\"bad\">paused, nothing is dropped</span>"
+    } else if cfg.has(config_flags::NO_RATE_LIMIT) {
+        "blocklist only"
+    } else {
+        "rate limiting"
+    };
+    body.push_str("<table>\n");
+    row(&mut body, "attached", flag(health.attached));
+    row(&mut body, "link up", flag(health.link_up));
+    if health.dataplane != Dataplane::Up {
+        row(
+            &mut body,
+            "rates",
+            format!(
+                "<span class=\"bad\">paused, the dataplane is {}</span>",
+                health.dataplane.name()
+            ),
+        );
+    }
+    row(&mut body, "mode", mode.to_owned());
+    if health.aborted_rate != 0 {
+        row(
+            &mut body,
+            "aborted",
+            format!(
+                "<span class=\"bad\">{}/s, see guardctl last-abort</span>",
+                health.aborted_rate
+            ),
+        );
+    }
+    if health.sample_loss != 0.0 {
+        row(
+            &mut body,
+            "samples lost",
+            format!(
+                "<span class=\"bad\">{:.1}%, see --ringbuf-size</span>",
+                health.sample_loss * 100.0
+            ),
+        );
+    }
+    if let Some(check) = health.crosscheck.filter(|check| check.over_tolerance) {
+        row(
+            &mut body,
+            "counters",
+            format!(
+                "<span class=\"bad\">{} counted, the interface received {}, {:.1}% apart</span>",
+                check.seen, check.received, check.discrepancy
+            ),
+        );
+    }
+    if let Some(error) = &report.error {
+        row(
+            &mut body,
+            "stats",
+            format!("<span class=\"bad\">{}</span>", escape(error)),
+        );
+    }
+    body.push_str("</table>\n");
+
+    let rates = serde_json::to_value(report).unwrap_or_default();
+    body.push_str("<h2>Rates (per second)</h2>\n<table>\n");
+    for key in [
+        "drop_rate",
+        "drop_rate_smoothed",
+        "pass_rate",
+        "pass_rate_smoothed",
+    ] {
+        row(&mut body, key, number(&rates[key]));
+    }
+    body.push_str("</table>\n");
+
+    // Drop reasons apart from the rest, each sorted by name
+    let totals = serde_json::to_value(report.totals).unwrap_or_default();
+    let (drops, counters): (Vec<_>, Vec<_>) = totals
+        .as_object()
+        .into_iter()
+        .flatten()
+        .partition(|(key, _)| key.ends_with("_drops") && *key != "paused_drops");
+    body.push_str("<h2>Counters</h2>\n<table>\n");
+    for (key, value) in counters {
+        row(&mut body, key, number(value));
+    }
+    body.push_str("</table>\n<h2>Drops by reason</h2>\n<table>\n");
+    for (key, value) in drops {
+        row(&mut body, key.trim_end_matches("_drops"), number(value));
+    }
+    body.push_str("</table>\n");
+
+    offenders_table(&mut body, &offenders, control.geo.as_deref());
+
+    TEMPLATE
+        .replace("{{refresh}}", &REFRESH_SECS.to_string())
+        .replace("{{body}}", &body)
+}
+
+// With `geo`, a column of what the databases know of each source
+fn offenders_table(body: &mut String, offenders: &[Offender], geo: Option<&GeoIp>) {
+    let _ = writeln!(
+        body,
+        "<h2>Top offenders ({} sources in their current window)</h2>",
+        offenders.len()
+    );
+    body.push_str("<table>\n<tr><th>source</th><th>count</th><th>limit</th><th>age</th>");
+    if geo.is_some() {
+        body.push_str("<th>network</th>");
+    }
+    body.push_str("</tr>\n");
+    for o in offenders.iter().take(TOP) {
+        let limit = if o.limit == 0 {
+            "blocked by tag".to_owned()
+        } else if o.nat {
+            format!("{} all ports", o.limit)
+        } else {
+            o.limit.to_string()
+        };
+        let port = o.port.map(|port| format!(":{port}")).unwrap_or_default();
+        let class = if o.limit == 0 || o.count > o.limit {
+            " class=\"bad\""
+        } else {
+            ""
+        };
+        let _ = write!(
+            body,
+            "<tr{class}><td>{}{port}</td><td class=\"n\">{}</td><td class=\"n\">{limit}</td>\
+             <td class=\"n\">{}</td>",
+            Masked(o.addr),
+            o.count,
+            heatmap::format_age(o.age)
+        );
+        if let Some(geo) = geo {
+            let found = geo.lookup(o.addr).map(|found| escape(&found.to_string()));
+            let _ = write!(body, "<td>{}</td>", found.unwrap_or_default());
+        }
+        body.push_str("</tr>\n");
+    }
+    body.push_str("</table>\n");
+}
+
+fn row(body: &mut String, name: &str, value: String) {
+    let _ = writeln!(
+        body,
+        "<tr><td>{}</td><td class=\"n\">{value}</td></tr>",
+        escape(&name.replace('_', " "))
+    );
+}
+
+fn flag(set: bool) -> String {
+    if set {
+        "<span class=\"ok\">yes</span>".to_owned()
+    } else {
+        "<span class=\"bad\">no</span>".to_owned()
+    }
+}
+
+// Numbers as the JSON has them; arrays (per-class counters) space-separated
+fn number(value: &Value) -> String {
+    match value {
+        Value::Number(n) => n.to_string(),
+        Value::Array(values) => values.iter().map(number).collect::<Vec<_>>().join(" "),
+        other => escape(&other.to_string()),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,442 @@
+//! Deletes limiter entries of sources that went quiet.
+//!
+//! The limiter maps are plain hash maps, so a source that sent one packet a week ago keeps
+//! its slot and, once the map is full, new sources get no entry at all. The sweeper scans the
+//! maps on a slow interval and deletes entries idle for longer than `--tracking-idle-secs`.
+//! Verdicts never depend on it: the datapath deletes a stale entry its source comes back to
+//! and starts it over as a new source, so the sweeper only has to catch the sources that
+//! stay away and can run as rarely as `--sweep-interval` says.
+//!
+//! With `--prefix-quota` it also recounts the entries each /16 holds, which frees the slots
+//! of the entries it deleted.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    hash::Hash,
+    io,
+    mem::MaybeUninit,
+    os::fd::{AsFd as _, AsRawFd as _},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use aya::{
+    Pod,
+    maps::{HashMap, MapData, MapError},
+};
+use log::{debug, warn};
+use xdp_api_guard_common::{PREFIX_MASK, PacketLog};
+
+use crate::{control::ControlState, timebase, trace::Span};
+
+// Keys walked per lock, so control commands get the maps in between
+const CHUNK: usize = 256;
+
+const BPF_MAP_GET_NEXT_KEY: libc::c_long = 4;
+
+/// Self-metrics of the sweeper, exported on `/metrics`.
+#[derive(Debug, Default)]
+pub struct SweepStats {
+    pub evicted_total: AtomicU64,
+    /// Entries found by the last sweep, before deleting.
+    pub entries: AtomicU64,
+    pub last_duration_us: AtomicU64,
+}
+
+/// Sweeps every `every`, or a few times per `idle` period without it, until the task is
+/// dropped.
+pub async fn run(
+    state: Arc<ControlState>,
+    stats: Arc<SweepStats>,
+    idle: Duration,
+    every: Option<Duration>,
+) {
+    let mut tick = tokio::time::interval(every.unwrap_or_else(|| interval(idle)));
+    // The first tick completes right away, nothing is idle yet
+    tick.tick().await;
+    loop {
+        tick.tick().await;
+        let state = state.clone();
+        let mut span = Span::root("sweep");
+        let started = Instant::now();
+        let idle_ns = idle.as_nanos() as u64;
+        // Thousands of map syscalls, keep them off the runtime threads
+        let result = tokio::task::spawn_blocking(move || sweep_all(&state, idle_ns)).await;
+        let elapsed = started.elapsed();
+        match result {
+            Ok(Ok((entries, evicted))) => {
+                stats.evicted_total.fetch_add(evicted, Ordering::Relaxed);
+                stats.entries.store(entries, Ordering::Relaxed);
+                stats
+                    .last_duration_us
+                    .store(elapsed.as_micros() as u64, Ordering::Relaxed);
+                debug!("sweep: {evicted} of {entries} limiter entries idle, took {elapsed:?}");
+                span.set("entries", entries);
+                span.set("evicted", evicted);
+            }
+            Ok(Err(e)) => {
+                warn!("sweep failed: {e:#}");
+                span.fail(format_args!("{e:#}"));
+            }
+            Err(e) => {
+                warn!("sweep task failed: {e}");
+                span.fail(&e);
+            }
+        }
+    }
+}
+
+/// A few sweeps per idle period, so entries live at most a little longer than it.
+fn interval(idle: Duration) -> Duration {
+    (idle / 4).clamp(Duration::from_secs(5), Duration::from_secs(60))
+}
+
+fn sweep_all(state: &ControlState, idle_ns: u64) -> anyhow::Result<(u64, u64)> {
+    let mut entries = 0;
+    let mut evicted = 0;
+    for (found, gone) in [
+        sweep(&state.rate_limit, timebase::boot_ns, idle_ns)?,
+        sweep(&state.rate_limit6, timebase::boot_ns, idle_ns)?,
+        sweep(&state.quic_initial, timebase::boot_ns, idle_ns)?,
+        sweep_if(&state.ack, idle_ns)?,
+        sweep_if(&state.http, idle_ns)?,
+        sweep(&state.prefix, timebase::boot_ns, idle_ns)?,
+        sweep(&state.nat, timebase::boot_ns, idle_ns)?,
+    ] {
+        entries += found;
+        evicted += gone;
+    }
+    if state.config.lock().unwrap().get().prefix_quota != 0 {
+        recount(state)?;
+    }
+    Ok((entries, evicted))
+}
+
+// Sets the slots of each /16 to the entries its sources hold. The kernel may admit a few
+// more during the walk, the next sweep counts those.
+fn recount(state: &ControlState) -> anyhow::Result<()> {
+    let mut held = BTreeMap::<u32, u32>::new();
+    walk(&state.rate_limit, |_, key| {
+        *held.entry(key & PREFIX_MASK).or_default() += 1;
+        Ok(())
+    })?;
+    let mut slots = state.prefix_sources.lock().unwrap();
+    for prefix in slots.keys().collect::<Result<Vec<u32>, _>>()? {
+        if held.contains_key(&prefix) {
+            continue;
+        }
+        match slots.remove(&prefix) {
+            Ok(()) | Err(MapError::KeyNotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    for (prefix, sources) in held {
+        slots.insert(prefix, sources, 0)?;
+    }
+    Ok(())
+}
+
+// A map the program was built without has nothing to sweep
+pub(crate) fn sweep_if<K: Pod + Eq + Hash>(
+    map: &Option<Mutex<HashMap<MapData, K, PacketLog>>>,
+    idle_ns: u64,
+) -> anyhow::Result<(u64, u64)> {
+    match map {
+        Some(map) => sweep(map, timebase::boot_ns, idle_ns),
+        None => Ok((0, 0)),
+    }
+}
+
+/// What a sweep needs of a limiter map: the kernel's for the daemon, one of their own for
+/// the tests.
+trait Entries {
+    type Key: Copy + Eq + Hash;
+
+    /// The key after `key` in the map's order, the first one for None, None past the last.
+    /// After a key that is gone the kernel starts over from the first.
+    fn next_key(&mut self, key: Option<&Self::Key>) -> anyhow::Result<Option<Self::Key>>;
+
+    /// When the source of `key` was last seen, None if it's gone.
+    fn last_seen(&mut self, key: &Self::Key) -> anyhow::Result<Option<u64>>;
+
+    /// Whether it was still there.
+    fn remove(&mut self, key: &Self::Key) -> anyhow::Result<bool>;
+}
+
+// The element members of `union bpf_attr`
+#[repr(C)]
+struct NextKeyAttr {
+    map_fd: u32,
+    key: u64,
+    next_key: u64,
+    flags: u64,
+}
+
+impl<K: Pod + Eq + Hash> Entries for HashMap<MapData, K, PacketLog> {
+    type Key = K;
+
+    // aya's key iterator borrows the map, so it can't be put down between locks
+    fn next_key(&mut self, key: Option<&K>) -> anyhow::Result<Option<K>> {
+        let mut next = MaybeUninit::<K>::uninit();
+        let mut attr = NextKeyAttr {
+            map_fd: self.map().fd().as_fd().as_raw_fd() as u32,
+            // Null asks for the first key
+            key: key.map_or(0, |key| key as *const K as u64),
+            next_key: next.as_mut_ptr() as u64,
+            flags: 0,
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                BPF_MAP_GET_NEXT_KEY,
+                &mut attr as *mut NextKeyAttr,
+                size_of::<NextKeyAttr>() as u32,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENOENT) {
+                return Ok(None);
+            }
+            return Err(anyhow::Error::new(e).context("BPF_MAP_GET_NEXT_KEY failed"));
+        }
+        // SAFETY: the kernel wrote a whole key, and any bytes are a valid Pod
+        Ok(Some(unsafe { next.assume_init() }))
+    }
+
+    fn last_seen(&mut self, key: &K) -> anyhow::Result<Option<u64>> {
+        match self.get(key, 0) {
+            Ok(log) => Ok(Some(log.last_seen)),
+            Err(MapError::KeyNotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> anyhow::Result<bool> {
+        match HashMap::remove(self, key) {
+            Ok(()) => Ok(true),
+            Err(MapError::KeyNotFound) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Calls `visit` on each key of `map` once, `CHUNK` keys per lock, and returns how many it
+/// saw. Entries admitted during the walk may or may not be seen. `visit` may remove the key
+/// it is given.
+fn walk<M: Entries>(
+    map: &Mutex<M>,
+    mut visit: impl FnMut(&mut M, M::Key) -> anyhow::Result<()>,
+) -> anyhow::Result<u64> {
+    let mut seen = HashSet::new();
+    // The last key of a chunk is visited once the walk has gone past it under the next lock,
+    // so a visit that removes it can't send the kernel back to the first key
+    let mut last: Option<M::Key> = None;
+    // Keys seen again because another removed the key the walk stopped at, and the kernel
+    // started over. Past as many as there are keys the map is churning, the next sweep gets
+    // the rest
+    let mut again = 0;
+    loop {
+        let mut map = map.lock().unwrap();
+        let mut key = last;
+        let mut chunk = Vec::with_capacity(CHUNK);
+        while chunk.len() < CHUNK && again <= seen.len() {
+            let Some(next) = map.next_key(key.as_ref())? else {
+                break;
+            };
+            key = Some(next);
+            if seen.insert(next) {
+                chunk.push(next);
+            } else {
+                again += 1;
+            }
+        }
+        if let Some(key) = last {
+            visit(&mut *map, key)?;
+        }
+        last = chunk.pop();
+        for key in chunk {
+            visit(&mut *map, key)?;
+        }
+        if last.is_none() {
+            return Ok(seen.len() as u64);
+        }
+    }
+}
+
+/// Deletes the entries of `map` idle for longer than `idle_ns` at `now`, read anew for each
+/// entry with its `last_seen` right before the delete: a source may have come back since
+/// the sweep started. Returns the entries seen and those deleted.
+fn sweep<M: Entries>(
+    map: &Mutex<M>,
+    now: impl Fn() -> u64,
+    idle_ns: u64,
+) -> anyhow::Result<(u64, u64)> {
+    let mut evicted = 0;
+    let entries = walk(map, |map, key| {
+        let Some(last_seen) = map.last_seen(&key)? else {
+            return Ok(());
+        };
+        if now().saturating_sub(last_seen) > idle_ns && map.remove(&key)? {
+            evicted += 1;
+        }
+        Ok(())
+    })?;
+    Ok((entries, evicted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_000_000;
+    const IDLE: u64 = 100;
+
+    type Meddle = Box<dyn FnMut(&mut Vec<(u32, u64)>, Option<u32>)>;
+
+    // A hash map as the kernel walks it: keys in a fixed order, and the first key after one
+    // that is gone
+    struct Fake {
+        entries: Vec<(u32, u64)>,
+        restarts: u32,
+        // Called before each step of the walk with the key it steps from, as the datapath
+        // and other clients might change the map between syscalls
+        meddle: Meddle,
+    }
+
+    impl Fake {
+        fn new(entries: impl IntoIterator<Item = (u32, u64)>) -> Self {
+            Self {
+                entries: entries.into_iter().collect(),
+                restarts: 0,
+                meddle: Box::new(|_, _| {}),
+            }
+        }
+
+        fn keys(&self) -> Vec<u32> {
+            self.entries.iter().map(|&(key, _)| key).collect()
+        }
+    }
+
+    impl Entries for Fake {
+        type Key = u32;
+
+        fn next_key(&mut self, key: Option<&u32>) -> anyhow::Result<Option<u32>> {
+            (self.meddle)(&mut self.entries, key.copied());
+            let at = match key {
+                None => 0,
+                Some(key) => match self.entries.iter().position(|(k, _)| k == key) {
+                    Some(i) => i + 1,
+                    None => {
+                        self.restarts += 1;
+                        0
+                    }
+                },
+            };
+            Ok(self.entries.get(at).map(|&(key, _)| key))
+        }
+
+        fn last_seen(&mut self, key: &u32) -> anyhow::Result<Option<u64>> {
+            Ok(self
+                .entries
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|&(_, at)| at))
+        }
+
+        fn remove(&mut self, key: &u32) -> anyhow::Result<bool> {
+            let before = self.entries.len();
+            self.entries.retain(|(k, _)| k != key);
+            Ok(self.entries.len() < before)
+        }
+    }
+
+    fn run(fake: Fake) -> (u64, u64, Fake) {
+        let map = Mutex::new(fake);
+        let (entries, evicted) = sweep(&map, || NOW, IDLE).unwrap();
+        (entries, evicted, map.into_inner().unwrap())
+    }
+
+    #[test]
+    fn idle_entries_go_and_busy_ones_stay() {
+        // Even keys busy, odd ones idle, over several chunks
+        let n = CHUNK as u32 * 3 + 7;
+        let fake = Fake::new((0..n).map(|key| (key, if key % 2 == 0 { NOW } else { 0 })));
+        let (entries, evicted, fake) = run(fake);
+        assert_eq!(entries, u64::from(n));
+        assert_eq!(evicted, u64::from(n / 2));
+        assert!(fake.keys().iter().all(|key| key % 2 == 0));
+        // Deleting the key a chunk ended at never sent the walk back to the start
+        assert_eq!(fake.restarts, 0);
+    }
+
+    #[test]
+    fn every_key_is_visited_once() {
+        for n in [0, 1, CHUNK - 1, CHUNK, CHUNK + 1, CHUNK * 2] {
+            let map = Mutex::new(Fake::new((0..n as u32).map(|key| (key, 0))));
+            let mut visited = Vec::new();
+            let seen = walk(&map, |_, key| {
+                visited.push(key);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(seen, n as u64);
+            visited.sort_unstable();
+            assert_eq!(visited, (0..n as u32).collect::<Vec<_>>(), "{n} keys");
+        }
+    }
+
+    #[test]
+    fn sources_that_come_back_during_the_walk_stay() {
+        let mut fake = Fake::new((0..CHUNK as u32 * 2).map(|key| (key, 0)));
+        // Key 400 is seen again while the walk is in its first chunk
+        fake.meddle = Box::new(|entries, from| {
+            if from == Some(10) {
+                entries.iter_mut().find(|(key, _)| *key == 400).unwrap().1 = NOW;
+            }
+        });
+        let (_, evicted, fake) = run(fake);
+        assert_eq!(fake.keys(), [400]);
+        assert_eq!(evicted, CHUNK as u64 * 2 - 1);
+    }
+
+    #[test]
+    fn a_key_removed_under_the_walk_makes_it_start_over_not_stop() {
+        let n = CHUNK as u32 * 3;
+        let mut fake = Fake::new((0..n).map(|key| (key, 0)));
+        // Another client resets the key the first chunk ended at, between two locks
+        let stop = CHUNK as u32 - 1;
+        fake.meddle = Box::new(move |entries, from| {
+            if from == Some(stop) {
+                entries.retain(|(key, _)| *key != stop);
+            }
+        });
+        let (entries, evicted, fake) = run(fake);
+        assert_eq!(fake.restarts, 1);
+        assert!(fake.entries.is_empty());
+        // The keys it saw twice count once
+        assert_eq!(entries, u64::from(n));
+        assert_eq!(evicted, u64::from(n - 1));
+    }
+
+    #[test]
+    fn a_churning_map_ends_the_walk() {
+        let mut fake = Fake::new((0..CHUNK as u32 * 2).map(|key| (key, NOW)));
+        // Every key it steps from is gone by then, and back in the map right after
+        let mut gone = None;
+        fake.meddle = Box::new(move |entries, from| {
+            entries.extend(gone.take());
+            if let Some(from) = from {
+                let at = entries.iter().position(|(key, _)| *key == from).unwrap();
+                gone = Some(entries.remove(at));
+            }
+        });
+        let (entries, evicted, fake) = run(fake);
+        assert!(fake.restarts > 0);
+        assert_eq!(entries, CHUNK as u64 * 2);
+        assert_eq!(evicted, 0);
+    }
+}
@@ -0,0 +1,251 @@
+//! A small merging t-digest (Dunning & Ertl) for streaming quantile estimates in bounded memory.
+//! Accuracy is best at the tails, which is where rate-limit percentiles live.
+
+use std::f64::consts::PI;
+
+#[derive(Clone, Copy, Debug)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct TDigest {
+    compression: f64,
+    centroids: Vec<Centroid>,
+    buffer: Vec<f64>,
+    count: f64,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    /// `compression` bounds the number of centroids (roughly `compression / 2` after a merge).
+    pub fn new(compression: f64) -> Self {
+        Self {
+            compression,
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+            count: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.buffer.push(value);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if self.buffer.len() as f64 >= self.compression * 4.0 {
+            self.merge();
+        }
+    }
+
+    pub fn count(&mut self) -> u64 {
+        self.merge();
+        self.count as u64
+    }
+
+    /// Estimated value at quantile `q` (0.0..=1.0), `None` while empty.
+    pub fn quantile(&mut self, q: f64) -> Option<f64> {
+        self.merge();
+        let first = self.centroids.first()?;
+        let last = self.centroids.last()?;
+        let q = q.clamp(0.0, 1.0);
+        let target = q * self.count;
+        if target <= first.weight / 2.0 {
+            // Between the minimum and the middle of the first centroid
+            return Some(lerp(self.min, first.mean, target / (first.weight / 2.0)));
+        }
+        if target >= self.count - last.weight / 2.0 {
+            let into = (target - (self.count - last.weight / 2.0)) / (last.weight / 2.0);
+            return Some(lerp(last.mean, self.max, into));
+        }
+        // Walk centroid midpoints until the target falls between two of them
+        let mut cumulative = first.weight / 2.0;
+        for pair in self.centroids.windows(2) {
+            let step = (pair[0].weight + pair[1].weight) / 2.0;
+            if cumulative + step >= target {
+                return Some(lerp(
+                    pair[0].mean,
+                    pair[1].mean,
+                    (target - cumulative) / step,
+                ));
+            }
+            cumulative += step;
+        }
+        Some(last.mean)
+    }
+
+    fn merge(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut points: Vec<Centroid> = self
+            .buffer
+            .drain(..)
+            .map(|mean| Centroid { mean, weight: 1.0 })
+            .collect();
+        points.append(&mut self.centroids);
+        points.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+        self.count = points.iter().map(|c| c.weight).sum();
+
+        let mut merged: Vec<Centroid> = Vec::with_capacity(points.len());
+        let mut so_far = 0.0;
+        let mut k_limit = self.k(0.0) + 1.0;
+        for point in points {
+            let q = (so_far + point.weight) / self.count;
+            match merged.last_mut() {
+                Some(current) if self.k(q) <= k_limit => {
+                    current.weight += point.weight;
+                    current.mean += (point.mean - current.mean) * point.weight / current.weight;
+                }
+                _ => {
+                    if !merged.is_empty() {
+                        k_limit = self.k(so_far / self.count) + 1.0;
+                    }
+                    merged.push(point);
+                }
+            }
+            so_far += point.weight;
+        }
+        self.centroids = merged;
+    }
+
+    /// The k1 scale function: centroids get small near the tails and large in the middle.
+    fn k(&self, q: f64) -> f64 {
+        self.compression / (2.0 * PI) * (2.0 * q - 1.0).asin()
+    }
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t.clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fixed sequence in [0, 1), the same on every run
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> f64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 11) as f64 / (1u64 << 53) as f64
+        }
+
+        // Box-Muller, the cosine half
+        fn normal(&mut self) -> f64 {
+            let u = 1.0 - self.next();
+            (-2.0 * u.ln()).sqrt() * (2.0 * PI * self.next()).cos()
+        }
+    }
+
+    const QUANTILES: [f64; 7] = [0.01, 0.1, 0.5, 0.9, 0.99, 0.999, 0.9999];
+
+    // Fed `values` in their order, the digest's estimate of each quantile falls within
+    // `tolerance` (times the spread of q, as t-digest promises) of it in rank among them
+    fn assert_ranks(values: &[f64], tolerance: f64) {
+        let mut digest = TDigest::new(100.0);
+        for &value in values {
+            digest.add(value);
+        }
+        assert_eq!(digest.count(), values.len() as u64);
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        for q in QUANTILES {
+            let estimate = digest.quantile(q).unwrap();
+            let rank = sorted.partition_point(|&v| v <= estimate) as f64 / sorted.len() as f64;
+            let allowed = tolerance * (q * (1.0 - q)).sqrt().max(0.01);
+            assert!(
+                (rank - q).abs() <= allowed,
+                "q {q}: estimate {estimate} has rank {rank}, allowed {allowed}"
+            );
+        }
+    }
+
+    #[test]
+    fn uniform_quantiles() {
+        let mut rng = Lcg(1);
+        let values: Vec<f64> = (0..100_000).map(|_| rng.next() * 1000.0).collect();
+        assert_ranks(&values, 0.02);
+        // Against the distribution itself, sampling error included
+        let mut digest = TDigest::new(100.0);
+        values.iter().for_each(|&v| digest.add(v));
+        for q in QUANTILES {
+            let estimate = digest.quantile(q).unwrap();
+            assert!((estimate - q * 1000.0).abs() < 5.0, "q {q}: {estimate}");
+        }
+    }
+
+    #[test]
+    fn normal_quantiles() {
+        let mut rng = Lcg(2);
+        let values: Vec<f64> = (0..100_000).map(|_| rng.normal()).collect();
+        assert_ranks(&values, 0.02);
+        let mut digest = TDigest::new(100.0);
+        values.iter().for_each(|&v| digest.add(v));
+        // The standard normal's own, as close as 100k samples get to them
+        for (q, z, within) in [
+            (0.5, 0.0, 0.02),
+            (0.9, 1.2816, 0.03),
+            (0.99, 2.3263, 0.05),
+            (0.999, 3.0902, 0.1),
+        ] {
+            let estimate = digest.quantile(q).unwrap();
+            assert!(
+                (estimate - z).abs() < within,
+                "q {q}: {estimate}, expected {z}"
+            );
+        }
+    }
+
+    #[test]
+    fn sorted_input_and_repeated_values() {
+        let values: Vec<f64> = (0..50_000).map(f64::from).collect();
+        assert_ranks(&values, 0.02);
+        let reversed: Vec<f64> = values.iter().rev().copied().collect();
+        assert_ranks(&reversed, 0.02);
+        // Peaks are whole packet counts, many of them the same
+        let mut rng = Lcg(3);
+        let steps: Vec<f64> = (0..50_000).map(|_| (rng.next() * 10.0).floor()).collect();
+        let mut digest = TDigest::new(100.0);
+        steps.iter().for_each(|&v| digest.add(v));
+        let median = digest.quantile(0.5).unwrap();
+        assert!((4.0..=5.0).contains(&median), "{median}");
+    }
+
+    #[test]
+    fn edges() {
+        let mut digest = TDigest::new(100.0);
+        assert_eq!(digest.quantile(0.5), None);
+        assert_eq!(digest.count(), 0);
+        digest.add(7.0);
+        assert_eq!(digest.quantile(0.0), Some(7.0));
+        assert_eq!(digest.quantile(1.0), Some(7.0));
+        for value in [1.0, 3.0, 9.0] {
+            digest.add(value);
+        }
+        // The extremes are exact, and out of range quantiles are clamped to them
+        assert_eq!(digest.quantile(0.0), Some(1.0));
+        assert_eq!(digest.quantile(1.0), Some(9.0));
+        assert_eq!(digest.quantile(-1.0), Some(1.0));
+        assert_eq!(digest.quantile(2.0), Some(9.0));
+    }
+
+    #[test]
+    fn centroids_stay_bounded() {
+        let mut rng = Lcg(4);
+        let mut digest = TDigest::new(100.0);
+        for _ in 0..200_000 {
+            digest.add(rng.next());
+        }
+        digest.merge();
+        assert!(digest.centroids.len() <= 100, "{}", digest.centroids.len());
+        assert!(digest.buffer.is_empty());
+    }
+}
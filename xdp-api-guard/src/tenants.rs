@@ -0,0 +1,453 @@
+//! `--tenant`: protected destination addresses whose traffic is counted on its own, for the
+//! status pages of the customers behind them.
+//!
+//! The kernel finds a packet's destination in `PROTECTED_DSTS` and counts it, its bytes and
+//! the feature that dropped it, if any, under the tenant id in `TENANT_STATS`, along with one
+//! bit per source for an estimate of how many there are. Drops are decisions, like the drop
+//! counters: while paused they are counted and the packets pass anyway. Once a second the
+//! sampler takes the deltas into a ring of the last minute per tenant; `report` turns that
+//! into what `GET /v1/tenants/{vip}/status` shows.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::Ipv4Addr,
+};
+
+use anyhow::bail;
+use aya::maps::{HashMap, MapData, PerCpuArray, PerCpuValues};
+use serde::Serialize;
+use xdp_api_guard_common::{MAX_TENANTS, feature, tenant_stat};
+
+/// Seconds of history kept per tenant, also what drops are summed over.
+pub const HISTORY_SECS: usize = 60;
+
+/// Seconds the rates and the state are averaged over.
+pub const RECENT_SECS: usize = 10;
+
+// Drops by these are the baseline, the entries an operator or a feed put there on purpose
+const BASELINE: &[u32] = &[feature::BLOCKLIST];
+
+/// From `--tenant-elevated-pps` and `--tenant-mitigating-pps`.
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    /// Packets a second to the tenant from which it is elevated.
+    pub elevated_pps: u64,
+    /// Packets a second dropped by anything beyond the baseline from which it is mitigating.
+    pub mitigating_pps: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TenantState {
+    Normal,
+    Elevated,
+    Mitigating,
+}
+
+/// What `GET /v1/tenants/{vip}/status` returns.
+#[derive(Debug, Serialize)]
+pub struct TenantStatus {
+    pub vip: Ipv4Addr,
+    pub state: TenantState,
+    /// Packets and bits a second to the tenant, over the last `RECENT_SECS`.
+    pub pps: u64,
+    pub bps: u64,
+    /// Packets dropped in the last `HISTORY_SECS`, by feature, only those that dropped any.
+    pub drops_last_minute: BTreeMap<&'static str, u64>,
+    /// Estimated distinct sources in the last one to two minutes.
+    pub sources: u64,
+    /// The estimate ran out of bits: there were at least `sources`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub sources_saturated: bool,
+    /// Features beyond the baseline that dropped packets to the tenant in the last minute,
+    /// auto-bans and the global limit's pressure drops among them.
+    pub mitigation: Vec<&'static str>,
+    /// Share of the SYNs to the tenant in the last `HISTORY_SECS` that became established
+    /// flows, with --conntrack and SYNs to go by.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub syn_conversion: Option<f64>,
+}
+
+pub struct Tenants {
+    dsts: HashMap<MapData, u32, u8>,
+    stats: PerCpuArray<MapData, u64>,
+    thresholds: Thresholds,
+    // Index + 1 is the tenant id
+    tenants: Vec<Tenant>,
+    // Samples since the source bitmaps were last cleared
+    ticks: usize,
+}
+
+struct Tenant {
+    vip: Ipv4Addr,
+    // Counters at the last sample, `None` before the first
+    totals: Option<Counts>,
+    // Deltas of the last `HISTORY_SECS` samples, oldest first
+    history: VecDeque<Counts>,
+    // The source bitmap of the minute before the current one
+    previous_sources: [u64; tenant_stat::SOURCE_WORDS as usize],
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    packets: u64,
+    bytes: u64,
+    drops: [u64; feature::LEN as usize],
+    syns: u64,
+    converted: u64,
+}
+
+impl Counts {
+    fn delta(&self, before: &Counts) -> Counts {
+        Counts {
+            packets: self.packets.saturating_sub(before.packets),
+            bytes: self.bytes.saturating_sub(before.bytes),
+            drops: std::array::from_fn(|i| self.drops[i].saturating_sub(before.drops[i])),
+            syns: self.syns.saturating_sub(before.syns),
+            converted: self.converted.saturating_sub(before.converted),
+        }
+    }
+}
+
+impl Tenant {
+    // Takes the counters of a sample, the first only to have something to take deltas from
+    fn record(&mut self, counts: Counts) {
+        if let Some(before) = self.totals.replace(counts) {
+            if self.history.len() == HISTORY_SECS {
+                self.history.pop_front();
+            }
+            self.history.push_back(counts.delta(&before));
+        }
+    }
+
+    // The status from the history and `sources`, the bitmap of the last one to two minutes
+    fn status(&self, sources: &[u64], thresholds: &Thresholds) -> TenantStatus {
+        let (sources, sources_saturated) = estimate(sources);
+
+        let recent_len = self.history.len().min(RECENT_SECS);
+        let recent = self.history.range(self.history.len() - recent_len..);
+        let mut sum = Counts::default();
+        for delta in recent {
+            sum.packets += delta.packets;
+            sum.bytes += delta.bytes;
+            for (total, drops) in sum.drops.iter_mut().zip(delta.drops) {
+                *total += drops;
+            }
+        }
+        let secs = recent_len.max(1) as u64;
+        let pps = sum.packets / secs;
+        let mitigating_pps = (0..feature::LEN)
+            .filter(|f| !BASELINE.contains(f))
+            .map(|f| sum.drops[f as usize])
+            .sum::<u64>()
+            / secs;
+
+        let mut drops_last_minute = BTreeMap::new();
+        for (i, name) in feature::NAMES.iter().enumerate() {
+            let drops: u64 = self.history.iter().map(|delta| delta.drops[i]).sum();
+            if drops != 0 {
+                drops_last_minute.insert(*name, drops);
+            }
+        }
+        let mitigation: Vec<&'static str> = drops_last_minute
+            .keys()
+            .copied()
+            .filter(|name| feature::from_name(name).is_some_and(|f| !BASELINE.contains(&f)))
+            .collect();
+
+        let syns: u64 = self.history.iter().map(|delta| delta.syns).sum();
+        let converted: u64 = self.history.iter().map(|delta| delta.converted).sum();
+        let syn_conversion = (syns != 0).then(|| (converted as f64 / syns as f64).min(1.0));
+
+        let state = if mitigating_pps >= thresholds.mitigating_pps.max(1) {
+            TenantState::Mitigating
+        } else if pps >= thresholds.elevated_pps || !mitigation.is_empty() {
+            TenantState::Elevated
+        } else {
+            TenantState::Normal
+        };
+        TenantStatus {
+            vip: self.vip,
+            state,
+            pps,
+            bps: sum.bytes * 8 / secs,
+            drops_last_minute,
+            sources,
+            sources_saturated,
+            mitigation,
+            syn_conversion,
+        }
+    }
+}
+
+impl Tenants {
+    pub fn new(
+        dsts: HashMap<MapData, u32, u8>,
+        stats: PerCpuArray<MapData, u64>,
+        thresholds: Thresholds,
+    ) -> Self {
+        Self {
+            dsts,
+            stats,
+            thresholds,
+            tenants: Vec::new(),
+            ticks: 0,
+        }
+    }
+
+    /// Protects `vip`. Returns false if it already was.
+    pub fn add(&mut self, vip: Ipv4Addr) -> anyhow::Result<bool> {
+        if self.tenants.iter().any(|tenant| tenant.vip == vip) {
+            return Ok(false);
+        }
+        if self.tenants.len() + 1 >= MAX_TENANTS as usize {
+            bail!("at most {} tenants", MAX_TENANTS - 1);
+        }
+        let id = self.tenants.len() as u8 + 1;
+        self.dsts.insert(u32::from(vip), id, 0)?;
+        self.tenants.push(Tenant {
+            vip,
+            totals: None,
+            history: VecDeque::with_capacity(HISTORY_SECS),
+            previous_sources: [0; tenant_stat::SOURCE_WORDS as usize],
+        });
+        Ok(true)
+    }
+
+    /// Takes the counters of every tenant into its history, called once a second. Every
+    /// `HISTORY_SECS` the source bitmaps start over.
+    pub fn sample(&mut self) -> anyhow::Result<()> {
+        for id in 1..=self.tenants.len() as u32 {
+            let counts = self.counts(id)?;
+            self.tenants[id as usize - 1].record(counts);
+        }
+        self.ticks += 1;
+        if self.ticks >= HISTORY_SECS {
+            self.ticks = 0;
+            for id in 1..=self.tenants.len() as u32 {
+                let words = self.take_sources(id)?;
+                self.tenants[id as usize - 1].previous_sources = words;
+            }
+        }
+        Ok(())
+    }
+
+    /// The status of `vip`, `None` if it isn't a tenant.
+    pub fn report(&self, vip: Ipv4Addr) -> anyhow::Result<Option<TenantStatus>> {
+        let Some(pos) = self.tenants.iter().position(|tenant| tenant.vip == vip) else {
+            return Ok(None);
+        };
+        let tenant = &self.tenants[pos];
+        let mut sources = self.sources(pos as u32 + 1)?;
+        for (word, previous) in sources.iter_mut().zip(tenant.previous_sources) {
+            *word |= previous;
+        }
+        Ok(Some(tenant.status(&sources, &self.thresholds)))
+    }
+
+    fn counts(&self, id: u32) -> anyhow::Result<Counts> {
+        let base = id * tenant_stat::PER_TENANT;
+        let slot =
+            |slot| -> anyhow::Result<u64> { Ok(self.stats.get(&(base + slot), 0)?.iter().sum()) };
+        let mut drops = [0; feature::LEN as usize];
+        for (f, count) in drops.iter_mut().enumerate() {
+            *count = slot(tenant_stat::FEATURE_DROPS + f as u32)?;
+        }
+        Ok(Counts {
+            packets: slot(tenant_stat::PACKETS)?,
+            bytes: slot(tenant_stat::BYTES)?,
+            drops,
+            syns: slot(tenant_stat::SYNS)?,
+            converted: slot(tenant_stat::CONVERTED)?,
+        })
+    }
+
+    // The source bitmap of tenant `id`, ORed over the CPUs
+    fn sources(&self, id: u32) -> anyhow::Result<[u64; tenant_stat::SOURCE_WORDS as usize]> {
+        let base = id * tenant_stat::PER_TENANT + tenant_stat::SOURCES;
+        let mut words = [0; tenant_stat::SOURCE_WORDS as usize];
+        for (i, word) in words.iter_mut().enumerate() {
+            let copies = self.stats.get(&(base + i as u32), 0)?;
+            *word = copies.iter().fold(0, |bits, copy| bits | copy);
+        }
+        Ok(words)
+    }
+
+    // The source bitmap of tenant `id`, cleared behind it. A bit set in between is lost,
+    // which only makes the estimate a little low.
+    fn take_sources(&self, id: u32) -> anyhow::Result<[u64; tenant_stat::SOURCE_WORDS as usize]> {
+        let words = self.sources(id)?;
+        let base = id * tenant_stat::PER_TENANT + tenant_stat::SOURCES;
+        // As many values as the map hands out, which is what it takes back
+        let cpus = self.stats.get(&base, 0)?.len();
+        for i in 0..tenant_stat::SOURCE_WORDS {
+            self.stats
+                .set(base + i, PerCpuValues::try_from(vec![0u64; cpus])?, 0)?;
+        }
+        Ok(words)
+    }
+}
+
+// Linear counting: with a fraction `z` of the bits still clear, about `-m ln z` sources set
+// the others. With none clear there were at least `m ln m`.
+fn estimate(words: &[u64]) -> (u64, bool) {
+    let m = f64::from(tenant_stat::SOURCE_BITS);
+    let clear: u32 = words.iter().map(|word| word.count_zeros()).sum();
+    if clear == 0 {
+        return ((m * m.ln()) as u64, true);
+    }
+    ((-m * (f64::from(clear) / m).ln()).round() as u64, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        elevated_pps: 1000,
+        mitigating_pps: 100,
+    };
+
+    fn tenant() -> Tenant {
+        Tenant {
+            vip: Ipv4Addr::new(203, 0, 113, 10),
+            totals: None,
+            history: VecDeque::new(),
+            previous_sources: [0; tenant_stat::SOURCE_WORDS as usize],
+        }
+    }
+
+    // Counters that grew by `pps` packets of 100 bytes and `drops` drops by `by` a second
+    fn run(tenant: &mut Tenant, secs: u64, pps: u64, by: u32, drops: u64) {
+        let mut counts = tenant.totals.unwrap_or_default();
+        for _ in 0..secs {
+            counts.packets += pps;
+            counts.bytes += pps * 100;
+            counts.drops[by as usize] += drops;
+            tenant.record(counts);
+        }
+    }
+
+    fn status(tenant: &Tenant) -> TenantStatus {
+        tenant.status(&[0; tenant_stat::SOURCE_WORDS as usize], &THRESHOLDS)
+    }
+
+    #[test]
+    fn the_first_sample_only_primes_the_totals() {
+        let mut tenant = tenant();
+        let mut counts = Counts {
+            packets: 5_000_000,
+            ..Counts::default()
+        };
+        tenant.record(counts);
+        assert!(tenant.history.is_empty());
+        assert_eq!(status(&tenant).pps, 0);
+        counts.packets += 10;
+        tenant.record(counts);
+        assert_eq!(tenant.history.back().unwrap().packets, 10);
+    }
+
+    #[test]
+    fn the_ring_keeps_the_last_minute() {
+        let mut tenant = tenant();
+        run(&mut tenant, 1, 0, feature::RATE_LIMIT, 0);
+        run(&mut tenant, 30, 10, feature::RATE_LIMIT, 5);
+        run(&mut tenant, HISTORY_SECS as u64, 20, feature::RATE_LIMIT, 0);
+        assert_eq!(tenant.history.len(), HISTORY_SECS);
+        assert!(tenant.history.iter().all(|delta| delta.packets == 20));
+        // The drops of thirty seconds ago have aged out with them
+        assert!(status(&tenant).drops_last_minute.is_empty());
+        assert_eq!(status(&tenant).pps, 20);
+        assert_eq!(status(&tenant).bps, 20 * 100 * 8);
+    }
+
+    #[test]
+    fn counters_that_went_back_count_as_nothing() {
+        let mut tenant = tenant();
+        run(&mut tenant, 3, 10, feature::RATE_LIMIT, 1);
+        tenant.record(Counts::default());
+        assert_eq!(tenant.history.back().unwrap().packets, 0);
+        run(&mut tenant, 1, 7, feature::RATE_LIMIT, 0);
+        assert_eq!(tenant.history.back().unwrap().packets, 7);
+    }
+
+    #[test]
+    fn rates_are_over_the_recent_seconds() {
+        let mut tenant = tenant();
+        run(&mut tenant, 1, 0, feature::RATE_LIMIT, 0);
+        run(&mut tenant, 50, 5000, feature::RATE_LIMIT, 0);
+        run(&mut tenant, RECENT_SECS as u64, 10, feature::RATE_LIMIT, 0);
+        assert_eq!(status(&tenant).pps, 10);
+        assert_eq!(status(&tenant).state, TenantState::Normal);
+    }
+
+    #[test]
+    fn state_follows_the_thresholds() {
+        let mut quiet = tenant();
+        run(&mut quiet, 20, 999, feature::RATE_LIMIT, 0);
+        assert_eq!(status(&quiet).state, TenantState::Normal);
+        assert!(status(&quiet).mitigation.is_empty());
+
+        let mut busy = tenant();
+        run(&mut busy, 20, 1000, feature::RATE_LIMIT, 0);
+        assert_eq!(status(&busy).state, TenantState::Elevated);
+
+        // Any drop beyond the baseline is a mitigation, and elevated below the threshold
+        let mut few = tenant();
+        run(&mut few, 20, 10, feature::RATE_LIMIT, 1);
+        let few = status(&few);
+        assert_eq!(few.state, TenantState::Elevated);
+        assert_eq!(
+            few.mitigation,
+            [feature::NAMES[feature::RATE_LIMIT as usize]]
+        );
+        assert_eq!(few.drops_last_minute.values().sum::<u64>(), 19);
+
+        let mut many = tenant();
+        run(&mut many, 20, 10, feature::RATE_LIMIT, 100);
+        assert_eq!(status(&many).state, TenantState::Mitigating);
+    }
+
+    #[test]
+    fn blocklist_drops_are_the_baseline() {
+        let mut tenant = tenant();
+        run(&mut tenant, 20, 10, feature::BLOCKLIST, 500);
+        let status = status(&tenant);
+        assert_eq!(status.state, TenantState::Normal);
+        assert!(status.mitigation.is_empty());
+        let blocklist = feature::NAMES[feature::BLOCKLIST as usize];
+        assert_eq!(status.drops_last_minute[blocklist], 19 * 500);
+    }
+
+    #[test]
+    fn syn_conversion_is_only_there_with_syns() {
+        let mut tenant = tenant();
+        run(&mut tenant, 5, 10, feature::RATE_LIMIT, 0);
+        assert_eq!(status(&tenant).syn_conversion, None);
+        let mut counts = tenant.totals.unwrap();
+        counts.syns += 40;
+        counts.converted += 10;
+        tenant.record(counts);
+        assert_eq!(status(&tenant).syn_conversion, Some(0.25));
+    }
+
+    #[test]
+    fn sources_are_estimated_from_the_clear_bits() {
+        let words = tenant_stat::SOURCE_WORDS as usize;
+        assert_eq!(estimate(&vec![0; words]), (0, false));
+        let mut set = vec![0u64; words];
+        let mut distinct = std::collections::BTreeSet::new();
+        for i in 0..200u32 {
+            let addr = u32::from(Ipv4Addr::new(198, 51, 100, 0)) + i * 7919;
+            let bit = tenant_stat::source_bit(addr);
+            set[bit as usize / 64] |= 1 << (bit % 64);
+            distinct.insert(bit);
+        }
+        let (sources, saturated) = estimate(&set);
+        assert!(!saturated);
+        // Within the 15% linear counting manages at this fill
+        assert!((170..=230).contains(&sources), "{sources}");
+        assert!(sources >= distinct.len() as u64);
+        assert_eq!(estimate(&vec![u64::MAX; words]), (7097, true));
+    }
+}
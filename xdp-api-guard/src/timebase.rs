@@ -0,0 +1,405 @@
+//! The kernel's clocks and the wall clock, and conversions between them.
+//!
+//! Which clock a timestamp in the maps is on depends on the field:
+//!
+//! - Rate windows, limiter, conntrack and rule entries come from `bpf_ktime_get_ns()`:
+//!   CLOCK_MONOTONIC, which stands still while the machine is suspended. After a resume their
+//!   sources look as idle as they were before the sleep, and their windows start over.
+//! - The event stamps, `AbortRecord::at` and `MalformedSample::at`, come from
+//!   `bpf_ktime_get_boot_ns()` where the kernel has it (5.8): CLOCK_BOOTTIME, which goes on
+//!   through a suspend, so converting one to wall clock time stays right after a resume.
+//!   Older kernels stamp them on CLOCK_MONOTONIC too; see [`stamp_ns`].
+//! - Ban expiries aren't in the kernel at all: userspace keeps them in Unix time, so time
+//!   asleep counts towards a ban, and a ban that ran out during the sleep is lifted on the
+//!   first tick after the resume.
+//!
+//! The offset of each kernel clock to the wall clock is measured once and re-measured every
+//! tick. The monotonic one moves after a resume or a wall clock step, the boottime one only
+//! after a step; such jumps are logged and conversions use the latest offsets.
+
+use std::{
+    io,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+
+// Smaller changes are the wall clock being disciplined, not an event
+const JUMP_NS: i64 = 1_000_000_000;
+
+/// Where the clocks are read: the system, or a test's own.
+trait Clock {
+    fn read(&self, id: libc::clockid_t) -> u64;
+}
+
+struct System;
+
+impl Clock for System {
+    fn read(&self, id: libc::clockid_t) -> u64 {
+        clock(id)
+    }
+}
+
+/// The offsets of the kernel clocks to the wall clock as last measured on `clock`.
+struct Timebase<C> {
+    clock: C,
+    // Wall clock minus kernel clock, 0 until first measured
+    offset_ns: AtomicU64,
+    boot_offset_ns: AtomicU64,
+    // Whether the program stamps events on CLOCK_BOOTTIME
+    boot_stamps: AtomicBool,
+}
+
+static SYSTEM: Timebase<System> = Timebase::new(System);
+
+/// How the offsets moved between two measurements.
+#[derive(Debug, PartialEq, Eq)]
+enum Jump {
+    None,
+    /// The wall clock was set, by this many nanoseconds.
+    Step(i64),
+    /// The machine slept this many nanoseconds.
+    Resume(i64),
+}
+
+impl<C: Clock> Timebase<C> {
+    const fn new(clock: C) -> Self {
+        Self {
+            clock,
+            offset_ns: AtomicU64::new(0),
+            boot_offset_ns: AtomicU64::new(0),
+            boot_stamps: AtomicBool::new(false),
+        }
+    }
+
+    fn boot_ns(&self) -> u64 {
+        self.clock.read(libc::CLOCK_MONOTONIC)
+    }
+
+    fn to_wallclock(&self, boot_ns: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(boot_ns.saturating_add(self.offset(&self.offset_ns)))
+    }
+
+    fn to_boot_ns(&self, at: SystemTime) -> u64 {
+        let unix_ns = at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        unix_ns.saturating_sub(self.offset(&self.offset_ns))
+    }
+
+    fn stamp_ns(&self) -> u64 {
+        if self.boot_stamps.load(Ordering::Relaxed) {
+            self.clock.read(libc::CLOCK_BOOTTIME)
+        } else {
+            self.boot_ns()
+        }
+    }
+
+    fn stamp_to_wallclock(&self, stamp_ns: u64) -> SystemTime {
+        if !self.boot_stamps.load(Ordering::Relaxed) {
+            return self.to_wallclock(stamp_ns);
+        }
+        let offset = self.offset(&self.boot_offset_ns);
+        UNIX_EPOCH + Duration::from_nanos(stamp_ns.saturating_add(offset))
+    }
+
+    fn refresh(&self) -> Jump {
+        let monotonic = jump(&self.offset_ns, self.measure(libc::CLOCK_MONOTONIC));
+        let boot = jump(&self.boot_offset_ns, self.measure(libc::CLOCK_BOOTTIME));
+        // CLOCK_BOOTTIME counts the sleep the monotonic clock misses, a step moves both
+        if boot.abs() >= JUMP_NS {
+            Jump::Step(boot)
+        } else if monotonic.abs() >= JUMP_NS {
+            Jump::Resume(monotonic)
+        } else {
+            Jump::None
+        }
+    }
+
+    fn offset(&self, stored: &AtomicU64) -> u64 {
+        match stored.load(Ordering::Relaxed) {
+            0 => {
+                // The first measurement, nothing to have jumped from
+                self.refresh();
+                stored.load(Ordering::Relaxed)
+            }
+            offset => offset,
+        }
+    }
+
+    // Reads the wall clock between two reads of kernel clock `id` and pairs it with their
+    // midpoint, so being preempted in between skews the result by half the delay at most
+    fn measure(&self, id: libc::clockid_t) -> u64 {
+        let before = self.clock.read(id);
+        let wall = self.clock.read(libc::CLOCK_REALTIME);
+        let after = self.clock.read(id);
+        wall.saturating_sub(before + (after - before) / 2)
+    }
+}
+
+/// Nanoseconds on the clock `bpf_ktime_get_ns()` reads.
+pub fn boot_ns() -> u64 {
+    SYSTEM.boot_ns()
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Wall-clock time of a kernel timestamp on CLOCK_MONOTONIC. After a resume the offset has
+/// grown by the sleep, so a timestamp from before it converts to a time that much later.
+pub fn to_wallclock(boot_ns: u64) -> SystemTime {
+    SYSTEM.to_wallclock(boot_ns)
+}
+
+/// CLOCK_MONOTONIC timestamp of a wall-clock time, the inverse of [`to_wallclock`]. Times
+/// before the machine booted come out as 0.
+#[allow(dead_code)]
+pub fn to_boot_ns(at: SystemTime) -> u64 {
+    SYSTEM.to_boot_ns(at)
+}
+
+/// Sets whether the program stamps events on CLOCK_BOOTTIME, as the loader told it to.
+pub fn set_boot_stamps(boot: bool) {
+    SYSTEM.boot_stamps.store(boot, Ordering::Relaxed);
+}
+
+/// Nanoseconds on the clock the event stamps are on.
+pub fn stamp_ns() -> u64 {
+    SYSTEM.stamp_ns()
+}
+
+/// Wall-clock time of an event stamp.
+pub fn stamp_to_wallclock(stamp_ns: u64) -> SystemTime {
+    SYSTEM.stamp_to_wallclock(stamp_ns)
+}
+
+/// Which clock each kind of timestamp is on, for `status`.
+pub fn describe() -> String {
+    let stamps = if SYSTEM.boot_stamps.load(Ordering::Relaxed) {
+        "CLOCK_BOOTTIME"
+    } else {
+        "CLOCK_MONOTONIC"
+    };
+    format!(
+        "clocks: windows and entries on CLOCK_MONOTONIC, event stamps on {stamps}, ban \
+         expiries on the wall clock"
+    )
+}
+
+/// Re-measures the offsets, warning when one jumped since the last measurement.
+pub fn refresh() {
+    match SYSTEM.refresh() {
+        Jump::None => {}
+        Jump::Step(ns) => warn!(
+            "wall clock stepped {:+.3}s, timestamps are converted with the new offset",
+            ns as f64 / 1e9
+        ),
+        Jump::Resume(ns) => warn!(
+            "resumed after {:.3}s of suspend: windows started over, bans count the sleep",
+            ns as f64 / 1e9
+        ),
+    }
+}
+
+// Stores a new offset, returning how far it moved, 0 on the first measurement
+fn jump(stored: &AtomicU64, offset: u64) -> i64 {
+    match stored.swap(offset, Ordering::Relaxed) {
+        0 => 0,
+        prev => offset as i64 - prev as i64,
+    }
+}
+
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_FUNC_KTIME_GET_BOOT_NS: i32 = 125;
+
+#[repr(C)]
+struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+// The start of the `BPF_PROG_LOAD` member of `union bpf_attr`, the rest stays zero
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+/// Whether the kernel gives XDP programs `bpf_ktime_get_boot_ns()`, by loading one that
+/// calls it.
+pub fn kernel_has_boot_ns() -> bool {
+    let insns = [
+        // call bpf_ktime_get_boot_ns
+        Insn {
+            code: 0x85,
+            regs: 0,
+            off: 0,
+            imm: BPF_FUNC_KTIME_GET_BOOT_NS,
+        },
+        // r0 = XDP_PASS
+        Insn {
+            code: 0xb7,
+            regs: 0,
+            off: 0,
+            imm: 2,
+        },
+        // exit
+        Insn {
+            code: 0x95,
+            regs: 0,
+            off: 0,
+            imm: 0,
+        },
+    ];
+    let license = c"GPL";
+    let mut attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_XDP,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        ..ProgLoadAttr::default()
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_PROG_LOAD,
+            &mut attr as *mut ProgLoadAttr,
+            size_of::<ProgLoadAttr>() as u32,
+        )
+    };
+    if fd < 0 {
+        let e = io::Error::last_os_error();
+        debug!("no bpf_ktime_get_boot_ns, event stamps stay on CLOCK_MONOTONIC: {e}");
+        return false;
+    }
+    unsafe { libc::close(fd as libc::c_int) };
+    true
+}
+
+fn clock(id: libc::clockid_t) -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(id, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: u64 = 1_000_000_000;
+
+    // Clocks that only move when told to
+    struct Fake {
+        monotonic: AtomicU64,
+        boottime: AtomicU64,
+        realtime: AtomicU64,
+    }
+
+    impl Clock for Fake {
+        fn read(&self, id: libc::clockid_t) -> u64 {
+            match id {
+                libc::CLOCK_MONOTONIC => &self.monotonic,
+                libc::CLOCK_BOOTTIME => &self.boottime,
+                _ => &self.realtime,
+            }
+            .load(Ordering::Relaxed)
+        }
+    }
+
+    impl Timebase<Fake> {
+        // Booted 1000s ago, at Unix time 1700000000
+        fn fake() -> Self {
+            Timebase::new(Fake {
+                monotonic: AtomicU64::new(1000 * SEC),
+                boottime: AtomicU64::new(1000 * SEC),
+                realtime: AtomicU64::new(1_700_001_000 * SEC),
+            })
+        }
+
+        fn advance(&self, monotonic: u64, boottime: u64, realtime: u64) {
+            self.clock.monotonic.fetch_add(monotonic, Ordering::Relaxed);
+            self.clock.boottime.fetch_add(boottime, Ordering::Relaxed);
+            self.clock.realtime.fetch_add(realtime, Ordering::Relaxed);
+        }
+    }
+
+    fn unix(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn kernel_and_wall_clock_times_convert_both_ways() {
+        let timebase = Timebase::fake();
+        assert_eq!(timebase.to_wallclock(0), unix(1_700_000_000));
+        assert_eq!(timebase.to_wallclock(995 * SEC), unix(1_700_000_995));
+        assert_eq!(timebase.to_boot_ns(unix(1_700_000_995)), 995 * SEC);
+        let at = UNIX_EPOCH + Duration::from_nanos(1_700_000_500 * SEC + 123);
+        assert_eq!(timebase.to_wallclock(timebase.to_boot_ns(at)), at);
+        // Before the boot
+        assert_eq!(timebase.to_boot_ns(unix(1_600_000_000)), 0);
+        assert_eq!(timebase.refresh(), Jump::None);
+    }
+
+    #[test]
+    fn a_suspend_moves_the_monotonic_offset_only() {
+        let timebase = Timebase::fake();
+        timebase.boot_stamps.store(true, Ordering::Relaxed);
+        let before = timebase.boot_ns();
+        let stamp = timebase.stamp_ns();
+        timebase.to_wallclock(0);
+        // An hour asleep, then a minute awake
+        timebase.advance(60 * SEC, 3660 * SEC, 3660 * SEC);
+        assert_eq!(timebase.refresh(), Jump::Resume(3600 * SEC as i64));
+        // A monotonic time from before the sleep converts as if the sleep came before it,
+        // an event stamp on CLOCK_BOOTTIME converts to when it was taken
+        assert_eq!(timebase.to_wallclock(before), unix(1_700_004_600));
+        assert_eq!(timebase.stamp_to_wallclock(stamp), unix(1_700_001_000));
+        assert_eq!(timebase.refresh(), Jump::None);
+    }
+
+    #[test]
+    fn a_wall_clock_step_moves_both_offsets() {
+        let timebase = Timebase::fake();
+        timebase.to_wallclock(0);
+        timebase.advance(0, 0, 120 * SEC);
+        assert_eq!(timebase.refresh(), Jump::Step(120 * SEC as i64));
+        assert_eq!(timebase.to_wallclock(1000 * SEC), unix(1_700_001_120));
+        timebase
+            .clock
+            .realtime
+            .fetch_sub(300 * SEC, Ordering::Relaxed);
+        assert_eq!(timebase.refresh(), Jump::Step(-300 * SEC as i64));
+        // Discipline adjusting it by less than a second is no event
+        timebase.advance(0, 0, SEC / 2);
+        assert_eq!(timebase.refresh(), Jump::None);
+    }
+
+    #[test]
+    fn event_stamps_follow_the_clock_the_program_uses() {
+        let timebase = Timebase::fake();
+        timebase.advance(0, 30 * SEC, 30 * SEC);
+        assert_eq!(timebase.stamp_ns(), 1000 * SEC);
+        assert_eq!(timebase.stamp_to_wallclock(1000 * SEC), unix(1_700_001_030));
+        timebase.boot_stamps.store(true, Ordering::Relaxed);
+        assert_eq!(timebase.stamp_ns(), 1030 * SEC);
+        assert_eq!(timebase.stamp_to_wallclock(1030 * SEC), unix(1_700_001_030));
+    }
+}
@@ -0,0 +1,425 @@
+//! Bearer tokens of the REST API and what each may do.
+//!
+//! `--http-token` is a single admin token. `--http-tokens` adds named tokens, one per line:
+//!
+//! ```text
+//! # id      role       secret                    expires (unix time, optional)
+//! noc       read-only  3d1f0c9a6b2e47d8a1c4
+//! oncall    operator   9a7e5c3b1d0f2e4a6c8b      1798761600
+//! acme      tenant:203.0.113.10,203.0.113.11    5b8d2f6a0c4e1b3d7f9a
+//! ```
+//!
+//! Tenant tokens only read `GET /v1/tenants/{vip}/status` of the addresses listed after the
+//! colon, so a customer can poll their own endpoint and nothing else. Read-only tokens get
+//! every `GET` endpoint and the commands that only look. Operators also
+//! block, unblock, tag and reset sources. Admin tokens can do everything, including allow
+//! entries and pausing, which take addresses or all traffic past the filter. The file is read
+//! again on SIGHUP, or `guard_reload` when embedded, so a removed line revokes its token
+//! without a restart.
+
+use std::{
+    fmt, fs,
+    net::Ipv4Addr,
+    os::unix::fs::PermissionsExt as _,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use anyhow::{Context as _, bail};
+use log::warn;
+
+use crate::control::{Command, FlushTarget, GroupOp};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    ReadOnly,
+    Operator,
+    Admin,
+}
+
+impl Role {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "read-only" => Some(Role::ReadOnly),
+            "operator" => Some(Role::Operator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    /// The role a command needs. Every command is listed, so a new one has to pick.
+    pub fn needed(cmd: &Command) -> Self {
+        match cmd {
+            Command::List
+            | Command::Export { .. }
+            | Command::Status
+            | Command::Params
+            | Command::Why(_)
+            | Command::Offenders(_)
+            | Command::Rules(_)
+            | Command::LastAbort
+            | Command::LogLevel { level: None, .. }
+            | Command::Group(GroupOp::List)
+            | Command::Suggest { apply: false, .. } => Role::ReadOnly,
+            Command::Block(..)
+            | Command::Unblock(_)
+            | Command::Tag(..)
+            | Command::Untag(_)
+            | Command::Reset(_)
+            | Command::Profile(_)
+            | Command::Verify(_)
+            | Command::Flush(FlushTarget::RateLimit)
+            | Command::Flush(FlushTarget::Bans)
+            | Command::Flush(FlushTarget::Conntrack) => Role::Operator,
+            Command::Allow(_)
+            | Command::Enforce { .. }
+            | Command::Pause
+            | Command::Resume
+            | Command::Param(..)
+            | Command::Suggest { apply: true, .. }
+            | Command::Group(_)
+            | Command::Chain(_)
+            | Command::Resize(..)
+            | Command::LogLevel { .. }
+            | Command::SnapshotSave { .. }
+            | Command::SnapshotLoad(_)
+            | Command::Flush(FlushTarget::Blocklist { .. })
+            | Command::Flush(FlushTarget::Stats) => Role::Admin,
+        }
+    }
+
+    /// Whether a token of this role may send `cmd`.
+    pub fn may(self, cmd: &Command) -> bool {
+        self >= Role::needed(cmd)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::ReadOnly => "read-only",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+/// Who sent a request: the id of its token, never the secret, and the token's role.
+#[derive(Clone, Debug)]
+pub struct Caller {
+    pub id: String,
+    pub role: Role,
+    /// Set for a tenant token: the addresses whose status it may read, and all it may do.
+    pub tenants: Option<Vec<Ipv4Addr>>,
+}
+
+struct Token {
+    id: String,
+    role: Role,
+    tenants: Option<Vec<Ipv4Addr>>,
+    secret: String,
+    /// Unix time the token stops working.
+    expires: Option<u64>,
+}
+
+pub struct Tokens {
+    file: Option<PathBuf>,
+    // --http-token, kept across reloads of the file
+    fixed: Option<Token>,
+    tokens: RwLock<Vec<Token>>,
+}
+
+impl Tokens {
+    pub fn new(fixed: Option<String>, file: Option<PathBuf>) -> anyhow::Result<Self> {
+        let tokens = match &file {
+            Some(path) => read(path)?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            file,
+            fixed: fixed.map(|secret| Token {
+                id: "http-token".to_owned(),
+                role: Role::Admin,
+                tenants: None,
+                secret,
+                expires: None,
+            }),
+            tokens: RwLock::new(tokens),
+        })
+    }
+
+    /// Whether any token is configured. Without one the API is open for reading and takes no
+    /// commands.
+    pub fn enabled(&self) -> bool {
+        self.fixed.is_some() || self.file.is_some()
+    }
+
+    /// Reads `--http-tokens` again. On error the tokens read before stay in force.
+    pub fn reload(&self) -> anyhow::Result<Option<usize>> {
+        let Some(path) = &self.file else {
+            return Ok(None);
+        };
+        let tokens = read(path)?;
+        let count = tokens.len();
+        *self.tokens.write().unwrap() = tokens;
+        Ok(Some(count))
+    }
+
+    /// The caller a bearer token belongs to, if it is one of ours and hasn't expired.
+    pub fn check(&self, given: &str, unix_now: u64) -> Option<Caller> {
+        let tokens = self.tokens.read().unwrap();
+        // Every token is compared, so the time taken says nothing about which one matched
+        let mut found = None;
+        for token in self.fixed.iter().chain(tokens.iter()) {
+            if same(given.as_bytes(), token.secret.as_bytes()) && found.is_none() {
+                found = Some(token);
+            }
+        }
+        let token = found?;
+        if token.expires.is_some_and(|at| at <= unix_now) {
+            return None;
+        }
+        Some(Caller {
+            id: token.id.clone(),
+            role: token.role,
+            tenants: token.tenants.clone(),
+        })
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<Token>> {
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("failed to read token file {}", path.display()))?;
+    if let Ok(meta) = fs::metadata(path)
+        && meta.permissions().mode() & 0o077 != 0
+    {
+        warn!(
+            "token file {} is readable by other users, chmod 600 it",
+            path.display()
+        );
+    }
+    let mut tokens: Vec<Token> = Vec::new();
+    for (n, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fail = |what: &str| format!("{}:{}: {what}", path.display(), n + 1);
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (id, role, secret, expires) = match words[..] {
+            [id, role, secret] => (id, role, secret, None),
+            [id, role, secret, expires] => (id, role, secret, Some(expires)),
+            _ => bail!(fail("expected: id role secret [expires]")),
+        };
+        let valid_id = id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid_id {
+            bail!(fail("token ids are letters, digits, - and _"));
+        }
+        let (role, tenants) = match role.strip_prefix("tenant:") {
+            Some(vips) => {
+                let vips = vips
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<Vec<Ipv4Addr>, _>>()
+                    .with_context(|| fail("tenant: takes IPv4 addresses, comma separated"))?;
+                (Role::ReadOnly, Some(vips))
+            }
+            None => match Role::parse(role) {
+                Some(role) => (role, None),
+                None => bail!(fail(
+                    "role must be read-only, operator, admin or tenant:VIP,..."
+                )),
+            },
+        };
+        // Also the `/?token=` query, which is taken as it is
+        if secret.len() < 16 || !secret.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            bail!(fail("secrets are at least 16 letters and digits"));
+        }
+        let expires = match expires {
+            Some(at) => Some(at.parse().with_context(|| fail("invalid expiry"))?),
+            None => None,
+        };
+        if tokens.iter().any(|token| token.id == id) {
+            bail!(fail("duplicate token id"));
+        }
+        if tokens.iter().any(|token| token.secret == secret) {
+            bail!(fail("secret already used by another token"));
+        }
+        tokens.push(Token {
+            id: id.to_owned(),
+            role,
+            tenants,
+            secret: secret.to_owned(),
+            expires,
+        });
+    }
+    Ok(tokens)
+}
+
+// Comparison that takes as long for a wrong first byte as for a wrong last one
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use super::*;
+
+    const READ_ONLY: &str = "3d1f0c9a6b2e47d8a1c4";
+    const OPERATOR: &str = "9a7e5c3b1d0f2e4a6c8b";
+    const ADMIN: &str = "0c4e1b3d7f9a5b8d2f6a";
+
+    // A token file of its own for each test
+    fn token_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("guard-tokens-{}-{name}", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        path
+    }
+
+    fn role(tokens: &Tokens, secret: &str) -> Option<Role> {
+        tokens
+            .check(secret, 1_700_000_000)
+            .map(|caller| caller.role)
+    }
+
+    // Each command with who may send it: read-only, operator, admin
+    const MATRIX: &[(&str, [bool; 3])] = &[
+        ("list", [true, true, true]),
+        ("export --limit 10", [true, true, true]),
+        ("status", [true, true, true]),
+        ("params", [true, true, true]),
+        ("why 10.0.0.1", [true, true, true]),
+        ("offenders 5", [true, true, true]),
+        ("rules --unused", [true, true, true]),
+        ("last-abort", [true, true, true]),
+        ("log-level", [true, true, true]),
+        ("group list", [true, true, true]),
+        ("suggest", [true, true, true]),
+        ("block 10.0.0.1", [false, true, true]),
+        ("block 10.0.0.1 --ttl 60", [false, true, true]),
+        ("unblock 10.0.0.1", [false, true, true]),
+        ("tag 10.0.0.1 50", [false, true, true]),
+        ("tag 10.0.0.1 +5", [false, true, true]),
+        ("untag 10.0.0.1", [false, true, true]),
+        ("reset 10.0.0.1", [false, true, true]),
+        ("profile 5", [false, true, true]),
+        ("verify 10.0.0.1 --dport 443", [false, true, true]),
+        ("flush rate-limit", [false, true, true]),
+        ("flush bans", [false, true, true]),
+        ("flush conntrack", [false, true, true]),
+        ("allow 10.0.0.1", [false, false, true]),
+        ("pause", [false, false, true]),
+        ("resume", [false, false, true]),
+        ("param global-rate 1000", [false, false, true]),
+        ("suggest --apply", [false, false, true]),
+        ("group add office 10.1.0.0/16", [false, false, true]),
+        ("group rate office 50", [false, false, true]),
+        ("chain off", [false, false, true]),
+        ("resize blocklist 200000", [false, false, true]),
+        ("log-level debug", [false, false, true]),
+        ("snapshot save before --limiters", [false, false, true]),
+        ("snapshot load before", [false, false, true]),
+        ("flush blocklist --origin feed", [false, false, true]),
+        ("flush blocklist --all", [false, false, true]),
+        ("flush stats", [false, false, true]),
+    ];
+
+    #[test]
+    fn each_role_sends_what_it_may_and_nothing_more() {
+        let roles = [Role::ReadOnly, Role::Operator, Role::Admin];
+        for &(line, allowed) in MATRIX {
+            let cmd = Command::parse(line).unwrap();
+            for (role, allowed) in roles.into_iter().zip(allowed) {
+                assert_eq!(role.may(&cmd), allowed, "{role} sending {line}");
+            }
+        }
+        let feature = xdp_api_guard_common::feature::NAMES[0];
+        let enforce = Command::parse(&format!("enforce {feature} observe")).unwrap();
+        assert_eq!(Role::needed(&enforce), Role::Admin);
+    }
+
+    #[test]
+    fn removed_tokens_stop_working_on_reload() {
+        let both = format!("noc read-only {READ_ONLY}\noncall operator {OPERATOR}\n");
+        let path = token_file("revoke", &both);
+        let tokens = Tokens::new(Some(ADMIN.to_owned()), Some(path.clone())).unwrap();
+        assert_eq!(role(&tokens, READ_ONLY), Some(Role::ReadOnly));
+        assert_eq!(role(&tokens, OPERATOR), Some(Role::Operator));
+        fs::write(&path, format!("noc read-only {READ_ONLY}\n")).unwrap();
+        // In force until the file is read again
+        assert_eq!(role(&tokens, OPERATOR), Some(Role::Operator));
+        assert_eq!(tokens.reload().unwrap(), Some(1));
+        assert_eq!(role(&tokens, OPERATOR), None);
+        assert_eq!(role(&tokens, READ_ONLY), Some(Role::ReadOnly));
+        // --http-token isn't the file's to revoke
+        assert_eq!(role(&tokens, ADMIN), Some(Role::Admin));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_broken_file_keeps_the_tokens_read_before() {
+        let path = token_file("broken", &format!("noc read-only {READ_ONLY}\n"));
+        let tokens = Tokens::new(None, Some(path.clone())).unwrap();
+        fs::write(&path, "noc superuser 3d1f0c9a6b2e47d8a1c4\n").unwrap();
+        let e = tokens.reload().unwrap_err().to_string();
+        assert!(e.contains(":1: role must be"), "{e}");
+        assert_eq!(role(&tokens, READ_ONLY), Some(Role::ReadOnly));
+        fs::remove_file(&path).unwrap();
+        assert!(tokens.reload().is_err());
+        assert_eq!(role(&tokens, READ_ONLY), Some(Role::ReadOnly));
+    }
+
+    #[test]
+    fn tokens_expire_and_only_their_secret_matches() {
+        let file = format!(
+            "# id role secret expires\n\noncall operator {OPERATOR} 1700000000\n\
+             acme tenant:203.0.113.10 {READ_ONLY}\n"
+        );
+        let path = token_file("expiry", &file);
+        let tokens = Tokens::new(None, Some(path.clone())).unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(tokens.enabled());
+        assert_eq!(tokens.check(OPERATOR, 1_699_999_999).unwrap().id, "oncall");
+        assert!(tokens.check(OPERATOR, 1_700_000_000).is_none());
+        let acme = tokens.check(READ_ONLY, 0).unwrap();
+        assert_eq!(acme.role, Role::ReadOnly);
+        assert_eq!(acme.tenants, Some(vec![Ipv4Addr::new(203, 0, 113, 10)]));
+        assert!(tokens.check(&READ_ONLY[1..], 0).is_none());
+        assert!(tokens.check(ADMIN, 0).is_none());
+        assert!(!Tokens::new(None, None).unwrap().enabled());
+    }
+
+    #[test]
+    fn bad_lines_are_refused_with_their_number() {
+        for (line, why) in [
+            ("noc read-only", "expected: id role secret"),
+            ("no/c read-only 3d1f0c9a6b2e47d8a1c4", "token ids"),
+            ("noc root 3d1f0c9a6b2e47d8a1c4", "role must be"),
+            (
+                "noc tenant:example.com 3d1f0c9a6b2e47d8a1c4",
+                "tenant: takes",
+            ),
+            ("noc read-only short", "at least 16"),
+            ("noc read-only 3d1f0c9a6b2e47d8a1c4 soon", "invalid expiry"),
+        ] {
+            let path = token_file("bad", &format!("# header\n{line}\n"));
+            let e = format!("{:#}", Tokens::new(None, Some(path.clone())).err().unwrap());
+            fs::remove_file(path).unwrap();
+            assert!(e.contains(":2: ") && e.contains(why), "{line}: {e}");
+        }
+        for file in [
+            format!("a read-only {READ_ONLY}\na operator {OPERATOR}\n"),
+            format!("a read-only {READ_ONLY}\nb operator {READ_ONLY}\n"),
+        ] {
+            let path = token_file("twice", &file);
+            let refused = Tokens::new(None, Some(path.clone())).is_err();
+            fs::remove_file(path).unwrap();
+            assert!(refused, "{file}");
+        }
+    }
+}
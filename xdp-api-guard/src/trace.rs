@@ -0,0 +1,406 @@
+//! Spans of control-plane work, exported over OTLP with the `otel` feature and
+//! `--otlp-endpoint`.
+//!
+//! Work that comes in from outside starts a trace with `Span::root` or `Span::server`, the
+//! latter continuing the caller's trace from a W3C `traceparent` header. Below it, only
+//! `Span::child` is used, under whichever span `scope` or `in_scope` made current. A child of
+//! nothing isn't recorded, so the blocklist writes of startup and of the per-second expiry
+//! only show up inside a traced command.
+//!
+//! Without the feature a `Span` is empty and every call on it compiles to nothing. With it
+//! but without an endpoint, starting one costs a check. Finished spans are queued and sent
+//! once a second to the collector's OTLP/HTTP receiver, JSON-encoded, over plain HTTP like
+//! the REST API. A collector that is down or slow loses spans past `MAX_QUEUED`, never
+//! commands.
+
+use std::fmt::Display;
+
+#[cfg(feature = "otel")]
+pub use export::init;
+
+/// Trace and span id of a span, as they go into a `traceparent` header.
+#[cfg(feature = "otel")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+}
+
+#[cfg(feature = "otel")]
+impl SpanContext {
+    /// The parent a `traceparent` header names, `00-TRACE-SPAN-FLAGS`. `None` for a malformed
+    /// header and for one whose caller didn't sample the trace.
+    pub fn from_traceparent(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let (Some("00"), Some(trace), Some(span), Some(flags)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return None;
+        };
+        let context = Self {
+            trace_id: hex(trace)?,
+            span_id: hex(span)?,
+        };
+        let sampled = u8::from_str_radix(flags, 16).ok()? & 1 != 0;
+        let valid = context.trace_id != [0; 16] && context.span_id != [0; 8];
+        (sampled && valid).then_some(context)
+    }
+}
+
+#[cfg(feature = "otel")]
+fn hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != 2 * N {
+        return None;
+    }
+    let mut out = [0; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+/// A span while it runs, sent off when dropped.
+#[must_use]
+pub struct Span {
+    #[cfg(feature = "otel")]
+    live: Option<export::Live>,
+}
+
+#[cfg(not(feature = "otel"))]
+impl Span {
+    #[inline(always)]
+    pub fn root(_name: &'static str) -> Self {
+        Self {}
+    }
+
+    #[inline(always)]
+    pub fn server(_name: &'static str, _traceparent: Option<&str>) -> Self {
+        Self {}
+    }
+
+    #[inline(always)]
+    pub fn child(_name: &'static str) -> Self {
+        Self {}
+    }
+
+    #[inline(always)]
+    pub fn set(&mut self, _key: &'static str, _value: impl Display) {}
+
+    #[inline(always)]
+    pub fn fail(&mut self, _message: impl Display) {}
+
+    #[inline(always)]
+    pub async fn scope<F: Future>(&self, f: F) -> F::Output {
+        f.await
+    }
+
+    #[inline(always)]
+    pub fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+}
+
+#[cfg(feature = "otel")]
+impl Span {
+    /// Starts a trace.
+    pub fn root(name: &'static str) -> Self {
+        Self {
+            live: export::start(name, export::KIND_INTERNAL, None, true),
+        }
+    }
+
+    /// Starts a span for a request, in the caller's trace when `traceparent` names one.
+    pub fn server(name: &'static str, traceparent: Option<&str>) -> Self {
+        let parent = traceparent.and_then(SpanContext::from_traceparent);
+        Self {
+            live: export::start(name, export::KIND_SERVER, parent, true),
+        }
+    }
+
+    /// Starts a span under the current one, if there is one.
+    pub fn child(name: &'static str) -> Self {
+        let parent = export::CURRENT.try_with(|current| *current).ok();
+        Self {
+            live: export::start(name, export::KIND_INTERNAL, parent, false),
+        }
+    }
+
+    pub fn set(&mut self, key: &'static str, value: impl Display) {
+        if let Some(live) = &mut self.live {
+            live.attrs.push((key, value.to_string()));
+        }
+    }
+
+    /// Marks the span failed, with why.
+    pub fn fail(&mut self, message: impl Display) {
+        if let Some(live) = &mut self.live {
+            live.error = Some(message.to_string());
+        }
+    }
+
+    /// Runs `f` with this span current, so `child` spans started in it go under it.
+    pub async fn scope<F: Future>(&self, f: F) -> F::Output {
+        match &self.live {
+            Some(live) => export::CURRENT.scope(live.context, f).await,
+            None => f.await,
+        }
+    }
+
+    /// The same for synchronous code.
+    pub fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.live {
+            Some(live) => export::CURRENT.sync_scope(live.context, f),
+            None => f(),
+        }
+    }
+}
+
+/// Runs `f` in a child span named `name`, failed if `f` fails.
+pub fn within<T, E: Display>(name: &'static str, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let mut span = Span::child(name);
+    let result = f();
+    if let Err(e) = &result {
+        span.fail(e);
+    }
+    result
+}
+
+#[cfg(feature = "otel")]
+mod export {
+    use std::{
+        fmt::Write as _,
+        sync::{
+            Mutex, OnceLock,
+            atomic::{AtomicU64, Ordering},
+        },
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use anyhow::{Context as _, bail};
+    use log::{debug, info, warn};
+    use serde_json::{Value, json};
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::TcpStream,
+    };
+
+    use super::{Span, SpanContext};
+
+    /// Finished spans waiting for the next export, the newest are dropped past it.
+    pub const MAX_QUEUED: usize = 4096;
+
+    // OTLP span kinds
+    pub const KIND_INTERNAL: u8 = 1;
+    pub const KIND_SERVER: u8 = 2;
+
+    tokio::task_local! {
+        pub static CURRENT: SpanContext;
+    }
+
+    static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+    struct Exporter {
+        queue: Mutex<Vec<Finished>>,
+        dropped: AtomicU64,
+    }
+
+    pub struct Live {
+        pub context: SpanContext,
+        parent: Option<[u8; 8]>,
+        name: &'static str,
+        kind: u8,
+        start: SystemTime,
+        pub attrs: Vec<(&'static str, String)>,
+        pub error: Option<String>,
+    }
+
+    struct Finished {
+        live: Live,
+        end: SystemTime,
+    }
+
+    /// Starts exporting to the OTLP/HTTP receiver at `endpoint`, `host:port`, with
+    /// `service.instance.id` set to `instance`.
+    pub fn init(endpoint: String, instance: String) {
+        let exporter = Exporter {
+            queue: Mutex::new(Vec::new()),
+            dropped: AtomicU64::new(0),
+        };
+        if EXPORTER.set(exporter).is_err() {
+            return;
+        }
+        info!("exporting control-plane traces to http://{endpoint}/v1/traces");
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_secs(1));
+            let mut failing = false;
+            loop {
+                tick.tick().await;
+                let Some(exporter) = EXPORTER.get() else {
+                    return;
+                };
+                let spans = std::mem::take(&mut *exporter.queue.lock().unwrap());
+                let dropped = exporter.dropped.swap(0, Ordering::Relaxed);
+                if dropped != 0 {
+                    warn!("otlp: dropped {dropped} spans, the export queue was full");
+                }
+                if spans.is_empty() {
+                    continue;
+                }
+                let body = encode(&spans, &instance).to_string();
+                match post(&endpoint, &body).await {
+                    Ok(()) => failing = false,
+                    // Warn once per outage, a collector that is down would otherwise log
+                    // every second
+                    Err(e) if !failing => {
+                        failing = true;
+                        warn!("otlp: export to {endpoint} failed: {e:#}");
+                    }
+                    Err(e) => debug!("otlp: export to {endpoint} failed: {e:#}"),
+                }
+            }
+        });
+    }
+
+    pub fn start(
+        name: &'static str,
+        kind: u8,
+        parent: Option<SpanContext>,
+        root: bool,
+    ) -> Option<Live> {
+        EXPORTER.get()?;
+        let trace_id = match parent {
+            Some(parent) => parent.trace_id,
+            None if root => random(),
+            None => return None,
+        };
+        Some(Live {
+            context: SpanContext {
+                trace_id,
+                span_id: random(),
+            },
+            parent: parent.map(|parent| parent.span_id),
+            name,
+            kind,
+            start: SystemTime::now(),
+            attrs: Vec::new(),
+            error: None,
+        })
+    }
+
+    impl Drop for Span {
+        fn drop(&mut self) {
+            let (Some(live), Some(exporter)) = (self.live.take(), EXPORTER.get()) else {
+                return;
+            };
+            let mut queue = exporter.queue.lock().unwrap();
+            if queue.len() >= MAX_QUEUED {
+                exporter.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            queue.push(Finished {
+                live,
+                end: SystemTime::now(),
+            });
+        }
+    }
+
+    fn random<const N: usize>() -> [u8; N] {
+        let mut out = [0; N];
+        // Never fails for a few bytes once the pool is initialized
+        unsafe { libc::getrandom(out.as_mut_ptr().cast(), N, 0) };
+        out
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().fold(String::new(), |mut out, b| {
+            let _ = write!(out, "{b:02x}");
+            out
+        })
+    }
+
+    fn nanos(at: SystemTime) -> String {
+        at.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            .to_string()
+    }
+
+    fn attribute(key: &str, value: &str) -> Value {
+        json!({ "key": key, "value": { "stringValue": value } })
+    }
+
+    // The JSON encoding of an ExportTraceServiceRequest
+    fn encode(spans: &[Finished], instance: &str) -> Value {
+        let spans: Vec<Value> = spans
+            .iter()
+            .map(|span| {
+                let live = &span.live;
+                let attributes: Vec<Value> = live
+                    .attrs
+                    .iter()
+                    .map(|(key, value)| attribute(key, value))
+                    .collect();
+                let mut value = json!({
+                    "traceId": hex(&live.context.trace_id),
+                    "spanId": hex(&live.context.span_id),
+                    "name": live.name,
+                    "kind": live.kind,
+                    "startTimeUnixNano": nanos(live.start),
+                    "endTimeUnixNano": nanos(span.end),
+                    "attributes": attributes,
+                    // Unset or error, OK is for the caller to decide
+                    "status": match &live.error {
+                        Some(message) => json!({ "code": 2, "message": message }),
+                        None => json!({}),
+                    },
+                });
+                if let Some(parent) = live.parent {
+                    value["parentSpanId"] = hex(&parent).into();
+                }
+                value
+            })
+            .collect();
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        attribute("service.name", "xdp-api-guard"),
+                        attribute("service.version", env!("CARGO_PKG_VERSION")),
+                        attribute("service.instance.id", instance),
+                    ],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "xdp-api-guard" },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+
+    // One request per connection, like the client crate's transport
+    async fn post(endpoint: &str, body: &str) -> anyhow::Result<()> {
+        let mut stream = tokio::time::timeout(Duration::from_secs(5), TcpStream::connect(endpoint))
+            .await
+            .context("connect timed out")?
+            .with_context(|| format!("failed to connect to {endpoint}"))?;
+        let head = format!(
+            "POST /v1/traces HTTP/1.1\r\nHost: {endpoint}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body.as_bytes()).await?;
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .context("no response within 5s")??;
+        let status = std::str::from_utf8(&response)
+            .ok()
+            .and_then(|head| head.split_whitespace().nth(1))
+            .unwrap_or("none");
+        if !status.starts_with('2') {
+            bail!("collector answered with status {status}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! `--trusted-flow-map`: TCP flows verified by a program in front of the guard, such as an
+//! AF_XDP TLS terminator, skip rate limiting. The blocklist still applies to them.
+//!
+//! The map belongs to the other program and is pinned wherever it keeps it. The object
+//! declares `TRUSTED_FLOWS` as pinned by name, so the loader reuses a map pinned under that
+//! name in `PIN_DIR` instead of creating one. The external map is pinned there a second time
+//! before loading and unpinned right after; without one the loader pins a fresh empty map,
+//! which is unpinned the same way.
+
+use std::{fs, io, mem, path::Path};
+
+use anyhow::{Context as _, bail};
+use aya::maps::{MapData, MapType};
+use log::{info, warn};
+use xdp_api_guard_common::FlowKey;
+
+/// Directory the loader looks for pinned maps in. Nothing stays there after loading.
+pub const PIN_DIR: &str = "/sys/fs/bpf/xdp-api-guard";
+
+const MAP_NAME: &str = "TRUSTED_FLOWS";
+
+/// Checks the map pinned at `path` against the key schema of `FlowKey` and pins it for the
+/// loader. Returns whether there is a map to use; a missing one is logged, not an error.
+pub fn prepare(path: Option<&Path>) -> anyhow::Result<bool> {
+    fs::create_dir_all(PIN_DIR).with_context(|| {
+        format!("failed to create {PIN_DIR}, is the BPF filesystem mounted on /sys/fs/bpf?")
+    })?;
+    // Left over from a daemon that died while loading, would be reused otherwise
+    unpin();
+    let Some(path) = path else {
+        return Ok(false);
+    };
+    if !path.exists() {
+        info!(
+            "no trusted flow map at {}, every flow is rate limited",
+            path.display()
+        );
+        return Ok(false);
+    }
+    let map = MapData::from_pin(path)
+        .with_context(|| format!("failed to open trusted flow map {}", path.display()))?;
+    let map_info = map.info()?;
+    let map_type = map_info.map_type()?;
+    if !matches!(map_type, MapType::Hash | MapType::LruHash) {
+        bail!(
+            "trusted flow map {} is a {map_type:?} map, expected a hash or LRU hash",
+            path.display()
+        );
+    }
+    // Values are never read, any size will do
+    if map_info.key_size() as usize != mem::size_of::<FlowKey>() {
+        bail!(
+            "trusted flow map {} has {}-byte keys, expected {} (src, dst, sport, dport)",
+            path.display(),
+            map_info.key_size(),
+            mem::size_of::<FlowKey>()
+        );
+    }
+    map.pin(Path::new(PIN_DIR).join(MAP_NAME))?;
+    info!(
+        "flows in {} ({} entries max) bypass rate limiting",
+        path.display(),
+        map_info.max_entries()
+    );
+    Ok(true)
+}
+
+/// Removes the loader's pin. The program keeps the map it was loaded with.
+pub fn unpin() {
+    match fs::remove_file(Path::new(PIN_DIR).join(MAP_NAME)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => warn!("failed to unpin {PIN_DIR}/{MAP_NAME}: {e}"),
+    }
+}
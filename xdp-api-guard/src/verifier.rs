@@ -0,0 +1,138 @@
+//! How close the program comes to the verifier's limits, so an old kernel refusing it after
+//! the next feature doesn't come as a surprise.
+//!
+//! After a successful load the kernel reports how many instructions the verifier processed
+//! (5.16 and later); the log of a successful load is not kept by the loader. When a load
+//! fails, the verifier log is there and its statistics line is parsed instead.
+
+use std::fmt;
+
+use aya::programs::{ProgramError, Xdp};
+use log::{info, warn};
+
+/// Instructions the verifier processes at most per program, since Linux 5.2.
+pub const COMPLEXITY_LIMIT: u64 = 1_000_000;
+
+/// Program size limit before Linux 5.2 (and for unprivileged loads ever since).
+pub const OLD_INSN_LIMIT: u64 = 4096;
+
+// Lines of a failed load's log worth showing; the cause is at the end
+const LOG_TAIL: usize = 30;
+
+/// What is known about one verification.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// Instructions processed by the verifier.
+    pub processed: Option<u64>,
+    /// Instructions in the program.
+    pub insns: Option<u64>,
+    /// Most verifier states kept at once, from a log only.
+    pub peak_states: Option<u64>,
+}
+
+impl Stats {
+    /// What the kernel says about a loaded program.
+    pub fn of(program: &Xdp) -> Self {
+        let Ok(info) = program.info() else {
+            return Self::default();
+        };
+        Self {
+            processed: info.verified_instruction_count().map(u64::from),
+            // Eight bytes per instruction
+            insns: info.size_translated().map(|bytes| u64::from(bytes) / 8),
+            peak_states: None,
+        }
+    }
+
+    /// The statistics line of a verifier log, as in
+    /// `processed 1234 insns (limit 1000000) max_states_per_insn 4 total_states 99 peak_states 98`.
+    pub fn parse(log: &str) -> Self {
+        let mut stats = Self::default();
+        for line in log.lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            for pair in words.windows(2) {
+                let Ok(value) = pair[1].parse() else {
+                    continue;
+                };
+                match pair[0] {
+                    "processed" => stats.processed = Some(value),
+                    "peak_states" => stats.peak_states = Some(value),
+                    _ => {}
+                }
+            }
+        }
+        stats
+    }
+
+    /// Warnings for every limit used above `percent`.
+    pub fn warnings(&self, percent: u64) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let Some(processed) = self.processed
+            && processed * 100 / COMPLEXITY_LIMIT >= percent
+        {
+            warnings.push(format!(
+                "the verifier processed {processed} instructions, {}% of its limit of \
+                 {COMPLEXITY_LIMIT}",
+                processed * 100 / COMPLEXITY_LIMIT
+            ));
+        }
+        if let Some(insns) = self.insns
+            && insns * 100 / OLD_INSN_LIMIT >= percent
+        {
+            warnings.push(format!(
+                "the program has {insns} instructions, kernels before 5.2 refuse more than \
+                 {OLD_INSN_LIMIT}"
+            ));
+        }
+        warnings
+    }
+
+    /// Logs the numbers, and warns above `percent` of a limit.
+    pub fn log(&self, percent: u64) {
+        info!("verifier: {self}");
+        for warning in self.warnings(percent) {
+            warn!("verifier: {warning}");
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.processed {
+            Some(n) => write!(f, "{n} instructions processed (limit {COMPLEXITY_LIMIT})")?,
+            None => f.write_str("processed instructions unknown (kernel before 5.16)")?,
+        }
+        if let Some(insns) = self.insns {
+            write!(f, ", program of {insns} instructions")?;
+        }
+        if let Some(peak) = self.peak_states {
+            write!(f, ", peak states {peak}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The error for a failed load: the end of the verifier log, its numbers and what can be
+/// done about it.
+pub fn explain(e: ProgramError) -> anyhow::Error {
+    let ProgramError::LoadError {
+        io_error,
+        verifier_log,
+    } = &e
+    else {
+        return anyhow::Error::new(e).context("failed to load the XDP program");
+    };
+    let log = verifier_log.to_string();
+    let lines: Vec<&str> = log.lines().collect();
+    let tail = lines[lines.len().saturating_sub(LOG_TAIL)..].join("\n");
+    let stats = Stats::parse(&log);
+    anyhow::anyhow!(
+        "the kernel refused the XDP program: {io_error}\n\
+         --- end of the verifier log ---\n{tail}\n---\n\
+         verifier: {stats}\n\
+         The verifier checks every path of the program whatever flags are set, so options \
+         can't make it smaller. The program needs Linux 5.2 or later for its size; on an older \
+         kernel, upgrade or run an older release of the guard. --check-verifier prints the \
+         full log."
+    )
+}
@@ -0,0 +1,490 @@
+//! `guardctl verify`: what the loaded program really does with a packet from an address,
+//! compared with what the daemon's copy of the blocklist says it should do.
+//!
+//! An entry can be "in the list" and never match, e.g. when a tool wrote it under a key in
+//! the wrong byte order. So a packet from the address is built here and run through the live
+//! program with `BPF_PROG_TEST_RUN`, against the real maps. The way it took through the
+//! program comes from the profile counters (see `profile.rs`) of the CPU it ran on, read
+//! before and after; packets of real traffic that CPU handled meanwhile would blur it, so
+//! those runs are repeated up to `ATTEMPTS` times. A verdict other than the expected one is
+//! a mismatch, and so is a `BLOCKLIST` entry that differs from the daemon's copy.
+//!
+//! The packet is counted like any other one: it shows in the stats, charges the limiter of a
+//! source that isn't blocked, and a TCP SYN that passes opens a conntrack flow.
+
+use std::{
+    fmt::{self, Write as _},
+    io,
+    net::Ipv4Addr,
+    os::fd::{AsFd as _, AsRawFd as _, BorrowedFd},
+    thread,
+};
+
+use anyhow::{Context as _, anyhow, bail};
+use aya::maps::{MapData, PerCpuArray};
+use xdp_api_guard_common::{
+    ACTION_ALLOW, ACTION_DROP, BlockEntry, Config, Origin, config_flags, path,
+};
+
+use crate::{control::ControlState, profile::Sampling};
+
+/// Most entries `verify --sample-blocklist` takes at once.
+pub const MAX_SAMPLE: usize = 1000;
+
+/// Runs per probe when other packets get into the trace.
+pub const ATTEMPTS: usize = 3;
+
+const BPF_PROG_TEST_RUN: libc::c_long = 10;
+
+// The probes go to a documentation address nothing matches on
+const DST: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const SPORT: u16 = 40000;
+
+const XDP_ABORTED: u32 = 0;
+const XDP_DROP: u32 = 1;
+const XDP_PASS: u32 = 2;
+const VERDICTS: &[&str] = &["aborted", "drop", "pass", "tx", "redirect"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Proto {
+    Tcp,
+    Udp,
+}
+
+/// The packet to verify: a TCP SYN or a UDP datagram from `src` to `dport`.
+#[derive(Clone, Copy, Debug)]
+pub struct Probe {
+    pub src: Ipv4Addr,
+    pub proto: Proto,
+    pub dport: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum VerifyTarget {
+    Probe(Probe),
+    /// This many random blocklist entries that should drop, each probed with a TCP SYN.
+    Sample(usize),
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let proto = match self.proto {
+            Proto::Tcp => "tcp",
+            Proto::Udp => "udp",
+        };
+        write!(f, "{} {proto}/{}", self.src, self.dport)
+    }
+}
+
+/// `verify IP [--dport N] [--proto tcp|udp]` or `verify --sample-blocklist N`.
+pub fn parse(words: &[&str]) -> anyhow::Result<VerifyTarget> {
+    if let ["--sample-blocklist", n] = words {
+        let n: usize = n.parse().context("invalid count")?;
+        if n == 0 || n > MAX_SAMPLE {
+            bail!("--sample-blocklist takes 1 to {MAX_SAMPLE} entries");
+        }
+        return Ok(VerifyTarget::Sample(n));
+    }
+    let (ip, mut rest) = words
+        .split_first()
+        .ok_or_else(|| anyhow!("missing address"))?;
+    let mut probe = Probe {
+        src: ip
+            .parse()
+            .with_context(|| format!("invalid address {ip:?}"))?,
+        proto: Proto::Tcp,
+        dport: 80,
+    };
+    loop {
+        rest = match rest {
+            ["--dport", port, rest @ ..] => {
+                probe.dport = port.parse().context("invalid --dport")?;
+                rest
+            }
+            ["--proto", "tcp", rest @ ..] => {
+                probe.proto = Proto::Tcp;
+                rest
+            }
+            ["--proto", "udp", rest @ ..] => {
+                probe.proto = Proto::Udp;
+                rest
+            }
+            ["--proto", other, ..] => bail!("unknown protocol {other:?}, expected tcp or udp"),
+            [] => return Ok(VerifyTarget::Probe(probe)),
+            rest => bail!("unexpected arguments {rest:?}"),
+        };
+    }
+}
+
+pub fn run(state: &ControlState, target: VerifyTarget) -> anyhow::Result<String> {
+    let program = state.program.as_ref().ok_or_else(|| {
+        anyhow!("verify needs the daemon's own program, not one of --external-maps")
+    })?;
+    // A running profile has the counters on already, and lends them to us
+    let _sampling = Sampling::start(state).ok();
+    match target {
+        VerifyTarget::Probe(probe) => {
+            let outcome = check(state, program.as_fd(), &probe)?;
+            let mut out = match outcome.mismatches.first() {
+                Some(first) => format!("err MISMATCH {first}\n{probe}"),
+                None => format!("ok {probe}"),
+            };
+            outcome.render(&mut out);
+            Ok(out)
+        }
+        VerifyTarget::Sample(n) => sample(state, program.as_fd(), n),
+    }
+}
+
+fn sample(state: &ControlState, program: BorrowedFd<'_>, n: usize) -> anyhow::Result<String> {
+    let mut candidates: Vec<Ipv4Addr> = {
+        let blocklist = state.blocklist.lock().unwrap();
+        blocklist
+            .entries()
+            .filter(|(ip, _)| {
+                blocklist
+                    .effective(*ip)
+                    .is_some_and(|origin| origin.action() == ACTION_DROP)
+            })
+            .map(|(ip, _)| ip)
+            .collect()
+    };
+    // Partial Fisher-Yates, the first `n` are the sample
+    let n = n.min(candidates.len());
+    for i in 0..n {
+        let j = i + (random() % (candidates.len() - i) as u64) as usize;
+        candidates.swap(i, j);
+    }
+    candidates.truncate(n);
+    candidates.sort();
+
+    let mut failed = Vec::new();
+    let mut skipped = 0;
+    for src in candidates {
+        let probe = Probe {
+            src,
+            proto: Proto::Tcp,
+            dport: 80,
+        };
+        let outcome = check(state, program, &probe)?;
+        if outcome.expected.is_none() {
+            // Unblocked since it was sampled
+            skipped += 1;
+        } else if !outcome.mismatches.is_empty() {
+            failed.push((probe, outcome));
+        }
+    }
+    let checked = n - skipped;
+    let mut out = match failed.len() {
+        0 => format!("ok {checked} sampled blocklist entries, all match"),
+        bad => format!("err MISMATCH {bad} of {checked} sampled blocklist entries"),
+    };
+    if skipped != 0 {
+        let _ = write!(out, ", {skipped} gone meanwhile");
+    }
+    for (probe, outcome) in &failed {
+        let _ = write!(out, "\n{probe}");
+        outcome.render(&mut out);
+    }
+    Ok(out)
+}
+
+struct Outcome {
+    verdict: u32,
+    /// The deciding origin and the verdict it stands for, `None` when nothing in the
+    /// blocklist decides and the limits do.
+    expected: Option<(Origin, u32)>,
+    kernel: Option<BlockEntry>,
+    /// Paths the probe took, `None` if no run was free of other packets.
+    trace: Option<Vec<&'static str>>,
+    mismatches: Vec<String>,
+}
+
+impl Outcome {
+    fn render(&self, out: &mut String) {
+        let _ = write!(out, "\nverdict     {}", verdict_name(self.verdict));
+        match self.expected {
+            Some((origin, verdict)) => {
+                let _ = write!(
+                    out,
+                    "\nexpected    {} ({})",
+                    verdict_name(verdict),
+                    origin.name()
+                );
+            }
+            None => out.push_str("\nexpected    no blocklist decision, up to the limits"),
+        }
+        match &self.kernel {
+            Some(entry) => {
+                let origin = Origin::from_u8(entry.origin).map_or("unknown", Origin::name);
+                let action = match entry.action {
+                    ACTION_ALLOW => "allow",
+                    ACTION_DROP => "drop",
+                    _ => "unknown",
+                };
+                let _ = write!(out, "\nmap entry   {origin}, action {action}");
+            }
+            None => out.push_str("\nmap entry   none"),
+        }
+        match &self.trace {
+            Some(paths) => {
+                let _ = write!(out, "\ntrace       {}", paths.join(", "));
+            }
+            None => out.push_str("\ntrace       unavailable, the CPU was busy with other packets"),
+        }
+        for mismatch in &self.mismatches {
+            let _ = write!(out, "\nMISMATCH    {mismatch}");
+        }
+    }
+}
+
+fn check(state: &ControlState, program: BorrowedFd<'_>, probe: &Probe) -> anyhow::Result<Outcome> {
+    let cfg = state.config.lock().unwrap().get();
+    let key = u32::from(probe.src);
+    let (expected, entry, kernel, swapped) = {
+        let blocklist = state.blocklist.lock().unwrap();
+        let expected = blocklist
+            .effective(probe.src)
+            .map(|origin| (origin, expected_verdict(origin, &cfg)));
+        let entry = blocklist.get(probe.src).map(|entry| entry.origin);
+        let kernel = blocklist.kernel_entry(key)?;
+        let swapped = match key.swap_bytes() {
+            other if other == key => None,
+            other if blocklist.get(Ipv4Addr::from(other)).is_some() => None,
+            other => blocklist.kernel_entry(other)?,
+        };
+        (expected, entry, kernel, swapped)
+    };
+
+    let frame = packet(probe);
+    let (verdict, trace) = traced_run(&state.paths.lock().unwrap(), program, &frame)?;
+
+    let mut mismatches = Vec::new();
+    if let Some((origin, want)) = expected
+        && verdict != want
+    {
+        mismatches.push(format!(
+            "{} entry should {}, the program returned {}",
+            origin.name(),
+            verdict_name(want),
+            verdict_name(verdict)
+        ));
+    }
+    if verdict == XDP_ABORTED {
+        mismatches.push("the program aborted, see `guardctl last-abort`".to_owned());
+    }
+    match (entry, kernel) {
+        (Some(origin), None) => mismatches.push(format!(
+            "the daemon holds a {} entry that BLOCKLIST doesn't have",
+            origin.name()
+        )),
+        (None, Some(_)) => {
+            mismatches.push("BLOCKLIST holds an entry the daemon doesn't know of".to_owned())
+        }
+        (Some(origin), Some(kernel))
+            if kernel.origin != origin as u8 || kernel.action != origin.action() =>
+        {
+            mismatches.push(format!(
+                "BLOCKLIST holds another entry than the daemon's {}",
+                origin.name()
+            ))
+        }
+        _ => {}
+    }
+    if swapped.is_some() {
+        mismatches.push(format!(
+            "BLOCKLIST has an entry under the byte-swapped key {}, written in network order",
+            Ipv4Addr::from(key.swap_bytes())
+        ));
+    }
+    Ok(Outcome {
+        verdict,
+        expected,
+        kernel,
+        trace,
+        mismatches,
+    })
+}
+
+// The verdict of an entry once the paused and observing switches are taken into account
+fn expected_verdict(origin: Origin, cfg: &Config) -> u32 {
+    if origin.action() == ACTION_ALLOW {
+        return XDP_PASS;
+    }
+    let feature = BlockEntry {
+        action: origin.action(),
+        origin: origin as u8,
+        feed: 0,
+    }
+    .feature();
+    if cfg.has(config_flags::PAUSED) || cfg.observes(feature) {
+        XDP_PASS
+    } else {
+        XDP_DROP
+    }
+}
+
+fn verdict_name(verdict: u32) -> &'static str {
+    VERDICTS.get(verdict as usize).copied().unwrap_or("unknown")
+}
+
+/// Runs `frame` through the program on a thread of its own, pinned to the CPU it started
+/// on so the counters of that CPU hold its path.
+fn traced_run(
+    paths: &PerCpuArray<MapData, u64>,
+    program: BorrowedFd<'_>,
+    frame: &[u8],
+) -> anyhow::Result<(u32, Option<Vec<&'static str>>)> {
+    thread::scope(|scope| {
+        scope
+            .spawn(|| -> anyhow::Result<_> {
+                let cpu = pin()?;
+                let mut verdict = XDP_ABORTED;
+                for _ in 0..ATTEMPTS {
+                    let before = read(paths, cpu)?;
+                    verdict = test_run(program, frame)?;
+                    let after = read(paths, cpu)?;
+                    let diff: Vec<u64> = after
+                        .iter()
+                        .zip(&before)
+                        .map(|(a, b)| a.saturating_sub(*b))
+                        .collect();
+                    // Exactly our packet, otherwise real traffic got in between
+                    if diff[path::PACKET as usize] == 1 {
+                        let trace = path::NAMES
+                            .iter()
+                            .zip(&diff)
+                            .filter(|(_, count)| **count != 0)
+                            .map(|(name, _)| *name)
+                            .collect();
+                        return Ok((verdict, Some(trace)));
+                    }
+                }
+                Ok((verdict, None))
+            })
+            .join()
+            .map_err(|_| anyhow!("verify thread panicked"))?
+    })
+}
+
+// Pins the calling thread to the CPU it runs on, and returns that CPU
+fn pin() -> anyhow::Result<usize> {
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu < 0 {
+        return Err(io::Error::last_os_error()).context("sched_getcpu failed");
+    }
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu as usize, &mut set) };
+    let ret = unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error()).context("sched_setaffinity failed");
+    }
+    Ok(cpu as usize)
+}
+
+fn read(map: &PerCpuArray<MapData, u64>, cpu: usize) -> anyhow::Result<Vec<u64>> {
+    (0..path::LEN)
+        .map(|index| -> anyhow::Result<u64> { Ok(map.get(&index, 0)?[cpu]) })
+        .collect()
+}
+
+// `union bpf_attr` as BPF_PROG_TEST_RUN reads it
+#[repr(C)]
+#[derive(Default)]
+struct TestRunAttr {
+    prog_fd: u32,
+    retval: u32,
+    data_size_in: u32,
+    data_size_out: u32,
+    data_in: u64,
+    data_out: u64,
+    repeat: u32,
+    duration: u32,
+    ctx_size_in: u32,
+    ctx_size_out: u32,
+    ctx_in: u64,
+    ctx_out: u64,
+    flags: u32,
+    cpu: u32,
+    batch_size: u32,
+}
+
+pub(crate) fn test_run(program: BorrowedFd<'_>, frame: &[u8]) -> anyhow::Result<u32> {
+    let mut attr = TestRunAttr {
+        prog_fd: program.as_raw_fd() as u32,
+        data_size_in: frame.len() as u32,
+        data_in: frame.as_ptr() as u64,
+        repeat: 1,
+        ..TestRunAttr::default()
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_PROG_TEST_RUN,
+            &mut attr as *mut TestRunAttr,
+            size_of::<TestRunAttr>() as u32,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error()).context("BPF_PROG_TEST_RUN failed");
+    }
+    Ok(attr.retval)
+}
+
+/// An Ethernet frame with the probe: a SYN with an MSS of 1460, or a 4-byte datagram.
+fn packet(probe: &Probe) -> Vec<u8> {
+    let (proto, l4) = match probe.proto {
+        Proto::Tcp => {
+            let mut tcp = Vec::with_capacity(24);
+            tcp.extend_from_slice(&SPORT.to_be_bytes());
+            tcp.extend_from_slice(&probe.dport.to_be_bytes());
+            tcp.extend_from_slice(&1u32.to_be_bytes());
+            tcp.extend_from_slice(&0u32.to_be_bytes());
+            // Six words of header, SYN, window
+            tcp.extend_from_slice(&[6 << 4, 0x02]);
+            tcp.extend_from_slice(&64240u16.to_be_bytes());
+            // Checksum and urgent pointer, XDP doesn't look at either
+            tcp.extend_from_slice(&[0; 4]);
+            tcp.extend_from_slice(&[2, 4]);
+            tcp.extend_from_slice(&1460u16.to_be_bytes());
+            (6, tcp)
+        }
+        Proto::Udp => {
+            let mut udp = Vec::with_capacity(12);
+            udp.extend_from_slice(&SPORT.to_be_bytes());
+            udp.extend_from_slice(&probe.dport.to_be_bytes());
+            udp.extend_from_slice(&12u16.to_be_bytes());
+            udp.extend_from_slice(&[0; 2]);
+            udp.extend_from_slice(b"ping");
+            (17, udp)
+        }
+    };
+    let mut ip = [0u8; 20];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&(20 + l4.len() as u16).to_be_bytes());
+    ip[8] = 64;
+    ip[9] = proto;
+    ip[12..16].copy_from_slice(&probe.src.octets());
+    ip[16..20].copy_from_slice(&DST.octets());
+    let mut sum: u32 = ip
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    let checksum = !(sum as u16);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    // Locally administered addresses, then IPv4
+    let mut frame = vec![0x02, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0x02, 0x08, 0x00];
+    frame.extend_from_slice(&ip);
+    frame.extend_from_slice(&l4);
+    frame
+}
+
+fn random() -> u64 {
+    let mut out = [0u8; 8];
+    unsafe { libc::getrandom(out.as_mut_ptr().cast(), out.len(), 0) };
+    u64::from_ne_bytes(out)
+}
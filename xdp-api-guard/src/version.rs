@@ -0,0 +1,205 @@
+//! Build identity of the running binary and of the eBPF object loaded in the kernel.
+
+use std::fmt;
+
+use aya::maps::{Array, MapData};
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+use xdp_api_guard_common::{VersionInfo, build_id};
+
+/// The id of this build, the one the eBPF object built into it carries.
+pub const BUILD_ID: [u8; 16] = build_id(option_env!("XDP_API_GUARD_BUILD_ID"));
+
+#[derive(Clone, Copy, Debug)]
+pub struct Versions {
+    pub binary: VersionInfo,
+    /// What `VERSION_INFO` says about the loaded object.
+    pub loaded: VersionInfo,
+}
+
+impl Versions {
+    /// Stamps a fresh `VERSION_INFO` with this build and reads back what the map holds. A map
+    /// that already carries a version (one kept from an earlier load) is left alone, so
+    /// [`mismatch`](Self::mismatch) can tell. `embedded` is the `BUILD_ID` the loaded object
+    /// carries, which goes for its id whatever the stamp says; without it the id is zeros.
+    pub fn install(
+        map: &mut Array<MapData, VersionInfo>,
+        object: &[u8],
+        embedded: Option<[u8; 16]>,
+    ) -> anyhow::Result<Self> {
+        let binary = VersionInfo::current(Sha256::digest(object).into(), BUILD_ID);
+        let mut loaded = map.get(&0, 0)?;
+        let embedded = embedded.unwrap_or_default();
+        if loaded.schema == 0 {
+            loaded = VersionInfo {
+                build_id: embedded,
+                ..binary
+            };
+            map.set(0, loaded, 0)?;
+        }
+        loaded.build_id = embedded;
+        Ok(Self { binary, loaded })
+    }
+
+    pub fn mismatch(&self) -> Option<String> {
+        let (binary, loaded) = (&self.binary, &self.loaded);
+        if binary.schema != loaded.schema {
+            Some(format!(
+                "loaded eBPF object uses map schema {}, this binary expects {}",
+                loaded.schema, binary.schema
+            ))
+        } else if loaded.build_id == [0; 16] {
+            Some(format!(
+                "loaded eBPF object carries no build id, it may not be the one built into this \
+                 binary ({})",
+                Build(binary)
+            ))
+        } else if binary.build_id != loaded.build_id
+            || binary.git_hash != loaded.git_hash
+            || binary.object_sha256 != loaded.object_sha256
+        {
+            Some(format!(
+                "loaded eBPF object ({}) is not the one built into this binary ({})",
+                Build(loaded),
+                Build(binary)
+            ))
+        } else {
+            None
+        }
+    }
+
+    pub fn report(&self) -> VersionsReport {
+        VersionsReport {
+            binary: BuildReport::new(&self.binary),
+            loaded: BuildReport::new(&self.loaded),
+            mismatch: self.mismatch(),
+        }
+    }
+}
+
+/// One line summary: `git 1a2b3c4d5e6f, built 2026-10-14 09:30:00 UTC, schema 1, id 5e0c..,
+/// object 3f2a..`
+pub struct Build<'a>(pub &'a VersionInfo);
+
+impl fmt::Display for Build<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sha = hex(&self.0.object_sha256);
+        write!(
+            f,
+            "git {}, built {}, schema {}, id {}, object {}",
+            git_hash(self.0),
+            utc(self.0.build_time),
+            self.0.schema,
+            &hex(&self.0.build_id)[..8],
+            &sha[..16]
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BuildReport {
+    pub git_hash: String,
+    pub build_time: u64,
+    pub schema: u32,
+    pub build_id: String,
+    pub object_sha256: String,
+}
+
+impl BuildReport {
+    fn new(info: &VersionInfo) -> Self {
+        Self {
+            git_hash: git_hash(info),
+            build_time: info.build_time,
+            schema: info.schema,
+            build_id: hex(&info.build_id),
+            object_sha256: hex(&info.object_sha256),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionsReport {
+    pub binary: BuildReport,
+    pub loaded: BuildReport,
+    pub mismatch: Option<String>,
+}
+
+fn git_hash(info: &VersionInfo) -> String {
+    let len = info.git_hash.iter().position(|b| *b == 0).unwrap_or(16);
+    String::from_utf8_lossy(&info.git_hash[..len]).into_owned()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// `2026-10-14 09:30:00 UTC`
+pub fn utc(ts: u64) -> String {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let time = ts as libc::time_t;
+    if unsafe { libc::gmtime_r(&time, &mut tm) }.is_null() {
+        return format!("@{ts}");
+    }
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(loaded: impl FnOnce(&mut VersionInfo)) -> Versions {
+        let binary = VersionInfo::current([7; 32], [1; 16]);
+        let mut versions = Versions {
+            binary,
+            loaded: binary,
+        };
+        loaded(&mut versions.loaded);
+        versions
+    }
+
+    #[test]
+    fn the_same_build_matches() {
+        assert_eq!(versions(|_| ()).mismatch(), None);
+    }
+
+    #[test]
+    fn an_object_of_another_build_mismatches() {
+        let mismatch = versions(|loaded| loaded.build_id = [2; 16])
+            .mismatch()
+            .unwrap();
+        assert!(mismatch.contains("is not the one built into"), "{mismatch}");
+        assert!(mismatch.contains("id 02020202"), "{mismatch}");
+        assert!(
+            versions(|loaded| loaded.git_hash[0] ^= 1)
+                .mismatch()
+                .is_some()
+        );
+        assert!(
+            versions(|loaded| loaded.object_sha256[0] = 0)
+                .mismatch()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn an_object_without_an_id_mismatches() {
+        let mismatch = versions(|loaded| loaded.build_id = [0; 16])
+            .mismatch()
+            .unwrap();
+        assert!(mismatch.contains("carries no build id"), "{mismatch}");
+    }
+
+    #[test]
+    fn other_schemas_are_named() {
+        let mismatch = versions(|loaded| loaded.schema += 1).mismatch().unwrap();
+        assert!(mismatch.contains("map schema"), "{mismatch}");
+    }
+}
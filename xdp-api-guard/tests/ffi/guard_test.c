@@ -0,0 +1,102 @@
+/*
+ * Drives the C ABI of the `ffi` feature against a veth pair, see run.sh. Runs in the
+ * namespace of one end, gt-guard, with the guard on veth-guard; the peer, 10.99.0.2, pings
+ * it from gt-peer.
+ */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+#include "xdp_api_guard.h"
+
+#define PEER "10.99.0.2"
+#define TOKENS "/tmp/gt-guard.tokens"
+#define PING "ip netns exec gt-peer ping -c 1 -W 1 10.99.0.1 >/dev/null 2>&1"
+
+static int failures;
+
+static void check(int ok, const char *what) {
+    const char *why = guard_last_error_message();
+    if (!ok) {
+        fprintf(stderr, "FAIL %s%s%s\n", what, why ? ": " : "", why ? why : "");
+        failures++;
+    } else {
+        fprintf(stderr, "ok   %s\n", what);
+    }
+}
+
+static int reachable(void) {
+    return system(PING) == 0;
+}
+
+static void write_file(const char *path, const char *contents) {
+    FILE *f = fopen(path, "w");
+    if (f) {
+        fputs(contents, f);
+        fclose(f);
+    }
+}
+
+int main(void) {
+    check(guard_new("{\"iface\": \"lo\"}") == NULL, "iface is refused in the config");
+    check(guard_new("{\"check_verifier\": true}") == NULL, "check_verifier is refused");
+    check(guard_new("[1, 2]") == NULL, "a config that isn't an object is refused");
+    check(guard_attach(NULL, "veth-guard", GUARD_MODE_SKB) == GUARD_ERR_ARGUMENT,
+          "a NULL guard is an argument error");
+
+    Guard *g = guard_new("{\"rate\": 1000, \"json\": true}");
+    check(g != NULL, "guard_new");
+    if (!g)
+        return 1;
+    check(guard_block(g, PEER, 0) == GUARD_ERR_STATE, "block before attach");
+    check(guard_attach(g, "veth-guard", 42) == GUARD_ERR_ARGUMENT, "unknown mode");
+    check(guard_attach(g, "veth-guard", GUARD_MODE_SKB) == GUARD_OK, "guard_attach");
+    check(guard_attach(g, "veth-guard", GUARD_MODE_SKB) == GUARD_ERR_STATE, "attach twice");
+    /* The default socket is the daemon's, an embedder that didn't ask for one gets none */
+    check(access("/run/xdp-api-guard.sock", F_OK) != 0, "no control socket by default");
+
+    check(reachable(), "the peer passes");
+    check(guard_block(g, "not an address", 0) == GUARD_ERR_ARGUMENT, "block a non-address");
+    check(guard_block(g, PEER, 600) == GUARD_OK, "guard_block");
+    check(!reachable(), "the blocked peer is dropped");
+
+    char *stats = guard_stats_json(g);
+    check(stats != NULL, "guard_stats_json");
+    if (stats) {
+        check(strstr(stats, "\"drop_rate\"") != NULL, "the stats have drop_rate");
+        guard_string_free(stats);
+    }
+
+    check(guard_unblock(g, PEER) == GUARD_OK, "guard_unblock");
+    check(reachable(), "the unblocked peer passes");
+    check(guard_detach(g) == GUARD_OK, "guard_detach");
+    check(guard_detach(g) == GUARD_ERR_STATE, "detach twice");
+
+    /* Attached again, and freed without a detach */
+    check(guard_attach(g, "veth-guard", GUARD_MODE_SKB) == GUARD_OK, "attach again");
+    guard_free(g);
+
+    Guard *s = guard_new("{\"control_socket\": \"/run/gt-guard.sock\"}");
+    check(s && guard_attach(s, "veth-guard", GUARD_MODE_SKB) == GUARD_OK,
+          "attach with a control socket");
+    usleep(200 * 1000);
+    check(access("/run/gt-guard.sock", F_OK) == 0, "the control socket asked for");
+    guard_free(s);
+
+    /* The embedder's SIGHUP: the token file is read again, a broken one keeps the old tokens */
+    write_file(TOKENS, "noc read-only 3d1f0c9a6b2e47d8a1c4\n");
+    Guard *t = guard_new("{\"http_tokens\": \"" TOKENS "\"}");
+    check(t && guard_reload(t) == GUARD_ERR_STATE, "reload before attach");
+    check(t && guard_attach(t, "veth-guard", GUARD_MODE_SKB) == GUARD_OK,
+          "attach with a token file");
+    write_file(TOKENS, "");
+    check(guard_reload(t) == GUARD_OK, "guard_reload");
+    write_file(TOKENS, "noc superuser 3d1f0c9a6b2e47d8a1c4\n");
+    check(guard_reload(t) == GUARD_ERR_FAILED, "reload a broken token file");
+    guard_free(t);
+    unlink(TOKENS);
+
+    fprintf(stderr, "%d failures\n", failures);
+    return failures != 0;
+}
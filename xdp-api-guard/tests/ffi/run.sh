@@ -0,0 +1,34 @@
+#!/bin/sh
+# Builds the library with the `ffi` feature, compiles guard_test.c against it and runs it on a
+# veth pair between two network namespaces of its own. Needs root. Anything the guard writes
+# to stdout fails the test, that is the embedding program's.
+set -eu
+
+cd "$(dirname "$0")/../../.."
+cargo rustc -p xdp-api-guard --lib --release --features ffi --crate-type cdylib
+header=$(ls -t target/release/build/xdp-api-guard-*/out/xdp_api_guard.h | head -n 1)
+out=$(mktemp -d)
+cleanup() {
+    ip netns del gt-guard 2>/dev/null || true
+    ip netns del gt-peer 2>/dev/null || true
+    rm -rf "$out"
+}
+trap cleanup EXIT
+
+cc -Wall -Werror -o "$out/guard_test" xdp-api-guard/tests/ffi/guard_test.c \
+    -I"$(dirname "$header")" -Ltarget/release -lxdp_api_guard
+
+ip netns add gt-guard
+ip netns add gt-peer
+ip link add veth-guard netns gt-guard type veth peer name veth-peer netns gt-peer
+ip -n gt-guard addr add 10.99.0.1/24 dev veth-guard
+ip -n gt-peer addr add 10.99.0.2/24 dev veth-peer
+ip -n gt-guard link set veth-guard up
+ip -n gt-peer link set veth-peer up
+
+LD_LIBRARY_PATH=target/release ip netns exec gt-guard "$out/guard_test" >"$out/stdout"
+if [ -s "$out/stdout" ]; then
+    echo "FAIL the guard wrote to stdout:" >&2
+    cat "$out/stdout" >&2
+    exit 1
+fi